@@ -0,0 +1,262 @@
+-- The schema of the SQLite migrations up to this version, in one step.
+-- Columns in keys or with defaults are VARCHAR, as MySQL allows neither for TEXT.
+CREATE TABLE `user` (
+	id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+	username VARCHAR(255) UNIQUE NOT NULL,
+	enabled BOOLEAN NOT NULL DEFAULT TRUE,
+	email TEXT,
+	default_options TEXT
+);
+
+CREATE TABLE host (
+	id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+	name VARCHAR(255) UNIQUE NOT NULL,
+	username TEXT NOT NULL,
+	address VARCHAR(255) NOT NULL,
+	port INTEGER NOT NULL,
+	key_fingerprint TEXT,
+	jump_via INTEGER,
+	forbid_forwarding BOOLEAN NOT NULL DEFAULT FALSE,
+	owner TEXT,
+	default_options TEXT,
+	key_trust TEXT,
+	importance VARCHAR(255) NOT NULL DEFAULT 'normal',
+	observed_until DATETIME(6),
+	CONSTRAINT unique_address_port UNIQUE (address, port),
+	FOREIGN KEY (jump_via) REFERENCES host(id) ON DELETE CASCADE
+);
+
+CREATE TABLE user_key (
+	id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+	key_type TEXT NOT NULL,
+	key_base64 VARCHAR(2000) CHARACTER SET ascii UNIQUE NOT NULL,
+	comment TEXT,
+	user_id INTEGER NOT NULL,
+	expires_at DATETIME(6),
+	attestation BLOB,
+	attested_by TEXT,
+	FOREIGN KEY (user_id) REFERENCES `user`(id) ON DELETE CASCADE
+);
+
+CREATE TABLE `authorization` (
+	id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+	host_id INTEGER NOT NULL,
+	user_id INTEGER NOT NULL,
+	login VARCHAR(255) NOT NULL,
+	options TEXT,
+	expires_at DATETIME(6),
+	UNIQUE (user_id, host_id, login),
+	FOREIGN KEY (host_id) REFERENCES host(id) ON DELETE CASCADE,
+	FOREIGN KEY (user_id) REFERENCES `user`(id) ON DELETE CASCADE
+);
+
+CREATE TABLE host_archive (
+	id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+	name TEXT NOT NULL,
+	username TEXT NOT NULL,
+	address TEXT NOT NULL,
+	port INTEGER NOT NULL,
+	key_fingerprint TEXT,
+	decommissioned_at DATETIME(6) NOT NULL,
+	access_report TEXT NOT NULL
+);
+
+CREATE TABLE user_offboarding (
+	id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+	username TEXT NOT NULL,
+	offboarded_at DATETIME(6) NOT NULL,
+	report TEXT NOT NULL
+);
+
+CREATE TABLE api_token (
+	id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+	name TEXT NOT NULL,
+	token_hash VARCHAR(64) CHARACTER SET ascii UNIQUE NOT NULL,
+	created_by TEXT NOT NULL,
+	created_at DATETIME(6) NOT NULL,
+	last_used DATETIME(6)
+);
+
+CREATE TABLE web_user (
+	id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+	username VARCHAR(255) UNIQUE NOT NULL,
+	oidc_subject VARCHAR(255) UNIQUE,
+	created_at DATETIME(6) NOT NULL,
+	last_login DATETIME(6),
+	role VARCHAR(255) NOT NULL DEFAULT 'admin',
+	totp_secret TEXT,
+	totp_last_step BIGINT
+);
+
+CREATE TABLE cluster_worker (
+	id VARCHAR(255) PRIMARY KEY,
+	heartbeat DATETIME(6) NOT NULL
+);
+
+CREATE TABLE host_sync (
+	host_id INTEGER PRIMARY KEY,
+	worker_id TEXT,
+	claimed_at DATETIME(6),
+	finished_at DATETIME(6),
+	FOREIGN KEY (host_id) REFERENCES host(id) ON DELETE CASCADE
+);
+
+CREATE TABLE audit_log (
+	id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+	created_at DATETIME(6) NOT NULL,
+	actor TEXT NOT NULL,
+	action TEXT NOT NULL,
+	target TEXT NOT NULL,
+	success BOOLEAN NOT NULL,
+	result TEXT NOT NULL,
+	client TEXT
+);
+
+CREATE TABLE integrity_report (
+	id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+	created_at DATETIME(6) NOT NULL,
+	summary TEXT NOT NULL,
+	hosts TEXT NOT NULL
+);
+
+CREATE TABLE access_request (
+	id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+	created_at DATETIME(6) NOT NULL,
+	requested_by TEXT NOT NULL,
+	user_id INTEGER NOT NULL,
+	host_id INTEGER NOT NULL,
+	login TEXT NOT NULL,
+	justification TEXT NOT NULL,
+	status TEXT NOT NULL,
+	decided_by TEXT,
+	decided_at DATETIME(6),
+	result TEXT,
+	FOREIGN KEY (user_id) REFERENCES `user`(id) ON DELETE CASCADE,
+	FOREIGN KEY (host_id) REFERENCES host(id) ON DELETE CASCADE
+);
+
+CREATE TABLE host_group (
+	id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+	name VARCHAR(255) NOT NULL UNIQUE
+);
+
+CREATE TABLE host_group_member (
+	group_id INTEGER NOT NULL,
+	host_id INTEGER NOT NULL,
+	PRIMARY KEY (group_id, host_id),
+	FOREIGN KEY (group_id) REFERENCES host_group(id) ON DELETE CASCADE,
+	FOREIGN KEY (host_id) REFERENCES host(id) ON DELETE CASCADE
+);
+
+CREATE TABLE user_group (
+	id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+	name VARCHAR(255) NOT NULL UNIQUE
+);
+
+CREATE TABLE user_group_member (
+	group_id INTEGER NOT NULL,
+	user_id INTEGER NOT NULL,
+	PRIMARY KEY (group_id, user_id),
+	FOREIGN KEY (group_id) REFERENCES user_group(id) ON DELETE CASCADE,
+	FOREIGN KEY (user_id) REFERENCES `user`(id) ON DELETE CASCADE
+);
+
+CREATE TABLE group_authorization (
+	id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+	user_group_id INTEGER NOT NULL,
+	host_group_id INTEGER NOT NULL,
+	login VARCHAR(255) NOT NULL,
+	options TEXT,
+	UNIQUE (user_group_id, host_group_id, login),
+	FOREIGN KEY (user_group_id) REFERENCES user_group(id) ON DELETE CASCADE,
+	FOREIGN KEY (host_group_id) REFERENCES host_group(id) ON DELETE CASCADE
+);
+
+CREATE TABLE pending_removal (
+	id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+	created_at DATETIME(6) NOT NULL,
+	host_id INTEGER NOT NULL,
+	login VARCHAR(255) NOT NULL,
+	key_base64 VARCHAR(2000) CHARACTER SET ascii NOT NULL,
+	reason TEXT NOT NULL,
+	attempts INTEGER NOT NULL,
+	last_attempt DATETIME(6),
+	last_error TEXT,
+	UNIQUE (host_id, login, key_base64),
+	FOREIGN KEY (host_id) REFERENCES host(id) ON DELETE CASCADE
+);
+
+CREATE TABLE finding (
+	id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+	created_at DATETIME(6) NOT NULL,
+	kind VARCHAR(255) NOT NULL,
+	subject VARCHAR(512) NOT NULL,
+	details TEXT NOT NULL,
+	status TEXT NOT NULL,
+	decided_by TEXT,
+	decided_at DATETIME(6),
+	UNIQUE (kind, subject)
+);
+
+CREATE TABLE pending_authorization (
+	id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+	created_at DATETIME(6) NOT NULL,
+	proposed_by TEXT NOT NULL,
+	host_id INTEGER NOT NULL,
+	user_id INTEGER NOT NULL,
+	login TEXT NOT NULL,
+	options TEXT,
+	expires_at DATETIME(6),
+	status TEXT NOT NULL,
+	decided_by TEXT,
+	decided_at DATETIME(6),
+	FOREIGN KEY (host_id) REFERENCES host(id) ON DELETE CASCADE,
+	FOREIGN KEY (user_id) REFERENCES `user`(id) ON DELETE CASCADE
+);
+
+CREATE TABLE deployment (
+	host_id INTEGER NOT NULL,
+	login VARCHAR(255) NOT NULL,
+	deployed_at DATETIME(6) NOT NULL,
+	PRIMARY KEY (host_id, login),
+	FOREIGN KEY (host_id) REFERENCES host(id) ON DELETE CASCADE
+);
+
+CREATE TABLE login_attempt (
+	id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+	created_at DATETIME(6) NOT NULL,
+	username VARCHAR(255) NOT NULL,
+	client VARCHAR(255),
+	outcome TEXT NOT NULL
+);
+CREATE INDEX login_attempt_username ON login_attempt (username, created_at);
+CREATE INDEX login_attempt_client ON login_attempt (client, created_at);
+
+CREATE TABLE recovery_code (
+	id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+	web_user_id INTEGER NOT NULL,
+	code_hash TEXT NOT NULL,
+	FOREIGN KEY (web_user_id) REFERENCES web_user(id) ON DELETE CASCADE
+);
+
+CREATE TABLE web_session (
+	id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+	key_hash VARCHAR(64) CHARACTER SET ascii NOT NULL UNIQUE,
+	state TEXT NOT NULL,
+	username VARCHAR(255),
+	client TEXT,
+	created_at DATETIME(6) NOT NULL,
+	last_activity DATETIME(6) NOT NULL,
+	expires_at DATETIME(6) NOT NULL
+);
+CREATE INDEX web_session_username ON web_session (username);
+CREATE INDEX web_session_expires_at ON web_session (expires_at);
+
+CREATE TABLE password_reset (
+	id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+	web_user_id INTEGER NOT NULL,
+	token_hash VARCHAR(64) CHARACTER SET ascii NOT NULL UNIQUE,
+	created_at DATETIME(6) NOT NULL,
+	expires_at DATETIME(6) NOT NULL,
+	FOREIGN KEY (web_user_id) REFERENCES web_user(id) ON DELETE CASCADE
+);
@@ -0,0 +1,13 @@
+CREATE TABLE user_account (
+	user_id INTEGER NOT NULL,
+	provider VARCHAR(64) NOT NULL,
+	login TEXT NOT NULL,
+	PRIMARY KEY (user_id, provider),
+	FOREIGN KEY (user_id) REFERENCES `user`(id) ON DELETE CASCADE
+);
+
+CREATE TABLE imported_key (
+	key_id INTEGER NOT NULL PRIMARY KEY,
+	provider TEXT NOT NULL,
+	FOREIGN KEY (key_id) REFERENCES user_key(id) ON DELETE CASCADE
+);
@@ -0,0 +1,26 @@
+DROP TABLE password_reset;
+DROP TABLE web_session;
+DROP TABLE recovery_code;
+DROP TABLE login_attempt;
+DROP TABLE deployment;
+DROP TABLE pending_authorization;
+DROP TABLE finding;
+DROP TABLE pending_removal;
+DROP TABLE group_authorization;
+DROP TABLE user_group_member;
+DROP TABLE user_group;
+DROP TABLE host_group_member;
+DROP TABLE host_group;
+DROP TABLE access_request;
+DROP TABLE integrity_report;
+DROP TABLE audit_log;
+DROP TABLE host_sync;
+DROP TABLE cluster_worker;
+DROP TABLE web_user;
+DROP TABLE api_token;
+DROP TABLE user_offboarding;
+DROP TABLE host_archive;
+DROP TABLE "authorization";
+DROP TABLE user_key;
+DROP TABLE host;
+DROP TABLE "user";
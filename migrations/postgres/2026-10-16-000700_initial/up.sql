@@ -0,0 +1,242 @@
+-- The schema of the SQLite migrations up to this version, in one step
+CREATE TABLE "user" (
+	id SERIAL PRIMARY KEY,
+	username TEXT UNIQUE NOT NULL,
+	enabled BOOLEAN NOT NULL DEFAULT TRUE,
+	email TEXT,
+	default_options TEXT
+);
+
+CREATE TABLE host (
+	id SERIAL PRIMARY KEY,
+	name TEXT UNIQUE NOT NULL,
+	username TEXT NOT NULL,
+	address TEXT NOT NULL,
+	port INTEGER NOT NULL,
+	key_fingerprint TEXT,
+	jump_via INTEGER REFERENCES host(id) ON DELETE CASCADE,
+	forbid_forwarding BOOLEAN NOT NULL DEFAULT FALSE,
+	owner TEXT,
+	default_options TEXT,
+	key_trust TEXT,
+	importance TEXT NOT NULL DEFAULT 'normal',
+	observed_until TIMESTAMP,
+	CONSTRAINT unique_address_port UNIQUE (address, port)
+);
+
+CREATE TABLE user_key (
+	id SERIAL PRIMARY KEY,
+	key_type TEXT NOT NULL,
+	key_base64 TEXT UNIQUE NOT NULL,
+	comment TEXT,
+	user_id INTEGER NOT NULL REFERENCES "user"(id) ON DELETE CASCADE,
+	expires_at TIMESTAMP,
+	attestation BYTEA,
+	attested_by TEXT
+);
+
+CREATE TABLE "authorization" (
+	id SERIAL PRIMARY KEY,
+	host_id INTEGER NOT NULL REFERENCES host(id) ON DELETE CASCADE,
+	user_id INTEGER NOT NULL REFERENCES "user"(id) ON DELETE CASCADE,
+	login TEXT NOT NULL,
+	options TEXT,
+	expires_at TIMESTAMP,
+	UNIQUE (user_id, host_id, login)
+);
+
+CREATE TABLE host_archive (
+	id SERIAL PRIMARY KEY,
+	name TEXT NOT NULL,
+	username TEXT NOT NULL,
+	address TEXT NOT NULL,
+	port INTEGER NOT NULL,
+	key_fingerprint TEXT,
+	decommissioned_at TIMESTAMP NOT NULL,
+	access_report TEXT NOT NULL
+);
+
+CREATE TABLE user_offboarding (
+	id SERIAL PRIMARY KEY,
+	username TEXT NOT NULL,
+	offboarded_at TIMESTAMP NOT NULL,
+	report TEXT NOT NULL
+);
+
+CREATE TABLE api_token (
+	id SERIAL PRIMARY KEY,
+	name TEXT NOT NULL,
+	token_hash TEXT UNIQUE NOT NULL,
+	created_by TEXT NOT NULL,
+	created_at TIMESTAMP NOT NULL,
+	last_used TIMESTAMP
+);
+
+CREATE TABLE web_user (
+	id SERIAL PRIMARY KEY,
+	username TEXT UNIQUE NOT NULL,
+	oidc_subject TEXT UNIQUE,
+	created_at TIMESTAMP NOT NULL,
+	last_login TIMESTAMP,
+	role TEXT NOT NULL DEFAULT 'admin',
+	totp_secret TEXT,
+	totp_last_step BIGINT
+);
+
+CREATE TABLE cluster_worker (
+	id TEXT PRIMARY KEY,
+	heartbeat TIMESTAMP NOT NULL
+);
+
+CREATE TABLE host_sync (
+	host_id INTEGER PRIMARY KEY REFERENCES host(id) ON DELETE CASCADE,
+	worker_id TEXT,
+	claimed_at TIMESTAMP,
+	finished_at TIMESTAMP
+);
+
+CREATE TABLE audit_log (
+	id SERIAL PRIMARY KEY,
+	created_at TIMESTAMP NOT NULL,
+	actor TEXT NOT NULL,
+	action TEXT NOT NULL,
+	target TEXT NOT NULL,
+	success BOOLEAN NOT NULL,
+	result TEXT NOT NULL,
+	client TEXT
+);
+
+CREATE TABLE integrity_report (
+	id SERIAL PRIMARY KEY,
+	created_at TIMESTAMP NOT NULL,
+	summary TEXT NOT NULL,
+	hosts TEXT NOT NULL
+);
+
+CREATE TABLE access_request (
+	id SERIAL PRIMARY KEY,
+	created_at TIMESTAMP NOT NULL,
+	requested_by TEXT NOT NULL,
+	user_id INTEGER NOT NULL REFERENCES "user"(id) ON DELETE CASCADE,
+	host_id INTEGER NOT NULL REFERENCES host(id) ON DELETE CASCADE,
+	login TEXT NOT NULL,
+	justification TEXT NOT NULL,
+	status TEXT NOT NULL,
+	decided_by TEXT,
+	decided_at TIMESTAMP,
+	result TEXT
+);
+
+CREATE TABLE host_group (
+	id SERIAL PRIMARY KEY,
+	name TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE host_group_member (
+	group_id INTEGER NOT NULL REFERENCES host_group(id) ON DELETE CASCADE,
+	host_id INTEGER NOT NULL REFERENCES host(id) ON DELETE CASCADE,
+	PRIMARY KEY (group_id, host_id)
+);
+
+CREATE TABLE user_group (
+	id SERIAL PRIMARY KEY,
+	name TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE user_group_member (
+	group_id INTEGER NOT NULL REFERENCES user_group(id) ON DELETE CASCADE,
+	user_id INTEGER NOT NULL REFERENCES "user"(id) ON DELETE CASCADE,
+	PRIMARY KEY (group_id, user_id)
+);
+
+CREATE TABLE group_authorization (
+	id SERIAL PRIMARY KEY,
+	user_group_id INTEGER NOT NULL REFERENCES user_group(id) ON DELETE CASCADE,
+	host_group_id INTEGER NOT NULL REFERENCES host_group(id) ON DELETE CASCADE,
+	login TEXT NOT NULL,
+	options TEXT,
+	UNIQUE (user_group_id, host_group_id, login)
+);
+
+CREATE TABLE pending_removal (
+	id SERIAL PRIMARY KEY,
+	created_at TIMESTAMP NOT NULL,
+	host_id INTEGER NOT NULL REFERENCES host(id) ON DELETE CASCADE,
+	login TEXT NOT NULL,
+	key_base64 TEXT NOT NULL,
+	reason TEXT NOT NULL,
+	attempts INTEGER NOT NULL,
+	last_attempt TIMESTAMP,
+	last_error TEXT,
+	UNIQUE (host_id, login, key_base64)
+);
+
+CREATE TABLE finding (
+	id SERIAL PRIMARY KEY,
+	created_at TIMESTAMP NOT NULL,
+	kind TEXT NOT NULL,
+	subject TEXT NOT NULL,
+	details TEXT NOT NULL,
+	status TEXT NOT NULL,
+	decided_by TEXT,
+	decided_at TIMESTAMP,
+	UNIQUE (kind, subject)
+);
+
+CREATE TABLE pending_authorization (
+	id SERIAL PRIMARY KEY,
+	created_at TIMESTAMP NOT NULL,
+	proposed_by TEXT NOT NULL,
+	host_id INTEGER NOT NULL REFERENCES host(id) ON DELETE CASCADE,
+	user_id INTEGER NOT NULL REFERENCES "user"(id) ON DELETE CASCADE,
+	login TEXT NOT NULL,
+	options TEXT,
+	expires_at TIMESTAMP,
+	status TEXT NOT NULL,
+	decided_by TEXT,
+	decided_at TIMESTAMP
+);
+
+CREATE TABLE deployment (
+	host_id INTEGER NOT NULL REFERENCES host(id) ON DELETE CASCADE,
+	login TEXT NOT NULL,
+	deployed_at TIMESTAMP NOT NULL,
+	PRIMARY KEY (host_id, login)
+);
+
+CREATE TABLE login_attempt (
+	id SERIAL PRIMARY KEY,
+	created_at TIMESTAMP NOT NULL,
+	username TEXT NOT NULL,
+	client TEXT,
+	outcome TEXT NOT NULL
+);
+CREATE INDEX login_attempt_username ON login_attempt (username, created_at);
+CREATE INDEX login_attempt_client ON login_attempt (client, created_at);
+
+CREATE TABLE recovery_code (
+	id SERIAL PRIMARY KEY,
+	web_user_id INTEGER NOT NULL REFERENCES web_user(id) ON DELETE CASCADE,
+	code_hash TEXT NOT NULL
+);
+
+CREATE TABLE web_session (
+	id SERIAL PRIMARY KEY,
+	key_hash TEXT NOT NULL UNIQUE,
+	state TEXT NOT NULL,
+	username TEXT,
+	client TEXT,
+	created_at TIMESTAMP NOT NULL,
+	last_activity TIMESTAMP NOT NULL,
+	expires_at TIMESTAMP NOT NULL
+);
+CREATE INDEX web_session_username ON web_session (username);
+CREATE INDEX web_session_expires_at ON web_session (expires_at);
+
+CREATE TABLE password_reset (
+	id SERIAL PRIMARY KEY,
+	web_user_id INTEGER NOT NULL REFERENCES web_user(id) ON DELETE CASCADE,
+	token_hash TEXT NOT NULL UNIQUE,
+	created_at TIMESTAMP NOT NULL,
+	expires_at TIMESTAMP NOT NULL
+);
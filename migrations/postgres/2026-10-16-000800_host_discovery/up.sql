@@ -0,0 +1,6 @@
+CREATE TABLE host_discovery (
+	host_id INTEGER NOT NULL PRIMARY KEY REFERENCES host(id) ON DELETE CASCADE,
+	source TEXT NOT NULL,
+	external_id TEXT NOT NULL,
+	UNIQUE (source, external_id)
+);
@@ -0,0 +1,11 @@
+CREATE TABLE user_account (
+	user_id INTEGER NOT NULL REFERENCES "user"(id) ON DELETE CASCADE,
+	provider TEXT NOT NULL,
+	login TEXT NOT NULL,
+	PRIMARY KEY (user_id, provider)
+);
+
+CREATE TABLE imported_key (
+	key_id INTEGER NOT NULL PRIMARY KEY REFERENCES user_key(id) ON DELETE CASCADE,
+	provider TEXT NOT NULL
+);
@@ -0,0 +1,10 @@
+CREATE TABLE host_archive (
+	id INTEGER NOT NULL PRIMARY KEY,
+	name TEXT NOT NULL,
+	username TEXT NOT NULL,
+	address TEXT NOT NULL,
+	port INTEGER NOT NULL,
+	key_fingerprint TEXT,
+	decommissioned_at TIMESTAMP NOT NULL,
+	access_report TEXT NOT NULL
+);
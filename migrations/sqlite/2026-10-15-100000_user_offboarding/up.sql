@@ -0,0 +1,6 @@
+CREATE TABLE user_offboarding (
+	id INTEGER NOT NULL PRIMARY KEY,
+	username TEXT NOT NULL,
+	offboarded_at TIMESTAMP NOT NULL,
+	report TEXT NOT NULL
+);
@@ -0,0 +1,8 @@
+CREATE TABLE api_token (
+	id INTEGER NOT NULL PRIMARY KEY,
+	name TEXT NOT NULL,
+	token_hash TEXT UNIQUE NOT NULL,
+	created_by TEXT NOT NULL,
+	created_at TIMESTAMP NOT NULL,
+	last_used TIMESTAMP
+);
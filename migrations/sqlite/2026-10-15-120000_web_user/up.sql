@@ -0,0 +1,7 @@
+CREATE TABLE web_user (
+	id INTEGER NOT NULL PRIMARY KEY,
+	username TEXT UNIQUE NOT NULL,
+	oidc_subject TEXT UNIQUE,
+	created_at TIMESTAMP NOT NULL,
+	last_login TIMESTAMP
+);
@@ -0,0 +1,2 @@
+DROP TABLE host_sync;
+DROP TABLE cluster_worker;
@@ -0,0 +1,12 @@
+CREATE TABLE cluster_worker (
+	id TEXT NOT NULL PRIMARY KEY,
+	heartbeat TIMESTAMP NOT NULL
+);
+
+CREATE TABLE host_sync (
+	host_id INTEGER NOT NULL PRIMARY KEY,
+	worker_id TEXT,
+	claimed_at TIMESTAMP,
+	finished_at TIMESTAMP,
+	FOREIGN KEY (host_id) REFERENCES host(id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+-- Existing users keep the full access they had before roles existed
+ALTER TABLE web_user ADD COLUMN role TEXT NOT NULL DEFAULT 'admin';
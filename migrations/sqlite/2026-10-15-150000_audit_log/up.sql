@@ -0,0 +1,9 @@
+CREATE TABLE audit_log (
+	id INTEGER NOT NULL PRIMARY KEY,
+	created_at TIMESTAMP NOT NULL,
+	actor TEXT NOT NULL,
+	action TEXT NOT NULL,
+	target TEXT NOT NULL,
+	success BOOLEAN NOT NULL,
+	result TEXT NOT NULL
+);
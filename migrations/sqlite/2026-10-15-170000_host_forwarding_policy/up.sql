@@ -0,0 +1 @@
+ALTER TABLE host ADD COLUMN forbid_forwarding BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,6 @@
+CREATE TABLE integrity_report (
+	id INTEGER NOT NULL PRIMARY KEY,
+	created_at TIMESTAMP NOT NULL,
+	summary TEXT NOT NULL,
+	hosts TEXT NOT NULL
+);
@@ -0,0 +1,2 @@
+DROP TABLE access_request;
+ALTER TABLE host DROP COLUMN owner;
@@ -0,0 +1,17 @@
+ALTER TABLE host ADD COLUMN owner TEXT;
+
+CREATE TABLE access_request (
+	id INTEGER NOT NULL PRIMARY KEY,
+	created_at TIMESTAMP NOT NULL,
+	requested_by TEXT NOT NULL,
+	user_id INTEGER NOT NULL,
+	host_id INTEGER NOT NULL,
+	login TEXT NOT NULL,
+	justification TEXT NOT NULL,
+	status TEXT NOT NULL,
+	decided_by TEXT,
+	decided_at TIMESTAMP,
+	result TEXT,
+	FOREIGN KEY (user_id) REFERENCES user(id) ON DELETE CASCADE,
+	FOREIGN KEY (host_id) REFERENCES host(id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+ALTER TABLE user DROP COLUMN default_options;
+ALTER TABLE host DROP COLUMN default_options;
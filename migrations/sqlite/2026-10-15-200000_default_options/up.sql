@@ -0,0 +1,2 @@
+ALTER TABLE host ADD COLUMN default_options TEXT;
+ALTER TABLE user ADD COLUMN default_options TEXT;
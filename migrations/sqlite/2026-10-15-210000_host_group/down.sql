@@ -0,0 +1,2 @@
+DROP TABLE host_group_member;
+DROP TABLE host_group;
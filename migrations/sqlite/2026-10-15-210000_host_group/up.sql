@@ -0,0 +1,12 @@
+CREATE TABLE host_group (
+	id INTEGER NOT NULL PRIMARY KEY,
+	name TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE host_group_member (
+	group_id INTEGER NOT NULL,
+	host_id INTEGER NOT NULL,
+	PRIMARY KEY (group_id, host_id),
+	FOREIGN KEY (group_id) REFERENCES host_group(id) ON DELETE CASCADE,
+	FOREIGN KEY (host_id) REFERENCES host(id) ON DELETE CASCADE
+);
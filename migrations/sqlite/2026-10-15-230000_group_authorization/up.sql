@@ -0,0 +1,10 @@
+CREATE TABLE group_authorization (
+	id INTEGER NOT NULL PRIMARY KEY,
+	user_group_id INTEGER NOT NULL,
+	host_group_id INTEGER NOT NULL,
+	login TEXT NOT NULL,
+	options TEXT,
+	UNIQUE (user_group_id, host_group_id, login),
+	FOREIGN KEY (user_group_id) REFERENCES user_group(id) ON DELETE CASCADE,
+	FOREIGN KEY (host_group_id) REFERENCES host_group(id) ON DELETE CASCADE
+);
@@ -0,0 +1,13 @@
+CREATE TABLE pending_removal (
+	id INTEGER NOT NULL PRIMARY KEY,
+	created_at TIMESTAMP NOT NULL,
+	host_id INTEGER NOT NULL,
+	login TEXT NOT NULL,
+	key_base64 TEXT NOT NULL,
+	reason TEXT NOT NULL,
+	attempts INTEGER NOT NULL,
+	last_attempt TIMESTAMP,
+	last_error TEXT,
+	UNIQUE (host_id, login, key_base64),
+	FOREIGN KEY (host_id) REFERENCES host(id) ON DELETE CASCADE
+);
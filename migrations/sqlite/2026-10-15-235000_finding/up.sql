@@ -0,0 +1,11 @@
+CREATE TABLE finding (
+	id INTEGER NOT NULL PRIMARY KEY,
+	created_at TIMESTAMP NOT NULL,
+	kind TEXT NOT NULL,
+	subject TEXT NOT NULL,
+	details TEXT NOT NULL,
+	status TEXT NOT NULL,
+	decided_by TEXT,
+	decided_at TIMESTAMP,
+	UNIQUE (kind, subject)
+);
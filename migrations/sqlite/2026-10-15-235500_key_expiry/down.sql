@@ -0,0 +1 @@
+ALTER TABLE user_key DROP COLUMN expires_at;
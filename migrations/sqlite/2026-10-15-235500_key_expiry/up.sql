@@ -0,0 +1 @@
+ALTER TABLE user_key ADD COLUMN expires_at TIMESTAMP;
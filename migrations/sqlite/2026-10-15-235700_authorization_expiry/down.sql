@@ -0,0 +1 @@
+ALTER TABLE authorization DROP COLUMN expires_at;
@@ -0,0 +1 @@
+ALTER TABLE authorization ADD COLUMN expires_at TIMESTAMP;
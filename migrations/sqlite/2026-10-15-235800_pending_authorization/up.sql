@@ -0,0 +1,15 @@
+CREATE TABLE pending_authorization (
+	id INTEGER NOT NULL PRIMARY KEY,
+	created_at TIMESTAMP NOT NULL,
+	proposed_by TEXT NOT NULL,
+	host_id INTEGER NOT NULL,
+	user_id INTEGER NOT NULL,
+	login TEXT NOT NULL,
+	options TEXT,
+	expires_at TIMESTAMP,
+	status TEXT NOT NULL,
+	decided_by TEXT,
+	decided_at TIMESTAMP,
+	FOREIGN KEY (host_id) REFERENCES host(id) ON DELETE CASCADE,
+	FOREIGN KEY (user_id) REFERENCES user(id) ON DELETE CASCADE
+);
@@ -0,0 +1,7 @@
+CREATE TABLE deployment (
+	host_id INTEGER NOT NULL,
+	login TEXT NOT NULL,
+	deployed_at TIMESTAMP NOT NULL,
+	PRIMARY KEY (host_id, login),
+	FOREIGN KEY (host_id) REFERENCES host(id) ON DELETE CASCADE
+);
@@ -0,0 +1 @@
+ALTER TABLE host ADD COLUMN importance TEXT NOT NULL DEFAULT 'normal';
@@ -0,0 +1,2 @@
+ALTER TABLE user_key DROP COLUMN attested_by;
+ALTER TABLE user_key DROP COLUMN attestation;
@@ -0,0 +1,2 @@
+ALTER TABLE user_key ADD COLUMN attestation BLOB;
+ALTER TABLE user_key ADD COLUMN attested_by TEXT;
@@ -0,0 +1 @@
+ALTER TABLE host ADD COLUMN observed_until TIMESTAMP;
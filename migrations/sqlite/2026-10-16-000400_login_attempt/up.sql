@@ -0,0 +1,9 @@
+CREATE TABLE login_attempt (
+	id INTEGER NOT NULL PRIMARY KEY,
+	created_at TIMESTAMP NOT NULL,
+	username TEXT NOT NULL,
+	client TEXT,
+	outcome TEXT NOT NULL
+);
+CREATE INDEX login_attempt_username ON login_attempt (username, created_at);
+CREATE INDEX login_attempt_client ON login_attempt (client, created_at);
@@ -0,0 +1,3 @@
+DROP TABLE recovery_code;
+ALTER TABLE web_user DROP COLUMN totp_last_step;
+ALTER TABLE web_user DROP COLUMN totp_secret;
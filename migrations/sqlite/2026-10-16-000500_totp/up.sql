@@ -0,0 +1,8 @@
+ALTER TABLE web_user ADD COLUMN totp_secret TEXT;
+ALTER TABLE web_user ADD COLUMN totp_last_step BIGINT;
+CREATE TABLE recovery_code (
+	id INTEGER NOT NULL PRIMARY KEY,
+	web_user_id INTEGER NOT NULL,
+	code_hash TEXT NOT NULL,
+	FOREIGN KEY (web_user_id) REFERENCES web_user(id) ON DELETE CASCADE
+);
@@ -0,0 +1,12 @@
+CREATE TABLE web_session (
+	id INTEGER NOT NULL PRIMARY KEY,
+	key_hash TEXT NOT NULL UNIQUE,
+	state TEXT NOT NULL,
+	username TEXT,
+	client TEXT,
+	created_at TIMESTAMP NOT NULL,
+	last_activity TIMESTAMP NOT NULL,
+	expires_at TIMESTAMP NOT NULL
+);
+CREATE INDEX web_session_username ON web_session (username);
+CREATE INDEX web_session_expires_at ON web_session (expires_at);
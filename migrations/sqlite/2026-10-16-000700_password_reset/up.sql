@@ -0,0 +1,7 @@
+CREATE TABLE password_reset (
+	id INTEGER NOT NULL PRIMARY KEY,
+	web_user_id INTEGER NOT NULL REFERENCES web_user(id) ON DELETE CASCADE,
+	token_hash TEXT NOT NULL UNIQUE,
+	created_at TIMESTAMP NOT NULL,
+	expires_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,7 @@
+CREATE TABLE host_discovery (
+	host_id INTEGER NOT NULL PRIMARY KEY,
+	source TEXT NOT NULL,
+	external_id TEXT NOT NULL,
+	UNIQUE (source, external_id),
+	FOREIGN KEY (host_id) REFERENCES host(id) ON DELETE CASCADE
+);
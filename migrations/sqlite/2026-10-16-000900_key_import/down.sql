@@ -0,0 +1,2 @@
+DROP TABLE imported_key;
+DROP TABLE user_account;
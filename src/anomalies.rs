@@ -0,0 +1,248 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use log::{error, info};
+use serde::Deserialize;
+use time::Weekday;
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    db::{now, BlockingPool},
+    models::{AuditEntry, Finding, Host},
+    DbConnection,
+};
+
+/// A key authorized on more hosts than `max_hosts_per_key`
+pub const WIDESPREAD_KEY: &str = "widespread_key";
+/// A root authorization added outside of business hours
+pub const ROOT_AFTER_HOURS: &str = "root_after_hours";
+/// Drift remediated at least `recurring_drift` times on the same host
+pub const RECURRING_DRIFT: &str = "recurring_drift";
+
+const fn default_interval() -> Option<Duration> {
+    Some(Duration::from_secs(3600))
+}
+
+const fn default_max_hosts_per_key() -> usize {
+    20
+}
+
+const fn default_business_hours_start() -> u8 {
+    8
+}
+
+const fn default_business_hours_end() -> u8 {
+    18
+}
+
+const fn default_recurring_drift() -> usize {
+    3
+}
+
+const fn default_lookback_days() -> i64 {
+    7
+}
+
+/// Heuristics flagging suspicious access patterns
+#[derive(Debug, Deserialize, Clone)]
+pub struct AnomalyConfig {
+    /// Seconds between analyses (default 3600, 0 disables)
+    #[serde(
+        default = "default_interval",
+        deserialize_with = "crate::deserialize_interval"
+    )]
+    interval: Option<Duration>,
+    /// Keys authorized on more hosts are flagged
+    #[serde(default = "default_max_hosts_per_key")]
+    max_hosts_per_key: usize,
+    /// First hour of business hours, in UTC
+    #[serde(default = "default_business_hours_start")]
+    business_hours_start: u8,
+    /// Hour business hours end at, in UTC. Weekends are outside business hours.
+    #[serde(default = "default_business_hours_end")]
+    business_hours_end: u8,
+    /// Hosts whose drift was remediated this often within the lookback are flagged
+    #[serde(default = "default_recurring_drift")]
+    recurring_drift: usize,
+    /// Days of the audit log that are analyzed
+    #[serde(default = "default_lookback_days")]
+    lookback_days: i64,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        Self {
+            interval: default_interval(),
+            max_hosts_per_key: default_max_hosts_per_key(),
+            business_hours_start: default_business_hours_start(),
+            business_hours_end: default_business_hours_end(),
+            recurring_drift: default_recurring_drift(),
+            lookback_days: default_lookback_days(),
+        }
+    }
+}
+
+impl AnomalyConfig {
+    fn is_business_hours(&self, time: time::PrimitiveDateTime) -> bool {
+        !matches!(time.weekday(), Weekday::Saturday | Weekday::Sunday)
+            && (self.business_hours_start..self.business_hours_end).contains(&time.hour())
+    }
+}
+
+/// Fields of an audit log target, e.g. `host_id=1, login=root`
+fn target_field<'a>(target: &'a str, name: &str) -> Option<&'a str> {
+    target
+        .split(", ")
+        .filter_map(|field| field.split_once('='))
+        .find(|(field, _)| *field == name)
+        .map(|(_, value)| value)
+}
+
+/// Whether an audit log action authorizes users, through the web interface or the API
+fn is_authorization(action: &str) -> bool {
+    action.strip_prefix("POST ").is_some_and(|path| {
+        !path.contains("/delete")
+            && (path.ends_with("/authorize") || path.ends_with("/authorizations"))
+    })
+}
+
+/// Periodically looks for suspicious patterns and records them as findings
+#[derive(Clone)]
+pub struct AnomalyDetector {
+    db: BlockingPool,
+    config: AnomalyConfig,
+}
+
+impl AnomalyDetector {
+    pub fn new(db: BlockingPool, config: AnomalyConfig) -> Self {
+        Self { db, config }
+    }
+
+    /// Analyzes now and then every configured interval, unless disabled
+    pub fn start(&self) {
+        let Some(interval) = self.config.interval else {
+            return;
+        };
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                if let Err(e) = this.run().await {
+                    error!("Failed to look for anomalies: {e}");
+                }
+            }
+        });
+    }
+
+    /// Runs every heuristic. Returns the number of new findings.
+    pub async fn run(&self) -> Result<usize, String> {
+        let config = self.config.clone();
+        let raised = self
+            .db
+            .run(move |conn| {
+                let mut raised = 0;
+                for (kind, subject, details) in widespread_keys(conn, &config)?
+                    .into_iter()
+                    .chain(audit_anomalies(conn, &config)?)
+                {
+                    if Finding::raise(conn, kind, &subject, &details)? {
+                        raised += 1;
+                    }
+                }
+                Ok::<_, String>(raised)
+            })
+            .await??;
+        if raised > 0 {
+            info!("Found {raised} new anomalies");
+        }
+        Ok(raised)
+    }
+}
+
+type Anomaly = (&'static str, String, String);
+
+fn widespread_keys(
+    conn: &mut DbConnection,
+    config: &AnomalyConfig,
+) -> Result<Vec<Anomaly>, String> {
+    let mut hosts_per_key: BTreeMap<i32, (String, String, usize)> = BTreeMap::new();
+    for host in Host::get_all_hosts(conn)? {
+        let mut keys: Vec<_> = host
+            .get_authorized_keys(conn)?
+            .into_iter()
+            .map(|entry| (entry.key, entry.username))
+            .collect();
+        keys.sort_by_key(|(key, _)| key.id);
+        keys.dedup_by_key(|(key, _)| key.id);
+        for (key, username) in keys {
+            let name = key.comment.unwrap_or(key.key_type);
+            hosts_per_key.entry(key.id).or_insert((name, username, 0)).2 += 1;
+        }
+    }
+
+    Ok(hosts_per_key
+        .into_iter()
+        .filter(|(_, (_, _, hosts))| *hosts > config.max_hosts_per_key)
+        .map(|(id, (name, username, hosts))| {
+            (
+                WIDESPREAD_KEY,
+                format!("key:{id}"),
+                format!(
+                    "The key {name} of {username} is authorized on {hosts} hosts, more than {}",
+                    config.max_hosts_per_key
+                ),
+            )
+        })
+        .collect())
+}
+
+fn audit_anomalies(
+    conn: &mut DbConnection,
+    config: &AnomalyConfig,
+) -> Result<Vec<Anomaly>, String> {
+    let since = now() - time::Duration::days(config.lookback_days);
+    let entries = AuditEntry::get_successful_since(conn, since)?;
+    let mut anomalies = Vec::new();
+
+    for entry in &entries {
+        if is_authorization(&entry.action)
+            && target_field(&entry.target, "login") == Some("root")
+            && !config.is_business_hours(entry.created_at)
+        {
+            anomalies.push((
+                ROOT_AFTER_HOURS,
+                format!("audit:{}", entry.id),
+                format!(
+                    "{} authorized root at {} UTC outside of business hours: {} ({})",
+                    entry.actor, entry.created_at, entry.action, entry.target
+                ),
+            ));
+        }
+    }
+
+    let mut remediations: BTreeMap<&str, usize> = BTreeMap::new();
+    for entry in &entries {
+        if entry.action == "remediate drift" {
+            if let Some(host) = target_field(&entry.target, "host") {
+                *remediations.entry(host).or_default() += 1;
+            }
+        }
+    }
+    anomalies.extend(
+        remediations
+            .into_iter()
+            .filter(|(_, count)| *count >= config.recurring_drift)
+            .map(|(host, count)| {
+                (
+                    RECURRING_DRIFT,
+                    format!("host:{host}"),
+                    format!(
+                        "Drift was remediated {count} times on {host} in the last {} days",
+                        config.lookback_days
+                    ),
+                )
+            }),
+    );
+    Ok(anomalies)
+}
@@ -0,0 +1,402 @@
+//! Hosts of Ansible inventories, in the INI or YAML format
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use yaml_rust2::{Yaml, YamlLoader};
+
+/// Groups every host is in, which don't become host groups
+const IMPLICIT_GROUPS: [&str; 2] = ["all", "ungrouped"];
+
+/// A host of an inventory, with the connection variables that apply to it
+pub struct AnsibleHost {
+    /// Line of the first entry of the host
+    pub line: usize,
+    pub name: String,
+    /// `ansible_host`, else the name
+    pub address: String,
+    pub port: Option<String>,
+    pub user: Option<String>,
+    /// Its groups and all groups they are children of
+    pub groups: Vec<String>,
+}
+
+#[derive(Default)]
+struct Group {
+    hosts: Vec<String>,
+    children: Vec<String>,
+    vars: HashMap<String, String>,
+}
+
+#[derive(Default)]
+struct Inventory {
+    /// Host names in the order of their first appearance, with that line
+    hosts: Vec<(String, usize)>,
+    host_vars: HashMap<String, HashMap<String, String>>,
+    /// Sorted by name, which decides between variables of groups at the same depth
+    groups: BTreeMap<String, Group>,
+}
+
+/// Expands ranges like `www[01:50].example.com` or `db-[a:c]`, with an optional stride
+fn expand_range(pattern: &str) -> Result<Vec<String>, String> {
+    let Some((prefix, rest)) = pattern.split_once('[') else {
+        return Ok(vec![pattern.to_owned()]);
+    };
+    let (range, suffix) = rest
+        .split_once(']')
+        .ok_or_else(|| format!("Unclosed range in {pattern}"))?;
+    let mut parts = range.split(':');
+    let (Some(start), Some(end)) = (parts.next(), parts.next()) else {
+        return Err(format!("Invalid range [{range}] in {pattern}"));
+    };
+    let stride = match parts.next() {
+        Some(stride) => stride
+            .parse::<usize>()
+            .ok()
+            .filter(|stride| *stride > 0)
+            .ok_or_else(|| format!("Invalid stride in {pattern}"))?,
+        None => 1,
+    };
+
+    let values: Vec<String> = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(first), Ok(last)) if first <= last => (first..=last)
+            .step_by(stride)
+            .map(|i| format!("{i:0width$}", width = start.len()))
+            .collect(),
+        _ => match (start.as_bytes(), end.as_bytes()) {
+            ([first], [last]) if first.is_ascii_alphabetic() && first <= last => (*first..=*last)
+                .step_by(stride)
+                .map(|c| char::from(c).to_string())
+                .collect(),
+            _ => return Err(format!("Invalid range [{range}] in {pattern}")),
+        },
+    };
+
+    let mut expanded = Vec::new();
+    for end in expand_range(suffix)? {
+        expanded.extend(values.iter().map(|value| format!("{prefix}{value}{end}")));
+    }
+    Ok(expanded)
+}
+
+/// Splits at whitespace outside of quotes, the quotes are removed
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quote = None;
+    for c in line.chars() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            (c, None) if c.is_whitespace() => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            (c, _) => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// Splits `host:port` of INI inventories, leaving IPv6 addresses and ranges alone
+fn split_port(host: &str) -> (&str, Option<&str>) {
+    match host.rsplit_once(':') {
+        Some((name, port))
+            if !port.is_empty()
+                && port.chars().all(|c| c.is_ascii_digit())
+                && !name.contains(':')
+                && !name.ends_with('[') =>
+        {
+            (name, Some(port))
+        }
+        _ => (host, None),
+    }
+}
+
+fn yaml_value(value: &Yaml) -> Option<String> {
+    match value {
+        Yaml::String(s) | Yaml::Real(s) => Some(s.clone()),
+        Yaml::Integer(i) => Some(i.to_string()),
+        Yaml::Boolean(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// The first line declaring a key of a YAML document, as the loader doesn't keep them
+fn line_of_key(inventory: &str, key: &str) -> usize {
+    inventory
+        .lines()
+        .position(|line| {
+            line.split_once(':').is_some_and(|(declared, _)| {
+                declared.trim().trim_matches(|c| c == '"' || c == '\'') == key
+            })
+        })
+        .map_or(0, |index| index + 1)
+}
+
+impl Inventory {
+    fn add_host(&mut self, name: String, line: usize, vars: HashMap<String, String>) {
+        if !self.hosts.iter().any(|(known, _)| known == &name) {
+            self.hosts.push((name.clone(), line));
+        }
+        self.host_vars.entry(name).or_default().extend(vars);
+    }
+
+    fn parse_ini(inventory: &str) -> Result<Self, String> {
+        let mut parsed = Self::default();
+        // Hosts before the first section are ungrouped
+        let mut section = (String::from("ungrouped"), String::from("hosts"));
+
+        for (index, line) in inventory.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = match header.split_once(':') {
+                    Some((group, kind @ ("vars" | "children"))) => {
+                        (group.to_owned(), kind.to_owned())
+                    }
+                    Some(_) => {
+                        return Err(format!("Line {}: unknown section [{header}]", index + 1))
+                    }
+                    None => (header.to_owned(), String::from("hosts")),
+                };
+                parsed.groups.entry(section.0.clone()).or_default();
+                continue;
+            }
+
+            match section.1.as_str() {
+                "vars" => {
+                    let Some((key, value)) = line.split_once('=') else {
+                        return Err(format!("Line {}: expected key=value", index + 1));
+                    };
+                    let value = split_words(value).join(" ");
+                    let group = parsed.groups.entry(section.0.clone()).or_default();
+                    group.vars.insert(key.trim().to_owned(), value);
+                }
+                "children" => {
+                    let group = parsed.groups.entry(section.0.clone()).or_default();
+                    group.children.push(line.to_owned());
+                }
+                _ => {
+                    let mut words = split_words(line).into_iter();
+                    let pattern = words.next().unwrap_or_default();
+                    let mut vars: HashMap<String, String> = words
+                        .filter_map(|word| {
+                            word.split_once('=')
+                                .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                        })
+                        .collect();
+                    let (pattern, port) = split_port(&pattern);
+                    if let Some(port) = port {
+                        vars.entry(String::from("ansible_port"))
+                            .or_insert_with(|| port.to_owned());
+                    }
+                    let names = expand_range(pattern)
+                        .map_err(|error| format!("Line {}: {error}", index + 1))?;
+                    for name in names {
+                        let group = parsed.groups.entry(section.0.clone()).or_default();
+                        group.hosts.push(name.clone());
+                        parsed.add_host(name, index + 1, vars.clone());
+                    }
+                }
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn parse_yaml(inventory: &str) -> Result<Self, String> {
+        let documents =
+            YamlLoader::load_from_str(inventory).map_err(|e| format!("Invalid YAML: {e}"))?;
+        let mut parsed = Self::default();
+        let Some(root) = documents.first() else {
+            return Ok(parsed);
+        };
+        let groups = root
+            .as_hash()
+            .ok_or_else(|| String::from("Expected groups at the top level, usually all:"))?;
+        for (name, group) in groups {
+            let name = yaml_value(name).ok_or_else(|| String::from("Invalid group name"))?;
+            parsed.parse_yaml_group(inventory, name, group)?;
+        }
+        Ok(parsed)
+    }
+
+    fn parse_yaml_group(
+        &mut self,
+        inventory: &str,
+        name: String,
+        group: &Yaml,
+    ) -> Result<(), String> {
+        self.groups.entry(name.clone()).or_default();
+        let Some(group) = group.as_hash() else {
+            // A group without hosts, children or vars is empty
+            return Ok(());
+        };
+
+        for (key, value) in group {
+            match key.as_str() {
+                Some("hosts") => {
+                    for (pattern, vars) in value.as_hash().into_iter().flatten() {
+                        let pattern =
+                            yaml_value(pattern).ok_or_else(|| format!("Invalid host in {name}"))?;
+                        let vars: HashMap<String, String> = vars
+                            .as_hash()
+                            .into_iter()
+                            .flatten()
+                            .filter_map(|(key, value)| Some((yaml_value(key)?, yaml_value(value)?)))
+                            .collect();
+                        let line = line_of_key(inventory, &pattern);
+                        for host in expand_range(&pattern)? {
+                            self.groups
+                                .entry(name.clone())
+                                .or_default()
+                                .hosts
+                                .push(host.clone());
+                            self.add_host(host, line, vars.clone());
+                        }
+                    }
+                }
+                Some("children") => {
+                    for (child, child_group) in value.as_hash().into_iter().flatten() {
+                        let child =
+                            yaml_value(child).ok_or_else(|| format!("Invalid child of {name}"))?;
+                        self.groups
+                            .entry(name.clone())
+                            .or_default()
+                            .children
+                            .push(child.clone());
+                        self.parse_yaml_group(inventory, child, child_group)?;
+                    }
+                }
+                Some("vars") => {
+                    let vars = value
+                        .as_hash()
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|(key, value)| Some((yaml_value(key)?, yaml_value(value)?)));
+                    self.groups
+                        .entry(name.clone())
+                        .or_default()
+                        .vars
+                        .extend(vars);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Parents of each group, `all` is the parent of every group without one
+    fn parents(&self) -> HashMap<&str, Vec<&str>> {
+        let mut parents: HashMap<&str, Vec<&str>> = HashMap::new();
+        for (name, group) in &self.groups {
+            for child in &group.children {
+                parents
+                    .entry(child.as_str())
+                    .or_default()
+                    .push(name.as_str());
+            }
+        }
+        for name in self.groups.keys() {
+            if name != "all" {
+                parents.entry(name.as_str()).or_insert_with(|| vec!["all"]);
+            }
+        }
+        parents
+    }
+
+    /// Distance of a group from `all`, the deepest path counts. Cycles are cut.
+    fn depth(parents: &HashMap<&str, Vec<&str>>, group: &str, seen: &mut Vec<String>) -> usize {
+        if group == "all" || seen.iter().any(|s| s == group) {
+            return 0;
+        }
+        seen.push(group.to_owned());
+        let depth = parents
+            .get(group)
+            .into_iter()
+            .flatten()
+            .map(|parent| Self::depth(parents, parent, seen) + 1)
+            .max()
+            .unwrap_or(1);
+        seen.pop();
+        depth
+    }
+
+    fn ancestors<'a>(
+        parents: &HashMap<&'a str, Vec<&'a str>>,
+        group: &'a str,
+        found: &mut BTreeSet<&'a str>,
+    ) {
+        if !found.insert(group) {
+            return;
+        }
+        for parent in parents.get(group).into_iter().flatten() {
+            Self::ancestors(parents, parent, found);
+        }
+    }
+
+    /// Each host with its variables merged like Ansible does: host variables win,
+    /// then those of the deepest group, then of the group sorted last by name
+    fn resolve(self) -> Vec<AnsibleHost> {
+        let parents = self.parents();
+        self.hosts
+            .iter()
+            .map(|(name, line)| {
+                let mut groups = BTreeSet::from(["all"]);
+                for (group_name, group) in &self.groups {
+                    if group.hosts.contains(name) {
+                        Self::ancestors(&parents, group_name, &mut groups);
+                    }
+                }
+                let mut ranked: Vec<(usize, &str)> = groups
+                    .iter()
+                    .map(|group| (Self::depth(&parents, group, &mut Vec::new()), *group))
+                    .collect();
+                ranked.sort_unstable();
+
+                let host_vars = self.host_vars.get(name);
+                let var = |keys: &[&str]| {
+                    keys.iter().find_map(|key| {
+                        host_vars
+                            .and_then(|vars| vars.get(*key))
+                            .cloned()
+                            .or_else(|| {
+                                ranked.iter().rev().find_map(|(_, group)| {
+                                    self.groups.get(*group)?.vars.get(*key).cloned()
+                                })
+                            })
+                    })
+                };
+
+                AnsibleHost {
+                    line: *line,
+                    name: name.clone(),
+                    address: var(&["ansible_host", "ansible_ssh_host"])
+                        .unwrap_or_else(|| name.clone()),
+                    port: var(&["ansible_port", "ansible_ssh_port"]),
+                    user: var(&["ansible_user", "ansible_ssh_user"]),
+                    groups: groups
+                        .into_iter()
+                        .filter(|group| !IMPLICIT_GROUPS.contains(group))
+                        .map(str::to_owned)
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Hosts of an inventory in the INI format
+pub fn parse_ini_inventory(inventory: &str) -> Result<Vec<AnsibleHost>, String> {
+    Inventory::parse_ini(inventory).map(Inventory::resolve)
+}
+
+/// Hosts of an inventory in the YAML format
+pub fn parse_yaml_inventory(inventory: &str) -> Result<Vec<AnsibleHost>, String> {
+    Inventory::parse_yaml(inventory).map(Inventory::resolve)
+}
@@ -0,0 +1,182 @@
+use std::{fs, path::PathBuf, sync::OnceLock, time::Duration};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use ssh_key::PublicKey;
+use time::OffsetDateTime;
+use x509_parser::pem::Pem;
+
+use crate::ssh::Attestation;
+
+/// Prefix of the challenges, so they can't be mistaken for anything else
+const CHALLENGE_PREFIX: &str = "ssm-attest";
+
+const fn default_challenge_lifetime() -> Duration {
+    Duration::from_secs(3600)
+}
+
+/// Verifying the FIDO attestation of hardware-backed keys when they are uploaded
+#[derive(Debug, Deserialize, Clone)]
+pub struct AttestationConfig {
+    /// PEM files with the attestation root certificates of the trusted authenticator vendors
+    #[serde(default)]
+    roots: Vec<PathBuf>,
+    /// Seconds a challenge can be used to create a key (default 3600)
+    #[serde(
+        default = "default_challenge_lifetime",
+        deserialize_with = "crate::deserialize_timeout"
+    )]
+    challenge_lifetime: Duration,
+}
+
+impl Default for AttestationConfig {
+    fn default() -> Self {
+        Self {
+            roots: Vec::new(),
+            challenge_lifetime: default_challenge_lifetime(),
+        }
+    }
+}
+
+struct Attestations {
+    /// DER encoded root certificates
+    roots: Vec<Vec<u8>>,
+    /// Signs the challenges, so they don't have to be stored
+    secret: Vec<u8>,
+    challenge_lifetime: Duration,
+}
+
+static ATTESTATIONS: OnceLock<Attestations> = OnceLock::new();
+
+fn read_roots(path: &PathBuf) -> Result<Vec<Vec<u8>>, String> {
+    let pem = fs::read(path).map_err(|e| format!("Failed to read {path:?}: {e}"))?;
+    let roots: Vec<Vec<u8>> = Pem::iter_from_buffer(&pem)
+        .map(|pem| {
+            pem.map(|pem| pem.contents)
+                .map_err(|e| format!("Invalid certificate in {path:?}: {e}"))
+        })
+        .collect::<Result<_, _>>()?;
+    if roots.is_empty() {
+        return Err(format!("There are no certificates in {path:?}"));
+    }
+    Ok(roots)
+}
+
+impl AttestationConfig {
+    /// Reads the root certificates, then makes them available to key uploads. Challenges are
+    /// signed with `secret`, so every instance accepts them.
+    pub fn install(self, secret: &str) -> Result<(), String> {
+        let mut roots = Vec::new();
+        for path in &self.roots {
+            roots.extend(read_roots(path)?);
+        }
+        ATTESTATIONS
+            .set(Attestations {
+                roots,
+                secret: secret.as_bytes().to_vec(),
+                challenge_lifetime: self.challenge_lifetime,
+            })
+            .map_err(|_| String::from("The attestation settings were already installed"))
+    }
+}
+
+impl Attestations {
+    fn mac(&self, username: &str, issued: i64) -> Hmac<Sha256> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.secret)
+            .expect("HMAC takes keys of any size");
+        mac.update(format!("{CHALLENGE_PREFIX}\0{username}\0{issued}").as_bytes());
+        mac
+    }
+
+    /// Whether ssm issued the challenge to the user, and not too long ago
+    fn check_challenge(&self, username: &str, challenge: &str) -> Result<(), String> {
+        let invalid = || String::from("Invalid challenge, please create the key with a new one");
+        let mut parts = challenge.trim().split(':');
+        let (Some(CHALLENGE_PREFIX), Some(issued), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        let issued = issued.parse::<i64>().map_err(|_| invalid())?;
+        let signature = HEXLOWER_PERMISSIVE
+            .decode(signature.as_bytes())
+            .map_err(|_| invalid())?;
+        self.mac(username, issued)
+            .verify_slice(&signature)
+            .map_err(|_| invalid())?;
+
+        let age = OffsetDateTime::now_utc().unix_timestamp() - issued;
+        if age < 0 || age as u64 > self.challenge_lifetime.as_secs() {
+            return Err(String::from(
+                "The challenge has expired, please create the key with a new one",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Whether uploads of attested keys are possible
+pub fn enabled() -> bool {
+    ATTESTATIONS
+        .get()
+        .is_some_and(|attestations| !attestations.roots.is_empty())
+}
+
+/// A challenge for creating a key of `username` with `ssh-keygen -O challenge=<file>`
+pub fn challenge(username: &str) -> Result<String, String> {
+    let attestations = ATTESTATIONS
+        .get()
+        .filter(|_| enabled())
+        .ok_or_else(|| String::from("Key attestation isn't configured"))?;
+    let issued = OffsetDateTime::now_utc().unix_timestamp();
+    let signature = attestations.mac(username, issued).finalize().into_bytes();
+    Ok(format!(
+        "{CHALLENGE_PREFIX}:{issued}:{}",
+        HEXLOWER.encode(&signature)
+    ))
+}
+
+/// A verified attestation, stored with the key
+pub struct KeyAttestation {
+    /// As written by `ssh-keygen -O write-attestation=<file>`
+    pub data: Vec<u8>,
+    /// Subject of the attestation certificate
+    pub attested_by: String,
+}
+
+/// Verifies the base64 encoded attestation of a key of `username`, created with the
+/// `challenge` ssm issued
+pub fn verify(
+    username: &str,
+    key: &PublicKey,
+    attestation: &str,
+    challenge: &str,
+) -> Result<KeyAttestation, String> {
+    let attestations = ATTESTATIONS
+        .get()
+        .filter(|_| enabled())
+        .ok_or_else(|| String::from("Key attestation isn't configured"))?;
+    attestations.check_challenge(username, challenge)?;
+    let data: String = attestation.split_whitespace().collect();
+    let data = BASE64
+        .decode(data)
+        .map_err(|e| format!("The attestation isn't valid base64: {e}"))?;
+    let parsed = Attestation::parse(&data)?;
+    let challenge = challenge.trim();
+    // Also accept challenge files written with `echo`
+    let attested_by = parsed
+        .verify(key, challenge.as_bytes(), &attestations.roots)
+        .or_else(|e| {
+            parsed
+                .verify(
+                    key,
+                    format!("{challenge}\n").as_bytes(),
+                    &attestations.roots,
+                )
+                .map_err(|_| e)
+        })?;
+    Ok(KeyAttestation { data, attested_by })
+}
@@ -0,0 +1,138 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::{Method, StatusCode},
+    middleware::Next,
+    web::{Bytes, Data},
+    Error, HttpMessage,
+};
+use futures_util::{future::ready, stream};
+use log::error;
+use serde_json::Value;
+
+use crate::{
+    db::BlockingPool,
+    models::AuditEntry,
+    notifications::{authorization_change, Notifier},
+    privacy::{replace_all_mentions, Erased},
+    proxy::Client,
+};
+
+/// Requests that don't change anything, besides the `*_dialog` ones
+const READ_ONLY_POSTS: [&str; 1] = ["/hosts/gen_authorized_keys"];
+
+/// Fields containing these words aren't recorded
+const SECRET_FIELDS: [&str; 3] = ["password", "secret", "token"];
+
+/// Longer values, e.g. whole authorized_keys files, are shortened
+const MAX_VALUE_LENGTH: usize = 100;
+
+/// Who made a request, added to the request extensions by the `AuthMiddleware`
+#[derive(Debug, Clone)]
+pub struct Actor(pub String);
+
+/// Fields of a form or JSON object
+fn body_fields(content_type: &str, body: &[u8]) -> Vec<(String, String)> {
+    match content_type {
+        "application/x-www-form-urlencoded" => {
+            serde_urlencoded::from_bytes(body).unwrap_or_default()
+        }
+        "application/json" => match serde_json::from_slice(body) {
+            Ok(Value::Object(object)) => object
+                .into_iter()
+                .map(|(name, value)| match value {
+                    Value::String(value) => (name, value),
+                    value => (name, value.to_string()),
+                })
+                .collect(),
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+fn describe_target(fields: &[(String, String)]) -> String {
+    fields
+        .iter()
+        .filter(|(name, _)| !SECRET_FIELDS.iter().any(|secret| name.contains(secret)))
+        .map(|(name, value)| {
+            if value.chars().count() > MAX_VALUE_LENGTH {
+                let value: String = value.chars().take(MAX_VALUE_LENGTH).collect();
+                format!("{name}={value}…")
+            } else {
+                format!("{name}={value}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Middleware recording every change in the audit log
+pub async fn record_action(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let is_change = !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    let db = req.app_data::<Data<BlockingPool>>().cloned();
+    // Logins are recorded with the web users
+    let is_recorded = is_change
+        && !req.path().starts_with("/auth/")
+        && !req.path().ends_with("_dialog")
+        && !READ_ONLY_POSTS.contains(&req.path());
+    let (Some(db), true) = (db, is_recorded) else {
+        return next.call(req).await;
+    };
+
+    // The handler still needs the body, so it is put back after reading it
+    let body = req.extract::<Bytes>().await?;
+    let fields = body_fields(req.content_type(), &body);
+    req.set_payload(Payload::Stream {
+        payload: Box::pin(stream::once(ready(Ok(body)))),
+    });
+
+    let res = next.call(req).await?;
+
+    let request = res.request();
+    let actor = request
+        .extensions()
+        .get::<Actor>()
+        .map_or_else(|| String::from("unknown"), |actor| actor.0.clone());
+    let client = request
+        .extensions()
+        .get::<Client>()
+        .and_then(|client| client.addr)
+        .map(|addr| addr.to_string());
+    let mut action = format!("{} {}", request.method(), request.path());
+    let mut target = describe_target(&fields);
+    // The user was erased by the request itself
+    if let Some(erased) = request.extensions().get::<Erased>() {
+        let names: Vec<&str> = erased.names.iter().map(String::as_str).collect();
+        action = replace_all_mentions(&action, &names, &erased.pseudonym).unwrap_or(action);
+        target = replace_all_mentions(&target, &names, &erased.pseudonym).unwrap_or(target);
+    }
+    let status = res.status();
+    // Proposals are answered with 202 and change nothing until approved
+    if status.is_success() && status != StatusCode::ACCEPTED {
+        if let (Some(notifier), Some(change)) = (
+            request.app_data::<Data<Notifier>>(),
+            authorization_change(&action),
+        ) {
+            notifier.authorization_changed(&actor, change, &action, &target);
+        }
+    }
+    let result = if status.is_success() {
+        Ok(status.to_string())
+    } else {
+        Err(status.to_string())
+    };
+
+    if let Err(e) = db
+        .run(move |conn| AuditEntry::record_request(conn, actor, client, action, target, result))
+        .await
+        .map_err(String::from)
+        .and_then(|res| res)
+    {
+        error!("Failed to record audit log entry: {e}");
+    }
+    Ok(res)
+}
@@ -0,0 +1,210 @@
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use log::{error, info, warn};
+
+use super::{AuthBackend, PasswordHasher};
+
+/// Users from an Apache htpasswd file with bcrypt or scrypt hashes
+pub struct HtpasswdBackend {
+    path: PathBuf,
+    hasher: Arc<PasswordHasher>,
+    /// Rehashes of concurrent logins are written one after the other
+    rewrite: Mutex<()>,
+}
+
+/// The hash of a user in the contents of an htpasswd file
+fn find_hash<'a>(password_file: &'a str, username: &str) -> Option<&'a str> {
+    password_file
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| *name == username)
+        .map(|(_, hash)| hash)
+}
+
+/// Reads the accounts of an htpasswd file, for reports
+pub fn read_accounts(path: &Path) -> Result<Vec<(String, String)>, String> {
+    let password_file =
+        fs::read_to_string(path).map_err(|e| format!("Error reading '{}': {e}", path.display()))?;
+    Ok(password_file
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, hash)| (name.to_owned(), hash.to_owned()))
+        .collect())
+}
+
+impl HtpasswdBackend {
+    pub fn new(path: PathBuf, hasher: PasswordHasher) -> Self {
+        Self {
+            path,
+            hasher: Arc::new(hasher),
+            rewrite: Mutex::new(()),
+        }
+    }
+
+    fn read(&self) -> Result<String, String> {
+        // Check if password file exists
+        if !self.path.exists() {
+            error!("Authentication file not found");
+            return Err("Authentication file not found".to_owned());
+        }
+
+        fs::read_to_string(&self.path).map_err(|e| {
+            error!("Error reading authentication file: {e}");
+            "Error reading authentication file".to_owned()
+        })
+    }
+
+    /// Replaces the hash of a user, unless it was changed from `old` in the meantime.
+    /// The file is written next to the old one and moved over it.
+    fn replace_hash(&self, username: &str, old: Option<&str>, new: &str) -> Result<(), String> {
+        let _guard = self.rewrite.lock().expect("htpasswd lock poisoned");
+        let password_file = fs::read_to_string(&self.path).map_err(|e| e.to_string())?;
+        match (find_hash(&password_file, username), old) {
+            (current, Some(old)) if current != Some(old) => return Ok(()),
+            (None, None) => return Err(format!("Unknown user '{username}'")),
+            _ => {}
+        }
+
+        let mut replaced = false;
+        let mut contents = String::with_capacity(password_file.len() + new.len());
+        for line in password_file.lines() {
+            match line.split_once(':') {
+                Some((name, _)) if name == username && !replaced => {
+                    replaced = true;
+                    contents.push_str(&format!("{name}:{new}"));
+                }
+                _ => contents.push_str(line),
+            }
+            contents.push('\n');
+        }
+
+        let permissions = fs::metadata(&self.path)
+            .map_err(|e| e.to_string())?
+            .permissions();
+        self.write(contents, permissions)
+    }
+
+    /// Writes the file next to the old one and moves it over it
+    fn write(&self, contents: String, permissions: fs::Permissions) -> Result<(), String> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, contents).map_err(|e| e.to_string())?;
+        fs::set_permissions(&tmp, permissions).map_err(|e| e.to_string())?;
+        fs::rename(&tmp, &self.path).map_err(|e| e.to_string())
+    }
+
+    /// Adds an account, creating the file readable only by its owner if it doesn't exist
+    pub async fn add_user(&self, username: &str, password: &str) -> Result<(), String> {
+        if username.is_empty() || username.contains([':', '\n', '\r']) {
+            return Err(String::from(
+                "Usernames can't be empty or contain ':' or line breaks",
+            ));
+        }
+        let (hasher, entered) = (Arc::clone(&self.hasher), password.to_owned());
+        let hash = tokio::task::spawn_blocking(move || hasher.hash(&entered))
+            .await
+            .map_err(|e| e.to_string())??;
+
+        let _guard = self.rewrite.lock().expect("htpasswd lock poisoned");
+        let (mut contents, permissions) = match fs::read_to_string(&self.path) {
+            Ok(contents) => (
+                contents,
+                fs::metadata(&self.path)
+                    .map_err(|e| e.to_string())?
+                    .permissions(),
+            ),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                (String::new(), fs::Permissions::from_mode(0o600))
+            }
+            Err(e) => return Err(format!("Error reading '{}': {e}", self.path.display())),
+        };
+        if find_hash(&contents, username).is_some() {
+            return Err(format!("The account '{username}' already exists"));
+        }
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+        contents.push_str(&format!("{username}:{hash}\n"));
+        self.write(contents, permissions)?;
+        info!("Added the account {username}");
+        Ok(())
+    }
+
+    /// Upgrades an outdated hash after a successful login. Failures are only logged.
+    async fn rehash(&self, username: &str, password: &str, old: &str) {
+        let (hasher, password) = (Arc::clone(&self.hasher), password.to_owned());
+        let new = match tokio::task::spawn_blocking(move || hasher.hash(&password)).await {
+            Ok(Ok(new)) => new,
+            Ok(Err(e)) => return warn!("Failed to rehash the password of {username}: {e}"),
+            Err(e) => return warn!("Failed to rehash the password of {username}: {e}"),
+        };
+        match self.replace_hash(username, Some(old), &new) {
+            Ok(()) => info!(
+                "Upgraded the password hash of {username} from {}",
+                PasswordHasher::describe(old)
+            ),
+            Err(e) => warn!("Failed to store the new password hash of {username}: {e}"),
+        }
+    }
+}
+
+#[async_trait]
+impl AuthBackend for HtpasswdBackend {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<bool, String> {
+        let password_file = self.read()?;
+        let Some(hash) = find_hash(&password_file, username).map(str::to_owned) else {
+            return Ok(false);
+        };
+
+        // scrypt takes a lot of memory and time, which shouldn't block other requests
+        let (hasher, entered, stored) =
+            (Arc::clone(&self.hasher), password.to_owned(), hash.clone());
+        let is_valid = match tokio::task::spawn_blocking(move || hasher.verify(&entered, &stored))
+            .await
+            .map_err(|e| e.to_string())?
+        {
+            Ok(valid) => valid,
+            Err(e) => {
+                error!("{e}");
+                false
+            }
+        };
+
+        if is_valid && self.hasher.rehash_on_login() && self.hasher.needs_rehash(&hash) {
+            self.rehash(username, password, &hash).await;
+        }
+        Ok(is_valid)
+    }
+
+    async fn has_user(&self, username: &str) -> Result<bool, String> {
+        Ok(find_hash(&self.read()?, username).is_some())
+    }
+
+    async fn check(&self) -> Result<(), String> {
+        if self.path.exists() {
+            Ok(())
+        } else {
+            Err(format!("'{}' does not exist", self.path.display()))
+        }
+    }
+
+    fn can_set_password(&self) -> bool {
+        true
+    }
+
+    async fn set_password(&self, username: &str, password: &str) -> Result<(), String> {
+        let (hasher, password) = (Arc::clone(&self.hasher), password.to_owned());
+        let hash = tokio::task::spawn_blocking(move || hasher.hash(&password))
+            .await
+            .map_err(|e| e.to_string())??;
+        self.replace_hash(username, None, &hash)?;
+        info!("Changed the password of {username}");
+        Ok(())
+    }
+}
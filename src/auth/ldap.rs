@@ -0,0 +1,186 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use ldap3::{ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use log::{error, info};
+use serde::Deserialize;
+
+use crate::secrets;
+
+use super::AuthBackend;
+
+/// Result code of a bind with a wrong password
+const INVALID_CREDENTIALS: u32 = 49;
+
+fn default_user_filter() -> String {
+    String::from("(uid={username})")
+}
+
+const fn default_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LdapConfig {
+    /// e.g. `ldaps://ldap.example.com`
+    url: String,
+    /// Account used to search for users, binds anonymously if unset
+    #[serde(default)]
+    bind_dn: Option<String>,
+    #[serde(default, deserialize_with = "secrets::deserialize_optional_secret")]
+    bind_password: Option<String>,
+    /// Where to search for users
+    base_dn: String,
+    /// `{username}` is replaced by the escaped username
+    #[serde(default = "default_user_filter")]
+    user_filter: String,
+    /// Only users matching this filter may log in,
+    /// e.g. `(memberOf=cn=ssm,ou=groups,dc=example,dc=com)`
+    #[serde(default)]
+    group_filter: Option<String>,
+    /// Connection timeout in seconds
+    #[serde(
+        default = "default_timeout",
+        deserialize_with = "crate::deserialize_timeout"
+    )]
+    timeout: Duration,
+}
+
+/// Users from an LDAP directory or Active Directory.
+///
+/// Users are searched with the service account, then their password is
+/// verified by binding as the user.
+pub struct LdapBackend {
+    config: LdapConfig,
+}
+
+/// Logs LDAP errors and returns a generic message for the login page
+fn ldap_error(what: &str, e: impl std::fmt::Display) -> String {
+    error!("LDAP {what} failed: {e}");
+    String::from("Error contacting the directory server")
+}
+
+impl LdapBackend {
+    pub const fn new(config: LdapConfig) -> Self {
+        Self { config }
+    }
+
+    /// Connects and binds with the service account
+    async fn connect(&self) -> Result<Ldap, String> {
+        let settings = LdapConnSettings::new().set_conn_timeout(self.config.timeout);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.config.url)
+            .await
+            .map_err(|e| ldap_error("connection", e))?;
+        ldap3::drive!(conn);
+
+        if let Some(bind_dn) = &self.config.bind_dn {
+            ldap.simple_bind(bind_dn, self.config.bind_password.as_deref().unwrap_or(""))
+                .await
+                .and_then(|res| res.success())
+                .map_err(|e| ldap_error("service bind", e))?;
+        }
+        Ok(ldap)
+    }
+
+    /// Returns the DN of the user
+    async fn find_user(&self, ldap: &mut Ldap, username: &str) -> Result<Option<String>, String> {
+        let filter = self
+            .config
+            .user_filter
+            .replace("{username}", &ldap_escape(username));
+        let (entries, _) = ldap
+            .search(&self.config.base_dn, Scope::Subtree, &filter, vec!["1.1"])
+            .await
+            .and_then(|res| res.success())
+            .map_err(|e| ldap_error("user search", e))?;
+
+        match entries.len() {
+            0 => Ok(None),
+            1 => Ok(entries
+                .into_iter()
+                .next()
+                .map(|entry| SearchEntry::construct(entry).dn)),
+            n => Err(ldap_error(
+                "user search",
+                format!("{n} entries match '{username}', check user_filter"),
+            )),
+        }
+    }
+
+    async fn matches_group_filter(&self, ldap: &mut Ldap, dn: &str) -> Result<bool, String> {
+        let Some(group_filter) = &self.config.group_filter else {
+            return Ok(true);
+        };
+        let (entries, _) = ldap
+            .search(dn, Scope::Base, group_filter, vec!["1.1"])
+            .await
+            .and_then(|res| res.success())
+            .map_err(|e| ldap_error("group search", e))?;
+
+        Ok(!entries.is_empty())
+    }
+
+    async fn verify(
+        &self,
+        ldap: &mut Ldap,
+        username: &str,
+        password: &str,
+    ) -> Result<bool, String> {
+        let Some(dn) = self.find_user(ldap, username).await? else {
+            return Ok(false);
+        };
+        if !self.matches_group_filter(ldap, &dn).await? {
+            info!("LDAP user '{username}' doesn't match the group filter");
+            return Ok(false);
+        }
+
+        let res = ldap
+            .simple_bind(&dn, password)
+            .await
+            .map_err(|e| ldap_error("user bind", e))?;
+        match res.rc {
+            0 => Ok(true),
+            INVALID_CREDENTIALS => Ok(false),
+            _ => Err(ldap_error("user bind", res)),
+        }
+    }
+}
+
+#[async_trait]
+impl AuthBackend for LdapBackend {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<bool, String> {
+        // An empty password would be an unauthenticated bind, which succeeds
+        if password.is_empty() {
+            return Ok(false);
+        }
+
+        let mut ldap = self.connect().await?;
+        let res = self.verify(&mut ldap, username, password).await;
+        let _ = ldap.unbind().await;
+        res
+    }
+
+    async fn has_user(&self, username: &str) -> Result<bool, String> {
+        let mut ldap = self.connect().await?;
+        let res = self.find_user(&mut ldap, username).await;
+        let _ = ldap.unbind().await;
+        res.map(|dn| dn.is_some())
+    }
+
+    async fn check(&self) -> Result<(), String> {
+        let mut ldap = self
+            .connect()
+            .await
+            .map_err(|_| format!("Can't connect or bind to '{}', see log", self.config.url))?;
+        let _ = ldap.unbind().await;
+        Ok(())
+    }
+
+    fn can_set_password(&self) -> bool {
+        false
+    }
+
+    async fn set_password(&self, _username: &str, _password: &str) -> Result<(), String> {
+        Err(String::from("Passwords are managed in the LDAP directory"))
+    }
+}
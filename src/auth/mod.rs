@@ -0,0 +1,59 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::Configuration;
+
+mod htpasswd;
+mod ldap;
+mod password;
+mod role;
+
+pub use htpasswd::{read_accounts, HtpasswdBackend};
+pub use ldap::{LdapBackend, LdapConfig};
+pub use password::{check_new_password, PasswordHasher, PasswordHashingConfig};
+pub use role::Role;
+
+/// Verifies the credentials entered on the login page
+#[async_trait]
+pub trait AuthBackend: Send + Sync {
+    /// Whether the password is valid for this user.
+    /// Errors are shown on the login page.
+    async fn authenticate(&self, username: &str, password: &str) -> Result<bool, String>;
+
+    /// Whether the user exists, so single sign-on can't take over the name
+    async fn has_user(&self, username: &str) -> Result<bool, String>;
+
+    /// Checks that the backend is usable, for `ssm check`
+    async fn check(&self) -> Result<(), String>;
+
+    /// Whether passwords can be changed through ssm
+    fn can_set_password(&self) -> bool;
+
+    /// Replaces the password of an existing user
+    async fn set_password(&self, username: &str, password: &str) -> Result<(), String>;
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthBackendKind {
+    #[default]
+    Htpasswd,
+    Ldap,
+}
+
+/// Creates the backend selected by `auth_backend`
+pub fn from_config(configuration: &Configuration) -> Result<Arc<dyn AuthBackend>, String> {
+    Ok(match configuration.auth_backend {
+        AuthBackendKind::Htpasswd => Arc::new(HtpasswdBackend::new(
+            configuration.htpasswd_path.clone(),
+            PasswordHasher::new(configuration.password_hashing.clone())?,
+        )),
+        AuthBackendKind::Ldap => {
+            Arc::new(LdapBackend::new(configuration.ldap.clone().ok_or_else(
+                || String::from("auth_backend is 'ldap', but the [ldap] section is missing"),
+            )?))
+        }
+    })
+}
@@ -0,0 +1,385 @@
+use base64::{engine::general_purpose::STANDARD_NO_PAD as BASE64, Engine};
+use serde::Deserialize;
+
+const fn default_argon2_memory_kib() -> u32 {
+    19 * 1024
+}
+
+const fn default_argon2_iterations() -> u32 {
+    2
+}
+
+const fn default_argon2_parallelism() -> u32 {
+    1
+}
+
+const fn default_scrypt_log_n() -> u8 {
+    scrypt::Params::RECOMMENDED_LOG_N
+}
+
+const fn default_scrypt_r() -> u32 {
+    scrypt::Params::RECOMMENDED_R
+}
+
+const fn default_scrypt_p() -> u32 {
+    scrypt::Params::RECOMMENDED_P
+}
+
+const fn default_bcrypt_cost() -> u32 {
+    12
+}
+
+const fn default_rehash_on_login() -> bool {
+    true
+}
+
+const SALT_LEN: usize = 16;
+const ARGON2_HASH_LEN: usize = 32;
+
+/// Shorter passwords are refused when changing them through ssm
+const MIN_PASSWORD_LENGTH: usize = 10;
+
+/// Checks a password a user chose, and that it was entered the same twice
+pub fn check_new_password(password: &str, repeated: &str) -> Result<(), String> {
+    if password != repeated {
+        return Err(String::from("The passwords don't match"));
+    }
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(format!(
+            "The password has to be at least {MIN_PASSWORD_LENGTH} characters long"
+        ));
+    }
+    Ok(())
+}
+
+/// How new password hashes are made
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PasswordScheme {
+    /// `$argon2id$v=19$m=..,t=..,p=..$salt$hash`, memory-hard, the current recommendation
+    #[default]
+    Argon2id,
+    /// `$scrypt$ln=..,r=..,p=..$salt$hash`, memory-hard
+    Scrypt,
+    /// `$2b$cost$...`, readable by Apache and the `htpasswd` tool
+    Bcrypt,
+}
+
+/// The scheme and parameters of local account passwords
+#[derive(Debug, Clone, Deserialize)]
+pub struct PasswordHashingConfig {
+    #[serde(default)]
+    scheme: PasswordScheme,
+    /// Memory cost in KiB
+    #[serde(default = "default_argon2_memory_kib")]
+    argon2_memory_kib: u32,
+    /// Time cost, passes over the memory
+    #[serde(default = "default_argon2_iterations")]
+    argon2_iterations: u32,
+    #[serde(default = "default_argon2_parallelism")]
+    argon2_parallelism: u32,
+    /// Memory and time cost as a power of two
+    #[serde(default = "default_scrypt_log_n")]
+    scrypt_log_n: u8,
+    #[serde(default = "default_scrypt_r")]
+    scrypt_r: u32,
+    #[serde(default = "default_scrypt_p")]
+    scrypt_p: u32,
+    #[serde(default = "default_bcrypt_cost")]
+    bcrypt_cost: u32,
+    /// Replaces outdated hashes in the htpasswd file when their user logs in
+    #[serde(default = "default_rehash_on_login")]
+    rehash_on_login: bool,
+}
+
+impl Default for PasswordHashingConfig {
+    fn default() -> Self {
+        Self {
+            scheme: PasswordScheme::default(),
+            argon2_memory_kib: default_argon2_memory_kib(),
+            argon2_iterations: default_argon2_iterations(),
+            argon2_parallelism: default_argon2_parallelism(),
+            scrypt_log_n: default_scrypt_log_n(),
+            scrypt_r: default_scrypt_r(),
+            scrypt_p: default_scrypt_p(),
+            bcrypt_cost: default_bcrypt_cost(),
+            rehash_on_login: default_rehash_on_login(),
+        }
+    }
+}
+
+/// A stored hash, split into its scheme and parameters
+enum StoredHash {
+    Argon2id {
+        params: argon2::Params,
+        salt: Vec<u8>,
+        hash: Vec<u8>,
+    },
+    Bcrypt {
+        cost: u32,
+        /// `$2y$` of Apache is the same as `$2b$`
+        hash: String,
+    },
+    Scrypt {
+        params: scrypt::Params,
+        salt: Vec<u8>,
+        hash: Vec<u8>,
+    },
+}
+
+impl StoredHash {
+    fn parse(hash: &str) -> Result<Self, String> {
+        if let Some(rest) = hash
+            .strip_prefix("$2y$")
+            .or_else(|| hash.strip_prefix("$2b$"))
+        {
+            let cost = rest
+                .split('$')
+                .next()
+                .and_then(|cost| cost.parse().ok())
+                .ok_or_else(|| String::from("Invalid bcrypt hash"))?;
+            return Ok(Self::Bcrypt {
+                cost,
+                hash: format!("$2b${rest}"),
+            });
+        }
+
+        if let Some(rest) = hash.strip_prefix("$argon2id$") {
+            let invalid = || String::from("Invalid Argon2id hash");
+            let mut parts = rest.split('$');
+            let (Some("v=19"), Some(params), Some(salt), Some(hash), None) = (
+                parts.next(),
+                parts.next(),
+                parts.next(),
+                parts.next(),
+                parts.next(),
+            ) else {
+                return Err(invalid());
+            };
+            let (mut m, mut t, mut p) = (None, None, None);
+            for param in params.split(',') {
+                match param.split_once('=') {
+                    Some(("m", value)) => m = value.parse().ok(),
+                    Some(("t", value)) => t = value.parse().ok(),
+                    Some(("p", value)) => p = value.parse().ok(),
+                    _ => return Err(invalid()),
+                }
+            }
+            let hash = BASE64.decode(hash).map_err(|_| invalid())?;
+            let params = argon2::Params::new(
+                m.ok_or_else(invalid)?,
+                t.ok_or_else(invalid)?,
+                p.ok_or_else(invalid)?,
+                Some(hash.len()),
+            )
+            .map_err(|_| invalid())?;
+            return Ok(Self::Argon2id {
+                params,
+                salt: BASE64.decode(salt).map_err(|_| invalid())?,
+                hash,
+            });
+        }
+
+        if let Some(rest) = hash.strip_prefix("$scrypt$") {
+            let invalid = || String::from("Invalid scrypt hash");
+            let mut parts = rest.split('$');
+            let (Some(params), Some(salt), Some(hash), None) =
+                (parts.next(), parts.next(), parts.next(), parts.next())
+            else {
+                return Err(invalid());
+            };
+            let (mut log_n, mut r, mut p) = (None, None, None);
+            for param in params.split(',') {
+                match param.split_once('=') {
+                    Some(("ln", value)) => log_n = value.parse().ok(),
+                    Some(("r", value)) => r = value.parse().ok(),
+                    Some(("p", value)) => p = value.parse().ok(),
+                    _ => return Err(invalid()),
+                }
+            }
+            let hash = BASE64.decode(hash).map_err(|_| invalid())?;
+            let params = scrypt::Params::new(
+                log_n.ok_or_else(invalid)?,
+                r.ok_or_else(invalid)?,
+                p.ok_or_else(invalid)?,
+                hash.len(),
+            )
+            .map_err(|_| invalid())?;
+            return Ok(Self::Scrypt {
+                params,
+                salt: BASE64.decode(salt).map_err(|_| invalid())?,
+                hash,
+            });
+        }
+
+        Err(format!(
+            "Unsupported hash type '{}'",
+            hash.get(..4).unwrap_or(hash)
+        ))
+    }
+}
+
+/// Compares without returning early, so the time taken says nothing about the hash
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn argon2_hash(password: &str, salt: &[u8], params: argon2::Params) -> Result<Vec<u8>, String> {
+    let mut hash = vec![0; params.output_len().unwrap_or(ARGON2_HASH_LEN)];
+    argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+        .hash_password_into(password.as_bytes(), salt, &mut hash)
+        .map_err(|e| e.to_string())?;
+    Ok(hash)
+}
+
+fn scrypt_hash(
+    password: &str,
+    salt: &[u8],
+    params: &scrypt::Params,
+    len: usize,
+) -> Result<Vec<u8>, String> {
+    let mut hash = vec![0; len];
+    scrypt::scrypt(password.as_bytes(), salt, params, &mut hash).map_err(|e| e.to_string())?;
+    Ok(hash)
+}
+
+/// Hashes and verifies passwords of local accounts. Hashes carry their scheme and
+/// parameters, so older ones keep working and can be upgraded one login at a time.
+#[derive(Debug, Clone)]
+pub struct PasswordHasher {
+    config: PasswordHashingConfig,
+    argon2_params: argon2::Params,
+    scrypt_params: scrypt::Params,
+}
+
+impl PasswordHasher {
+    pub fn new(config: PasswordHashingConfig) -> Result<Self, String> {
+        let argon2_params = argon2::Params::new(
+            config.argon2_memory_kib,
+            config.argon2_iterations,
+            config.argon2_parallelism,
+            Some(ARGON2_HASH_LEN),
+        )
+        .map_err(|e| format!("Invalid Argon2 parameters: {e}"))?;
+        let scrypt_params = scrypt::Params::new(
+            config.scrypt_log_n,
+            config.scrypt_r,
+            config.scrypt_p,
+            scrypt::Params::RECOMMENDED_LEN,
+        )
+        .map_err(|e| format!("Invalid scrypt parameters: {e}"))?;
+        if !(4..=31).contains(&config.bcrypt_cost) {
+            return Err(String::from("bcrypt_cost has to be between 4 and 31"));
+        }
+        Ok(Self {
+            config,
+            argon2_params,
+            scrypt_params,
+        })
+    }
+
+    pub const fn rehash_on_login(&self) -> bool {
+        self.config.rehash_on_login
+    }
+
+    /// Hashes with the configured scheme and parameters
+    pub fn hash(&self, password: &str) -> Result<String, String> {
+        match self.config.scheme {
+            PasswordScheme::Argon2id => {
+                let salt: [u8; SALT_LEN] = rand::random();
+                let hash = argon2_hash(password, &salt, self.argon2_params.clone())?;
+                Ok(format!(
+                    "$argon2id$v=19$m={},t={},p={}${}${}",
+                    self.argon2_params.m_cost(),
+                    self.argon2_params.t_cost(),
+                    self.argon2_params.p_cost(),
+                    BASE64.encode(salt),
+                    BASE64.encode(hash)
+                ))
+            }
+            PasswordScheme::Bcrypt => {
+                bcrypt::hash(password, self.config.bcrypt_cost).map_err(|e| e.to_string())
+            }
+            PasswordScheme::Scrypt => {
+                let salt: [u8; SALT_LEN] = rand::random();
+                let hash = scrypt_hash(
+                    password,
+                    &salt,
+                    &self.scrypt_params,
+                    scrypt::Params::RECOMMENDED_LEN,
+                )?;
+                Ok(format!(
+                    "$scrypt$ln={},r={},p={}${}${}",
+                    self.scrypt_params.log_n(),
+                    self.scrypt_params.r(),
+                    self.scrypt_params.p(),
+                    BASE64.encode(salt),
+                    BASE64.encode(hash)
+                ))
+            }
+        }
+    }
+
+    /// Whether the password matches a hash of any supported scheme
+    pub fn verify(&self, password: &str, hash: &str) -> Result<bool, String> {
+        match StoredHash::parse(hash)? {
+            StoredHash::Argon2id { params, salt, hash } => Ok(constant_time_eq(
+                &argon2_hash(password, &salt, params)?,
+                &hash,
+            )),
+            StoredHash::Bcrypt { hash, .. } => {
+                bcrypt::verify(password, &hash).map_err(|e| e.to_string())
+            }
+            StoredHash::Scrypt { params, salt, hash } => Ok(constant_time_eq(
+                &scrypt_hash(password, &salt, &params, hash.len())?,
+                &hash,
+            )),
+        }
+    }
+
+    /// Whether the hash was made with another scheme or other parameters than configured
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        match (StoredHash::parse(hash), self.config.scheme) {
+            (Ok(StoredHash::Argon2id { params, .. }), PasswordScheme::Argon2id) => {
+                (params.m_cost(), params.t_cost(), params.p_cost())
+                    != (
+                        self.argon2_params.m_cost(),
+                        self.argon2_params.t_cost(),
+                        self.argon2_params.p_cost(),
+                    )
+            }
+            (Ok(StoredHash::Bcrypt { cost, .. }), PasswordScheme::Bcrypt) => {
+                cost != self.config.bcrypt_cost
+            }
+            (Ok(StoredHash::Scrypt { params, .. }), PasswordScheme::Scrypt) => {
+                (params.log_n(), params.r(), params.p())
+                    != (
+                        self.scrypt_params.log_n(),
+                        self.scrypt_params.r(),
+                        self.scrypt_params.p(),
+                    )
+            }
+            _ => true,
+        }
+    }
+
+    /// Scheme and parameters of a hash, for reports
+    pub fn describe(hash: &str) -> String {
+        match StoredHash::parse(hash) {
+            Ok(StoredHash::Argon2id { params, .. }) => format!(
+                "Argon2id, m={} t={} p={}",
+                params.m_cost(),
+                params.t_cost(),
+                params.p_cost()
+            ),
+            Ok(StoredHash::Bcrypt { cost, .. }) => format!("bcrypt, cost {cost}"),
+            Ok(StoredHash::Scrypt { params, .. }) => format!(
+                "scrypt, ln={} r={} p={}",
+                params.log_n(),
+                params.r(),
+                params.p()
+            ),
+            Err(e) => e,
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use std::{fmt, str::FromStr};
+
+use actix_web::{dev::ServiceRequest, http::Method};
+use serde::Deserialize;
+
+/// Only admins may open these sections
+const ADMIN_SCOPES: [&str; 6] = [
+    "/tokens",
+    "/perf",
+    "/logs",
+    "/web_users",
+    "/audit",
+    "/admin",
+];
+
+/// Every web user may request access and manage their own account here
+const SELF_SERVICE_SCOPES: [&str; 2] = ["/portal", "/account"];
+
+/// What a web user may do, each role includes the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Browse hosts, users, keys and diffs
+    Viewer,
+    /// Additionally change hosts, users, keys and authorizations
+    Operator,
+    /// Additionally manage API tokens and web users and read the audit log and logs
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Self; 3] = [Self::Viewer, Self::Operator, Self::Admin];
+
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Viewer => "viewer",
+            Self::Operator => "operator",
+            Self::Admin => "admin",
+        }
+    }
+
+    /// The lowest role allowed to make a request, judged by the path the router
+    /// matches. It is percent-decoded, so `/%61dmin` is `/admin` here as well.
+    pub fn required_for_request(request: &ServiceRequest) -> Self {
+        Self::required_for(request.method(), request.match_info().as_str())
+    }
+
+    /// The lowest role allowed to make a request to a decoded path
+    pub fn required_for(method: &Method, path: &str) -> Self {
+        let in_scope = |scope: &str| {
+            path.strip_prefix(scope)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        };
+        // Opening the self-service portal of a user is impersonating them
+        let is_portal = path.starts_with("/users/") && path.ends_with("/portal");
+        // The export of a user contains their audit log entries
+        let is_export = path.starts_with("/users/") && path.ends_with("/export.json");
+        // Everyone sees proposed authorizations, only admins decide them
+        let is_approval = *method == Method::POST && in_scope("/approvals");
+
+        if ADMIN_SCOPES.into_iter().any(in_scope) || is_portal || is_export || is_approval {
+            Self::Admin
+        } else if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+            || SELF_SERVICE_SCOPES.into_iter().any(in_scope)
+        {
+            Self::Viewer
+        } else {
+            Self::Operator
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|role| role.as_str() == s)
+            .ok_or_else(|| format!("Unknown role '{s}'"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn required(method: Method, uri: &str) -> Role {
+        Role::required_for_request(&TestRequest::with_uri(uri).method(method).to_srv_request())
+    }
+
+    #[test]
+    fn role_matrix() {
+        let cases = [
+            (Method::GET, "/hosts", Role::Viewer),
+            (Method::HEAD, "/users/alice", Role::Viewer),
+            (Method::POST, "/hosts/add", Role::Operator),
+            (Method::DELETE, "/api/v1/hosts/a", Role::Operator),
+            (Method::POST, "/portal/request", Role::Viewer),
+            (Method::POST, "/account/password", Role::Viewer),
+            (Method::GET, "/tokens", Role::Admin),
+            (Method::GET, "/admin/backup", Role::Admin),
+            (Method::GET, "/logs", Role::Admin),
+            (Method::GET, "/audit/export", Role::Admin),
+            (Method::GET, "/users/alice/portal", Role::Admin),
+            (Method::GET, "/users/alice/export.json", Role::Admin),
+            (Method::GET, "/approvals", Role::Viewer),
+            (Method::POST, "/approvals/1/approve", Role::Admin),
+            // Only whole segments are scopes
+            (Method::GET, "/tokensfoo", Role::Viewer),
+        ];
+        for (method, uri, role) in cases {
+            assert_eq!(required(method.clone(), uri), role, "{method} {uri}");
+        }
+    }
+
+    #[test]
+    fn percent_encoded_paths() {
+        let cases = [
+            (Method::GET, "/%61dmin/backup", Role::Admin),
+            (Method::GET, "/%74okens", Role::Admin),
+            (Method::GET, "/%6cogs", Role::Admin),
+            (Method::GET, "/web%5Fusers", Role::Admin),
+            (Method::GET, "/users/alice/%70ortal", Role::Admin),
+            (Method::POST, "/%61pprovals/1/approve", Role::Admin),
+            (Method::POST, "/%68osts/add", Role::Operator),
+        ];
+        for (method, uri, role) in cases {
+            assert_eq!(required(method.clone(), uri), role, "{method} {uri}");
+        }
+    }
+}
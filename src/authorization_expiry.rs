@@ -0,0 +1,127 @@
+use std::time::Duration;
+
+use log::{error, info};
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    db::{BlockingPool, ExpiredAuthorization},
+    models::{AuditEntry, Host, User},
+    notifications::Notifier,
+    removal_queue::RemovalQueue,
+};
+
+/// Drops authorizations once they expired and removes the keys they granted from the hosts
+#[derive(Clone)]
+pub struct AuthorizationExpirer {
+    db: BlockingPool,
+    removals: RemovalQueue,
+    notifier: Notifier,
+}
+
+impl AuthorizationExpirer {
+    pub fn new(db: BlockingPool, removals: RemovalQueue, notifier: Notifier) -> Self {
+        Self {
+            db,
+            removals,
+            notifier,
+        }
+    }
+
+    /// Expires authorizations now and then every `interval`
+    pub fn start(&self, interval: Duration) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                if let Err(e) = this.run().await {
+                    error!("Failed to expire authorizations: {e}");
+                }
+            }
+        });
+    }
+
+    /// Deletes every expired authorization and removes the keys of its user from the login,
+    /// unless they are still authorized otherwise. Failed removals are queued.
+    /// Returns the number of expired authorizations.
+    pub async fn run(&self) -> Result<usize, String> {
+        let expired = self.db.run(Host::delete_expired_authorizations).await??;
+
+        for authorization in &expired {
+            let res = self.remove_keys(authorization).await;
+            let ExpiredAuthorization {
+                host,
+                username,
+                login,
+                ..
+            } = authorization;
+            let (actor, action, target) = (
+                String::from("scheduler"),
+                String::from("expire authorization"),
+                format!("host={}, login={login}, user={username}", host.name),
+            );
+            self.notifier
+                .authorization_changed(&actor, "removed", &action, &target);
+            let result = res.map(|removed| format!("Removed {removed} key(s)"));
+            if let Err(e) = self
+                .db
+                .run(move |conn| AuditEntry::record(conn, actor, action, target, result))
+                .await
+                .map_err(String::from)
+                .and_then(|res| res)
+            {
+                error!("Failed to record an expired authorization: {e}");
+            }
+        }
+
+        if !expired.is_empty() {
+            info!("Expired {} authorization(s)", expired.len());
+        }
+        Ok(expired.len())
+    }
+
+    /// Removes the keys that are no longer authorized for the login. Returns how many.
+    async fn remove_keys(&self, authorization: &ExpiredAuthorization) -> Result<usize, String> {
+        let (host, user_id, login) = (
+            authorization.host.clone(),
+            authorization.user_id,
+            authorization.login.clone(),
+        );
+        let keys = self
+            .db
+            .run(move |conn| {
+                let still_authorized: Vec<i32> = host
+                    .get_authorized_keys(conn)?
+                    .into_iter()
+                    .filter(|allowed| allowed.login == login)
+                    .map(|allowed| allowed.key.id)
+                    .collect();
+                let Some(user) = User::get_by_id(conn, user_id)? else {
+                    return Ok(Vec::new());
+                };
+                Ok::<_, String>(
+                    user.get_keys(conn)?
+                        .into_iter()
+                        .filter(|key| !still_authorized.contains(&key.id))
+                        .map(|key| key.key_base64)
+                        .collect(),
+                )
+            })
+            .await??;
+        if keys.is_empty() {
+            return Ok(0);
+        }
+
+        self.removals
+            .remove(
+                &authorization.host,
+                &authorization.login,
+                &keys,
+                &format!("expiry of the authorization of {}", authorization.username),
+            )
+            .await
+            .map(|()| keys.len())
+            .map_err(|e| format!("{e}, retrying later"))
+    }
+}
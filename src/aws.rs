@@ -0,0 +1,115 @@
+use data_encoding::HEXLOWER;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+/// Percent-encodes like AWS Signature Version 4 expects, keeping `/` in paths
+pub fn uri_encode(value: &str, keep_slash: bool) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                char::from(b).to_string()
+            }
+            b'/' if keep_slash => String::from("/"),
+            b => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Access key of an AWS account, or of a compatible service
+pub struct AwsCredentials<'a> {
+    pub access_key_id: &'a str,
+    pub secret_access_key: &'a str,
+}
+
+/// A request to sign, its path already percent-encoded
+pub struct AwsRequest<'a> {
+    pub service: &'a str,
+    pub region: &'a str,
+    pub method: &'a str,
+    /// With the port, if not the default one
+    pub host: &'a str,
+    pub path: &'a str,
+    pub query: &'a [(&'a str, &'a str)],
+    pub body: &'a [u8],
+}
+
+/// What to send along with a signed request
+pub struct SignedRequest {
+    /// The sorted and encoded query string the signature covers
+    pub query: String,
+    pub authorization: String,
+    /// For the `x-amz-date` header
+    pub timestamp: String,
+    /// For the `x-amz-content-sha256` header
+    pub payload_hash: String,
+}
+
+impl AwsRequest<'_> {
+    /// Signs with AWS Signature Version 4
+    pub fn sign(&self, credentials: &AwsCredentials) -> SignedRequest {
+        let mut query: Vec<(String, String)> = self
+            .query
+            .iter()
+            .map(|(name, value)| (uri_encode(name, false), uri_encode(value, false)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let now = OffsetDateTime::now_utc();
+        let date = format!(
+            "{:04}{:02}{:02}",
+            now.year(),
+            u8::from(now.month()),
+            now.day()
+        );
+        let timestamp = format!(
+            "{date}T{:02}{:02}{:02}Z",
+            now.hour(),
+            now.minute(),
+            now.second()
+        );
+        let payload_hash = HEXLOWER.encode(&Sha256::digest(self.body));
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n{query}\nhost:{}\nx-amz-content-sha256:{payload_hash}\n\
+            x-amz-date:{timestamp}\n\n{signed_headers}\n{payload_hash}",
+            self.method, self.path, self.host
+        );
+        let scope = format!("{date}/{}/{}/aws4_request", self.region, self.service);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+            HEXLOWER.encode(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let signing_key = [self.region, self.service, "aws4_request"].iter().fold(
+            hmac_sha256(
+                format!("AWS4{}", credentials.secret_access_key).as_bytes(),
+                &date,
+            ),
+            |key, part| hmac_sha256(&key, part),
+        );
+        let signature = HEXLOWER.encode(&hmac_sha256(&signing_key, &string_to_sign));
+
+        SignedRequest {
+            query,
+            authorization: format!(
+                "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, \
+                Signature={signature}",
+                credentials.access_key_id
+            ),
+            timestamp,
+            payload_hash,
+        }
+    }
+}
@@ -0,0 +1,448 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+use data_encoding::HEXLOWER;
+use diesel::{sql_query, sql_types::Text, QueryableByName, RunQueryDsl};
+use log::{error, info};
+use openidconnect::reqwest;
+use serde::Deserialize;
+use time::OffsetDateTime;
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    aws::{uri_encode, AwsCredentials, AwsRequest},
+    db::BlockingPool,
+    secrets, DbConnection,
+};
+
+/// Snapshots are named `ssm-<timestamp>.db`, or `.db.enc` if encrypted
+const SNAPSHOT_PREFIX: &str = "ssm-";
+/// Start of encrypted snapshots, followed by the salt, the nonce and the ciphertext
+const ENCRYPTED_MAGIC: &[u8; 8] = b"SSMBAK1\0";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const SQLITE_MAGIC: &[u8; 16] = b"SQLite format 3\0";
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(300);
+
+const fn default_interval() -> Option<Duration> {
+    Some(Duration::from_secs(86400))
+}
+
+const fn default_keep() -> usize {
+    7
+}
+
+/// A bucket of S3 or a compatible store like MinIO, addressed path-style
+#[derive(Debug, Clone, Deserialize)]
+pub struct S3Config {
+    /// e.g. `https://s3.eu-central-1.amazonaws.com`
+    endpoint: String,
+    region: String,
+    bucket: String,
+    /// Put in front of the snapshot names, e.g. `ssm/`
+    #[serde(default)]
+    prefix: String,
+    access_key_id: String,
+    #[serde(deserialize_with = "secrets::deserialize_secret")]
+    secret_access_key: String,
+}
+
+/// Scheduled snapshots of the SQLite database
+#[derive(Debug, Clone, Deserialize)]
+pub struct BackupConfig {
+    /// Seconds between snapshots, starting at startup (default 86400, 0 disables)
+    #[serde(
+        default = "default_interval",
+        deserialize_with = "crate::deserialize_interval"
+    )]
+    interval: Option<Duration>,
+    /// Directory the snapshots are written to
+    #[serde(default)]
+    path: Option<PathBuf>,
+    #[serde(default)]
+    s3: Option<S3Config>,
+    /// Snapshots kept in each place, older ones are deleted
+    #[serde(default = "default_keep")]
+    keep: usize,
+    /// Passphrase snapshots are encrypted with using AES-256-GCM
+    #[serde(default, deserialize_with = "secrets::deserialize_optional_secret")]
+    encryption_key: Option<String>,
+}
+
+impl BackupConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.path.is_none() && self.s3.is_none() {
+            return Err(String::from(
+                "[backup] needs a path or an [backup.s3] bucket",
+            ));
+        }
+        if self.keep == 0 {
+            return Err(String::from("The keep of [backup] can't be 0"));
+        }
+        Ok(())
+    }
+
+    pub const fn is_encrypted(&self) -> bool {
+        self.encryption_key.is_some()
+    }
+
+    /// Decrypts the snapshot if it is encrypted, with the configured passphrase
+    pub fn open_snapshot(config: Option<&Self>, data: Vec<u8>) -> Result<Vec<u8>, String> {
+        if !data.starts_with(ENCRYPTED_MAGIC) {
+            return Ok(data);
+        }
+        let passphrase = config
+            .and_then(|config| config.encryption_key.as_deref())
+            .ok_or_else(|| {
+                String::from("The snapshot is encrypted, but [backup] has no encryption_key")
+            })?;
+        decrypt(passphrase, &data)
+    }
+}
+
+/// Name of a new snapshot, e.g. `ssm-20261015T190000Z.db`
+fn snapshot_name(encrypted: bool) -> String {
+    let now = OffsetDateTime::now_utc();
+    format!(
+        "{SNAPSHOT_PREFIX}{:04}{:02}{:02}T{:02}{:02}{:02}Z.db{}",
+        now.year(),
+        u8::from(now.month()),
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second(),
+        if encrypted { ".enc" } else { "" }
+    )
+}
+
+fn is_snapshot(name: &str) -> bool {
+    name.starts_with(SNAPSHOT_PREFIX) && (name.ends_with(".db") || name.ends_with(".db.enc"))
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<[u8; 32], String> {
+    let params = scrypt::Params::new(15, 8, 1, 32).map_err(|e| e.to_string())?;
+    let mut key = [0; 32];
+    scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut key).map_err(|e| e.to_string())?;
+    Ok(key)
+}
+
+fn encrypt(passphrase: &str, data: &[u8]) -> Result<Vec<u8>, String> {
+    let salt: [u8; SALT_LEN] = rand::random();
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    let cipher =
+        Aes256Gcm::new_from_slice(&derive_key(passphrase, &salt)?).map_err(|e| e.to_string())?;
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), data)
+        .map_err(|_| String::from("Failed to encrypt the snapshot"))?;
+    Ok([ENCRYPTED_MAGIC.as_slice(), &salt, &nonce, &ciphertext].concat())
+}
+
+fn decrypt(passphrase: &str, data: &[u8]) -> Result<Vec<u8>, String> {
+    let header = ENCRYPTED_MAGIC.len() + SALT_LEN + NONCE_LEN;
+    if data.len() < header {
+        return Err(String::from("The snapshot is truncated"));
+    }
+    let (salt, rest) = data[ENCRYPTED_MAGIC.len()..].split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let cipher =
+        Aes256Gcm::new_from_slice(&derive_key(passphrase, salt)?).map_err(|e| e.to_string())?;
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| String::from("Failed to decrypt the snapshot, is the encryption_key right?"))
+}
+
+#[derive(QueryableByName)]
+pub struct DatabaseFile {
+    #[diesel(sql_type = Text)]
+    pub name: String,
+    #[diesel(sql_type = Text)]
+    pub file: String,
+}
+
+/// Whether the data is an SQLite database
+pub fn is_sqlite(data: &[u8]) -> bool {
+    data.starts_with(SQLITE_MAGIC)
+}
+
+/// The directory of the database file
+fn database_dir(conn: &mut DbConnection) -> Result<PathBuf, String> {
+    sql_query("PRAGMA database_list")
+        .load::<DatabaseFile>(conn)
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .find(|database| database.name == "main" && !database.file.is_empty())
+        .and_then(|database| Path::new(&database.file).parent().map(Path::to_path_buf))
+        .ok_or_else(|| String::from("The database isn't stored in a file"))
+}
+
+/// Creates a directory only ssm can open in `parent`. Its name is random and creating it
+/// fails if it exists, so nobody can prepare it beforehand.
+fn private_dir(parent: &Path) -> Result<PathBuf, String> {
+    let suffix = HEXLOWER.encode(&rand::random::<[u8; 16]>());
+    let dir = parent.join(format!(".ssm-snapshot-{suffix}"));
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder
+        .create(&dir)
+        .map_err(|e| format!("Failed to create {}: {e}", dir.display()))?;
+    Ok(dir)
+}
+
+/// Writes a consistent copy of the database while it stays in use. The copy is put into a
+/// private directory in `dir`, or next to the database, since it isn't encrypted yet.
+/// Returns the private directory, which holds the copy as `name`.
+fn snapshot(conn: &mut DbConnection, dir: Option<&Path>, name: &str) -> Result<PathBuf, String> {
+    #[allow(irrefutable_let_patterns)]
+    let DbConnection::Sqlite(_) = conn
+    else {
+        return Err(String::from("Backups are only supported for SQLite"));
+    };
+    let parent = match dir {
+        Some(dir) => dir.to_path_buf(),
+        None => database_dir(conn)?,
+    };
+    let private = private_dir(&parent)?;
+    let target = private.join(name);
+    let res = target
+        .to_str()
+        .ok_or_else(|| format!("Invalid backup path {target:?}"))
+        .and_then(|target| {
+            sql_query(format!("VACUUM INTO '{}'", target.replace('\'', "''")))
+                .execute(conn)
+                .map_err(|e| format!("Failed to snapshot the database: {e}"))
+        });
+    match res {
+        Ok(_) => Ok(private),
+        Err(e) => {
+            let _ = fs::remove_dir_all(&private);
+            Err(e)
+        }
+    }
+}
+
+/// Takes a snapshot through a private directory in `dir`, or next to the database. Returns
+/// its name and content, encrypted with the passphrase if given.
+async fn take_snapshot(
+    db: &BlockingPool,
+    dir: Option<PathBuf>,
+    encryption_key: Option<String>,
+) -> Result<(String, Vec<u8>), String> {
+    let name = snapshot_name(encryption_key.is_some());
+
+    let target = name.clone();
+    let private = db
+        .run(move |conn| snapshot(conn, dir.as_deref(), &target))
+        .await??;
+
+    let partial = private.join(&name);
+    let data = tokio::task::spawn_blocking(move || {
+        let res = fs::read(&partial)
+            .map_err(|e| format!("Failed to read the snapshot: {e}"))
+            .and_then(|data| match &encryption_key {
+                Some(passphrase) => encrypt(passphrase, &data),
+                None => Ok(data),
+            });
+        let _ = fs::remove_dir_all(&private);
+        res
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+    Ok((name, data))
+}
+
+/// A snapshot to download, encrypted if `[backup]` has an `encryption_key`
+pub async fn download(
+    db: &BlockingPool,
+    config: Option<&BackupConfig>,
+) -> Result<(String, Vec<u8>), String> {
+    let encryption_key = config.and_then(|config| config.encryption_key.clone());
+    take_snapshot(db, None, encryption_key).await
+}
+
+/// Deletes the oldest snapshots in the directory beyond `keep`
+fn rotate_local(dir: &Path, keep: usize) -> Result<(), String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .map_err(|e| format!("Failed to list {}: {e}", dir.display()))?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| is_snapshot(name))
+        .collect();
+    names.sort();
+    for name in names.iter().rev().skip(keep) {
+        fs::remove_file(dir.join(name))
+            .map_err(|e| format!("Failed to delete the old snapshot {name}: {e}"))?;
+    }
+    Ok(())
+}
+
+impl S3Config {
+    /// Sends a request signed with AWS Signature Version 4
+    async fn send(
+        &self,
+        http: &reqwest::Client,
+        method: reqwest::Method,
+        key: &str,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<String, String> {
+        let endpoint = reqwest::Url::parse(&self.endpoint)
+            .map_err(|e| format!("Invalid S3 endpoint '{}': {e}", self.endpoint))?;
+        let host = match (endpoint.host_str(), endpoint.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_owned(),
+            (None, _) => return Err(format!("Invalid S3 endpoint '{}'", self.endpoint)),
+        };
+        let path = format!(
+            "/{}/{}",
+            uri_encode(&self.bucket, false),
+            uri_encode(key, true)
+        );
+        let signed = AwsRequest {
+            service: "s3",
+            region: &self.region,
+            method: method.as_str(),
+            host: &host,
+            path: &path,
+            query,
+            body: &body,
+        }
+        .sign(&AwsCredentials {
+            access_key_id: &self.access_key_id,
+            secret_access_key: &self.secret_access_key,
+        });
+
+        let mut url = endpoint;
+        url.set_path(&path);
+        url.set_query((!signed.query.is_empty()).then_some(signed.query.as_str()));
+        let response = http
+            .request(method, url)
+            .header(reqwest::header::AUTHORIZATION, signed.authorization)
+            .header("x-amz-content-sha256", signed.payload_hash)
+            .header("x-amz-date", signed.timestamp)
+            .body(body)
+            .timeout(UPLOAD_TIMEOUT)
+            .send()
+            .await
+            .map_err(|e| format!("S3: {e}"))?;
+        let status = response.status();
+        let text = response.text().await.map_err(|e| format!("S3: {e}"))?;
+        match status.is_success() {
+            true => Ok(text),
+            false => Err(format!("S3 answered {status}: {text}")),
+        }
+    }
+
+    async fn upload(
+        &self,
+        http: &reqwest::Client,
+        name: &str,
+        data: Vec<u8>,
+    ) -> Result<(), String> {
+        let key = format!("{}{name}", self.prefix);
+        self.send(http, reqwest::Method::PUT, &key, &[], data)
+            .await
+            .map(|_| ())
+    }
+
+    /// Deletes the oldest snapshots in the bucket beyond `keep`
+    async fn rotate(&self, http: &reqwest::Client, keep: usize) -> Result<(), String> {
+        let prefix = format!("{}{SNAPSHOT_PREFIX}", self.prefix);
+        // The first 1000 keys, which is plenty for the snapshots kept
+        let listing = self
+            .send(
+                http,
+                reqwest::Method::GET,
+                "",
+                &[("list-type", "2"), ("prefix", &prefix)],
+                Vec::new(),
+            )
+            .await?;
+        let mut keys: Vec<&str> = listing
+            .split("<Key>")
+            .skip(1)
+            .filter_map(|rest| rest.split_once("</Key>").map(|(key, _)| key))
+            .filter(|key| key.strip_prefix(&self.prefix).is_some_and(is_snapshot))
+            .collect();
+        keys.sort_unstable();
+        for key in keys.iter().rev().skip(keep) {
+            self.send(http, reqwest::Method::DELETE, key, &[], Vec::new())
+                .await
+                .map_err(|e| format!("Failed to delete the old snapshot {key}: {e}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Takes snapshots of the database, keeps the latest ones in a directory and a bucket
+#[derive(Clone)]
+pub struct BackupJob {
+    db: BlockingPool,
+    config: BackupConfig,
+    http: reqwest::Client,
+}
+
+impl BackupJob {
+    pub fn new(db: BlockingPool, config: BackupConfig) -> Result<Self, String> {
+        config.validate()?;
+        let http = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Self { db, config, http })
+    }
+
+    /// Backs up now and then every configured interval, unless disabled
+    pub fn start(&self) {
+        let Some(interval) = self.config.interval else {
+            return;
+        };
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                if let Err(e) = this.run().await {
+                    error!("Failed to back up the database: {e}");
+                }
+            }
+        });
+    }
+
+    /// Takes a snapshot, stores it everywhere configured and deletes old ones.
+    /// Returns the name of the snapshot.
+    pub async fn run(&self) -> Result<String, String> {
+        let (name, data) = take_snapshot(
+            &self.db,
+            self.config.path.clone(),
+            self.config.encryption_key.clone(),
+        )
+        .await?;
+
+        let config = self.config.clone();
+        let final_name = name.clone();
+        let data = tokio::task::spawn_blocking(move || {
+            if let Some(dir) = &config.path {
+                let temporary = dir.join(format!("{final_name}.partial"));
+                fs::write(&temporary, &data)
+                    .and_then(|()| fs::rename(&temporary, dir.join(&final_name)))
+                    .map_err(|e| format!("Failed to write {}: {e}", dir.display()))?;
+                rotate_local(dir, config.keep)?;
+            }
+            Ok::<_, String>(data)
+        })
+        .await
+        .map_err(|e| e.to_string())??;
+
+        if let Some(s3) = &self.config.s3 {
+            s3.upload(&self.http, &name, data).await?;
+            s3.rotate(&self.http, self.config.keep).await?;
+        }
+        info!("Backed up the database as {name}");
+        Ok(name)
+    }
+}
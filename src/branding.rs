@@ -0,0 +1,114 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use serde::Deserialize;
+
+/// Shown if no instance name is configured
+const DEFAULT_NAME: &str = "Secure SSH Manager";
+
+/// Larger logos are rejected, they are kept in memory
+const MAX_LOGO_SIZE: u64 = 1024 * 1024;
+
+/// Name, logo and login notice of this instance
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct BrandingConfig {
+    /// Shown in the header and the title of every page
+    #[serde(default)]
+    instance_name: Option<String>,
+    /// Image shown next to the instance name: png, jpg, gif, webp or svg
+    #[serde(default)]
+    logo: Option<PathBuf>,
+    /// Shown above the login form, e.g. an "authorized use only" banner
+    #[serde(default)]
+    login_notice: Option<String>,
+}
+
+/// The logo read at startup
+pub struct Logo {
+    pub content_type: &'static str,
+    pub data: Vec<u8>,
+}
+
+struct Branding {
+    name: String,
+    logo: Option<Logo>,
+    login_notice: Option<String>,
+}
+
+static BRANDING: OnceLock<Branding> = OnceLock::new();
+
+fn logo_content_type(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    match extension.as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        "webp" => Some("image/webp"),
+        "svg" => Some("image/svg+xml"),
+        _ => None,
+    }
+}
+
+fn read_logo(path: PathBuf) -> Result<Logo, String> {
+    let content_type = logo_content_type(&path)
+        .ok_or_else(|| format!("The logo {path:?} has to be a png, jpg, gif, webp or svg file"))?;
+    let size = fs::metadata(&path)
+        .map_err(|e| format!("Failed to read the logo {path:?}: {e}"))?
+        .len();
+    if size > MAX_LOGO_SIZE {
+        return Err(format!("The logo {path:?} is larger than 1 MiB"));
+    }
+    let data = fs::read(&path).map_err(|e| format!("Failed to read the logo {path:?}: {e}"))?;
+    Ok(Logo { content_type, data })
+}
+
+impl BrandingConfig {
+    /// Reads the logo, then makes the settings available to the templates
+    pub fn install(self) -> Result<(), String> {
+        let name = match self.instance_name {
+            Some(name) if name.trim().is_empty() => {
+                return Err(String::from("The instance name can't be empty"));
+            }
+            Some(name) => name.trim().to_owned(),
+            None => DEFAULT_NAME.to_owned(),
+        };
+        let logo = self.logo.map(read_logo).transpose()?;
+        let login_notice = self
+            .login_notice
+            .map(|notice| notice.trim().to_owned())
+            .filter(|notice| !notice.is_empty());
+        BRANDING
+            .set(Branding {
+                name,
+                logo,
+                login_notice,
+            })
+            .map_err(|_| String::from("The branding was already installed"))
+    }
+}
+
+/// Name of this instance, shown in the header and the page titles
+pub fn instance_name() -> &'static str {
+    BRANDING
+        .get()
+        .map_or(DEFAULT_NAME, |branding| branding.name.as_str())
+}
+
+/// The configured logo, served at `/branding/logo`
+pub fn logo() -> Option<&'static Logo> {
+    BRANDING.get().and_then(|branding| branding.logo.as_ref())
+}
+
+pub fn has_logo() -> bool {
+    logo().is_some()
+}
+
+/// Notice shown above the login form
+pub fn login_notice() -> Option<&'static str> {
+    BRANDING
+        .get()
+        .and_then(|branding| branding.login_notice.as_deref())
+}
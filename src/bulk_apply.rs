@@ -0,0 +1,355 @@
+use std::{sync::Arc, time::Duration};
+
+use futures::{stream, StreamExt};
+use log::{error, info, warn};
+use openidconnect::reqwest;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::BlockingPool,
+    jobs::{is_finished, JobTracker, Step, StepStatus},
+    models::{AuditEntry, Host},
+    notifications::Notifier,
+    secrets,
+    shutdown::{self, Operation},
+    ssh::{describe, CachingSshClient, Severity, SshClient, SshClientError},
+};
+
+/// Key of the fleet-wide job, there is only ever one
+const JOB: &str = "all hosts";
+
+/// How many hosts are written to at the same time
+const APPLY_CONCURRENCY: usize = 8;
+
+/// Name of the step calling the validation webhook
+const VALIDATION_STEP: &str = "Validation webhook";
+
+/// Reason of the steps of hosts not applied to because the server shut down
+const SHUTTING_DOWN: &str = "Shutting down";
+
+/// Longest part of a rejecting response shown as the reason
+const MAX_REASON_LEN: usize = 500;
+
+const fn default_webhook_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+/// An external policy engine that has to allow the changes before they are applied
+#[derive(Debug, Clone, Deserialize)]
+pub struct ValidationWebhookConfig {
+    /// Receives the change set as JSON via POST
+    url: String,
+    /// Sent as `Authorization: Bearer <token>`
+    #[serde(default, deserialize_with = "secrets::deserialize_optional_secret")]
+    token: Option<String>,
+    /// Timeout in seconds
+    #[serde(
+        default = "default_webhook_timeout",
+        deserialize_with = "crate::deserialize_timeout"
+    )]
+    timeout: Duration,
+}
+
+/// What applying would change, sent to the validation webhook
+#[derive(Debug, Serialize)]
+struct ChangeSet {
+    hosts: Vec<HostChangeSet>,
+}
+
+#[derive(Debug, Serialize)]
+struct HostChangeSet {
+    host: String,
+    /// Why the changes of this host couldn't be determined
+    error: Option<String>,
+    logins: Vec<LoginChangeSet>,
+}
+
+#[derive(Debug, Serialize)]
+struct LoginChangeSet {
+    login: String,
+    /// Drift that applying removes
+    changes: Vec<Change>,
+}
+
+#[derive(Debug, Serialize)]
+struct Change {
+    severity: Severity,
+    description: String,
+}
+
+/// Optional answer of the webhook, anything else with a success status allows the changes
+#[derive(Debug, Deserialize)]
+struct Verdict {
+    allow: bool,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+#[derive(Clone)]
+struct ValidationWebhook {
+    config: ValidationWebhookConfig,
+    http: reqwest::Client,
+}
+
+impl ValidationWebhook {
+    /// Asks the webhook whether the changes may be applied. Fails closed: if the webhook
+    /// can't be reached, nothing is applied.
+    async fn validate(&self, change_set: &ChangeSet) -> Result<(), String> {
+        let body = serde_json::to_vec(change_set).map_err(|e| e.to_string())?;
+        let mut request = self
+            .http
+            .post(&self.config.url)
+            .timeout(self.config.timeout)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body);
+        if let Some(token) = &self.config.token {
+            request = request.bearer_auth(token);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| format!("Couldn't reach the validation webhook: {e}"))?;
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        let text: String = text.trim().chars().take(MAX_REASON_LEN).collect();
+
+        if !status.is_success() {
+            return Err(format!(
+                "Rejected by the validation webhook ({status}): {text}"
+            ));
+        }
+        match serde_json::from_str::<Verdict>(&text) {
+            Ok(Verdict {
+                allow: false,
+                reason,
+            }) => Err(format!(
+                "Rejected by the validation webhook: {}",
+                reason.unwrap_or_else(|| String::from("no reason given"))
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Applies the database state to every host in the background, with one step
+/// per host, preceded by the validation webhook if configured
+#[derive(Clone)]
+pub struct BulkApplier {
+    db: BlockingPool,
+    ssh_client: SshClient,
+    caching_ssh_client: Arc<CachingSshClient>,
+    jobs: JobTracker,
+    webhook: Option<ValidationWebhook>,
+    notifier: Notifier,
+}
+
+impl BulkApplier {
+    pub fn new(
+        db: BlockingPool,
+        ssh_client: SshClient,
+        caching_ssh_client: Arc<CachingSshClient>,
+    ) -> Self {
+        Self {
+            db,
+            ssh_client,
+            caching_ssh_client,
+            jobs: JobTracker::default(),
+            webhook: None,
+            notifier: Notifier::default(),
+        }
+    }
+
+    /// Lets a webhook allow or refuse every run before anything is written
+    pub fn with_validation(mut self, config: ValidationWebhookConfig) -> Result<Self, String> {
+        let http = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|e| format!("Failed to create HTTP client: {e}"))?;
+        self.webhook = Some(ValidationWebhook { config, http });
+        Ok(self)
+    }
+
+    /// Posts the results of every run to the chats that want them
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = notifier;
+        self
+    }
+
+    /// Per host results of the last run, steps are named after the hosts
+    pub async fn status(&self) -> Option<Vec<Step>> {
+        self.jobs.status(JOB).await
+    }
+
+    /// Starts applying to all hosts, critical ones first, unless that is still running
+    pub async fn start(&self) -> Result<usize, String> {
+        let hosts = self.db.run(Host::get_all_by_priority).await??;
+        self.start_hosts(hosts).await
+    }
+
+    /// Starts applying to the hosts with these names, unless a run is still going on
+    pub async fn start_for(&self, names: Vec<String>) -> Result<usize, String> {
+        let hosts = self
+            .db
+            .run(move |conn| {
+                Host::get_all_by_priority(conn).map(|hosts| {
+                    hosts
+                        .into_iter()
+                        .filter(|host| names.contains(&host.name))
+                        .collect()
+                })
+            })
+            .await??;
+        self.start_hosts(hosts).await
+    }
+
+    async fn start_hosts(&self, hosts: Vec<Host>) -> Result<usize, String> {
+        if hosts.is_empty() {
+            return Err(String::from("There are no hosts"));
+        }
+        let mut names: Vec<&str> = hosts.iter().map(|host| host.name.as_str()).collect();
+        if self.webhook.is_some() {
+            names.insert(0, VALIDATION_STEP);
+        }
+        self.jobs.begin(JOB, &names).await?;
+
+        let count = hosts.len();
+        let this = self.clone();
+        actix_web::rt::spawn(async move { this.run(hosts).await });
+        Ok(count)
+    }
+
+    async fn run(&self, hosts: Vec<Host>) {
+        let _operation = Operation::begin("bulk apply");
+        let mut first_host = 0;
+        if let Some(webhook) = &self.webhook {
+            self.jobs.set(JOB, 0, StepStatus::Running).await;
+            let change_set = self.change_set(&hosts).await;
+            if let Err(e) = webhook.validate(&change_set).await {
+                warn!("Not applying the database state: {e}");
+                self.jobs.fail(JOB, 0, e).await;
+                return;
+            }
+            self.jobs
+                .set(JOB, 0, StepStatus::Done(String::from("Allowed")))
+                .await;
+            first_host = 1;
+        }
+
+        info!("Applying the database state to {} hosts", hosts.len());
+        let apply = stream::iter(hosts.into_iter().enumerate()).for_each_concurrent(
+            APPLY_CONCURRENCY,
+            |(i, host)| async move {
+                let i = first_host + i;
+                if shutdown::is_stopping() {
+                    return;
+                }
+                self.jobs.set(JOB, i, StepStatus::Running).await;
+                let status = match self.ssh_client.apply_authorized_keys(host.clone()).await {
+                    Ok(logins) => StepStatus::Done(format!("Rewrote {}", logins.join(", "))),
+                    Err(e @ SshClientError::Observed(_)) => StepStatus::Skipped(e.to_string()),
+                    Err(e) => StepStatus::Failed(e.to_string()),
+                };
+                // Refresh the cache, so the diff shows what was actually written
+                let _ = self.caching_ssh_client.get_host_diff(host, true).await;
+                self.jobs.set(JOB, i, status).await;
+            },
+        );
+        tokio::pin!(apply);
+        // Hosts being written to are finished, the others are left for after the restart
+        tokio::select! {
+            () = &mut apply => {}
+            () = shutdown::stopped() => {
+                self.record_interrupted().await;
+                apply.await;
+            }
+        }
+
+        if let Some(steps) = self.status().await.filter(|steps| is_finished(steps)) {
+            let steps = &steps[first_host..];
+            let failed: Vec<(String, String)> = steps
+                .iter()
+                .filter_map(|step| match &step.status {
+                    StepStatus::Failed(e) => Some((step.name.clone(), e.clone())),
+                    _ => None,
+                })
+                .collect();
+            info!(
+                "Applied the database state to {} hosts, {} failed",
+                steps.len() - failed.len(),
+                failed.len()
+            );
+            self.notifier
+                .apply_finished(steps.len() - failed.len(), &failed);
+        }
+    }
+
+    /// Skips the hosts not started on yet and records them in the audit log, since the job
+    /// progress is lost with the restart
+    async fn record_interrupted(&self) {
+        let interrupted = self.jobs.skip_pending(JOB, SHUTTING_DOWN).await;
+        if interrupted.is_empty() {
+            return;
+        }
+        warn!(
+            "Shutting down before applying to {} host(s): {}",
+            interrupted.len(),
+            interrupted.join(", ")
+        );
+        let target = format!("hosts={}", interrupted.join(","));
+        if let Err(e) = self
+            .db
+            .run(move |conn| {
+                AuditEntry::record(
+                    conn,
+                    String::from("scheduler"),
+                    String::from("bulk apply"),
+                    target,
+                    Err(String::from("Not applied, the server shut down")),
+                )
+            })
+            .await
+            .map_err(String::from)
+            .and_then(|res| res)
+        {
+            error!("Failed to record an interrupted bulk apply: {e}");
+        }
+    }
+
+    /// The current drift of every host, which applying removes
+    async fn change_set(&self, hosts: &[Host]) -> ChangeSet {
+        let hosts = stream::iter(hosts.iter().cloned())
+            .map(|host| async move {
+                let name = host.name.clone();
+                match self.caching_ssh_client.get_host_diff(host, true).await.1 {
+                    Ok(logins) => HostChangeSet {
+                        host: name,
+                        error: None,
+                        logins: logins
+                            .into_iter()
+                            .filter(|(_, items)| !items.is_empty())
+                            .map(|(login, items)| LoginChangeSet {
+                                login,
+                                changes: items
+                                    .iter()
+                                    .map(|item| Change {
+                                        severity: item.severity(),
+                                        description: describe(item),
+                                    })
+                                    .collect(),
+                            })
+                            .collect(),
+                    },
+                    Err(e) => HostChangeSet {
+                        host: name,
+                        error: Some(e.to_string()),
+                        logins: Vec::new(),
+                    },
+                }
+            })
+            .buffered(APPLY_CONCURRENCY)
+            .collect()
+            .await;
+        ChangeSet { hosts }
+    }
+}
@@ -0,0 +1,138 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use openidconnect::reqwest::{self, Url};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::secrets;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Matrix drops messages whose transaction id was already used by the access token
+static TRANSACTION: AtomicU64 = AtomicU64::new(0);
+
+/// What a chat message is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatEvent {
+    /// A host answered with another host key, or someone replaced it
+    HostKey,
+    /// A host has keys that aren't authorized there
+    Drift,
+    /// Authorizations were added or removed
+    Authorization,
+    /// A bulk apply finished
+    Apply,
+}
+
+fn all_events() -> Vec<ChatEvent> {
+    vec![
+        ChatEvent::HostKey,
+        ChatEvent::Drift,
+        ChatEvent::Authorization,
+        ChatEvent::Apply,
+    ]
+}
+
+/// Where chat messages are posted
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ChatTarget {
+    /// An incoming webhook of a Slack channel
+    Slack {
+        #[serde(deserialize_with = "secrets::deserialize_secret")]
+        webhook_url: String,
+    },
+    /// A Matrix room the account of the access token has joined
+    Matrix {
+        /// e.g. `https://matrix.example.com`
+        homeserver: String,
+        /// e.g. `!abcdef:example.com`
+        room_id: String,
+        #[serde(deserialize_with = "secrets::deserialize_secret")]
+        access_token: String,
+    },
+}
+
+/// A channel or room and the events posted there
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChatConfig {
+    #[serde(flatten)]
+    target: ChatTarget,
+    /// Defaults to every event
+    #[serde(default = "all_events")]
+    events: Vec<ChatEvent>,
+}
+
+impl ChatConfig {
+    pub fn wants(&self, event: ChatEvent) -> bool {
+        self.events.contains(&event)
+    }
+
+    fn name(&self) -> &str {
+        match &self.target {
+            ChatTarget::Slack { .. } => "Slack",
+            ChatTarget::Matrix { room_id, .. } => room_id,
+        }
+    }
+
+    /// Posts the message, with the subject as first line
+    pub async fn post(
+        &self,
+        http: &reqwest::Client,
+        subject: &str,
+        body: &str,
+    ) -> Result<(), String> {
+        let (request, payload) = match &self.target {
+            ChatTarget::Slack { webhook_url } => (
+                http.post(webhook_url),
+                json!({ "text": format!("*{subject}*\n{body}") }),
+            ),
+            ChatTarget::Matrix {
+                homeserver,
+                room_id,
+                access_token,
+            } => {
+                let mut url = Url::parse(homeserver)
+                    .map_err(|e| format!("Invalid homeserver '{homeserver}': {e}"))?;
+                let transaction = format!(
+                    "ssm-{}-{}",
+                    time::OffsetDateTime::now_utc().unix_timestamp_nanos(),
+                    TRANSACTION.fetch_add(1, Ordering::Relaxed)
+                );
+                url.path_segments_mut()
+                    .map_err(|()| format!("Invalid homeserver '{homeserver}'"))?
+                    .pop_if_empty()
+                    .extend([
+                        "_matrix",
+                        "client",
+                        "v3",
+                        "rooms",
+                        room_id,
+                        "send",
+                        "m.room.message",
+                        &transaction,
+                    ]);
+                (
+                    http.put(url).bearer_auth(access_token),
+                    json!({ "msgtype": "m.text", "body": format!("{subject}\n\n{body}") }),
+                )
+            }
+        };
+
+        let response = request
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(payload.to_string())
+            .timeout(TIMEOUT)
+            .send()
+            .await
+            .map_err(|e| format!("{}: {e}", self.name()))?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(format!("{} answered {status}", self.name())),
+        }
+    }
+}
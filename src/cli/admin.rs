@@ -0,0 +1,159 @@
+use std::io::BufRead;
+
+use clap::Subcommand;
+use diesel_migrations::MigrationHarness;
+use rand::{distributions::Alphanumeric, Rng};
+
+use crate::{
+    auth::{
+        self, check_new_password, AuthBackend, AuthBackendKind, HtpasswdBackend, PasswordHasher,
+        Role,
+    },
+    create_pool, migrations,
+    models::{WebSession, WebUser},
+    Configuration, DbConnection,
+};
+
+/// Length of generated passwords
+const GENERATED_PASSWORD_LENGTH: usize = 20;
+
+#[derive(Subcommand)]
+pub enum AdminCommand {
+    /// Create a local account with the admin role, e.g. the first one of a fresh install.
+    /// With LDAP, only gives the directory user the admin role.
+    Create {
+        #[arg(long)]
+        username: String,
+        /// Read the password from the first line of stdin instead of generating one
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// Set a new password for a local account and end its sessions
+    ResetPassword {
+        #[arg(long)]
+        username: String,
+        /// Read the password from the first line of stdin instead of generating one
+        #[arg(long)]
+        password_stdin: bool,
+    },
+}
+
+/// The entered password, or a generated one which is printed
+fn password(from_stdin: bool) -> Result<String, String> {
+    if !from_stdin {
+        let password: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(GENERATED_PASSWORD_LENGTH)
+            .map(char::from)
+            .collect();
+        println!("Generated password: {password}");
+        return Ok(password);
+    }
+    let mut line = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .map_err(|e| format!("Couldn't read the password: {e}"))?;
+    let password = line.trim_end_matches(['\r', '\n']).to_owned();
+    check_new_password(&password, &password)?;
+    Ok(password)
+}
+
+fn htpasswd_backend(configuration: &Configuration) -> Result<HtpasswdBackend, String> {
+    Ok(HtpasswdBackend::new(
+        configuration.htpasswd_path.clone(),
+        PasswordHasher::new(configuration.password_hashing.clone())?,
+    ))
+}
+
+/// Runs `f` on the database, brought up to date like on startup
+fn with_database<T>(
+    configuration: &Configuration,
+    f: impl FnOnce(&mut DbConnection) -> Result<T, String>,
+) -> Result<T, String> {
+    let pool = create_pool(configuration, None)?;
+    let mut conn = pool.get().map_err(|e| e.to_string())?;
+    let migrations = migrations(&conn);
+    conn.run_pending_migrations(migrations)
+        .map_err(|e| format!("Error while running migrations: {e}"))?;
+    f(&mut conn)
+}
+
+async fn create(
+    configuration: &Configuration,
+    username: &str,
+    from_stdin: bool,
+) -> Result<(), String> {
+    if configuration.auth_backend == AuthBackendKind::Htpasswd {
+        let backend = htpasswd_backend(configuration)?;
+        let path = &configuration.htpasswd_path;
+        // A fresh install has no file yet
+        let exists = path.exists()
+            && auth::read_accounts(path)?
+                .iter()
+                .any(|(name, _)| name == username);
+        if exists {
+            return Err(format!(
+                "The account '{username}' already exists, use `ssm admin reset-password`"
+            ));
+        }
+        let password = password(from_stdin)?;
+        // The role first, so the account can't exist without it
+        with_database(configuration, |conn| {
+            WebUser::grant_role(conn, username, Role::Admin)
+        })?;
+        backend.add_user(username, &password).await?;
+        println!(
+            "Added the admin '{username}' to {}",
+            configuration.htpasswd_path.display()
+        );
+    } else {
+        with_database(configuration, |conn| {
+            WebUser::grant_role(conn, username, Role::Admin)
+        })?;
+        println!("'{username}' is an admin, the password is managed in the LDAP directory");
+    }
+    Ok(())
+}
+
+async fn reset_password(
+    configuration: &Configuration,
+    username: &str,
+    from_stdin: bool,
+) -> Result<(), String> {
+    if configuration.auth_backend != AuthBackendKind::Htpasswd {
+        return Err(String::from("Passwords are managed in the LDAP directory"));
+    }
+    let backend = htpasswd_backend(configuration)?;
+    if !backend.has_user(username).await? {
+        return Err(format!("There is no account '{username}'"));
+    }
+    backend
+        .set_password(username, &password(from_stdin)?)
+        .await?;
+    let ended = with_database(configuration, |conn| {
+        WebSession::delete_for_user(conn, username)
+    })?;
+    println!("Changed the password of '{username}' and ended {ended} session(s)");
+    Ok(())
+}
+
+pub async fn run(command: AdminCommand, configuration: &Configuration) -> i32 {
+    let res = match command {
+        AdminCommand::Create {
+            username,
+            password_stdin,
+        } => create(configuration, &username, password_stdin).await,
+        AdminCommand::ResetPassword {
+            username,
+            password_stdin,
+        } => reset_password(configuration, &username, password_stdin).await,
+    };
+    match res {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("[FAIL] {e}");
+            1
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use diesel::{
+    sql_query, sql_types::Text, Connection, QueryableByName, RunQueryDsl, SqliteConnection,
+};
+use time::OffsetDateTime;
+
+use crate::{
+    backup::{is_sqlite, BackupConfig, BackupJob, DatabaseFile},
+    create_pool,
+    db::BlockingPool,
+    Configuration,
+};
+
+#[derive(QueryableByName)]
+struct IntegrityCheck {
+    #[diesel(sql_type = Text)]
+    integrity_check: String,
+}
+
+/// The file of the SQLite database the configuration points to
+fn database_file(database_url: &str) -> Result<PathBuf, String> {
+    let mut conn = SqliteConnection::establish(database_url)
+        .map_err(|e| format!("Couldn't open the SQLite database: {e}"))?;
+    sql_query("PRAGMA database_list")
+        .load::<DatabaseFile>(&mut conn)
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .find(|database| database.name == "main" && !database.file.is_empty())
+        .map(|database| PathBuf::from(database.file))
+        .ok_or_else(|| String::from("The database isn't stored in a file"))
+}
+
+/// Checks that the restored file is an intact SQLite database
+fn check_integrity(path: &Path) -> Result<(), String> {
+    let url = path
+        .to_str()
+        .ok_or_else(|| format!("Invalid path {path:?}"))?;
+    let mut conn = SqliteConnection::establish(url).map_err(|e| e.to_string())?;
+    let rows = sql_query("PRAGMA integrity_check")
+        .load::<IntegrityCheck>(&mut conn)
+        .map_err(|e| e.to_string())?;
+    match rows.first() {
+        Some(row) if row.integrity_check == "ok" => Ok(()),
+        Some(row) => Err(format!("The snapshot is damaged: {}", row.integrity_check)),
+        None => Err(String::from("The snapshot couldn't be checked")),
+    }
+}
+
+/// Appends a suffix to the file name, e.g. `ssm.db-wal`
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn restore_snapshot(configuration: &Configuration, snapshot: &Path) -> Result<String, String> {
+    let data = fs::read(snapshot).map_err(|e| format!("Failed to read {snapshot:?}: {e}"))?;
+    let data = BackupConfig::open_snapshot(configuration.backup.as_ref(), data)?;
+    if !is_sqlite(&data) {
+        return Err(format!("{snapshot:?} isn't an SQLite database"));
+    }
+
+    let target = database_file(&configuration.database_url)?;
+    let restored = with_suffix(&target, ".restore");
+    fs::write(&restored, &data).map_err(|e| format!("Failed to write {restored:?}: {e}"))?;
+    if let Err(e) = check_integrity(&restored) {
+        let _ = fs::remove_file(&restored);
+        return Err(e);
+    }
+
+    // Kept with its journal files, in case the wrong snapshot was restored
+    let now = OffsetDateTime::now_utc();
+    let previous_suffix = format!(".{}.before-restore", now.unix_timestamp());
+    let previous = with_suffix(&target, &previous_suffix);
+    for journal in ["", "-wal", "-shm"] {
+        let file = with_suffix(&target, journal);
+        if file.exists() {
+            fs::rename(&file, with_suffix(&previous, journal))
+                .map_err(|e| format!("Failed to move {file:?} aside: {e}"))?;
+        }
+    }
+    fs::rename(&restored, &target).map_err(|e| format!("Failed to replace {target:?}: {e}"))?;
+    Ok(format!(
+        "Restored {snapshot:?} to {target:?}, the previous database is kept as {previous:?}"
+    ))
+}
+
+/// Replaces the database with a snapshot. The server has to be stopped.
+pub fn restore(configuration: &Configuration, snapshot: &Path) -> i32 {
+    match restore_snapshot(configuration, snapshot) {
+        Ok(message) => {
+            println!("{message}");
+            0
+        }
+        Err(e) => {
+            println!("Restore failed: {e}");
+            1
+        }
+    }
+}
+
+/// Takes a snapshot now, like the scheduled backup
+pub async fn backup(configuration: Configuration) -> i32 {
+    let Some(config) = configuration.backup.clone() else {
+        println!("Backup failed: there is no [backup] section in the configuration");
+        return 1;
+    };
+    let res = create_pool(&configuration, None).and_then(|pool| {
+        let db = BlockingPool::new(
+            pool,
+            configuration.db_pool_size as usize,
+            configuration.db_timeout,
+        );
+        BackupJob::new(db, config)
+    });
+    let res = match res {
+        Ok(job) => job.run().await,
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(name) => {
+            println!("Backed up the database as {name}");
+            0
+        }
+        Err(e) => {
+            println!("Backup failed: {e}");
+            1
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use diesel_migrations::MigrationHarness;
+
+use crate::{
+    auth::{self, AuthBackendKind},
+    create_pool,
+    db::BlockingPool,
+    load_private_key, migrations,
+    models::Host,
+    ssh::{SshClient, SshClientError},
+    Configuration,
+};
+
+fn report<T, E: std::fmt::Display>(what: &str, res: Result<T, E>) -> Option<T> {
+    match res {
+        Ok(t) => {
+            println!("[ OK ] {what}");
+            Some(t)
+        }
+        Err(e) => {
+            println!("[FAIL] {what}: {e}");
+            None
+        }
+    }
+}
+
+pub async fn check(configuration: Configuration, host: Option<String>) -> i32 {
+    let mut failed = false;
+    println!("[ OK ] Configuration");
+
+    let auth_backend = match configuration.auth_backend {
+        AuthBackendKind::Htpasswd => "htpasswd file",
+        AuthBackendKind::Ldap => "LDAP server",
+    };
+    failed |= report(
+        auth_backend,
+        match auth::from_config(&configuration) {
+            Ok(backend) => backend.check().await,
+            Err(e) => Err(e),
+        },
+    )
+    .is_none();
+
+    let pool = report("Database connection", create_pool(&configuration, None));
+
+    if let Some(ref pool) = pool {
+        let pending = pool.get().map_err(|e| e.to_string()).and_then(|mut conn| {
+            let migrations = migrations(&conn);
+            conn.pending_migrations(migrations)
+                .map(|migrations| migrations.len())
+                .map_err(|e| e.to_string())
+        });
+        let pending = report("Migrations", pending);
+        failed |= pending.is_none();
+        if let Some(pending) = pending {
+            if pending > 0 {
+                println!("       {pending} pending migration(s) will be applied on startup");
+            }
+        }
+    } else {
+        failed = true;
+    }
+
+    let key = report("Private key", load_private_key(&configuration.ssh));
+    failed |= key.is_none();
+
+    if let Some(host_name) = host {
+        let what = format!("Connection to '{host_name}'");
+        let (Some(pool), Some(key)) = (pool, key) else {
+            println!("[SKIP] {what}: database or key unavailable");
+            return 1;
+        };
+
+        let db = BlockingPool::new(
+            pool,
+            configuration.db_pool_size as usize,
+            configuration.db_timeout,
+        );
+        let ssh_client = SshClient::new(db.clone(), key, configuration.ssh.clone());
+
+        let res = match Host::get_from_name(&db, host_name).await {
+            Ok(Some(host)) => ssh_client.check_connection(host).await,
+            Ok(None) => Err(SshClientError::NoSuchHost),
+            Err(e) => Err(SshClientError::ExecutionError(e)),
+        };
+        failed |= report(&what, res).is_none();
+    }
+
+    i32::from(failed)
+}
@@ -0,0 +1,98 @@
+use clap::Subcommand;
+
+use crate::{
+    db::BlockingPool,
+    load_private_key,
+    models::Host,
+    ssh::{describe, CachingSshClient, SshClient},
+    Configuration,
+};
+
+#[derive(Subcommand)]
+pub enum HostCommand {
+    /// List the hosts, critical ones first
+    List,
+    /// Show how the authorized_keys of a host differ from the database.
+    /// Exits with a nonzero code if they differ or the host can't be read.
+    Diff {
+        /// Name of the host
+        name: String,
+    },
+    /// Write the authorized_keys expected from the database to a host
+    Apply {
+        /// Name of the host
+        name: String,
+    },
+}
+
+async fn list(configuration: &Configuration) -> Result<(), String> {
+    let db = super::database(configuration)?;
+    for host in db.run(Host::get_all_by_priority).await?? {
+        println!(
+            "{:<24} {}@{}:{} ({})",
+            host.name, host.username, host.address, host.port, host.importance
+        );
+    }
+    Ok(())
+}
+
+/// The host and a client to connect to it
+async fn connect(
+    configuration: &Configuration,
+    name: String,
+) -> Result<(BlockingPool, Host, SshClient), String> {
+    let db = super::database(configuration)?;
+    let key = load_private_key(&configuration.ssh)?;
+    let host = Host::get_from_name(&db, name.clone())
+        .await?
+        .ok_or_else(|| format!("There is no host '{name}'"))?;
+    let ssh_client = SshClient::new(db.clone(), key, configuration.ssh.clone());
+    Ok((db, host, ssh_client))
+}
+
+/// Whether the host differs from the database
+async fn diff(configuration: &Configuration, name: String) -> Result<bool, String> {
+    let (db, host, ssh_client) = connect(configuration, name).await?;
+    let caching_client = CachingSshClient::new(db, ssh_client);
+    let (_, diff) = caching_client.get_host_diff(host, true).await;
+    let logins = diff.map_err(|e| e.to_string())?;
+    for (login, items) in &logins {
+        println!("{login}:");
+        for item in items {
+            println!("  {}", describe(item));
+        }
+    }
+    if logins.is_empty() {
+        println!("In sync with the database");
+    }
+    Ok(!logins.is_empty())
+}
+
+async fn apply(configuration: &Configuration, name: String) -> Result<(), String> {
+    let (_, host, ssh_client) = connect(configuration, name).await?;
+    let host_name = host.name.clone();
+    let logins = ssh_client
+        .apply_authorized_keys(host)
+        .await
+        .map_err(|e| e.to_string())?;
+    println!(
+        "Rewrote authorized_keys of {} on {host_name}",
+        logins.join(", ")
+    );
+    Ok(())
+}
+
+pub async fn run(command: HostCommand, configuration: &Configuration) -> i32 {
+    let res = match command {
+        HostCommand::List => list(configuration).await.map(|()| false),
+        HostCommand::Diff { name } => diff(configuration, name).await,
+        HostCommand::Apply { name } => apply(configuration, name).await.map(|()| false),
+    };
+    match res {
+        Ok(differs) => i32::from(differs),
+        Err(e) => {
+            println!("[FAIL] {e}");
+            1
+        }
+    }
+}
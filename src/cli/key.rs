@@ -0,0 +1,79 @@
+use std::io::BufRead;
+
+use clap::Subcommand;
+
+use crate::{
+    models::{parse_expiry, parse_key_line, PublicUserKey, User},
+    Configuration,
+};
+
+#[derive(Subcommand)]
+pub enum KeyCommand {
+    /// Add keys in OpenSSH format to a user. Hosts get them with the next `host apply`.
+    Add {
+        /// The user the keys belong to
+        #[arg(long)]
+        user: String,
+        /// When the keys expire, e.g. 2027-01-31T12:00
+        #[arg(long)]
+        expires: Option<String>,
+        /// The key, `<algorithm> <base64> [comment]`. Without it, one key is read per line
+        /// of stdin.
+        key: Option<String>,
+    },
+}
+
+async fn add(
+    configuration: &Configuration,
+    username: String,
+    expires: Option<String>,
+    key: Option<String>,
+) -> Result<(), String> {
+    let expires_at = parse_expiry(expires.as_deref().unwrap_or_default())?;
+    let lines = match key {
+        Some(key) => vec![key],
+        None => std::io::stdin()
+            .lock()
+            .lines()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Couldn't read the keys: {e}"))?
+            .into_iter()
+            .filter(|line| !line.trim().is_empty())
+            .collect(),
+    };
+    if lines.is_empty() {
+        return Err(String::from("No keys given"));
+    }
+
+    let db = super::database(configuration)?;
+    let added = db
+        .run(move |conn| {
+            let user = User::get_from_name(conn, &username)?
+                .ok_or_else(|| format!("There is no user '{username}'"))?;
+            // All parsed first, so a typo doesn't leave half of the keys added
+            let keys = lines
+                .iter()
+                .map(|line| parse_key_line(line.trim(), user.id))
+                .collect::<Result<Vec<_>, _>>()?;
+            for key in keys {
+                PublicUserKey::add_key(conn, key.with_expiry(expires_at))?;
+            }
+            Ok::<_, String>((username, lines.len()))
+        })
+        .await??;
+    println!("Added {} key(s) to '{}'", added.1, added.0);
+    Ok(())
+}
+
+pub async fn run(command: KeyCommand, configuration: &Configuration) -> i32 {
+    let res = match command {
+        KeyCommand::Add { user, expires, key } => add(configuration, user, expires, key).await,
+    };
+    match res {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("[FAIL] {e}");
+            1
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use diesel_migrations::MigrationHarness;
+
+use crate::{create_pool, db::BlockingPool, migrations, Configuration};
+
+mod admin;
+mod backup;
+mod check;
+mod host;
+mod key;
+mod passwords;
+
+/// Manage your ssh keys from a simple Web UI.
+/// Starts the web server when no command is given.
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Validate configuration, database, migrations and ssh key, then exit.
+    /// Exits with a nonzero code if any check fails.
+    Check {
+        /// Additionally try to connect to this host
+        #[arg(long)]
+        host: Option<String>,
+    },
+    /// List the accounts of the htpasswd file whose password hash is outdated.
+    /// Exits with a nonzero code if there are any.
+    Passwords {
+        /// List every account, not only the outdated ones
+        #[arg(long)]
+        all: bool,
+    },
+    /// Take a snapshot of the SQLite database now, like the scheduled backup of `[backup]`.
+    Backup,
+    /// Manage local admin accounts directly in the htpasswd file and database, e.g. to create
+    /// the first admin of a fresh install
+    Admin {
+        #[command(subcommand)]
+        command: admin::AdminCommand,
+    },
+    /// Show and apply the authorized_keys of hosts without the web UI, e.g. from cron jobs
+    Host {
+        #[command(subcommand)]
+        command: host::HostCommand,
+    },
+    /// Manage the keys of users without the web UI
+    Key {
+        #[command(subcommand)]
+        command: key::KeyCommand,
+    },
+    /// Replace the SQLite database with a snapshot, decrypting it with the `encryption_key`
+    /// of `[backup]`. Stop the server first. The current database is kept next to it.
+    Restore {
+        /// Snapshot file written by a backup
+        snapshot: PathBuf,
+    },
+}
+
+/// Runs a command and returns the exit code
+pub async fn run(command: Command, configuration: Configuration) -> i32 {
+    match command {
+        Command::Check { host } => check::check(configuration, host).await,
+        Command::Passwords { all } => passwords::report(&configuration, all),
+        Command::Admin { command } => admin::run(command, &configuration).await,
+        Command::Host { command } => host::run(command, &configuration).await,
+        Command::Key { command } => key::run(command, &configuration).await,
+        Command::Backup => backup::backup(configuration).await,
+        Command::Restore { snapshot } => backup::restore(&configuration, &snapshot),
+    }
+}
+
+/// The database, brought up to date like on startup
+fn database(configuration: &Configuration) -> Result<BlockingPool, String> {
+    let pool = create_pool(configuration, None)?;
+    let mut conn = pool.get().map_err(|e| e.to_string())?;
+    let migrations = migrations(&conn);
+    conn.run_pending_migrations(migrations)
+        .map_err(|e| format!("Error while running migrations: {e}"))?;
+    Ok(BlockingPool::new(
+        pool,
+        configuration.db_pool_size as usize,
+        configuration.db_timeout,
+    ))
+}
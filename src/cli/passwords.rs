@@ -0,0 +1,42 @@
+use crate::{
+    auth::{self, AuthBackendKind, PasswordHasher},
+    Configuration,
+};
+
+/// Lists accounts whose hash doesn't use the configured scheme and parameters.
+/// They are upgraded on their next login, unless `rehash_on_login` is off.
+pub fn report(configuration: &Configuration, all: bool) -> i32 {
+    if configuration.auth_backend != AuthBackendKind::Htpasswd {
+        println!("auth_backend isn't 'htpasswd', there are no local accounts");
+        return 0;
+    }
+    let hasher = match PasswordHasher::new(configuration.password_hashing.clone()) {
+        Ok(hasher) => hasher,
+        Err(e) => {
+            println!("[FAIL] {e}");
+            return 1;
+        }
+    };
+    let accounts = match auth::read_accounts(&configuration.htpasswd_path) {
+        Ok(accounts) => accounts,
+        Err(e) => {
+            println!("[FAIL] {e}");
+            return 1;
+        }
+    };
+
+    let mut outdated = 0;
+    for (username, hash) in &accounts {
+        let needs_rehash = hasher.needs_rehash(hash);
+        outdated += usize::from(needs_rehash);
+        if needs_rehash || all {
+            let status = match needs_rehash {
+                true => "[ OLD]",
+                false => "[ OK ]",
+            };
+            println!("{status} {username}: {}", PasswordHasher::describe(hash));
+        }
+    }
+    println!("{outdated} of {} account(s) outdated", accounts.len());
+    i32::from(outdated > 0)
+}
@@ -0,0 +1,210 @@
+use std::{sync::Arc, time::Duration};
+
+use log::{error, info, warn};
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+use time::PrimitiveDateTime;
+
+use crate::{
+    db::{self, BlockingPool},
+    drift_webhooks::DriftWebhooks,
+    models::Host,
+    shutdown::{self, Operation},
+    ssh::{self, CachingSshClient, RemediationPolicy, SshClient},
+};
+
+/// Scheduled runs of the instances start at slightly different times.
+/// Hosts checked this recently count as checked in the current run.
+const CLOCK_SKEW: Duration = Duration::from_secs(10);
+
+fn default_worker_id() -> String {
+    let hostname = std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| String::from("ssm"));
+    format!("{hostname}-{}", std::process::id())
+}
+
+const fn default_heartbeat_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+const fn default_worker_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClusterConfig {
+    /// Unique name of this instance, defaults to the hostname and process id
+    #[serde(default = "default_worker_id")]
+    worker_id: String,
+    /// Seconds between heartbeats
+    #[serde(
+        default = "default_heartbeat_interval",
+        deserialize_with = "crate::deserialize_timeout"
+    )]
+    heartbeat_interval: Duration,
+    /// Seconds without heartbeat after which the hosts of a worker are reassigned
+    #[serde(
+        default = "default_worker_timeout",
+        deserialize_with = "crate::deserialize_timeout"
+    )]
+    worker_timeout: Duration,
+}
+
+/// Splits the scheduled check job between all instances sharing the database.
+///
+/// Every instance runs the job on schedule and claims hosts one at a time,
+/// so faster instances take over more hosts. Claims of instances that stop
+/// sending heartbeats are released and picked up by the others.
+pub struct ClusterWorker {
+    config: ClusterConfig,
+    db: BlockingPool,
+    ssh_client: SshClient,
+    caching_ssh_client: Arc<CachingSshClient>,
+    remediation: Arc<Vec<RemediationPolicy>>,
+    drift_webhooks: DriftWebhooks,
+}
+
+impl ClusterWorker {
+    pub fn new(
+        config: ClusterConfig,
+        db: BlockingPool,
+        ssh_client: SshClient,
+        caching_ssh_client: Arc<CachingSshClient>,
+        remediation: Arc<Vec<RemediationPolicy>>,
+        drift_webhooks: DriftWebhooks,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            db,
+            ssh_client,
+            caching_ssh_client,
+            remediation,
+            drift_webhooks,
+        })
+    }
+
+    /// Sends heartbeats and takes over hosts of dead workers in the background
+    pub fn start(self: &Arc<Self>) {
+        info!("Joining cluster as worker '{}'", self.config.worker_id);
+        let this = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(this.config.heartbeat_interval);
+            loop {
+                interval.tick().await;
+                this.heartbeat().await;
+            }
+        });
+    }
+
+    async fn heartbeat(&self) {
+        let (worker_id, timeout) = (self.config.worker_id.clone(), self.config.worker_timeout);
+        let released = match self
+            .db
+            .run(move |conn| {
+                db::heartbeat(conn, &worker_id)?;
+                db::release_dead_claims(conn, timeout)
+            })
+            .await
+            .map_err(String::from)
+            .and_then(|res| res)
+        {
+            Ok(released) => released,
+            Err(e) => {
+                error!("Cluster heartbeat failed: {e}");
+                return;
+            }
+        };
+
+        if released > 0 {
+            warn!("Taking over {released} host(s) of unresponsive workers");
+            self.check_hosts(None).await;
+        }
+    }
+
+    /// Runs this instance's share of the scheduled check job
+    pub async fn run_check(&self) {
+        info!("Running check job");
+        self.check_hosts(Some(db::now() - CLOCK_SKEW)).await;
+        info!("Finished check job");
+    }
+
+    /// Checks all hosts that can be claimed, see [`db::claim_host`]
+    async fn check_hosts(&self, due_before: Option<PrimitiveDateTime>) {
+        let mut hosts = match self
+            .db
+            .run(Host::get_all_hosts)
+            .await
+            .map_err(String::from)
+            .and_then(|res| res)
+        {
+            Ok(hosts) => hosts,
+            Err(e) => {
+                error!("Failed check job: {e}");
+                return;
+            }
+        };
+        // Workers starting at the same time shouldn't fight over the same hosts,
+        // but all of them check critical hosts first
+        hosts.shuffle(&mut rand::thread_rng());
+        hosts.sort_by_key(Host::priority);
+
+        let _operation = Operation::begin("cluster check");
+        let mut checked = 0;
+        for host in hosts {
+            // Unclaimed hosts are left to the other workers
+            if shutdown::is_stopping() {
+                break;
+            }
+            let (host_id, worker_id) = (host.id, self.config.worker_id.clone());
+            match self
+                .db
+                .run(move |conn| db::claim_host(conn, host_id, &worker_id, due_before))
+                .await
+                .map_err(String::from)
+                .and_then(|res| res)
+            {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(e) => {
+                    error!(
+                        host = host.name.as_str(), action = "check";
+                        "Failed to claim {}: {e}", host.name
+                    );
+                    continue;
+                }
+            }
+
+            self.check_host(host).await;
+            checked += 1;
+
+            let worker_id = self.config.worker_id.clone();
+            if let Err(e) = self
+                .db
+                .run(move |conn| db::finish_host(conn, host_id, &worker_id))
+                .await
+                .map_err(String::from)
+                .and_then(|res| res)
+            {
+                // The claim was probably reassigned while this worker was unresponsive
+                warn!("Failed to finish check of host {host_id}: {e}");
+            }
+        }
+        info!("Checked {checked} host(s) on this worker");
+    }
+
+    async fn check_host(&self, host: Host) {
+        let host_name = host.name.clone();
+        let diff = self.caching_ssh_client.get_host_diff(host, true).await;
+        if let Err(e) = &diff.1 {
+            error!(host = host_name.as_str(), action = "check"; "Failed to check {host_name}: {e}");
+        }
+
+        let state = [(host_name, diff)];
+        self.drift_webhooks.notify(&state).await;
+        ssh::remediate(&self.remediation, &self.ssh_client, &self.db, &state).await;
+    }
+}
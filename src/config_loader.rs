@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+
+use config::{Value, ValueKind};
+use serde::de::DeserializeOwned;
+use serde_path_to_error::Segment;
+
+/// Stops looking for further errors after this many
+const MAX_ERRORS: usize = 50;
+
+/// A step into a table or an array of the configuration
+#[derive(Clone)]
+enum Step {
+    Key(String),
+    Index(usize),
+}
+
+fn format_path(path: &[Step]) -> String {
+    let mut formatted = String::new();
+    for step in path {
+        match step {
+            Step::Key(key) if formatted.is_empty() => formatted.push_str(key),
+            Step::Key(key) => {
+                formatted.push('.');
+                formatted.push_str(key);
+            }
+            Step::Index(index) => formatted.push_str(&format!("[{index}]")),
+        }
+    }
+    formatted
+}
+
+/// Removes the value at `path`, returns whether it was there
+fn remove(value: &mut Value, path: &[Step]) -> bool {
+    match (&mut value.kind, path) {
+        (_, []) => false,
+        (ValueKind::Table(table), [Step::Key(key)]) => table.remove(key).is_some(),
+        (ValueKind::Array(array), [Step::Index(index)]) if *index < array.len() => {
+            array.remove(*index);
+            true
+        }
+        (ValueKind::Table(table), [Step::Key(key), rest @ ..]) => {
+            table.get_mut(key).is_some_and(|child| remove(child, rest))
+        }
+        (ValueKind::Array(array), [Step::Index(index), rest @ ..]) => array
+            .get_mut(*index)
+            .is_some_and(|child| remove(child, rest)),
+        _ => false,
+    }
+}
+
+/// Deserializes and validates the configuration, reporting every invalid field instead of only
+/// the first: after each error the field, or the table missing a required field, is left out
+/// and deserializing is tried again. Leaving out a required field ends the search, it would
+/// only be reported as missing.
+pub fn load<T: DeserializeOwned>(
+    mut value: Value,
+    validate: impl Fn(&T) -> Vec<String>,
+) -> Result<T, Vec<String>> {
+    let mut errors = Vec::new();
+    let mut removed: Vec<String> = Vec::new();
+    // Elements left out of each array, to report the indices of the configuration
+    let mut removed_elements: HashMap<String, usize> = HashMap::new();
+
+    while errors.len() < MAX_ERRORS {
+        let e = match serde_path_to_error::deserialize::<_, T>(value.clone()) {
+            Ok(config) => {
+                errors.extend(validate(&config));
+                errors.sort();
+                return match errors.is_empty() {
+                    true => Ok(config),
+                    false => Err(errors),
+                };
+            }
+            Err(e) => e,
+        };
+
+        // The path in `value`, and the same path with the indices of the configuration
+        let mut path = Vec::new();
+        let mut original = Vec::new();
+        let mut complete = true;
+        for segment in e.path().iter() {
+            match segment {
+                Segment::Map { key } => {
+                    path.push(Step::Key(key.clone()));
+                    original.push(Step::Key(key.clone()));
+                }
+                Segment::Seq { index } => {
+                    let offset = removed_elements
+                        .get(&format_path(&original))
+                        .copied()
+                        .unwrap_or_default();
+                    path.push(Step::Index(*index));
+                    original.push(Step::Index(index + offset));
+                }
+                Segment::Enum { .. } | Segment::Unknown => {
+                    complete = false;
+                    break;
+                }
+            }
+        }
+
+        let message = e.into_inner().to_string();
+        let missing = message
+            .strip_prefix("missing field `")
+            .and_then(|rest| rest.split('`').next());
+        match missing {
+            Some(field) => {
+                let mut field_path = original.clone();
+                field_path.push(Step::Key(field.to_owned()));
+                let field_path = format_path(&field_path);
+                // Left out after an error of its own
+                if removed.contains(&field_path) {
+                    break;
+                }
+                errors.push(format!("{field_path}: is required"));
+            }
+            None => {
+                let name = match format_path(&original) {
+                    name if name.is_empty() => String::from("(top level)"),
+                    name => name,
+                };
+                errors.push(format!("{name}: {message}"));
+            }
+        }
+
+        // Leaves out the invalid field, or the table missing a field
+        if !complete || !remove(&mut value, &path) {
+            break;
+        }
+        if let Some(Step::Index(_)) = original.last() {
+            *removed_elements
+                .entry(format_path(&original[..original.len() - 1]))
+                .or_default() += 1;
+        }
+        removed.push(format_path(&original));
+    }
+    errors.sort();
+    Err(errors)
+}
@@ -0,0 +1,251 @@
+use actix_session::{Session, SessionExt};
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::{header, Method},
+    middleware::Next,
+    web::Bytes,
+    Error, HttpMessage,
+};
+use data_encoding::HEXLOWER;
+use futures_util::{future::ready, stream};
+use log::warn;
+use rand::RngCore;
+use subtle::ConstantTimeEq;
+
+use crate::{middleware::is_public, routes::invalid_csrf_token};
+
+/// Where the token is kept in the session
+const SESSION_KEY: &str = "csrf_token";
+/// HTMX requests send the token in this header, set by forms.js
+const HEADER: &str = "x-csrf-token";
+/// Plain forms send the token in this field, added by forms.js
+const FIELD: &str = "csrf_token";
+/// Posted without a session, the reset link carries a secret of its own
+const EXEMPT_PATHS: [&str; 2] = ["/auth/login", "/auth/reset"];
+
+tokio::task_local! {
+    static SESSION: Session;
+}
+
+/// The token of the session of the current request, for the templates. It is only given to
+/// sessions that exist already, so pages like the login page don't start one. Empty outside
+/// of requests.
+pub fn token() -> String {
+    SESSION.try_with(issue).unwrap_or_default()
+}
+
+fn issue(session: &Session) -> String {
+    if let Ok(Some(token)) = session.get::<String>(SESSION_KEY) {
+        return token;
+    }
+    if session.entries().is_empty() {
+        return String::new();
+    }
+    let token = new_token();
+    match session.insert(SESSION_KEY, &token) {
+        Ok(()) => token,
+        Err(e) => {
+            warn!("Failed to store the CSRF token in the session: {e}");
+            String::new()
+        }
+    }
+}
+
+fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    HEXLOWER.encode(&bytes)
+}
+
+/// Browsers don't send API tokens along by themselves, so these requests can't be forged
+fn has_api_token(req: &ServiceRequest) -> bool {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("Bearer "))
+}
+
+/// Requests that don't change anything, and logins, which have no session yet
+fn is_exempt(method: &Method, path: &str) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) || EXEMPT_PATHS.contains(&path)
+}
+
+/// The token sent with the request, from the header or a form field
+async fn submitted_token(req: &mut ServiceRequest) -> Result<Option<String>, Error> {
+    if let Some(token) = req
+        .headers()
+        .get(HEADER)
+        .and_then(|value| value.to_str().ok())
+    {
+        return Ok(Some(token.to_owned()));
+    }
+    if req.content_type() != "application/x-www-form-urlencoded" {
+        return Ok(None);
+    }
+
+    // The handler still needs the body, so it is put back after reading it
+    let body = req.extract::<Bytes>().await?;
+    let token = serde_urlencoded::from_bytes::<Vec<(String, String)>>(&body)
+        .unwrap_or_default()
+        .into_iter()
+        .find_map(|(name, value)| (name == FIELD).then_some(value));
+    req.set_payload(Payload::Stream {
+        payload: Box::pin(stream::once(ready(Ok(body)))),
+    });
+    Ok(token)
+}
+
+/// Middleware checking that requests changing anything send back the token of their session,
+/// so other sites can't make changes in the name of a logged in user. Pages get the token
+/// through [`token`].
+pub async fn protect(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    // The path the router matches, like the auth middleware
+    let path = req.match_info().as_str().to_owned();
+    // Probes, metrics and assets neither change anything nor show forms. Logging in and
+    // out is public too, but only logging in is exempt.
+    if has_api_token(&req) || (is_public(&path) && !path.starts_with("/auth/")) {
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+
+    let session = req.get_session();
+    if !is_exempt(req.method(), &path) {
+        let token = session.get::<String>(SESSION_KEY).ok().flatten();
+        let submitted = submitted_token(&mut req).await?;
+        let valid = match (token, submitted) {
+            (Some(token), Some(submitted)) => submitted.as_bytes().ct_eq(token.as_bytes()).into(),
+            _ => false,
+        };
+        if !valid {
+            warn!("[Web] {} {} (invalid CSRF token)", req.method(), req.path());
+            let response = invalid_csrf_token(req.request());
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    }
+
+    let res = SESSION.scope(session, next.call(req)).await?;
+    Ok(res.map_into_left_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        dev::Service,
+        http::StatusCode,
+        middleware::from_fn,
+        test::{call_service, init_service, TestRequest},
+        web, App, HttpResponse,
+    };
+
+    use super::*;
+
+    const TOKEN: &str = "0123456789abcdef";
+
+    /// An app whose sessions hold a token, unless `logged_in` is false, and which echoes
+    /// posted forms and the token of pages
+    async fn app(
+        logged_in: bool,
+    ) -> impl Service<actix_http::Request, Response = ServiceResponse<impl MessageBody>, Error = Error>
+    {
+        init_service(
+            App::new()
+                .wrap(from_fn(protect))
+                .wrap_fn(move |req, srv| {
+                    if logged_in {
+                        let session = req.get_session();
+                        session.insert("actix_identity.user_id", "alice").unwrap();
+                        session.insert(SESSION_KEY, TOKEN).unwrap();
+                    }
+                    srv.call(req)
+                })
+                .default_service(web::to(|body: String| async move {
+                    HttpResponse::Ok().body(format!("{body}|{}", token()))
+                })),
+        )
+        .await
+    }
+
+    async fn status(logged_in: bool, req: TestRequest) -> StatusCode {
+        call_service(&app(logged_in).await, req.to_request())
+            .await
+            .status()
+    }
+
+    #[actix_web::test]
+    async fn accepts_the_token_of_the_session() {
+        let req = TestRequest::post()
+            .uri("/hosts/add")
+            .insert_header((HEADER, TOKEN));
+        assert_eq!(status(true, req).await, StatusCode::OK);
+
+        // Forms get the body they posted, after the token was taken from it
+        let req = TestRequest::post()
+            .uri("/hosts/add")
+            .insert_header(header::ContentType::form_url_encoded())
+            .set_payload(format!("name=a&{FIELD}={TOKEN}"));
+        let res = call_service(&app(true).await, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = actix_web::test::read_body(res).await;
+        assert!(body.starts_with(format!("name=a&{FIELD}={TOKEN}|").as_bytes()));
+    }
+
+    #[actix_web::test]
+    async fn rejects_missing_and_wrong_tokens() {
+        let req = TestRequest::post().uri("/hosts/add");
+        assert_eq!(status(true, req).await, StatusCode::FORBIDDEN);
+
+        let req = TestRequest::post()
+            .uri("/hosts/add")
+            .insert_header((HEADER, "wrong"));
+        assert_eq!(status(true, req).await, StatusCode::FORBIDDEN);
+
+        // A session without a token doesn't accept an empty one
+        let req = TestRequest::post()
+            .uri("/hosts/add")
+            .insert_header((HEADER, ""));
+        assert_eq!(status(false, req).await, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn only_logging_in_is_exempt() {
+        let req = TestRequest::post().uri("/auth/login");
+        assert_eq!(status(false, req).await, StatusCode::OK);
+        let req = TestRequest::post().uri("/auth/logout");
+        assert_eq!(status(true, req).await, StatusCode::FORBIDDEN);
+        let req = TestRequest::post().uri("/auth/totp");
+        assert_eq!(status(true, req).await, StatusCode::FORBIDDEN);
+        // Encoded paths are judged like the router sees them
+        let req = TestRequest::post().uri("/%61uth/logout");
+        assert_eq!(status(true, req).await, StatusCode::FORBIDDEN);
+
+        let req = TestRequest::post()
+            .uri("/hosts/add")
+            .insert_header((header::AUTHORIZATION, "Bearer abc"));
+        assert_eq!(status(false, req).await, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn tokens_are_only_given_to_existing_sessions() {
+        let anonymous = app(false).await;
+        for uri in [
+            "/healthz",
+            "/metrics",
+            "/static/forms.js",
+            "/auth/login",
+            "/hosts",
+        ] {
+            let res = call_service(&anonymous, TestRequest::get().uri(uri).to_request()).await;
+            assert!(res.request().get_session().entries().is_empty(), "{uri}");
+            let body = actix_web::test::read_body(res).await;
+            assert_eq!(body, "|", "{uri}");
+        }
+
+        let logged_in = app(true).await;
+        let res = call_service(&logged_in, TestRequest::get().uri("/hosts").to_request()).await;
+        let body = actix_web::test::read_body(res).await;
+        assert_eq!(body, format!("|{TOKEN}"));
+    }
+}
@@ -0,0 +1,245 @@
+use diesel::dsl::insert_into;
+use diesel::prelude::*;
+use diesel::result::Error;
+
+use crate::schema::{access_request, authorization, host, user};
+use crate::{
+    models::{AccessRequest, NewAccessRequest},
+    DbConnection,
+};
+
+use super::{now, query, query_drop};
+
+pub const PENDING: &str = "pending";
+pub const APPROVED: &str = "approved";
+pub const DENIED: &str = "denied";
+
+/// A request with the names of its host and user
+pub type AccessRequestWithNames = (AccessRequest, String, String);
+
+impl AccessRequest {
+    /// Adds a pending request, unless the access exists or was already requested
+    pub fn create(conn: &mut DbConnection, request: NewAccessRequest) -> Result<(), String> {
+        let authorized = query(
+            authorization::table
+                .filter(authorization::host_id.eq(request.host_id))
+                .filter(authorization::user_id.eq(request.user_id))
+                .filter(authorization::login.eq(&request.login))
+                .count()
+                .get_result::<i64>(conn),
+        )?;
+        if authorized > 0 {
+            return Err(String::from("You already have this access"));
+        }
+
+        let pending = query(
+            access_request::table
+                .filter(access_request::host_id.eq(request.host_id))
+                .filter(access_request::user_id.eq(request.user_id))
+                .filter(access_request::login.eq(&request.login))
+                .filter(access_request::status.eq(PENDING))
+                .count()
+                .get_result::<i64>(conn),
+        )?;
+        if pending > 0 {
+            return Err(String::from("This access was already requested"));
+        }
+
+        query_drop(
+            insert_into(access_request::table)
+                .values(request)
+                .execute(conn),
+        )
+    }
+
+    pub fn get_from_id(conn: &mut DbConnection, id: i32) -> Result<Option<Self>, String> {
+        query(
+            access_request::table
+                .find(id)
+                .first::<Self>(conn)
+                .optional(),
+        )
+    }
+
+    /// Requests made by a web user, newest first
+    pub fn get_by_requester(
+        conn: &mut DbConnection,
+        requested_by: &str,
+    ) -> Result<Vec<AccessRequestWithNames>, String> {
+        query(
+            access_request::table
+                .inner_join(host::table)
+                .inner_join(user::table)
+                .filter(access_request::requested_by.eq(requested_by))
+                .order(access_request::id.desc())
+                .select((AccessRequest::as_select(), host::name, user::username))
+                .load(conn),
+        )
+    }
+
+    /// Pending requests for the hosts owned by `approver`, or for all hosts
+    pub fn get_pending(
+        conn: &mut DbConnection,
+        approver: Option<&str>,
+    ) -> Result<Vec<AccessRequestWithNames>, String> {
+        let mut pending = access_request::table
+            .inner_join(host::table)
+            .inner_join(user::table)
+            .filter(access_request::status.eq(PENDING))
+            .order(access_request::id)
+            .select((AccessRequest::as_select(), host::name, user::username))
+            .into_boxed();
+        if let Some(approver) = approver {
+            pending = pending.filter(host::owner.eq(approver));
+        }
+        query(pending.load(conn))
+    }
+
+    /// Approves or denies a pending request. Fails if it was decided in the meantime.
+    pub fn decide(
+        &self,
+        conn: &mut DbConnection,
+        status: &str,
+        decided_by: &str,
+        result: Option<String>,
+    ) -> Result<(), String> {
+        let updated = query(
+            diesel::update(
+                access_request::table
+                    .filter(access_request::id.eq(self.id))
+                    .filter(access_request::status.eq(PENDING)),
+            )
+            .set((
+                access_request::status.eq(status),
+                access_request::decided_by.eq(decided_by),
+                access_request::decided_at.eq(now()),
+                access_request::result.eq(result),
+            ))
+            .execute(conn),
+        )?;
+        match updated {
+            0 => Err(String::from("This request was already decided")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Approves a pending request and grants its access with `grant`, both or neither. If
+    /// granting fails, the request stays pending with the failure as its result, so it can be
+    /// approved again.
+    pub fn approve(
+        &self,
+        conn: &mut DbConnection,
+        decided_by: &str,
+        result: Option<String>,
+        grant: impl FnOnce(&mut DbConnection) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let res = conn.transaction::<_, ApproveError, _>(|conn| {
+            self.decide(conn, APPROVED, decided_by, result)
+                .map_err(ApproveError::Decide)?;
+            grant(conn).map_err(ApproveError::Grant)
+        });
+        match res {
+            Ok(()) => Ok(()),
+            Err(ApproveError::Grant(error)) => {
+                // Outside of the rolled back transaction
+                self.set_result(conn, format!("Failed to authorize: {error}"))?;
+                Err(error)
+            }
+            Err(ApproveError::Decide(error)) => Err(error),
+            Err(ApproveError::Database(error)) => query(Err(error)),
+        }
+    }
+
+    /// Records how deploying an approved request went
+    pub fn set_result(&self, conn: &mut DbConnection, result: String) -> Result<(), String> {
+        query_drop(
+            diesel::update(access_request::table.filter(access_request::id.eq(self.id)))
+                .set(access_request::result.eq(result))
+                .execute(conn),
+        )
+    }
+}
+
+/// Why approving a request was rolled back
+enum ApproveError {
+    Database(Error),
+    /// The request was decided in the meantime
+    Decide(String),
+    Grant(String),
+}
+
+impl From<Error> for ApproveError {
+    fn from(error: Error) -> Self {
+        Self::Database(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::Connection;
+    use diesel_migrations::MigrationHarness;
+
+    use super::*;
+
+    fn request(conn: &mut DbConnection) -> AccessRequest {
+        insert_into(host::table)
+            .values((
+                host::name.eq("web-1"),
+                host::username.eq("root"),
+                host::address.eq("web-1.example.com"),
+                host::port.eq(22),
+            ))
+            .execute(conn)
+            .unwrap();
+        insert_into(user::table)
+            .values(user::username.eq("alice"))
+            .execute(conn)
+            .unwrap();
+        AccessRequest::create(
+            conn,
+            NewAccessRequest {
+                created_at: now(),
+                requested_by: String::from("alice"),
+                user_id: 1,
+                host_id: 1,
+                login: String::from("deploy"),
+                justification: String::from("Releases"),
+                status: PENDING.to_owned(),
+            },
+        )
+        .unwrap();
+        AccessRequest::get_from_id(conn, 1).unwrap().unwrap()
+    }
+
+    #[test]
+    fn failed_grant_keeps_the_request_pending() {
+        let mut conn = DbConnection::establish(":memory:").unwrap();
+        conn.run_pending_migrations(crate::SQLITE_MIGRATIONS)
+            .unwrap();
+        let request = request(&mut conn);
+
+        let res = request.approve(&mut conn, "bob", None, |_| {
+            Err(String::from("Blocked by policy"))
+        });
+        assert_eq!(res, Err(String::from("Blocked by policy")));
+        let failed = AccessRequest::get_from_id(&mut conn, request.id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(failed.status, PENDING);
+        assert_eq!(
+            failed.result.as_deref(),
+            Some("Failed to authorize: Blocked by policy")
+        );
+
+        request.approve(&mut conn, "bob", None, |_| Ok(())).unwrap();
+        let approved = AccessRequest::get_from_id(&mut conn, request.id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(approved.status, APPROVED);
+        assert_eq!(approved.decided_by.as_deref(), Some("bob"));
+        assert_eq!(
+            request.approve(&mut conn, "bob", None, |_| Ok(())),
+            Err(String::from("This request was already decided"))
+        );
+    }
+}
@@ -0,0 +1,66 @@
+use diesel::dsl::insert_into;
+use diesel::prelude::*;
+
+use crate::schema::{host_archive, user_offboarding};
+use crate::{
+    models::{ArchivedHost, NewArchivedHost, NewOffboardingReport, OffboardingReport},
+    DbConnection,
+};
+
+use super::{query, query_drop};
+
+impl ArchivedHost {
+    /// All decommissioned hosts, newest first
+    pub fn get_all(conn: &mut DbConnection) -> Result<Vec<Self>, String> {
+        query(
+            host_archive::table
+                .order(host_archive::decommissioned_at.desc())
+                .load::<Self>(conn),
+        )
+    }
+
+    pub fn get_from_id(conn: &mut DbConnection, id: i32) -> Result<Option<Self>, String> {
+        query(
+            host_archive::table
+                .filter(host_archive::id.eq(id))
+                .first::<Self>(conn)
+                .optional(),
+        )
+    }
+
+    pub fn add(conn: &mut DbConnection, archived: NewArchivedHost) -> Result<(), String> {
+        query_drop(
+            insert_into(host_archive::table)
+                .values(archived)
+                .execute(conn),
+        )
+    }
+}
+
+impl OffboardingReport {
+    /// All offboarding reports, newest first
+    pub fn get_all(conn: &mut DbConnection) -> Result<Vec<Self>, String> {
+        query(
+            user_offboarding::table
+                .order(user_offboarding::offboarded_at.desc())
+                .load::<Self>(conn),
+        )
+    }
+
+    pub fn get_from_id(conn: &mut DbConnection, id: i32) -> Result<Option<Self>, String> {
+        query(
+            user_offboarding::table
+                .filter(user_offboarding::id.eq(id))
+                .first::<Self>(conn)
+                .optional(),
+        )
+    }
+
+    pub fn add(conn: &mut DbConnection, report: NewOffboardingReport) -> Result<(), String> {
+        query_drop(
+            insert_into(user_offboarding::table)
+                .values(report)
+                .execute(conn),
+        )
+    }
+}
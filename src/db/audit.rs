@@ -0,0 +1,94 @@
+use diesel::dsl::insert_into;
+use diesel::prelude::*;
+
+use crate::schema::audit_log;
+use crate::{
+    models::{AuditEntry, NewAuditEntry},
+    DbConnection,
+};
+
+use super::{now, query, query_drop};
+
+impl AuditEntry {
+    /// Records a change made by the scheduler
+    pub fn record(
+        conn: &mut DbConnection,
+        actor: String,
+        action: String,
+        target: String,
+        result: Result<String, String>,
+    ) -> Result<(), String> {
+        Self::record_request(conn, actor, None, action, target, result)
+    }
+
+    /// Records a change requested by `client`
+    pub fn record_request(
+        conn: &mut DbConnection,
+        actor: String,
+        client: Option<String>,
+        action: String,
+        target: String,
+        result: Result<String, String>,
+    ) -> Result<(), String> {
+        let (success, result) = match result {
+            Ok(result) => (true, result),
+            Err(error) => (false, error),
+        };
+        query_drop(
+            insert_into(audit_log::table)
+                .values(NewAuditEntry {
+                    created_at: now(),
+                    actor,
+                    action,
+                    target,
+                    success,
+                    result,
+                    client,
+                })
+                .execute(conn),
+        )
+    }
+
+    /// One page of entries, newest first, and the total number of entries
+    pub fn get_page(
+        conn: &mut DbConnection,
+        page: i64,
+        per_page: i64,
+    ) -> Result<(Vec<Self>, i64), String> {
+        let total = query(audit_log::table.count().get_result::<i64>(conn))?;
+        let entries = query(
+            audit_log::table
+                .order(audit_log::id.desc())
+                .limit(per_page)
+                .offset(page * per_page)
+                .select(Self::as_select())
+                .load::<Self>(conn),
+        )?;
+        Ok((entries, total))
+    }
+
+    /// All entries, oldest first
+    pub fn get_all(conn: &mut DbConnection) -> Result<Vec<Self>, String> {
+        query(
+            audit_log::table
+                .order(audit_log::id)
+                .select(Self::as_select())
+                .load::<Self>(conn),
+        )
+    }
+
+    /// Successful entries since `since`, oldest first
+    pub fn get_successful_since(
+        conn: &mut DbConnection,
+        since: time::PrimitiveDateTime,
+    ) -> Result<Vec<Self>, String> {
+        query(
+            audit_log::table
+                .filter(audit_log::created_at.ge(since))
+                .filter(audit_log::success.eq(true))
+                .order(audit_log::id)
+                .select(Self::as_select())
+                .load::<Self>(conn),
+        )
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    fmt,
+    future::Future,
+    panic::Location,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use actix_web::{http::StatusCode, ResponseError};
+use log::warn;
+use tokio::{sync::Semaphore, time::Instant};
+
+use crate::{
+    metrics::Histogram,
+    otel::{Span, SpanKind},
+    ConnectionPool, DbConnection,
+};
+
+#[derive(Debug, Clone)]
+pub enum BlockingError {
+    /// No slot became available before the timeout
+    Busy,
+    /// The operation didn't finish before the timeout
+    Timeout,
+    /// Couldn't get a connection from the pool
+    Connection(String),
+    /// The blocking task panicked
+    Panicked,
+}
+
+impl fmt::Display for BlockingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Busy => write!(f, "The database is busy, please try again later."),
+            Self::Timeout => write!(f, "The database operation timed out."),
+            Self::Connection(e) => write!(f, "Couldn't connect to the database: {e}"),
+            Self::Panicked => write!(f, "The database operation failed unexpectedly."),
+        }
+    }
+}
+
+impl ResponseError for BlockingError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::Busy | Self::Timeout => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Connection(_) | Self::Panicked => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl From<BlockingError> for String {
+    fn from(value: BlockingError) -> Self {
+        value.to_string()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    queued: AtomicUsize,
+    running: AtomicUsize,
+    completed: AtomicU64,
+    rejected: AtomicU64,
+    timed_out: AtomicU64,
+    /// Time operations waited for a slot, including those that never got one
+    wait: Mutex<Histogram>,
+}
+
+/// Decrements the running counter, even if the task panics
+struct RunningGuard(Arc<Counters>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.0.running.fetch_sub(1, Ordering::Relaxed);
+        self.0.completed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Snapshot of the blocking pool usage
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct BlockingStats {
+    /// Maximum number of concurrent operations
+    pub size: usize,
+    /// Operations waiting for a free slot
+    pub queued: usize,
+    /// Operations currently running
+    pub running: usize,
+    /// Operations that finished, successful or not
+    pub completed: u64,
+    /// Operations that never got a slot
+    pub rejected: u64,
+    /// Operations that exceeded the timeout
+    pub timed_out: u64,
+}
+
+/// Runs blocking database work on a size-limited set of threads, so a stalled
+/// database can't exhaust the actix workers.
+#[derive(Debug, Clone)]
+pub struct BlockingPool {
+    pool: ConnectionPool,
+    /// Read-only copy of the database for heavy reads, see [`Self::run_read`]
+    replica: Option<ConnectionPool>,
+    permits: Arc<Semaphore>,
+    size: usize,
+    timeout: Duration,
+    counters: Arc<Counters>,
+}
+
+impl BlockingPool {
+    pub fn new(pool: ConnectionPool, size: usize, timeout: Duration) -> Self {
+        Self {
+            pool,
+            replica: None,
+            permits: Arc::new(Semaphore::new(size)),
+            size,
+            timeout,
+            counters: Arc::default(),
+        }
+    }
+
+    /// Uses `replica` for [`Self::run_read`]
+    pub fn with_replica(mut self, replica: ConnectionPool) -> Self {
+        self.replica = Some(replica);
+        self
+    }
+
+    pub fn stats(&self) -> BlockingStats {
+        BlockingStats {
+            size: self.size,
+            queued: self.counters.queued.load(Ordering::Relaxed),
+            running: self.counters.running.load(Ordering::Relaxed),
+            completed: self.counters.completed.load(Ordering::Relaxed),
+            rejected: self.counters.rejected.load(Ordering::Relaxed),
+            timed_out: self.counters.timed_out.load(Ordering::Relaxed),
+        }
+    }
+
+    /// How long operations waited for a free slot since startup
+    pub fn wait_times(&self) -> Histogram {
+        self.counters
+            .wait
+            .lock()
+            .expect("Wait times poisoned")
+            .clone()
+    }
+
+    /// Runs `f` with a database connection. Waiting for a free slot and the
+    /// operation itself share the configured timeout.
+    #[track_caller]
+    pub fn run<F, T>(&self, f: F) -> impl Future<Output = Result<T, BlockingError>> + '_
+    where
+        F: FnOnce(&mut DbConnection) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.run_on(None, f, Location::caller())
+    }
+
+    /// Like [`Self::run`], but on the read replica if one is configured, so reports and
+    /// exports don't contend with writes. Falls back to the primary if the replica is
+    /// unavailable. `f` must not write.
+    #[track_caller]
+    pub fn run_read<F, T>(&self, f: F) -> impl Future<Output = Result<T, BlockingError>> + '_
+    where
+        F: FnOnce(&mut DbConnection) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.run_on(self.replica.clone(), f, Location::caller())
+    }
+
+    /// Traced as a span named after the caller, so slow queries can be found in the code
+    async fn run_on<F, T>(
+        &self,
+        replica: Option<ConnectionPool>,
+        f: F,
+        caller: &'static Location<'static>,
+    ) -> Result<T, BlockingError>
+    where
+        F: FnOnce(&mut DbConnection) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        Span::start(
+            format!("db {}:{}", caller.file(), caller.line()),
+            SpanKind::Client,
+        )
+        .with_attribute("code.filepath", caller.file())
+        .with_attribute("code.lineno", caller.line())
+        .with_attribute("db.replica", replica.is_some())
+        .run(self.run_timed(replica, f))
+        .await
+    }
+
+    async fn run_timed<F, T>(
+        &self,
+        replica: Option<ConnectionPool>,
+        f: F,
+    ) -> Result<T, BlockingError>
+    where
+        F: FnOnce(&mut DbConnection) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let queued_at = Instant::now();
+        let deadline = queued_at + self.timeout;
+
+        self.counters.queued.fetch_add(1, Ordering::Relaxed);
+        let permit =
+            tokio::time::timeout_at(deadline, Arc::clone(&self.permits).acquire_owned()).await;
+        self.counters.queued.fetch_sub(1, Ordering::Relaxed);
+        self.counters
+            .wait
+            .lock()
+            .expect("Wait times poisoned")
+            .observe(queued_at.elapsed());
+
+        let Ok(Ok(permit)) = permit else {
+            self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+            warn!("Rejected database operation: {:?}", self.stats());
+            return Err(BlockingError::Busy);
+        };
+
+        let pool = self.pool.clone();
+        self.counters.running.fetch_add(1, Ordering::Relaxed);
+        let guard = RunningGuard(Arc::clone(&self.counters));
+
+        let handle = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let _guard = guard;
+
+            let conn = match replica.map(|replica| replica.get()) {
+                Some(Ok(conn)) => Ok(conn),
+                Some(Err(e)) => {
+                    warn!("Read replica unavailable, using the primary database: {e}");
+                    pool.get()
+                }
+                None => pool.get(),
+            };
+            conn.map(|mut conn| f(&mut conn))
+                .map_err(|e| BlockingError::Connection(e.to_string()))
+        });
+
+        match tokio::time::timeout_at(deadline, handle).await {
+            Ok(Ok(res)) => res,
+            Ok(Err(_)) => Err(BlockingError::Panicked),
+            Err(_) => {
+                self.counters.timed_out.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Database operation exceeded {:?}: {:?}",
+                    self.timeout,
+                    self.stats()
+                );
+                Err(BlockingError::Timeout)
+            }
+        }
+    }
+}
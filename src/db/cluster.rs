@@ -0,0 +1,125 @@
+use std::time::Duration;
+
+use diesel::dsl::insert_into;
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error};
+use time::PrimitiveDateTime;
+
+use crate::schema::{cluster_worker, host_sync};
+use crate::DbConnection;
+
+use super::{now, query, query_drop};
+
+/// Workers that haven't been seen for this long are forgotten
+const FORGET_WORKERS_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Records that a worker is alive, registering it if necessary
+pub fn heartbeat(conn: &mut DbConnection, worker_id: &str) -> Result<(), String> {
+    let updated = query(
+        diesel::update(cluster_worker::table.filter(cluster_worker::id.eq(worker_id)))
+            .set(cluster_worker::heartbeat.eq(now()))
+            .execute(conn),
+    )?;
+    if updated > 0 {
+        return Ok(());
+    }
+
+    query_drop(
+        insert_into(cluster_worker::table)
+            .values((
+                cluster_worker::id.eq(worker_id),
+                cluster_worker::heartbeat.eq(now()),
+            ))
+            .execute(conn),
+    )
+}
+
+/// Releases unfinished claims of workers without a heartbeat for `timeout`,
+/// so other workers pick them up. Returns the number of released hosts.
+pub fn release_dead_claims(conn: &mut DbConnection, timeout: Duration) -> Result<usize, String> {
+    query(
+        diesel::delete(
+            cluster_worker::table
+                .filter(cluster_worker::heartbeat.lt(now() - FORGET_WORKERS_AFTER)),
+        )
+        .execute(conn),
+    )?;
+
+    let alive = cluster_worker::table
+        .filter(cluster_worker::heartbeat.ge(now() - timeout))
+        .select(cluster_worker::id);
+
+    query(
+        diesel::update(
+            host_sync::table
+                .filter(host_sync::finished_at.is_null())
+                .filter(host_sync::worker_id.is_not_null())
+                .filter(host_sync::worker_id.assume_not_null().ne_all(alive)),
+        )
+        .set((
+            host_sync::worker_id.eq(None::<String>),
+            host_sync::claimed_at.eq(None::<PrimitiveDateTime>),
+        ))
+        .execute(conn),
+    )
+}
+
+/// Claims a host for checking.
+///
+/// A host can be claimed if its claim was released, or if the last check
+/// finished and was claimed before `due_before`. Without `due_before`, only
+/// released claims are taken. Returns whether this worker got the claim.
+pub fn claim_host(
+    conn: &mut DbConnection,
+    host_id: i32,
+    worker_id: &str,
+    due_before: Option<PrimitiveDateTime>,
+) -> Result<bool, String> {
+    let claimed = query(
+        diesel::update(
+            host_sync::table
+                .filter(host_sync::host_id.eq(host_id))
+                .filter(
+                    host_sync::claimed_at.is_null().or(host_sync::claimed_at
+                        .lt(due_before)
+                        .and(host_sync::finished_at.is_not_null())),
+                ),
+        )
+        .set((
+            host_sync::worker_id.eq(worker_id),
+            host_sync::claimed_at.eq(now()),
+            host_sync::finished_at.eq(None::<PrimitiveDateTime>),
+        ))
+        .execute(conn),
+    )?;
+    if claimed > 0 || due_before.is_none() {
+        return Ok(claimed > 0);
+    }
+
+    // The host was never checked before, the first insert wins
+    match insert_into(host_sync::table)
+        .values((
+            host_sync::host_id.eq(host_id),
+            host_sync::worker_id.eq(worker_id),
+            host_sync::claimed_at.eq(now()),
+        ))
+        .execute(conn)
+    {
+        Ok(_) => Ok(true),
+        Err(Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => Ok(false),
+        Err(e) => query(Err(e)),
+    }
+}
+
+/// Marks the check of a claimed host as finished
+pub fn finish_host(conn: &mut DbConnection, host_id: i32, worker_id: &str) -> Result<(), String> {
+    query_drop(
+        diesel::update(
+            host_sync::table
+                .filter(host_sync::host_id.eq(host_id))
+                .filter(host_sync::worker_id.eq(worker_id)),
+        )
+        .set(host_sync::finished_at.eq(now()))
+        .execute(conn),
+    )
+}
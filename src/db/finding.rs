@@ -0,0 +1,89 @@
+use diesel::dsl::insert_into;
+use diesel::prelude::*;
+
+use crate::schema::finding;
+use crate::{models::Finding, DbConnection};
+
+use super::{now, query};
+
+pub const OPEN: &str = "open";
+pub const ACKNOWLEDGED: &str = "acknowledged";
+pub const DISMISSED: &str = "dismissed";
+
+impl Finding {
+    /// Records a finding unless the same one was found before. Open findings get the new
+    /// details, decided ones stay as they are. Returns whether the finding is new.
+    pub fn raise(
+        conn: &mut DbConnection,
+        kind: &str,
+        subject: &str,
+        details: &str,
+    ) -> Result<bool, String> {
+        let res = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            let known = finding::table
+                .filter(finding::kind.eq(kind))
+                .filter(finding::subject.eq(subject))
+                .count()
+                .get_result::<i64>(conn)?;
+            if known > 0 {
+                diesel::update(
+                    finding::table
+                        .filter(finding::kind.eq(kind))
+                        .filter(finding::subject.eq(subject))
+                        .filter(finding::status.eq(OPEN)),
+                )
+                .set(finding::details.eq(details))
+                .execute(conn)?;
+                return Ok(false);
+            }
+            insert_into(finding::table)
+                .values((
+                    finding::created_at.eq(now()),
+                    finding::kind.eq(kind),
+                    finding::subject.eq(subject),
+                    finding::details.eq(details),
+                    finding::status.eq(OPEN),
+                ))
+                .execute(conn)?;
+            Ok(true)
+        });
+        query(res)
+    }
+
+    /// Open findings first, newest first. Decided findings only if `all`.
+    pub fn get_all(conn: &mut DbConnection, all: bool) -> Result<Vec<Self>, String> {
+        let mut findings = finding::table.into_boxed();
+        if !all {
+            findings = findings.filter(finding::status.eq(OPEN));
+        }
+        let mut findings: Vec<Self> = query(findings.order(finding::id.desc()).load(conn))?;
+        findings.sort_by_key(|finding| finding.status != OPEN);
+        Ok(findings)
+    }
+
+    /// Acknowledges or dismisses an open finding
+    pub fn decide(
+        conn: &mut DbConnection,
+        id: i32,
+        status: &str,
+        decided_by: &str,
+    ) -> Result<(), String> {
+        let updated = query(
+            diesel::update(
+                finding::table
+                    .filter(finding::id.eq(id))
+                    .filter(finding::status.eq(OPEN)),
+            )
+            .set((
+                finding::status.eq(status),
+                finding::decided_by.eq(decided_by),
+                finding::decided_at.eq(now()),
+            ))
+            .execute(conn),
+        )?;
+        match updated {
+            0 => Err(String::from("This finding was already decided")),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use diesel::dsl::insert_into;
+use diesel::prelude::*;
+
+use crate::schema::{
+    authorization, group_authorization, host_group, host_group_member, user, user_group,
+    user_group_member,
+};
+use crate::ssh::KeyOptions;
+use crate::{
+    models::{GroupAuthorization, Host, HostGroup, UserGroup},
+    policy, DbConnection,
+};
+
+use super::{now, query, query_drop};
+
+/// A rule with the names of its team and host group
+pub type GroupAuthorizationWithNames = (GroupAuthorization, String, String);
+
+impl GroupAuthorization {
+    /// All rules, sorted by team, host group and login
+    pub fn get_all_with_names(
+        conn: &mut DbConnection,
+    ) -> Result<Vec<GroupAuthorizationWithNames>, String> {
+        query(
+            group_authorization::table
+                .inner_join(user_group::table)
+                .inner_join(host_group::table)
+                .order((
+                    user_group::name,
+                    host_group::name,
+                    group_authorization::login,
+                ))
+                .select((
+                    GroupAuthorization::as_select(),
+                    user_group::name,
+                    host_group::name,
+                ))
+                .load(conn),
+        )
+    }
+
+    /// Lets every member of a team access `login` on every host of a host group
+    pub fn create(
+        conn: &mut DbConnection,
+        team: &str,
+        host_group: &str,
+        login: &str,
+        options: Option<String>,
+    ) -> Result<(), String> {
+        let login = login.trim();
+        if login.is_empty() {
+            return Err(String::from("Please enter a login"));
+        }
+        let options = KeyOptions::normalize(options)?;
+        let team =
+            UserGroup::get_from_name(conn, team)?.ok_or_else(|| String::from("Team not found"))?;
+        let host_group = HostGroup::get_from_name(conn, host_group)?
+            .ok_or_else(|| String::from("Host group not found"))?;
+
+        let exists = query(
+            group_authorization::table
+                .filter(group_authorization::user_group_id.eq(team.id))
+                .filter(group_authorization::host_group_id.eq(host_group.id))
+                .filter(group_authorization::login.eq(login))
+                .count()
+                .get_result::<i64>(conn),
+        )?;
+        if exists > 0 {
+            return Err(format!(
+                "{} may already access {login} on {}",
+                team.name, host_group.name
+            ));
+        }
+
+        let members = team.get_members(conn)?;
+        for host in host_group.get_hosts(conn)? {
+            for member in &members {
+                policy::enforce(conn, &host, member.id, login)?;
+            }
+        }
+        query_drop(
+            insert_into(group_authorization::table)
+                .values((
+                    group_authorization::user_group_id.eq(team.id),
+                    group_authorization::host_group_id.eq(host_group.id),
+                    group_authorization::login.eq(login),
+                    group_authorization::options.eq(options),
+                ))
+                .execute(conn),
+        )
+    }
+
+    pub fn delete(conn: &mut DbConnection, id: i32) -> Result<(), String> {
+        query_drop(diesel::delete(group_authorization::table.find(id)).execute(conn))
+    }
+
+    /// Checks the policies for the access the rules of a team give a user about to join it
+    pub fn enforce_for_member(
+        conn: &mut DbConnection,
+        team: &UserGroup,
+        user_id: i32,
+    ) -> Result<(), String> {
+        let rules = query(
+            group_authorization::table
+                .inner_join(host_group::table)
+                .filter(group_authorization::user_group_id.eq(team.id))
+                .select((HostGroup::as_select(), group_authorization::login))
+                .load::<(HostGroup, String)>(conn),
+        )?;
+        for (host_group, login) in rules {
+            for host in host_group.get_hosts(conn)? {
+                policy::enforce(conn, &host, user_id, &login)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the policies for the access the rules on a host group give on a host about to
+    /// join it
+    pub fn enforce_for_host(
+        conn: &mut DbConnection,
+        host_group: &HostGroup,
+        host: &Host,
+    ) -> Result<(), String> {
+        let rules = query(
+            group_authorization::table
+                .inner_join(user_group::table)
+                .filter(group_authorization::host_group_id.eq(host_group.id))
+                .select((UserGroup::as_select(), group_authorization::login))
+                .load::<(UserGroup, String)>(conn),
+        )?;
+        for (team, login) in rules {
+            for member in team.get_members(conn)? {
+                policy::enforce_joining(conn, host, &host_group.name, member.id, &login)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Access of a user to a login on a host, through an authorization, a rule or both
+pub(super) struct Access {
+    /// None if only a rule grants the access
+    pub authorization: Option<i32>,
+    pub user_id: i32,
+    pub username: String,
+    pub login: String,
+    /// Options of the authorization
+    pub options: Option<String>,
+    /// Options of the first rule granting the access
+    pub group_options: Option<String>,
+    /// Whether a rule grants the access
+    pub by_rule: bool,
+    /// Team of the first rule granting the access
+    pub team: Option<String>,
+    pub user_defaults: Option<String>,
+}
+
+impl Host {
+    /// Who may access which login on this host: the authorizations, followed by the members
+    /// of teams whose rules cover a group of this host. Disabled users get no access through
+    /// rules. A user is listed once per login, the first rule only adds its options.
+    pub(super) fn get_access(&self, conn: &mut DbConnection) -> Result<Vec<Access>, String> {
+        let mut access: Vec<Access> = query(
+            authorization::table
+                .inner_join(user::table)
+                .filter(authorization::host_id.eq(self.id))
+                // Expired ones are dropped by the scheduler, but they stop working right away
+                .filter(
+                    authorization::expires_at
+                        .is_null()
+                        .or(authorization::expires_at.gt(now())),
+                )
+                .select((
+                    authorization::id,
+                    user::id,
+                    user::username,
+                    authorization::login,
+                    authorization::options,
+                    user::default_options,
+                ))
+                .load::<(i32, i32, String, String, Option<String>, Option<String>)>(conn),
+        )?
+        .into_iter()
+        .map(
+            |(id, user_id, username, login, options, user_defaults)| Access {
+                authorization: Some(id),
+                user_id,
+                username,
+                login,
+                options,
+                group_options: None,
+                by_rule: false,
+                team: None,
+                user_defaults,
+            },
+        )
+        .collect();
+
+        let host_groups: Vec<i32> = query(
+            host_group_member::table
+                .filter(host_group_member::host_id.eq(self.id))
+                .select(host_group_member::group_id)
+                .load(conn),
+        )?;
+        let rules = query(
+            group_authorization::table
+                .inner_join(user_group::table)
+                .filter(group_authorization::host_group_id.eq_any(host_groups))
+                .order(group_authorization::id)
+                .select((GroupAuthorization::as_select(), user_group::name))
+                .load::<(GroupAuthorization, String)>(conn),
+        )?;
+
+        for (rule, team) in rules {
+            let members = query(
+                user_group_member::table
+                    .inner_join(user::table)
+                    .filter(user_group_member::group_id.eq(rule.user_group_id))
+                    .filter(user::enabled.eq(true))
+                    .select((user::id, user::username, user::default_options))
+                    .load::<(i32, String, Option<String>)>(conn),
+            )?;
+            for (user_id, username, user_defaults) in members {
+                match access
+                    .iter_mut()
+                    .find(|entry| entry.user_id == user_id && entry.login == rule.login)
+                {
+                    Some(entry) if !entry.by_rule => {
+                        entry.by_rule = true;
+                        entry.group_options = rule.options.clone();
+                        entry.team = Some(team.clone());
+                    }
+                    Some(_) => {}
+                    None => access.push(Access {
+                        authorization: None,
+                        user_id,
+                        username,
+                        login: rule.login.clone(),
+                        options: None,
+                        group_options: rule.options.clone(),
+                        by_rule: true,
+                        team: Some(team.clone()),
+                        user_defaults,
+                    }),
+                }
+            }
+        }
+        Ok(access)
+    }
+}
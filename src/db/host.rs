@@ -1,25 +1,59 @@
+use std::collections::BTreeMap;
+
 use crate::schema::authorization;
+use crate::schema::deployment;
 use crate::schema::host;
 use crate::schema::user;
 use crate::schema::user_key;
+use crate::ssh::render_authorized_keys;
 use crate::ssh::ConnectionDetails;
+use crate::ssh::KeyOptions;
+use crate::ssh::OptionSources;
 use crate::ssh::SshClient;
 use crate::ssh::SshClientError;
+use crate::ssh::TRUST_MANUAL;
 use crate::{
     models::{Host, NewHost, PublicUserKey},
-    DbConnection,
+    policy, DbConnection,
 };
 use diesel::dsl::insert_into;
 use diesel::prelude::*;
-use diesel::r2d2::ConnectionManager;
-use diesel::r2d2::PooledConnection;
 
+use super::now;
+
+/// Synced and remediated first, drift is alerted with higher severity
+pub const CRITICAL: &str = "critical";
+pub const NORMAL: &str = "normal";
+/// Synced and remediated after all other hosts
+pub const LOW: &str = "low";
+/// In the order fleet operations process them
+pub const IMPORTANCES: [&str; 3] = [CRITICAL, NORMAL, LOW];
 use super::query;
 use super::query_drop;
 use super::AllowedUserOnHost;
 use super::AuthorizedKeysList;
+use super::BlockingPool;
+use super::PutOutcome;
 use super::UserAndOptions;
 
+/// The state `PUT /api/v1/hosts/{name}` converges a host to
+pub struct DesiredHost {
+    pub address: String,
+    pub port: i32,
+    pub username: String,
+    pub jump_via: Option<i32>,
+    /// `None` keeps the stored key
+    pub key_fingerprint: Option<String>,
+}
+
+/// An authorization that was dropped because it expired
+pub struct ExpiredAuthorization {
+    pub host: Host,
+    pub user_id: i32,
+    pub username: String,
+    pub login: String,
+}
+
 impl Host {
     pub fn to_connection(&self) -> Result<ConnectionDetails, SshClientError> {
         Ok(ConnectionDetails::new(
@@ -30,9 +64,102 @@ impl Host {
         ))
     }
 
-    /// Adds a new host to the database
+    /// Adds a new host to the database. Returns the id of the new host
     pub fn add_host(conn: &mut DbConnection, host: &NewHost) -> Result<i32, String> {
-        query(insert_into(host::table).values(host.clone()).execute(conn)).map(|id| (id as i32))
+        query(insert_into(host::table).values(host.clone()).execute(conn))?;
+        query(
+            host::table
+                .filter(host::name.eq(&host.name))
+                .select(host::id)
+                .first::<i32>(conn),
+        )
+    }
+
+    /// Whether following the jump hosts from `from` leads to `target`
+    pub fn jumps_reach(conn: &mut DbConnection, from: i32, target: i32) -> QueryResult<bool> {
+        let mut seen = std::collections::BTreeSet::new();
+        let mut next = Some(from);
+        while let Some(id) = next {
+            if id == target {
+                return Ok(true);
+            }
+            if !seen.insert(id) {
+                return Ok(false);
+            }
+            next = host::table
+                .find(id)
+                .select(host::jump_via)
+                .first::<Option<i32>>(conn)
+                .optional()?
+                .flatten();
+        }
+        Ok(false)
+    }
+
+    /// Creates the host or updates what differs from the desired state. A given
+    /// key has to be verified by the caller and is trusted like one verified by hand.
+    pub fn put_host(
+        conn: &mut DbConnection,
+        name: &str,
+        desired: &DesiredHost,
+        observed_until: Option<time::PrimitiveDateTime>,
+    ) -> Result<PutOutcome, String> {
+        let res = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            let existing = host::table
+                .filter(host::name.eq(name))
+                .first::<Self>(conn)
+                .optional()?;
+            let Some(existing) = existing else {
+                insert_into(host::table)
+                    .values((
+                        host::name.eq(name),
+                        host::address.eq(&desired.address),
+                        host::port.eq(desired.port),
+                        host::username.eq(&desired.username),
+                        host::jump_via.eq(desired.jump_via),
+                        host::key_fingerprint.eq(&desired.key_fingerprint),
+                        host::key_trust.eq(desired.key_fingerprint.as_ref().map(|_| TRUST_MANUAL)),
+                        host::observed_until.eq(observed_until),
+                    ))
+                    .execute(conn)?;
+                return Ok(Ok(PutOutcome::Created));
+            };
+            if let Some(jump_via) = desired.jump_via {
+                if Self::jumps_reach(conn, jump_via, existing.id)? {
+                    return Ok(Err(String::from("Jump hosts can't form a cycle")));
+                }
+            }
+            let key_fingerprint = desired
+                .key_fingerprint
+                .clone()
+                .or_else(|| existing.key_fingerprint.clone());
+            if existing.address == desired.address
+                && existing.port == desired.port
+                && existing.username == desired.username
+                && existing.jump_via == desired.jump_via
+                && existing.key_fingerprint == key_fingerprint
+            {
+                return Ok(Ok(PutOutcome::Unchanged));
+            }
+            diesel::update(host::table.find(existing.id))
+                .set((
+                    host::address.eq(&desired.address),
+                    host::port.eq(desired.port),
+                    host::username.eq(&desired.username),
+                    host::jump_via.eq(desired.jump_via),
+                ))
+                .execute(conn)?;
+            if key_fingerprint != existing.key_fingerprint {
+                diesel::update(host::table.find(existing.id))
+                    .set((
+                        host::key_fingerprint.eq(key_fingerprint),
+                        host::key_trust.eq(TRUST_MANUAL),
+                    ))
+                    .execute(conn)?;
+            }
+            Ok(Ok(PutOutcome::Updated))
+        });
+        query(res)?
     }
 
     pub fn authorize_user(
@@ -40,11 +167,16 @@ impl Host {
         host_id: i32,
         user_id: i32,
         login: String,
-        mut options: Option<String>,
+        options: Option<String>,
+        expires_at: Option<time::PrimitiveDateTime>,
     ) -> Result<(), String> {
-        if options.as_ref().is_some_and(String::is_empty) {
-            options = None;
+        let options = KeyOptions::normalize(options)?;
+        if expires_at.is_some_and(|expires_at| expires_at <= now()) {
+            return Err(String::from("The expiry date has to be in the future"));
         }
+        let host =
+            Self::get_from_id_sync(conn, host_id)?.ok_or_else(|| String::from("Host not found"))?;
+        policy::enforce(conn, &host, user_id, &login)?;
         query_drop(
             insert_into(authorization::table)
                 .values((
@@ -52,65 +184,247 @@ impl Host {
                     authorization::user_id.eq(user_id),
                     authorization::login.eq(login),
                     authorization::options.eq(options),
+                    authorization::expires_at.eq(expires_at),
                 ))
                 .execute(conn),
         )
     }
 
-    /// Get authorized Users and associated options
-    pub fn get_authorized_users(
+    /// Authorizes a user on all of `hosts` at once, or on none of them if one fails. Hosts
+    /// where the user already has this login are skipped. Returns the names of the hosts
+    /// authorized and skipped.
+    pub fn authorize_user_on_hosts(
+        conn: &mut DbConnection,
+        hosts: &[Self],
+        user_id: i32,
+        login: &str,
+        options: Option<String>,
+    ) -> Result<(Vec<String>, Vec<String>), String> {
+        let options = KeyOptions::normalize(options)?;
+        for host in hosts {
+            policy::enforce(conn, host, user_id, login)?;
+        }
+        let (mut authorized, mut skipped) = (Vec::new(), Vec::new());
+        let res = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            for host in hosts {
+                let existing = authorization::table
+                    .filter(authorization::host_id.eq(host.id))
+                    .filter(authorization::user_id.eq(user_id))
+                    .filter(authorization::login.eq(login))
+                    .count()
+                    .get_result::<i64>(conn)?;
+                if existing > 0 {
+                    skipped.push(host.name.clone());
+                    continue;
+                }
+                insert_into(authorization::table)
+                    .values((
+                        authorization::host_id.eq(host.id),
+                        authorization::user_id.eq(user_id),
+                        authorization::login.eq(login),
+                        authorization::options.eq(&options),
+                    ))
+                    .execute(conn)?;
+                authorized.push(host.name.clone());
+            }
+            Ok(())
+        });
+        query(res).map(|()| (authorized, skipped))
+    }
+
+    /// Authorizations on `source` that this host doesn't have yet, with the same user and login
+    fn missing_authorizations(
         &self,
         conn: &mut DbConnection,
+        source: &Self,
+    ) -> QueryResult<Vec<(i32, UserAndOptions)>> {
+        let existing: Vec<(i32, String)> = authorization::table
+            .filter(authorization::host_id.eq(self.id))
+            .select((authorization::user_id, authorization::login))
+            .load(conn)?;
+        Ok(authorization::table
+            .inner_join(user::table)
+            .filter(authorization::host_id.eq(source.id))
+            .order((user::username, authorization::login))
+            .select((
+                authorization::id,
+                user::id,
+                user::username,
+                authorization::login,
+                authorization::options,
+            ))
+            .load::<(i32, i32, String, String, Option<String>)>(conn)?
+            .into_iter()
+            .filter(|(_, user_id, _, login, _)| !existing.contains(&(*user_id, login.clone())))
+            .map(|(id, user_id, username, login, options)| {
+                (user_id, (id, username, login, options))
+            })
+            .collect())
+    }
+
+    /// The authorizations that copying those of `source` would add
+    pub fn get_missing_authorizations(
+        &self,
+        conn: &mut DbConnection,
+        source: &Self,
     ) -> Result<Vec<UserAndOptions>, String> {
-        // let user_ids = self.get_authorized_user_ids(conn)?;
+        query(self.missing_authorizations(conn, source))
+            .map(|missing| missing.into_iter().map(|(_, entry)| entry).collect())
+    }
 
-        query(
-            authorization::table
-                .inner_join(user::table)
-                .filter(authorization::host_id.eq(self.id))
-                .select((
-                    authorization::id,
-                    user::username,
-                    authorization::login,
-                    authorization::options,
-                ))
-                .load::<UserAndOptions>(conn),
+    /// Copies the authorizations of `source` that this host doesn't have yet, options included,
+    /// all at once. Returns the copied authorizations.
+    pub fn copy_authorizations_from(
+        &self,
+        conn: &mut DbConnection,
+        source: &Self,
+    ) -> Result<Vec<UserAndOptions>, String> {
+        for (user_id, (_, _, login, _)) in query(self.missing_authorizations(conn, source))? {
+            policy::enforce(conn, self, user_id, &login)?;
+        }
+        let res = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            let missing = self.missing_authorizations(conn, source)?;
+            let mut copied = Vec::with_capacity(missing.len());
+            for (user_id, (id, username, login, options)) in missing {
+                insert_into(authorization::table)
+                    .values((
+                        authorization::host_id.eq(self.id),
+                        authorization::user_id.eq(user_id),
+                        authorization::login.eq(&login),
+                        authorization::options.eq(&options),
+                    ))
+                    .execute(conn)?;
+                copied.push((id, username, login, options));
+            }
+            Ok(copied)
+        });
+        query(res)
+    }
+
+    /// The options actually written for an authorization, after applying the options of the
+    /// rule granting it, the defaults of this host and the user and this host's forwarding policy
+    pub fn effective_options(
+        &self,
+        options: Option<&str>,
+        group_options: Option<&str>,
+        user_defaults: Option<&str>,
+    ) -> Result<Option<String>, String> {
+        let options = OptionSources {
+            explicit: options,
+            group: group_options,
+            host: self.default_options.as_deref(),
+            user: user_defaults,
+            deny_forwarding: self.forbid_forwarding,
+        }
+        .resolve()?;
+        Ok((!options.is_empty()).then(|| options.to_string()))
+    }
+
+    /// Sets the options of authorizations on this host that don't set them themselves
+    pub fn set_default_options(
+        &self,
+        conn: &mut DbConnection,
+        options: Option<String>,
+    ) -> Result<(), String> {
+        let options = KeyOptions::normalize(options)?;
+        query_drop(
+            diesel::update(host::table.filter(host::id.eq(self.id)))
+                .set(host::default_options.eq(options))
+                .execute(conn),
         )
     }
 
-    /// Get a host from a name
-    pub async fn get_from_name(
-        mut conn: PooledConnection<ConnectionManager<DbConnection>>,
-        host: String,
-    ) -> Result<Option<Self>, String> {
-        actix_web::web::block(move || {
-            query(
-                host::table
-                    .filter(host::name.eq(host))
-                    .first::<Self>(&mut conn)
-                    .optional(),
-            )
+    pub fn set_forbid_forwarding(
+        &self,
+        conn: &mut DbConnection,
+        forbid: bool,
+    ) -> Result<(), String> {
+        query_drop(
+            diesel::update(host::table.filter(host::id.eq(self.id)))
+                .set(host::forbid_forwarding.eq(forbid))
+                .execute(conn),
+        )
+    }
+
+    /// Sets the web user approving access requests for this host
+    pub fn set_owner(&self, conn: &mut DbConnection, owner: Option<String>) -> Result<(), String> {
+        query_drop(
+            diesel::update(host::table.filter(host::id.eq(self.id)))
+                .set(host::owner.eq(owner))
+                .execute(conn),
+        )
+    }
+
+    /// Sets how early fleet operations process this host and how loud its drift is alerted
+    pub fn set_importance(&self, conn: &mut DbConnection, importance: &str) -> Result<(), String> {
+        if !IMPORTANCES.contains(&importance) {
+            return Err(format!("Unknown importance '{importance}'"));
+        }
+        query_drop(
+            diesel::update(host::table.filter(host::id.eq(self.id)))
+                .set(host::importance.eq(importance))
+                .execute(conn),
+        )
+    }
+
+    /// Until when ssm only reports the drift of this host, if it is still observed
+    pub fn observed_until(&self) -> Option<time::PrimitiveDateTime> {
+        self.observed_until.filter(|until| *until > now())
+    }
+
+    /// How much longer the host is observed, e.g. "3 days 4 hours"
+    pub fn observation_left(&self) -> Option<String> {
+        let left = self.observed_until()? - now();
+        let (days, hours) = (left.whole_days(), left.whole_hours() % 24);
+        Some(match (days, hours) {
+            (0, 0) => String::from("less than an hour"),
+            (0, hours) => format!("{hours} hour(s)"),
+            (days, 0) => format!("{days} day(s)"),
+            (days, hours) => format!("{days} day(s) {hours} hour(s)"),
         })
-        .await
-        .map_err(|_| "Blocking error.".to_owned())?
+    }
+
+    /// Lets ssm change the host from now on
+    pub fn end_observation(&self, conn: &mut DbConnection) -> Result<(), String> {
+        query_drop(
+            diesel::update(host::table.filter(host::id.eq(self.id)))
+                .set(host::observed_until.eq(None::<time::PrimitiveDateTime>))
+                .execute(conn),
+        )
+    }
+
+    /// Get authorized Users and associated options, including those granted by group rules.
+    /// Access only granted by a rule has no authorization and uses 0 as its id.
+    pub fn get_authorized_users(
+        &self,
+        conn: &mut DbConnection,
+    ) -> Result<Vec<UserAndOptions>, String> {
+        Ok(self
+            .get_access(conn)?
+            .into_iter()
+            .map(|access| {
+                (
+                    access.authorization.unwrap_or(0),
+                    access.username,
+                    access.login,
+                    access.options.or(access.group_options),
+                )
+            })
+            .collect())
+    }
+
+    /// Get a host from a name
+    pub async fn get_from_name(db: &BlockingPool, host: String) -> Result<Option<Self>, String> {
+        db.run(move |conn| Self::get_from_name_sync(conn, host))
+            .await?
     }
 
     /// Get a host from an id
-    pub async fn get_from_id(
-        mut conn: PooledConnection<ConnectionManager<DbConnection>>,
-        host_id: i32,
-    ) -> Result<Option<Self>, String> {
-        actix_web::web::block(move || {
-            query(
-                host::table
-                    .filter(host::id.eq(host_id))
-                    .first::<Self>(&mut conn)
-                    .optional(),
-            )
-        })
-        .await
-        .map_err(|_| "Blocking error.".to_owned())?
+    pub async fn get_from_id(db: &BlockingPool, host_id: i32) -> Result<Option<Self>, String> {
+        db.run(move |conn| Self::get_from_id_sync(conn, host_id))
+            .await?
     }
+
     /// Get a host from a name
     pub fn get_from_name_sync(
         conn: &mut DbConnection,
@@ -137,31 +451,74 @@ impl Host {
         query(host::table.load::<Self>(conn))
     }
 
-    /// Gets all allowed users allowed on this host, sorted by login
+    /// All hosts, critical ones first and low ones last, by name within each importance
+    pub fn get_all_by_priority(conn: &mut DbConnection) -> Result<Vec<Self>, String> {
+        let mut hosts = query(host::table.order(host::name).load::<Self>(conn))?;
+        hosts.sort_by_key(Self::priority);
+        Ok(hosts)
+    }
+
+    /// Position in fleet operations, lower goes first
+    pub fn priority(&self) -> usize {
+        IMPORTANCES
+            .iter()
+            .position(|importance| *importance == self.importance)
+            .unwrap_or(1)
+    }
+
+    pub fn is_critical(&self) -> bool {
+        self.importance == CRITICAL
+    }
+
+    /// The hosts with these names, failing on the first unknown one
+    pub fn get_all_named(conn: &mut DbConnection, names: &[String]) -> Result<Vec<Self>, String> {
+        names
+            .iter()
+            .map(|name| {
+                Self::get_from_name_sync(conn, name.clone())?
+                    .ok_or_else(|| format!("There is no host {name}"))
+            })
+            .collect()
+    }
+
+    /// Gets all allowed users allowed on this host, sorted by login. Expired keys are left out.
     pub fn get_authorized_keys(
         &self,
         conn: &mut DbConnection,
     ) -> Result<AuthorizedKeysList, String> {
-        query(
-            user::table
-                .inner_join(user_key::table)
-                .inner_join(authorization::table)
-                .select((
-                    PublicUserKey::as_select(),
-                    authorization::login,
-                    user::username,
-                    authorization::options,
-                ))
-                .filter(authorization::host_id.eq(self.id))
-                .order(authorization::login.desc())
-                .load::<(PublicUserKey, String, String, Option<String>)>(conn),
-        )
-        .map(|allowed_list| {
-            allowed_list
-                .into_iter()
-                .map(AllowedUserOnHost::from)
-                .collect()
-        })
+        let access = self.get_access(conn)?;
+        let user_ids: Vec<i32> = access.iter().map(|access| access.user_id).collect();
+        let keys = query(
+            user_key::table
+                .filter(user_key::user_id.eq_any(user_ids))
+                .filter(
+                    user_key::expires_at
+                        .is_null()
+                        .or(user_key::expires_at.gt(now())),
+                )
+                .select(PublicUserKey::as_select())
+                .load::<PublicUserKey>(conn),
+        )?;
+
+        let mut allowed_list = Vec::with_capacity(keys.len());
+        for access in access {
+            let options = self.effective_options(
+                access.options.as_deref(),
+                access.group_options.as_deref(),
+                access.user_defaults.as_deref(),
+            )?;
+            for key in keys.iter().filter(|key| key.user_id == access.user_id) {
+                allowed_list.push(AllowedUserOnHost {
+                    key: key.clone(),
+                    login: access.login.clone(),
+                    username: access.username.clone(),
+                    options: options.clone(),
+                    team: access.team.clone(),
+                });
+            }
+        }
+        allowed_list.sort_by(|a, b| b.login.cmp(&a.login));
+        Ok(allowed_list)
     }
 
     /// Generate authorized key file for a login on a host. Includes ssm key, if applicable
@@ -171,31 +528,24 @@ impl Host {
         conn: &mut DbConnection,
         login: &str,
     ) -> Result<String, String> {
-        let res: Vec<(PublicUserKey, Option<String>)> = query(
-            user::table
-                .inner_join(user_key::table)
-                .inner_join(authorization::table)
-                .select((PublicUserKey::as_select(), authorization::options))
-                .filter(authorization::host_id.eq(self.id))
-                .filter(authorization::login.eq(login))
-                .load::<(PublicUserKey, Option<String>)>(conn),
-        )?;
+        let res: Vec<AllowedUserOnHost> = self
+            .get_authorized_keys(conn)?
+            .into_iter()
+            .filter(|allowed| allowed.login == login)
+            .collect();
 
-        let estimated_size = (res.len() + 2) * 150;
-
-        Ok(res.into_iter().fold(
-            String::with_capacity(estimated_size),
-            |buf, (key, options)| {
-                buf + options.map_or_else(String::new, |o| o + " ").as_str()
-                    + key.to_openssh().as_str()
-                    + "\n"
-            },
-        ) + (if self.username.eq(&login) {
-            ssh_client.get_own_key_openssh() + "\n"
+        let own_keys = if self.username.eq(&login) {
+            std::iter::once(ssh_client.get_own_key_openssh())
+                .chain(ssh_client.get_break_glass_keys().iter().cloned())
+                .collect()
         } else {
-            String::new()
-        })
-        .as_str())
+            Vec::new()
+        };
+        Ok(render_authorized_keys(
+            res,
+            &own_keys,
+            ssh_client.key_order(),
+        ))
     }
 
     pub fn get_dependant_hosts(&self, conn: &mut DbConnection) -> Result<Vec<String>, String> {
@@ -211,6 +561,57 @@ impl Host {
         query(diesel::delete(host::table.filter(host::id.eq(self.id))).execute(conn))
     }
 
+    /// When the authorizations of this host expire, by authorization id
+    pub fn get_authorization_expiries(
+        &self,
+        conn: &mut DbConnection,
+    ) -> Result<BTreeMap<i32, time::PrimitiveDateTime>, String> {
+        let expiries = query(
+            authorization::table
+                .filter(authorization::host_id.eq(self.id))
+                .filter(authorization::expires_at.is_not_null())
+                .select((authorization::id, authorization::expires_at))
+                .load::<(i32, Option<time::PrimitiveDateTime>)>(conn),
+        )?;
+        Ok(expiries
+            .into_iter()
+            .filter_map(|(id, expires_at)| Some((id, expires_at?)))
+            .collect())
+    }
+
+    /// Deletes every authorization that expired and returns them
+    pub fn delete_expired_authorizations(
+        conn: &mut DbConnection,
+    ) -> Result<Vec<ExpiredAuthorization>, String> {
+        let res = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            let expired = authorization::table
+                .inner_join(host::table)
+                .inner_join(user::table)
+                .filter(authorization::expires_at.le(now()))
+                .select((
+                    authorization::id,
+                    Self::as_select(),
+                    user::id,
+                    user::username,
+                    authorization::login,
+                ))
+                .load::<(i32, Self, i32, String, String)>(conn)?;
+            let ids: Vec<i32> = expired.iter().map(|(id, ..)| *id).collect();
+            diesel::delete(authorization::table.filter(authorization::id.eq_any(ids)))
+                .execute(conn)?;
+            Ok(expired
+                .into_iter()
+                .map(|(_, host, user_id, username, login)| ExpiredAuthorization {
+                    host,
+                    user_id,
+                    username,
+                    login,
+                })
+                .collect())
+        });
+        query(res)
+    }
+
     pub fn delete_authorization(conn: &mut DbConnection, authorization: i32) -> Result<(), String> {
         query_drop(
             diesel::delete(authorization::table.filter(authorization::id.eq(authorization)))
@@ -218,15 +619,43 @@ impl Host {
         )
     }
 
+    /// Remembers that the authorized_keys of a login was just written
+    pub fn record_deployment(&self, conn: &mut DbConnection, login: &str) -> Result<(), String> {
+        let updated = query(
+            diesel::update(deployment::table)
+                .filter(deployment::host_id.eq(self.id))
+                .filter(deployment::login.eq(login))
+                .set(deployment::deployed_at.eq(now()))
+                .execute(conn),
+        )?;
+        if updated > 0 {
+            return Ok(());
+        }
+        query_drop(
+            insert_into(deployment::table)
+                .values((
+                    deployment::host_id.eq(self.id),
+                    deployment::login.eq(login),
+                    deployment::deployed_at.eq(now()),
+                ))
+                .execute(conn),
+        )
+    }
+
+    /// Stores a new host key, with how it was verified
     pub fn update_fingerprint(
         &self,
         conn: &mut DbConnection,
         fingerprint: String,
+        trust: &str,
     ) -> Result<(), String> {
         query_drop(
             diesel::update(host::table)
                 .filter(host::id.eq(self.id))
-                .set(host::key_fingerprint.eq(fingerprint))
+                .set((
+                    host::key_fingerprint.eq(fingerprint),
+                    host::key_trust.eq(trust),
+                ))
                 .execute(conn),
         )
     }
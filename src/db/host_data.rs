@@ -0,0 +1,146 @@
+use std::{collections::BTreeMap, fmt};
+
+use diesel::prelude::*;
+use log::error;
+
+use crate::schema::{authorization, deployment, host, user, user_key};
+use crate::{
+    models::{Host, User},
+    DbConnection,
+};
+
+use super::UserAndOptions;
+
+diesel::alias!(host as jumphost: JumphostAlias);
+
+/// Everything the host page and the host API show about a host
+pub struct HostData {
+    pub host: Host,
+    /// Name of the jump host
+    pub jumphost: Option<String>,
+    pub authorized_users: Vec<UserAndOptions>,
+    /// When authorizations expire, by authorization id
+    pub expiries: BTreeMap<i32, time::PrimitiveDateTime>,
+    /// Keys (base64) of the authorized users, by username
+    pub user_keys: BTreeMap<String, Vec<String>>,
+    /// When the authorized_keys of a login was last written, by login
+    pub deployments: BTreeMap<String, time::PrimitiveDateTime>,
+    /// Users that can be authorized, empty if the host key is unknown
+    pub user_list: Vec<User>,
+}
+
+#[derive(Debug)]
+pub enum HostDataError {
+    HostNotFound,
+    Database(diesel::result::Error),
+}
+
+impl From<diesel::result::Error> for HostDataError {
+    fn from(e: diesel::result::Error) -> Self {
+        error!("Encountered a database error: {e}");
+        Self::Database(e)
+    }
+}
+
+impl fmt::Display for HostDataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::HostNotFound => write!(f, "Host not found"),
+            Self::Database(_) => write!(f, "A database error occured. Please consult the logs."),
+        }
+    }
+}
+
+impl std::error::Error for HostDataError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::HostNotFound => None,
+            Self::Database(e) => Some(e),
+        }
+    }
+}
+
+impl HostData {
+    /// Loads a host together with its jump host and authorizations in one query
+    pub fn load(conn: &mut DbConnection, name: &str) -> Result<Self, HostDataError> {
+        let rows = host::table
+            .left_join(jumphost.on(host::jump_via.eq(jumphost.field(host::id).nullable())))
+            .left_join(authorization::table.inner_join(user::table))
+            .filter(host::name.eq(name))
+            .order(authorization::login)
+            .select((
+                Host::as_select(),
+                jumphost.field(host::name).nullable(),
+                (
+                    authorization::id,
+                    user::username,
+                    authorization::login,
+                    authorization::options,
+                )
+                    .nullable(),
+                authorization::expires_at.nullable(),
+            ))
+            .load::<(
+                Host,
+                Option<String>,
+                Option<UserAndOptions>,
+                Option<time::PrimitiveDateTime>,
+            )>(conn)?;
+
+        let mut rows = rows.into_iter();
+        let Some((host, jumphost_name, first, first_expiry)) = rows.next() else {
+            return Err(HostDataError::HostNotFound);
+        };
+        let mut expiries = BTreeMap::new();
+        let mut authorized_users = Vec::new();
+        for (authorization, expires_at) in std::iter::once((first, first_expiry))
+            .chain(rows.map(|(_, _, authorization, expires_at)| (authorization, expires_at)))
+        {
+            let Some(authorization) = authorization else {
+                continue;
+            };
+            if let Some(expires_at) = expires_at {
+                expiries.insert(authorization.0, expires_at);
+            }
+            authorized_users.push(authorization);
+        }
+
+        let usernames: Vec<&String> = authorized_users
+            .iter()
+            .map(|(_, username, _, _)| username)
+            .collect();
+        let mut user_keys: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (username, key) in user_key::table
+            .inner_join(user::table)
+            .filter(user::username.eq_any(usernames))
+            .select((user::username, user_key::key_base64))
+            .load::<(String, String)>(conn)?
+        {
+            user_keys.entry(username).or_default().push(key);
+        }
+
+        let deployments = deployment::table
+            .filter(deployment::host_id.eq(host.id))
+            .select((deployment::login, deployment::deployed_at))
+            .load::<(String, time::PrimitiveDateTime)>(conn)?
+            .into_iter()
+            .collect();
+
+        // Skip getting users if we can't connect
+        let user_list = if host.key_fingerprint.is_some() {
+            user::table.load::<User>(conn)?
+        } else {
+            Vec::new()
+        };
+
+        Ok(Self {
+            host,
+            jumphost: jumphost_name,
+            authorized_users,
+            expiries,
+            user_keys,
+            deployments,
+            user_list,
+        })
+    }
+}
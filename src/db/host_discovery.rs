@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+
+use diesel::{dsl::insert_into, prelude::*, result::Error};
+use log::{info, warn};
+
+use crate::{
+    models::Finding,
+    schema::{host, host_discovery},
+    DbConnection,
+};
+
+use super::query;
+
+/// Finding about a discovered host that its source doesn't list anymore
+pub const HOST_GONE: &str = "host_gone";
+
+/// A host as a source lists it
+pub struct DiscoveredHost {
+    /// Id at the source, e.g. the EC2 instance id
+    pub external_id: String,
+    /// Name of the host, with the id appended if another host has it
+    pub name: String,
+    /// `None` while it has none, e.g. a stopped instance without public address.
+    /// Such hosts aren't created, and existing ones keep their address.
+    pub address: Option<String>,
+}
+
+/// What a discovery run changed
+#[derive(Debug, Default)]
+pub struct DiscoverySummary {
+    pub created: usize,
+    /// Hosts whose name or address changed
+    pub updated: usize,
+    /// Newly flagged hosts the source doesn't list anymore
+    pub gone: usize,
+}
+
+/// A name that no other host has, the source's id is added if needed
+fn free_name(conn: &mut DbConnection, found: &DiscoveredHost) -> Result<Option<String>, Error> {
+    for name in [
+        found.name.clone(),
+        format!("{}-{}", found.name, found.external_id),
+    ] {
+        let taken = host::table
+            .filter(host::name.eq(&name))
+            .count()
+            .get_result::<i64>(conn)?;
+        if taken == 0 {
+            return Ok(Some(name));
+        }
+    }
+    Ok(None)
+}
+
+/// Creates the hosts a source lists for the first time, without a host key, and updates
+/// the name and address of those it found before. Hosts it found before but doesn't list anymore
+/// are flagged with a finding, `gone` tells why given the id and the host name. Hosts are never deleted.
+pub fn sync_discovered_hosts(
+    conn: &mut DbConnection,
+    source: &str,
+    username: &str,
+    observed_until: Option<time::PrimitiveDateTime>,
+    found: Vec<DiscoveredHost>,
+    gone: impl Fn(&str, &str) -> String,
+) -> Result<DiscoverySummary, String> {
+    let mut summary = DiscoverySummary::default();
+    let res = conn.transaction::<_, Error, _>(|conn| {
+        let mut known: HashMap<String, (i32, String, String)> = host_discovery::table
+            .inner_join(host::table)
+            .filter(host_discovery::source.eq(source))
+            .select((
+                host_discovery::external_id,
+                host::id,
+                host::name,
+                host::address,
+            ))
+            .load::<(String, i32, String, String)>(conn)?
+            .into_iter()
+            .map(|(external_id, id, name, address)| (external_id, (id, name, address)))
+            .collect();
+
+        for found in found {
+            if let Some((id, mut name, address)) = known.remove(&found.external_id) {
+                let mut updated = false;
+                let fallback = format!("{}-{}", found.name, found.external_id);
+                if name != found.name && name != fallback {
+                    if let Some(new_name) = free_name(conn, &found)? {
+                        diesel::update(host::table.find(id))
+                            .set(host::name.eq(&new_name))
+                            .execute(conn)?;
+                        info!("{name} was renamed to {new_name} at {source}");
+                        name = new_name;
+                        updated = true;
+                    }
+                }
+                match found.address {
+                    Some(new_address) if new_address != address => {
+                        diesel::update(host::table.find(id))
+                            .set(host::address.eq(&new_address))
+                            .execute(conn)?;
+                        info!(
+                            "The address of {name} changed from {address} to {new_address} at {source}"
+                        );
+                        updated = true;
+                    }
+                    _ => {}
+                }
+                if updated {
+                    summary.updated += 1;
+                }
+                continue;
+            }
+            let Some(address) = &found.address else {
+                continue;
+            };
+
+            let Some(name) = free_name(conn, &found)? else {
+                warn!(
+                    "Not adding {} from {source}, a host named {} exists",
+                    found.external_id, found.name
+                );
+                continue;
+            };
+            insert_into(host::table)
+                .values((
+                    host::name.eq(&name),
+                    host::address.eq(address),
+                    host::port.eq(22),
+                    host::username.eq(username),
+                    host::observed_until.eq(observed_until),
+                ))
+                .execute(conn)?;
+            let host_id = host::table
+                .filter(host::name.eq(&name))
+                .select(host::id)
+                .first::<i32>(conn)?;
+            insert_into(host_discovery::table)
+                .values((
+                    host_discovery::host_id.eq(host_id),
+                    host_discovery::source.eq(source),
+                    host_discovery::external_id.eq(&found.external_id),
+                ))
+                .execute(conn)?;
+            info!("Added {name} ({address}) from {source}");
+            summary.created += 1;
+        }
+        Ok(known)
+    });
+
+    // Whatever is left wasn't listed this time
+    for (external_id, (_, name, _)) in query(res)? {
+        let details = gone(&external_id, &name);
+        if Finding::raise(conn, HOST_GONE, &format!("host:{name}"), &details)? {
+            summary.gone += 1;
+        }
+    }
+    Ok(summary)
+}
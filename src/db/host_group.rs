@@ -0,0 +1,126 @@
+use diesel::dsl::insert_into;
+use diesel::prelude::*;
+
+use crate::schema::{host, host_group, host_group_member};
+use crate::{
+    models::{GroupAuthorization, Host, HostGroup},
+    DbConnection,
+};
+
+use super::{group_name, query, query_drop};
+
+impl HostGroup {
+    /// All groups, sorted by name
+    pub fn get_all(conn: &mut DbConnection) -> Result<Vec<Self>, String> {
+        query(host_group::table.order(host_group::name).load::<Self>(conn))
+    }
+
+    pub fn get_from_name(conn: &mut DbConnection, name: &str) -> Result<Option<Self>, String> {
+        query(
+            host_group::table
+                .filter(host_group::name.eq(name))
+                .first::<Self>(conn)
+                .optional(),
+        )
+    }
+
+    /// Names of the groups of every host that is in one, as host id and group name
+    pub fn get_memberships(conn: &mut DbConnection) -> Result<Vec<(i32, String)>, String> {
+        query(
+            host_group_member::table
+                .inner_join(host_group::table)
+                .order(host_group::name)
+                .select((host_group_member::host_id, host_group::name))
+                .load(conn),
+        )
+    }
+
+    /// The hosts in this group, sorted by name
+    pub fn get_hosts(&self, conn: &mut DbConnection) -> Result<Vec<Host>, String> {
+        query(
+            host_group_member::table
+                .inner_join(host::table)
+                .filter(host_group_member::group_id.eq(self.id))
+                .order(host::name)
+                .select(Host::as_select())
+                .load(conn),
+        )
+    }
+
+    /// Deletes the group, its hosts stay
+    pub fn delete(self, conn: &mut DbConnection) -> Result<(), String> {
+        query_drop(diesel::delete(host_group::table.find(self.id)).execute(conn))
+    }
+}
+
+impl Host {
+    /// The groups of this host, sorted by name
+    pub fn get_groups(&self, conn: &mut DbConnection) -> Result<Vec<HostGroup>, String> {
+        query(
+            host_group_member::table
+                .inner_join(host_group::table)
+                .filter(host_group_member::host_id.eq(self.id))
+                .order(host_group::name)
+                .select(HostGroup::as_select())
+                .load(conn),
+        )
+    }
+
+    /// Adds this host to a group, which is created if it doesn't exist
+    pub fn add_to_group(&self, conn: &mut DbConnection, name: &str) -> Result<(), String> {
+        let name = group_name(name)?;
+
+        let group = match HostGroup::get_from_name(conn, name)? {
+            Some(group) => group,
+            None => {
+                query_drop(
+                    insert_into(host_group::table)
+                        .values(host_group::name.eq(name))
+                        .execute(conn),
+                )?;
+                HostGroup::get_from_name(conn, name)?
+                    .ok_or_else(|| format!("Failed to create group {name}"))?
+            }
+        };
+        if self.get_groups(conn)?.iter().any(|g| g.id == group.id) {
+            return Err(format!("{} is already in {name}", self.name));
+        }
+        GroupAuthorization::enforce_for_host(conn, &group, self)?;
+        query_drop(
+            insert_into(host_group_member::table)
+                .values((
+                    host_group_member::group_id.eq(group.id),
+                    host_group_member::host_id.eq(self.id),
+                ))
+                .execute(conn),
+        )
+    }
+
+    /// Removes this host from a group. Groups without hosts are kept.
+    pub fn remove_from_group(&self, conn: &mut DbConnection, name: &str) -> Result<(), String> {
+        let group = HostGroup::get_from_name(conn, name)?
+            .ok_or_else(|| format!("There is no group {name}"))?;
+        query_drop(
+            diesel::delete(
+                host_group_member::table
+                    .filter(host_group_member::group_id.eq(group.id))
+                    .filter(host_group_member::host_id.eq(self.id)),
+            )
+            .execute(conn),
+        )
+    }
+
+    /// All hosts, or only those in a group
+    pub fn get_all_hosts_in(
+        conn: &mut DbConnection,
+        group: Option<&str>,
+    ) -> Result<Vec<Self>, String> {
+        let Some(group) = group else {
+            return Self::get_all_hosts(conn);
+        };
+        match HostGroup::get_from_name(conn, group)? {
+            Some(group) => group.get_hosts(conn),
+            None => Ok(Vec::new()),
+        }
+    }
+}
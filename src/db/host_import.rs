@@ -0,0 +1,425 @@
+use std::collections::HashSet;
+
+use diesel::{dsl::insert_into, prelude::*, result::Error};
+use serde::Deserialize;
+
+use crate::{
+    ansible::{parse_ini_inventory, parse_yaml_inventory, AnsibleHost},
+    models::{GroupAuthorization, Host},
+    schema::{host, host_group, host_group_member},
+    ssh::{parse_ssh_config, KnownHosts, SshConfigHost, TRUST_KNOWN_HOSTS},
+    DbConnection,
+};
+
+use super::{group_name, query};
+
+/// Columns of a CSV row, the jumphost may be left out
+pub const HOST_CSV_COLUMNS: &str = "name,hostname,port,username,jumphost";
+
+/// What the hosts to import are described with
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HostImportFormat {
+    /// name,hostname,port,username,jumphost per line
+    #[default]
+    Csv,
+    /// An OpenSSH client configuration, like `~/.ssh/config`
+    SshConfig,
+    /// An Ansible inventory in the INI format
+    AnsibleIni,
+    /// An Ansible inventory in the YAML format
+    AnsibleYaml,
+}
+
+impl HostImportFormat {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::SshConfig => "ssh_config",
+            Self::AnsibleIni => "ansible_ini",
+            Self::AnsibleYaml => "ansible_yaml",
+        }
+    }
+}
+
+/// A row that can be added as a host
+pub struct HostImportRow {
+    /// Line number in the file, counting from 1
+    pub line: usize,
+    /// The line as it was in the file
+    content: String,
+    pub name: String,
+    pub address: String,
+    pub port: i32,
+    pub username: String,
+    /// Name of an existing host or of another row
+    pub jumphost: Option<String>,
+    /// Taken from a known_hosts file
+    pub key_fingerprint: Option<String>,
+    /// Host groups to add the host to, created if they don't exist
+    pub groups: Vec<String>,
+}
+
+/// A row that was refused, with the reason
+pub struct FailedHostImportRow {
+    pub line: usize,
+    pub content: String,
+    pub error: String,
+}
+
+/// The rows of a file of hosts, checked against each other and the database
+#[derive(Default)]
+pub struct HostImport {
+    pub rows: Vec<HostImportRow>,
+    pub failed: Vec<FailedHostImportRow>,
+}
+
+/// Splits a line at commas outside of double quotes, which are doubled inside quotes
+fn split_line(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            ('"', true) => quoted = false,
+            ('"', false) if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            (',', false) => fields.push(std::mem::take(&mut field)),
+            (c, _) => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(String::from("Unterminated quote"));
+    }
+    fields.push(field);
+    Ok(fields.into_iter().map(|f| f.trim().to_owned()).collect())
+}
+
+/// An empty port is 22
+fn parse_port(port: &str) -> Result<i32, String> {
+    match port {
+        "" => Ok(22),
+        port => match port.parse::<u16>() {
+            Ok(port) if port > 0 => Ok(i32::from(port)),
+            _ => Err(format!("Invalid port '{port}'")),
+        },
+    }
+}
+
+fn parse_csv_row(line: usize, content: &str) -> Result<HostImportRow, String> {
+    let fields = split_line(content)?;
+    if !(4..=5).contains(&fields.len()) {
+        return Err(format!(
+            "Expected the columns {HOST_CSV_COLUMNS}, found {} columns",
+            fields.len()
+        ));
+    }
+    let mut fields = fields.into_iter();
+    let mut next = || fields.next().unwrap_or_default();
+    let (name, address, port, username, jumphost) = (next(), next(), next(), next(), next());
+
+    if name.is_empty() {
+        return Err(String::from("The name is empty"));
+    }
+    if address.is_empty() {
+        return Err(String::from("The hostname is empty"));
+    }
+    if username.is_empty() {
+        return Err(String::from("The username is empty"));
+    }
+    Ok(HostImportRow {
+        line,
+        content: content.to_owned(),
+        name,
+        address,
+        port: parse_port(&port)?,
+        username,
+        jumphost: Some(jumphost).filter(|j| !j.is_empty()),
+        key_fingerprint: None,
+        groups: Vec::new(),
+    })
+}
+
+/// Empty lines, lines starting with `#` and a header are skipped
+fn parse_csv(csv: &str, checked: &mut HostImport) -> Vec<HostImportRow> {
+    let mut parsed = Vec::new();
+    for (index, content) in csv.lines().enumerate() {
+        let content = content.trim();
+        if content.is_empty() || content.starts_with('#') {
+            continue;
+        }
+        if index == 0
+            && split_line(content).is_ok_and(|fields| {
+                fields
+                    .first()
+                    .is_some_and(|f| f.eq_ignore_ascii_case("name"))
+            })
+        {
+            continue;
+        }
+        match parse_csv_row(index + 1, content) {
+            Ok(row) => parsed.push(row),
+            Err(error) => checked.fail(index + 1, content, error),
+        }
+    }
+    parsed
+}
+
+fn config_row(config_host: SshConfigHost, content: String) -> Result<HostImportRow, String> {
+    let username = config_host
+        .user
+        .clone()
+        .ok_or_else(|| String::from("No User is set"))?;
+    Ok(HostImportRow {
+        line: config_host.line,
+        content,
+        port: parse_port(config_host.port.as_deref().unwrap_or_default())?,
+        jumphost: config_host.jumphost()?,
+        name: config_host.alias,
+        address: config_host.hostname,
+        username,
+        key_fingerprint: None,
+        groups: Vec::new(),
+    })
+}
+
+/// Each alias of a `Host` line becomes a host, its ProxyJump the jumphost
+fn parse_config(config: &str, checked: &mut HostImport) -> Vec<HostImportRow> {
+    let mut parsed = Vec::new();
+    for config_host in parse_ssh_config(config) {
+        let (line, content) = (config_host.line, format!("Host {}", config_host.alias));
+        match config_row(config_host, content.clone()) {
+            Ok(row) => parsed.push(row),
+            Err(error) => checked.fail(line, &content, error),
+        }
+    }
+    parsed
+}
+
+fn ansible_row(ansible_host: AnsibleHost, content: String) -> Result<HostImportRow, String> {
+    let username = ansible_host
+        .user
+        .ok_or_else(|| String::from("No ansible_user is set"))?;
+    for group in &ansible_host.groups {
+        group_name(group)?;
+    }
+    Ok(HostImportRow {
+        line: ansible_host.line,
+        content,
+        port: parse_port(ansible_host.port.as_deref().unwrap_or_default())?,
+        jumphost: None,
+        name: ansible_host.name,
+        address: ansible_host.address,
+        username,
+        key_fingerprint: None,
+        groups: ansible_host.groups,
+    })
+}
+
+/// Each host of the inventory becomes a host in its groups and their parent groups
+fn parse_inventory(
+    inventory: &str,
+    format: HostImportFormat,
+    checked: &mut HostImport,
+) -> Result<Vec<HostImportRow>, String> {
+    let hosts = match format {
+        HostImportFormat::AnsibleYaml => parse_yaml_inventory(inventory)?,
+        _ => parse_ini_inventory(inventory)?,
+    };
+    let mut parsed = Vec::new();
+    for ansible_host in hosts {
+        let (line, content) = (ansible_host.line, ansible_host.name.clone());
+        match ansible_row(ansible_host, content.clone()) {
+            Ok(row) => parsed.push(row),
+            Err(error) => checked.fail(line, &content, error),
+        }
+    }
+    Ok(parsed)
+}
+
+impl HostImport {
+    /// Parses and validates every row, against each other and the existing hosts.
+    /// Host keys are looked up in the known_hosts file.
+    pub fn check(
+        conn: &mut DbConnection,
+        format: HostImportFormat,
+        text: &str,
+        known_hosts: &KnownHosts,
+    ) -> Result<Self, String> {
+        let mut checked = Self::default();
+        let parsed = match format {
+            HostImportFormat::Csv => parse_csv(text, &mut checked),
+            HostImportFormat::SshConfig => parse_config(text, &mut checked),
+            HostImportFormat::AnsibleIni | HostImportFormat::AnsibleYaml => {
+                parse_inventory(text, format, &mut checked)?
+            }
+        };
+
+        let existing: HashSet<String> = query(host::table.select(host::name).load(conn))?
+            .into_iter()
+            .collect();
+        let mut names = HashSet::new();
+        let mut valid = Vec::new();
+        for row in parsed {
+            if existing.contains(&row.name) {
+                checked.fail_row(&row, format!("A host named '{}' exists already", row.name));
+            } else if !names.insert(row.name.clone()) {
+                checked.fail_row(&row, format!("'{}' appears more than once", row.name));
+            } else {
+                valid.push(row);
+            }
+        }
+
+        // Jumphosts may be further down in the file, but not among the refused rows
+        loop {
+            let (known, unknown): (Vec<_>, Vec<_>) =
+                valid.into_iter().partition(|row| match &row.jumphost {
+                    Some(jumphost) => existing.contains(jumphost) || names.contains(jumphost),
+                    None => true,
+                });
+            valid = known;
+            if unknown.is_empty() {
+                break;
+            }
+            for row in unknown {
+                names.remove(&row.name);
+                let jumphost = row.jumphost.clone().unwrap_or_default();
+                checked.fail_row(&row, format!("Unknown jumphost '{jumphost}'"));
+            }
+        }
+        for row in &mut valid {
+            row.key_fingerprint = known_hosts.fingerprint(&row.address, row.port);
+        }
+        checked.rows = valid;
+        checked.failed.sort_by_key(|row| row.line);
+        Ok(checked)
+    }
+
+    fn fail(&mut self, line: usize, content: &str, error: String) {
+        self.failed.push(FailedHostImportRow {
+            line,
+            content: content.to_owned(),
+            error,
+        });
+    }
+
+    fn fail_row(&mut self, row: &HostImportRow, error: String) {
+        self.fail(row.line, &row.content, error);
+    }
+
+    /// Adds the valid rows in one transaction, with their groups. Hosts without a key from known_hosts have to
+    /// have it checked on their page. Returns how many hosts were added.
+    pub fn create(
+        self,
+        conn: &mut DbConnection,
+        observed_until: Option<time::PrimitiveDateTime>,
+    ) -> Result<usize, String> {
+        let res = conn.transaction::<_, CreateError, _>(|conn| {
+            for row in &self.rows {
+                insert_into(host::table)
+                    .values((
+                        host::name.eq(&row.name),
+                        host::address.eq(&row.address),
+                        host::port.eq(row.port),
+                        host::username.eq(&row.username),
+                        host::key_fingerprint.eq(&row.key_fingerprint),
+                        host::key_trust.eq(row.key_fingerprint.as_ref().map(|_| TRUST_KNOWN_HOSTS)),
+                        host::observed_until.eq(observed_until),
+                    ))
+                    .execute(conn)?;
+            }
+            // Once every host exists, as jumphosts may come later in the file
+            for row in &self.rows {
+                let Some(jumphost) = &row.jumphost else {
+                    continue;
+                };
+                let jump_via = host::table
+                    .filter(host::name.eq(jumphost))
+                    .select(host::id)
+                    .first::<i32>(conn)?;
+                diesel::update(host::table.filter(host::name.eq(&row.name)))
+                    .set(host::jump_via.eq(jump_via))
+                    .execute(conn)?;
+            }
+            for row in &self.rows {
+                if row.groups.is_empty() {
+                    continue;
+                }
+                for group in &row.groups {
+                    add_to_group(conn, &row.name, group)?;
+                }
+                let host = host::table
+                    .filter(host::name.eq(&row.name))
+                    .first::<Host>(conn)?;
+                for group in host.get_groups(conn)? {
+                    GroupAuthorization::enforce_for_host(conn, &group, &host)
+                        .map_err(|e| format!("{}: {e}", row.name))?;
+                }
+            }
+            Ok(self.rows.len())
+        });
+        match res {
+            Ok(created) => Ok(created),
+            Err(CreateError::Blocked(error)) => Err(error),
+            Err(CreateError::Database(error)) => query(Err(error)),
+        }
+    }
+}
+
+/// Why adding the hosts was rolled back
+enum CreateError {
+    Database(Error),
+    /// A policy blocks the access a rule on a group of a host would give
+    Blocked(String),
+}
+
+impl From<Error> for CreateError {
+    fn from(error: Error) -> Self {
+        Self::Database(error)
+    }
+}
+
+impl From<String> for CreateError {
+    fn from(error: String) -> Self {
+        Self::Blocked(error)
+    }
+}
+
+/// Adds a new host to a group, which is created if it doesn't exist
+fn add_to_group(conn: &mut DbConnection, host_name: &str, group: &str) -> Result<(), Error> {
+    let existing = host_group::table
+        .filter(host_group::name.eq(group))
+        .select(host_group::id)
+        .first::<i32>(conn)
+        .optional()?;
+    let group_id = match existing {
+        Some(id) => id,
+        None => {
+            insert_into(host_group::table)
+                .values(host_group::name.eq(group))
+                .execute(conn)?;
+            host_group::table
+                .filter(host_group::name.eq(group))
+                .select(host_group::id)
+                .first::<i32>(conn)?
+        }
+    };
+    let host_id = host::table
+        .filter(host::name.eq(host_name))
+        .select(host::id)
+        .first::<i32>(conn)?;
+    insert_into(host_group_member::table)
+        .values((
+            host_group_member::group_id.eq(group_id),
+            host_group_member::host_id.eq(host_id),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
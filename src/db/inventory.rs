@@ -0,0 +1,26 @@
+use diesel::prelude::*;
+
+use crate::schema::{authorization, host, user, user_key};
+use crate::DbConnection;
+
+use super::query;
+
+/// How many of each thing the database holds
+#[derive(Debug, Clone, Copy)]
+pub struct Inventory {
+    pub hosts: i64,
+    pub users: i64,
+    pub keys: i64,
+    pub authorizations: i64,
+}
+
+impl Inventory {
+    pub fn count(conn: &mut DbConnection) -> Result<Self, String> {
+        Ok(Self {
+            hosts: query(host::table.count().get_result(conn))?,
+            users: query(user::table.count().get_result(conn))?,
+            keys: query(user_key::table.count().get_result(conn))?,
+            authorizations: query(authorization::table.count().get_result(conn))?,
+        })
+    }
+}
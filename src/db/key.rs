@@ -1,16 +1,39 @@
+use std::collections::BTreeSet;
+
 use super::{query, query_drop, UsernameAndKey};
 use crate::models::NewPublicUserKey;
 use crate::schema::user;
 use crate::schema::user_key;
+use crate::schema::{authorization, group_authorization, user_group_member};
 use crate::{models::PublicUserKey, DbConnection};
 use diesel::dsl::insert_into;
 use diesel::prelude::*;
 
+/// A key with what the keys page filters by
+#[derive(Clone)]
+pub struct KeyWithOwner {
+    pub key: PublicUserKey,
+    pub username: String,
+    /// Whether the owner is enabled
+    pub enabled: bool,
+    /// Whether the owner is authorized anywhere, directly or through a team
+    pub assigned: bool,
+}
+
 impl PublicUserKey {
     pub fn get_all_keys(conn: &mut DbConnection) -> Result<Vec<Self>, String> {
         query(user_key::table.load::<Self>(conn))
     }
 
+    pub fn get_from_id(conn: &mut DbConnection, key: i32) -> Result<Option<Self>, String> {
+        query(
+            user_key::table
+                .filter(user_key::id.eq(key))
+                .first::<Self>(conn)
+                .optional(),
+        )
+    }
+
     pub fn get_all_keys_with_username(
         conn: &mut DbConnection,
     ) -> Result<Vec<UsernameAndKey>, String> {
@@ -22,6 +45,44 @@ impl PublicUserKey {
         )
     }
 
+    /// Every key with its owner, ordered by owner
+    pub fn get_all_keys_with_owner(conn: &mut DbConnection) -> Result<Vec<KeyWithOwner>, String> {
+        let mut assigned: BTreeSet<i32> = query(
+            authorization::table
+                .select(authorization::user_id)
+                .distinct()
+                .load::<i32>(conn),
+        )?
+        .into_iter()
+        .collect();
+        assigned.extend(query(
+            user_group_member::table
+                .inner_join(
+                    group_authorization::table
+                        .on(group_authorization::user_group_id.eq(user_group_member::group_id)),
+                )
+                .select(user_group_member::user_id)
+                .distinct()
+                .load::<i32>(conn),
+        )?);
+
+        Ok(query(
+            user_key::table
+                .inner_join(user::table)
+                .order((user::username, user_key::id))
+                .select((Self::as_select(), user::username, user::enabled))
+                .load::<(Self, String, bool)>(conn),
+        )?
+        .into_iter()
+        .map(|(key, username, enabled)| KeyWithOwner {
+            assigned: assigned.contains(&key.user_id),
+            key,
+            username,
+            enabled,
+        })
+        .collect())
+    }
+
     pub fn get_all_keys_as<T>(conn: &mut DbConnection) -> Result<Vec<T>, String>
     where
         T: From<Self>,
@@ -36,8 +97,9 @@ impl PublicUserKey {
     }
 
     /// Remove a key from the db
-    pub fn delete_key(conn: &mut DbConnection, key: i32) -> Result<(), String> {
-        query_drop(diesel::delete(user_key::table.filter(user_key::id.eq(key))).execute(conn))
+    /// Returns how many keys were deleted, none if the id doesn't exist
+    pub fn delete_key(conn: &mut DbConnection, key: i32) -> Result<usize, String> {
+        query(diesel::delete(user_key::table.filter(user_key::id.eq(key))).execute(conn))
     }
 
     pub fn update_comment(
@@ -54,4 +116,17 @@ impl PublicUserKey {
                 .execute(conn),
         )
     }
+
+    /// Sets when a key expires, None to keep it forever
+    pub fn set_expiry(
+        conn: &mut DbConnection,
+        key_id: i32,
+        expires_at: Option<time::PrimitiveDateTime>,
+    ) -> Result<(), String> {
+        query_drop(
+            diesel::update(user_key::table.filter(user_key::id.eq(key_id)))
+                .set(user_key::expires_at.eq(expires_at))
+                .execute(conn),
+        )
+    }
 }
@@ -0,0 +1,170 @@
+use std::collections::HashSet;
+
+use diesel::{dsl::insert_into, prelude::*, result::Error};
+use ssh_key::{HashAlg, PublicKey};
+
+use crate::{
+    models::{parse_key_line, Finding, User},
+    schema::{imported_key, user, user_account, user_key},
+    DbConnection,
+};
+
+use super::query;
+
+/// Finding about an imported key that its code host doesn't list anymore
+pub const KEY_REMOVED_UPSTREAM: &str = "key_removed_upstream";
+
+/// What importing the keys of an account did
+#[derive(Debug, Default)]
+pub struct KeyImportSummary {
+    pub added: usize,
+    /// Keys the user had already
+    pub known: usize,
+    /// Newly flagged keys the account doesn't list anymore
+    pub removed: usize,
+    /// Keys that couldn't be added, with the reason
+    pub failed: Vec<String>,
+}
+
+/// The account of a user at a code host, if linked
+pub fn account_login(
+    conn: &mut DbConnection,
+    user_id: i32,
+    provider: &str,
+) -> Result<Option<String>, String> {
+    query(
+        user_account::table
+            .filter(user_account::user_id.eq(user_id))
+            .filter(user_account::provider.eq(provider))
+            .select(user_account::login)
+            .first::<String>(conn)
+            .optional(),
+    )
+}
+
+/// Usernames and logins of every account linked at a code host
+pub fn linked_accounts(
+    conn: &mut DbConnection,
+    provider: &str,
+) -> Result<Vec<(String, String)>, String> {
+    query(
+        user_account::table
+            .inner_join(user::table)
+            .filter(user_account::provider.eq(provider))
+            .select((user::username, user_account::login))
+            .order(user::username)
+            .load(conn),
+    )
+}
+
+/// Links the user to the account and adds the listed keys it doesn't have yet, in
+/// authorized_keys format. Keys the user had already count as imported from then on.
+/// Imported keys the account doesn't list anymore are flagged, not deleted.
+pub fn sync_imported_keys(
+    conn: &mut DbConnection,
+    user: &User,
+    provider: &str,
+    login: &str,
+    label: &str,
+    lines: &[String],
+) -> Result<KeyImportSummary, String> {
+    let mut summary = KeyImportSummary::default();
+    let res = conn.transaction::<_, Error, _>(|conn| {
+        diesel::delete(
+            user_account::table
+                .filter(user_account::user_id.eq(user.id))
+                .filter(user_account::provider.eq(provider)),
+        )
+        .execute(conn)?;
+        insert_into(user_account::table)
+            .values((
+                user_account::user_id.eq(user.id),
+                user_account::provider.eq(provider),
+                user_account::login.eq(login),
+            ))
+            .execute(conn)?;
+
+        let mut listed = HashSet::new();
+        for line in lines {
+            let Some(key_base64) = line.split_whitespace().nth(1) else {
+                summary.failed.push(format!("Invalid key '{line}'"));
+                continue;
+            };
+            listed.insert(key_base64.to_owned());
+            let owner = user_key::table
+                .filter(user_key::key_base64.eq(key_base64))
+                .select((user_key::id, user_key::user_id))
+                .first::<(i32, i32)>(conn)
+                .optional()?;
+            let key_id = match owner {
+                Some((key_id, user_id)) if user_id == user.id => {
+                    summary.known += 1;
+                    key_id
+                }
+                Some(_) => {
+                    summary
+                        .failed
+                        .push(format!("{key_base64} belongs to another user"));
+                    continue;
+                }
+                None => {
+                    let new_key = match parse_key_line(line, user.id) {
+                        Ok(new_key) => new_key.with_default_comment(format!("{login} on {label}")),
+                        Err(e) => {
+                            summary.failed.push(e);
+                            continue;
+                        }
+                    };
+                    insert_into(user_key::table).values(new_key).execute(conn)?;
+                    summary.added += 1;
+                    user_key::table
+                        .filter(user_key::key_base64.eq(key_base64))
+                        .select(user_key::id)
+                        .first::<i32>(conn)?
+                }
+            };
+            let imported = imported_key::table
+                .find(key_id)
+                .count()
+                .get_result::<i64>(conn)?;
+            if imported == 0 {
+                insert_into(imported_key::table)
+                    .values((
+                        imported_key::key_id.eq(key_id),
+                        imported_key::provider.eq(provider),
+                    ))
+                    .execute(conn)?;
+            }
+        }
+
+        let imported: Vec<(i32, String, String)> = imported_key::table
+            .inner_join(user_key::table)
+            .filter(imported_key::provider.eq(provider))
+            .filter(user_key::user_id.eq(user.id))
+            .select((user_key::id, user_key::key_type, user_key::key_base64))
+            .load(conn)?;
+        Ok(imported
+            .into_iter()
+            .filter(|(_, _, key_base64)| !listed.contains(key_base64))
+            .collect::<Vec<_>>())
+    });
+
+    for (key_id, key_type, key_base64) in query(res)? {
+        let fingerprint = PublicKey::from_openssh(&format!("{key_type} {key_base64}"))
+            .map(|key| key.fingerprint(HashAlg::Sha256).to_string())
+            .unwrap_or(key_base64);
+        let details = format!(
+            "The key {fingerprint} of {} isn't listed for {login} on {label} anymore",
+            user.username
+        );
+        if Finding::raise(
+            conn,
+            KEY_REMOVED_UPSTREAM,
+            &format!("key:{key_id}"),
+            &details,
+        )? {
+            summary.removed += 1;
+        }
+    }
+    Ok(summary)
+}
@@ -0,0 +1,98 @@
+use diesel::dsl::insert_into;
+use diesel::prelude::*;
+
+use crate::schema::login_attempt;
+use crate::{
+    models::{LoginAttempt, NewLoginAttempt},
+    DbConnection,
+};
+
+use super::{now, query, query_drop};
+
+pub const SUCCESS: &str = "success";
+pub const FAILURE: &str = "failure";
+/// Rejected without checking the password
+pub const THROTTLED: &str = "throttled";
+
+impl LoginAttempt {
+    pub fn record(
+        conn: &mut DbConnection,
+        username: String,
+        client: Option<String>,
+        outcome: &str,
+    ) -> Result<(), String> {
+        query_drop(
+            insert_into(login_attempt::table)
+                .values(NewLoginAttempt {
+                    created_at: now(),
+                    username,
+                    client,
+                    outcome: outcome.to_owned(),
+                })
+                .execute(conn),
+        )
+    }
+
+    /// Forgets the attempts made before `before`
+    pub fn prune(conn: &mut DbConnection, before: time::PrimitiveDateTime) -> Result<(), String> {
+        query(
+            diesel::delete(login_attempt::table.filter(login_attempt::created_at.lt(before)))
+                .execute(conn),
+        )
+        .map(|_| ())
+    }
+
+    /// When the failed logins of `username` since `since` were made, oldest first.
+    /// A successful login starts over.
+    pub fn account_failures(
+        conn: &mut DbConnection,
+        username: &str,
+        since: time::PrimitiveDateTime,
+    ) -> Result<Vec<time::PrimitiveDateTime>, String> {
+        let last_success = query(
+            login_attempt::table
+                .filter(login_attempt::username.eq(username))
+                .filter(login_attempt::outcome.eq(SUCCESS))
+                .select(diesel::dsl::max(login_attempt::created_at))
+                .first::<Option<time::PrimitiveDateTime>>(conn),
+        )?;
+        let since = last_success.map_or(since, |success| success.max(since));
+        query(
+            login_attempt::table
+                .filter(login_attempt::username.eq(username))
+                .filter(login_attempt::outcome.eq(FAILURE))
+                .filter(login_attempt::created_at.gt(since))
+                .order(login_attempt::created_at)
+                .select(login_attempt::created_at)
+                .load(conn),
+        )
+    }
+
+    /// When the failed logins from `client` since `since` were made, oldest first
+    pub fn client_failures(
+        conn: &mut DbConnection,
+        client: &str,
+        since: time::PrimitiveDateTime,
+    ) -> Result<Vec<time::PrimitiveDateTime>, String> {
+        query(
+            login_attempt::table
+                .filter(login_attempt::client.eq(client))
+                .filter(login_attempt::outcome.eq(FAILURE))
+                .filter(login_attempt::created_at.gt(since))
+                .order(login_attempt::created_at)
+                .select(login_attempt::created_at)
+                .load(conn),
+        )
+    }
+
+    /// The latest attempts, newest first
+    pub fn get_latest(conn: &mut DbConnection, limit: i64) -> Result<Vec<Self>, String> {
+        query(
+            login_attempt::table
+                .order(login_attempt::id.desc())
+                .limit(limit)
+                .select(Self::as_select())
+                .load(conn),
+        )
+    }
+}
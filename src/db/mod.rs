@@ -2,13 +2,52 @@ use std::str::FromStr;
 
 use diesel::result::Error;
 use log::error;
-use ssh_key::{authorized_keys::ConfigOpts, Algorithm};
+use ssh_key::Algorithm;
 
 use crate::{models::PublicUserKey, ssh::AuthorizedKey};
 
+mod access_request;
+mod archive;
+mod audit;
+mod blocking;
+mod cluster;
+mod finding;
+mod group_authorization;
 mod host;
+mod host_data;
+mod host_discovery;
+mod host_group;
+mod host_import;
+mod inventory;
 mod key;
+mod key_import;
+mod login_attempt;
+mod pending_authorization;
+mod pending_removal;
+mod privacy;
+mod report;
+mod state;
+mod token;
 mod user;
+mod user_group;
+mod web_session;
+mod web_user;
+
+pub use access_request::{AccessRequestWithNames, APPROVED, DENIED, PENDING};
+pub use blocking::{BlockingError, BlockingPool, BlockingStats};
+pub use cluster::{claim_host, finish_host, heartbeat, release_dead_claims};
+pub use finding::{ACKNOWLEDGED, DISMISSED, OPEN};
+pub use group_authorization::GroupAuthorizationWithNames;
+pub use host::{DesiredHost, ExpiredAuthorization, IMPORTANCES};
+pub use host_data::{HostData, HostDataError};
+pub use host_discovery::{sync_discovered_hosts, DiscoveredHost, DiscoverySummary};
+pub use host_import::{HostImport, HostImportFormat, HOST_CSV_COLUMNS};
+pub use inventory::Inventory;
+pub use key::KeyWithOwner;
+pub use key_import::{account_login, linked_accounts, sync_imported_keys, KeyImportSummary};
+pub use login_attempt::{FAILURE, SUCCESS, THROTTLED};
+pub use pending_authorization::PendingAuthorizationWithNames;
+pub use state::{ImportSummary, State};
 
 // TODO: this should probably be a struct
 /// Authorization ID, Username, Login and SSH options
@@ -25,6 +64,8 @@ pub struct AllowedUserOnHost {
     pub username: String,
     /// Key options, if set
     pub options: Option<String>,
+    /// Team whose rule grants the access, None if the user is authorized directly
+    pub team: Option<String>,
 }
 
 impl From<AllowedUserOnHost> for AuthorizedKey {
@@ -32,24 +73,14 @@ impl From<AllowedUserOnHost> for AuthorizedKey {
         Self {
             options: value
                 .options
-                .map(|opts| ConfigOpts::new(opts).expect("Encountered invalid key"))
+                .map(|opts| opts.parse().expect("Encountered invalid key options"))
                 .unwrap_or_default(),
 
             algorithm: Algorithm::from_str(value.key.key_type.as_str())
                 .expect("Key algorithm in database is invalid"),
             base64: value.key.key_base64,
             comment: value.key.comment,
-        }
-    }
-}
-
-impl From<(PublicUserKey, String, String, Option<String>)> for AllowedUserOnHost {
-    fn from(value: (PublicUserKey, String, String, Option<String>)) -> Self {
-        Self {
-            key: value.0,
-            login: value.1,
-            username: value.2,
-            options: value.3,
+            certificate: None,
         }
     }
 }
@@ -60,6 +91,37 @@ pub type UsernameAndKey = (String, PublicUserKey);
 /// List of authorized_keys files
 pub type AuthorizedKeysList = Vec<AllowedUserOnHost>;
 
+/// What converging a row to a desired state did
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PutOutcome {
+    Created,
+    Updated,
+    Unchanged,
+}
+
+/// The current UTC time, as stored in timestamp columns
+pub fn now() -> time::PrimitiveDateTime {
+    let now = time::OffsetDateTime::now_utc();
+    time::PrimitiveDateTime::new(now.date(), now.time())
+}
+
+/// Trims and checks the name of a host group or team. Group names are used in paths.
+fn group_name(name: &str) -> Result<&str, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(String::from("The group needs a name"));
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(String::from(
+            "Group names may only contain letters, digits, '-', '_' and '.'",
+        ));
+    }
+    Ok(name)
+}
+
 /// Prints database Errors and returns a generic String
 pub fn query<T>(query_result: Result<T, Error>) -> Result<T, String> {
     query_result.map_err(|e| {
@@ -0,0 +1,162 @@
+use diesel::dsl::insert_into;
+use diesel::prelude::*;
+
+use crate::schema::{authorization, host, pending_authorization, user};
+use crate::ssh::KeyOptions;
+use crate::{
+    models::{Host, NewPendingAuthorization, PendingAuthorization},
+    policy, DbConnection,
+};
+
+use super::{now, query, query_drop, APPROVED, PENDING};
+
+pub const REJECTED: &str = "rejected";
+
+/// A proposed authorization with the names of its host and user
+pub type PendingAuthorizationWithNames = (PendingAuthorization, String, String);
+
+impl PendingAuthorization {
+    /// Proposes an authorization, unless it exists or was already proposed
+    pub fn propose(
+        conn: &mut DbConnection,
+        mut proposal: NewPendingAuthorization,
+    ) -> Result<(), String> {
+        proposal.options = KeyOptions::normalize(proposal.options)?;
+        if proposal
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= now())
+        {
+            return Err(String::from("The expiry date has to be in the future"));
+        }
+
+        let authorized = query(
+            authorization::table
+                .filter(authorization::host_id.eq(proposal.host_id))
+                .filter(authorization::user_id.eq(proposal.user_id))
+                .filter(authorization::login.eq(&proposal.login))
+                .count()
+                .get_result::<i64>(conn),
+        )?;
+        if authorized > 0 {
+            return Err(String::from("The user already has this login"));
+        }
+
+        let pending = query(
+            pending_authorization::table
+                .filter(pending_authorization::host_id.eq(proposal.host_id))
+                .filter(pending_authorization::user_id.eq(proposal.user_id))
+                .filter(pending_authorization::login.eq(&proposal.login))
+                .filter(pending_authorization::status.eq(PENDING))
+                .count()
+                .get_result::<i64>(conn),
+        )?;
+        if pending > 0 {
+            return Err(String::from("This authorization was already proposed"));
+        }
+
+        let host = Host::get_from_id_sync(conn, proposal.host_id)?
+            .ok_or_else(|| String::from("Host not found"))?;
+        policy::enforce(conn, &host, proposal.user_id, &proposal.login)?;
+
+        query_drop(
+            insert_into(pending_authorization::table)
+                .values(proposal)
+                .execute(conn),
+        )
+    }
+
+    pub fn get_from_id(conn: &mut DbConnection, id: i32) -> Result<Option<Self>, String> {
+        query(
+            pending_authorization::table
+                .find(id)
+                .first::<Self>(conn)
+                .optional(),
+        )
+    }
+
+    /// Pending proposals, oldest first, or every proposal, newest first
+    pub fn get_all(
+        conn: &mut DbConnection,
+        all: bool,
+    ) -> Result<Vec<PendingAuthorizationWithNames>, String> {
+        let proposals = pending_authorization::table
+            .inner_join(host::table)
+            .inner_join(user::table)
+            .select((
+                PendingAuthorization::as_select(),
+                host::name,
+                user::username,
+            ))
+            .into_boxed();
+        query(match all {
+            true => proposals.order(pending_authorization::id.desc()).load(conn),
+            false => proposals
+                .filter(pending_authorization::status.eq(PENDING))
+                .order(pending_authorization::id)
+                .load(conn),
+        })
+    }
+
+    /// Approves the proposal and authorizes the user in one transaction.
+    /// Fails if it was decided in the meantime.
+    pub fn approve(&self, conn: &mut DbConnection, decided_by: &str) -> Result<(), String> {
+        if self
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= now())
+        {
+            return Err(String::from(
+                "The authorization expired before it was approved",
+            ));
+        }
+        // The rules or the user's keys may have changed since the proposal
+        let host = Host::get_from_id_sync(conn, self.host_id)?
+            .ok_or_else(|| String::from("Host not found"))?;
+        policy::enforce(conn, &host, self.user_id, &self.login)?;
+        let res = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            if self.set_status(conn, APPROVED, decided_by)? == 0 {
+                return Ok(false);
+            }
+            insert_into(authorization::table)
+                .values((
+                    authorization::host_id.eq(self.host_id),
+                    authorization::user_id.eq(self.user_id),
+                    authorization::login.eq(&self.login),
+                    authorization::options.eq(&self.options),
+                    authorization::expires_at.eq(self.expires_at),
+                ))
+                .execute(conn)?;
+            Ok(true)
+        });
+        match query(res)? {
+            true => Ok(()),
+            false => Err(String::from("This authorization was already decided")),
+        }
+    }
+
+    /// Rejects the proposal. Fails if it was decided in the meantime.
+    pub fn reject(&self, conn: &mut DbConnection, decided_by: &str) -> Result<(), String> {
+        match query(self.set_status(conn, REJECTED, decided_by))? {
+            0 => Err(String::from("This authorization was already decided")),
+            _ => Ok(()),
+        }
+    }
+
+    fn set_status(
+        &self,
+        conn: &mut DbConnection,
+        status: &str,
+        decided_by: &str,
+    ) -> QueryResult<usize> {
+        diesel::update(
+            pending_authorization::table
+                .filter(pending_authorization::id.eq(self.id))
+                .filter(pending_authorization::status.eq(PENDING)),
+        )
+        .set((
+            pending_authorization::status.eq(status),
+            pending_authorization::decided_by.eq(decided_by),
+            pending_authorization::decided_at.eq(now()),
+        ))
+        .execute(conn)
+    }
+}
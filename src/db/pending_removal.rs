@@ -0,0 +1,113 @@
+use diesel::dsl::insert_into;
+use diesel::prelude::*;
+
+use crate::schema::pending_removal;
+use crate::{
+    models::{Host, PendingRemoval},
+    DbConnection,
+};
+
+use super::{now, query};
+
+impl PendingRemoval {
+    /// Queues the removal of `keys` from a login after a failed attempt.
+    /// Keys already queued for the login count the attempt instead.
+    pub fn queue(
+        conn: &mut DbConnection,
+        host_id: i32,
+        login: &str,
+        keys: &[String],
+        reason: &str,
+        error: &str,
+    ) -> Result<(), String> {
+        let res = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            for key in keys {
+                let queued = diesel::update(
+                    pending_removal::table
+                        .filter(pending_removal::host_id.eq(host_id))
+                        .filter(pending_removal::login.eq(login))
+                        .filter(pending_removal::key_base64.eq(key)),
+                )
+                .set((
+                    pending_removal::attempts.eq(pending_removal::attempts + 1),
+                    pending_removal::last_attempt.eq(now()),
+                    pending_removal::last_error.eq(error),
+                ))
+                .execute(conn)?;
+                if queued > 0 {
+                    continue;
+                }
+                insert_into(pending_removal::table)
+                    .values((
+                        pending_removal::created_at.eq(now()),
+                        pending_removal::host_id.eq(host_id),
+                        pending_removal::login.eq(login),
+                        pending_removal::key_base64.eq(key),
+                        pending_removal::reason.eq(reason),
+                        pending_removal::attempts.eq(1),
+                        pending_removal::last_attempt.eq(now()),
+                        pending_removal::last_error.eq(error),
+                    ))
+                    .execute(conn)?;
+            }
+            Ok(())
+        });
+        query(res)
+    }
+
+    /// All queued removals, by host and login
+    pub fn get_all(conn: &mut DbConnection) -> Result<Vec<Self>, String> {
+        query(
+            pending_removal::table
+                .order((pending_removal::host_id, pending_removal::login))
+                .load(conn),
+        )
+    }
+
+    pub fn get_for_host(conn: &mut DbConnection, host: &Host) -> Result<Vec<Self>, String> {
+        query(
+            pending_removal::table
+                .filter(pending_removal::host_id.eq(host.id))
+                .order((pending_removal::login, pending_removal::id))
+                .load(conn),
+        )
+    }
+
+    pub fn record_failure(conn: &mut DbConnection, ids: &[i32], error: &str) -> Result<(), String> {
+        query(
+            diesel::update(pending_removal::table.filter(pending_removal::id.eq_any(ids)))
+                .set((
+                    pending_removal::attempts.eq(pending_removal::attempts + 1),
+                    pending_removal::last_attempt.eq(now()),
+                    pending_removal::last_error.eq(error),
+                ))
+                .execute(conn),
+        )
+        .map(|_| ())
+    }
+
+    /// Forgets removals that succeeded or aren't wanted anymore
+    pub fn delete(conn: &mut DbConnection, ids: &[i32]) -> Result<(), String> {
+        query(
+            diesel::delete(pending_removal::table.filter(pending_removal::id.eq_any(ids)))
+                .execute(conn),
+        )
+        .map(|_| ())
+    }
+
+    /// Stops retrying a removal on this host
+    pub fn cancel(conn: &mut DbConnection, host: &Host, id: i32) -> Result<(), String> {
+        let deleted = query(
+            diesel::delete(
+                pending_removal::table
+                    .filter(pending_removal::id.eq(id))
+                    .filter(pending_removal::host_id.eq(host.id)),
+            )
+            .execute(conn),
+        )?;
+        match deleted {
+            0 => Err(String::from("Pending removal not found")),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,417 @@
+use diesel::prelude::*;
+use serde::Serialize;
+
+use crate::schema::{
+    access_request, audit_log, authorization, host, pending_authorization, pending_removal, user,
+    user_account, user_offboarding,
+};
+use crate::{
+    models::{
+        AccessRequest, AuditEntry, OffboardingReport, PendingAuthorization, PendingRemoval, User,
+    },
+    privacy::{mentions, pseudonym, replace_all_mentions},
+    DbConnection,
+};
+
+use super::{now, query};
+
+fn format_time(time: time::PrimitiveDateTime) -> String {
+    time.to_string()
+}
+
+#[derive(Serialize)]
+struct ExportedUser {
+    username: String,
+    enabled: bool,
+    email: Option<String>,
+    default_options: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ExportedKey {
+    key_type: String,
+    key_base64: String,
+    comment: Option<String>,
+    expires_at: Option<String>,
+    attested_by: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ExportedAuthorization {
+    host: String,
+    login: String,
+    options: Option<String>,
+    expires_at: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ExportedAccessRequest {
+    created_at: String,
+    requested_by: String,
+    host: String,
+    login: String,
+    justification: String,
+    status: String,
+    decided_by: Option<String>,
+    decided_at: Option<String>,
+    result: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ExportedProposal {
+    created_at: String,
+    proposed_by: String,
+    host: String,
+    login: String,
+    options: Option<String>,
+    expires_at: Option<String>,
+    status: String,
+    decided_by: Option<String>,
+    decided_at: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ExportedRemoval {
+    created_at: String,
+    host: String,
+    login: String,
+    key_base64: String,
+    reason: String,
+    attempts: i32,
+    last_error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ExportedAccount {
+    provider: String,
+    login: String,
+}
+
+#[derive(Serialize)]
+struct ExportedOffboarding {
+    offboarded_at: String,
+    report: String,
+}
+
+#[derive(Serialize)]
+struct ExportedAuditEvent {
+    id: i32,
+    created_at: String,
+    actor: String,
+    client: Option<String>,
+    action: String,
+    target: String,
+    success: bool,
+    result: String,
+}
+
+/// Everything stored about a user, for answering a data subject access request
+#[derive(Serialize)]
+pub struct UserData {
+    exported_at: String,
+    user: ExportedUser,
+    teams: Vec<String>,
+    keys: Vec<ExportedKey>,
+    /// Accounts at code hosts keys are imported from
+    accounts: Vec<ExportedAccount>,
+    authorizations: Vec<ExportedAuthorization>,
+    access_requests: Vec<ExportedAccessRequest>,
+    proposed_authorizations: Vec<ExportedProposal>,
+    pending_removals: Vec<ExportedRemoval>,
+    offboarding_reports: Vec<ExportedOffboarding>,
+    /// Entries made by the user, or mentioning their name or email
+    audit_events: Vec<ExportedAuditEvent>,
+}
+
+impl User {
+    /// Name and email, by which the user is mentioned in the audit log and reports
+    pub fn names(&self) -> Vec<&str> {
+        let mut names = vec![self.username.as_str()];
+        names.extend(self.email.as_deref().filter(|email| !email.is_empty()));
+        names
+    }
+
+    /// Audit log entries made by the user or mentioning them, oldest first
+    fn audit_entries(&self, conn: &mut DbConnection) -> Result<Vec<AuditEntry>, String> {
+        let mut candidates = audit_log::table
+            .filter(audit_log::actor.eq(&self.username))
+            .into_boxed();
+        for name in self.names() {
+            let pattern = format!("%{name}%");
+            candidates = candidates
+                .or_filter(audit_log::action.like(pattern.clone()))
+                .or_filter(audit_log::target.like(pattern));
+        }
+        let candidates = query(
+            candidates
+                .order(audit_log::id)
+                .select(AuditEntry::as_select())
+                .load(conn),
+        )?;
+        let names = self.names();
+        Ok(candidates
+            .into_iter()
+            .filter(|entry| {
+                entry.actor == self.username
+                    || names
+                        .iter()
+                        .any(|name| mentions(&entry.action, name) || mentions(&entry.target, name))
+            })
+            .collect())
+    }
+
+    fn offboarding_reports(
+        &self,
+        conn: &mut DbConnection,
+    ) -> Result<Vec<OffboardingReport>, String> {
+        let reports = query(
+            user_offboarding::table
+                .order(user_offboarding::offboarded_at)
+                .load::<OffboardingReport>(conn),
+        )?;
+        let names = self.names();
+        Ok(reports
+            .into_iter()
+            .filter(|report| {
+                report.username == self.username
+                    || names.iter().any(|name| mentions(&report.report, name))
+            })
+            .collect())
+    }
+
+    /// Queued removals of the user's keys, or queued because of them
+    fn pending_removals(
+        &self,
+        conn: &mut DbConnection,
+    ) -> Result<Vec<(PendingRemoval, String)>, String> {
+        let keys: Vec<String> = self
+            .get_keys(conn)?
+            .into_iter()
+            .map(|key| key.key_base64)
+            .collect();
+        let removals = query(
+            pending_removal::table
+                .inner_join(host::table)
+                .order(pending_removal::id)
+                .select((PendingRemoval::as_select(), host::name))
+                .load::<(PendingRemoval, String)>(conn),
+        )?;
+        let names = self.names();
+        Ok(removals
+            .into_iter()
+            .filter(|(removal, _)| {
+                keys.contains(&removal.key_base64)
+                    || names.iter().any(|name| mentions(&removal.reason, name))
+            })
+            .collect())
+    }
+
+    /// Collects everything stored about the user
+    pub fn export_data(&self, conn: &mut DbConnection) -> Result<UserData, String> {
+        let teams = self
+            .get_groups(conn)?
+            .into_iter()
+            .map(|team| team.name)
+            .collect();
+        let keys = self
+            .get_keys(conn)?
+            .into_iter()
+            .map(|key| ExportedKey {
+                key_type: key.key_type,
+                key_base64: key.key_base64,
+                comment: key.comment,
+                expires_at: key.expires_at.map(format_time),
+                attested_by: key.attested_by,
+            })
+            .collect();
+        let authorizations = query(
+            authorization::table
+                .inner_join(host::table)
+                .filter(authorization::user_id.eq(self.id))
+                .order(authorization::id)
+                .select((
+                    host::name,
+                    authorization::login,
+                    authorization::options,
+                    authorization::expires_at,
+                ))
+                .load::<(
+                    String,
+                    String,
+                    Option<String>,
+                    Option<time::PrimitiveDateTime>,
+                )>(conn),
+        )?
+        .into_iter()
+        .map(|(host, login, options, expires_at)| ExportedAuthorization {
+            host,
+            login,
+            options,
+            expires_at: expires_at.map(format_time),
+        })
+        .collect();
+        let access_requests = query(
+            access_request::table
+                .inner_join(host::table)
+                .filter(access_request::user_id.eq(self.id))
+                .order(access_request::id)
+                .select((AccessRequest::as_select(), host::name))
+                .load::<(AccessRequest, String)>(conn),
+        )?
+        .into_iter()
+        .map(|(request, host)| ExportedAccessRequest {
+            created_at: format_time(request.created_at),
+            requested_by: request.requested_by,
+            host,
+            login: request.login,
+            justification: request.justification,
+            status: request.status,
+            decided_by: request.decided_by,
+            decided_at: request.decided_at.map(format_time),
+            result: request.result,
+        })
+        .collect();
+        let proposed_authorizations = query(
+            pending_authorization::table
+                .inner_join(host::table)
+                .filter(pending_authorization::user_id.eq(self.id))
+                .order(pending_authorization::id)
+                .select((PendingAuthorization::as_select(), host::name))
+                .load::<(PendingAuthorization, String)>(conn),
+        )?
+        .into_iter()
+        .map(|(proposal, host)| ExportedProposal {
+            created_at: format_time(proposal.created_at),
+            proposed_by: proposal.proposed_by,
+            host,
+            login: proposal.login,
+            options: proposal.options,
+            expires_at: proposal.expires_at.map(format_time),
+            status: proposal.status,
+            decided_by: proposal.decided_by,
+            decided_at: proposal.decided_at.map(format_time),
+        })
+        .collect();
+        let pending_removals = self
+            .pending_removals(conn)?
+            .into_iter()
+            .map(|(removal, host)| ExportedRemoval {
+                created_at: format_time(removal.created_at),
+                host,
+                login: removal.login,
+                key_base64: removal.key_base64,
+                reason: removal.reason,
+                attempts: removal.attempts,
+                last_error: removal.last_error,
+            })
+            .collect();
+        let accounts = query(
+            user_account::table
+                .filter(user_account::user_id.eq(self.id))
+                .select((user_account::provider, user_account::login))
+                .load::<(String, String)>(conn),
+        )?
+        .into_iter()
+        .map(|(provider, login)| ExportedAccount { provider, login })
+        .collect();
+        let offboarding_reports = self
+            .offboarding_reports(conn)?
+            .into_iter()
+            .map(|report| ExportedOffboarding {
+                offboarded_at: format_time(report.offboarded_at),
+                report: report.report,
+            })
+            .collect();
+        let audit_events = self
+            .audit_entries(conn)?
+            .into_iter()
+            .map(|entry| ExportedAuditEvent {
+                id: entry.id,
+                created_at: format_time(entry.created_at),
+                actor: entry.actor,
+                client: entry.client,
+                action: entry.action,
+                target: entry.target,
+                success: entry.success,
+                result: entry.result,
+            })
+            .collect();
+
+        Ok(UserData {
+            exported_at: format_time(now()),
+            user: ExportedUser {
+                username: self.username.clone(),
+                enabled: self.enabled,
+                email: self.email.clone(),
+                default_options: self.default_options.clone(),
+            },
+            teams,
+            keys,
+            accounts,
+            authorizations,
+            access_requests,
+            proposed_authorizations,
+            pending_removals,
+            offboarding_reports,
+            audit_events,
+        })
+    }
+
+    /// Deletes the user like `delete_user`, and replaces their name and email with a
+    /// pseudonym in the audit log, the offboarding reports and the queued removals, which are
+    /// kept. Returns the pseudonym.
+    pub fn delete_anonymized(&self, conn: &mut DbConnection) -> Result<String, String> {
+        let pseudonym = pseudonym();
+        let names = self.names();
+        let entries = self.audit_entries(conn)?;
+        let reports = self.offboarding_reports(conn)?;
+        let removals = self.pending_removals(conn)?;
+
+        let res = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            for entry in entries {
+                // The address the user made changes from is theirs too
+                let (actor, client) = match entry.actor == self.username {
+                    true => (pseudonym.clone(), None),
+                    false => (entry.actor, entry.client),
+                };
+                let action =
+                    replace_all_mentions(&entry.action, &names, &pseudonym).unwrap_or(entry.action);
+                let target =
+                    replace_all_mentions(&entry.target, &names, &pseudonym).unwrap_or(entry.target);
+                diesel::update(audit_log::table.find(entry.id))
+                    .set((
+                        audit_log::actor.eq(actor),
+                        audit_log::client.eq(client),
+                        audit_log::action.eq(action),
+                        audit_log::target.eq(target),
+                    ))
+                    .execute(conn)?;
+            }
+            for report in reports {
+                let username = match report.username == self.username {
+                    true => pseudonym.clone(),
+                    false => report.username,
+                };
+                let text = replace_all_mentions(&report.report, &names, &pseudonym)
+                    .unwrap_or(report.report);
+                diesel::update(user_offboarding::table.find(report.id))
+                    .set((
+                        user_offboarding::username.eq(username),
+                        user_offboarding::report.eq(text),
+                    ))
+                    .execute(conn)?;
+            }
+            for (removal, _) in removals {
+                if let Some(reason) = replace_all_mentions(&removal.reason, &names, &pseudonym) {
+                    diesel::update(pending_removal::table.find(removal.id))
+                        .set(pending_removal::reason.eq(reason))
+                        .execute(conn)?;
+                }
+            }
+            diesel::delete(user::table.find(self.id)).execute(conn)?;
+            Ok(())
+        });
+        query(res)?;
+        Ok(pseudonym)
+    }
+}
@@ -0,0 +1,56 @@
+use diesel::dsl::insert_into;
+use diesel::prelude::*;
+
+use crate::schema::integrity_report;
+use crate::{
+    models::{IntegrityReport, NewIntegrityReport},
+    DbConnection,
+};
+
+use super::{query, query_drop};
+
+impl IntegrityReport {
+    /// All reports without their content, newest first
+    pub fn get_all(
+        conn: &mut DbConnection,
+    ) -> Result<Vec<(i32, time::PrimitiveDateTime, String)>, String> {
+        query(
+            integrity_report::table
+                .order(integrity_report::id.desc())
+                .select((
+                    integrity_report::id,
+                    integrity_report::created_at,
+                    integrity_report::summary,
+                ))
+                .load(conn),
+        )
+    }
+
+    pub fn get_from_id(conn: &mut DbConnection, id: i32) -> Result<Option<Self>, String> {
+        query(
+            integrity_report::table
+                .find(id)
+                .first::<Self>(conn)
+                .optional(),
+        )
+    }
+
+    /// The report generated right before this one
+    pub fn get_previous(&self, conn: &mut DbConnection) -> Result<Option<Self>, String> {
+        query(
+            integrity_report::table
+                .filter(integrity_report::id.lt(self.id))
+                .order(integrity_report::id.desc())
+                .first::<Self>(conn)
+                .optional(),
+        )
+    }
+
+    pub fn add(conn: &mut DbConnection, report: NewIntegrityReport) -> Result<(), String> {
+        query_drop(
+            insert_into(integrity_report::table)
+                .values(report)
+                .execute(conn),
+        )
+    }
+}
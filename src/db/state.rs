@@ -0,0 +1,564 @@
+use std::collections::HashMap;
+
+use diesel::{dsl::insert_into, prelude::*, result::Error};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    models::{deserialize_expiry, serialize_expiry, Host, PublicUserKey, User},
+    policy,
+    schema::{authorization, host, user, user_key},
+    ssh::{KeyOptions, TRUST_KNOWN_HOSTS, TRUST_MANUAL, TRUST_SSHFP, TRUST_SSHFP_DNSSEC},
+    DbConnection,
+};
+
+use super::{query, IMPORTANCES};
+
+/// Version of the document, raised when older instances couldn't import it anymore
+pub const STATE_VERSION: u32 = 1;
+
+/// Hosts, users, keys and authorizations of an instance, referring to each other by name
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct State {
+    pub version: u32,
+    #[serde(default)]
+    pub hosts: Vec<StateHost>,
+    #[serde(default)]
+    pub users: Vec<StateUser>,
+    #[serde(default)]
+    pub authorizations: Vec<StateAuthorization>,
+}
+
+const fn default_true() -> bool {
+    true
+}
+
+fn default_importance() -> String {
+    String::from("normal")
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct StateHost {
+    pub name: String,
+    pub username: String,
+    pub address: String,
+    pub port: i32,
+    #[serde(default)]
+    pub key_fingerprint: Option<String>,
+    /// Name of the jumphost
+    #[serde(default)]
+    pub jump_via: Option<String>,
+    #[serde(default)]
+    pub forbid_forwarding: bool,
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub default_options: Option<String>,
+    #[serde(default)]
+    pub key_trust: Option<String>,
+    #[serde(default = "default_importance")]
+    pub importance: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct StateUser {
+    pub username: String,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub default_options: Option<String>,
+    #[serde(default)]
+    pub keys: Vec<StateKey>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct StateKey {
+    pub key_type: String,
+    pub key_base64: String,
+    #[serde(default)]
+    pub comment: Option<String>,
+    /// In UTC
+    #[serde(
+        default,
+        serialize_with = "serialize_expiry",
+        deserialize_with = "deserialize_expiry"
+    )]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub expires_at: Option<time::PrimitiveDateTime>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct StateAuthorization {
+    /// Name of the user
+    pub user: String,
+    /// Name of the host
+    pub host: String,
+    pub login: String,
+    #[serde(default)]
+    pub options: Option<String>,
+    /// In UTC
+    #[serde(
+        default,
+        serialize_with = "serialize_expiry",
+        deserialize_with = "deserialize_expiry"
+    )]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub expires_at: Option<time::PrimitiveDateTime>,
+}
+
+/// What an import added, everything else existed already
+#[derive(Serialize, ToSchema, Default, Debug)]
+pub struct ImportSummary {
+    pub hosts: usize,
+    pub users: usize,
+    pub keys: usize,
+    pub authorizations: usize,
+    /// Hosts, users, keys and authorizations that existed already
+    pub skipped: usize,
+}
+
+/// Why an import was rolled back
+enum ImportError {
+    Database(Error),
+    Invalid(String),
+}
+
+impl From<Error> for ImportError {
+    fn from(error: Error) -> Self {
+        Self::Database(error)
+    }
+}
+
+impl From<String> for ImportError {
+    fn from(error: String) -> Self {
+        Self::Invalid(error)
+    }
+}
+
+/// Checks the entries that would break the authorized_keys of hosts later
+fn validate(state: &State) -> Result<(), String> {
+    if state.version != STATE_VERSION {
+        return Err(format!(
+            "Unsupported version {}, expected {STATE_VERSION}",
+            state.version
+        ));
+    }
+    for host in &state.hosts {
+        if !IMPORTANCES.contains(&host.importance.as_str()) {
+            return Err(format!(
+                "Unknown importance '{}' of host '{}'",
+                host.importance, host.name
+            ));
+        }
+        if let Some(trust) = &host.key_trust {
+            if ![
+                TRUST_MANUAL,
+                TRUST_SSHFP,
+                TRUST_SSHFP_DNSSEC,
+                TRUST_KNOWN_HOSTS,
+            ]
+            .contains(&trust.as_str())
+            {
+                return Err(format!(
+                    "Unknown key trust '{trust}' of host '{}'",
+                    host.name
+                ));
+            }
+        }
+        KeyOptions::normalize(host.default_options.clone())
+            .map_err(|e| format!("Host '{}': {e}", host.name))?;
+    }
+    for user in &state.users {
+        KeyOptions::normalize(user.default_options.clone())
+            .map_err(|e| format!("User '{}': {e}", user.username))?;
+        for key in &user.keys {
+            ssh_key::Algorithm::new(&key.key_type).map_err(|_| {
+                format!(
+                    "Invalid key algorithm '{}' of user '{}'",
+                    key.key_type, user.username
+                )
+            })?;
+        }
+    }
+    for authorization in &state.authorizations {
+        KeyOptions::normalize(authorization.options.clone()).map_err(|e| {
+            format!(
+                "Authorization of '{}' on '{}': {e}",
+                authorization.user, authorization.host
+            )
+        })?;
+    }
+    Ok(())
+}
+
+impl State {
+    /// Everything needed to set up another instance with the same access
+    pub fn export(conn: &mut DbConnection) -> Result<Self, String> {
+        let hosts = Host::get_all_hosts(conn)?;
+        let host_names: HashMap<i32, String> = hosts
+            .iter()
+            .map(|host| (host.id, host.name.clone()))
+            .collect();
+        let users = User::get_all_users(conn)?;
+        let usernames: HashMap<i32, String> = users
+            .iter()
+            .map(|user| (user.id, user.username.clone()))
+            .collect();
+        let mut keys: HashMap<i32, Vec<StateKey>> = HashMap::new();
+        for key in query(
+            user_key::table
+                .order(user_key::id)
+                .select(PublicUserKey::as_select())
+                .load(conn),
+        )? {
+            keys.entry(key.user_id).or_default().push(StateKey {
+                key_type: key.key_type,
+                key_base64: key.key_base64,
+                comment: key.comment,
+                expires_at: key.expires_at,
+            });
+        }
+        let authorizations = query(
+            authorization::table
+                .order(authorization::id)
+                .select((
+                    authorization::user_id,
+                    authorization::host_id,
+                    authorization::login,
+                    authorization::options,
+                    authorization::expires_at,
+                ))
+                .load::<(
+                    i32,
+                    i32,
+                    String,
+                    Option<String>,
+                    Option<time::PrimitiveDateTime>,
+                )>(conn),
+        )?;
+
+        Ok(Self {
+            version: STATE_VERSION,
+            hosts: hosts
+                .into_iter()
+                .map(|host| StateHost {
+                    jump_via: host.jump_via.and_then(|id| host_names.get(&id).cloned()),
+                    name: host.name,
+                    username: host.username,
+                    address: host.address,
+                    port: host.port,
+                    key_fingerprint: host.key_fingerprint,
+                    forbid_forwarding: host.forbid_forwarding,
+                    owner: host.owner,
+                    default_options: host.default_options,
+                    key_trust: host.key_trust,
+                    importance: host.importance,
+                })
+                .collect(),
+            users: users
+                .into_iter()
+                .map(|user| StateUser {
+                    keys: keys.remove(&user.id).unwrap_or_default(),
+                    username: user.username,
+                    enabled: user.enabled,
+                    email: user.email,
+                    default_options: user.default_options,
+                })
+                .collect(),
+            authorizations: authorizations
+                .into_iter()
+                .filter_map(|(user_id, host_id, login, options, expires_at)| {
+                    Some(StateAuthorization {
+                        user: usernames.get(&user_id)?.clone(),
+                        host: host_names.get(&host_id)?.clone(),
+                        login,
+                        options,
+                        expires_at,
+                    })
+                })
+                .collect(),
+        })
+    }
+
+    /// Adds what doesn't exist yet, in one transaction. Existing hosts, users, keys and
+    /// authorizations are left as they are.
+    pub fn import(self, conn: &mut DbConnection) -> Result<ImportSummary, String> {
+        validate(&self)?;
+        let mut summary = ImportSummary::default();
+        let res = conn.transaction::<_, ImportError, _>(|conn| {
+            self.import_hosts(conn, &mut summary)?;
+            self.import_users(conn, &mut summary)?;
+            self.import_authorizations(conn, &mut summary)
+        });
+        match res {
+            Ok(()) => Ok(summary),
+            Err(ImportError::Invalid(error)) => Err(error),
+            Err(ImportError::Database(error)) => query(Err(error)),
+        }
+    }
+
+    fn import_hosts(
+        &self,
+        conn: &mut DbConnection,
+        summary: &mut ImportSummary,
+    ) -> Result<(), ImportError> {
+        let mut created = Vec::new();
+        for state_host in &self.hosts {
+            if Host::get_from_name_sync(conn, state_host.name.clone())?.is_some() {
+                summary.skipped += 1;
+                continue;
+            }
+            insert_into(host::table)
+                .values((
+                    host::name.eq(&state_host.name),
+                    host::username.eq(&state_host.username),
+                    host::address.eq(&state_host.address),
+                    host::port.eq(state_host.port),
+                    host::key_fingerprint.eq(&state_host.key_fingerprint),
+                    host::forbid_forwarding.eq(state_host.forbid_forwarding),
+                    host::owner.eq(&state_host.owner),
+                    host::default_options.eq(&state_host.default_options),
+                    // Whoever wrote the document vouches for the key, not this instance
+                    host::key_trust.eq(None::<String>),
+                    host::importance.eq(&state_host.importance),
+                ))
+                .execute(conn)?;
+            created.push(state_host);
+            summary.hosts += 1;
+        }
+
+        // Once every host exists, as jumphosts may come later in the document
+        for state_host in &created {
+            let Some(jumphost) = &state_host.jump_via else {
+                continue;
+            };
+            let jump_via = Host::get_from_name_sync(conn, jumphost.clone())?.ok_or_else(|| {
+                format!(
+                    "Unknown jumphost '{jumphost}' of host '{}'",
+                    state_host.name
+                )
+            })?;
+            diesel::update(host::table.filter(host::name.eq(&state_host.name)))
+                .set(host::jump_via.eq(jump_via.id))
+                .execute(conn)?;
+        }
+        // Only new hosts can close a cycle, existing ones don't jump via them
+        for state_host in created {
+            let Some(host) = Host::get_from_name_sync(conn, state_host.name.clone())? else {
+                continue;
+            };
+            let Some(jump_via) = host.jump_via else {
+                continue;
+            };
+            if Host::jumps_reach(conn, jump_via, host.id)? {
+                return Err(
+                    format!("Jump hosts of host '{}' form a cycle", state_host.name).into(),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn import_users(
+        &self,
+        conn: &mut DbConnection,
+        summary: &mut ImportSummary,
+    ) -> Result<(), ImportError> {
+        for state_user in &self.users {
+            let user_id = match User::get_from_name(conn, &state_user.username)? {
+                Some(user) => {
+                    summary.skipped += 1;
+                    user.id
+                }
+                None => {
+                    insert_into(user::table)
+                        .values((
+                            user::username.eq(&state_user.username),
+                            user::enabled.eq(state_user.enabled),
+                            user::email.eq(&state_user.email),
+                            user::default_options.eq(&state_user.default_options),
+                        ))
+                        .execute(conn)?;
+                    summary.users += 1;
+                    User::get_user(conn, state_user.username.clone())?.id
+                }
+            };
+
+            for key in &state_user.keys {
+                let exists = user_key::table
+                    .filter(user_key::key_base64.eq(&key.key_base64))
+                    .count()
+                    .get_result::<i64>(conn)?
+                    > 0;
+                if exists {
+                    summary.skipped += 1;
+                    continue;
+                }
+                insert_into(user_key::table)
+                    .values((
+                        user_key::key_type.eq(&key.key_type),
+                        user_key::key_base64.eq(&key.key_base64),
+                        user_key::comment.eq(&key.comment),
+                        user_key::user_id.eq(user_id),
+                        user_key::expires_at.eq(key.expires_at),
+                    ))
+                    .execute(conn)?;
+                summary.keys += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn import_authorizations(
+        &self,
+        conn: &mut DbConnection,
+        summary: &mut ImportSummary,
+    ) -> Result<(), ImportError> {
+        for state_authorization in &self.authorizations {
+            let host = Host::get_from_name_sync(conn, state_authorization.host.clone())?
+                .ok_or_else(|| format!("Unknown host '{}'", state_authorization.host))?;
+            let user = User::get_from_name(conn, &state_authorization.user)?
+                .ok_or_else(|| format!("Unknown user '{}'", state_authorization.user))?;
+            let exists = authorization::table
+                .filter(authorization::host_id.eq(host.id))
+                .filter(authorization::user_id.eq(user.id))
+                .filter(authorization::login.eq(&state_authorization.login))
+                .count()
+                .get_result::<i64>(conn)?
+                > 0;
+            if exists {
+                summary.skipped += 1;
+                continue;
+            }
+            policy::enforce(conn, &host, user.id, &state_authorization.login)?;
+            insert_into(authorization::table)
+                .values((
+                    authorization::host_id.eq(host.id),
+                    authorization::user_id.eq(user.id),
+                    authorization::login.eq(&state_authorization.login),
+                    authorization::options
+                        .eq(KeyOptions::normalize(state_authorization.options.clone())?),
+                    authorization::expires_at.eq(state_authorization.expires_at),
+                ))
+                .execute(conn)?;
+            summary.authorizations += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::Connection;
+    use diesel_migrations::MigrationHarness;
+
+    use super::*;
+
+    fn database() -> DbConnection {
+        let mut conn = DbConnection::establish(":memory:").unwrap();
+        conn.run_pending_migrations(crate::SQLITE_MIGRATIONS)
+            .unwrap();
+        conn
+    }
+
+    fn state(json: serde_json::Value) -> State {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn host(name: &str, jump_via: Option<&str>) -> serde_json::Value {
+        serde_json::json!({
+            "name": name,
+            "username": "root",
+            "address": format!("{name}.example.com"),
+            "port": 22,
+            "key_fingerprint": "SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU",
+            "jump_via": jump_via,
+            "key_trust": "manual",
+        })
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut conn = database();
+        let summary = state(serde_json::json!({
+            "version": STATE_VERSION,
+            "hosts": [host("web", Some("bastion")), host("bastion", None)],
+            "users": [{
+                "username": "alice",
+                "keys": [{
+                    "key_type": "ssh-ed25519",
+                    "key_base64": "AAAAC3NzaC1lZDI1NTE5AAAAIAxTf8anGjRB8KZVR/jjPvKhPeoDir8UUyEoVkJ9forE",
+                    "comment": "alice@laptop",
+                }],
+            }],
+            "authorizations": [{"user": "alice", "host": "web", "login": "deploy"}],
+        }))
+        .import(&mut conn)
+        .unwrap();
+        assert_eq!(
+            (
+                summary.hosts,
+                summary.users,
+                summary.keys,
+                summary.authorizations
+            ),
+            (2, 1, 1, 1)
+        );
+
+        let exported = State::export(&mut conn).unwrap();
+        let web = exported.hosts.iter().find(|h| h.name == "web").unwrap();
+        assert_eq!(web.jump_via.as_deref(), Some("bastion"));
+        // Imported keys aren't trusted like checked ones
+        assert_eq!(web.key_trust, None);
+        assert_eq!(
+            exported.users[0].keys[0].comment.as_deref(),
+            Some("alice@laptop")
+        );
+        assert_eq!(exported.authorizations[0].login, "deploy");
+
+        let mut other = database();
+        let json = serde_json::to_value(&exported).unwrap();
+        state(json.clone()).import(&mut other).unwrap();
+        let again = State::export(&mut other).unwrap();
+        assert_eq!(serde_json::to_value(&again).unwrap(), json);
+
+        let summary = state(json).import(&mut other).unwrap();
+        assert_eq!(summary.hosts + summary.users + summary.keys, 0);
+        assert_eq!(summary.skipped, 5);
+    }
+
+    #[test]
+    fn rejects_jump_cycles() {
+        for hosts in [
+            vec![host("a", Some("a"))],
+            vec![host("a", Some("b")), host("b", Some("a"))],
+            vec![
+                host("a", Some("b")),
+                host("b", Some("c")),
+                host("c", Some("a")),
+            ],
+        ] {
+            let mut conn = database();
+            let res = state(serde_json::json!({"version": STATE_VERSION, "hosts": hosts}))
+                .import(&mut conn);
+            assert!(res.unwrap_err().contains("form a cycle"));
+            assert!(Host::get_all_hosts(&mut conn).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_strings() {
+        let mut conn = database();
+        let mut bad_trust = host("a", None);
+        bad_trust["key_trust"] = "trusted".into();
+        let mut bad_importance = host("a", None);
+        bad_importance["importance"] = "urgent".into();
+        for bad in [bad_trust, bad_importance] {
+            let res = state(serde_json::json!({"version": STATE_VERSION, "hosts": [bad]}))
+                .import(&mut conn);
+            assert!(res.unwrap_err().starts_with("Unknown"));
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use data_encoding::HEXLOWER;
+use diesel::dsl::insert_into;
+use diesel::prelude::*;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::schema::api_token;
+use crate::{
+    models::{ApiToken, NewApiToken},
+    DbConnection,
+};
+
+use super::{now, query, query_drop};
+
+const TOKEN_PREFIX: &str = "ssm_";
+
+/// Only the hash of a token is stored
+fn hash_token(token: &str) -> String {
+    HEXLOWER.encode(&Sha256::digest(token.as_bytes()))
+}
+
+impl ApiToken {
+    pub fn get_all(conn: &mut DbConnection) -> Result<Vec<Self>, String> {
+        query(
+            api_token::table
+                .order(api_token::created_at.desc())
+                .select(Self::as_select())
+                .load::<Self>(conn),
+        )
+    }
+
+    /// Creates a new token. Returns the token, which can't be retrieved later
+    pub fn create(
+        conn: &mut DbConnection,
+        name: String,
+        created_by: String,
+    ) -> Result<String, String> {
+        let mut secret = [0u8; 32];
+        rand::rngs::OsRng.fill_bytes(&mut secret);
+        let token = format!("{TOKEN_PREFIX}{}", HEXLOWER.encode(&secret));
+
+        query_drop(
+            insert_into(api_token::table)
+                .values(NewApiToken {
+                    name,
+                    token_hash: hash_token(&token),
+                    created_by,
+                    created_at: now(),
+                })
+                .execute(conn),
+        )
+        .map(|()| token)
+    }
+
+    pub fn delete(conn: &mut DbConnection, id: i32) -> Result<(), String> {
+        query_drop(diesel::delete(api_token::table.filter(api_token::id.eq(id))).execute(conn))
+    }
+
+    /// Looks up a token and records its usage
+    pub fn authenticate(conn: &mut DbConnection, token: &str) -> Result<Option<Self>, String> {
+        let hash = hash_token(token);
+        let Some(token) = query(
+            api_token::table
+                .filter(api_token::token_hash.eq(hash))
+                .select(Self::as_select())
+                .first::<Self>(conn)
+                .optional(),
+        )?
+        else {
+            return Ok(None);
+        };
+
+        query_drop(
+            diesel::update(api_token::table.filter(api_token::id.eq(token.id)))
+                .set(api_token::last_used.eq(now()))
+                .execute(conn),
+        )?;
+        Ok(Some(token))
+    }
+}
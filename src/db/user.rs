@@ -5,10 +5,11 @@ use crate::schema::user_key;
 use crate::schema::{authorization, host, user};
 use crate::{
     models::{NewUser, PublicUserKey, User},
+    ssh::KeyOptions,
     DbConnection,
 };
 
-use super::{query, query_drop, UserAndOptions};
+use super::{query, query_drop, PutOutcome, UserAndOptions};
 
 impl User {
     pub fn get_all_users(conn: &mut DbConnection) -> Result<Vec<Self>, String> {
@@ -23,6 +24,21 @@ impl User {
         )
     }
 
+    /// Get a user by id, if it exists
+    pub fn get_by_id(conn: &mut DbConnection, id: i32) -> Result<Option<Self>, String> {
+        query(user::table.find(id).first::<Self>(conn).optional())
+    }
+
+    /// Get a user by name, if it exists
+    pub fn get_from_name(conn: &mut DbConnection, username: &str) -> Result<Option<Self>, String> {
+        query(
+            user::table
+                .filter(user::username.eq(username))
+                .first::<Self>(conn)
+                .optional(),
+        )
+    }
+
     pub fn get_keys(&self, conn: &mut DbConnection) -> Result<Vec<PublicUserKey>, String> {
         query(
             user_key::table
@@ -42,8 +58,9 @@ impl User {
     }
 
     /// Delete a user from the Database
-    pub fn delete_user(conn: &mut DbConnection, username: &str) -> Result<(), String> {
-        query_drop(delete(user::table.filter(user::username.eq(username))).execute(conn))
+    /// Returns how many users were deleted, none if the name doesn't exist
+    pub fn delete_user(conn: &mut DbConnection, username: &str) -> Result<usize, String> {
+        query(delete(user::table.filter(user::username.eq(username))).execute(conn))
     }
 
     /// Update a user's enabled status and username in the Database
@@ -51,7 +68,7 @@ impl User {
         conn: &mut DbConnection,
         old_username: &str,
         new_username: &str,
-        _enabled: bool,
+        new_enabled: bool,
     ) -> Result<(), String> {
         use crate::schema::user::dsl::*;
         use diesel::prelude::*;
@@ -59,13 +76,100 @@ impl User {
         // Update username and enabled status
         diesel::update(user)
             .filter(username.eq(old_username))
-            .set((username.eq(new_username), enabled.eq(enabled)))
+            .set((username.eq(new_username), enabled.eq(new_enabled)))
             .execute(conn)
             .map_err(|e| e.to_string())?;
 
         Ok(())
     }
 
+    /// Creates the user or updates what differs. `new_username` renames it, a user
+    /// that already has the new name counts as renamed. Omitted values are kept, or
+    /// default to enabled and no email for new users; an empty email removes it.
+    /// Returns the final username.
+    pub fn put_user(
+        conn: &mut DbConnection,
+        username: &str,
+        new_username: Option<&str>,
+        enabled: Option<bool>,
+        email: Option<&str>,
+    ) -> Result<(String, PutOutcome), String> {
+        let target = new_username.unwrap_or(username);
+        if target != username
+            && Self::get_from_name(conn, username)?.is_some()
+            && Self::get_from_name(conn, target)?.is_some()
+        {
+            return Err(format!("A user named {target} exists already"));
+        }
+        let email_given = email.is_some();
+        let email = email.map(str::trim).filter(|email| !email.is_empty());
+        let res = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            let existing = match user::table
+                .filter(user::username.eq(username))
+                .first::<Self>(conn)
+                .optional()?
+            {
+                Some(user) => Some(user),
+                None => user::table
+                    .filter(user::username.eq(target))
+                    .first::<Self>(conn)
+                    .optional()?,
+            };
+            let Some(existing) = existing else {
+                insert_into(user::table)
+                    .values((
+                        user::username.eq(target),
+                        user::enabled.eq(enabled.unwrap_or(true)),
+                        user::email.eq(email),
+                    ))
+                    .execute(conn)?;
+                return Ok(PutOutcome::Created);
+            };
+
+            let enabled = enabled.unwrap_or(existing.enabled);
+            let email = match email {
+                Some(email) => Some(email.to_owned()),
+                None if email_given => None,
+                None => existing.email.clone(),
+            };
+            if existing.username == target && existing.enabled == enabled && existing.email == email
+            {
+                return Ok(PutOutcome::Unchanged);
+            }
+            diesel::update(user::table.find(existing.id))
+                .set((
+                    user::username.eq(target),
+                    user::enabled.eq(enabled),
+                    user::email.eq(email),
+                ))
+                .execute(conn)?;
+            Ok(PutOutcome::Updated)
+        });
+        query(res).map(|outcome| (target.to_owned(), outcome))
+    }
+
+    /// Sets the options of this user's authorizations that don't get them otherwise
+    pub fn set_default_options(
+        &self,
+        conn: &mut DbConnection,
+        options: Option<String>,
+    ) -> Result<(), String> {
+        let options = KeyOptions::normalize(options)?;
+        query_drop(
+            diesel::update(user::table.filter(user::id.eq(self.id)))
+                .set(user::default_options.eq(options))
+                .execute(conn),
+        )
+    }
+
+    /// Delete all authorizations of this user. Returns the number of deleted authorizations
+    pub fn revoke_authorizations(&self, conn: &mut DbConnection) -> Result<usize, String> {
+        query(
+            diesel::delete(authorization::table.filter(authorization::user_id.eq(self.id)))
+                .execute(conn),
+        )
+    }
+
     /// Find all hosts this user is authorized on
     pub fn get_authorizations(
         &self,
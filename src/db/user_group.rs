@@ -0,0 +1,124 @@
+use diesel::dsl::insert_into;
+use diesel::prelude::*;
+
+use crate::schema::{user, user_group, user_group_member};
+use crate::{
+    models::{GroupAuthorization, User, UserGroup},
+    DbConnection,
+};
+
+use super::{group_name, query, query_drop};
+
+impl UserGroup {
+    /// All teams, sorted by name
+    pub fn get_all(conn: &mut DbConnection) -> Result<Vec<Self>, String> {
+        query(user_group::table.order(user_group::name).load::<Self>(conn))
+    }
+
+    pub fn get_from_name(conn: &mut DbConnection, name: &str) -> Result<Option<Self>, String> {
+        query(
+            user_group::table
+                .filter(user_group::name.eq(name))
+                .first::<Self>(conn)
+                .optional(),
+        )
+    }
+
+    pub fn create(conn: &mut DbConnection, name: &str) -> Result<Self, String> {
+        let name = group_name(name)?;
+        if Self::get_from_name(conn, name)?.is_some() {
+            return Err(format!("There already is a team named {name}"));
+        }
+        query_drop(
+            insert_into(user_group::table)
+                .values(user_group::name.eq(name))
+                .execute(conn),
+        )?;
+        Self::get_from_name(conn, name)?.ok_or_else(|| format!("Failed to create team {name}"))
+    }
+
+    pub fn rename(&self, conn: &mut DbConnection, new_name: &str) -> Result<(), String> {
+        let new_name = group_name(new_name)?;
+        if new_name == self.name {
+            return Ok(());
+        }
+        if Self::get_from_name(conn, new_name)?.is_some() {
+            return Err(format!("There already is a team named {new_name}"));
+        }
+        query_drop(
+            diesel::update(user_group::table.find(self.id))
+                .set(user_group::name.eq(new_name))
+                .execute(conn),
+        )
+    }
+
+    /// Deletes the team, its users stay
+    pub fn delete(self, conn: &mut DbConnection) -> Result<(), String> {
+        query_drop(diesel::delete(user_group::table.find(self.id)).execute(conn))
+    }
+
+    /// The users in this team, sorted by name
+    pub fn get_members(&self, conn: &mut DbConnection) -> Result<Vec<User>, String> {
+        query(
+            user_group_member::table
+                .inner_join(user::table)
+                .filter(user_group_member::group_id.eq(self.id))
+                .order(user::username)
+                .select(User::as_select())
+                .load(conn),
+        )
+    }
+
+    /// The enabled users in the team with the given name, i.e. who the team stands for when
+    /// granting access. Unknown teams have no members.
+    pub fn resolve(conn: &mut DbConnection, name: &str) -> Result<Vec<User>, String> {
+        match Self::get_from_name(conn, name)? {
+            Some(group) => Ok(group
+                .get_members(conn)?
+                .into_iter()
+                .filter(|user| user.enabled)
+                .collect()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn add_member(&self, conn: &mut DbConnection, user: &User) -> Result<(), String> {
+        if user.get_groups(conn)?.iter().any(|g| g.id == self.id) {
+            return Err(format!("{} is already in {}", user.username, self.name));
+        }
+        GroupAuthorization::enforce_for_member(conn, self, user.id)?;
+        query_drop(
+            insert_into(user_group_member::table)
+                .values((
+                    user_group_member::group_id.eq(self.id),
+                    user_group_member::user_id.eq(user.id),
+                ))
+                .execute(conn),
+        )
+    }
+
+    pub fn remove_member(&self, conn: &mut DbConnection, user: &User) -> Result<(), String> {
+        query_drop(
+            diesel::delete(
+                user_group_member::table
+                    .filter(user_group_member::group_id.eq(self.id))
+                    .filter(user_group_member::user_id.eq(user.id)),
+            )
+            .execute(conn),
+        )
+    }
+}
+
+impl User {
+    /// The teams of this user, sorted by name
+    pub fn get_groups(&self, conn: &mut DbConnection) -> Result<Vec<UserGroup>, String> {
+        query(
+            user_group_member::table
+                .inner_join(user_group::table)
+                .filter(user_group_member::user_id.eq(self.id))
+                .order(user_group::name)
+                .select(UserGroup::as_select())
+                .load(conn),
+        )
+    }
+}
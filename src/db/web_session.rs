@@ -0,0 +1,116 @@
+use diesel::dsl::insert_into;
+use diesel::prelude::*;
+
+use crate::schema::web_session;
+use crate::{
+    models::{NewWebSession, WebSession},
+    DbConnection,
+};
+
+use super::{now, query, query_drop};
+
+impl WebSession {
+    /// The session with this key, unless it expired
+    pub fn get_by_key_hash(
+        conn: &mut DbConnection,
+        key_hash: &str,
+    ) -> Result<Option<Self>, String> {
+        query(
+            web_session::table
+                .filter(web_session::key_hash.eq(key_hash))
+                .filter(web_session::expires_at.gt(now()))
+                .select(Self::as_select())
+                .first(conn)
+                .optional(),
+        )
+    }
+
+    pub fn create(conn: &mut DbConnection, session: NewWebSession) -> Result<(), String> {
+        query_drop(
+            insert_into(web_session::table)
+                .values(session)
+                .execute(conn),
+        )
+    }
+
+    /// Replaces the state of a session. Returns whether it still existed.
+    pub fn update_state(
+        conn: &mut DbConnection,
+        key_hash: &str,
+        state: String,
+        username: Option<String>,
+        expires_at: time::PrimitiveDateTime,
+    ) -> Result<bool, String> {
+        let updated = query(
+            diesel::update(web_session::table.filter(web_session::key_hash.eq(key_hash)))
+                .set((
+                    web_session::state.eq(state),
+                    web_session::username.eq(username),
+                    web_session::last_activity.eq(now()),
+                    web_session::expires_at.eq(expires_at),
+                ))
+                .execute(conn),
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Records that the session was used
+    pub fn touch(
+        conn: &mut DbConnection,
+        id: i32,
+        client: Option<String>,
+        expires_at: time::PrimitiveDateTime,
+    ) -> Result<(), String> {
+        query(
+            diesel::update(web_session::table.filter(web_session::id.eq(id)))
+                .set((
+                    web_session::client.eq(client),
+                    web_session::last_activity.eq(now()),
+                    web_session::expires_at.eq(expires_at),
+                ))
+                .execute(conn),
+        )
+        .map(|_| ())
+    }
+
+    pub fn delete_by_key_hash(conn: &mut DbConnection, key_hash: &str) -> Result<(), String> {
+        query(
+            diesel::delete(web_session::table.filter(web_session::key_hash.eq(key_hash)))
+                .execute(conn),
+        )
+        .map(|_| ())
+    }
+
+    pub fn delete(conn: &mut DbConnection, id: i32) -> Result<(), String> {
+        query_drop(diesel::delete(web_session::table.filter(web_session::id.eq(id))).execute(conn))
+    }
+
+    /// Ends every session of a web user. Returns how many there were.
+    pub fn delete_for_user(conn: &mut DbConnection, username: &str) -> Result<usize, String> {
+        query(
+            diesel::delete(web_session::table.filter(web_session::username.eq(username)))
+                .execute(conn),
+        )
+    }
+
+    /// Forgets the expired sessions
+    pub fn prune(conn: &mut DbConnection) -> Result<(), String> {
+        query(
+            diesel::delete(web_session::table.filter(web_session::expires_at.le(now())))
+                .execute(conn),
+        )
+        .map(|_| ())
+    }
+
+    /// The sessions of logged in web users, most recently used first
+    pub fn get_logged_in(conn: &mut DbConnection) -> Result<Vec<Self>, String> {
+        query(
+            web_session::table
+                .filter(web_session::username.is_not_null())
+                .filter(web_session::expires_at.gt(now()))
+                .order(web_session::last_activity.desc())
+                .select(Self::as_select())
+                .load(conn),
+        )
+    }
+}
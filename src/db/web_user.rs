@@ -0,0 +1,412 @@
+use data_encoding::HEXLOWER;
+use diesel::dsl::insert_into;
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::schema::{password_reset, recovery_code, web_user};
+use crate::{
+    auth::Role,
+    models::{NewWebUser, WebUser},
+    totp, DbConnection,
+};
+
+use super::{now, query, query_drop};
+
+impl WebUser {
+    pub fn get_all(conn: &mut DbConnection) -> Result<Vec<Self>, String> {
+        query(web_user::table.order(web_user::username).load::<Self>(conn))
+    }
+
+    /// Looks up the web user of an OpenID Connect identity, creating it on the
+    /// first login. Returns the username to use for the session.
+    pub fn provision_oidc(
+        conn: &mut DbConnection,
+        subject: String,
+        username: String,
+        default_role: Role,
+    ) -> Result<String, String> {
+        let existing = query(
+            web_user::table
+                .filter(web_user::oidc_subject.eq(&subject))
+                .select(web_user::username)
+                .first::<String>(conn)
+                .optional(),
+        )?;
+
+        if let Some(existing) = existing {
+            query_drop(
+                diesel::update(web_user::table.filter(web_user::oidc_subject.eq(&subject)))
+                    .set(web_user::last_login.eq(now()))
+                    .execute(conn),
+            )?;
+            return Ok(existing);
+        }
+
+        let taken = query(
+            web_user::table
+                .filter(web_user::username.eq(&username))
+                .count()
+                .get_result::<i64>(conn),
+        )?;
+        if taken > 0 {
+            return Err(format!(
+                "The username '{username}' already belongs to another account"
+            ));
+        }
+
+        query_drop(
+            insert_into(web_user::table)
+                .values(NewWebUser {
+                    username: username.clone(),
+                    oidc_subject: Some(subject),
+                    created_at: now(),
+                    last_login: Some(now()),
+                    role: default_role.to_string(),
+                })
+                .execute(conn),
+        )?;
+        Ok(username)
+    }
+
+    /// Registers a user unless it already exists
+    fn create(conn: &mut DbConnection, username: &str, role: Role) -> Result<(), String> {
+        match insert_into(web_user::table)
+            .values(NewWebUser {
+                username: username.to_owned(),
+                oidc_subject: None,
+                created_at: now(),
+                last_login: None,
+                role: role.to_string(),
+            })
+            .execute(conn)
+        {
+            // Another request registered the user first
+            Ok(_) | Err(Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => Ok(()),
+            Err(e) => query(Err(e)),
+        }
+    }
+
+    /// Records a password login, registering the user on its first login
+    pub fn record_login(
+        conn: &mut DbConnection,
+        username: &str,
+        default_role: Role,
+    ) -> Result<(), String> {
+        Self::create(conn, username, default_role)?;
+        query_drop(
+            diesel::update(web_user::table.filter(web_user::username.eq(username)))
+                .set(web_user::last_login.eq(now()))
+                .execute(conn),
+        )
+    }
+
+    /// The role of a user. Users that logged in before roles existed are
+    /// registered with `default_role`.
+    pub fn role(
+        conn: &mut DbConnection,
+        username: &str,
+        default_role: Role,
+    ) -> Result<Role, String> {
+        let role = query(
+            web_user::table
+                .filter(web_user::username.eq(username))
+                .select(web_user::role)
+                .first::<String>(conn)
+                .optional(),
+        )?;
+
+        match role {
+            Some(role) => role.parse(),
+            None => Self::create(conn, username, default_role).map(|()| default_role),
+        }
+    }
+
+    /// Gives a user a role, registering it if it never logged in
+    pub fn grant_role(conn: &mut DbConnection, username: &str, role: Role) -> Result<(), String> {
+        Self::create(conn, username, role)?;
+        query_drop(
+            diesel::update(web_user::table.filter(web_user::username.eq(username)))
+                .set(web_user::role.eq(role.to_string()))
+                .execute(conn),
+        )
+    }
+
+    pub fn set_role(conn: &mut DbConnection, id: i32, role: Role) -> Result<(), String> {
+        query_drop(
+            diesel::update(web_user::table.filter(web_user::id.eq(id)))
+                .set(web_user::role.eq(role.to_string()))
+                .execute(conn),
+        )
+    }
+
+    pub fn get_by_username(
+        conn: &mut DbConnection,
+        username: &str,
+    ) -> Result<Option<Self>, String> {
+        query(
+            web_user::table
+                .filter(web_user::username.eq(username))
+                .first::<Self>(conn)
+                .optional(),
+        )
+    }
+
+    /// Whether logins of this user need a TOTP code
+    pub fn has_totp(&self) -> bool {
+        self.totp_secret.is_some()
+    }
+
+    /// Replaces the recovery codes of the user with new ones, which are returned
+    pub fn replace_recovery_codes(&self, conn: &mut DbConnection) -> Result<Vec<String>, String> {
+        let codes = totp::new_recovery_codes();
+        let res = conn.transaction::<_, Error, _>(|conn| {
+            diesel::delete(recovery_code::table.filter(recovery_code::web_user_id.eq(self.id)))
+                .execute(conn)?;
+            for code in &codes {
+                insert_into(recovery_code::table)
+                    .values((
+                        recovery_code::web_user_id.eq(self.id),
+                        recovery_code::code_hash.eq(totp::hash_recovery_code(code)),
+                    ))
+                    .execute(conn)?;
+            }
+            Ok(())
+        });
+        query(res)?;
+        Ok(codes)
+    }
+
+    /// Requires a TOTP code with this secret from now on, whose code of `step` was just
+    /// entered. Returns new recovery codes.
+    pub fn enable_totp(
+        &self,
+        conn: &mut DbConnection,
+        secret: &str,
+        step: i64,
+    ) -> Result<Vec<String>, String> {
+        query_drop(
+            diesel::update(web_user::table.filter(web_user::id.eq(self.id)))
+                .set((
+                    web_user::totp_secret.eq(secret),
+                    web_user::totp_last_step.eq(step),
+                ))
+                .execute(conn),
+        )?;
+        self.replace_recovery_codes(conn)
+    }
+
+    /// Logs the user in with the password alone again
+    pub fn disable_totp(conn: &mut DbConnection, id: i32) -> Result<(), String> {
+        let res = conn.transaction::<_, Error, _>(|conn| {
+            diesel::delete(recovery_code::table.filter(recovery_code::web_user_id.eq(id)))
+                .execute(conn)?;
+            diesel::update(web_user::table.filter(web_user::id.eq(id)))
+                .set((
+                    web_user::totp_secret.eq(None::<String>),
+                    web_user::totp_last_step.eq(None::<i64>),
+                ))
+                .execute(conn)
+        });
+        query_drop(res)
+    }
+
+    /// Marks the code of `step` as used. Fails if it or a later one was used meanwhile.
+    pub fn use_totp_step(&self, conn: &mut DbConnection, step: i64) -> Result<(), String> {
+        let updated = query(
+            diesel::update(
+                web_user::table.filter(web_user::id.eq(self.id)).filter(
+                    web_user::totp_last_step
+                        .is_null()
+                        .or(web_user::totp_last_step.lt(step)),
+                ),
+            )
+            .set(web_user::totp_last_step.eq(step))
+            .execute(conn),
+        )?;
+        match updated {
+            0 => Err(String::from("This code was already used")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Uses up a recovery code. Returns whether it was one of the user's.
+    pub fn use_recovery_code(&self, conn: &mut DbConnection, code: &str) -> Result<bool, String> {
+        let deleted = query(
+            diesel::delete(
+                recovery_code::table
+                    .filter(recovery_code::web_user_id.eq(self.id))
+                    .filter(recovery_code::code_hash.eq(totp::hash_recovery_code(code))),
+            )
+            .execute(conn),
+        )?;
+        Ok(deleted > 0)
+    }
+
+    pub fn recovery_codes_left(&self, conn: &mut DbConnection) -> Result<i64, String> {
+        query(
+            recovery_code::table
+                .filter(recovery_code::web_user_id.eq(self.id))
+                .count()
+                .get_result(conn),
+        )
+    }
+
+    /// Creates a one-time token for choosing a new password, replacing earlier ones
+    pub fn create_password_reset(
+        &self,
+        conn: &mut DbConnection,
+        validity: time::Duration,
+    ) -> Result<String, String> {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = HEXLOWER.encode(&bytes);
+        let res = conn.transaction::<_, Error, _>(|conn| {
+            diesel::delete(password_reset::table.filter(password_reset::web_user_id.eq(self.id)))
+                .execute(conn)?;
+            insert_into(password_reset::table)
+                .values((
+                    password_reset::web_user_id.eq(self.id),
+                    password_reset::token_hash.eq(hash_token(&token)),
+                    password_reset::created_at.eq(now()),
+                    password_reset::expires_at.eq(now() + validity),
+                ))
+                .execute(conn)
+        });
+        query_drop(res)?;
+        Ok(token)
+    }
+
+    /// The web user a password reset token was made for, unless it expired
+    pub fn get_by_password_reset(
+        conn: &mut DbConnection,
+        token: &str,
+    ) -> Result<Option<Self>, String> {
+        query(
+            web_user::table
+                .inner_join(password_reset::table)
+                .filter(password_reset::token_hash.eq(hash_token(token)))
+                .filter(password_reset::expires_at.gt(now()))
+                .select(web_user::all_columns)
+                .first::<Self>(conn)
+                .optional(),
+        )
+    }
+
+    /// Makes the password reset tokens of the user unusable
+    pub fn finish_password_reset(&self, conn: &mut DbConnection) -> Result<(), String> {
+        query(
+            diesel::delete(password_reset::table.filter(password_reset::web_user_id.eq(self.id)))
+                .execute(conn),
+        )
+        .map(|_| ())
+    }
+}
+
+/// Only hashes of password reset tokens are stored, so the database doesn't hand out resets
+fn hash_token(token: &str) -> String {
+    HEXLOWER.encode(&Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::Connection;
+    use diesel_migrations::MigrationHarness;
+
+    use super::*;
+
+    /// A new database with one web user
+    fn alice() -> (DbConnection, WebUser) {
+        let mut conn = DbConnection::establish(":memory:").unwrap();
+        conn.run_pending_migrations(crate::SQLITE_MIGRATIONS)
+            .unwrap();
+        WebUser::record_login(&mut conn, "alice", Role::Viewer).unwrap();
+        let alice = WebUser::get_by_username(&mut conn, "alice")
+            .unwrap()
+            .unwrap();
+        (conn, alice)
+    }
+
+    #[test]
+    fn totp_steps_are_used_once() {
+        let (mut conn, alice) = alice();
+        alice
+            .enable_totp(&mut conn, &totp::new_secret(), 100)
+            .unwrap();
+
+        assert!(alice.use_totp_step(&mut conn, 100).is_err());
+        alice.use_totp_step(&mut conn, 101).unwrap();
+        assert!(alice.use_totp_step(&mut conn, 101).is_err());
+        // Nor an earlier code once a later one was used
+        assert!(alice.use_totp_step(&mut conn, 99).is_err());
+    }
+
+    #[test]
+    fn recovery_codes_are_used_once() {
+        let (mut conn, alice) = alice();
+        let codes = alice
+            .enable_totp(&mut conn, &totp::new_secret(), 100)
+            .unwrap();
+        assert_eq!(alice.recovery_codes_left(&mut conn).unwrap(), 10);
+
+        assert!(alice
+            .use_recovery_code(&mut conn, &codes[0].to_uppercase())
+            .unwrap());
+        assert!(!alice.use_recovery_code(&mut conn, &codes[0]).unwrap());
+        assert!(!alice.use_recovery_code(&mut conn, "00000-00000").unwrap());
+        assert_eq!(alice.recovery_codes_left(&mut conn).unwrap(), 9);
+
+        // New codes replace the old ones
+        let fresh = alice.replace_recovery_codes(&mut conn).unwrap();
+        assert!(!alice.use_recovery_code(&mut conn, &codes[1]).unwrap());
+        assert!(alice.use_recovery_code(&mut conn, &fresh[1]).unwrap());
+
+        WebUser::disable_totp(&mut conn, alice.id).unwrap();
+        assert_eq!(alice.recovery_codes_left(&mut conn).unwrap(), 0);
+    }
+
+    #[test]
+    fn reset_tokens_are_used_once() {
+        let (mut conn, alice) = alice();
+        let validity = time::Duration::hours(1);
+        let first = alice.create_password_reset(&mut conn, validity).unwrap();
+        let token = alice.create_password_reset(&mut conn, validity).unwrap();
+        assert_ne!(first, token);
+
+        // Only the last token of a user works
+        assert!(WebUser::get_by_password_reset(&mut conn, &first)
+            .unwrap()
+            .is_none());
+        let found = WebUser::get_by_password_reset(&mut conn, &token)
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.id, alice.id);
+        assert!(WebUser::get_by_password_reset(&mut conn, &token[1..])
+            .unwrap()
+            .is_none());
+
+        found.finish_password_reset(&mut conn).unwrap();
+        assert!(WebUser::get_by_password_reset(&mut conn, &token)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn reset_tokens_expire() {
+        let (mut conn, alice) = alice();
+        let token = alice
+            .create_password_reset(&mut conn, -time::Duration::seconds(1))
+            .unwrap();
+        assert!(WebUser::get_by_password_reset(&mut conn, &token)
+            .unwrap()
+            .is_none());
+
+        let token = alice
+            .create_password_reset(&mut conn, time::Duration::minutes(1))
+            .unwrap();
+        assert!(WebUser::get_by_password_reset(&mut conn, &token)
+            .unwrap()
+            .is_some());
+    }
+}
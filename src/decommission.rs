@@ -0,0 +1,254 @@
+use std::{collections::HashMap, sync::Arc};
+
+use log::{info, warn};
+use time::OffsetDateTime;
+
+use crate::{
+    db::{self, AllowedUserOnHost, BlockingPool},
+    jobs::{JobTracker, Step, StepStatus},
+    models::{ArchivedHost, Host, NewArchivedHost},
+    ssh::{CachingSshClient, SshClient},
+};
+
+const STEPS: [&str; 5] = [
+    "Export access report",
+    "Remove managed keys",
+    "Archive host",
+    "Release name",
+    "Remove own access",
+];
+/// The step deleting the host, ssm gives up its access only afterwards
+pub const RELEASE_STEP: usize = 3;
+
+/// Runs decommission jobs in the background and keeps track of their progress
+#[derive(Clone)]
+pub struct Decommissioner {
+    db: BlockingPool,
+    ssh_client: SshClient,
+    caching_ssh_client: Arc<CachingSshClient>,
+    /// Progress by host name
+    jobs: JobTracker,
+}
+
+impl Decommissioner {
+    pub fn new(
+        db: BlockingPool,
+        ssh_client: SshClient,
+        caching_ssh_client: Arc<CachingSshClient>,
+    ) -> Self {
+        Self {
+            db,
+            ssh_client,
+            caching_ssh_client,
+            jobs: JobTracker::default(),
+        }
+    }
+
+    /// Progress of the last decommission job for this host name
+    pub async fn status(&self, host_name: &str) -> Option<Vec<Step>> {
+        self.jobs.status(host_name).await
+    }
+
+    /// Starts decommissioning a host. If `skip_unreachable` is set, failing to
+    /// remove the keys from the host doesn't stop the job.
+    pub async fn start(&self, host: Host, skip_unreachable: bool) -> Result<(), String> {
+        let dependant_hosts = {
+            let host = host.clone();
+            self.db
+                .run(move |conn| host.get_dependant_hosts(conn))
+                .await??
+        };
+        if !dependant_hosts.is_empty() {
+            return Err(format!(
+                "These hosts connect via {}: {}",
+                host.name,
+                dependant_hosts.join(", ")
+            ));
+        }
+
+        self.jobs.begin(&host.name, &STEPS).await?;
+
+        let this = self.clone();
+        actix_web::rt::spawn(async move { this.run(host, skip_unreachable).await });
+        Ok(())
+    }
+
+    async fn run(&self, host: Host, skip_unreachable: bool) {
+        let name = host.name.clone();
+        info!(host = name.as_str(), action = "decommission"; "Decommissioning {name}");
+
+        self.jobs.set(&name, 0, StepStatus::Running).await;
+        let db_host = host.clone();
+        let entries = match self
+            .db
+            .run(move |conn| db_host.get_authorized_keys(conn))
+            .await
+            .map_err(String::from)
+            .and_then(|res| res)
+        {
+            Ok(entries) => entries,
+            Err(e) => return self.jobs.fail(&name, 0, e).await,
+        };
+        let report = access_report(&host, &entries);
+        self.jobs
+            .set(
+                &name,
+                0,
+                StepStatus::Done(format!("{} authorization(s)", entries.len())),
+            )
+            .await;
+
+        // Whether failing to reach the host is fine for the job
+        let may_skip = skip_unreachable || host.observed_until().is_some();
+
+        self.jobs.set(&name, 1, StepStatus::Running).await;
+        match self.remove_keys(&host, &entries).await {
+            Ok(logins) => {
+                self.jobs
+                    .set(&name, 1, StepStatus::Done(format!("{logins} login(s)")))
+                    .await;
+            }
+            // Observed hosts were never changed by ssm, so it leaves them as they are
+            Err(e) if may_skip => {
+                warn!(
+                    host = name.as_str(), action = "decommission";
+                    "Skipping key removal on {name}: {e}"
+                );
+                self.jobs.set(&name, 1, StepStatus::Skipped(e)).await;
+            }
+            Err(e) => return self.jobs.fail(&name, 1, e).await,
+        }
+
+        self.jobs.set(&name, 2, StepStatus::Running).await;
+        let archived = NewArchivedHost {
+            name: host.name.clone(),
+            username: host.username.clone(),
+            address: host.address.clone(),
+            port: host.port,
+            key_fingerprint: host.key_fingerprint.clone(),
+            decommissioned_at: db::now(),
+            access_report: report,
+        };
+        match self
+            .db
+            .run(move |conn| ArchivedHost::add(conn, archived))
+            .await
+        {
+            Ok(Ok(())) => {
+                self.jobs
+                    .set(&name, 2, StepStatus::Done(String::new()))
+                    .await
+            }
+            Ok(Err(e)) => return self.jobs.fail(&name, 2, e).await,
+            Err(e) => return self.jobs.fail(&name, 2, e.to_string()).await,
+        }
+
+        self.jobs
+            .set(&name, RELEASE_STEP, StepStatus::Running)
+            .await;
+        let db_host = host.clone();
+        match self.db.run(move |conn| db_host.delete(conn)).await {
+            Ok(Ok(amt)) => {
+                self.caching_ssh_client.remove(&name).await;
+                self.jobs
+                    .set(
+                        &name,
+                        RELEASE_STEP,
+                        StepStatus::Done(format!("Deleted {amt} record(s)")),
+                    )
+                    .await;
+            }
+            Ok(Err(e)) => return self.jobs.fail(&name, RELEASE_STEP, e).await,
+            Err(e) => return self.jobs.fail(&name, RELEASE_STEP, e.to_string()).await,
+        }
+
+        // Only once the host is gone from the database, so a failed step before
+        // leaves ssm able to retry
+        self.jobs.set(&name, 4, StepStatus::Running).await;
+        let own_login: Vec<String> = entries
+            .iter()
+            .filter(|entry| entry.login == host.username)
+            .map(|entry| entry.key.key_base64.clone())
+            .collect();
+        match self
+            .ssh_client
+            .remove_own_access(host.clone(), &own_login)
+            .await
+        {
+            Ok(()) => {
+                self.jobs
+                    .set(&name, 4, StepStatus::Done(host.username.clone()))
+                    .await;
+            }
+            Err(e) if may_skip => {
+                warn!(
+                    host = name.as_str(), action = "decommission";
+                    "Skipping removal of own access on {name}: {e}"
+                );
+                self.jobs
+                    .set(&name, 4, StepStatus::Skipped(e.to_string()))
+                    .await;
+            }
+            Err(e) => {
+                return self
+                    .jobs
+                    .fail(&name, 4, format!("{}: {e}", host.username))
+                    .await
+            }
+        }
+        info!(host = name.as_str(), action = "decommission"; "Decommissioned {name}");
+    }
+
+    /// Removes the keys we manage from all logins but our own, which is left for
+    /// after the host was archived. Returns the number of logins touched.
+    async fn remove_keys(
+        &self,
+        host: &Host,
+        entries: &[AllowedUserOnHost],
+    ) -> Result<usize, String> {
+        if host.key_fingerprint.is_none() {
+            return Err(String::from("Host has no known host key"));
+        }
+
+        let mut by_login: HashMap<&str, Vec<String>> = HashMap::new();
+        for entry in entries {
+            by_login
+                .entry(entry.login.as_str())
+                .or_default()
+                .push(entry.key.key_base64.clone());
+        }
+        by_login.remove(host.username.as_str());
+
+        let logins = by_login.len();
+        for (login, keys) in by_login {
+            self.ssh_client
+                .remove_keys(host.clone(), login.to_owned(), &keys)
+                .await
+                .map_err(|e| format!("{login}: {e}"))?;
+        }
+
+        Ok(logins)
+    }
+}
+
+/// Who had access to a host, one line per key
+fn access_report(host: &Host, entries: &[AllowedUserOnHost]) -> String {
+    let header = format!(
+        "# Access report for {} ({}@{}:{}), generated {}\n# user\tlogin\toptions\tkey\n",
+        host.name,
+        host.username,
+        host.address,
+        host.port,
+        OffsetDateTime::now_utc()
+    );
+
+    entries.iter().fold(header, |buf, entry| {
+        buf + &format!(
+            "{}\t{}\t{}\t{}\n",
+            entry.username,
+            entry.login,
+            entry.options.as_deref().unwrap_or(""),
+            entry.key.to_openssh()
+        )
+    })
+}
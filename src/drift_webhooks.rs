@@ -0,0 +1,162 @@
+use std::time::Duration;
+
+use data_encoding::HEXLOWER;
+use hmac::{Hmac, Mac};
+use log::{error, info};
+use openidconnect::reqwest;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use time::format_description::well_known::Rfc3339;
+
+use crate::{
+    secrets,
+    ssh::{DiffItem, HostDiff},
+};
+
+/// Header carrying the signature of the body
+const SIGNATURE_HEADER: &str = "X-SSM-Signature";
+
+const fn default_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+/// An endpoint that is told about unauthorized keys found by the scheduled check
+#[derive(Debug, Clone, Deserialize)]
+pub struct DriftWebhookConfig {
+    /// Receives the drift as JSON via POST
+    url: String,
+    /// Signs the body with HMAC-SHA256, sent as `X-SSM-Signature: sha256=<hex>`
+    #[serde(default, deserialize_with = "secrets::deserialize_optional_secret")]
+    secret: Option<String>,
+    /// Timeout in seconds
+    #[serde(
+        default = "default_timeout",
+        deserialize_with = "crate::deserialize_timeout"
+    )]
+    timeout: Duration,
+}
+
+/// Sent once per host with unauthorized keys
+#[derive(Debug, Serialize)]
+struct DriftEvent<'a> {
+    event: &'static str,
+    host: &'a str,
+    /// RFC 3339
+    checked_at: String,
+    keys: Vec<DriftKey<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct DriftKey<'a> {
+    login: &'a str,
+    /// `unknown`, `unauthorized` (of a known user) or `expired`
+    kind: &'static str,
+    algorithm: String,
+    /// The key, base64 encoded
+    key: &'a str,
+    comment: Option<&'a str>,
+    /// Owner of the key, if it belongs to a known user
+    user: Option<&'a str>,
+}
+
+/// Posts the unauthorized keys the scheduled check finds to every configured webhook
+#[derive(Clone)]
+pub struct DriftWebhooks {
+    hooks: Vec<DriftWebhookConfig>,
+    http: reqwest::Client,
+}
+
+impl DriftWebhooks {
+    pub fn new(hooks: Vec<DriftWebhookConfig>) -> Result<Self, String> {
+        let http = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|e| format!("Failed to create HTTP client: {e}"))?;
+        Ok(Self { hooks, http })
+    }
+
+    /// Sends one event per host with unauthorized keys. Failures are logged, the check goes on.
+    pub async fn notify(&self, state: &[(String, HostDiff)]) {
+        if self.hooks.is_empty() {
+            return;
+        }
+        for (host, (checked_at, diff)) in state {
+            let Ok(logins) = diff else {
+                continue;
+            };
+            let keys: Vec<DriftKey> = logins
+                .iter()
+                .flat_map(|(login, items)| items.iter().map(move |item| (login, item)))
+                .filter_map(|(login, item)| {
+                    let (kind, key, user) = match item {
+                        DiffItem::UnknownKey(key) => ("unknown", key, None),
+                        DiffItem::UnauthorizedKey(key, user) => ("unauthorized", key, Some(user)),
+                        DiffItem::ExpiredKey(key, user) => ("expired", key, Some(user)),
+                        _ => return None,
+                    };
+                    Some(DriftKey {
+                        login,
+                        kind,
+                        algorithm: key.algorithm.to_string(),
+                        key: &key.base64,
+                        comment: key.comment.as_deref(),
+                        user: user.map(String::as_str),
+                    })
+                })
+                .collect();
+            if keys.is_empty() {
+                continue;
+            }
+
+            let event = DriftEvent {
+                event: "drift",
+                host,
+                checked_at: checked_at.format(&Rfc3339).unwrap_or_default(),
+                keys,
+            };
+            let body = match serde_json::to_vec(&event) {
+                Ok(body) => body,
+                Err(e) => {
+                    error!(
+                        host = host.as_str(), action = "drift_webhook";
+                        "Failed to serialize the drift of {host}: {e}"
+                    );
+                    continue;
+                }
+            };
+            for hook in &self.hooks {
+                match self.send(hook, body.clone()).await {
+                    Ok(()) => info!(
+                        host = host.as_str(), action = "drift_webhook";
+                        "Sent the drift of {host} to {}", hook.url
+                    ),
+                    Err(e) => error!(
+                        host = host.as_str(), action = "drift_webhook";
+                        "Failed to send the drift of {host} to {}: {e}", hook.url
+                    ),
+                }
+            }
+        }
+    }
+
+    async fn send(&self, hook: &DriftWebhookConfig, body: Vec<u8>) -> Result<(), String> {
+        let mut request = self
+            .http
+            .post(&hook.url)
+            .timeout(hook.timeout)
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        if let Some(secret) = &hook.secret {
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+                .map_err(|e| format!("Invalid secret: {e}"))?;
+            mac.update(&body);
+            let signature = HEXLOWER.encode(&mac.finalize().into_bytes());
+            request = request.header(SIGNATURE_HEADER, format!("sha256={signature}"));
+        }
+
+        let response = request.body(body).send().await.map_err(|e| e.to_string())?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(format!("answered {status}")),
+        }
+    }
+}
@@ -0,0 +1,331 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use log::{error, info};
+use openidconnect::reqwest;
+use serde::Deserialize;
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    aws::{AwsCredentials, AwsRequest},
+    db::{sync_discovered_hosts, BlockingPool, DiscoveredHost, DiscoverySummary},
+    secrets,
+    ssh::SshClient,
+};
+
+/// `source` of the hosts discovered here
+const SOURCE: &str = "ec2";
+const API_VERSION: &str = "2016-11-15";
+const PAGE_SIZE: &str = "1000";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+const fn default_interval() -> Option<Duration> {
+    Some(Duration::from_secs(3600))
+}
+
+/// Which address of an instance hosts are connected to
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Ec2Address {
+    /// The private DNS name, or the private IP address
+    #[default]
+    Private,
+    /// The public DNS name, or the public IP address
+    Public,
+}
+
+/// Creates hosts for the EC2 instances of a region
+#[derive(Debug, Clone, Deserialize)]
+pub struct Ec2Config {
+    /// e.g. `eu-central-1`
+    region: String,
+    /// Defaults to `https://ec2.<region>.amazonaws.com`
+    #[serde(default)]
+    endpoint: Option<String>,
+    access_key_id: String,
+    #[serde(deserialize_with = "secrets::deserialize_secret")]
+    secret_access_key: String,
+    /// Only instances with all of these tags, e.g. `{ ssm = "managed" }`
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+    /// User ssm logs in as on the new hosts
+    username: String,
+    #[serde(default)]
+    address: Ec2Address,
+    /// Seconds between discoveries, starting at startup (default 3600, 0 disables)
+    #[serde(
+        default = "default_interval",
+        deserialize_with = "crate::deserialize_interval"
+    )]
+    interval: Option<Duration>,
+}
+
+impl Ec2Config {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.region.is_empty() {
+            return Err(String::from("The region of [ec2] can't be empty"));
+        }
+        if self.username.is_empty() {
+            return Err(String::from("The username of [ec2] can't be empty"));
+        }
+        if let Some(endpoint) = &self.endpoint {
+            reqwest::Url::parse(endpoint)
+                .map_err(|e| format!("Invalid [ec2] endpoint '{endpoint}': {e}"))?;
+        }
+        Ok(())
+    }
+
+    fn endpoint(&self) -> String {
+        self.endpoint
+            .clone()
+            .unwrap_or_else(|| format!("https://ec2.{}.amazonaws.com", self.region))
+    }
+}
+
+/// An instance from a DescribeInstances response
+struct Instance {
+    id: String,
+    /// e.g. `running` or `terminated`
+    state: String,
+    name: Option<String>,
+    private_address: Option<String>,
+    public_address: Option<String>,
+}
+
+/// Content of the first `<tag>` element, `None` if it is missing or empty
+fn element<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let (_, rest) = xml.split_once(&format!("<{tag}>"))?;
+    let (value, _) = rest.split_once(&format!("</{tag}>"))?;
+    Some(value.trim()).filter(|value| !value.is_empty())
+}
+
+/// Undoes the escaping of XML text
+fn unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// The instances of a DescribeInstances response. Each instance's own fields come
+/// before those of its network interfaces, so the first match is the right one.
+fn parse_instances(xml: &str) -> Vec<Instance> {
+    xml.split("<instanceId>")
+        .skip(1)
+        .filter_map(|instance| {
+            let (id, rest) = instance.split_once("</instanceId>")?;
+            let name = rest
+                .split_once("<tagSet>")
+                .and_then(|(_, tags)| tags.split_once("</tagSet>"))
+                .and_then(|(tags, _)| {
+                    tags.split("<item>")
+                        .find(|tag| element(tag, "key") == Some("Name"))
+                        .and_then(|tag| element(tag, "value"))
+                })
+                .map(unescape);
+            let state = rest
+                .split_once("<instanceState>")
+                .and_then(|(_, state)| element(state, "name"))?;
+            Some(Instance {
+                id: id.trim().to_owned(),
+                state: state.to_owned(),
+                name,
+                private_address: element(rest, "privateDnsName")
+                    .or_else(|| element(rest, "privateIpAddress"))
+                    .map(str::to_owned),
+                public_address: element(rest, "dnsName")
+                    .or_else(|| element(rest, "ipAddress"))
+                    .map(str::to_owned),
+            })
+        })
+        .collect()
+}
+
+/// Name tags may contain anything, host names are better without whitespace
+fn host_name(instance: &Instance) -> String {
+    instance
+        .name
+        .as_deref()
+        .map(|name| name.split_whitespace().collect::<Vec<_>>().join("-"))
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| instance.id.clone())
+}
+
+/// Periodically creates hosts for new EC2 instances, keeps their names and addresses up to date
+/// and flags the hosts of terminated instances
+#[derive(Clone)]
+pub struct Ec2Discovery {
+    db: BlockingPool,
+    config: Ec2Config,
+    ssh_client: SshClient,
+    http: reqwest::Client,
+}
+
+impl Ec2Discovery {
+    pub fn new(db: BlockingPool, config: Ec2Config, ssh_client: SshClient) -> Result<Self, String> {
+        config.validate()?;
+        let http = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            db,
+            config,
+            ssh_client,
+            http,
+        })
+    }
+
+    /// Discovers now and then every configured interval, unless disabled
+    pub fn start(&self) {
+        let Some(interval) = self.config.interval else {
+            return;
+        };
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                if let Err(e) = this.run().await {
+                    error!("Failed to discover EC2 instances: {e}");
+                }
+            }
+        });
+    }
+
+    /// One page of DescribeInstances, signed with AWS Signature Version 4
+    async fn describe_instances(&self, next_token: Option<&str>) -> Result<String, String> {
+        let endpoint = self.config.endpoint();
+        let url = reqwest::Url::parse(&endpoint)
+            .map_err(|e| format!("Invalid EC2 endpoint '{endpoint}': {e}"))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_owned(),
+            (None, _) => return Err(format!("Invalid EC2 endpoint '{endpoint}'")),
+        };
+
+        let filters: Vec<(String, String)> = self
+            .config
+            .tags
+            .iter()
+            .enumerate()
+            .flat_map(|(index, (key, value))| {
+                [
+                    (format!("Filter.{}.Name", index + 1), format!("tag:{key}")),
+                    (format!("Filter.{}.Value.1", index + 1), value.clone()),
+                ]
+            })
+            .collect();
+        let mut query: Vec<(&str, &str)> = vec![
+            ("Action", "DescribeInstances"),
+            ("Version", API_VERSION),
+            ("MaxResults", PAGE_SIZE),
+        ];
+        query.extend(filters.iter().map(|(n, v)| (n.as_str(), v.as_str())));
+        if let Some(next_token) = next_token {
+            query.push(("NextToken", next_token));
+        }
+
+        let signed = AwsRequest {
+            service: "ec2",
+            region: &self.config.region,
+            method: "GET",
+            host: &host,
+            path: "/",
+            query: &query,
+            body: &[],
+        }
+        .sign(&AwsCredentials {
+            access_key_id: &self.config.access_key_id,
+            secret_access_key: &self.config.secret_access_key,
+        });
+
+        let mut url = url;
+        url.set_path("/");
+        url.set_query(Some(&signed.query));
+        let response = self
+            .http
+            .get(url)
+            .header(reqwest::header::AUTHORIZATION, signed.authorization)
+            .header("x-amz-content-sha256", signed.payload_hash)
+            .header("x-amz-date", signed.timestamp)
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .await
+            .map_err(|e| format!("EC2: {e}"))?;
+        let status = response.status();
+        let text = response.text().await.map_err(|e| format!("EC2: {e}"))?;
+        match status.is_success() {
+            true => Ok(text),
+            false => Err(format!("EC2 answered {status}: {text}")),
+        }
+    }
+
+    /// Lists the instances and brings the hosts in line with them
+    pub async fn run(&self) -> Result<DiscoverySummary, String> {
+        let mut instances = Vec::new();
+        let mut next_token = None;
+        loop {
+            let page = self.describe_instances(next_token.as_deref()).await?;
+            instances.extend(parse_instances(&page));
+            next_token = page
+                .rsplit_once("<nextToken>")
+                .and_then(|(_, rest)| rest.split_once("</nextToken>"))
+                .map(|(token, _)| unescape(token.trim()))
+                .filter(|token| !token.is_empty());
+            if next_token.is_none() {
+                break;
+            }
+        }
+
+        let mut terminated = Vec::new();
+        let mut found = Vec::new();
+        for instance in instances {
+            if matches!(instance.state.as_str(), "shutting-down" | "terminated") {
+                terminated.push(instance.id);
+                continue;
+            }
+            found.push(DiscoveredHost {
+                name: host_name(&instance),
+                address: match self.config.address {
+                    Ec2Address::Private => instance.private_address,
+                    Ec2Address::Public => instance.public_address,
+                },
+                external_id: instance.id,
+            });
+        }
+
+        let username = self.config.username.clone();
+        let observed_until = self.ssh_client.observation_end();
+        let summary = self
+            .db
+            .run(move |conn| {
+                sync_discovered_hosts(
+                    conn,
+                    SOURCE,
+                    &username,
+                    observed_until,
+                    found,
+                    |id, name| {
+                        if terminated.iter().any(|terminated| terminated == id) {
+                            format!("EC2 instance {id} of {name} was terminated")
+                        } else {
+                            format!(
+                            "EC2 instance {id} of {name} isn't listed anymore, it lost its tags"
+                        )
+                        }
+                    },
+                )
+            })
+            .await??;
+        if summary.created + summary.updated + summary.gone > 0 {
+            info!(
+                "EC2: {} new hosts, {} updated, {} gone",
+                summary.created, summary.updated, summary.gone
+            );
+        }
+        Ok(summary)
+    }
+}
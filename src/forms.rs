@@ -77,7 +77,7 @@ impl FormResponseBuilder {
         }
     }
 
-    pub fn _set_status(mut self, status: StatusCode) -> Self {
+    pub fn set_status(mut self, status: StatusCode) -> Self {
         self.status = status;
         self
     }
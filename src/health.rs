@@ -0,0 +1,253 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use log::{info, warn};
+use serde::Deserialize;
+use time::OffsetDateTime;
+use tokio::{sync::RwLock, time::MissedTickBehavior};
+
+use crate::{db::BlockingPool, ssh::SshClient};
+
+/// Fewer recent connections don't tell whether SSH is failing
+const MIN_CONNECTIONS: usize = 10;
+
+const fn default_interval() -> Option<Duration> {
+    Some(Duration::from_secs(10))
+}
+
+const fn default_max_pool_usage() -> usize {
+    80
+}
+
+const fn default_max_ssh_failure_rate() -> usize {
+    50
+}
+
+const fn default_max_queue_depth() -> usize {
+    10
+}
+
+const fn default_slowdown_delay() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn deserialize_millis<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    u64::deserialize(deserializer).map(Duration::from_millis)
+}
+
+/// When fleet operations slow down or pause. A signal above its threshold slows them down,
+/// at twice the threshold (at most 100%) they pause until the signal recovers.
+#[derive(Debug, Deserialize, Clone)]
+pub struct HealthConfig {
+    /// Seconds between health checks (default 10, 0 disables the backpressure)
+    #[serde(
+        default = "default_interval",
+        deserialize_with = "crate::deserialize_interval"
+    )]
+    interval: Option<Duration>,
+    /// Percentage of busy database slots
+    #[serde(default = "default_max_pool_usage")]
+    max_pool_usage: usize,
+    /// Percentage of failed SSH connections in the last five minutes
+    #[serde(default = "default_max_ssh_failure_rate")]
+    max_ssh_failure_rate: usize,
+    /// Database operations waiting for a free slot
+    #[serde(default = "default_max_queue_depth")]
+    max_queue_depth: usize,
+    /// Milliseconds fleet operations wait before each host while slowed down
+    #[serde(
+        default = "default_slowdown_delay",
+        deserialize_with = "deserialize_millis"
+    )]
+    slowdown_delay: Duration,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            interval: default_interval(),
+            max_pool_usage: default_max_pool_usage(),
+            max_ssh_failure_rate: default_max_ssh_failure_rate(),
+            max_queue_depth: default_max_queue_depth(),
+            slowdown_delay: default_slowdown_delay(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HealthStatus {
+    Healthy,
+    SlowedDown,
+    Paused,
+}
+
+impl fmt::Display for HealthStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Healthy => "healthy",
+            Self::SlowedDown => "slowed down",
+            Self::Paused => "paused",
+        })
+    }
+}
+
+/// A health signal with its threshold
+#[derive(Debug, Clone)]
+pub struct Signal {
+    pub name: &'static str,
+    pub value: usize,
+    pub threshold: usize,
+    /// Values are percentages
+    pub percentage: bool,
+}
+
+impl Signal {
+    fn status(&self) -> HealthStatus {
+        let pause_at = match self.percentage {
+            true => (self.threshold * 2).min(100),
+            false => self.threshold * 2,
+        };
+        if self.value >= pause_at {
+            HealthStatus::Paused
+        } else if self.value > self.threshold {
+            HealthStatus::SlowedDown
+        } else {
+            HealthStatus::Healthy
+        }
+    }
+}
+
+/// The outcome of the last health check
+#[derive(Debug, Clone)]
+pub struct HealthState {
+    pub status: HealthStatus,
+    /// When the status last changed
+    pub since: OffsetDateTime,
+    pub checked: Option<OffsetDateTime>,
+    pub signals: Vec<Signal>,
+}
+
+/// Watches the database pool and SSH connections and slows down or pauses fleet operations
+/// while they are overloaded
+#[derive(Debug, Clone)]
+pub struct HealthMonitor {
+    db: BlockingPool,
+    ssh_client: SshClient,
+    config: HealthConfig,
+    state: Arc<RwLock<HealthState>>,
+}
+
+impl HealthMonitor {
+    pub fn new(db: BlockingPool, ssh_client: SshClient, config: HealthConfig) -> Self {
+        Self {
+            db,
+            ssh_client,
+            config,
+            state: Arc::new(RwLock::new(HealthState {
+                status: HealthStatus::Healthy,
+                since: OffsetDateTime::now_utc(),
+                checked: None,
+                signals: Vec::new(),
+            })),
+        }
+    }
+
+    /// Checks the health every configured interval, unless disabled
+    pub fn start(&self) {
+        let Some(interval) = self.config.interval else {
+            return;
+        };
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                this.check().await;
+            }
+        });
+    }
+
+    pub const fn is_enabled(&self) -> bool {
+        self.config.interval.is_some()
+    }
+
+    pub async fn state(&self) -> HealthState {
+        self.state.read().await.clone()
+    }
+
+    fn signals(&self) -> Vec<Signal> {
+        let pool = self.db.stats();
+        let (connections, failed) = self.ssh_client.connection_failures();
+        let failure_rate = match connections >= MIN_CONNECTIONS {
+            true => failed * 100 / connections,
+            false => 0,
+        };
+        vec![
+            Signal {
+                name: "Database pool usage",
+                value: pool.running * 100 / pool.size.max(1),
+                threshold: self.config.max_pool_usage,
+                percentage: true,
+            },
+            Signal {
+                name: "SSH failure rate",
+                value: failure_rate,
+                threshold: self.config.max_ssh_failure_rate,
+                percentage: true,
+            },
+            Signal {
+                name: "Queued database operations",
+                value: pool.queued,
+                threshold: self.config.max_queue_depth,
+                percentage: false,
+            },
+        ]
+    }
+
+    /// Reads the signals and updates the status
+    pub async fn check(&self) -> HealthStatus {
+        let signals = self.signals();
+        let status = signals
+            .iter()
+            .map(Signal::status)
+            .max()
+            .unwrap_or(HealthStatus::Healthy);
+
+        let mut state = self.state.write().await;
+        let now = OffsetDateTime::now_utc();
+        if state.status != status {
+            let reasons: Vec<String> = signals
+                .iter()
+                .filter(|signal| signal.status() != HealthStatus::Healthy)
+                .map(|signal| format!("{} at {}", signal.name, signal.value))
+                .collect();
+            match status {
+                HealthStatus::Healthy => info!("Fleet operations resume, the system is healthy"),
+                _ => warn!("Fleet operations are {status}: {}", reasons.join(", ")),
+            }
+            state.status = status;
+            state.since = now;
+        }
+        state.checked = Some(now);
+        state.signals = signals;
+        status
+    }
+
+    /// Waits before the next host of a fleet operation: not at all while healthy, the
+    /// slowdown delay while slowed down and until the next healthier check while paused
+    pub async fn throttle(&self) {
+        loop {
+            match self.state.read().await.status {
+                HealthStatus::Healthy => return,
+                HealthStatus::SlowedDown => {
+                    return tokio::time::sleep(self.config.slowdown_delay).await;
+                }
+                HealthStatus::Paused => {}
+            }
+            tokio::time::sleep(self.config.interval.unwrap_or(default_slowdown_delay())).await;
+        }
+    }
+}
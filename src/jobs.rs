@@ -0,0 +1,92 @@
+use std::{collections::HashMap, sync::Arc};
+
+use log::warn;
+use tokio::sync::RwLock;
+
+#[derive(Debug, Clone)]
+pub enum StepStatus {
+    Pending,
+    Running,
+    Done(String),
+    Skipped(String),
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Step {
+    pub name: String,
+    pub status: StepStatus,
+}
+
+/// Whether no step of a job is pending or running
+pub fn is_finished(steps: &[Step]) -> bool {
+    !steps
+        .iter()
+        .any(|step| matches!(step.status, StepStatus::Pending | StepStatus::Running))
+}
+
+/// Keeps track of the progress of multi-step background jobs since startup
+#[derive(Debug, Clone, Default)]
+pub struct JobTracker {
+    jobs: Arc<RwLock<HashMap<String, Vec<Step>>>>,
+}
+
+impl JobTracker {
+    /// Progress of the last job for this key
+    pub async fn status(&self, key: &str) -> Option<Vec<Step>> {
+        self.jobs.read().await.get(key).cloned()
+    }
+
+    /// Registers a new job, unless one is still running for this key
+    pub async fn begin(&self, key: &str, steps: &[&str]) -> Result<(), String> {
+        let mut jobs = self.jobs.write().await;
+        if jobs.get(key).is_some_and(|steps| !is_finished(steps)) {
+            return Err(String::from("This job is already running"));
+        }
+
+        jobs.insert(
+            key.to_owned(),
+            steps
+                .iter()
+                .map(|name| Step {
+                    name: (*name).to_owned(),
+                    status: StepStatus::Pending,
+                })
+                .collect(),
+        );
+        Ok(())
+    }
+
+    pub async fn set(&self, key: &str, step: usize, status: StepStatus) {
+        if let Some(steps) = self.jobs.write().await.get_mut(key) {
+            steps[step].status = status;
+        }
+    }
+
+    /// Skips the steps that haven't started yet. Returns their names.
+    pub async fn skip_pending(&self, key: &str, reason: &str) -> Vec<String> {
+        let mut jobs = self.jobs.write().await;
+        let Some(steps) = jobs.get_mut(key) else {
+            return Vec::new();
+        };
+        steps
+            .iter_mut()
+            .filter(|step| matches!(step.status, StepStatus::Pending))
+            .map(|step| {
+                step.status = StepStatus::Skipped(reason.to_owned());
+                step.name.clone()
+            })
+            .collect()
+    }
+
+    /// Marks a step as failed and skips all remaining steps
+    pub async fn fail(&self, key: &str, step: usize, error: String) {
+        warn!("Job for {key} failed: {error}");
+        if let Some(steps) = self.jobs.write().await.get_mut(key) {
+            steps[step].status = StepStatus::Failed(error);
+            for later in &mut steps[step + 1..] {
+                later.status = StepStatus::Skipped(String::from("Previous step failed"));
+            }
+        }
+    }
+}
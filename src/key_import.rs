@@ -0,0 +1,310 @@
+use std::time::Duration;
+
+use log::{error, info};
+use openidconnect::reqwest;
+use serde::Deserialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    db::{linked_accounts, now, sync_imported_keys, BlockingPool, KeyImportSummary},
+    models::User,
+    secrets,
+};
+
+/// Keys listed at `<url>/<login>.keys`
+pub const GITHUB: &str = "github";
+/// Keys listed by the users API of GitLab
+pub const GITLAB: &str = "gitlab";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn default_github_url() -> String {
+    String::from("https://github.com")
+}
+
+fn default_gitlab_url() -> String {
+    String::from("https://gitlab.com")
+}
+
+/// Where keys of GitHub accounts are imported from
+#[derive(Debug, Clone, Deserialize)]
+pub struct GitHubConfig {
+    /// `https://github.com` or the URL of a GitHub Enterprise Server
+    #[serde(default = "default_github_url")]
+    url: String,
+    /// Seconds between refreshes of every linked account (default 0, disabled)
+    #[serde(default, deserialize_with = "crate::deserialize_interval")]
+    interval: Option<Duration>,
+}
+
+impl Default for GitHubConfig {
+    fn default() -> Self {
+        Self {
+            url: default_github_url(),
+            interval: None,
+        }
+    }
+}
+
+impl GitHubConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        reqwest::Url::parse(&self.url)
+            .map(|_| ())
+            .map_err(|e| format!("Invalid [github] url '{}': {e}", self.url))
+    }
+}
+
+/// Where keys of GitLab accounts are imported from
+#[derive(Debug, Clone, Deserialize)]
+pub struct GitLabConfig {
+    /// `https://gitlab.com` or the URL of a self-managed instance
+    #[serde(default = "default_gitlab_url")]
+    url: String,
+    /// Personal or group access token with the `read_api` scope, for instances that
+    /// don't show users to anonymous visitors
+    #[serde(default, deserialize_with = "secrets::deserialize_optional_secret")]
+    token: Option<String>,
+    /// Seconds between refreshes of every linked account (default 0, disabled)
+    #[serde(default, deserialize_with = "crate::deserialize_interval")]
+    interval: Option<Duration>,
+}
+
+impl Default for GitLabConfig {
+    fn default() -> Self {
+        Self {
+            url: default_gitlab_url(),
+            token: None,
+            interval: None,
+        }
+    }
+}
+
+impl GitLabConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        reqwest::Url::parse(&self.url)
+            .map(|_| ())
+            .map_err(|e| format!("Invalid [gitlab] url '{}': {e}", self.url))
+    }
+}
+
+#[derive(Deserialize)]
+struct GitLabUser {
+    id: i64,
+}
+
+#[derive(Deserialize)]
+struct GitLabKey {
+    /// In authorized_keys format, usually with the title as comment
+    key: String,
+    /// e.g. `2026-10-15T00:00:00.000Z`
+    expires_at: Option<String>,
+}
+
+impl GitLabKey {
+    fn is_expired(&self) -> bool {
+        self.expires_at
+            .as_deref()
+            .and_then(|expires_at| OffsetDateTime::parse(expires_at, &Rfc3339).ok())
+            .is_some_and(|expires_at| {
+                let expires_at = expires_at.to_offset(time::UtcOffset::UTC);
+                time::PrimitiveDateTime::new(expires_at.date(), expires_at.time()) <= now()
+            })
+    }
+}
+
+/// Name of a code host for messages
+pub fn provider_label(provider: &str) -> &'static str {
+    match provider {
+        GITHUB => "GitHub",
+        GITLAB => "GitLab",
+        _ => "an unknown provider",
+    }
+}
+
+/// Logins are put into URLs, so only what code hosts allow in them is accepted
+fn check_login(login: &str) -> Result<&str, String> {
+    let login = login.trim();
+    if login.is_empty()
+        || !login
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(format!("Invalid account name '{login}'"));
+    }
+    Ok(login)
+}
+
+/// Imports the public keys of users' accounts at code hosts, on demand and periodically
+#[derive(Clone)]
+pub struct KeyImporter {
+    db: BlockingPool,
+    github: GitHubConfig,
+    gitlab: GitLabConfig,
+    http: reqwest::Client,
+}
+
+impl KeyImporter {
+    pub fn new(
+        db: BlockingPool,
+        github: GitHubConfig,
+        gitlab: GitLabConfig,
+    ) -> Result<Self, String> {
+        github.validate()?;
+        gitlab.validate()?;
+        let http = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            db,
+            github,
+            gitlab,
+            http,
+        })
+    }
+
+    /// Refreshes each provider every configured interval, unless disabled
+    pub fn start(&self) {
+        for (provider, interval) in [
+            (GITHUB, self.github.interval),
+            (GITLAB, self.gitlab.interval),
+        ] {
+            let Some(interval) = interval else {
+                continue;
+            };
+            let this = self.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(interval);
+                interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = this.refresh(provider).await {
+                        error!(
+                            "Failed to refresh the keys from {}: {e}",
+                            provider_label(provider)
+                        );
+                    }
+                }
+            });
+        }
+    }
+
+    /// The body of a successful response, `None` if not found
+    async fn get(
+        &self,
+        provider: &str,
+        url: reqwest::Url,
+        token: Option<&str>,
+    ) -> Result<Option<String>, String> {
+        let label = provider_label(provider);
+        let mut request = self.http.get(url).timeout(REQUEST_TIMEOUT);
+        if let Some(token) = token {
+            request = request.header("PRIVATE-TOKEN", token);
+        }
+        let response = request.send().await.map_err(|e| format!("{label}: {e}"))?;
+        let status = response.status();
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let text = response.text().await.map_err(|e| format!("{label}: {e}"))?;
+        match status.is_success() {
+            true => Ok(Some(text)),
+            false => Err(format!("{label} answered {status}: {text}")),
+        }
+    }
+
+    /// Parses the URL of an API path below a configured base URL
+    fn url(base: &str, path: &str) -> Result<reqwest::Url, String> {
+        let url = format!("{}/{path}", base.trim_end_matches('/'));
+        reqwest::Url::parse(&url).map_err(|e| format!("Invalid URL '{url}': {e}"))
+    }
+
+    /// The keys an account lists, in authorized_keys format
+    async fn fetch(&self, provider: &str, login: &str) -> Result<Vec<String>, String> {
+        let unknown = || format!("{} doesn't know {login}", provider_label(provider));
+        let lines: Vec<String> = match provider {
+            GITHUB => {
+                let url = Self::url(&self.github.url, &format!("{login}.keys"))?;
+                let text = self.get(provider, url, None).await?.ok_or_else(unknown)?;
+                text.lines().map(ToOwned::to_owned).collect()
+            }
+            GITLAB => {
+                let token = self.gitlab.token.as_deref();
+                let mut url = Self::url(&self.gitlab.url, "api/v4/users")?;
+                url.query_pairs_mut().append_pair("username", login);
+                let users = self.get(provider, url, token).await?.unwrap_or_default();
+                let users: Vec<GitLabUser> = serde_json::from_str(&users)
+                    .map_err(|e| format!("Unexpected answer from GitLab: {e}"))?;
+                let user = users.first().ok_or_else(unknown)?;
+
+                let url = Self::url(&self.gitlab.url, &format!("api/v4/users/{}/keys", user.id))?;
+                let keys = self.get(provider, url, token).await?.ok_or_else(unknown)?;
+                let keys: Vec<GitLabKey> = serde_json::from_str(&keys)
+                    .map_err(|e| format!("Unexpected answer from GitLab: {e}"))?;
+                // GitLab lists expired keys, but doesn't accept them anymore
+                keys.into_iter()
+                    .filter(|key| !key.is_expired())
+                    .map(|key| key.key)
+                    .collect()
+            }
+            _ => return Err(format!("Unknown provider '{provider}'")),
+        };
+        Ok(lines
+            .iter()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .map(ToOwned::to_owned)
+            .collect())
+    }
+
+    /// Links the user to the account and imports its keys
+    pub async fn import(
+        &self,
+        username: String,
+        provider: &'static str,
+        login: &str,
+    ) -> Result<KeyImportSummary, String> {
+        let login = check_login(login)?.to_owned();
+        let lines = self.fetch(provider, &login).await?;
+        self.db
+            .run(move |conn| {
+                let user = User::get_from_name(conn, &username)?
+                    .ok_or_else(|| String::from("User not found"))?;
+                sync_imported_keys(
+                    conn,
+                    &user,
+                    provider,
+                    &login,
+                    provider_label(provider),
+                    &lines,
+                )
+            })
+            .await?
+    }
+
+    /// Imports the keys of every linked account at the provider again. One account
+    /// failing doesn't stop the others.
+    pub async fn refresh(&self, provider: &'static str) -> Result<(), String> {
+        let accounts = self
+            .db
+            .run(move |conn| linked_accounts(conn, provider))
+            .await??;
+        let (mut added, mut removed) = (0, 0);
+        for (username, login) in accounts {
+            match self.import(username.clone(), provider, &login).await {
+                Ok(summary) => {
+                    added += summary.added;
+                    removed += summary.removed;
+                }
+                Err(e) => error!("Failed to refresh the keys of {username}: {e}"),
+            }
+        }
+        if added + removed > 0 {
+            info!(
+                "{}: added {added} keys, {removed} keys aren't listed anymore",
+                provider_label(provider)
+            );
+        }
+        Ok(())
+    }
+}
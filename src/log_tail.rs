@@ -0,0 +1,283 @@
+use std::{
+    collections::VecDeque,
+    io::Write,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+    Error,
+};
+use log::{
+    kv::{self, Key, Value, VisitSource},
+    Level, Log, Metadata, Record,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::sync::broadcast;
+
+/// Live subscribers that fall further behind than this skip the missed events
+const LIVE_CAPACITY: usize = 256;
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    /// Id of the request being handled, added to the events logged while handling it
+    static REQUEST_ID: String;
+}
+
+/// How log lines are written to stderr
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Colored lines for humans
+    #[default]
+    Text,
+    /// One JSON object per line with the fields of the event, for Loki or ELK
+    Json,
+}
+
+/// A log line as shown on the log page
+#[derive(Debug, Clone, Serialize)]
+pub struct LogEvent {
+    /// Increasing, so a reconnecting page can continue where it stopped
+    pub id: u64,
+    /// RFC 3339
+    pub time: String,
+    pub level: String,
+    /// Module that logged the event, e.g. `ssm::ssh::sshclient`
+    pub module: String,
+    pub message: String,
+    /// Request the event was logged while handling
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Host the event is about
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+}
+
+/// Which events the log page shows, all fields are optional
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LogFilter {
+    /// Only events mentioning this host
+    #[serde(default)]
+    pub host: String,
+    /// Only events at this level or more severe
+    #[serde(default)]
+    pub level: String,
+    /// Only events of modules containing this
+    #[serde(default)]
+    pub module: String,
+}
+
+impl LogFilter {
+    pub fn matches(&self, event: &LogEvent) -> bool {
+        let level_matches = match (Level::from_str(&self.level), Level::from_str(&event.level)) {
+            (Ok(min), Ok(level)) => level <= min,
+            _ => true,
+        };
+        let host = self.host.trim();
+        level_matches
+            && event.module.contains(self.module.trim())
+            && (event.message.contains(host)
+                || event
+                    .host
+                    .as_deref()
+                    .is_some_and(|name| name.contains(host)))
+    }
+}
+
+/// The most recent log events, kept in memory for the log page, which also
+/// receives new ones as they are logged
+pub struct LogBuffer {
+    capacity: usize,
+    events: Mutex<VecDeque<LogEvent>>,
+    next_id: AtomicU64,
+    live: broadcast::Sender<LogEvent>,
+}
+
+impl LogBuffer {
+    pub fn new(capacity: usize) -> Arc<Self> {
+        Arc::new(Self {
+            capacity,
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+            next_id: AtomicU64::new(1),
+            live: broadcast::channel(LIVE_CAPACITY).0,
+        })
+    }
+
+    fn push(&self, record: &Record, time: &str, request_id: Option<&String>, fields: &Fields) {
+        if self.capacity == 0 {
+            return;
+        }
+        let event = LogEvent {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            time: time.to_owned(),
+            level: record.level().to_string(),
+            module: record.target().to_owned(),
+            message: record.args().to_string(),
+            request_id: request_id.cloned(),
+            host: fields
+                .0
+                .get("host")
+                .and_then(|host| host.as_str())
+                .map(str::to_owned),
+        };
+
+        let mut events = self.events.lock().expect("Log buffer lock poisoned");
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event.clone());
+        // Nobody watching the log page is fine
+        let _ = self.live.send(event);
+    }
+
+    /// Buffered events after the given id that match the filter, oldest first
+    pub fn recent(&self, filter: &LogFilter, after: u64) -> Vec<LogEvent> {
+        self.events
+            .lock()
+            .expect("Log buffer lock poisoned")
+            .iter()
+            .filter(|event| event.id > after && filter.matches(event))
+            .cloned()
+            .collect()
+    }
+
+    /// Receives every event logged from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<LogEvent> {
+        self.live.subscribe()
+    }
+}
+
+/// The key-value fields of an event, e.g. `host` and `action`
+#[derive(Default)]
+struct Fields(Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(value) = value.to_bool() {
+            json!(value)
+        } else if let Some(value) = value.to_i64() {
+            json!(value)
+        } else if let Some(value) = value.to_u64() {
+            json!(value)
+        } else if let Some(value) = value.to_f64() {
+            json!(value)
+        } else {
+            json!(value.to_string())
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Writes an event as one line of JSON to stderr
+fn write_json(record: &Record, time: &str, request_id: Option<&String>, fields: Fields) {
+    let mut line = Map::new();
+    line.insert(String::from("time"), json!(time));
+    line.insert(String::from("level"), json!(record.level().as_str()));
+    line.insert(String::from("target"), json!(record.target()));
+    line.insert(String::from("message"), json!(record.args().to_string()));
+    if let Some(request_id) = request_id {
+        line.insert(String::from("request_id"), json!(request_id));
+    }
+    for (key, value) in fields.0 {
+        line.entry(key).or_insert(value);
+    }
+    // There's nowhere to report a failure to log
+    let _ = writeln!(
+        std::io::stderr().lock(),
+        "{}",
+        serde_json::Value::Object(line)
+    );
+}
+
+/// Writes to the usual logger and additionally keeps the events in the buffer
+struct BufferingLogger {
+    inner: env_logger::Logger,
+    format: LogFormat,
+    buffer: Arc<LogBuffer>,
+}
+
+impl Log for BufferingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        let time = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default();
+        let request_id = REQUEST_ID.try_with(Clone::clone).ok();
+        let mut fields = Fields::default();
+        let _ = record.key_values().visit(&mut fields);
+
+        self.buffer
+            .push(record, &time, request_id.as_ref(), &fields);
+        match self.format {
+            LogFormat::Text => self.inner.log(record),
+            LogFormat::Json => write_json(record, &time, request_id.as_ref(), fields),
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Sets up the logger like `pretty_env_logger::init`, with the filters of `RUST_LOG`
+pub fn init(buffer: Arc<LogBuffer>, format: LogFormat) {
+    let mut builder = pretty_env_logger::formatted_builder();
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    let inner = builder.build();
+    log::set_max_level(inner.filter());
+    log::set_boxed_logger(Box::new(BufferingLogger {
+        inner,
+        format,
+        buffer,
+    }))
+    .expect("Failed to set up logging");
+}
+
+/// Ids sent by a proxy are kept, if they are reasonably short and plain
+fn is_valid_request_id(id: &str) -> bool {
+    (1..=64).contains(&id.len())
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+}
+
+/// Middleware giving every request an id, taken from `X-Request-Id` or generated. The id is
+/// added to the events logged while handling the request and sent back in `X-Request-Id`.
+pub async fn with_request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
+        .map_or_else(|| format!("{:032x}", rand::random::<u128>()), str::to_owned);
+
+    let mut res = REQUEST_ID.scope(id.clone(), next.call(req)).await?;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        res.headers_mut()
+            .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    Ok(res)
+}
@@ -0,0 +1,219 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::{db::now, models::LoginAttempt, DbConnection};
+
+const fn default_free_attempts() -> u32 {
+    3
+}
+
+const fn default_client_free_attempts() -> u32 {
+    10
+}
+
+const fn default_base_delay() -> Duration {
+    Duration::from_secs(1)
+}
+
+const fn default_max_delay() -> Duration {
+    Duration::from_secs(60)
+}
+
+const fn default_account_lockout() -> u32 {
+    10
+}
+
+const fn default_client_lockout() -> u32 {
+    50
+}
+
+const fn default_lockout_duration() -> Duration {
+    Duration::from_secs(900)
+}
+
+const fn default_failure_window() -> Duration {
+    Duration::from_secs(3600)
+}
+
+const fn default_keep() -> Option<Duration> {
+    Some(Duration::from_secs(90 * 24 * 3600))
+}
+
+/// Slowing down password guessing on the login page. Failed logins are counted per account
+/// and per client address.
+#[derive(Debug, Deserialize, Clone)]
+pub struct LoginThrottlingConfig {
+    /// Failed logins of an account before each further attempt has to wait
+    #[serde(default = "default_free_attempts")]
+    free_attempts: u32,
+    /// Failed logins from an address before each further attempt has to wait, higher as
+    /// many users may share an address
+    #[serde(default = "default_client_free_attempts")]
+    client_free_attempts: u32,
+    /// Seconds to wait after the first failure beyond the free attempts, doubling with every
+    /// further failure
+    #[serde(
+        default = "default_base_delay",
+        deserialize_with = "crate::deserialize_timeout"
+    )]
+    base_delay: Duration,
+    /// Seconds the wait grows to at most
+    #[serde(
+        default = "default_max_delay",
+        deserialize_with = "crate::deserialize_timeout"
+    )]
+    max_delay: Duration,
+    /// Failed logins of an account that lock it (0 disables)
+    #[serde(default = "default_account_lockout")]
+    account_lockout: u32,
+    /// Failed logins from an address that lock it out (0 disables)
+    #[serde(default = "default_client_lockout")]
+    client_lockout: u32,
+    /// Seconds a lockout lasts after the last failed login
+    #[serde(
+        default = "default_lockout_duration",
+        deserialize_with = "crate::deserialize_timeout"
+    )]
+    lockout_duration: Duration,
+    /// Seconds after which failed logins no longer count
+    #[serde(
+        default = "default_failure_window",
+        deserialize_with = "crate::deserialize_timeout"
+    )]
+    failure_window: Duration,
+    /// Days the attempts are kept for review (default 90, 0 keeps them forever)
+    #[serde(
+        default = "default_keep",
+        rename = "keep_days",
+        deserialize_with = "crate::deserialize_days"
+    )]
+    keep: Option<Duration>,
+}
+
+impl Default for LoginThrottlingConfig {
+    fn default() -> Self {
+        Self {
+            free_attempts: default_free_attempts(),
+            client_free_attempts: default_client_free_attempts(),
+            base_delay: default_base_delay(),
+            max_delay: default_max_delay(),
+            account_lockout: default_account_lockout(),
+            client_lockout: default_client_lockout(),
+            lockout_duration: default_lockout_duration(),
+            failure_window: default_failure_window(),
+            keep: default_keep(),
+        }
+    }
+}
+
+impl LoginThrottlingConfig {
+    /// How long to wait after these failures, oldest first
+    fn delay(
+        &self,
+        failures: &[time::PrimitiveDateTime],
+        free_attempts: u32,
+        lockout: u32,
+    ) -> Option<Duration> {
+        let last = *failures.last()?;
+        let count = u32::try_from(failures.len()).unwrap_or(u32::MAX);
+        let delay = if lockout > 0 && count >= lockout {
+            self.lockout_duration
+        } else if count >= free_attempts {
+            let factor = 1u32.checked_shl(count - free_attempts).unwrap_or(u32::MAX);
+            self.base_delay.saturating_mul(factor).min(self.max_delay)
+        } else {
+            return None;
+        };
+        let left = last + delay - now();
+        left.is_positive()
+            .then(|| Duration::from_secs(left.whole_seconds().max(1).unsigned_abs()))
+    }
+
+    /// How long `username` or `client` have to wait before the next login attempt, if at all
+    pub fn retry_after(
+        &self,
+        conn: &mut DbConnection,
+        username: &str,
+        client: Option<&str>,
+    ) -> Result<Option<Duration>, String> {
+        let since = now() - self.failure_window;
+        let account = LoginAttempt::account_failures(conn, username, since)?;
+        let client = match client {
+            Some(client) => LoginAttempt::client_failures(conn, client, since)?,
+            None => Vec::new(),
+        };
+        Ok(self
+            .delay(&account, self.free_attempts, self.account_lockout)
+            .max(self.delay(&client, self.client_free_attempts, self.client_lockout)))
+    }
+
+    /// Records an attempt and forgets those older than configured
+    pub fn record(
+        &self,
+        conn: &mut DbConnection,
+        username: String,
+        client: Option<String>,
+        outcome: &str,
+    ) -> Result<(), String> {
+        LoginAttempt::record(conn, username, client, outcome)?;
+        match self.keep {
+            Some(keep) => LoginAttempt::prune(conn, now() - keep),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `count` failures, the last one `ago` before now. Half a second is added, so the
+    /// whole seconds left aren't rounded down while the test runs.
+    fn failures(count: usize, ago: time::Duration) -> Vec<time::PrimitiveDateTime> {
+        vec![now() - ago + time::Duration::milliseconds(500); count]
+    }
+
+    fn seconds(seconds: u64) -> Option<Duration> {
+        Some(Duration::from_secs(seconds))
+    }
+
+    #[test]
+    fn delays_double_after_the_free_attempts() {
+        let config = LoginThrottlingConfig::default();
+        let just_now = time::Duration::ZERO;
+        assert_eq!(config.delay(&[], 3, 10), None);
+        assert_eq!(config.delay(&failures(2, just_now), 3, 10), None);
+        assert_eq!(config.delay(&failures(3, just_now), 3, 10), seconds(1));
+        assert_eq!(config.delay(&failures(4, just_now), 3, 10), seconds(2));
+        assert_eq!(config.delay(&failures(5, just_now), 3, 10), seconds(4));
+        assert_eq!(config.delay(&failures(9, just_now), 3, 10), seconds(60));
+        // Without a lockout the delay stays at the maximum
+        assert_eq!(config.delay(&failures(100, just_now), 3, 0), seconds(60));
+    }
+
+    #[test]
+    fn locks_out_at_the_threshold() {
+        let config = LoginThrottlingConfig::default();
+        let just_now = time::Duration::ZERO;
+        assert_eq!(config.delay(&failures(9, just_now), 3, 10), seconds(60));
+        assert_eq!(config.delay(&failures(10, just_now), 3, 10), seconds(900));
+        assert_eq!(config.delay(&failures(50, just_now), 10, 50), seconds(900));
+        assert_eq!(config.delay(&failures(49, just_now), 10, 50), seconds(60));
+    }
+
+    #[test]
+    fn delays_expire() {
+        let config = LoginThrottlingConfig::default();
+        let ago = time::Duration::seconds;
+        assert_eq!(config.delay(&failures(4, ago(1)), 3, 10), seconds(1));
+        assert_eq!(config.delay(&failures(4, ago(3)), 3, 10), None);
+        assert_eq!(config.delay(&failures(10, ago(600)), 3, 10), seconds(300));
+        assert_eq!(config.delay(&failures(10, ago(901)), 3, 10), None);
+        // Only the last failure counts for how long is left
+        let mut spread = failures(9, ago(3600));
+        spread.extend(failures(1, ago(10)));
+        assert_eq!(spread.len(), 10);
+        assert_eq!(config.delay(&spread, 3, 10), seconds(890));
+    }
+}
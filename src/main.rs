@@ -1,7 +1,13 @@
-use std::{env, net::IpAddr, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    env,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
 use actix_identity::IdentityMiddleware;
-use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+use actix_session::SessionMiddleware;
 use actix_web::{
     dev::ServiceResponse,
     http::{header, StatusCode},
@@ -10,33 +16,110 @@ use actix_web::{
     App, HttpResponse, HttpServer,
 };
 use actix_web_static_files::ResourceFiles;
+use anomalies::{AnomalyConfig, AnomalyDetector};
+use attestation::AttestationConfig;
+use auth::{AuthBackend, AuthBackendKind, LdapConfig, PasswordHashingConfig, Role};
+use authorization_expiry::AuthorizationExpirer;
+use backup::{BackupConfig, BackupJob};
+use branding::BrandingConfig;
+use bulk_apply::{BulkApplier, ValidationWebhookConfig};
+use chat::ChatConfig;
+use clap::Parser;
+use cluster::{ClusterConfig, ClusterWorker};
 use config::Config;
 use croner::Cron;
+use db::BlockingPool;
+use decommission::Decommissioner;
 use diesel::prelude::QueryResult;
+use drift_webhooks::{DriftWebhookConfig, DriftWebhooks};
+use ec2::{Ec2Config, Ec2Discovery};
+use health::{HealthConfig, HealthMonitor};
+use key_import::{GitHubConfig, GitLabConfig, KeyImporter};
 use log::{error, info};
+use log_tail::{LogBuffer, LogFormat};
+use login_throttling::LoginThrottlingConfig;
+use netbox::{NetBoxConfig, NetBoxSync};
+use notifications::{NotificationConfig, Notifier};
+use offboarding::Offboarder;
+use oidc::{OidcConfig, OidcLogin};
+use otel::OtelConfig;
+use perf::PerfStats;
+use policy::{PolicyConfig, PolicyScanner};
+use proxy::ProxyConfig;
+use removal_queue::RemovalQueue;
+use report::IntegrityReporter;
 use serde::Deserialize;
-use ssh::{CachingSshClient, SshClient};
+use session_store::DbSessionStore;
+use ssh::{CachingSshClient, KeyOrder, RemediationPolicy, SshClient};
 
 use diesel::r2d2::ConnectionManager;
+use diesel::r2d2::CustomizeConnection;
 use diesel::r2d2::Pool;
 
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use russh::keys::key::PrivateKeyWithHashAlg;
 use ssh_key::PrivateKey;
+use tls::TlsConfig;
 use tokio_cron_scheduler::{JobBuilder, JobScheduler};
 
+mod anomalies;
+mod ansible;
+mod attestation;
+mod audit;
+mod auth;
+mod authorization_expiry;
+mod aws;
+mod backup;
+mod branding;
+mod bulk_apply;
+mod chat;
+mod cli;
+mod cluster;
+mod config_loader;
+mod csrf;
 mod db;
+mod decommission;
+mod drift_webhooks;
+mod ec2;
 mod forms;
+mod health;
+mod jobs;
+mod key_import;
+mod log_tail;
+mod login_throttling;
+mod metrics;
 mod middleware;
 mod models;
+mod netbox;
+mod notifications;
+mod offboarding;
+mod oidc;
+mod otel;
+mod perf;
+mod policy;
+mod privacy;
+mod proxy;
+mod removal_queue;
+mod report;
 mod routes;
 mod schema;
+mod secrets;
+mod selection;
+mod session_store;
+mod shutdown;
+mod smtp;
 mod ssh;
 mod templates;
+mod tls;
+mod totp;
 
 include!(concat!(env!("OUT_DIR"), "/generated.rs"));
 
-pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
+const SQLITE_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/sqlite");
+#[cfg(feature = "postgres")]
+const POSTGRES_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/postgres");
+#[cfg(feature = "mysql")]
+const MYSQL_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/mysql");
 
 #[derive(diesel::MultiConnection)]
 pub enum DbConnection {
@@ -48,6 +131,17 @@ pub enum DbConnection {
     Sqlite(diesel::SqliteConnection),
 }
 
+/// The migrations for the kind of database behind `conn`
+pub const fn migrations(conn: &DbConnection) -> EmbeddedMigrations {
+    match conn {
+        #[cfg(feature = "postgres")]
+        DbConnection::Postgresql(_) => POSTGRES_MIGRATIONS,
+        #[cfg(feature = "mysql")]
+        DbConnection::Mysql(_) => MYSQL_MIGRATIONS,
+        DbConnection::Sqlite(_) => SQLITE_MIGRATIONS,
+    }
+}
+
 pub type ConnectionPool = Pool<ConnectionManager<DbConnection>>;
 
 const fn default_timeout() -> Duration {
@@ -62,19 +156,69 @@ where
     Ok(Duration::from_secs(seconds))
 }
 
+/// Zero disables the interval
+fn deserialize_interval<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let seconds = Option::<u64>::deserialize(deserializer)?;
+    Ok(seconds
+        .filter(|seconds| *seconds > 0)
+        .map(Duration::from_secs))
+}
+
+/// Zero disables the period
+fn deserialize_days<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let days = Option::<u64>::deserialize(deserializer)?;
+    Ok(days
+        .filter(|days| *days > 0)
+        .map(|days| Duration::from_secs(days * 24 * 3600)))
+}
+
+fn deserialize_millis<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let millis = u64::deserialize(deserializer)?;
+    Ok(Duration::from_millis(millis))
+}
+
 fn deserialize_cron<'de, D>(deserializer: D) -> Result<Option<Cron>, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
     let pat = String::deserialize(deserializer)?;
 
-    match Cron::new(pat.as_str()).with_seconds_optional().parse() {
-        Ok(cron) => Ok(Some(cron)),
-        Err(e) => {
-            eprintln!("Failed to parse Cron syntax '{pat}': {e}");
-            std::process::exit(3);
-        }
-    }
+    Cron::new(pat.as_str())
+        .with_seconds_optional()
+        .parse()
+        .map(Some)
+        .map_err(|e| serde::de::Error::custom(format!("Invalid cron syntax '{pat}': {e}")))
+}
+
+fn deserialize_public_keys<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|key| {
+            ssh_key::PublicKey::from_openssh(key)
+                .and_then(|key| key.to_openssh())
+                .map_err(|e| serde::de::Error::custom(format!("Invalid public key '{key}': {e}")))
+        })
+        .collect()
+}
+
+const fn default_removal_retry_interval() -> Option<Duration> {
+    Some(Duration::from_secs(300))
+}
+
+const fn default_authorization_expiry_interval() -> Option<Duration> {
+    Some(Duration::from_secs(60))
 }
 
 fn no_cron() -> Option<Cron> {
@@ -93,13 +237,66 @@ pub struct SshConfig {
     #[serde(default = "no_cron", deserialize_with = "deserialize_cron")]
     update_schedule: Option<Cron>,
 
+    /// Cron schedule when to generate an integrity report of all hosts (default disabled)
+    #[serde(default = "no_cron", deserialize_with = "deserialize_cron")]
+    report_schedule: Option<Cron>,
+
+    /// Seconds between background syncs of all hosts, starting at startup (default disabled).
+    /// The diff page then only shows synced results instead of connecting to hosts.
+    #[serde(default, deserialize_with = "deserialize_interval")]
+    sync_interval: Option<Duration>,
+
+    /// Seconds between retries of key removals that failed (default 300, 0 disables)
+    #[serde(
+        default = "default_removal_retry_interval",
+        deserialize_with = "deserialize_interval"
+    )]
+    removal_retry_interval: Option<Duration>,
+
+    /// Seconds between checks for expired authorizations (default 60, 0 disables)
+    #[serde(
+        default = "default_authorization_expiry_interval",
+        deserialize_with = "deserialize_interval"
+    )]
+    authorization_expiry_interval: Option<Duration>,
+
     /// Path to an OpenSSH Private Key
-    private_key_file: PathBuf,
+    #[serde(default)]
+    private_key_file: Option<PathBuf>,
+    /// The OpenSSH Private Key itself, read from a secret source.
+    /// Takes precedence over `private_key_file`
+    #[serde(default, deserialize_with = "secrets::deserialize_optional_secret")]
+    private_key: Option<String>,
     /// Passphrase for the key
+    #[serde(default, deserialize_with = "secrets::deserialize_optional_secret")]
     private_key_passphrase: Option<String>,
     /// Connection timeout in seconds (default 2m)
     #[serde(default = "default_timeout", deserialize_with = "deserialize_timeout")]
     timeout: Duration,
+
+    /// Write whole authorized_keys files instead of only the block between the
+    /// `# BEGIN ssh-key-manager` and `# END ssh-key-manager` markers (default false)
+    #[serde(default)]
+    manage_whole_keyfile: bool,
+
+    /// Order of the generated authorized_keys entries: "unsorted" (default), "alphabetical",
+    /// or in sections per "user" or per "group"
+    #[serde(default)]
+    key_order: KeyOrder,
+
+    /// Public keys that always stay authorized for the login of ssm on every host,
+    /// to get in when ssm can't (OpenSSH format)
+    #[serde(default, deserialize_with = "deserialize_public_keys")]
+    break_glass_keys: Vec<String>,
+
+    /// Days after adding a host in which ssm only reports its drift, without changing
+    /// anything on it (default 0, disabled)
+    #[serde(
+        default,
+        rename = "observation_days",
+        deserialize_with = "deserialize_days"
+    )]
+    observation_period: Option<Duration>,
 }
 
 fn default_database_url() -> String {
@@ -119,6 +316,10 @@ fn default_loglevel() -> String {
     "info".to_owned()
 }
 
+const fn default_log_buffer_size() -> usize {
+    1000
+}
+
 fn default_session_key() -> String {
     String::from("my-secret-key-please-change-me-in-production")
 }
@@ -127,21 +328,165 @@ fn default_htpasswd_path() -> PathBuf {
     PathBuf::from(".htpasswd")
 }
 
+const fn default_role() -> Role {
+    // Users that logged in before roles existed were made admins by the migration.
+    // New accounts, e.g. every identity of the OIDC provider, only get to look.
+    Role::Viewer
+}
+
+const fn default_db_pool_size() -> u32 {
+    10
+}
+
+const fn default_shutdown_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+const fn default_db_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+const fn default_slow_query_threshold() -> Duration {
+    Duration::from_millis(200)
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Configuration {
     ssh: SshConfig,
-    #[serde(default = "default_database_url")]
+    #[serde(
+        default = "default_database_url",
+        deserialize_with = "secrets::deserialize_secret"
+    )]
     database_url: String,
+    /// Read-only replica of the database used by reports and exports
+    #[serde(default, deserialize_with = "secrets::deserialize_optional_secret")]
+    read_database_url: Option<String>,
     #[serde(default = "default_listen")]
     listen: IpAddr,
     #[serde(default = "default_port")]
     port: u16,
+    /// Serves HTTPS on the port instead of HTTP
+    #[serde(default)]
+    tls: Option<TlsConfig>,
     #[serde(default = "default_loglevel")]
     loglevel: String,
+    /// Colored text, or JSON lines for log collectors
+    #[serde(default)]
+    log_format: LogFormat,
+    /// Bearer token Prometheus scrapes `/metrics` with, which is disabled without it
+    #[serde(default, deserialize_with = "secrets::deserialize_optional_secret")]
+    metrics_token: Option<String>,
+    /// `/readyz` also fails while the SSH private key can't be loaded
+    #[serde(default)]
+    probe_ssh_key: bool,
+    /// Log events kept in memory for the log page
+    #[serde(default = "default_log_buffer_size")]
+    log_buffer_size: usize,
     #[serde(default = "default_session_key")]
     session_key: String,
+    /// Where the login page checks passwords
+    #[serde(default)]
+    auth_backend: AuthBackendKind,
     #[serde(default = "default_htpasswd_path")]
     htpasswd_path: PathBuf,
+    /// How passwords in the htpasswd file are hashed
+    #[serde(default)]
+    password_hashing: PasswordHashingConfig,
+    /// Role of web users on their first login
+    #[serde(default = "default_role")]
+    default_role: Role,
+    /// Authorizations of operators and API tokens only take effect once an admin approves them
+    #[serde(default)]
+    authorization_approval: bool,
+    /// Deleting a user replaces their name and email in the audit log and reports with a
+    /// pseudonym
+    #[serde(default)]
+    anonymize_deleted_users: bool,
+    /// Delays and lockouts after failed password logins
+    #[serde(default)]
+    login_throttling: LoginThrottlingConfig,
+    /// Directory used with `auth_backend = "ldap"`
+    #[serde(default)]
+    ldap: Option<LdapConfig>,
+    /// Maximum number of concurrent database operations
+    #[serde(default = "default_db_pool_size")]
+    db_pool_size: u32,
+    /// Timeout in seconds for database operations, including the time spent waiting for a connection
+    #[serde(
+        default = "default_db_timeout",
+        deserialize_with = "deserialize_timeout"
+    )]
+    db_timeout: Duration,
+    /// Seconds a shutdown waits for the SSH operations still running, and then again for
+    /// the requests being handled
+    #[serde(
+        default = "default_shutdown_timeout",
+        deserialize_with = "deserialize_timeout"
+    )]
+    shutdown_timeout: Duration,
+    /// Queries taking longer than this many milliseconds are logged
+    #[serde(
+        default = "default_slow_query_threshold",
+        deserialize_with = "deserialize_millis"
+    )]
+    slow_query_threshold: Duration,
+    /// Split scheduled jobs between several instances sharing the database
+    #[serde(default)]
+    cluster: Option<ClusterConfig>,
+    /// Single sign-on with OpenID Connect, in addition to the htpasswd file
+    #[serde(default)]
+    oidc: Option<OidcConfig>,
+    /// Drift remediation policies, evaluated by the check job
+    #[serde(default)]
+    remediation: Vec<RemediationPolicy>,
+    /// Has to allow every bulk apply before anything is written
+    #[serde(default)]
+    apply_validation: Option<ValidationWebhookConfig>,
+    /// Told about unauthorized keys found by the check job
+    #[serde(default)]
+    drift_webhooks: Vec<DriftWebhookConfig>,
+    /// Base path and trusted proxies when running behind a reverse proxy
+    #[serde(default)]
+    proxy: ProxyConfig,
+    /// Instance name, logo and login notice
+    #[serde(default)]
+    branding: BrandingConfig,
+    /// Root certificates for verifying that uploaded sk- keys are hardware-backed
+    #[serde(default)]
+    attestation: AttestationConfig,
+    /// Heuristics flagging suspicious access patterns
+    #[serde(default)]
+    anomalies: AnomalyConfig,
+    /// Rules every authorization has to follow
+    #[serde(default)]
+    policies: PolicyConfig,
+    /// When fleet operations slow down or pause
+    #[serde(default)]
+    health: HealthConfig,
+    /// Emails admins about changed host keys, unexpected keys and authorizations
+    #[serde(default)]
+    notifications: Option<NotificationConfig>,
+    /// Slack channels and Matrix rooms that are told about events
+    #[serde(default)]
+    chat: Vec<ChatConfig>,
+    /// Exports spans of requests, SSH and database operations to an OpenTelemetry collector
+    #[serde(default)]
+    otel: Option<OtelConfig>,
+    /// Scheduled snapshots of the SQLite database to a directory or bucket
+    #[serde(default)]
+    backup: Option<BackupConfig>,
+    /// Creates hosts for EC2 instances and flags those of terminated ones
+    #[serde(default)]
+    ec2: Option<Ec2Config>,
+    /// Keeps hosts in line with the tagged devices and virtual machines of NetBox
+    #[serde(default)]
+    netbox: Option<NetBoxConfig>,
+    /// Where keys of GitHub accounts are imported from, and how often
+    #[serde(default)]
+    github: GitHubConfig,
+    /// Where keys of GitLab accounts are imported from, and how often
+    #[serde(default)]
+    gitlab: GitLabConfig,
 }
 
 fn get_configuration() -> (Configuration, String) {
@@ -161,23 +506,202 @@ fn get_configuration() -> (Configuration, String) {
         )
     };
 
-    (
-        config_builder
-            .add_source(config::Environment::default())
-            .build()
-            .unwrap_or_else(|e| {
-                eprintln!("Error while reading configuration source: {e}");
-                std::process::exit(3);
+    let value = config_builder
+        .add_source(config::Environment::default())
+        .build()
+        .unwrap_or_else(|e| {
+            eprintln!("Error while reading configuration source: {e}");
+            std::process::exit(3);
+        })
+        .cache;
+    let configuration =
+        config_loader::load(value, Configuration::validate).unwrap_or_else(|errors| {
+            eprintln!("Invalid configuration ({config_path}):");
+            for e in errors {
+                eprintln!("  {e}");
+            }
+            std::process::exit(3);
+        });
+    (configuration, config_source)
+}
+
+impl Configuration {
+    /// Checks values that deserialize fine, but ssm can't work with
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.db_pool_size == 0 {
+            errors.push(String::from("db_pool_size: has to be at least 1"));
+        }
+        if self.db_timeout.is_zero() {
+            errors.push(String::from("db_timeout: has to be at least 1 second"));
+        }
+        if self.ssh.timeout.is_zero() {
+            errors.push(String::from("ssh.timeout: has to be at least 1 second"));
+        }
+        // Required to derive the cookie keys
+        if self.session_key.len() < 32 {
+            errors.push(String::from(
+                "session_key: has to be at least 32 bytes long",
+            ));
+        }
+        if let Some(Err(e)) = self.backup.as_ref().map(BackupConfig::validate) {
+            errors.push(e);
+        }
+        if let Some(Err(e)) = self.ec2.as_ref().map(Ec2Config::validate) {
+            errors.push(e);
+        }
+        if let Some(Err(e)) = self.netbox.as_ref().map(NetBoxConfig::validate) {
+            errors.push(e);
+        }
+        if let Err(e) = self.github.validate() {
+            errors.push(e);
+        }
+        if let Err(e) = self.gitlab.validate() {
+            errors.push(e);
+        }
+        errors
+    }
+}
+
+/// Applied to every new pool connection
+#[derive(Debug)]
+struct ConnectionSetup(Option<perf::QueryTiming>);
+
+impl CustomizeConnection<DbConnection, diesel::r2d2::Error> for ConnectionSetup {
+    fn on_acquire(&self, conn: &mut DbConnection) -> Result<(), diesel::r2d2::Error> {
+        use diesel::{sql_query, RunQueryDsl};
+
+        #[allow(irrefutable_let_patterns)]
+        if let DbConnection::Sqlite(_) = conn {
+            // Wait for concurrent writes, e.g. to the audit log, instead of failing right away
+            sql_query("PRAGMA busy_timeout = 5000")
+                .execute(conn)
+                .map_err(diesel::r2d2::Error::QueryError)?;
+        }
+        match &self.0 {
+            Some(timing) => timing.on_acquire(conn),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The database URL without its password, for logs
+fn redacted_database_url(url: &str) -> String {
+    let Ok(mut parsed) = openidconnect::url::Url::parse(url) else {
+        // e.g. `host=db password=...` of PostgreSQL
+        return String::from("(connection string)");
+    };
+    let in_query = parsed.query_pairs().any(|(name, _)| name == "password");
+    if parsed.password().is_none() && !in_query {
+        return url.to_owned();
+    }
+    if parsed.password().is_some() {
+        let _ = parsed.set_password(Some("***"));
+    }
+    if in_query {
+        let pairs: Vec<(String, String)> = parsed
+            .query_pairs()
+            .map(|(name, value)| match name == "password" {
+                true => (name.into_owned(), String::from("***")),
+                false => (name.into_owned(), value.into_owned()),
             })
-            .try_deserialize()
-            .unwrap_or_else(|e| {
-                eprintln!("Error while parsing configuration: {e}");
-                std::process::exit(3);
-            }),
-        config_source,
+            .collect();
+        parsed.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    parsed.to_string()
+}
+
+/// Creates the connection pool and enables foreign key support
+/// Connects to the database. With `perf`, slow queries are recorded there.
+pub fn create_pool(
+    configuration: &Configuration,
+    perf: Option<PerfStats>,
+) -> Result<ConnectionPool, String> {
+    use diesel::{sql_query, RunQueryDsl};
+
+    let manager = ConnectionManager::<DbConnection>::new(configuration.database_url.clone());
+    let pool: ConnectionPool = Pool::builder()
+        .max_size(configuration.db_pool_size)
+        .connection_timeout(configuration.db_timeout)
+        .connection_customizer(Box::new(ConnectionSetup(perf.map(perf::QueryTiming))))
+        .build(manager)
+        .map_err(|e| format!("Couldn't connect to database: {e}"))?;
+
+    let mut conn = pool
+        .get()
+        .map_err(|e| format!("Couldn't connect to database: {e}"))?;
+
+    // Other databases enforce foreign keys anyway
+    #[allow(irrefutable_let_patterns)]
+    let DbConnection::Sqlite(_) = &mut *conn
+    else {
+        return Ok(pool);
+    };
+    sql_query("PRAGMA foreign_keys = on")
+        .execute(&mut conn)
+        .map_err(|e| format!("Couldn't activate foreign key support: {e}"))?;
+
+    Ok(pool)
+}
+
+/// How long to wait for a connection to the read replica before using the primary
+const REPLICA_CONNECTION_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Creates the connection pool of the read replica, if configured. Connections are only
+/// made when needed, so an unavailable replica doesn't prevent the start.
+pub fn create_read_pool(
+    configuration: &Configuration,
+    perf: Option<PerfStats>,
+) -> Option<ConnectionPool> {
+    let url = configuration.read_database_url.as_ref()?;
+    let manager = ConnectionManager::<DbConnection>::new(url.clone());
+    Some(
+        Pool::builder()
+            .max_size(configuration.db_pool_size)
+            .min_idle(Some(0))
+            .connection_timeout(REPLICA_CONNECTION_TIMEOUT.min(configuration.db_timeout))
+            .connection_customizer(Box::new(ConnectionSetup(perf.map(perf::QueryTiming))))
+            .build_unchecked(manager),
     )
 }
 
+/// Reads and decrypts the private key used to authenticate on hosts
+pub fn load_private_key(config: &SshConfig) -> Result<PrivateKeyWithHashAlg, String> {
+    let mut key = match (&config.private_key, &config.private_key_file) {
+        (Some(key), _) => PrivateKey::from_openssh(key)
+            .map_err(|e| format!("Failed to parse private key: {e}"))?,
+        (None, Some(key_path)) => PrivateKey::read_openssh_file(key_path)
+            .map_err(|e| format!("Failed to read key from '{}': {e}", key_path.display()))?,
+        (None, None) => {
+            return Err(String::from(
+                "Neither private_key nor private_key_file is configured.",
+            ))
+        }
+    };
+
+    if let Some(key_passphrase) = config.private_key_passphrase.as_ref() {
+        key = match key.decrypt(key_passphrase) {
+            Ok(k) => k,
+            Err(ssh_key::Error::Decrypted) => {
+                return Err(String::from(
+                    "Tried to decrypt ssh key, but it is already decrypted.",
+                ));
+            }
+            Err(e) => {
+                return Err(format!("Failed to decrypt ssh key: {e}"));
+            }
+        };
+    };
+
+    let hash = match key.algorithm() {
+        ssh_key::Algorithm::Rsa { hash } => hash,
+        _ => None,
+    };
+
+    PrivateKeyWithHashAlg::new(Arc::new(key), hash)
+        .map_err(|e| format!("Failed to convert key to Private key: {e}"))
+}
+
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
     color_eyre::install().expect("Couldn't intall color_eyre");
@@ -186,16 +710,59 @@ async fn main() -> Result<(), std::io::Error> {
         std::env::set_var("RUST_SPANTRACE", "0");
     }
 
+    let cli = cli::Cli::parse();
     let (configuration, config_source) = get_configuration();
 
     if env::var("RUST_LOG").is_err() {
         let loglevel = configuration.loglevel.clone();
         env::set_var("RUST_LOG", loglevel);
     }
-    pretty_env_logger::init();
+    let log_buffer = LogBuffer::new(configuration.log_buffer_size);
+    log_tail::init(Arc::clone(&log_buffer), configuration.log_format);
     info!("{}", config_source);
 
-    if !configuration.htpasswd_path.exists() {
+    if let Err(e) = configuration.policies.clone().install() {
+        error!("{e}");
+        std::process::exit(6);
+    }
+
+    if let Some(command) = cli.command {
+        std::process::exit(cli::run(command, configuration).await);
+    }
+
+    if let Err(e) = configuration.proxy.clone().install() {
+        error!("{e}");
+        std::process::exit(3);
+    }
+    if let Err(e) = configuration.branding.clone().install() {
+        error!("{e}");
+        std::process::exit(6);
+    }
+    if let Err(e) = configuration
+        .attestation
+        .clone()
+        .install(&configuration.session_key)
+    {
+        error!("{e}");
+        std::process::exit(6);
+    }
+    let tls = match configuration.tls.clone().map(TlsConfig::load).transpose() {
+        Ok(tls) => tls,
+        Err(e) => {
+            error!("{e}");
+            std::process::exit(6);
+        }
+    };
+    if let Some(otel) = configuration.otel.clone() {
+        if let Err(e) = otel.install() {
+            error!("{e}");
+            std::process::exit(6);
+        }
+    }
+
+    if configuration.auth_backend == AuthBackendKind::Htpasswd
+        && !configuration.htpasswd_path.exists()
+    {
         error!(
             "htpasswd file does not exist: {:?}",
             configuration.htpasswd_path
@@ -203,71 +770,191 @@ async fn main() -> Result<(), std::io::Error> {
         std::process::exit(3);
     }
 
-    let database_url = configuration.database_url.clone();
-    let manager = ConnectionManager::<DbConnection>::new(database_url);
-    let pool: ConnectionPool = Pool::builder()
-        .build(manager)
-        .expect("Database URL should be a valid URI");
+    info!(
+        "Trying to connect to database '{}'",
+        redacted_database_url(&configuration.database_url)
+    );
+    let perf_stats = Data::new(PerfStats::new(configuration.slow_query_threshold));
+    let log_buffer = Data::new(log_buffer);
+    let pool =
+        create_pool(&configuration, Some(PerfStats::clone(&perf_stats))).unwrap_or_else(|e| {
+            error!("{e}");
+            std::process::exit(5);
+        });
 
     {
-        use diesel::{sql_query, RunQueryDsl};
-
-        info!(
-            "Trying to connect to database '{}'",
-            configuration.database_url
-        );
         let mut conn = pool.get().expect("Couldn't connect to database");
 
-        sql_query("PRAGMA foreign_keys = on")
-            .execute(&mut conn)
-            .expect("Couldn't activate foreign key support");
-
-        conn.run_pending_migrations(MIGRATIONS)
+        let migrations = migrations(&conn);
+        conn.run_pending_migrations(migrations)
             .expect("Error while running migrations:");
     }
 
-    let key_path = &configuration.ssh.private_key_file;
-
-    let mut key =
-        PrivateKey::read_openssh_file(key_path).expect("Failed to read key from '{key_path}'.");
-
-    if let Some(key_passphrase) = configuration.ssh.private_key_passphrase.as_ref() {
-        key = match key.decrypt(key_passphrase) {
-            Ok(k) => k,
-            Err(ssh_key::Error::Decrypted) => {
-                error!("Tried to decrypt ssh key, but it is already decrypted.");
-                std::process::exit(4);
-            }
-            Err(e) => {
-                error!("Failed to decrypt ssh key: {e}");
-                std::process::exit(4);
-            }
-        };
-    };
+    let key = load_private_key(&configuration.ssh).unwrap_or_else(|e| {
+        error!("{e}");
+        std::process::exit(4);
+    });
 
-    let hash = match key.algorithm() {
-        ssh_key::Algorithm::Rsa { hash } => hash,
-        _ => None,
-    };
+    let config = Data::new(configuration.clone());
+    let mut db = BlockingPool::new(
+        pool,
+        configuration.db_pool_size as usize,
+        configuration.db_timeout,
+    );
+    if let Some(replica) = create_read_pool(&configuration, Some(PerfStats::clone(&perf_stats))) {
+        info!("Using a read replica for reports and exports");
+        db = db.with_replica(replica);
+    }
+    let ssh_client = SshClient::new(db.clone(), key, configuration.ssh.clone());
 
-    // TODO: maybe a better error message
-    let key = PrivateKeyWithHashAlg::new(Arc::new(key), hash)
-        .expect("Failed to convert key to Private key");
+    let health = HealthMonitor::new(db.clone(), ssh_client.clone(), configuration.health.clone());
+    health.start();
+    let notifier = Notifier::new(
+        configuration.notifications.clone(),
+        configuration.chat.clone(),
+    )
+    .unwrap_or_else(|e| {
+        error!("{e}");
+        std::process::exit(6);
+    });
+    let caching_ssh_client = Data::new(
+        CachingSshClient::new(db.clone(), ssh_client.clone())
+            .with_health(health.clone())
+            .with_notifier(notifier.clone()),
+    );
+    let removals = RemovalQueue::new(
+        db.clone(),
+        ssh_client.clone(),
+        Arc::clone(&caching_ssh_client),
+    )
+    .with_health(health.clone());
+    let health = Data::new(health);
+    if let Some(retry_interval) = configuration.ssh.removal_retry_interval {
+        removals.start(retry_interval);
+    }
+    if let Some(expiry_interval) = configuration.ssh.authorization_expiry_interval {
+        AuthorizationExpirer::new(db.clone(), removals.clone(), notifier.clone())
+            .start(expiry_interval);
+    }
+    if let Some(backup) = configuration.backup.clone() {
+        BackupJob::new(db.clone(), backup)
+            .unwrap_or_else(|e| {
+                error!("{e}");
+                std::process::exit(6);
+            })
+            .start();
+    }
+    if let Some(ec2) = configuration.ec2.clone() {
+        Ec2Discovery::new(db.clone(), ec2, ssh_client.clone())
+            .unwrap_or_else(|e| {
+                error!("{e}");
+                std::process::exit(6);
+            })
+            .start();
+    }
+    if let Some(netbox) = configuration.netbox.clone() {
+        NetBoxSync::new(db.clone(), netbox, ssh_client.clone())
+            .unwrap_or_else(|e| {
+                error!("{e}");
+                std::process::exit(6);
+            })
+            .start();
+    }
+    let key_importer = KeyImporter::new(
+        db.clone(),
+        configuration.github.clone(),
+        configuration.gitlab.clone(),
+    )
+    .unwrap_or_else(|e| {
+        error!("{e}");
+        std::process::exit(6);
+    });
+    key_importer.start();
+    let key_importer = Data::new(key_importer);
+    let offboarder = Data::new(Offboarder::new(db.clone(), removals.clone()));
+    let removals = Data::new(removals);
+    let detector = AnomalyDetector::new(db.clone(), configuration.anomalies.clone());
+    detector.start();
+    let detector = Data::new(detector);
+    let policy_scanner = PolicyScanner::new(db.clone());
+    policy_scanner.start();
+    let policy_scanner = Data::new(policy_scanner);
+    let selections = Data::new(selection::SelectionStore::default());
+    let decommissioner = Data::new(Decommissioner::new(
+        db.clone(),
+        ssh_client.clone(),
+        Arc::clone(&caching_ssh_client),
+    ));
+    let mut bulk_applier = BulkApplier::new(
+        db.clone(),
+        ssh_client.clone(),
+        Arc::clone(&caching_ssh_client),
+    )
+    .with_notifier(notifier.clone());
+    if let Some(webhook) = configuration.apply_validation.clone() {
+        bulk_applier = bulk_applier.with_validation(webhook).unwrap_or_else(|e| {
+            error!("{e}");
+            std::process::exit(6);
+        });
+    }
+    let bulk_applier = Data::new(bulk_applier);
+    let notifier = Data::new(notifier);
+    let reporter = Data::new(IntegrityReporter::new(
+        db.clone(),
+        Arc::clone(&caching_ssh_client),
+    ));
 
-    let config = Data::new(configuration.clone());
-    let ssh_client = SshClient::new(pool.clone(), key, configuration.ssh.clone());
+    let auth_backend: Data<dyn AuthBackend> =
+        Data::from(auth::from_config(&configuration).unwrap_or_else(|e| {
+            error!("{e}");
+            std::process::exit(3);
+        }));
 
-    let caching_ssh_client = Data::new(CachingSshClient::new(pool.clone(), ssh_client.clone()));
+    let oidc_login = configuration.oidc.clone().map(|oidc| {
+        Data::new(OidcLogin::new(oidc).unwrap_or_else(|e| {
+            error!("{e}");
+            std::process::exit(6);
+        }))
+    });
 
     info!("Starting Secure SSH Manager");
     let secret_key = cookie::Key::derive_from(configuration.session_key.as_bytes());
+    let session_store = DbSessionStore::new(db.clone());
 
     let caching_client_jobs = Arc::clone(&caching_ssh_client);
+    let remediation = Arc::new(configuration.remediation.clone());
+    let drift_webhooks =
+        DriftWebhooks::new(configuration.drift_webhooks.clone()).unwrap_or_else(|e| {
+            error!("{e}");
+            std::process::exit(6);
+        });
+    let (remediation_client, remediation_db) = (ssh_client.clone(), db.clone());
+
+    let cluster_worker = configuration.cluster.clone().map(|cluster| {
+        ClusterWorker::new(
+            cluster,
+            db.clone(),
+            ssh_client.clone(),
+            Arc::clone(&caching_ssh_client),
+            Arc::clone(&remediation),
+            drift_webhooks.clone(),
+        )
+    });
+    if let Some(cluster_worker) = &cluster_worker {
+        cluster_worker.start();
+    }
+
+    if let Some(sync_interval) = configuration.ssh.sync_interval {
+        info!("Syncing all hosts every {}s", sync_interval.as_secs());
+        caching_ssh_client.start_sync(sync_interval);
+    }
 
     let check_schedule = configuration.ssh.check_schedule;
     let update_schedule = configuration.ssh.update_schedule;
+    let report_schedule = configuration.ssh.report_schedule;
+    let scheduled_reporter = Data::clone(&reporter);
 
-    if check_schedule.is_some() || update_schedule.is_some() {
+    if check_schedule.is_some() || update_schedule.is_some() || report_schedule.is_some() {
         let sched = JobScheduler::new()
             .await
             .expect("Failed to create job scheduler");
@@ -275,17 +962,27 @@ async fn main() -> Result<(), std::io::Error> {
         tokio::spawn(async move {
             if let Some(check_schedule) = check_schedule {
                 let client = caching_client_jobs.clone();
+                let (ssh_client, db) = (remediation_client, remediation_db);
 
                 let mut job = JobBuilder::new().with_cron_job_type();
                 job.schedule = Some(check_schedule.clone());
                 job = job.with_run_async(Box::new(move |_uuid, _sched| {
                     let client = client.clone();
+                    let (remediation, ssh_client, db) =
+                        (Arc::clone(&remediation), ssh_client.clone(), db.clone());
+                    let drift_webhooks = drift_webhooks.clone();
+                    let cluster_worker = cluster_worker.clone();
                     Box::pin(async move {
+                        if let Some(cluster_worker) = cluster_worker {
+                            return cluster_worker.run_check().await;
+                        }
+
                         info!("Running check job");
                         match client.get_current_state().await {
-                            Ok(_data) => {
+                            Ok(data) => {
                                 info!("Succeeded check job");
-                                // TODO: do something with data
+                                drift_webhooks.notify(&data).await;
+                                ssh::remediate(&remediation, &ssh_client, &db, &data).await;
                             }
                             Err(e) => {
                                 error!("Failed check job: {e}");
@@ -326,26 +1023,54 @@ async fn main() -> Result<(), std::io::Error> {
                 info!("Scheduled update job: '{}'", update_schedule.pattern);
             }
 
+            if let Some(report_schedule) = report_schedule {
+                let mut job = JobBuilder::new().with_cron_job_type();
+                job.schedule = Some(report_schedule.clone());
+                job = job.with_run_async(Box::new(move |_uuid, _sched| {
+                    let reporter = scheduled_reporter.clone();
+                    Box::pin(async move {
+                        if let Err(e) = reporter.start().await {
+                            error!("Failed report job: {e}");
+                        }
+                    })
+                }));
+
+                sched
+                    .add(job.build().expect("Failed to build report job"))
+                    .await
+                    .expect("Failed to create report job");
+                info!("Scheduled report job: '{}'", report_schedule.pattern);
+            }
+
             info!("Starting scheduler");
             sched.start().await
         });
     }
 
-    HttpServer::new(move || {
+    let app = move || {
         let generated = generate();
 
         App::new()
+            // Inside the AuthMiddleware, which identifies the actor
+            .wrap(actix_web::middleware::from_fn(audit::record_action))
+            .wrap(actix_web::middleware::from_fn(csrf::protect))
             .wrap(middleware::AuthMiddleware)
             .wrap(
-                SessionMiddleware::builder(CookieSessionStore::default(), secret_key.clone())
+                SessionMiddleware::builder(session_store.clone(), secret_key.clone())
                     .cookie_name("ssm_session".to_owned())
-                    .cookie_secure(false) // Set to true in production
+                    // Marked secure by the proxy middleware if the browser uses https
+                    .cookie_secure(false)
                     .cookie_http_only(true)
                     .build(),
             )
             .wrap(IdentityMiddleware::default())
             .wrap(
                 ErrorHandlers::new().handler(StatusCode::UNAUTHORIZED, |res: ServiceResponse| {
+                    // API clients and scrapers get the error itself instead of the login page
+                    let path = res.request().path();
+                    if path.starts_with("/api/") || path == "/metrics" {
+                        return Ok(ErrorHandlerResponse::Response(res.map_into_left_body()));
+                    }
                     let req = res.request().clone();
                     let response = HttpResponse::Found()
                         .insert_header((header::LOCATION, "/auth/login"))
@@ -356,15 +1081,56 @@ async fn main() -> Result<(), std::io::Error> {
                     )))
                 }),
             )
+            // Outside of the session middleware, whose store records the client address
+            .wrap(actix_web::middleware::from_fn(session_store::with_client))
+            .wrap(actix_web::middleware::from_fn(perf::record_timing))
+            .wrap(actix_web::middleware::from_fn(otel::trace_request))
+            // Outside of the others, so they see the paths without the base path
+            .wrap(actix_web::middleware::from_fn(proxy::apply))
+            // Around everything, so every event of a request has its id
+            .wrap(actix_web::middleware::from_fn(log_tail::with_request_id))
             .app_data(Data::new(ssh_client.clone()))
             .app_data(caching_ssh_client.clone())
+            .app_data(decommissioner.clone())
+            .app_data(bulk_applier.clone())
+            .app_data(reporter.clone())
+            .app_data(offboarder.clone())
+            .app_data(key_importer.clone())
+            .app_data(removals.clone())
+            .app_data(detector.clone())
+            .app_data(policy_scanner.clone())
+            .app_data(health.clone())
+            .app_data(notifier.clone())
+            .app_data(selections.clone())
+            .app_data(perf_stats.clone())
+            .app_data(Data::clone(&log_buffer))
             .app_data(config.clone())
-            .app_data(web::Data::new(pool.clone()))
-            .service(ResourceFiles::new("/", generated).skip_handler_when_not_found())
-            .service(web::scope("/auth").configure(routes::auth::auth_config))
+            .app_data(auth_backend.clone())
+            .app_data(web::Data::new(db.clone()))
+            .service(web::scope("/auth").configure(|cfg| {
+                if let Some(oidc_login) = &oidc_login {
+                    cfg.app_data(oidc_login.clone());
+                }
+                routes::auth::auth_config(cfg);
+            }))
             .configure(routes::route_config)
-    })
-    .bind((configuration.listen, configuration.port))?
-    .run()
-    .await
+            // Registered last, so route patterns are matched before the static files
+            .service(ResourceFiles::new("/", generated).skip_handler_when_not_found())
+    };
+    let server = match tls {
+        Some(tls) => {
+            tls.start();
+            let addr = SocketAddr::new(configuration.listen, configuration.port);
+            tls.serve(app, addr, configuration.shutdown_timeout)?
+        }
+        None => HttpServer::new(app)
+            .disable_signals()
+            .shutdown_timeout(configuration.shutdown_timeout.as_secs())
+            .bind((configuration.listen, configuration.port))?
+            .run(),
+    };
+    shutdown::handle_signals(server.handle(), configuration.shutdown_timeout);
+    server.await?;
+    info!("Stopped");
+    Ok(())
 }
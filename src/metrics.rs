@@ -0,0 +1,206 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Upper bounds in seconds of the duration histograms
+const BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Counts of durations by bucket, like a Prometheus histogram
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    /// Observations per bucket, not cumulative
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: Duration,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += elapsed;
+    }
+}
+
+/// What the last diff of a host found
+#[derive(Debug, Default)]
+struct HostMetrics {
+    /// Diffs that connected to the host
+    diff_duration: Histogram,
+    drift: bool,
+    /// Entries of all authorized_keys files on the host
+    authorized_keys: Option<usize>,
+}
+
+/// Diff results of every host since startup
+#[derive(Debug, Default)]
+pub struct FleetMetrics {
+    hosts: Mutex<HashMap<String, HostMetrics>>,
+}
+
+impl FleetMetrics {
+    /// Records a diff. `started` is set if the diff connected to the host.
+    pub fn record_diff(
+        &self,
+        host_name: &str,
+        started: Option<Instant>,
+        drift: bool,
+        authorized_keys: Option<usize>,
+    ) {
+        let mut hosts = self.hosts.lock().expect("Fleet metrics poisoned");
+        let host = hosts.entry(host_name.to_owned()).or_default();
+        if let Some(started) = started {
+            host.diff_duration.observe(started.elapsed());
+        }
+        host.drift = drift;
+        if authorized_keys.is_some() {
+            host.authorized_keys = authorized_keys;
+        }
+    }
+
+    pub fn remove(&self, host_name: &str) {
+        self.hosts
+            .lock()
+            .expect("Fleet metrics poisoned")
+            .remove(host_name);
+    }
+}
+
+/// Escapes a label value of the text format
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Writes metrics in the Prometheus text format
+#[derive(Debug, Default)]
+pub struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {name} {help}\n# TYPE {name} {kind}");
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, "counter", help);
+        let _ = writeln!(self.text, "{name} {value}");
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: i64) {
+        self.header(name, "gauge", help);
+        let _ = writeln!(self.text, "{name} {value}");
+    }
+
+    /// A gauge with one sample per value of the label
+    pub fn labeled_gauge<'a>(
+        &mut self,
+        name: &str,
+        help: &str,
+        label: &str,
+        values: impl IntoIterator<Item = (&'a str, u64)>,
+    ) {
+        self.header(name, "gauge", help);
+        for (label_value, value) in values {
+            let _ = writeln!(
+                self.text,
+                "{name}{{{label}=\"{}\"}} {value}",
+                escape(label_value)
+            );
+        }
+    }
+
+    /// A histogram without labels
+    pub fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) {
+        self.header(name, "histogram", help);
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+            cumulative += count;
+            let _ = writeln!(self.text, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+        let _ = writeln!(
+            self.text,
+            "{name}_bucket{{le=\"+Inf\"}} {}\n{name}_sum {}\n{name}_count {}",
+            histogram.count,
+            histogram.sum.as_secs_f64(),
+            histogram.count
+        );
+    }
+
+    /// A histogram with one series per value of the label
+    pub fn histograms<'a>(
+        &mut self,
+        name: &str,
+        help: &str,
+        label: &str,
+        histograms: impl IntoIterator<Item = (&'a str, &'a Histogram)>,
+    ) {
+        self.header(name, "histogram", help);
+        for (label_value, histogram) in histograms {
+            let label_value = escape(label_value);
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    self.text,
+                    "{name}_bucket{{{label}=\"{label_value}\",le=\"{bound}\"}} {cumulative}"
+                );
+            }
+            let _ = writeln!(
+                self.text,
+                "{name}_bucket{{{label}=\"{label_value}\",le=\"+Inf\"}} {}\n\
+                {name}_sum{{{label}=\"{label_value}\"}} {}\n\
+                {name}_count{{{label}=\"{label_value}\"}} {}",
+                histogram.count,
+                histogram.sum.as_secs_f64(),
+                histogram.count
+            );
+        }
+    }
+
+    /// Diff durations, hosts with drift and authorized_keys entries per host
+    pub fn fleet(&mut self, fleet: &FleetMetrics) {
+        let hosts = fleet.hosts.lock().expect("Fleet metrics poisoned");
+        let mut names: Vec<&String> = hosts.keys().collect();
+        names.sort();
+
+        self.histograms(
+            "ssm_diff_duration_seconds",
+            "Time to read the authorized_keys of a host and compare them with the database",
+            "host",
+            names
+                .iter()
+                .map(|name| (name.as_str(), &hosts[*name].diff_duration))
+                .filter(|(_, histogram)| histogram.count > 0),
+        );
+        self.gauge(
+            "ssm_hosts_with_drift",
+            "Hosts whose last diff found differences to the database",
+            i64::try_from(hosts.values().filter(|host| host.drift).count()).unwrap_or(i64::MAX),
+        );
+        self.labeled_gauge(
+            "ssm_host_authorized_keys",
+            "Entries of all authorized_keys files on the host at the last diff",
+            "host",
+            names.iter().filter_map(|name| {
+                let keys = hosts[*name].authorized_keys?;
+                Some((name.as_str(), keys as u64))
+            }),
+        );
+    }
+
+    pub fn finish(self) -> String {
+        self.text
+    }
+}
@@ -2,14 +2,46 @@ use actix_identity::Identity;
 use actix_web::{
     body::{BoxBody, MessageBody},
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    http::header,
-    Error, FromRequest, HttpResponse,
+    http::{header, StatusCode},
+    web::Data,
+    Error, FromRequest, HttpMessage, HttpResponse,
 };
 use futures_util::future::LocalBoxFuture;
 use log::warn;
 use std::future::{ready, Ready};
 use std::rc::Rc;
 
+use crate::{
+    audit::Actor,
+    auth::Role,
+    db::BlockingPool,
+    models::{ApiToken, WebUser},
+    routes::{forbidden, ApiResponse},
+    Configuration,
+};
+
+fn unauthorized_api() -> HttpResponse {
+    ApiResponse::with_status(
+        StatusCode::UNAUTHORIZED,
+        String::from("Missing or invalid credentials"),
+    )
+}
+
+fn is_probe(path: &str) -> bool {
+    path == "/healthz" || path == "/readyz"
+}
+
+/// Paths reachable without logging in, by their decoded path
+pub fn is_public(path: &str) -> bool {
+    is_probe(path)
+        || path.starts_with("/auth/")
+        || path == "/metrics"
+        || path == "/branding/logo"
+        || path.starts_with("/static/")
+        || path.ends_with(".css")
+        || path.ends_with(".js")
+}
+
 pub struct AuthMiddleware;
 
 impl<S, B> Transform<S, ServiceRequest> for AuthMiddleware
@@ -48,16 +80,14 @@ where
     forward_ready!(service);
 
     fn call(&self, request: ServiceRequest) -> Self::Future {
-        let path = request.path().to_owned();
+        // The percent-decoded path, which the router matches as well
+        let path = request.match_info().as_str().to_owned();
         let method = request.method().to_owned();
+        // Same as the action of the audit log
+        let action = format!("{method} {path}");
 
-        // Skip authentication for login page, static files, and assets
-        if request.path().starts_with("/auth/")
-            || request.path().starts_with("/static/")
-            || request.path().ends_with(".css")
-            || request.path().ends_with(".js")
-        {
-            warn!("[Web] {} {} (public path)", method, path);
+        // Probes of Kubernetes and load balancers are public and too frequent to log
+        if is_probe(&path) {
             let fut = self.service.call(request);
             return Box::pin(async move {
                 let res = fut.await?;
@@ -65,26 +95,122 @@ where
             });
         }
 
+        // Skip authentication for login page, static files, and assets.
+        // /metrics checks its own token.
+        if is_public(&path) {
+            warn!(action = action.as_str(); "[Web] {action} (public path)");
+            let fut = self.service.call(request);
+            return Box::pin(async move {
+                let res = fut.await?;
+                Ok(res.map_into_boxed_body())
+            });
+        }
+
+        // API clients can authenticate with `Authorization: Bearer <token>`
+        let is_api = path.starts_with("/api/");
+        let bearer = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_owned());
+        let db = request.app_data::<Data<BlockingPool>>().cloned();
+        let required = Role::required_for_request(&request);
+        let default_role = request
+            .app_data::<Data<Configuration>>()
+            .map_or(Role::Viewer, |config| config.default_role);
+
         let (http_req, payload) = request.into_parts();
         let identity = Identity::extract(&http_req);
         let service = self.service.clone();
 
         Box::pin(async move {
+            if let Some(token) = bearer {
+                let api_token = match (is_api, db) {
+                    (true, Some(db)) => db
+                        .run(move |conn| ApiToken::authenticate(conn, &token))
+                        .await
+                        .ok()
+                        .and_then(Result::ok)
+                        .flatten(),
+                    _ => None,
+                };
+
+                let Some(api_token) = api_token else {
+                    warn!(action = action.as_str(); "[Web] {action} (invalid api token)");
+                    return Ok(
+                        ServiceResponse::new(http_req, unauthorized_api()).map_into_boxed_body()
+                    );
+                };
+
+                // API tokens can change hosts and keys, but not administer the instance
+                if Role::Operator < required {
+                    warn!(
+                        action = action.as_str(), user = api_token.name.as_str();
+                        "[Web] {action} (forbidden for api token: {})", api_token.name
+                    );
+                    let response = forbidden(&http_req, required);
+                    return Ok(ServiceResponse::new(http_req, response).map_into_boxed_body());
+                }
+
+                warn!(
+                    action = action.as_str(), user = api_token.name.as_str();
+                    "[Web] {action} (api token: {})", api_token.name
+                );
+                http_req.extensions_mut().insert(Role::Operator);
+                http_req
+                    .extensions_mut()
+                    .insert(Actor(format!("api token '{}'", api_token.name)));
+                let req = ServiceRequest::from_parts(http_req, payload);
+                let res = service.call(req).await?;
+                return Ok(res.map_into_boxed_body());
+            }
+
             let Ok(id) = identity.await else {
-                warn!("[Web] {} {} (unauthorized)", method, path);
+                warn!(action = action.as_str(); "[Web] {action} (unauthorized)");
+                if is_api {
+                    return Ok(
+                        ServiceResponse::new(http_req, unauthorized_api()).map_into_boxed_body()
+                    );
+                }
                 let response = HttpResponse::Found()
                     .append_header((header::LOCATION, "/auth/login"))
                     .insert_header(("HX-Redirect", "/auth/login"))
-                    .body("<a href=\"/auth/login\">Login</a>");
+                    .body(format!(
+                        "<a href=\"{}\">Login</a>",
+                        crate::proxy::url("/auth/login")
+                    ));
                 return Ok(ServiceResponse::new(http_req, response).map_into_boxed_body());
             };
 
+            let username = id.id().unwrap_or_else(|_| "unknown".to_owned());
+            let role = match db {
+                Some(db) => {
+                    let username = username.clone();
+                    db.run(move |conn| WebUser::role(conn, &username, default_role))
+                        .await
+                        .map_err(String::from)
+                        .and_then(|res| res)
+                }
+                None => Err(String::from("Database is not configured")),
+            }
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+
+            if role < required {
+                warn!(
+                    action = action.as_str(), user = username.as_str();
+                    "[Web] {action} (forbidden for {username} with role {role})"
+                );
+                let response = forbidden(&http_req, required);
+                return Ok(ServiceResponse::new(http_req, response).map_into_boxed_body());
+            }
+
             warn!(
-                "[Web] {} {} (authenticated user: {})",
-                method,
-                path,
-                id.id().unwrap_or_else(|_| "unknown".to_owned())
+                action = action.as_str(), user = username.as_str();
+                "[Web] {action} (authenticated user: {username}, role: {role})"
             );
+            http_req.extensions_mut().insert(role);
+            http_req.extensions_mut().insert(Actor(username));
             let req = ServiceRequest::from_parts(http_req, payload);
             let res = service.call(req).await?;
             Ok(res.map_into_boxed_body())
@@ -1,6 +1,7 @@
-use diesel::prelude::*;
-use serde::Deserialize;
 use crate::DbConnection;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
 #[derive(Queryable, Selectable, Associations, Clone, Debug)]
 #[diesel(table_name = crate::schema::host)]
@@ -14,19 +15,25 @@ pub struct Host {
     pub port: i32,
     pub key_fingerprint: Option<String>,
     pub jump_via: Option<i32>,
+    pub forbid_forwarding: bool,
+    pub owner: Option<String>,
+    pub default_options: Option<String>,
+    pub key_trust: Option<String>,
+    pub importance: String,
+    pub observed_until: Option<time::PrimitiveDateTime>,
 }
 
 impl Host {
     /// Updates the host's name, address, username, port, key_fingerprint, and jump_via. This is a stub implementation; in a real application, you should perform a database update.
     pub fn update_host(
-        conn: &mut crate::DbConnection,
+        conn: &mut DbConnection,
         old_name: String,
         new_name: String,
         new_address: String,
         new_username: String,
         new_port: i32,
         new_key_fingerprint: Option<String>,
-        new_jump_via: Option<i32>
+        new_jump_via: Option<i32>,
     ) -> Result<(), actix_web::Error> {
         use crate::schema::host::dsl::*;
         log::warn!(
@@ -66,9 +73,57 @@ pub struct NewHost {
     pub username: String,
     pub key_fingerprint: String,
     pub jump_via: Option<i32>,
+    pub key_trust: Option<String>,
+    pub observed_until: Option<time::PrimitiveDateTime>,
 }
 
-#[derive(Queryable, Selectable, Associations, Clone, Debug)]
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::host_archive)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct ArchivedHost {
+    pub id: i32,
+    pub name: String,
+    pub username: String,
+    pub address: String,
+    pub port: i32,
+    pub key_fingerprint: Option<String>,
+    pub decommissioned_at: time::PrimitiveDateTime,
+    pub access_report: String,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = crate::schema::host_archive)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewArchivedHost {
+    pub name: String,
+    pub username: String,
+    pub address: String,
+    pub port: i32,
+    pub key_fingerprint: Option<String>,
+    pub decommissioned_at: time::PrimitiveDateTime,
+    pub access_report: String,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::user_offboarding)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct OffboardingReport {
+    pub id: i32,
+    pub username: String,
+    pub offboarded_at: time::PrimitiveDateTime,
+    pub report: String,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = crate::schema::user_offboarding)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewOffboardingReport {
+    pub username: String,
+    pub offboarded_at: time::PrimitiveDateTime,
+    pub report: String,
+}
+
+#[derive(Queryable, Selectable, Associations, Clone, Debug, Serialize, ToSchema)]
 #[diesel(table_name = crate::schema::user_key)]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
 #[diesel(belongs_to(User))]
@@ -78,6 +133,15 @@ pub struct PublicUserKey {
     pub key_base64: String,
     pub comment: Option<String>,
     pub user_id: i32,
+    /// When the key stops being authorized, in UTC
+    #[serde(serialize_with = "serialize_expiry")]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub expires_at: Option<time::PrimitiveDateTime>,
+    /// FIDO attestation of an sk- key, as written by ssh-keygen
+    #[serde(skip)]
+    pub attestation: Option<Vec<u8>>,
+    /// Subject of the attestation certificate, if the key was attested when it was uploaded
+    pub attested_by: Option<String>,
 }
 
 #[derive(Insertable, Associations, Clone)]
@@ -89,6 +153,9 @@ pub struct NewPublicUserKey {
     key_base64: String,
     comment: Option<String>,
     user_id: i32,
+    expires_at: Option<time::PrimitiveDateTime>,
+    attestation: Option<Vec<u8>>,
+    attested_by: Option<String>,
 }
 
 impl NewPublicUserKey {
@@ -103,23 +170,57 @@ impl NewPublicUserKey {
             key_base64: base64,
             comment,
             user_id: user,
+            expires_at: None,
+            attestation: None,
+            attested_by: None,
         }
     }
+
+    pub const fn with_expiry(mut self, expires_at: Option<time::PrimitiveDateTime>) -> Self {
+        self.expires_at = expires_at;
+        self
+    }
+
+    /// Sets the comment unless the key has one
+    pub fn with_default_comment(mut self, comment: String) -> Self {
+        self.comment = self.comment.or(Some(comment));
+        self
+    }
+
+    pub fn with_attestation(mut self, attestation: crate::attestation::KeyAttestation) -> Self {
+        self.attestation = Some(attestation.data);
+        self.attested_by = Some(attestation.attested_by);
+        self
+    }
 }
 
-#[derive(Queryable, Selectable, Clone)]
+#[derive(Queryable, Selectable, Clone, Serialize, ToSchema)]
 #[diesel(table_name = crate::schema::user)]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
 pub struct User {
     pub id: i32,
     pub username: String,
     pub enabled: bool,
+    pub email: Option<String>,
+    pub default_options: Option<String>,
 }
 
-#[derive(Insertable, Deserialize, Clone)]
+#[derive(Insertable, Deserialize, Clone, ToSchema)]
 #[diesel(table_name = crate::schema::user)]
 pub struct NewUser {
     pub username: String,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub email: Option<String>,
+}
+
+/// Treats an empty form field like a missing one
+fn empty_as_none<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(Option::<String>::deserialize(deserializer)?
+        .map(|value| value.trim().to_owned())
+        .filter(|value| !value.is_empty()))
 }
 
 impl PublicUserKey {
@@ -130,6 +231,66 @@ impl PublicUserKey {
         }
     }
 
+    /// Expired keys aren't authorized anywhere anymore
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= crate::db::now())
+    }
+
+    /// When the key expires, for display
+    pub fn expiry(&self) -> Option<String> {
+        self.expires_at.map(format_expiry)
+    }
+
+    /// The expiry as value of a `datetime-local` input, empty if the key doesn't expire
+    pub fn expiry_input(&self) -> String {
+        self.expires_at
+            .and_then(|expires_at| expires_at.format(EXPIRY_INPUT_FORMAT).ok())
+            .unwrap_or_default()
+    }
+
+    /// Hardware-backed keys are stored on a security key, like a YubiKey
+    pub fn is_hardware_backed(&self) -> bool {
+        self.key_type.starts_with("sk-")
+    }
+
+    /// Whether the key was proven to be hardware-backed when it was uploaded
+    pub fn is_attested(&self) -> bool {
+        self.is_hardware_backed() && self.attested_by.is_some()
+    }
+
+    /// Whether the key is hardware-backed and attested, for display
+    pub fn attestation_status(&self) -> Option<String> {
+        match &self.attested_by {
+            Some(attested_by) if self.is_hardware_backed() => {
+                Some(format!("Attested hardware key ({attested_by})"))
+            }
+            _ if self.is_hardware_backed() => Some(String::from("Hardware key, not attested")),
+            _ => None,
+        }
+    }
+
+    /// Why the key is considered weak: DSA keys and RSA keys shorter than 2048 bits
+    pub fn weakness(&self) -> Option<String> {
+        use ssh_key::public::KeyData;
+
+        let key =
+            ssh_key::PublicKey::from_openssh(&format!("{} {}", self.key_type, self.key_base64))
+                .ok()?;
+        match key.key_data() {
+            KeyData::Dsa(_) => Some(String::from("DSA is deprecated")),
+            KeyData::Rsa(rsa) => {
+                let modulus = rsa.n.as_positive_bytes()?;
+                let bits = modulus.len() * 8
+                    - modulus
+                        .first()
+                        .map_or(0, |byte| byte.leading_zeros() as usize);
+                (bits < MIN_RSA_BITS).then(|| format!("RSA with only {bits} bits"))
+            }
+            _ => None,
+        }
+    }
+
     pub fn key_preview(&self) -> String {
         let preview: String = self
             .key_base64
@@ -144,9 +305,359 @@ impl PublicUserKey {
     }
 }
 
+/// Shorter RSA keys are weak
+const MIN_RSA_BITS: usize = 2048;
+
+const EXPIRY_FORMAT: &[time::format_description::FormatItem<'_>] =
+    time::macros::format_description!("[year]-[month]-[day] [hour]:[minute] UTC");
+/// As sent by `datetime-local` inputs
+const EXPIRY_INPUT_FORMAT: &[time::format_description::FormatItem<'_>] =
+    time::macros::format_description!("[year]-[month]-[day]T[hour]:[minute]");
+
+/// An expiry date for display
+pub fn format_expiry(expires_at: time::PrimitiveDateTime) -> String {
+    expires_at
+        .format(EXPIRY_FORMAT)
+        .unwrap_or_else(|_| expires_at.to_string())
+}
+
+/// Serializes an expiry date in UTC as RFC 3339
+pub fn serialize_expiry<S>(
+    expires_at: &Option<time::PrimitiveDateTime>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    use serde::ser::Error;
+
+    match expires_at {
+        Some(expires_at) => expires_at
+            .assume_utc()
+            .format(&time::format_description::well_known::Rfc3339)
+            .map_err(S::Error::custom)?
+            .serialize(serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Reads an expiry date written by [`serialize_expiry`], or entered like in the web UI
+pub fn deserialize_expiry<'de, D>(
+    deserializer: D,
+) -> Result<Option<time::PrimitiveDateTime>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+
+    match Option::<String>::deserialize(deserializer)? {
+        Some(value) => parse_expiry(&value).map_err(D::Error::custom),
+        None => Ok(None),
+    }
+}
+
+/// Parses when a key or an authorization expires: RFC 3339, `YYYY-MM-DDTHH:MM` as sent by
+/// browsers, or a date. Times without an offset are UTC. None if empty.
+pub fn parse_expiry(value: &str) -> Result<Option<time::PrimitiveDateTime>, String> {
+    use time::{format_description::well_known::Rfc3339, macros::format_description};
+
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    if let Ok(expires_at) = time::OffsetDateTime::parse(value, &Rfc3339) {
+        let expires_at = expires_at.to_offset(time::UtcOffset::UTC);
+        return Ok(Some(time::PrimitiveDateTime::new(
+            expires_at.date(),
+            expires_at.time(),
+        )));
+    }
+    time::PrimitiveDateTime::parse(value, EXPIRY_INPUT_FORMAT)
+        .or_else(|_| {
+            time::Date::parse(value, format_description!("[year]-[month]-[day]"))
+                .map(|date| date.midnight())
+        })
+        .map(Some)
+        .map_err(|_| {
+            format!("Invalid expiry date '{value}', expected e.g. 2026-12-31 or 2026-12-31T18:00")
+        })
+}
+
+/// Parses a key in OpenSSH format, `<algorithm> <base64> [comment]`
+pub fn parse_key_line(line: &str, user_id: i32) -> Result<NewPublicUserKey, String> {
+    let mut parts = line.splitn(3, ' ');
+    let (Some(key_type), Some(key_base64)) = (parts.next(), parts.next()) else {
+        return Err(format!("Invalid key '{line}'"));
+    };
+    let algo = ssh_key::Algorithm::new(key_type)
+        .map_err(|_| format!("Invalid key algorithm '{key_type}'"))?;
+    let comment = parts.next().map(ToOwned::to_owned);
+
+    Ok(NewPublicUserKey::new(
+        algo,
+        key_base64.to_owned(),
+        comment,
+        user_id,
+    ))
+}
+
 impl TryFrom<&PublicUserKey> for ssh_key::public::PublicKey {
     type Error = String;
     fn try_from(value: &PublicUserKey) -> Result<Self, Self::Error> {
         Self::from_openssh(&value.to_openssh()).map_err(|e| e.to_string())
     }
 }
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::api_token)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct ApiToken {
+    pub id: i32,
+    pub name: String,
+    pub created_by: String,
+    pub created_at: time::PrimitiveDateTime,
+    pub last_used: Option<time::PrimitiveDateTime>,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = crate::schema::api_token)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewApiToken {
+    pub name: String,
+    pub token_hash: String,
+    pub created_by: String,
+    pub created_at: time::PrimitiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::web_user)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct WebUser {
+    pub id: i32,
+    pub username: String,
+    pub oidc_subject: Option<String>,
+    pub created_at: time::PrimitiveDateTime,
+    pub last_login: Option<time::PrimitiveDateTime>,
+    pub role: String,
+    pub totp_secret: Option<String>,
+    pub totp_last_step: Option<i64>,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = crate::schema::web_user)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewWebUser {
+    pub username: String,
+    pub oidc_subject: Option<String>,
+    pub created_at: time::PrimitiveDateTime,
+    pub last_login: Option<time::PrimitiveDateTime>,
+    pub role: String,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::audit_log)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct AuditEntry {
+    pub id: i32,
+    pub created_at: time::PrimitiveDateTime,
+    pub actor: String,
+    pub action: String,
+    pub target: String,
+    pub success: bool,
+    pub result: String,
+    pub client: Option<String>,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = crate::schema::audit_log)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewAuditEntry {
+    pub created_at: time::PrimitiveDateTime,
+    pub actor: String,
+    pub action: String,
+    pub target: String,
+    pub success: bool,
+    pub result: String,
+    pub client: Option<String>,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::login_attempt)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct LoginAttempt {
+    pub created_at: time::PrimitiveDateTime,
+    pub username: String,
+    pub client: Option<String>,
+    pub outcome: String,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = crate::schema::login_attempt)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewLoginAttempt {
+    pub created_at: time::PrimitiveDateTime,
+    pub username: String,
+    pub client: Option<String>,
+    pub outcome: String,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::web_session)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct WebSession {
+    pub id: i32,
+    pub state: String,
+    pub username: Option<String>,
+    pub client: Option<String>,
+    pub created_at: time::PrimitiveDateTime,
+    pub last_activity: time::PrimitiveDateTime,
+    pub expires_at: time::PrimitiveDateTime,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = crate::schema::web_session)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewWebSession {
+    pub key_hash: String,
+    pub state: String,
+    pub username: Option<String>,
+    pub client: Option<String>,
+    pub created_at: time::PrimitiveDateTime,
+    pub last_activity: time::PrimitiveDateTime,
+    pub expires_at: time::PrimitiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::integrity_report)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct IntegrityReport {
+    pub id: i32,
+    pub created_at: time::PrimitiveDateTime,
+    pub summary: String,
+    pub hosts: String,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = crate::schema::integrity_report)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewIntegrityReport {
+    pub created_at: time::PrimitiveDateTime,
+    pub summary: String,
+    pub hosts: String,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::access_request)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct AccessRequest {
+    pub id: i32,
+    pub created_at: time::PrimitiveDateTime,
+    pub requested_by: String,
+    pub user_id: i32,
+    pub host_id: i32,
+    pub login: String,
+    pub justification: String,
+    pub status: String,
+    pub decided_by: Option<String>,
+    pub decided_at: Option<time::PrimitiveDateTime>,
+    pub result: Option<String>,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = crate::schema::access_request)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewAccessRequest {
+    pub created_at: time::PrimitiveDateTime,
+    pub requested_by: String,
+    pub user_id: i32,
+    pub host_id: i32,
+    pub login: String,
+    pub justification: String,
+    pub status: String,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug, Serialize, ToSchema)]
+#[diesel(table_name = crate::schema::host_group)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct HostGroup {
+    pub id: i32,
+    pub name: String,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug, Serialize, ToSchema)]
+#[diesel(table_name = crate::schema::user_group)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct UserGroup {
+    pub id: i32,
+    pub name: String,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::group_authorization)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct GroupAuthorization {
+    pub id: i32,
+    pub user_group_id: i32,
+    pub login: String,
+    pub options: Option<String>,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::pending_removal)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct PendingRemoval {
+    pub id: i32,
+    pub created_at: time::PrimitiveDateTime,
+    pub host_id: i32,
+    pub login: String,
+    pub key_base64: String,
+    pub reason: String,
+    pub attempts: i32,
+    pub last_attempt: Option<time::PrimitiveDateTime>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::finding)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct Finding {
+    pub id: i32,
+    pub created_at: time::PrimitiveDateTime,
+    pub kind: String,
+    pub subject: String,
+    pub details: String,
+    pub status: String,
+    pub decided_by: Option<String>,
+    pub decided_at: Option<time::PrimitiveDateTime>,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::pending_authorization)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct PendingAuthorization {
+    pub id: i32,
+    pub created_at: time::PrimitiveDateTime,
+    pub proposed_by: String,
+    pub host_id: i32,
+    pub user_id: i32,
+    pub login: String,
+    pub options: Option<String>,
+    pub expires_at: Option<time::PrimitiveDateTime>,
+    pub status: String,
+    pub decided_by: Option<String>,
+    pub decided_at: Option<time::PrimitiveDateTime>,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = crate::schema::pending_authorization)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewPendingAuthorization {
+    pub created_at: time::PrimitiveDateTime,
+    pub proposed_by: String,
+    pub host_id: i32,
+    pub user_id: i32,
+    pub login: String,
+    pub options: Option<String>,
+    pub expires_at: Option<time::PrimitiveDateTime>,
+    pub status: String,
+}
@@ -0,0 +1,217 @@
+use std::time::Duration;
+
+use log::{error, info};
+use openidconnect::reqwest;
+use serde::Deserialize;
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    db::{sync_discovered_hosts, BlockingPool, DiscoveredHost, DiscoverySummary},
+    secrets,
+    ssh::SshClient,
+};
+
+/// `source` of the hosts synced from NetBox
+const SOURCE: &str = "netbox";
+const PAGE_SIZE: usize = 1000;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+const fn default_interval() -> Option<Duration> {
+    Some(Duration::from_secs(3600))
+}
+
+/// Takes the devices and virtual machines of a NetBox instance as hosts
+#[derive(Debug, Clone, Deserialize)]
+pub struct NetBoxConfig {
+    /// e.g. `https://netbox.example.com`
+    url: String,
+    /// API token with read access to devices and virtual machines
+    #[serde(deserialize_with = "secrets::deserialize_secret")]
+    token: String,
+    /// Slug of the tag that devices and virtual machines need
+    tag: String,
+    /// User ssm logs in as on the new hosts
+    username: String,
+    /// Seconds between syncs, starting at startup (default 3600, 0 disables)
+    #[serde(
+        default = "default_interval",
+        deserialize_with = "crate::deserialize_interval"
+    )]
+    interval: Option<Duration>,
+}
+
+impl NetBoxConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        reqwest::Url::parse(&self.url)
+            .map_err(|e| format!("Invalid [netbox] url '{}': {e}", self.url))?;
+        if self.tag.is_empty() {
+            return Err(String::from("The tag of [netbox] can't be empty"));
+        }
+        if self.username.is_empty() {
+            return Err(String::from("The username of [netbox] can't be empty"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct Page {
+    count: usize,
+    results: Vec<Object>,
+}
+
+/// A device or virtual machine, of which only the name and primary IP are used
+#[derive(Deserialize)]
+struct Object {
+    id: i64,
+    /// Devices may be unnamed
+    name: Option<String>,
+    primary_ip: Option<IpAddress>,
+}
+
+#[derive(Deserialize)]
+struct IpAddress {
+    /// With the prefix length, e.g. `10.0.0.1/24`
+    address: String,
+}
+
+/// The kinds of objects synced, by their API path and the prefix of their ids
+const KINDS: [(&str, &str); 2] = [
+    ("dcim/devices", "device"),
+    ("virtualization/virtual-machines", "vm"),
+];
+
+/// Periodically creates hosts for new devices and virtual machines with the tag and
+/// keeps their names and addresses in line with NetBox
+#[derive(Clone)]
+pub struct NetBoxSync {
+    db: BlockingPool,
+    config: NetBoxConfig,
+    ssh_client: SshClient,
+    http: reqwest::Client,
+}
+
+impl NetBoxSync {
+    pub fn new(
+        db: BlockingPool,
+        config: NetBoxConfig,
+        ssh_client: SshClient,
+    ) -> Result<Self, String> {
+        config.validate()?;
+        let http = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            db,
+            config,
+            ssh_client,
+            http,
+        })
+    }
+
+    /// Syncs now and then every configured interval, unless disabled
+    pub fn start(&self) {
+        let Some(interval) = self.config.interval else {
+            return;
+        };
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                if let Err(e) = this.run().await {
+                    error!("Failed to sync with NetBox: {e}");
+                }
+            }
+        });
+    }
+
+    /// Every object with the tag at an API path, page by page
+    async fn list(&self, path: &str) -> Result<Vec<Object>, String> {
+        let mut url = reqwest::Url::parse(&self.config.url)
+            .map_err(|e| format!("Invalid NetBox url '{}': {e}", self.config.url))?;
+        url.set_path(&format!("{}/api/{path}/", url.path().trim_end_matches('/')));
+        let mut objects = Vec::new();
+        loop {
+            let response = self
+                .http
+                .get(url.clone())
+                .query(&[
+                    ("tag", self.config.tag.as_str()),
+                    ("limit", &PAGE_SIZE.to_string()),
+                    ("offset", &objects.len().to_string()),
+                ])
+                .header(
+                    reqwest::header::AUTHORIZATION,
+                    format!("Token {}", self.config.token),
+                )
+                .header(reqwest::header::ACCEPT, "application/json")
+                .timeout(REQUEST_TIMEOUT)
+                .send()
+                .await
+                .map_err(|e| format!("NetBox: {e}"))?;
+            let status = response.status();
+            let text = response.text().await.map_err(|e| format!("NetBox: {e}"))?;
+            if !status.is_success() {
+                return Err(format!("NetBox answered {status}: {text}"));
+            }
+            let page: Page = serde_json::from_str(&text)
+                .map_err(|e| format!("Unexpected answer from NetBox for {path}: {e}"))?;
+            let done = page.results.is_empty();
+            objects.extend(page.results);
+            if done || objects.len() >= page.count {
+                return Ok(objects);
+            }
+        }
+    }
+
+    /// Lists the devices and virtual machines and brings the hosts in line with them
+    pub async fn run(&self) -> Result<DiscoverySummary, String> {
+        let mut found = Vec::new();
+        for (path, kind) in KINDS {
+            for object in self.list(path).await? {
+                let external_id = format!("{kind}:{}", object.id);
+                found.push(DiscoveredHost {
+                    name: object
+                        .name
+                        .filter(|name| !name.is_empty())
+                        .unwrap_or_else(|| external_id.clone()),
+                    // The address without the prefix length
+                    address: object
+                        .primary_ip
+                        .map(|ip| match ip.address.split_once('/') {
+                            Some((address, _)) => address.to_owned(),
+                            None => ip.address,
+                        }),
+                    external_id,
+                });
+            }
+        }
+
+        let username = self.config.username.clone();
+        let tag = self.config.tag.clone();
+        let observed_until = self.ssh_client.observation_end();
+        let summary = self
+            .db
+            .run(move |conn| {
+                sync_discovered_hosts(
+                    conn,
+                    SOURCE,
+                    &username,
+                    observed_until,
+                    found,
+                    |id, name| format!("NetBox {id} of {name} was deleted or lost the tag {tag}"),
+                )
+            })
+            .await??;
+        if summary.created + summary.updated + summary.gone > 0 {
+            info!(
+                "NetBox: {} new hosts, {} updated, {} gone",
+                summary.created, summary.updated, summary.gone
+            );
+        }
+        Ok(summary)
+    }
+}
@@ -0,0 +1,235 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use log::{error, info};
+use openidconnect::reqwest;
+use serde::Deserialize;
+
+use crate::{
+    chat::{ChatConfig, ChatEvent},
+    models::Host,
+    smtp::SmtpConfig,
+    ssh::{DiffItem, SshClientError},
+};
+
+/// Who is emailed about changes to keys and access, and how
+#[derive(Debug, Clone, Deserialize)]
+pub struct NotificationConfig {
+    /// Addresses of the admins
+    recipients: Vec<String>,
+    smtp: SmtpConfig,
+}
+
+/// Whether a request adds or removes authorizations, going by its audit log action
+pub fn authorization_change(action: &str) -> Option<&'static str> {
+    let path = action.strip_prefix("POST ")?;
+    if path.ends_with("/delete_authorization") || path.ends_with("/offboard") {
+        return Some("removed");
+    }
+    let added = path.ends_with("/authorize")
+        || path.ends_with("/authorizations")
+        || path.ends_with("/copy_authorizations")
+        || ((path.starts_with("/approvals/") || path.starts_with("/portal/requests/"))
+            && path.ends_with("/approve"));
+    added.then_some("added")
+}
+
+/// What was last mailed about a host
+#[derive(Debug, Default)]
+struct Reported {
+    hostkey_mismatch: bool,
+    /// Unexpected keys by login and base64
+    unexpected_keys: Vec<String>,
+}
+
+/// Emails the admins and posts to chats when a host key changes, a host has unexpected keys or
+/// authorizations are added or removed, and to chats when a bulk apply finishes.
+/// Does nothing unless configured.
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    config: Option<Arc<NotificationConfig>>,
+    chats: Arc<Vec<ChatConfig>>,
+    http: reqwest::Client,
+    /// Findings of the diff are only mailed when they first show up
+    reported: Arc<Mutex<HashMap<String, Reported>>>,
+}
+
+impl Notifier {
+    pub fn new(config: Option<NotificationConfig>, chats: Vec<ChatConfig>) -> Result<Self, String> {
+        let http = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|e| format!("Failed to create HTTP client: {e}"))?;
+        Ok(Self {
+            config: config.map(Arc::new),
+            chats: Arc::new(chats),
+            http,
+            reported: Arc::default(),
+        })
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.config.is_some() || !self.chats.is_empty()
+    }
+
+    /// Sends the mail and chat messages in the background, failures are only logged.
+    /// Apply results only go to chats.
+    fn send(&self, event: ChatEvent, subject: String, body: String) {
+        if let Some(config) = self.config.clone().filter(|_| event != ChatEvent::Apply) {
+            let (subject, body) = (subject.clone(), body.clone());
+            tokio::spawn(async move {
+                match config.smtp.send(&config.recipients, &subject, &body).await {
+                    Ok(()) => info!("Sent notification '{subject}'"),
+                    Err(e) => error!("Failed to send notification '{subject}': {e}"),
+                }
+            });
+        }
+
+        let chats: Vec<ChatConfig> = self
+            .chats
+            .iter()
+            .filter(|chat| chat.wants(event))
+            .cloned()
+            .collect();
+        if chats.is_empty() {
+            return;
+        }
+        let http = self.http.clone();
+        tokio::spawn(async move {
+            for chat in chats {
+                if let Err(e) = chat.post(&http, &subject, &body).await {
+                    error!("Failed to post '{subject}' to chat: {e}");
+                }
+            }
+        });
+    }
+
+    /// Mails a single recipient right away, e.g. a password reset link
+    pub async fn mail(&self, to: &str, subject: &str, body: &str) -> Result<(), String> {
+        let Some(config) = &self.config else {
+            return Err(String::from(
+                "Mail is not configured, see the [notifications] section",
+            ));
+        };
+        config.smtp.send(&[to.to_owned()], subject, body).await
+    }
+
+    /// Mails new findings of a host's diff: a different host key or keys nobody authorized.
+    /// Subjects of critical hosts are marked as such.
+    pub fn host_diff(
+        &self,
+        host: &Host,
+        diff: &Result<Vec<(String, Vec<DiffItem>)>, SshClientError>,
+    ) {
+        if !self.is_enabled() {
+            return;
+        }
+        let host_name = host.name.as_str();
+        let severity = match host.is_critical() {
+            true => "[CRITICAL] ",
+            false => "",
+        };
+        let mut reported = self.reported.lock().expect("Notifier lock poisoned");
+        let reported = reported.entry(host_name.to_owned()).or_default();
+
+        let logins = match diff {
+            Err(SshClientError::UnknownKey) => {
+                if !reported.hostkey_mismatch {
+                    reported.hostkey_mismatch = true;
+                    self.send(
+                        ChatEvent::HostKey,
+                        format!("{severity}Host key of {host_name} changed"),
+                        format!(
+                            "{host_name} answered with a host key that doesn't match the stored one.\n\
+                            Someone may be intercepting the connection, or the host was reinstalled.\n\
+                            ssm won't connect to it until the new host key is accepted on the host page."
+                        ),
+                    );
+                }
+                return;
+            }
+            // Unreachable hosts say nothing about their keys
+            Err(_) => return,
+            Ok(logins) => logins,
+        };
+        reported.hostkey_mismatch = false;
+
+        let mut unexpected = BTreeMap::new();
+        for (login, items) in logins {
+            for item in items {
+                let (key, owner) = match item {
+                    DiffItem::UnknownKey(key) => (key, None),
+                    DiffItem::UnauthorizedKey(key, username) => (key, Some(username)),
+                    DiffItem::ExpiredKey(key, username) => (key, Some(username)),
+                    _ => continue,
+                };
+                let description = format!(
+                    "{login}: {} {}{}",
+                    key.algorithm,
+                    key.comment.as_deref().unwrap_or("(no comment)"),
+                    owner.map_or_else(String::new, |owner| format!(", key of {owner}")),
+                );
+                unexpected.insert(format!("{login} {}", key.base64), description);
+            }
+        }
+
+        let new: Vec<&String> = unexpected
+            .iter()
+            .filter(|(id, _)| !reported.unexpected_keys.contains(id))
+            .map(|(_, description)| description)
+            .collect();
+        if !new.is_empty() {
+            self.send(
+                ChatEvent::Drift,
+                format!("{severity}Unexpected keys on {host_name}"),
+                format!(
+                    "The authorized_keys of {host_name} contain {} key(s) that aren't authorized:\n\n{}\n\n\
+                    Apply the host to remove them.",
+                    new.len(),
+                    new.iter()
+                        .map(|description| format!("- {description}"))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                ),
+            );
+        }
+        reported.unexpected_keys = unexpected.into_keys().collect();
+    }
+
+    /// A web user accepted a new host key
+    pub fn host_key_replaced(&self, host_name: &str, actor: &str, fingerprint: &str) {
+        self.send(
+            ChatEvent::HostKey,
+            format!("Host key of {host_name} replaced"),
+            format!("{actor} replaced the stored host key of {host_name}.\nNew fingerprint: {fingerprint}"),
+        );
+    }
+
+    /// Authorizations were added or removed, see [`authorization_change`]
+    pub fn authorization_changed(&self, actor: &str, change: &str, action: &str, target: &str) {
+        self.send(
+            ChatEvent::Authorization,
+            format!("Authorization {change} by {actor}"),
+            format!("{actor} {change} authorizations.\n\nAction: {action}\nDetails: {target}"),
+        );
+    }
+
+    /// A bulk apply finished, with the hosts that failed and why
+    pub fn apply_finished(&self, applied: usize, failed: &[(String, String)]) {
+        let subject = match failed.len() {
+            0 => format!("Applied the database state to {applied} host(s)"),
+            n => format!("Applied the database state to {applied} host(s), {n} failed"),
+        };
+        let body = match failed.is_empty() {
+            true => String::from("Every host is up to date."),
+            false => failed
+                .iter()
+                .map(|(host, error)| format!("- {host}: {error}"))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        };
+        self.send(ChatEvent::Apply, subject, body);
+    }
+}
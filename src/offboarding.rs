@@ -0,0 +1,197 @@
+use std::fmt::Write;
+
+use log::info;
+
+use crate::{
+    db::{self, BlockingPool, UserAndOptions},
+    jobs::{JobTracker, Step, StepStatus},
+    models::{Host, NewOffboardingReport, OffboardingReport, PublicUserKey, User},
+    removal_queue::RemovalQueue,
+};
+
+const STEPS: [&str; 5] = [
+    "Disable account",
+    "Revoke authorizations",
+    "Remove keys from hosts",
+    "Revoke certificates",
+    "Write completion report",
+];
+
+/// Runs user offboarding jobs in the background and keeps track of their progress
+#[derive(Clone)]
+pub struct Offboarder {
+    db: BlockingPool,
+    removals: RemovalQueue,
+    /// Progress by username
+    jobs: JobTracker,
+}
+
+impl Offboarder {
+    pub fn new(db: BlockingPool, removals: RemovalQueue) -> Self {
+        Self {
+            db,
+            removals,
+            jobs: JobTracker::default(),
+        }
+    }
+
+    /// Progress of the last offboarding job for this user
+    pub async fn status(&self, username: &str) -> Option<Vec<Step>> {
+        self.jobs.status(username).await
+    }
+
+    pub async fn start(&self, user: User) -> Result<(), String> {
+        self.jobs.begin(&user.username, &STEPS).await?;
+
+        let this = self.clone();
+        actix_web::rt::spawn(async move { this.run(user).await });
+        Ok(())
+    }
+
+    async fn run(&self, user: User) {
+        let name = user.username.clone();
+        info!(user = name.as_str(), action = "offboard"; "Offboarding {name}");
+        let mut report = format!("# Offboarding report for {name}, started {}\n", db::now());
+
+        self.jobs.set(&name, 0, StepStatus::Running).await;
+        let username = name.clone();
+        match self
+            .db
+            .run(move |conn| User::update_user(conn, &username, &username, false))
+            .await
+            .map_err(String::from)
+            .and_then(|res| res)
+        {
+            Ok(()) => {
+                self.jobs
+                    .set(&name, 0, StepStatus::Done(String::new()))
+                    .await;
+                report.push_str("Disabled account\n");
+            }
+            Err(e) => return self.jobs.fail(&name, 0, e).await,
+        }
+
+        self.jobs.set(&name, 1, StepStatus::Running).await;
+        let db_user = user.clone();
+        let (keys, authorizations) = match self
+            .db
+            .run(move |conn| {
+                let keys = db_user.get_keys(conn)?;
+                let authorizations = db_user.get_authorizations(conn)?;
+                db_user.revoke_authorizations(conn)?;
+                Ok::<_, String>((keys, authorizations))
+            })
+            .await
+            .map_err(String::from)
+            .and_then(|res| res)
+        {
+            Ok(res) => res,
+            Err(e) => return self.jobs.fail(&name, 1, e).await,
+        };
+        self.jobs
+            .set(
+                &name,
+                1,
+                StepStatus::Done(format!("{} authorization(s)", authorizations.len())),
+            )
+            .await;
+        for (_, host, login, options) in &authorizations {
+            let _ = writeln!(
+                report,
+                "Revoked authorization for {login} on {host} (options: {})",
+                options.as_deref().unwrap_or("none")
+            );
+        }
+
+        self.jobs.set(&name, 2, StepStatus::Running).await;
+        let reason = format!("offboarding of {name}");
+        let failed = self
+            .remove_keys(&keys, &authorizations, &reason, &mut report)
+            .await;
+        self.jobs
+            .set(
+                &name,
+                2,
+                if failed == 0 {
+                    StepStatus::Done(format!("{} login(s)", authorizations.len()))
+                } else {
+                    StepStatus::Failed(format!(
+                        "{failed} of {} login(s) failed and are retried, see the report",
+                        authorizations.len()
+                    ))
+                },
+            )
+            .await;
+
+        self.jobs
+            .set(
+                &name,
+                3,
+                StepStatus::Skipped(String::from("No certificates are issued by this manager")),
+            )
+            .await;
+
+        self.jobs.set(&name, 4, StepStatus::Running).await;
+        let _ = writeln!(report, "Finished {}", db::now());
+        let new_report = NewOffboardingReport {
+            username: name.clone(),
+            offboarded_at: db::now(),
+            report,
+        };
+        match self
+            .db
+            .run(move |conn| OffboardingReport::add(conn, new_report))
+            .await
+            .map_err(String::from)
+            .and_then(|res| res)
+        {
+            Ok(()) => {
+                self.jobs
+                    .set(&name, 4, StepStatus::Done(String::new()))
+                    .await;
+                info!(user = name.as_str(), action = "offboard"; "Offboarded {name}");
+            }
+            Err(e) => self.jobs.fail(&name, 4, e).await,
+        }
+    }
+
+    /// Removes the keys of the user from every login it was authorized for. Failed removals
+    /// are queued and retried. Returns the number of failed logins.
+    async fn remove_keys(
+        &self,
+        keys: &[PublicUserKey],
+        authorizations: &[UserAndOptions],
+        reason: &str,
+        report: &mut String,
+    ) -> usize {
+        let keys: Vec<String> = keys.iter().map(|key| key.key_base64.clone()).collect();
+        let mut failed = 0;
+
+        for (_, host_name, login, _) in authorizations {
+            let res = match Host::get_from_name(&self.db, host_name.clone()).await {
+                Ok(Some(host)) => self
+                    .removals
+                    .remove(&host, login, &keys, reason)
+                    .await
+                    .map_err(|e| format!("{e}, retrying later")),
+                Ok(None) => Err(String::from("Host not found")),
+                Err(e) => Err(e),
+            };
+
+            match res {
+                Ok(()) => {
+                    let _ = writeln!(report, "Removed keys for {login} on {host_name}");
+                }
+                Err(e) => {
+                    failed += 1;
+                    let _ = writeln!(
+                        report,
+                        "FAILED to remove keys for {login} on {host_name}: {e}"
+                    );
+                }
+            }
+        }
+
+        failed
+    }
+}
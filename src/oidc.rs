@@ -0,0 +1,187 @@
+use openidconnect::{
+    core::{CoreAuthenticationFlow, CoreClient, CoreProviderMetadata},
+    reqwest, AuthorizationCode, ClientId, ClientSecret, CsrfToken, EndpointMaybeSet,
+    EndpointNotSet, EndpointSet, IssuerUrl, Nonce, PkceCodeChallenge, PkceCodeVerifier,
+    RedirectUrl, Scope, TokenResponse,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::OnceCell;
+
+use crate::secrets;
+
+/// Which ID token claim becomes the username
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsernameClaim {
+    #[default]
+    PreferredUsername,
+    Email,
+    Sub,
+}
+
+fn default_display_name() -> String {
+    String::from("Single sign-on")
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OidcConfig {
+    /// e.g. `https://accounts.example.com`
+    issuer_url: String,
+    client_id: String,
+    #[serde(deserialize_with = "secrets::deserialize_secret")]
+    client_secret: String,
+    /// Public URL of `/auth/oidc/callback`
+    redirect_url: String,
+    /// Label of the login button
+    #[serde(default = "default_display_name")]
+    display_name: String,
+    #[serde(default)]
+    username_claim: UsernameClaim,
+}
+
+type Client = CoreClient<
+    EndpointSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointMaybeSet,
+    EndpointMaybeSet,
+>;
+
+/// What has to be remembered between redirecting to the provider and the callback
+#[derive(Serialize, Deserialize)]
+pub struct PendingLogin {
+    csrf_token: String,
+    nonce: String,
+    pkce_verifier: String,
+}
+
+/// A verified identity
+pub struct OidcIdentity {
+    /// Issuer and subject, stable for the lifetime of the account
+    pub subject: String,
+    pub username: String,
+}
+
+/// OpenID Connect authorization code flow with PKCE
+pub struct OidcLogin {
+    config: OidcConfig,
+    http: reqwest::Client,
+    /// Discovered on first use, so an unreachable provider doesn't prevent startup
+    client: OnceCell<Client>,
+}
+
+impl OidcLogin {
+    pub fn new(config: OidcConfig) -> Result<Self, String> {
+        let http = reqwest::Client::builder()
+            // Following redirects opens the client up to SSRF
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|e| format!("Failed to create HTTP client: {e}"))?;
+
+        Ok(Self {
+            config,
+            http,
+            client: OnceCell::new(),
+        })
+    }
+
+    pub fn display_name(&self) -> &str {
+        &self.config.display_name
+    }
+
+    async fn client(&self) -> Result<&Client, String> {
+        self.client
+            .get_or_try_init(|| async {
+                let issuer = IssuerUrl::new(self.config.issuer_url.clone())
+                    .map_err(|e| format!("Invalid issuer URL: {e}"))?;
+                let redirect_url = RedirectUrl::new(self.config.redirect_url.clone())
+                    .map_err(|e| format!("Invalid redirect URL: {e}"))?;
+
+                let metadata = CoreProviderMetadata::discover_async(issuer, &self.http)
+                    .await
+                    .map_err(|e| format!("OpenID Connect discovery failed: {e}"))?;
+
+                Ok(CoreClient::from_provider_metadata(
+                    metadata,
+                    ClientId::new(self.config.client_id.clone()),
+                    Some(ClientSecret::new(self.config.client_secret.clone())),
+                )
+                .set_redirect_uri(redirect_url))
+            })
+            .await
+    }
+
+    /// Returns where to send the user to log in
+    pub async fn start(&self) -> Result<(String, PendingLogin), String> {
+        let client = self.client().await?;
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+
+        let (url, csrf_token, nonce) = client
+            .authorize_url(
+                CoreAuthenticationFlow::AuthorizationCode,
+                CsrfToken::new_random,
+                Nonce::new_random,
+            )
+            .add_scope(Scope::new(String::from("profile")))
+            .add_scope(Scope::new(String::from("email")))
+            .set_pkce_challenge(pkce_challenge)
+            .url();
+
+        Ok((
+            url.to_string(),
+            PendingLogin {
+                csrf_token: csrf_token.secret().clone(),
+                nonce: nonce.secret().clone(),
+                pkce_verifier: pkce_verifier.secret().clone(),
+            },
+        ))
+    }
+
+    /// Exchanges the code from the callback and verifies the ID token
+    pub async fn finish(
+        &self,
+        pending: PendingLogin,
+        state: &str,
+        code: String,
+    ) -> Result<OidcIdentity, String> {
+        if pending.csrf_token != state {
+            return Err(String::from("Invalid login state, please try again"));
+        }
+
+        let client = self.client().await?;
+        let token_response = client
+            .exchange_code(AuthorizationCode::new(code))
+            .map_err(|e| format!("The identity provider has no token endpoint: {e}"))?
+            .set_pkce_verifier(PkceCodeVerifier::new(pending.pkce_verifier))
+            .request_async(&self.http)
+            .await
+            .map_err(|e| format!("Failed to exchange the authorization code: {e}"))?;
+
+        let id_token = token_response
+            .id_token()
+            .ok_or_else(|| String::from("The identity provider didn't return an ID token"))?;
+        let claims = id_token
+            .claims(&client.id_token_verifier(), &Nonce::new(pending.nonce))
+            .map_err(|e| format!("Invalid ID token: {e}"))?;
+
+        let username = match self.config.username_claim {
+            UsernameClaim::PreferredUsername => {
+                claims.preferred_username().map(|name| name.to_string())
+            }
+            UsernameClaim::Email => claims.email().map(|email| email.to_string()),
+            UsernameClaim::Sub => Some(claims.subject().to_string()),
+        }
+        .ok_or_else(|| {
+            format!(
+                "The identity provider didn't return the {:?} claim",
+                self.config.username_claim
+            )
+        })?;
+
+        Ok(OidcIdentity {
+            subject: format!("{} {}", claims.issuer().as_str(), claims.subject().as_str()),
+            username,
+        })
+    }
+}
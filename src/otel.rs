@@ -0,0 +1,357 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Mutex, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    Error,
+};
+use log::error;
+use openidconnect::reqwest;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::time::MissedTickBehavior;
+
+/// Finished spans waiting for the export beyond this are dropped
+const MAX_QUEUED: usize = 10_000;
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn default_service_name() -> String {
+    String::from("ssm")
+}
+
+const fn default_export_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+/// Exporting spans to an OpenTelemetry collector over OTLP/HTTP
+#[derive(Debug, Clone, Deserialize)]
+pub struct OtelConfig {
+    /// Base URL of the collector, e.g. `http://localhost:4318`. Spans are sent to `/v1/traces`.
+    endpoint: String,
+    #[serde(default = "default_service_name")]
+    service_name: String,
+    /// Sent with every export, e.g. for authentication
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    /// Seconds between exports
+    #[serde(
+        default = "default_export_interval",
+        deserialize_with = "crate::deserialize_timeout"
+    )]
+    export_interval: Duration,
+}
+
+/// Identifies a span within its trace
+#[derive(Debug, Clone, Copy)]
+struct SpanContext {
+    trace_id: u128,
+    span_id: u64,
+}
+
+tokio::task_local! {
+    /// The span work of the current task belongs to
+    static CURRENT: SpanContext;
+}
+
+/// What a span represents, as OTLP numbers them
+#[derive(Debug, Clone, Copy)]
+pub enum SpanKind {
+    Internal = 1,
+    /// Handling a request
+    Server = 2,
+    /// A call to a host or the database
+    Client = 3,
+}
+
+#[derive(Debug)]
+struct SpanData {
+    context: SpanContext,
+    parent: Option<u64>,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    error: Option<String>,
+}
+
+struct Tracer {
+    config: OtelConfig,
+    finished: Mutex<Vec<(SpanData, SystemTime)>>,
+    http: reqwest::Client,
+}
+
+static TRACER: OnceLock<Tracer> = OnceLock::new();
+
+impl OtelConfig {
+    /// Makes spans record and exports them every `export_interval`
+    pub fn install(self) -> Result<(), String> {
+        if self.export_interval.is_zero() {
+            return Err(String::from("The export_interval of [otel] can't be 0"));
+        }
+        let endpoint = reqwest::Url::parse(&self.endpoint)
+            .map_err(|e| format!("Invalid OpenTelemetry endpoint '{}': {e}", self.endpoint))?;
+        let http = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|e| e.to_string())?;
+        let export_interval = self.export_interval;
+        TRACER
+            .set(Tracer {
+                config: self,
+                finished: Mutex::new(Vec::new()),
+                http,
+            })
+            .map_err(|_| String::from("OpenTelemetry was already installed"))?;
+
+        let url = endpoint
+            .join("v1/traces")
+            .map_err(|e| format!("Invalid OpenTelemetry endpoint: {e}"))?;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(export_interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                if let Some(tracer) = TRACER.get() {
+                    if let Err(e) = tracer.export(&url).await {
+                        error!("Failed to export spans: {e}");
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+impl Tracer {
+    fn finish(&self, span: SpanData) {
+        let mut finished = self.finished.lock().expect("Span queue poisoned");
+        if finished.len() < MAX_QUEUED {
+            finished.push((span, SystemTime::now()));
+        }
+    }
+
+    /// Sends the finished spans in the OTLP/JSON encoding
+    async fn export(&self, url: &reqwest::Url) -> Result<(), String> {
+        let spans = std::mem::take(&mut *self.finished.lock().expect("Span queue poisoned"));
+        if spans.is_empty() {
+            return Ok(());
+        }
+        let count = spans.len();
+        let spans: Vec<Value> = spans
+            .into_iter()
+            .map(|(span, end)| {
+                let attributes: Vec<Value> = span
+                    .attributes
+                    .into_iter()
+                    .map(|(key, value)| {
+                        let value = match value {
+                            Value::Bool(value) => json!({ "boolValue": value }),
+                            Value::Number(value) if value.is_i64() => {
+                                json!({ "intValue": value.to_string() })
+                            }
+                            Value::String(value) => json!({ "stringValue": value }),
+                            value => json!({ "stringValue": value.to_string() }),
+                        };
+                        json!({ "key": key, "value": value })
+                    })
+                    .collect();
+                let status = match span.error {
+                    Some(message) => json!({ "code": 2, "message": message }),
+                    None => json!({ "code": 0 }),
+                };
+                let mut encoded = json!({
+                    "traceId": format!("{:032x}", span.context.trace_id),
+                    "spanId": format!("{:016x}", span.context.span_id),
+                    "name": span.name,
+                    "kind": span.kind as u8,
+                    "startTimeUnixNano": unix_nanos(span.start),
+                    "endTimeUnixNano": unix_nanos(end),
+                    "attributes": attributes,
+                    "status": status,
+                });
+                if let Some(parent) = span.parent {
+                    encoded["parentSpanId"] = json!(format!("{parent:016x}"));
+                }
+                encoded
+            })
+            .collect();
+        let body = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{
+                        "key": "service.name",
+                        "value": { "stringValue": self.config.service_name },
+                    }],
+                },
+                "scopeSpans": [{ "scope": { "name": "ssm" }, "spans": spans }],
+            }],
+        });
+
+        let mut request = self
+            .http
+            .post(url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .timeout(EXPORT_TIMEOUT);
+        for (name, value) in &self.config.headers {
+            request = request.header(name, value);
+        }
+        let response = request
+            .body(body.to_string())
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(format!(
+                "The collector answered {status}, dropped {count} spans"
+            )),
+        }
+    }
+}
+
+/// A timed operation, exported when dropped. Spans started while another one is running
+/// in the same task become its children. Without OpenTelemetry configured, spans record
+/// nothing.
+pub struct Span {
+    data: Option<SpanData>,
+}
+
+impl Span {
+    pub fn start(name: impl Into<String>, kind: SpanKind) -> Self {
+        let parent = CURRENT.try_with(|current| *current).ok();
+        Self::start_with_parent(name, kind, parent)
+    }
+
+    fn start_with_parent(
+        name: impl Into<String>,
+        kind: SpanKind,
+        parent: Option<SpanContext>,
+    ) -> Self {
+        if TRACER.get().is_none() {
+            return Self { data: None };
+        }
+        let context = SpanContext {
+            trace_id: parent.map_or_else(|| rand::random::<u128>().max(1), |p| p.trace_id),
+            span_id: rand::random::<u64>().max(1),
+        };
+        Self {
+            data: Some(SpanData {
+                context,
+                parent: parent.map(|parent| parent.span_id),
+                name: name.into(),
+                kind,
+                start: SystemTime::now(),
+                attributes: Vec::new(),
+                error: None,
+            }),
+        }
+    }
+
+    pub fn with_attribute(mut self, key: &'static str, value: impl Into<Value>) -> Self {
+        self.set_attribute(key, value);
+        self
+    }
+
+    pub fn set_attribute(&mut self, key: &'static str, value: impl Into<Value>) {
+        if let Some(data) = &mut self.data {
+            data.attributes.push((key, value.into()));
+        }
+    }
+
+    pub fn set_error(&mut self, error: impl ToString) {
+        if let Some(data) = &mut self.data {
+            data.error = Some(error.to_string());
+        }
+    }
+
+    /// Runs `fut` as this span, which fails if it returns an error
+    pub async fn run<T, E, F>(mut self, fut: F) -> Result<T, E>
+    where
+        F: Future<Output = Result<T, E>>,
+        E: std::fmt::Display,
+    {
+        let res = match self.data.as_ref().map(|data| data.context) {
+            Some(context) => CURRENT.scope(context, fut).await,
+            None => fut.await,
+        };
+        if let Err(e) = &res {
+            self.set_error(e);
+        }
+        res
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let (Some(data), Some(tracer)) = (self.data.take(), TRACER.get()) {
+            tracer.finish(data);
+        }
+    }
+}
+
+/// The caller's span from a W3C `traceparent` header, e.g. of a proxy
+fn parse_traceparent(header: &str) -> Option<SpanContext> {
+    let mut parts = header.trim().split('-');
+    let (Some("00"), Some(trace_id), Some(span_id), Some(_flags)) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    let context = SpanContext {
+        trace_id: u128::from_str_radix(trace_id, 16).ok()?,
+        span_id: u64::from_str_radix(span_id, 16).ok()?,
+    };
+    (trace_id.len() == 32 && span_id.len() == 16 && context.trace_id != 0 && context.span_id != 0)
+        .then_some(context)
+}
+
+/// Middleware tracing every request as a server span, which the spans of its SSH and
+/// database operations belong to
+pub async fn trace_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    if TRACER.get().is_none() {
+        return next.call(req).await;
+    }
+    let parent = req
+        .headers()
+        .get("traceparent")
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_traceparent);
+    let mut span = Span::start_with_parent(
+        format!("{} {}", req.method(), req.path()),
+        SpanKind::Server,
+        parent,
+    )
+    .with_attribute("http.request.method", req.method().as_str())
+    .with_attribute("url.path", req.path());
+    let Some(context) = span.data.as_ref().map(|data| data.context) else {
+        return next.call(req).await;
+    };
+
+    let res = CURRENT.scope(context, next.call(req)).await;
+    match &res {
+        Ok(res) => {
+            span.set_attribute("http.response.status_code", res.status().as_u16());
+            if res.status().is_server_error() {
+                span.set_error(res.status());
+            }
+        }
+        Err(e) => span.set_error(e),
+    }
+    res
+}
@@ -0,0 +1,230 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    web::Data,
+    Error,
+};
+use diesel::{
+    connection::{Instrumentation, InstrumentationEvent},
+    r2d2::CustomizeConnection,
+};
+use log::warn;
+use time::OffsetDateTime;
+
+use crate::{metrics::Histogram, DbConnection};
+
+/// How many slow queries are kept for the performance page
+const SLOW_QUERY_HISTORY: usize = 100;
+
+/// Handler latency of one route since startup
+#[derive(Debug, Clone)]
+pub struct RouteTiming {
+    /// Method and route pattern, e.g. `GET /hosts/{name}`
+    pub route: String,
+    pub count: u64,
+    pub total: Duration,
+    pub max: Duration,
+    pub histogram: Histogram,
+}
+
+impl RouteTiming {
+    pub fn average(&self) -> Duration {
+        u32::try_from(self.count)
+            .ok()
+            .filter(|count| *count > 0)
+            .map_or(Duration::ZERO, |count| self.total / count)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SlowQuery {
+    pub at: OffsetDateTime,
+    pub duration: Duration,
+    pub sql: String,
+    /// Bind parameters with string values masked, if the connection exposes them
+    pub binds: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct PerfData {
+    routes: HashMap<String, RouteTiming>,
+    slow_queries: VecDeque<SlowQuery>,
+}
+
+/// Request timings and slow queries collected since startup
+#[derive(Debug, Clone)]
+pub struct PerfStats {
+    data: Arc<Mutex<PerfData>>,
+    slow_query_threshold: Duration,
+}
+
+impl PerfStats {
+    pub fn new(slow_query_threshold: Duration) -> Self {
+        Self {
+            data: Arc::default(),
+            slow_query_threshold,
+        }
+    }
+
+    pub const fn slow_query_threshold(&self) -> Duration {
+        self.slow_query_threshold
+    }
+
+    fn record_request(&self, route: String, elapsed: Duration) {
+        let mut data = self.data.lock().expect("perf stats poisoned");
+        let timing = data
+            .routes
+            .entry(route.clone())
+            .or_insert_with(|| RouteTiming {
+                route,
+                count: 0,
+                total: Duration::ZERO,
+                max: Duration::ZERO,
+                histogram: Histogram::default(),
+            });
+        timing.count += 1;
+        timing.total += elapsed;
+        timing.max = timing.max.max(elapsed);
+        timing.histogram.observe(elapsed);
+    }
+
+    fn record_slow_query(&self, query: SlowQuery) {
+        let mut data = self.data.lock().expect("perf stats poisoned");
+        if data.slow_queries.len() >= SLOW_QUERY_HISTORY {
+            data.slow_queries.pop_back();
+        }
+        data.slow_queries.push_front(query);
+    }
+
+    /// All routes, the ones with the most time spent first
+    pub fn routes(&self) -> Vec<RouteTiming> {
+        let data = self.data.lock().expect("perf stats poisoned");
+        let mut routes: Vec<_> = data.routes.values().cloned().collect();
+        routes.sort_by_key(|route| std::cmp::Reverse(route.total));
+        routes
+    }
+
+    /// The latest slow queries, newest first
+    pub fn slow_queries(&self) -> Vec<SlowQuery> {
+        let data = self.data.lock().expect("perf stats poisoned");
+        data.slow_queries.iter().cloned().collect()
+    }
+}
+
+/// Middleware recording how long each route takes to respond
+pub async fn record_timing(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let stats = req.app_data::<Data<PerfStats>>().cloned();
+    let started = Instant::now();
+
+    let res = next.call(req).await?;
+
+    if let Some(stats) = stats {
+        // Group by pattern, so `/hosts/a` and `/hosts/b` are one route
+        let pattern = match res.request().match_pattern() {
+            Some(pattern) if pattern.is_empty() => String::from("(static files)"),
+            Some(pattern) => pattern,
+            None => String::from("(unmatched)"),
+        };
+        stats.record_request(
+            format!("{} {pattern}", res.request().method()),
+            started.elapsed(),
+        );
+    }
+    Ok(res)
+}
+
+/// Replaces string values in the debug output of bind parameters, which
+/// might contain secrets, by their length
+fn sanitize_binds(binds: &str) -> String {
+    let mut sanitized = String::with_capacity(binds.len());
+    let mut chars = binds.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '"' {
+            sanitized.push(c);
+            continue;
+        }
+
+        let mut len = 0;
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                '\\' => {
+                    chars.next();
+                }
+                _ => {}
+            }
+            len += 1;
+        }
+        sanitized.push_str(&format!("<{len} chars>"));
+    }
+
+    sanitized
+}
+
+/// Logs queries taking longer than the threshold of the [`PerfStats`]
+struct SlowQueryLogger {
+    stats: PerfStats,
+    started: Option<Instant>,
+}
+
+impl Instrumentation for SlowQueryLogger {
+    fn on_connection_event(&mut self, event: InstrumentationEvent<'_>) {
+        match event {
+            InstrumentationEvent::StartQuery { .. } => self.started = Some(Instant::now()),
+            InstrumentationEvent::FinishQuery { query, error, .. } => {
+                let Some(started) = self.started.take() else {
+                    return;
+                };
+                let duration = started.elapsed();
+                if duration < self.stats.slow_query_threshold {
+                    return;
+                }
+
+                let query = query.to_string();
+                let (sql, binds) = query.split_once(" -- binds: ").unwrap_or((&query, "[]"));
+                let binds = (binds != "[]").then(|| sanitize_binds(binds));
+                warn!(
+                    "Slow query ({duration:?}): {sql} -- binds: {}",
+                    binds.as_deref().unwrap_or("[]")
+                );
+
+                self.stats.record_slow_query(SlowQuery {
+                    at: OffsetDateTime::now_utc(),
+                    duration,
+                    sql: sql.to_owned(),
+                    binds,
+                    error: error.map(ToString::to_string),
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Installs the [`SlowQueryLogger`] on every new pool connection
+#[derive(Debug)]
+pub struct QueryTiming(pub PerfStats);
+
+impl CustomizeConnection<DbConnection, diesel::r2d2::Error> for QueryTiming {
+    fn on_acquire(&self, conn: &mut DbConnection) -> Result<(), diesel::r2d2::Error> {
+        use diesel::Connection;
+
+        conn.set_instrumentation(SlowQueryLogger {
+            stats: self.0.clone(),
+            started: None,
+        });
+        Ok(())
+    }
+}
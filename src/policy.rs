@@ -0,0 +1,344 @@
+use std::{collections::BTreeMap, sync::OnceLock, time::Duration};
+
+use glob::Pattern;
+use log::{error, info, warn};
+use serde::Deserialize;
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    db::{now, BlockingPool},
+    models::{Finding, Host, PublicUserKey, User},
+    DbConnection,
+};
+
+/// An authorization that breaks a policy rule
+pub const POLICY_VIOLATION: &str = "policy_violation";
+
+const fn default_interval() -> Option<Duration> {
+    Some(Duration::from_secs(3600))
+}
+
+fn deserialize_optional_pattern<'de, D>(deserializer: D) -> Result<Option<Pattern>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|pat| {
+            Pattern::new(&pat)
+                .map_err(|e| serde::de::Error::custom(format!("Invalid host pattern '{pat}': {e}")))
+        })
+        .transpose()
+}
+
+/// What happens to authorizations breaking a rule
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Enforcement {
+    /// Refuse to add the authorization
+    #[default]
+    Block,
+    /// Add it anyway, but log it and raise a finding
+    Warn,
+}
+
+/// A constraint on who may be authorized where. A rule applies to the hosts matching both
+/// `hosts` and `host_group`, if set, and to the logins listed, or all of them.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PolicyRule {
+    /// Shown in errors and findings
+    name: String,
+    /// Glob matched against the host name, e.g. `prod-*`
+    #[serde(default, deserialize_with = "deserialize_optional_pattern")]
+    hosts: Option<Pattern>,
+    /// Only hosts in this host group, e.g. `pci`
+    #[serde(default)]
+    host_group: Option<String>,
+    /// Empty for every login
+    #[serde(default)]
+    logins: Vec<String>,
+    /// Nobody may be authorized
+    #[serde(default)]
+    forbid: bool,
+    /// Only users whose keys are all hardware-backed (`sk-` key types) may be authorized
+    #[serde(default)]
+    require_hardware_keys: bool,
+    /// Only users whose keys were all proven to be hardware-backed by their FIDO attestation
+    /// may be authorized
+    #[serde(default)]
+    require_attested_keys: bool,
+    #[serde(default)]
+    enforcement: Enforcement,
+}
+
+impl PolicyRule {
+    fn applies(&self, host: &Host, host_groups: &[String], login: &str) -> bool {
+        self.hosts
+            .as_ref()
+            .is_none_or(|pattern| pattern.matches(&host.name))
+            && self
+                .host_group
+                .as_ref()
+                .is_none_or(|group| host_groups.contains(group))
+            && (self.logins.is_empty() || self.logins.iter().any(|l| l == login))
+    }
+
+    /// Why an authorization of a user with these keys breaks the rule, if it does
+    fn check(&self, username: &str, login: &str, keys: &[PublicUserKey]) -> Option<String> {
+        if self.forbid {
+            return Some(format!("{username} may not be authorized as {login}"));
+        }
+        if self.require_hardware_keys {
+            let software = keys.iter().filter(|key| !key.is_hardware_backed()).count();
+            if software > 0 {
+                return Some(format!(
+                    "{username} has {software} key(s) that aren't hardware-backed"
+                ));
+            }
+        }
+        if self.require_attested_keys {
+            let unattested = keys.iter().filter(|key| !key.is_attested()).count();
+            if unattested > 0 {
+                return Some(format!(
+                    "{username} has {unattested} key(s) without a verified hardware attestation"
+                ));
+            }
+        }
+        None
+    }
+}
+
+/// Rules checked whenever users are authorized and by recurring compliance scans
+#[derive(Debug, Clone, Deserialize)]
+pub struct PolicyConfig {
+    /// Seconds between compliance scans (default 3600, 0 disables)
+    #[serde(
+        default = "default_interval",
+        deserialize_with = "crate::deserialize_interval"
+    )]
+    interval: Option<Duration>,
+    #[serde(default)]
+    rules: Vec<PolicyRule>,
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        Self {
+            interval: default_interval(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+static POLICIES: OnceLock<PolicyConfig> = OnceLock::new();
+
+impl PolicyConfig {
+    /// Checks the rules, then makes them available to every authorization
+    pub fn install(self) -> Result<(), String> {
+        for rule in &self.rules {
+            if !rule.forbid && !rule.require_hardware_keys && !rule.require_attested_keys {
+                return Err(format!(
+                    "The policy '{}' needs forbid, require_hardware_keys or require_attested_keys",
+                    rule.name
+                ));
+            }
+        }
+        POLICIES
+            .set(self)
+            .map_err(|_| String::from("The policies were already installed"))
+    }
+}
+
+fn rules() -> &'static [PolicyRule] {
+    POLICIES.get().map_or(&[], |config| config.rules.as_slice())
+}
+
+/// A rule broken by an authorization
+#[derive(Debug, Clone)]
+pub struct Violation {
+    pub rule: String,
+    pub enforcement: Enforcement,
+    /// Subject of the finding, e.g. `prod-root: alice as root on web-1`
+    pub subject: String,
+    pub details: String,
+}
+
+/// The rules broken by authorizing a user with these keys as `login` on the host
+fn evaluate(
+    host: &Host,
+    host_groups: &[String],
+    username: &str,
+    login: &str,
+    keys: &[PublicUserKey],
+) -> Vec<Violation> {
+    rules()
+        .iter()
+        .filter(|rule| rule.applies(host, host_groups, login))
+        .filter_map(|rule| {
+            let details = rule.check(username, login, keys)?;
+            Some(Violation {
+                rule: rule.name.clone(),
+                enforcement: rule.enforcement,
+                subject: format!("{}: {username} as {login} on {}", rule.name, host.name),
+                details,
+            })
+        })
+        .collect()
+}
+
+fn host_group_names(conn: &mut DbConnection, host: &Host) -> Result<Vec<String>, String> {
+    Ok(host
+        .get_groups(conn)?
+        .into_iter()
+        .map(|group| group.name)
+        .collect())
+}
+
+/// Checks an authorization before it is added. Fails on the first blocking violation,
+/// warning violations are logged and raised as findings.
+pub fn enforce(
+    conn: &mut DbConnection,
+    host: &Host,
+    user_id: i32,
+    login: &str,
+) -> Result<(), String> {
+    if rules().is_empty() {
+        return Ok(());
+    }
+    let host_groups = host_group_names(conn, host)?;
+    enforce_in_groups(conn, host, &host_groups, user_id, login)
+}
+
+/// Like `enforce`, for access the host gets by joining `new_group`
+pub fn enforce_joining(
+    conn: &mut DbConnection,
+    host: &Host,
+    new_group: &str,
+    user_id: i32,
+    login: &str,
+) -> Result<(), String> {
+    if rules().is_empty() {
+        return Ok(());
+    }
+    let mut host_groups = host_group_names(conn, host)?;
+    host_groups.push(new_group.to_owned());
+    enforce_in_groups(conn, host, &host_groups, user_id, login)
+}
+
+fn enforce_in_groups(
+    conn: &mut DbConnection,
+    host: &Host,
+    host_groups: &[String],
+    user_id: i32,
+    login: &str,
+) -> Result<(), String> {
+    let user = User::get_by_id(conn, user_id)?.ok_or_else(|| String::from("User not found"))?;
+    let keys: Vec<PublicUserKey> = user
+        .get_keys(conn)?
+        .into_iter()
+        .filter(|key| key.expires_at.is_none_or(|expires_at| expires_at > now()))
+        .collect();
+
+    let violations = evaluate(host, host_groups, &user.username, login, &keys);
+    if let Some(blocked) = violations
+        .iter()
+        .find(|violation| violation.enforcement == Enforcement::Block)
+    {
+        return Err(format!(
+            "Blocked by policy '{}': {}",
+            blocked.rule, blocked.details
+        ));
+    }
+    for violation in violations {
+        warn!(
+            host = host.name.as_str(), login = login, action = "policy";
+            "Policy '{}' violated on {}: {}",
+            violation.rule, host.name, violation.details
+        );
+        Finding::raise(
+            conn,
+            POLICY_VIOLATION,
+            &violation.subject,
+            &violation.details,
+        )?;
+    }
+    Ok(())
+}
+
+/// The violations of the access every host grants right now, directly and through teams
+pub fn scan(conn: &mut DbConnection) -> Result<Vec<Violation>, String> {
+    let mut violations = Vec::new();
+    if rules().is_empty() {
+        return Ok(violations);
+    }
+    for host in Host::get_all_hosts(conn)? {
+        let host_groups = host_group_names(conn, &host)?;
+        let mut access: BTreeMap<(String, String), Vec<PublicUserKey>> = BTreeMap::new();
+        for entry in host.get_authorized_keys(conn)? {
+            access
+                .entry((entry.username, entry.login))
+                .or_default()
+                .push(entry.key);
+        }
+        for ((username, login), keys) in access {
+            violations.extend(evaluate(&host, &host_groups, &username, &login, &keys));
+        }
+    }
+    Ok(violations)
+}
+
+/// Periodically scans the fleet for access breaking a policy and records it as findings
+#[derive(Clone)]
+pub struct PolicyScanner {
+    db: BlockingPool,
+}
+
+impl PolicyScanner {
+    pub const fn new(db: BlockingPool) -> Self {
+        Self { db }
+    }
+
+    /// Scans now and then every configured interval, unless disabled or without rules
+    pub fn start(&self) {
+        let Some(interval) = POLICIES.get().and_then(|config| config.interval) else {
+            return;
+        };
+        if rules().is_empty() {
+            return;
+        }
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                if let Err(e) = this.run().await {
+                    error!("Failed to scan for policy violations: {e}");
+                }
+            }
+        });
+    }
+
+    /// Scans every host. Returns the number of new findings.
+    pub async fn run(&self) -> Result<usize, String> {
+        let raised = self
+            .db
+            .run(|conn| {
+                let mut raised = 0;
+                for violation in scan(conn)? {
+                    let details = match violation.enforcement {
+                        Enforcement::Block => format!("{} (blocking rule)", violation.details),
+                        Enforcement::Warn => violation.details,
+                    };
+                    if Finding::raise(conn, POLICY_VIOLATION, &violation.subject, &details)? {
+                        raised += 1;
+                    }
+                }
+                Ok::<_, String>(raised)
+            })
+            .await??;
+        if raised > 0 {
+            info!("Found {raised} new policy violations");
+        }
+        Ok(raised)
+    }
+}
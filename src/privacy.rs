@@ -0,0 +1,71 @@
+use crate::models::User;
+
+/// Characters that continue a name, so `al` isn't found in `alice` or `al.smith@example.com`
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '-' | '_' | '.' | '@')
+}
+
+/// Replaces every mention of `name` in `text`, or None if there is none
+pub fn replace_mentions(text: &str, name: &str, replacement: &str) -> Option<String> {
+    if name.is_empty() {
+        return None;
+    }
+    let mut replaced = String::with_capacity(text.len());
+    let mut copied = 0;
+    for (start, _) in text.match_indices(name) {
+        let end = start + name.len();
+        let before = text[..start].chars().next_back();
+        let after = text[end..].chars().next();
+        if before.is_some_and(is_name_char) || after.is_some_and(is_name_char) {
+            continue;
+        }
+        replaced.push_str(&text[copied..start]);
+        replaced.push_str(replacement);
+        copied = end;
+    }
+    if copied == 0 {
+        return None;
+    }
+    replaced.push_str(&text[copied..]);
+    Some(replaced)
+}
+
+/// Whether `text` mentions `name`
+pub fn mentions(text: &str, name: &str) -> bool {
+    replace_mentions(text, name, "").is_some()
+}
+
+/// Replaces every mention of any of `names`, or None if there is none
+pub fn replace_all_mentions(text: &str, names: &[&str], replacement: &str) -> Option<String> {
+    let mut replaced = None;
+    for name in names {
+        let current = replaced.as_deref().unwrap_or(text);
+        if let Some(new) = replace_mentions(current, name, replacement) {
+            replaced = Some(new);
+        }
+    }
+    replaced
+}
+
+/// Stands in for a deleted user in the records that are kept. Random, as the ids of deleted
+/// users are given out again.
+pub fn pseudonym() -> String {
+    format!("deleted-user-{:08x}", rand::random::<u32>())
+}
+
+/// Added to the request extensions by handlers that erased a user. The audit log entry of
+/// the request then names the pseudonym instead.
+#[derive(Clone)]
+pub struct Erased {
+    pub names: Vec<String>,
+    pub pseudonym: String,
+}
+
+impl Erased {
+    pub fn new(user: &User, pseudonym: String) -> Self {
+        Self {
+            names: user.names().into_iter().map(str::to_owned).collect(),
+            pseudonym,
+        }
+    }
+}
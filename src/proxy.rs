@@ -0,0 +1,295 @@
+use std::{net::IpAddr, sync::OnceLock};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        header::{self, HeaderName, HeaderValue},
+        uri::{PathAndQuery, Uri},
+    },
+    middleware::Next,
+    Error, HttpMessage, HttpRequest,
+};
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer};
+
+use crate::tls::Https;
+
+/// Headers whose paths are prefixed with the base path
+const REDIRECT_HEADERS: [&str; 3] = ["location", "hx-redirect", "hx-location"];
+
+/// Running behind a reverse proxy, possibly under a sub-path
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ProxyConfig {
+    /// Path the proxy serves ssm under, e.g. `/sshkeys`. Empty if served at the root
+    #[serde(default)]
+    base_path: String,
+    /// Addresses or networks of the proxies whose `Forwarded`, `X-Forwarded-For` and
+    /// `X-Forwarded-Proto` headers are honored
+    #[serde(default, deserialize_with = "deserialize_networks")]
+    trusted_proxies: Vec<IpNet>,
+}
+
+/// Networks like `10.0.0.0/8`, or single addresses
+fn deserialize_networks<'de, D>(deserializer: D) -> Result<Vec<IpNet>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|network| {
+            network
+                .parse::<IpNet>()
+                .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| {
+                    serde::de::Error::custom(format!("'{network}' isn't an address or network"))
+                })
+        })
+        .collect()
+}
+
+static PROXY: OnceLock<ProxyConfig> = OnceLock::new();
+
+impl ProxyConfig {
+    /// Checks and normalizes the base path, then makes the settings available to
+    /// the middleware and the templates
+    pub fn install(mut self) -> Result<(), String> {
+        let base_path = self.base_path.trim().trim_end_matches('/');
+        if !base_path.is_empty() && !base_path.starts_with('/') {
+            return Err(format!(
+                "The base path '{base_path}' has to start with a '/'"
+            ));
+        }
+        if !base_path
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "/-._~".contains(c))
+        {
+            return Err(format!(
+                "The base path '{base_path}' may only contain letters, digits and '/-._~'"
+            ));
+        }
+        self.base_path = base_path.to_owned();
+        PROXY
+            .set(self)
+            .map_err(|_| String::from("The proxy settings were already installed"))
+    }
+
+    fn is_trusted(&self, addr: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|network| network.contains(&addr))
+    }
+}
+
+fn config() -> Option<&'static ProxyConfig> {
+    PROXY.get()
+}
+
+/// The path ssm is served under, empty or starting with a '/'
+pub fn base_path() -> &'static str {
+    config().map_or("", |config| config.base_path.as_str())
+}
+
+/// `path` as seen by the browser. Only absolute paths are prefixed.
+pub fn url(path: &str) -> String {
+    match path.starts_with('/') && !path.starts_with("//") {
+        true => format!("{}{path}", base_path()),
+        false => path.to_owned(),
+    }
+}
+
+/// `path` as absolute URL, for links sent elsewhere like mails
+pub fn absolute_url(req: &HttpRequest, path: &str) -> String {
+    let secure = req
+        .extensions()
+        .get::<Client>()
+        .is_some_and(|client| client.secure);
+    let scheme = match secure {
+        true => "https",
+        false => "http",
+    };
+    format!("{scheme}://{}{}", req.connection_info().host(), url(path))
+}
+
+/// The client of a request, as told by a trusted proxy, added to the request extensions
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub addr: Option<IpAddr>,
+    /// Whether the browser used https
+    pub secure: bool,
+}
+
+/// Address of the client, for logging
+pub fn client_addr(req: &HttpRequest) -> String {
+    req.extensions()
+        .get::<Client>()
+        .and_then(|client| client.addr)
+        .map_or_else(|| String::from("unknown"), |addr| addr.to_string())
+}
+
+fn forwarded_header(req: &ServiceRequest, name: &str) -> Option<String> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+}
+
+/// An address of the standard `Forwarded` header: `192.0.2.1`, `192.0.2.1:4711`,
+/// `"[2001:db8::1]:4711"`, or None for `unknown` and obfuscated identifiers
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    let addr = match node.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next()?,
+        None => node.split(':').next()?,
+    };
+    addr.parse::<IpAddr>().ok().map(|addr| addr.to_canonical())
+}
+
+/// The client address and protocol of each element of the `Forwarded` header, one element
+/// per proxy
+fn parse_forwarded(header: &str) -> Vec<(Option<IpAddr>, Option<String>)> {
+    header
+        .split(',')
+        .map(|element| {
+            let mut addr = None;
+            let mut proto = None;
+            for pair in element.split(';') {
+                let Some((name, value)) = pair.split_once('=') else {
+                    continue;
+                };
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "for" => addr = parse_node(value),
+                    "proto" => proto = Some(value.trim().trim_matches('"').to_owned()),
+                    _ => {}
+                }
+            }
+            (addr, proto)
+        })
+        .collect()
+}
+
+/// Works out the client from the headers of trusted proxies only. The client is the last
+/// address of `Forwarded`, or of `X-Forwarded-For` without it, that isn't a trusted proxy
+/// itself.
+fn client(req: &ServiceRequest, config: &ProxyConfig) -> Client {
+    // Listening on `::`, IPv4 clients connect from mapped addresses like `::ffff:10.0.0.1`
+    let peer = req.peer_addr().map(|addr| addr.ip().to_canonical());
+    let secure = req.app_config().secure() || req.conn_data::<Https>().is_some();
+    let Some(peer) = peer.filter(|peer| config.is_trusted(*peer)) else {
+        return Client { addr: peer, secure };
+    };
+    let is_https = |proto: &str| proto.trim().eq_ignore_ascii_case("https");
+
+    if let Some(forwarded) = forwarded_header(req, "forwarded") {
+        let elements = parse_forwarded(&forwarded);
+        let client = elements.iter().rev().find_map(|(addr, proto)| {
+            addr.filter(|addr| !config.is_trusted(*addr))
+                .map(|addr| (addr, proto))
+        });
+        return match client {
+            Some((addr, proto)) => Client {
+                addr: Some(addr),
+                secure: proto.as_deref().map_or(secure, is_https),
+            },
+            None => Client {
+                addr: Some(peer),
+                secure,
+            },
+        };
+    }
+
+    let addr = forwarded_header(req, "x-forwarded-for")
+        .and_then(|forwarded| {
+            forwarded
+                .rsplit(',')
+                .filter_map(|addr| addr.trim().parse::<IpAddr>().ok())
+                .map(|addr| addr.to_canonical())
+                .find(|addr| !config.is_trusted(*addr))
+        })
+        .unwrap_or(peer);
+    let secure = match forwarded_header(req, "x-forwarded-proto") {
+        Some(proto) => proto.split(',').next().is_some_and(is_https),
+        None => secure,
+    };
+    Client {
+        addr: Some(addr),
+        secure,
+    }
+}
+
+/// Requests for the base path itself reach the routes without it, so ssm also works
+/// behind proxies that don't strip it
+fn strip_base_path(req: &mut ServiceRequest, base_path: &str) {
+    let uri = req.uri();
+    let Some(path) = uri.path().strip_prefix(base_path) else {
+        return;
+    };
+    if !path.is_empty() && !path.starts_with('/') {
+        return;
+    }
+    let path = match path.is_empty() {
+        true => "/",
+        false => path,
+    };
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_owned(),
+    };
+
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = PathAndQuery::from_maybe_shared(path_and_query).ok();
+    if let Ok(uri) = Uri::from_parts(parts) {
+        req.match_info_mut().get_mut().update(&uri);
+        req.head_mut().uri = uri;
+    }
+}
+
+/// Middleware applying the proxy settings: it removes the base path from requests and adds
+/// it to redirects, and marks cookies as secure if the browser used https
+pub async fn apply(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let default = ProxyConfig::default();
+    let config = config().unwrap_or(&default);
+    let client = client(&req, config);
+    let secure = client.secure;
+    req.extensions_mut().insert(client);
+    if !config.base_path.is_empty() {
+        strip_base_path(&mut req, &config.base_path);
+    }
+
+    let mut res = next.call(req).await?;
+
+    let headers = res.headers_mut();
+    if !config.base_path.is_empty() {
+        for name in REDIRECT_HEADERS {
+            let name = HeaderName::from_static(name);
+            let prefixed = headers
+                .get(&name)
+                .and_then(|value| value.to_str().ok())
+                .filter(|path| path.starts_with('/') && !path.starts_with("//"))
+                .and_then(|path| HeaderValue::from_str(&url(path)).ok());
+            if let Some(prefixed) = prefixed {
+                headers.insert(name, prefixed);
+            }
+        }
+    }
+    if secure {
+        let cookies: Vec<HeaderValue> = headers
+            .get_all(header::SET_COOKIE)
+            .map(|cookie| match cookie.to_str() {
+                Ok(value) if !value.to_ascii_lowercase().contains("; secure") => {
+                    HeaderValue::from_str(&format!("{value}; Secure"))
+                        .unwrap_or_else(|_| cookie.clone())
+                }
+                _ => cookie.clone(),
+            })
+            .collect();
+        headers.remove(header::SET_COOKIE);
+        for cookie in cookies {
+            headers.append(header::SET_COOKIE, cookie);
+        }
+    }
+    Ok(res)
+}
@@ -0,0 +1,296 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use log::{error, info, warn};
+use serde::Serialize;
+use tokio::time::MissedTickBehavior;
+use utoipa::ToSchema;
+
+use crate::{
+    db::BlockingPool,
+    health::HealthMonitor,
+    models::{Host, PendingRemoval, PublicUserKey, User},
+    shutdown::{self, Operation},
+    ssh::{CachingSshClient, SshClient},
+};
+
+/// Outcome of removing a key from a login
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RemovalResult {
+    pub host: String,
+    pub login: String,
+    /// Why the removal failed, it is retried then
+    pub error: Option<String>,
+}
+
+/// Removes keys from hosts, and keeps retrying removals that failed until they
+/// succeed or are cancelled
+#[derive(Clone)]
+pub struct RemovalQueue {
+    db: BlockingPool,
+    ssh_client: SshClient,
+    caching_ssh_client: Arc<CachingSshClient>,
+    /// Slows down retries while the system is overloaded
+    health: Option<HealthMonitor>,
+}
+
+impl RemovalQueue {
+    pub fn new(
+        db: BlockingPool,
+        ssh_client: SshClient,
+        caching_ssh_client: Arc<CachingSshClient>,
+    ) -> Self {
+        Self {
+            db,
+            ssh_client,
+            caching_ssh_client,
+            health: None,
+        }
+    }
+
+    pub fn with_health(mut self, health: HealthMonitor) -> Self {
+        self.health = Some(health);
+        self
+    }
+
+    /// Removes `keys` (base64) from a login, queueing the removal if it fails.
+    /// Returns why it failed.
+    pub async fn remove(
+        &self,
+        host: &Host,
+        login: &str,
+        keys: &[String],
+        reason: &str,
+    ) -> Result<(), String> {
+        let error = match self
+            .ssh_client
+            .remove_keys(host.clone(), login.to_owned(), keys)
+            .await
+        {
+            Ok(()) => {
+                self.caching_ssh_client.remove(&host.name).await;
+                return Ok(());
+            }
+            Err(e) => e.to_string(),
+        };
+
+        warn!(
+            host = host.name.as_str(), login = login, action = "remove_keys";
+            "Queued the removal of {} key(s) from {login} on {}: {error}",
+            keys.len(),
+            host.name
+        );
+        let (host_id, login, keys, reason, queued_error) = (
+            host.id,
+            login.to_owned(),
+            keys.to_vec(),
+            reason.to_owned(),
+            error.clone(),
+        );
+        if let Err(e) = self
+            .db
+            .run(move |conn| {
+                PendingRemoval::queue(conn, host_id, &login, &keys, &reason, &queued_error)
+            })
+            .await
+            .map_err(String::from)
+            .and_then(|res| res)
+        {
+            error!("Failed to queue a key removal: {e}");
+        }
+        Err(error)
+    }
+
+    /// Deletes a key and removes it from every login that it is authorized for or was found
+    /// on when the hosts were last read. Failed removals are queued.
+    pub async fn revoke_everywhere(&self, key_id: i32) -> Result<Vec<RemovalResult>, String> {
+        let (key, username, mut targets) = self
+            .db
+            .run(move |conn| {
+                let key = PublicUserKey::get_from_id(conn, key_id)?
+                    .ok_or_else(|| String::from("Key not found"))?;
+                let username = User::get_by_id(conn, key.user_id)?
+                    .map_or_else(|| String::from("unknown"), |user| user.username);
+                let mut targets = Vec::new();
+                for host in Host::get_all_hosts(conn)? {
+                    let mut logins: Vec<String> = host
+                        .get_authorized_keys(conn)?
+                        .into_iter()
+                        .filter(|entry| entry.key.key_base64 == key.key_base64)
+                        .map(|entry| entry.login)
+                        .collect();
+                    logins.dedup();
+                    targets.extend(logins.into_iter().map(|login| (host.clone(), login)));
+                }
+                PublicUserKey::delete_key(conn, key.id)?;
+                Ok::<_, String>((key, username, targets))
+            })
+            .await??;
+
+        let found: Vec<(String, String)> = self
+            .caching_ssh_client
+            .find_key(&key.key_base64)
+            .await
+            .into_iter()
+            .filter(|(host_name, login)| {
+                !targets
+                    .iter()
+                    .any(|(host, target)| &host.name == host_name && target == login)
+            })
+            .collect();
+        let names: Vec<String> = found.iter().map(|(host, _)| host.clone()).collect();
+        let hosts = self
+            .db
+            .run(move |conn| Host::get_all_named(conn, &names))
+            .await??;
+        for (host_name, login) in found {
+            if let Some(host) = hosts.iter().find(|host| host.name == host_name) {
+                targets.push((host.clone(), login));
+            }
+        }
+
+        let reason = format!(
+            "revocation of the key {} of {username}",
+            key.comment.as_deref().unwrap_or(&key.key_type)
+        );
+        let keys = [key.key_base64];
+        let mut results = Vec::with_capacity(targets.len());
+        for (host, login) in targets {
+            let error = self.remove(&host, &login, &keys, &reason).await.err();
+            results.push(RemovalResult {
+                host: host.name,
+                login,
+                error,
+            });
+        }
+        info!("Revoked {reason} on {} login(s)", results.len());
+        Ok(results)
+    }
+
+    /// Retries all queued removals now and then every `interval`
+    pub fn start(&self, interval: Duration) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                if let Err(e) = this.retry_all().await {
+                    error!("Failed to retry key removals: {e}");
+                }
+            }
+        });
+    }
+
+    /// Retries every queued removal. Returns the number of removed keys and of keys still queued.
+    pub async fn retry_all(&self) -> Result<(usize, usize), String> {
+        let pending = self.db.run(PendingRemoval::get_all).await??;
+        let mut by_host: BTreeMap<i32, Vec<PendingRemoval>> = BTreeMap::new();
+        for removal in pending {
+            by_host.entry(removal.host_id).or_default().push(removal);
+        }
+
+        let _operation = Operation::begin("key removal retries");
+        let (mut removed, mut queued) = (0, 0);
+        for (host_id, removals) in by_host {
+            // The rest stays queued for the next start
+            if shutdown::is_stopping() {
+                break;
+            }
+            if let Some(health) = &self.health {
+                health.throttle().await;
+            }
+            let (done, left) = self.retry_host(host_id, removals).await?;
+            removed += done;
+            queued += left;
+        }
+        if removed > 0 {
+            info!("Removed {removed} queued key(s), {queued} still queued");
+        }
+        Ok((removed, queued))
+    }
+
+    /// Retries the queued removals of one host now
+    pub async fn retry(&self, host: Host) -> Result<(usize, usize), String> {
+        let host_id = host.id;
+        let removals = self
+            .db
+            .run(move |conn| PendingRemoval::get_for_host(conn, &host))
+            .await??;
+        self.retry_host(host_id, removals).await
+    }
+
+    async fn retry_host(
+        &self,
+        host_id: i32,
+        removals: Vec<PendingRemoval>,
+    ) -> Result<(usize, usize), String> {
+        let res = self
+            .db
+            .run(move |conn| {
+                let Some(host) = Host::get_from_id_sync(conn, host_id)? else {
+                    return Ok(None);
+                };
+                // Keys authorized again since are kept
+                let authorized = host.get_authorized_keys(conn)?;
+                let (wanted, outdated): (Vec<_>, Vec<_>) =
+                    removals.into_iter().partition(|removal| {
+                        !authorized.iter().any(|entry| {
+                            entry.login == removal.login
+                                && entry.key.key_base64 == removal.key_base64
+                        })
+                    });
+                let outdated: Vec<i32> = outdated.iter().map(|removal| removal.id).collect();
+                PendingRemoval::delete(conn, &outdated)?;
+                Ok::<_, String>(Some((host, wanted)))
+            })
+            .await??;
+        let Some((host, removals)) = res else {
+            return Ok((0, 0));
+        };
+
+        let mut by_login: BTreeMap<String, Vec<PendingRemoval>> = BTreeMap::new();
+        for removal in removals {
+            by_login
+                .entry(removal.login.clone())
+                .or_default()
+                .push(removal);
+        }
+
+        let (mut removed, mut queued) = (0, 0);
+        for (login, removals) in by_login {
+            let keys: Vec<String> = removals
+                .iter()
+                .map(|removal| removal.key_base64.clone())
+                .collect();
+            let ids: Vec<i32> = removals.iter().map(|removal| removal.id).collect();
+            let res = self
+                .ssh_client
+                .remove_keys(host.clone(), login.clone(), &keys)
+                .await;
+            let update = match res {
+                Ok(()) => {
+                    removed += ids.len();
+                    self.caching_ssh_client.remove(&host.name).await;
+                    self.db
+                        .run(move |conn| PendingRemoval::delete(conn, &ids))
+                        .await?
+                }
+                Err(e) => {
+                    queued += ids.len();
+                    let error = e.to_string();
+                    self.db
+                        .run(move |conn| PendingRemoval::record_failure(conn, &ids, &error))
+                        .await?
+                }
+            };
+            if let Err(e) = update {
+                error!(
+                    host = host.name.as_str(), login = login.as_str(), action = "remove_keys";
+                    "Failed to update the queued removals for {login} on {}: {e}",
+                    host.name
+                );
+            }
+        }
+        Ok((removed, queued))
+    }
+}
@@ -0,0 +1,253 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use futures::{stream, StreamExt};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::{self, BlockingPool},
+    models::{Host, IntegrityReport, NewIntegrityReport},
+    ssh::{describe, CachingSshClient, Severity},
+};
+
+/// How many hosts are checked at the same time
+const REPORT_CONCURRENCY: usize = 8;
+
+/// The state of one host at the time of a report
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostReport {
+    pub host: String,
+    /// Why the host couldn't be checked
+    pub error: Option<String>,
+    /// Number of drift items by severity
+    pub critical: usize,
+    pub warning: usize,
+    pub info: usize,
+    /// Logins with keys that ssm doesn't manage
+    pub unmanaged_accounts: Vec<String>,
+    /// Critical drift, i.e. someone has access who shouldn't, as "login: description"
+    pub violations: Vec<String>,
+}
+
+impl HostReport {
+    pub fn is_reachable(&self) -> bool {
+        self.error.is_none()
+    }
+
+    pub fn has_drift(&self) -> bool {
+        self.critical + self.warning + self.info > 0
+    }
+}
+
+impl IntegrityReport {
+    pub fn host_reports(&self) -> Result<Vec<HostReport>, String> {
+        serde_json::from_str(&self.hosts).map_err(|e| format!("Invalid report: {e}"))
+    }
+}
+
+/// Host names and what changed on them
+pub type HostChanges = Vec<(String, Vec<String>)>;
+
+/// What changed on the hosts between two reports
+pub fn changes(previous: &[HostReport], current: &[HostReport]) -> HostChanges {
+    let mut changes = Vec::new();
+    for host in current {
+        let host_changes = match previous.iter().find(|before| before.host == host.host) {
+            Some(before) => host_changes(before, host),
+            None => vec![String::from("New host")],
+        };
+        if !host_changes.is_empty() {
+            changes.push((host.host.clone(), host_changes));
+        }
+    }
+
+    for before in previous {
+        if !current.iter().any(|host| host.host == before.host) {
+            changes.push((before.host.clone(), vec![String::from("Host removed")]));
+        }
+    }
+    changes
+}
+
+fn host_changes(before: &HostReport, now: &HostReport) -> Vec<String> {
+    let mut changes = Vec::new();
+    match (&before.error, &now.error) {
+        (None, Some(error)) => changes.push(format!("Became unreachable: {error}")),
+        (Some(_), None) => changes.push(String::from("Reachable again")),
+        _ => {}
+    }
+    // Nothing is known about the drift of unreachable hosts
+    if !before.is_reachable() || !now.is_reachable() {
+        return changes;
+    }
+
+    for (severity, before, now) in [
+        ("critical", before.critical, now.critical),
+        ("warning", before.warning, now.warning),
+        ("info", before.info, now.info),
+    ] {
+        if before != now {
+            changes.push(format!("Drift ({severity}): {before} → {now}"));
+        }
+    }
+    added_and_removed(
+        &mut changes,
+        "unmanaged account",
+        &before.unmanaged_accounts,
+        &now.unmanaged_accounts,
+    );
+    added_and_removed(
+        &mut changes,
+        "violation",
+        &before.violations,
+        &now.violations,
+    );
+    changes
+}
+
+fn added_and_removed(changes: &mut Vec<String>, what: &str, before: &[String], now: &[String]) {
+    for item in now.iter().filter(|item| !before.contains(item)) {
+        changes.push(format!("New {what}: {item}"));
+    }
+    for item in before.iter().filter(|item| !now.contains(item)) {
+        changes.push(format!("Resolved {what}: {item}"));
+    }
+}
+
+fn summary(hosts: &[HostReport]) -> String {
+    let unreachable = hosts.iter().filter(|host| !host.is_reachable()).count();
+    let drifted = hosts.iter().filter(|host| host.has_drift()).count();
+    let violating = hosts
+        .iter()
+        .filter(|host| !host.violations.is_empty())
+        .count();
+    format!(
+        "{} hosts, {unreachable} unreachable, {drifted} with drift, {violating} with violations",
+        hosts.len()
+    )
+}
+
+/// Generates integrity reports of all hosts and stores them
+#[derive(Clone)]
+pub struct IntegrityReporter {
+    db: BlockingPool,
+    caching_ssh_client: Arc<CachingSshClient>,
+    running: Arc<AtomicBool>,
+}
+
+impl IntegrityReporter {
+    pub fn new(db: BlockingPool, caching_ssh_client: Arc<CachingSshClient>) -> Self {
+        Self {
+            db,
+            caching_ssh_client,
+            running: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Generates a report in the background, unless one is being generated
+    pub async fn start(&self) -> Result<(), String> {
+        if self.running.swap(true, Ordering::AcqRel) {
+            return Err(String::from("A report is already being generated"));
+        }
+        let hosts = match self
+            .db
+            .run(Host::get_all_hosts)
+            .await
+            .map_err(String::from)
+            .and_then(|res| res)
+        {
+            Ok(hosts) => hosts,
+            Err(e) => {
+                self.running.store(false, Ordering::Release);
+                return Err(e);
+            }
+        };
+
+        // Also started by the scheduler, outside of the actix runtime
+        let this = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = this.generate(hosts).await {
+                error!("Failed to generate integrity report: {e}");
+            }
+            this.running.store(false, Ordering::Release);
+        });
+        Ok(())
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Acquire)
+    }
+
+    async fn generate(&self, hosts: Vec<Host>) -> Result<(), String> {
+        info!("Generating integrity report of {} hosts", hosts.len());
+        let reports: Vec<HostReport> = stream::iter(hosts)
+            .map(|host| self.check_host(host))
+            .buffered(REPORT_CONCURRENCY)
+            .collect()
+            .await;
+
+        let summary = summary(&reports);
+        info!("Generated integrity report: {summary}");
+        let report = NewIntegrityReport {
+            created_at: db::now(),
+            summary,
+            hosts: serde_json::to_string(&reports).map_err(|e| e.to_string())?,
+        };
+        self.db
+            .run(move |conn| IntegrityReport::add(conn, report))
+            .await?
+    }
+
+    async fn check_host(&self, host: Host) -> HostReport {
+        let mut report = HostReport {
+            host: host.name.clone(),
+            error: None,
+            critical: 0,
+            warning: 0,
+            info: 0,
+            unmanaged_accounts: Vec::new(),
+            violations: Vec::new(),
+        };
+
+        let logins = match self
+            .caching_ssh_client
+            .get_host_diff(host.clone(), true)
+            .await
+            .1
+        {
+            Ok(logins) => logins,
+            Err(e) => {
+                report.error = Some(e.to_string());
+                return report;
+            }
+        };
+        for (login, items) in logins {
+            for item in items {
+                match item.severity() {
+                    Severity::Critical => {
+                        report.critical += 1;
+                        report
+                            .violations
+                            .push(format!("{login}: {}", describe(&item)));
+                    }
+                    Severity::Warning => report.warning += 1,
+                    Severity::Info => report.info += 1,
+                }
+            }
+        }
+
+        // Uses what was just loaded for the diff
+        match self
+            .caching_ssh_client
+            .get_unmanaged_logins(host, false)
+            .await
+        {
+            Ok(logins) => report.unmanaged_accounts = logins,
+            Err(e) => report.error = Some(e.to_string()),
+        }
+        report
+    }
+}
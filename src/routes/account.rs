@@ -0,0 +1,321 @@
+use actix_identity::Identity;
+use actix_session::Session;
+use actix_web::{
+    get, post,
+    web::{self, Data},
+    HttpMessage, HttpRequest, Responder,
+};
+use askama_actix::{Template, TemplateToResponse};
+use log::info;
+use serde::Deserialize;
+
+use crate::{
+    auth::{check_new_password, AuthBackend},
+    db::BlockingPool,
+    forms::{FormResponseBuilder, Modal},
+    models::{WebSession, WebUser},
+    routes::{ErrorTemplate, RenderErrorTemplate},
+    totp,
+};
+
+pub fn account_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(account_page)
+        .service(render_two_factor)
+        .service(start_totp)
+        .service(confirm_totp)
+        .service(disable_totp)
+        .service(new_recovery_codes)
+        .service(change_password);
+}
+
+/// The secret of an enrollment, until the user entered a code from it
+const ENROLLMENT_SESSION_KEY: &str = "totp_enrollment";
+
+#[derive(Template)]
+#[template(path = "account/index.html")]
+struct AccountTemplate {
+    username: String,
+    /// Whether the password can be changed here, not for single sign-on or LDAP
+    can_change_password: bool,
+}
+
+/// Settings of the logged in web user
+#[get("")]
+async fn account_page(
+    db: Data<BlockingPool>,
+    identity: Identity,
+    auth_backend: Data<dyn AuthBackend>,
+) -> actix_web::Result<impl Responder> {
+    let username = identity.id().unwrap_or_default();
+    let name = username.clone();
+    Ok(
+        match db
+            .run(move |conn| WebUser::get_by_username(conn, &name))
+            .await?
+        {
+            Ok(web_user) => AccountTemplate {
+                username,
+                can_change_password: auth_backend.can_set_password()
+                    && web_user.is_some_and(|web_user| web_user.oidc_subject.is_none()),
+            }
+            .to_response(),
+            Err(error) => ErrorTemplate { error }.to_response(),
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct ChangePasswordForm {
+    current_password: String,
+    new_password: String,
+    repeated_password: String,
+}
+
+/// Changes the password and ends the other sessions of the user
+#[post("/password")]
+async fn change_password(
+    req: HttpRequest,
+    db: Data<BlockingPool>,
+    identity: Identity,
+    auth_backend: Data<dyn AuthBackend>,
+    form: web::Form<ChangePasswordForm>,
+) -> actix_web::Result<impl Responder> {
+    let username = identity.id().unwrap_or_default();
+    let form = form.into_inner();
+    if let Err(e) = check_new_password(&form.new_password, &form.repeated_password) {
+        return Ok(FormResponseBuilder::error(e));
+    }
+    match auth_backend
+        .authenticate(&username, &form.current_password)
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => {
+            return Ok(FormResponseBuilder::error(String::from(
+                "The current password is wrong",
+            )))
+        }
+        Err(e) => return Ok(FormResponseBuilder::error(e)),
+    }
+    if let Err(e) = auth_backend
+        .set_password(&username, &form.new_password)
+        .await
+    {
+        return Ok(FormResponseBuilder::error(e));
+    }
+
+    let name = username.clone();
+    if let Err(e) = db
+        .run(move |conn| WebSession::delete_for_user(conn, &name))
+        .await?
+    {
+        return Ok(FormResponseBuilder::error(e));
+    }
+    // This browser gets a new session, which the others don't know
+    Identity::login(&req.extensions(), username)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(FormResponseBuilder::success(String::from(
+        "Changed your password, other browsers were logged out",
+    )))
+}
+
+#[derive(Template)]
+#[template(path = "account/two_factor.htm")]
+struct RenderTwoFactorTemplate {
+    /// Users signing in via single sign-on get their second factor from the identity provider
+    single_sign_on: bool,
+    enabled: bool,
+    recovery_codes_left: i64,
+}
+
+#[get("/two_factor.htm")]
+async fn render_two_factor(
+    db: Data<BlockingPool>,
+    identity: Identity,
+) -> actix_web::Result<impl Responder> {
+    let username = identity.id().unwrap_or_default();
+    let res = db
+        .run(move |conn| {
+            let web_user = WebUser::get_by_username(conn, &username)?
+                .ok_or_else(|| String::from("Unknown web user"))?;
+            let recovery_codes_left = web_user.recovery_codes_left(conn)?;
+            Ok::<_, String>(RenderTwoFactorTemplate {
+                single_sign_on: web_user.oidc_subject.is_some(),
+                enabled: web_user.has_totp(),
+                recovery_codes_left,
+            })
+        })
+        .await?;
+    Ok(match res {
+        Ok(template) => template.to_response(),
+        Err(error) => RenderErrorTemplate { error }.to_response(),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "account/enroll_dialog.htm")]
+struct EnrollDialog {
+    /// The QR code as SVG
+    qr_code: Option<String>,
+    secret: String,
+}
+
+#[derive(Template)]
+#[template(path = "account/recovery_codes_dialog.htm")]
+struct RecoveryCodesDialog {
+    codes: Vec<String>,
+}
+
+#[post("/totp/start")]
+async fn start_totp(
+    db: Data<BlockingPool>,
+    identity: Identity,
+    session: Session,
+) -> actix_web::Result<impl Responder> {
+    let username = identity.id().unwrap_or_default();
+    let name = username.clone();
+    let web_user = match db
+        .run(move |conn| WebUser::get_by_username(conn, &name))
+        .await?
+    {
+        Ok(Some(web_user)) => web_user,
+        Ok(None) => return Ok(FormResponseBuilder::error(String::from("Unknown web user"))),
+        Err(e) => return Ok(FormResponseBuilder::error(e)),
+    };
+    if web_user.oidc_subject.is_some() {
+        return Ok(FormResponseBuilder::error(String::from(
+            "You sign in via single sign-on, set up two-factor authentication at your identity provider",
+        )));
+    }
+    if web_user.has_totp() {
+        return Ok(FormResponseBuilder::error(String::from(
+            "Two-factor authentication is already enabled",
+        )));
+    }
+
+    let secret = totp::new_secret();
+    session
+        .insert(ENROLLMENT_SESSION_KEY, &secret)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(FormResponseBuilder::dialog(Modal {
+        title: String::from("Set up two-factor authentication"),
+        request_target: String::from("/account/totp/confirm"),
+        template: EnrollDialog {
+            qr_code: totp::qr_code(&totp::provisioning_uri(&secret, &username)),
+            secret,
+        }
+        .to_string(),
+    }))
+}
+
+#[derive(Deserialize)]
+struct CodeForm {
+    code: String,
+}
+
+#[post("/totp/confirm")]
+async fn confirm_totp(
+    db: Data<BlockingPool>,
+    identity: Identity,
+    session: Session,
+    form: web::Form<CodeForm>,
+) -> actix_web::Result<impl Responder> {
+    let Ok(Some(secret)) = session.get::<String>(ENROLLMENT_SESSION_KEY) else {
+        return Ok(FormResponseBuilder::error(String::from(
+            "No setup in progress, please start again",
+        )));
+    };
+    let Some(step) = totp::verify(&secret, &form.code, None) else {
+        return Ok(FormResponseBuilder::error(String::from(
+            "Invalid code, please check the time of your device",
+        )));
+    };
+
+    let username = identity.id().unwrap_or_default();
+    let name = username.clone();
+    let res = db
+        .run(move |conn| {
+            let web_user = WebUser::get_by_username(conn, &name)?
+                .ok_or_else(|| String::from("Unknown web user"))?;
+            web_user.enable_totp(conn, &secret, step)
+        })
+        .await?;
+    Ok(match res {
+        Ok(codes) => {
+            session.remove(ENROLLMENT_SESSION_KEY);
+            info!("{username} enabled two-factor authentication");
+            FormResponseBuilder::dialog(Modal {
+                title: String::from("Two-factor authentication is enabled"),
+                request_target: String::new(),
+                template: RecoveryCodesDialog { codes }.to_string(),
+            })
+            .add_trigger(String::from("reload-two-factor"))
+        }
+        Err(e) => FormResponseBuilder::error(e),
+    })
+}
+
+/// Checks a current TOTP or recovery code of the user, so a left open session alone can't
+/// change the second factor
+fn check_code(
+    conn: &mut crate::DbConnection,
+    username: &str,
+    code: &str,
+) -> Result<WebUser, String> {
+    let web_user = WebUser::get_by_username(conn, username)?
+        .ok_or_else(|| String::from("Unknown web user"))?;
+    let Some(secret) = &web_user.totp_secret else {
+        return Err(String::from("Two-factor authentication is not enabled"));
+    };
+    match totp::verify(secret, code, web_user.totp_last_step) {
+        Some(step) => web_user.use_totp_step(conn, step)?,
+        None if web_user.use_recovery_code(conn, code)? => {}
+        None => return Err(String::from("Invalid code")),
+    }
+    Ok(web_user)
+}
+
+#[post("/totp/disable")]
+async fn disable_totp(
+    db: Data<BlockingPool>,
+    identity: Identity,
+    form: web::Form<CodeForm>,
+) -> actix_web::Result<impl Responder> {
+    let username = identity.id().unwrap_or_default();
+    let name = username.clone();
+    let res = db
+        .run(move |conn| {
+            let web_user = check_code(conn, &name, &form.code)?;
+            WebUser::disable_totp(conn, web_user.id)
+        })
+        .await?;
+    Ok(match res {
+        Ok(()) => {
+            info!("{username} disabled two-factor authentication");
+            FormResponseBuilder::success(String::from("Disabled two-factor authentication"))
+                .add_trigger(String::from("reload-two-factor"))
+        }
+        Err(e) => FormResponseBuilder::error(e),
+    })
+}
+
+#[post("/totp/recovery_codes")]
+async fn new_recovery_codes(
+    db: Data<BlockingPool>,
+    identity: Identity,
+    form: web::Form<CodeForm>,
+) -> actix_web::Result<impl Responder> {
+    let username = identity.id().unwrap_or_default();
+    let res = db
+        .run(move |conn| check_code(conn, &username, &form.code)?.replace_recovery_codes(conn))
+        .await?;
+    Ok(match res {
+        Ok(codes) => FormResponseBuilder::dialog(Modal {
+            title: String::from("New recovery codes"),
+            request_target: String::new(),
+            template: RecoveryCodesDialog { codes }.to_string(),
+        })
+        .add_trigger(String::from("reload-two-factor")),
+        Err(e) => FormResponseBuilder::error(e),
+    })
+}
@@ -0,0 +1,51 @@
+use actix_web::{
+    get, post,
+    web::{self, Data},
+    Responder,
+};
+use askama_actix::{Template, TemplateToResponse};
+use log::info;
+
+use crate::{
+    backup,
+    db::BlockingPool,
+    routes::{attachment, ErrorTemplate},
+    Configuration,
+};
+
+pub fn admin_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(backup_page).service(download_backup);
+}
+
+#[derive(Template)]
+#[template(path = "admin/backup.html")]
+struct BackupTemplate {
+    /// Whether downloads are encrypted with the `encryption_key` of `[backup]`
+    encrypted: bool,
+}
+
+#[get("/backup")]
+async fn backup_page(config: Data<Configuration>) -> impl Responder {
+    BackupTemplate {
+        encrypted: config
+            .backup
+            .as_ref()
+            .is_some_and(|backup| backup.is_encrypted()),
+    }
+    .to_response()
+}
+
+/// A snapshot of the database taken right now, a POST so it is recorded in the audit log
+#[post("/backup")]
+async fn download_backup(
+    db: Data<BlockingPool>,
+    config: Data<Configuration>,
+) -> actix_web::Result<impl Responder> {
+    Ok(match backup::download(&db, config.backup.as_ref()).await {
+        Ok((name, data)) => {
+            info!("Snapshot {name} was downloaded");
+            attachment("application/octet-stream", name, data)
+        }
+        Err(error) => ErrorTemplate { error }.to_response(),
+    })
+}
@@ -0,0 +1,75 @@
+use actix_web::{
+    get, post,
+    web::{self, Data},
+    HttpResponse,
+};
+use serde::Serialize;
+use utoipa::{OpenApi, ToSchema};
+
+use crate::{
+    bulk_apply::BulkApplier,
+    jobs::{Step, StepStatus},
+};
+
+use super::{ApiError, ApiResponse};
+
+#[derive(OpenApi)]
+#[openapi(paths(apply_status, apply_all))]
+pub struct ApplyApi;
+
+pub fn apply_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(apply_status).service(apply_all);
+}
+
+#[derive(Serialize, ToSchema)]
+struct ApiStep {
+    /// A host, or the validation webhook
+    name: String,
+    /// pending, running, done, skipped or failed
+    status: &'static str,
+    message: Option<String>,
+}
+
+impl From<Step> for ApiStep {
+    fn from(step: Step) -> Self {
+        let (status, message) = match step.status {
+            StepStatus::Pending => ("pending", None),
+            StepStatus::Running => ("running", None),
+            StepStatus::Done(message) => ("done", Some(message)),
+            StepStatus::Skipped(message) => ("skipped", Some(message)),
+            StepStatus::Failed(message) => ("failed", Some(message)),
+        };
+        Self {
+            name: step.name,
+            status,
+            message,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct ApplyStarted {
+    hosts: usize,
+}
+
+/// Progress of the last run, empty if there was none since startup
+#[utoipa::path(responses((status = 200, body = Vec<ApiStep>)))]
+#[get("")]
+async fn apply_status(bulk_applier: Data<BulkApplier>) -> HttpResponse {
+    let steps = bulk_applier.status().await.unwrap_or_default();
+    ApiResponse::ok(steps.into_iter().map(ApiStep::from).collect::<Vec<_>>())
+}
+
+/// Apply the database state to all hosts in the background. If a validation webhook is
+/// configured, it has to allow the changes first.
+#[utoipa::path(responses(
+    (status = 202, body = ApplyStarted),
+    (status = 422, body = ApiError),
+))]
+#[post("")]
+async fn apply_all(bulk_applier: Data<BulkApplier>) -> HttpResponse {
+    match bulk_applier.start().await {
+        Ok(hosts) => HttpResponse::Accepted().json(ApplyStarted { hosts }),
+        Err(error) => ApiResponse::error(error),
+    }
+}
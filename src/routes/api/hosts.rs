@@ -0,0 +1,681 @@
+use std::collections::BTreeMap;
+
+use actix_web::{
+    delete, get, post, put,
+    web::{self, Data, Json, Path},
+    HttpRequest, HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+use time::PrimitiveDateTime;
+use utoipa::{OpenApi, ToSchema};
+
+use crate::{
+    db::{
+        now, BlockingPool, DesiredHost, HostData, HostDataError, PutOutcome, UserAndOptions,
+        PENDING,
+    },
+    models::{
+        parse_expiry, Host, HostGroup, NewHost, NewPendingAuthorization, PendingAuthorization, User,
+    },
+    routes::{proposer, APPROVAL_REQUIRED},
+    ssh::{CachingSshClient, ConnectionDetails, SshClient, TRUST_MANUAL},
+    Configuration,
+};
+
+use super::{ApiError, ApiResponse};
+
+#[derive(OpenApi)]
+#[openapi(paths(
+    list_hosts,
+    show_host,
+    create_host,
+    put_host,
+    delete_host,
+    authorize_user,
+    authorize_user_on_hosts
+))]
+pub struct HostsApi;
+
+pub fn hosts_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_hosts)
+        .service(show_host)
+        .service(create_host)
+        .service(put_host)
+        .service(delete_host)
+        .service(authorize_user_on_hosts)
+        .service(authorize_user);
+}
+
+#[derive(Serialize, ToSchema)]
+struct ApiHost {
+    id: i32,
+    name: String,
+    username: String,
+    address: String,
+    port: i32,
+    key_fingerprint: Option<String>,
+    /// How the host key was verified: manual, sshfp, sshfp-dnssec or known-hosts
+    key_trust: Option<String>,
+    jump_via: Option<i32>,
+    /// Port forwarding is forbidden regardless of the authorizations
+    forbid_forwarding: bool,
+    /// Web user approving access requests for this host
+    owner: Option<String>,
+    /// Options of authorizations on this host that don't set them themselves
+    default_options: Option<String>,
+    /// critical, normal or low
+    importance: String,
+    /// Until when ssm only reports the drift of the host, in UTC
+    #[serde(serialize_with = "crate::models::serialize_expiry")]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    observed_until: Option<PrimitiveDateTime>,
+}
+
+impl From<Host> for ApiHost {
+    fn from(host: Host) -> Self {
+        let observed_until = host.observed_until();
+        Self {
+            id: host.id,
+            name: host.name,
+            username: host.username,
+            address: host.address,
+            port: host.port,
+            key_fingerprint: host.key_fingerprint,
+            key_trust: host.key_trust,
+            jump_via: host.jump_via,
+            forbid_forwarding: host.forbid_forwarding,
+            owner: host.owner,
+            default_options: host.default_options,
+            importance: host.importance,
+            observed_until,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct ApiAuthorization {
+    id: i32,
+    username: String,
+    login: String,
+    options: Option<String>,
+    /// When the authorization is dropped, in UTC
+    #[serde(serialize_with = "crate::models::serialize_expiry")]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    expires_at: Option<PrimitiveDateTime>,
+}
+
+impl ApiAuthorization {
+    fn all(
+        authorizations: Vec<UserAndOptions>,
+        expiries: &BTreeMap<i32, PrimitiveDateTime>,
+    ) -> Vec<Self> {
+        authorizations
+            .into_iter()
+            .map(|(id, username, login, options)| Self {
+                id,
+                username,
+                login,
+                options,
+                expires_at: expiries.get(&id).copied(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize)]
+struct ListHostsQuery {
+    group: Option<String>,
+}
+
+/// List all hosts, or the hosts in a group
+#[utoipa::path(
+    params(("group" = Option<String>, Query, description = "Only hosts in this group")),
+    responses(
+        (status = 200, body = Vec<ApiHost>),
+        (status = 422, body = ApiError),
+    )
+)]
+#[get("")]
+async fn list_hosts(
+    db: Data<BlockingPool>,
+    query: web::Query<ListHostsQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let group = query.into_inner().group;
+    let res = db
+        .run(move |conn| Host::get_all_hosts_in(conn, group.as_deref()))
+        .await?;
+    Ok(match res {
+        Ok(hosts) => ApiResponse::ok(hosts.into_iter().map(ApiHost::from).collect::<Vec<_>>()),
+        Err(error) => ApiResponse::error(error),
+    })
+}
+
+#[derive(Serialize, ToSchema)]
+struct ShowHostResponse {
+    #[serde(flatten)]
+    host: ApiHost,
+    jumphost: Option<String>,
+    authorizations: Vec<ApiAuthorization>,
+}
+
+/// Show a host with its authorizations
+#[utoipa::path(
+    params(("name" = String, Path, description = "Host name")),
+    responses(
+        (status = 200, body = ShowHostResponse),
+        (status = 404, body = ApiError),
+        (status = 422, body = ApiError),
+    )
+)]
+#[get("/{name}")]
+async fn show_host(
+    db: Data<BlockingPool>,
+    host_name: Path<String>,
+) -> actix_web::Result<HttpResponse> {
+    let res = db.run(move |conn| HostData::load(conn, &host_name)).await?;
+
+    Ok(match res {
+        Ok(host_data) => ApiResponse::ok(ShowHostResponse {
+            host: host_data.host.into(),
+            jumphost: host_data.jumphost,
+            authorizations: ApiAuthorization::all(host_data.authorized_users, &host_data.expiries),
+        }),
+        Err(HostDataError::HostNotFound) => ApiResponse::not_found(String::from("Host not found")),
+        Err(error) => ApiResponse::error(error.to_string()),
+    })
+}
+
+#[derive(Deserialize, ToSchema)]
+struct CreateHostRequest {
+    name: String,
+    username: String,
+    address: String,
+    port: i32,
+    /// Name of the jump host
+    jumphost: Option<String>,
+    /// Must match the host key. If omitted, the request fails and the
+    /// response contains the fingerprint to verify.
+    key_fingerprint: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct UnverifiedHostkey {
+    error: String,
+    key_fingerprint: String,
+}
+
+/// Add a host
+///
+/// Without `key_fingerprint`, the host key is fetched and returned for verification.
+#[utoipa::path(
+    request_body = CreateHostRequest,
+    responses(
+        (status = 201, body = ApiHost),
+        (status = 404, description = "Jump host not found", body = ApiError),
+        (status = 422, description = "Invalid request or unverified host key", body = UnverifiedHostkey),
+    )
+)]
+#[post("")]
+async fn create_host(
+    db: Data<BlockingPool>,
+    ssh_client: Data<SshClient>,
+    req: Json<CreateHostRequest>,
+) -> actix_web::Result<HttpResponse> {
+    let req = req.into_inner();
+
+    let jumphost = match req.jumphost {
+        Some(name) => match Host::get_from_name(&db, name).await {
+            Ok(Some(jumphost)) => Some(jumphost),
+            Ok(None) => return Ok(ApiResponse::not_found(String::from("Jump host not found"))),
+            Err(error) => return Ok(ApiResponse::error(error)),
+        },
+        None => None,
+    };
+
+    let Ok(address) = ConnectionDetails::new_from_signed(req.address.clone(), req.port) else {
+        return Ok(ApiResponse::error(String::from("Invalid port number")));
+    };
+
+    let Some(key_fingerprint) = req.key_fingerprint else {
+        let connection_res = match jumphost {
+            Some(via) => ssh_client.get_hostkey_via(via, address).await,
+            None => ssh_client.get_hostkey(address).await,
+        };
+
+        let key_receiver = match connection_res {
+            Ok(r) => r,
+            Err(e) => return Ok(ApiResponse::error(e.to_string())),
+        };
+
+        let Ok(key_fingerprint) = web::block(move || key_receiver.recv()).await? else {
+            return Ok(ApiResponse::error(String::from("Connection timed out")));
+        };
+
+        return Ok(HttpResponse::UnprocessableEntity().json(UnverifiedHostkey {
+            error: String::from("Verify the host key and repeat the request with key_fingerprint"),
+            key_fingerprint,
+        }));
+    };
+
+    let auth_res = match jumphost {
+        Some(ref via) => {
+            ssh_client
+                .try_authenticate_via(
+                    via.clone(),
+                    address,
+                    key_fingerprint.clone(),
+                    req.username.clone(),
+                )
+                .await
+        }
+        None => {
+            ssh_client
+                .try_authenticate(address, key_fingerprint.clone(), req.username.clone())
+                .await
+        }
+    };
+    if let Err(error) = auth_res {
+        return Ok(ApiResponse::error(error.to_string()));
+    }
+
+    let new_host = NewHost {
+        name: req.name,
+        address: req.address,
+        port: req.port,
+        username: req.username,
+        key_fingerprint,
+        jump_via: jumphost.map(|h| h.id),
+        // The client verified the key
+        key_trust: Some(TRUST_MANUAL.to_owned()),
+        observed_until: ssh_client.observation_end(),
+    };
+    let id = match db.run(move |conn| Host::add_host(conn, &new_host)).await? {
+        Ok(id) => id,
+        Err(error) => return Ok(ApiResponse::error(error)),
+    };
+
+    if let Err(error) = ssh_client.install_script_on_host(id).await {
+        return Ok(ApiResponse::error(format!(
+            "Failed to install script: {error}"
+        )));
+    }
+
+    Ok(match Host::get_from_id(&db, id).await {
+        Ok(Some(host)) => ApiResponse::created(ApiHost::from(host)),
+        Ok(None) => ApiResponse::not_found(String::from("Host not found")),
+        Err(error) => ApiResponse::error(error),
+    })
+}
+
+#[derive(Deserialize, ToSchema)]
+struct PutHostRequest {
+    username: String,
+    address: String,
+    port: i32,
+    /// Name of the jump host, omitted to connect directly
+    jumphost: Option<String>,
+    /// Omitted to keep the stored key. New hosts without one get theirs returned
+    /// for verification.
+    key_fingerprint: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct PutHostResponse {
+    #[serde(flatten)]
+    host: ApiHost,
+    /// Whether the host was created or anything about it changed
+    changed: bool,
+}
+
+/// Create or update a host
+///
+/// Creates the host if it doesn't exist, else updates what differs. A new or changed
+/// `key_fingerprint` is checked by connecting like when adding a host, a new host
+/// without one gets its key returned for verification. Repeating the request changes
+/// nothing.
+#[utoipa::path(
+    params(("name" = String, Path, description = "Host name")),
+    request_body = PutHostRequest,
+    responses(
+        (status = 200, body = PutHostResponse),
+        (status = 201, description = "The host was created", body = PutHostResponse),
+        (status = 404, description = "Jump host not found", body = ApiError),
+        (status = 422, description = "Invalid request or unverified host key", body = UnverifiedHostkey),
+    )
+)]
+#[put("/{name}")]
+async fn put_host(
+    db: Data<BlockingPool>,
+    ssh_client: Data<SshClient>,
+    caching_ssh_client: Data<CachingSshClient>,
+    host_name: Path<String>,
+    req: Json<PutHostRequest>,
+) -> actix_web::Result<HttpResponse> {
+    let host_name = host_name.into_inner();
+    let req = req.into_inner();
+
+    let Ok(address) = ConnectionDetails::new_from_signed(req.address.clone(), req.port) else {
+        return Ok(ApiResponse::error(String::from("Invalid port number")));
+    };
+    let jumphost = match req.jumphost {
+        Some(name) if name == host_name => {
+            return Ok(ApiResponse::error(String::from(
+                "A host can't be its own jump host",
+            )))
+        }
+        Some(name) => match Host::get_from_name(&db, name).await {
+            Ok(Some(jumphost)) => Some(jumphost),
+            Ok(None) => return Ok(ApiResponse::not_found(String::from("Jump host not found"))),
+            Err(error) => return Ok(ApiResponse::error(error)),
+        },
+        None => None,
+    };
+    let existing = match Host::get_from_name(&db, host_name.clone()).await {
+        Ok(existing) => existing,
+        Err(error) => return Ok(ApiResponse::error(error)),
+    };
+
+    // A new or changed key is checked like when adding a host
+    let new_key = match (&req.key_fingerprint, &existing) {
+        (None, Some(_)) => None,
+        (Some(key), Some(host)) if host.key_fingerprint.as_ref() == Some(key) => None,
+        (Some(key), _) => Some(key.clone()),
+        (None, None) => {
+            let connection_res = match jumphost {
+                Some(via) => ssh_client.get_hostkey_via(via, address).await,
+                None => ssh_client.get_hostkey(address).await,
+            };
+
+            let key_receiver = match connection_res {
+                Ok(r) => r,
+                Err(e) => return Ok(ApiResponse::error(e.to_string())),
+            };
+
+            let Ok(key_fingerprint) = web::block(move || key_receiver.recv()).await? else {
+                return Ok(ApiResponse::error(String::from("Connection timed out")));
+            };
+
+            return Ok(HttpResponse::UnprocessableEntity().json(UnverifiedHostkey {
+                error: String::from(
+                    "Verify the host key and repeat the request with key_fingerprint",
+                ),
+                key_fingerprint,
+            }));
+        }
+    };
+    if let Some(key_fingerprint) = new_key {
+        let auth_res = match jumphost {
+            Some(ref via) => {
+                ssh_client
+                    .try_authenticate_via(
+                        via.clone(),
+                        address,
+                        key_fingerprint,
+                        req.username.clone(),
+                    )
+                    .await
+            }
+            None => {
+                ssh_client
+                    .try_authenticate(address, key_fingerprint, req.username.clone())
+                    .await
+            }
+        };
+        if let Err(error) = auth_res {
+            return Ok(ApiResponse::error(error.to_string()));
+        }
+    }
+
+    let desired = DesiredHost {
+        address: req.address,
+        port: req.port,
+        username: req.username,
+        jump_via: jumphost.map(|h| h.id),
+        key_fingerprint: req.key_fingerprint,
+    };
+    let observed_until = ssh_client.observation_end();
+    let name = host_name.clone();
+    let outcome = match db
+        .run(move |conn| Host::put_host(conn, &name, &desired, observed_until))
+        .await?
+    {
+        Ok(outcome) => outcome,
+        Err(error) => return Ok(ApiResponse::error(error)),
+    };
+    if outcome == PutOutcome::Updated {
+        caching_ssh_client.remove(&host_name).await;
+    }
+
+    let host = match Host::get_from_name(&db, host_name).await {
+        Ok(Some(host)) => host,
+        Ok(None) => return Ok(ApiResponse::not_found(String::from("Host not found"))),
+        Err(error) => return Ok(ApiResponse::error(error)),
+    };
+    if outcome == PutOutcome::Created {
+        if let Err(error) = ssh_client.install_script_on_host(host.id).await {
+            return Ok(ApiResponse::error(format!(
+                "Failed to install script: {error}"
+            )));
+        }
+    }
+
+    let body = PutHostResponse {
+        host: host.into(),
+        changed: outcome != PutOutcome::Unchanged,
+    };
+    Ok(match outcome {
+        PutOutcome::Created => ApiResponse::created(body),
+        _ => ApiResponse::ok(body),
+    })
+}
+
+#[derive(Serialize, ToSchema)]
+struct DeleteHostResponse {
+    deleted: usize,
+}
+
+/// Delete a host
+#[utoipa::path(
+    params(("name" = String, Path, description = "Host name")),
+    responses(
+        (status = 200, body = DeleteHostResponse),
+        (status = 404, body = ApiError),
+        (status = 422, body = ApiError),
+    )
+)]
+#[delete("/{name}")]
+async fn delete_host(
+    db: Data<BlockingPool>,
+    caching_ssh_client: Data<CachingSshClient>,
+    host_name: Path<String>,
+) -> actix_web::Result<HttpResponse> {
+    let host = match Host::get_from_name(&db, host_name.to_string()).await {
+        Ok(Some(host)) => host,
+        Ok(None) => return Ok(ApiResponse::not_found(String::from("Host not found"))),
+        Err(error) => return Ok(ApiResponse::error(error)),
+    };
+
+    Ok(match db.run(move |conn| host.delete(conn)).await? {
+        Ok(deleted) => {
+            caching_ssh_client.remove(host_name.as_str()).await;
+            ApiResponse::ok(DeleteHostResponse { deleted })
+        }
+        Err(error) => ApiResponse::error(error),
+    })
+}
+
+/// An authorization waiting for the approval of an admin
+#[derive(Serialize, ToSchema)]
+struct ProposedAuthorization {
+    host: String,
+    username: String,
+    login: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct AuthorizeUserRequest {
+    username: String,
+    login: String,
+    options: Option<String>,
+    /// When the authorization is dropped: RFC 3339, or a date in UTC
+    #[serde(default)]
+    expires_at: Option<String>,
+}
+
+/// Authorize a user on a host
+///
+/// Returns all authorizations of the host. If authorizations need approval, the authorization
+/// is only proposed and takes effect once an admin approves it.
+#[utoipa::path(
+    params(("name" = String, Path, description = "Host name")),
+    request_body = AuthorizeUserRequest,
+    responses(
+        (status = 201, body = Vec<ApiAuthorization>),
+        (status = 202, description = "Waiting for approval", body = ProposedAuthorization),
+        (status = 404, body = ApiError),
+        (status = 422, body = ApiError),
+    )
+)]
+#[post("/{name}/authorizations")]
+async fn authorize_user(
+    db: Data<BlockingPool>,
+    config: Data<Configuration>,
+    http_req: HttpRequest,
+    host_name: Path<String>,
+    req: Json<AuthorizeUserRequest>,
+) -> actix_web::Result<HttpResponse> {
+    let req = req.into_inner();
+    let expires_at = match parse_expiry(req.expires_at.as_deref().unwrap_or_default()) {
+        Ok(expires_at) => expires_at,
+        Err(e) => return Ok(ApiResponse::error(e)),
+    };
+    if let Some(proposed_by) = proposer(&http_req, &config) {
+        return propose_authorization(db, host_name.into_inner(), req, expires_at, proposed_by)
+            .await;
+    }
+
+    let res = db
+        .run(move |conn| {
+            let Some(host) = Host::get_from_name_sync(conn, host_name.to_string())? else {
+                return Ok(None);
+            };
+            let user = User::get_user(conn, req.username)?;
+
+            Host::authorize_user(conn, host.id, user.id, req.login, req.options, expires_at)?;
+            let expiries = host.get_authorization_expiries(conn)?;
+            host.get_authorized_users(conn)
+                .map(|authorizations| Some(ApiAuthorization::all(authorizations, &expiries)))
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(Some(authorizations)) => ApiResponse::created(authorizations),
+        Ok(None) => ApiResponse::not_found(String::from("Host not found")),
+        Err(error) => ApiResponse::error(error),
+    })
+}
+
+async fn propose_authorization(
+    db: Data<BlockingPool>,
+    host_name: String,
+    req: AuthorizeUserRequest,
+    expires_at: Option<PrimitiveDateTime>,
+    proposed_by: String,
+) -> actix_web::Result<HttpResponse> {
+    let res = db
+        .run(move |conn| {
+            let Some(host) = Host::get_from_name_sync(conn, host_name)? else {
+                return Ok(None);
+            };
+            let user = User::get_user(conn, req.username)?;
+            PendingAuthorization::propose(
+                conn,
+                NewPendingAuthorization {
+                    created_at: now(),
+                    proposed_by,
+                    host_id: host.id,
+                    user_id: user.id,
+                    login: req.login.clone(),
+                    options: req.options,
+                    expires_at,
+                    status: PENDING.to_owned(),
+                },
+            )?;
+            Ok(Some(ProposedAuthorization {
+                host: host.name,
+                username: user.username,
+                login: req.login,
+            }))
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(Some(proposal)) => ApiResponse::accepted(proposal),
+        Ok(None) => ApiResponse::not_found(String::from("Host not found")),
+        Err(error) => ApiResponse::error(error),
+    })
+}
+
+#[derive(Deserialize, ToSchema)]
+struct BulkAuthorizeRequest {
+    username: String,
+    login: String,
+    options: Option<String>,
+    /// Names of the hosts
+    #[serde(default)]
+    hosts: Vec<String>,
+    /// A host group, instead of the hosts
+    group: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct BulkAuthorizeResponse {
+    /// Hosts the user was authorized on
+    authorized: Vec<String>,
+    /// Hosts where the user already had the login
+    skipped: Vec<String>,
+}
+
+/// Authorize a user on several hosts or a host group
+///
+/// Either all hosts are authorized or none. Refused if authorizations need approval.
+#[utoipa::path(
+    request_body = BulkAuthorizeRequest,
+    responses(
+        (status = 201, body = BulkAuthorizeResponse),
+        (status = 422, body = ApiError),
+    )
+)]
+#[post("/authorizations")]
+async fn authorize_user_on_hosts(
+    db: Data<BlockingPool>,
+    config: Data<Configuration>,
+    http_req: HttpRequest,
+    req: Json<BulkAuthorizeRequest>,
+) -> actix_web::Result<HttpResponse> {
+    if proposer(&http_req, &config).is_some() {
+        return Ok(ApiResponse::error(APPROVAL_REQUIRED.to_owned()));
+    }
+    let req = req.into_inner();
+    let res = db
+        .run(move |conn| {
+            let hosts = match (req.group, req.hosts.is_empty()) {
+                (Some(group), true) => HostGroup::get_from_name(conn, &group)?
+                    .ok_or_else(|| format!("There is no host group {group}"))?
+                    .get_hosts(conn)?,
+                (None, false) => Host::get_all_named(conn, &req.hosts)?,
+                _ => return Err(String::from("Give either hosts or a group")),
+            };
+            let user = User::get_user(conn, req.username)?;
+            Host::authorize_user_on_hosts(conn, &hosts, user.id, &req.login, req.options)
+        })
+        .await?;
+
+    Ok(match res {
+        Ok((authorized, skipped)) => ApiResponse::created(BulkAuthorizeResponse {
+            authorized,
+            skipped,
+        }),
+        Err(error) => ApiResponse::error(error),
+    })
+}
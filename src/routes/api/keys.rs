@@ -0,0 +1,296 @@
+use actix_web::{
+    delete, get, post, put,
+    web::{self, Data, Json, Path},
+    HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+use ssh_key::PublicKey;
+use utoipa::{OpenApi, ToSchema};
+
+use crate::{
+    attestation,
+    db::BlockingPool,
+    models::{parse_expiry, NewPublicUserKey, PublicUserKey, User},
+    removal_queue::{RemovalQueue, RemovalResult},
+};
+
+use super::{ApiError, ApiResponse};
+
+#[derive(OpenApi)]
+#[openapi(paths(
+    list_keys,
+    key_challenge,
+    show_key,
+    create_key,
+    update_key,
+    delete_key,
+    revoke_key
+))]
+pub struct KeysApi;
+
+pub fn keys_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_keys)
+        .service(key_challenge)
+        .service(show_key)
+        .service(create_key)
+        .service(update_key)
+        .service(delete_key)
+        .service(revoke_key);
+}
+
+#[derive(Serialize, ToSchema)]
+struct ApiKey {
+    #[serde(flatten)]
+    key: PublicUserKey,
+    username: String,
+}
+
+/// List all keys
+#[utoipa::path(responses(
+    (status = 200, body = Vec<ApiKey>),
+    (status = 422, body = ApiError),
+))]
+#[get("")]
+async fn list_keys(db: Data<BlockingPool>) -> actix_web::Result<HttpResponse> {
+    Ok(
+        match db.run(PublicUserKey::get_all_keys_with_username).await? {
+            Ok(keys) => ApiResponse::ok(
+                keys.into_iter()
+                    .map(|(username, key)| ApiKey { key, username })
+                    .collect::<Vec<_>>(),
+            ),
+            Err(error) => ApiResponse::error(error),
+        },
+    )
+}
+
+/// Show a key
+#[utoipa::path(
+    params(("id" = i32, Path, description = "Key id")),
+    responses(
+        (status = 200, body = PublicUserKey),
+        (status = 404, body = ApiError),
+        (status = 422, body = ApiError),
+    )
+)]
+#[get("/{id}")]
+async fn show_key(db: Data<BlockingPool>, id: Path<i32>) -> actix_web::Result<HttpResponse> {
+    let id = id.into_inner();
+    Ok(
+        match db
+            .run(move |conn| PublicUserKey::get_from_id(conn, id))
+            .await?
+        {
+            Ok(Some(key)) => ApiResponse::ok(key),
+            Ok(None) => ApiResponse::not_found(String::from("Key not found")),
+            Err(error) => ApiResponse::error(error),
+        },
+    )
+}
+
+#[derive(Deserialize, ToSchema)]
+struct CreateKeyRequest {
+    username: String,
+    key_type: String,
+    key_base64: String,
+    comment: Option<String>,
+    /// When the key stops being authorized: RFC 3339, or a date in UTC
+    #[serde(default)]
+    expires_at: Option<String>,
+    /// Base64 encoded FIDO attestation of an sk- key, as written by
+    /// `ssh-keygen -O write-attestation=<file>`
+    #[serde(default)]
+    attestation: Option<String>,
+    /// The challenge from `GET /keys/challenge` the attested key was created with
+    #[serde(default)]
+    challenge: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ChallengeQuery {
+    username: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct Challenge {
+    /// Pass it to `ssh-keygen -O challenge=<file>` as the content of the file
+    challenge: String,
+}
+
+/// Get a challenge for creating an attested key
+#[utoipa::path(
+    params(("username" = String, Query, description = "Owner of the key that will be created")),
+    responses(
+        (status = 200, body = Challenge),
+        (status = 422, body = ApiError),
+    )
+)]
+#[get("/challenge")]
+async fn key_challenge(query: web::Query<ChallengeQuery>) -> HttpResponse {
+    match attestation::challenge(&query.username) {
+        Ok(challenge) => ApiResponse::ok(Challenge { challenge }),
+        Err(error) => ApiResponse::error(error),
+    }
+}
+
+/// Add a key to a user
+///
+/// Returns all keys of the user.
+#[utoipa::path(
+    request_body = CreateKeyRequest,
+    responses(
+        (status = 201, body = Vec<PublicUserKey>),
+        (status = 404, body = ApiError),
+        (status = 422, body = ApiError),
+    )
+)]
+#[post("")]
+async fn create_key(
+    db: Data<BlockingPool>,
+    req: Json<CreateKeyRequest>,
+) -> actix_web::Result<HttpResponse> {
+    let req = req.into_inner();
+
+    let key = match PublicKey::from_openssh(&format!("{} {}", req.key_type, req.key_base64)) {
+        Ok(key) => key,
+        Err(e) => return Ok(ApiResponse::error(format!("Invalid key: {e}"))),
+    };
+    let expires_at = match parse_expiry(req.expires_at.as_deref().unwrap_or_default()) {
+        Ok(expires_at) => expires_at,
+        Err(e) => return Ok(ApiResponse::error(e)),
+    };
+    let attested = match &req.attestation {
+        Some(data) => {
+            let challenge = req.challenge.as_deref().unwrap_or_default();
+            match attestation::verify(&req.username, &key, data, challenge) {
+                Ok(attestation) => Some(attestation),
+                Err(e) => return Ok(ApiResponse::error(e)),
+            }
+        }
+        None => None,
+    };
+
+    let res = db
+        .run(move |conn| {
+            let Some(user) = User::get_from_name(conn, &req.username)? else {
+                return Ok(None);
+            };
+            let mut new_key =
+                NewPublicUserKey::new(key.algorithm(), req.key_base64, req.comment, user.id)
+                    .with_expiry(expires_at);
+            if let Some(attestation) = attested {
+                new_key = new_key.with_attestation(attestation);
+            }
+            PublicUserKey::add_key(conn, new_key)?;
+
+            user.get_keys(conn).map(Some)
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(Some(keys)) => ApiResponse::created(keys),
+        Ok(None) => ApiResponse::not_found(String::from("User not found")),
+        Err(error) => ApiResponse::error(error),
+    })
+}
+
+#[derive(Deserialize, ToSchema)]
+struct UpdateKeyRequest {
+    /// New comment, unchanged if missing
+    #[serde(default)]
+    comment: Option<String>,
+    /// When the key stops being authorized: RFC 3339, or a date in UTC. An empty string
+    /// keeps the key forever, unchanged if missing
+    #[serde(default)]
+    expires_at: Option<String>,
+}
+
+/// Change the comment or expiry of a key
+#[utoipa::path(
+    params(("id" = i32, Path, description = "Key id")),
+    request_body = UpdateKeyRequest,
+    responses(
+        (status = 200, body = PublicUserKey),
+        (status = 404, body = ApiError),
+        (status = 422, body = ApiError),
+    )
+)]
+#[put("/{id}")]
+async fn update_key(
+    db: Data<BlockingPool>,
+    id: Path<i32>,
+    req: Json<UpdateKeyRequest>,
+) -> actix_web::Result<HttpResponse> {
+    let req = req.into_inner();
+    let expires_at = match req.expires_at.as_deref().map(parse_expiry).transpose() {
+        Ok(expires_at) => expires_at,
+        Err(e) => return Ok(ApiResponse::error(e)),
+    };
+    let id = id.into_inner();
+    let res = db
+        .run(move |conn| {
+            if PublicUserKey::get_from_id(conn, id)?.is_none() {
+                return Ok(None);
+            }
+            if let Some(comment) = req.comment {
+                PublicUserKey::update_comment(conn, id, &comment)?;
+            }
+            if let Some(expires_at) = expires_at {
+                PublicUserKey::set_expiry(conn, id, expires_at)?;
+            }
+            PublicUserKey::get_from_id(conn, id)
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(Some(key)) => ApiResponse::ok(key),
+        Ok(None) => ApiResponse::not_found(String::from("Key not found")),
+        Err(error) => ApiResponse::error(error),
+    })
+}
+
+/// Delete a key
+#[utoipa::path(
+    params(("id" = i32, Path, description = "Key id")),
+    responses(
+        (status = 204),
+        (status = 404, body = ApiError),
+        (status = 422, body = ApiError),
+    )
+)]
+#[delete("/{id}")]
+async fn delete_key(db: Data<BlockingPool>, id: Path<i32>) -> actix_web::Result<HttpResponse> {
+    let id = id.into_inner();
+    Ok(
+        match db
+            .run(move |conn| PublicUserKey::delete_key(conn, id))
+            .await?
+        {
+            Ok(0) => ApiResponse::not_found(String::from("Key not found")),
+            Ok(_) => HttpResponse::NoContent().finish(),
+            Err(error) => ApiResponse::error(error),
+        },
+    )
+}
+
+/// Delete a key and remove it from every host
+///
+/// Removes the key from every login it is authorized for or was last found on. Failed
+/// removals are retried in the background and have an error in the report.
+#[utoipa::path(
+    params(("id" = i32, Path, description = "Key id")),
+    responses(
+        (status = 200, body = Vec<RemovalResult>),
+        (status = 422, body = ApiError),
+    )
+)]
+#[post("/{id}/revoke")]
+async fn revoke_key(
+    removals: Data<RemovalQueue>,
+    id: Path<i32>,
+) -> actix_web::Result<HttpResponse> {
+    Ok(match removals.revoke_everywhere(id.into_inner()).await {
+        Ok(results) => ApiResponse::ok(results),
+        Err(error) => ApiResponse::error(error),
+    })
+}
@@ -0,0 +1,91 @@
+use actix_web::{get, http::StatusCode, web, HttpResponse, HttpResponseBuilder};
+use serde::Serialize;
+use utoipa::{
+    openapi::security::{Http, HttpAuthScheme, SecurityScheme},
+    Modify, OpenApi, ToSchema,
+};
+
+mod apply;
+mod hosts;
+mod keys;
+mod state;
+mod users;
+
+pub fn api_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(openapi_spec)
+        .service(web::scope("/v1/apply").configure(apply::apply_config))
+        .service(web::scope("/v1/hosts").configure(hosts::hosts_config))
+        .service(web::scope("/v1/users").configure(users::users_config))
+        .service(web::scope("/v1/keys").configure(keys::keys_config))
+        .service(web::scope("/v1").configure(state::state_config));
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Secure SSH Manager API"),
+    nest(
+        (path = "/api/v1/apply", api = apply::ApplyApi, tags = ["apply"]),
+        (path = "/api/v1/hosts", api = hosts::HostsApi, tags = ["hosts"]),
+        (path = "/api/v1/users", api = users::UsersApi, tags = ["users"]),
+        (path = "/api/v1/keys", api = keys::KeysApi, tags = ["keys"]),
+        (path = "/api/v1", api = state::StateApi, tags = ["state"]),
+    ),
+    components(schemas(ApiError)),
+    modifiers(&ApiTokenAuth),
+    security(("api_token" = []))
+)]
+struct ApiDoc;
+
+/// Documents authentication with `Authorization: Bearer <token>`
+struct ApiTokenAuth;
+
+impl Modify for ApiTokenAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "api_token",
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+            );
+        }
+    }
+}
+
+/// OpenAPI 3 document describing this API
+#[get("/openapi.json")]
+async fn openapi_spec() -> HttpResponse {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+#[derive(Serialize, ToSchema)]
+struct ApiError {
+    error: String,
+}
+
+/// JSON counterpart to [`crate::forms::FormResponseBuilder`]
+pub struct ApiResponse;
+
+impl ApiResponse {
+    pub fn ok<T: Serialize>(body: T) -> HttpResponse {
+        HttpResponse::Ok().json(body)
+    }
+
+    pub fn created<T: Serialize>(body: T) -> HttpResponse {
+        HttpResponse::Created().json(body)
+    }
+
+    pub fn accepted<T: Serialize>(body: T) -> HttpResponse {
+        HttpResponse::Accepted().json(body)
+    }
+
+    pub fn error(message: String) -> HttpResponse {
+        Self::with_status(StatusCode::UNPROCESSABLE_ENTITY, message)
+    }
+
+    pub fn not_found(message: String) -> HttpResponse {
+        Self::with_status(StatusCode::NOT_FOUND, message)
+    }
+
+    pub fn with_status(status: StatusCode, message: String) -> HttpResponse {
+        HttpResponseBuilder::new(status).json(ApiError { error: message })
+    }
+}
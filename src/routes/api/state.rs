@@ -0,0 +1,61 @@
+use actix_web::{
+    get, post,
+    web::{self, Data, Json},
+    HttpRequest, HttpResponse,
+};
+use utoipa::OpenApi;
+
+use crate::{
+    db::{BlockingPool, ImportSummary, State},
+    routes::{proposer, APPROVAL_REQUIRED},
+    Configuration,
+};
+
+use super::{ApiError, ApiResponse};
+
+#[derive(OpenApi)]
+#[openapi(paths(export_state, import_state))]
+pub struct StateApi;
+
+pub fn state_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(export_state).service(import_state);
+}
+
+/// Export hosts, users, keys and authorizations as one versioned document
+#[utoipa::path(responses(
+    (status = 200, body = State),
+    (status = 422, body = ApiError),
+))]
+#[get("/export")]
+async fn export_state(db: Data<BlockingPool>) -> actix_web::Result<HttpResponse> {
+    Ok(match db.run(State::export).await? {
+        Ok(state) => ApiResponse::ok(state),
+        Err(error) => ApiResponse::error(error),
+    })
+}
+
+/// Import an exported document. Adds what is missing in one transaction, existing hosts,
+/// users, keys and authorizations are left as they are.
+#[utoipa::path(
+    request_body = State,
+    responses(
+        (status = 200, body = ImportSummary),
+        (status = 422, body = ApiError),
+    )
+)]
+#[post("/import")]
+async fn import_state(
+    db: Data<BlockingPool>,
+    config: Data<Configuration>,
+    http_req: HttpRequest,
+    req: Json<State>,
+) -> actix_web::Result<HttpResponse> {
+    let state = req.into_inner();
+    if !state.authorizations.is_empty() && proposer(&http_req, &config).is_some() {
+        return Ok(ApiResponse::error(APPROVAL_REQUIRED.to_owned()));
+    }
+    Ok(match db.run(move |conn| state.import(conn)).await? {
+        Ok(summary) => ApiResponse::ok(summary),
+        Err(error) => ApiResponse::error(error),
+    })
+}
@@ -0,0 +1,242 @@
+use actix_web::{
+    delete, get, post, put,
+    web::{self, Data, Json, Path},
+    HttpMessage, HttpRequest, HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{OpenApi, ToSchema};
+
+use crate::{
+    db::{BlockingPool, PutOutcome, UserAndOptions},
+    models::{NewUser, PublicUserKey, User},
+    privacy::Erased,
+    Configuration,
+};
+
+use super::{ApiError, ApiResponse};
+
+#[derive(OpenApi)]
+#[openapi(paths(list_users, show_user, create_user, update_user, delete_user))]
+pub struct UsersApi;
+
+pub fn users_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_users)
+        .service(show_user)
+        .service(create_user)
+        .service(update_user)
+        .service(delete_user);
+}
+
+#[derive(Serialize, ToSchema)]
+struct ApiUserAuthorization {
+    id: i32,
+    host: String,
+    login: String,
+    options: Option<String>,
+}
+
+impl From<UserAndOptions> for ApiUserAuthorization {
+    fn from((id, host, login, options): UserAndOptions) -> Self {
+        Self {
+            id,
+            host,
+            login,
+            options,
+        }
+    }
+}
+
+/// List all users
+#[utoipa::path(responses(
+    (status = 200, body = Vec<User>),
+    (status = 422, body = ApiError),
+))]
+#[get("")]
+async fn list_users(db: Data<BlockingPool>) -> actix_web::Result<HttpResponse> {
+    Ok(match db.run(User::get_all_users).await? {
+        Ok(users) => ApiResponse::ok(users),
+        Err(error) => ApiResponse::error(error),
+    })
+}
+
+#[derive(Serialize, ToSchema)]
+struct ShowUserResponse {
+    #[serde(flatten)]
+    user: User,
+    keys: Vec<PublicUserKey>,
+    authorizations: Vec<ApiUserAuthorization>,
+}
+
+/// Show a user with its keys and authorizations
+#[utoipa::path(
+    params(("name" = String, Path, description = "Username")),
+    responses(
+        (status = 200, body = ShowUserResponse),
+        (status = 404, body = ApiError),
+        (status = 422, body = ApiError),
+    )
+)]
+#[get("/{name}")]
+async fn show_user(
+    db: Data<BlockingPool>,
+    username: Path<String>,
+) -> actix_web::Result<HttpResponse> {
+    let res = db
+        .run(move |conn| {
+            let Some(user) = User::get_from_name(conn, &username)? else {
+                return Ok(None);
+            };
+            let keys = user.get_keys(conn)?;
+            let authorizations = user.get_authorizations(conn)?;
+
+            Ok::<_, String>(Some(ShowUserResponse {
+                user,
+                keys,
+                authorizations: authorizations.into_iter().map(Into::into).collect(),
+            }))
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(Some(user)) => ApiResponse::ok(user),
+        Ok(None) => ApiResponse::not_found(String::from("User not found")),
+        Err(error) => ApiResponse::error(error),
+    })
+}
+
+/// Add a user
+#[utoipa::path(
+    request_body = NewUser,
+    responses(
+        (status = 201, body = User),
+        (status = 422, body = ApiError),
+    )
+)]
+#[post("")]
+async fn create_user(
+    db: Data<BlockingPool>,
+    req: Json<NewUser>,
+) -> actix_web::Result<HttpResponse> {
+    let new_user = req.into_inner();
+    let res = db
+        .run(move |conn| {
+            let username = User::add_user(conn, new_user)?;
+            User::get_user(conn, username)
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(user) => ApiResponse::created(user),
+        Err(error) => ApiResponse::error(error),
+    })
+}
+
+#[derive(Deserialize, ToSchema)]
+struct UpdateUserRequest {
+    /// Renames the user
+    username: Option<String>,
+    enabled: Option<bool>,
+    /// An empty email removes it
+    email: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct PutUserResponse {
+    #[serde(flatten)]
+    user: User,
+    /// Whether the user was created or anything about it changed
+    changed: bool,
+}
+
+/// Create or update a user
+///
+/// Creates the user if it doesn't exist, else renames or enables/disables it.
+/// Omitted values stay as they are. Repeating the request changes nothing.
+#[utoipa::path(
+    params(("name" = String, Path, description = "Username")),
+    request_body = UpdateUserRequest,
+    responses(
+        (status = 200, body = PutUserResponse),
+        (status = 201, description = "The user was created", body = PutUserResponse),
+        (status = 422, body = ApiError),
+    )
+)]
+#[put("/{name}")]
+async fn update_user(
+    db: Data<BlockingPool>,
+    username: Path<String>,
+    req: Json<UpdateUserRequest>,
+) -> actix_web::Result<HttpResponse> {
+    let res = db
+        .run(move |conn| {
+            let req = req.into_inner();
+            let (username, outcome) = User::put_user(
+                conn,
+                &username,
+                req.username.as_deref(),
+                req.enabled,
+                req.email.as_deref(),
+            )?;
+            User::get_user(conn, username).map(|user| (user, outcome))
+        })
+        .await?;
+
+    Ok(match res {
+        Ok((user, PutOutcome::Created)) => ApiResponse::created(PutUserResponse {
+            user,
+            changed: true,
+        }),
+        Ok((user, outcome)) => ApiResponse::ok(PutUserResponse {
+            user,
+            changed: outcome == PutOutcome::Updated,
+        }),
+        Err(error) => ApiResponse::error(error),
+    })
+}
+
+/// Delete a user
+#[utoipa::path(
+    params(("name" = String, Path, description = "Username")),
+    responses(
+        (status = 204),
+        (status = 404, body = ApiError),
+        (status = 422, body = ApiError),
+    )
+)]
+#[delete("/{name}")]
+async fn delete_user(
+    db: Data<BlockingPool>,
+    config: Data<Configuration>,
+    username: Path<String>,
+    http_req: HttpRequest,
+) -> actix_web::Result<HttpResponse> {
+    if config.anonymize_deleted_users {
+        let res = db
+            .run(move |conn| {
+                let Some(user) = User::get_from_name(conn, &username)? else {
+                    return Ok(None);
+                };
+                let pseudonym = user.delete_anonymized(conn)?;
+                Ok(Some(Erased::new(&user, pseudonym)))
+            })
+            .await?;
+        return Ok(match res {
+            Ok(Some(erased)) => {
+                http_req.extensions_mut().insert(erased);
+                HttpResponse::NoContent().finish()
+            }
+            Ok(None) => ApiResponse::not_found(String::from("User not found")),
+            Err(error) => ApiResponse::error(error),
+        });
+    }
+
+    let res = db
+        .run(move |conn| User::delete_user(conn, &username))
+        .await?;
+
+    Ok(match res {
+        Ok(0) => ApiResponse::not_found(String::from("User not found")),
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(error) => ApiResponse::error(error),
+    })
+}
@@ -0,0 +1,142 @@
+use actix_identity::Identity;
+use actix_web::{
+    get, post,
+    web::{self, Data, Path},
+    Responder,
+};
+use askama_actix::{Template, TemplateToResponse};
+use serde::Deserialize;
+
+use crate::{
+    db::{BlockingPool, PendingAuthorizationWithNames},
+    forms::FormResponseBuilder,
+    models::{format_expiry, Host, PendingAuthorization},
+    routes::RenderErrorTemplate,
+    ssh::CachingSshClient,
+    Configuration,
+};
+
+pub fn approvals_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(approvals_page)
+        .service(render_approvals)
+        .service(approve_authorization)
+        .service(reject_authorization);
+}
+
+#[derive(Deserialize)]
+struct ApprovalsQuery {
+    /// Also show approved and rejected authorizations
+    #[serde(default)]
+    all: bool,
+}
+
+#[derive(Template)]
+#[template(path = "approvals/index.html")]
+struct ApprovalsTemplate {
+    all: bool,
+    enabled: bool,
+}
+
+#[get("")]
+async fn approvals_page(
+    config: Data<Configuration>,
+    query: web::Query<ApprovalsQuery>,
+) -> impl Responder {
+    ApprovalsTemplate {
+        all: query.all,
+        enabled: config.authorization_approval,
+    }
+    .to_response()
+}
+
+#[derive(Template)]
+#[template(path = "approvals/approvals.htm")]
+struct ApprovalsListTemplate {
+    proposals: Vec<PendingAuthorizationWithNames>,
+}
+
+impl ApprovalsListTemplate {
+    fn expiry(proposal: &PendingAuthorization) -> Option<String> {
+        proposal.expires_at.map(format_expiry)
+    }
+}
+
+#[get("/approvals.htm")]
+async fn render_approvals(
+    db: Data<BlockingPool>,
+    query: web::Query<ApprovalsQuery>,
+) -> actix_web::Result<impl Responder> {
+    let all = query.all;
+    Ok(
+        match db
+            .run(move |conn| PendingAuthorization::get_all(conn, all))
+            .await?
+        {
+            Ok(proposals) => ApprovalsListTemplate { proposals }.to_response(),
+            Err(error) => RenderErrorTemplate { error }.to_response(),
+        },
+    )
+}
+
+/// Authorizes the user as proposed
+#[post("/{id}/approve")]
+async fn approve_authorization(
+    db: Data<BlockingPool>,
+    caching_ssh_client: Data<CachingSshClient>,
+    identity: Identity,
+    id: Path<i32>,
+) -> actix_web::Result<impl Responder> {
+    let username = identity.id().unwrap_or_default();
+    let id = id.into_inner();
+
+    let res = db
+        .run(move |conn| {
+            let proposal = PendingAuthorization::get_from_id(conn, id)?
+                .ok_or_else(|| String::from("Proposal not found"))?;
+            if proposal.proposed_by == username {
+                return Err(String::from("You can't approve your own proposal"));
+            }
+            proposal.approve(conn, &username)?;
+            Host::get_from_id_sync(conn, proposal.host_id)
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(host) => {
+            let message = match host {
+                Some(host) => {
+                    caching_ssh_client.remove(&host.name).await;
+                    format!("Approved, apply {} to deploy the keys", host.name)
+                }
+                None => String::from("Approved"),
+            };
+            FormResponseBuilder::success(message)
+        }
+        Err(error) => FormResponseBuilder::error(error),
+    }
+    .add_trigger(String::from("reload-approvals")))
+}
+
+#[post("/{id}/reject")]
+async fn reject_authorization(
+    db: Data<BlockingPool>,
+    identity: Identity,
+    id: Path<i32>,
+) -> actix_web::Result<impl Responder> {
+    let username = identity.id().unwrap_or_default();
+    let id = id.into_inner();
+
+    let res = db
+        .run(move |conn| {
+            PendingAuthorization::get_from_id(conn, id)?
+                .ok_or_else(|| String::from("Proposal not found"))?
+                .reject(conn, &username)
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(()) => FormResponseBuilder::success(String::from("Rejected the authorization")),
+        Err(error) => FormResponseBuilder::error(error),
+    }
+    .add_trigger(String::from("reload-approvals")))
+}
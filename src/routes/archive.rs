@@ -0,0 +1,91 @@
+use actix_web::{
+    get,
+    web::{self, Data, Path},
+    HttpRequest, Responder,
+};
+use askama_actix::{Template, TemplateToResponse};
+
+use crate::{
+    db::BlockingPool,
+    models::{ArchivedHost, OffboardingReport},
+    routes::{attachment, not_found, ErrorTemplate},
+};
+
+pub fn archive_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(archive_page)
+        .service(access_report)
+        .service(offboarding_report);
+}
+
+#[derive(Template)]
+#[template(path = "archive/index.html")]
+struct ArchiveTemplate {
+    hosts: Vec<ArchivedHost>,
+    offboardings: Vec<OffboardingReport>,
+}
+
+#[get("")]
+async fn archive_page(db: Data<BlockingPool>) -> actix_web::Result<impl Responder> {
+    let res = db
+        .run_read(|conn| {
+            ArchivedHost::get_all(conn).and_then(|hosts| {
+                OffboardingReport::get_all(conn).map(|offboardings| (hosts, offboardings))
+            })
+        })
+        .await?;
+
+    Ok(match res {
+        Ok((hosts, offboardings)) => ArchiveTemplate {
+            hosts,
+            offboardings,
+        }
+        .to_response(),
+        Err(error) => ErrorTemplate { error }.to_response(),
+    })
+}
+
+#[get("/{id}/report")]
+async fn access_report(
+    db: Data<BlockingPool>,
+    id: Path<i32>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+    Ok(
+        match db
+            .run_read(move |conn| ArchivedHost::get_from_id(conn, id))
+            .await?
+        {
+            Ok(Some(host)) => attachment(
+                "text/tab-separated-values; charset=utf-8",
+                format!("{}-access-report.tsv", host.name),
+                host.access_report,
+            ),
+            Ok(None) => not_found(&req, String::from("Archive entry not found")),
+            Err(error) => ErrorTemplate { error }.to_response(),
+        },
+    )
+}
+
+#[get("/offboarding/{id}/report")]
+async fn offboarding_report(
+    db: Data<BlockingPool>,
+    id: Path<i32>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+    Ok(
+        match db
+            .run_read(move |conn| OffboardingReport::get_from_id(conn, id))
+            .await?
+        {
+            Ok(Some(offboarding)) => attachment(
+                "text/plain; charset=utf-8",
+                format!("{}-offboarding-report.txt", offboarding.username),
+                offboarding.report,
+            ),
+            Ok(None) => not_found(&req, String::from("Report not found")),
+            Err(error) => ErrorTemplate { error }.to_response(),
+        },
+    )
+}
@@ -0,0 +1,95 @@
+use actix_web::{
+    get,
+    http::header,
+    web::{self, Data},
+    HttpResponse, Responder,
+};
+use askama_actix::{Template, TemplateToResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::{db::BlockingPool, models::AuditEntry, routes::ErrorTemplate};
+
+const ENTRIES_PER_PAGE: i64 = 50;
+
+pub fn audit_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(audit_page).service(export_audit_log);
+}
+
+#[derive(Deserialize)]
+struct PageQuery {
+    page: Option<i64>,
+}
+
+#[derive(Template)]
+#[template(path = "audit/index.html")]
+struct AuditTemplate {
+    entries: Vec<AuditEntry>,
+    page: i64,
+    pages: i64,
+}
+
+#[get("")]
+async fn audit_page(
+    db: Data<BlockingPool>,
+    query: web::Query<PageQuery>,
+) -> actix_web::Result<impl Responder> {
+    let page = query.page.unwrap_or(0).max(0);
+
+    let res = db
+        .run_read(move |conn| AuditEntry::get_page(conn, page, ENTRIES_PER_PAGE))
+        .await?;
+    Ok(match res {
+        Ok((entries, total)) => AuditTemplate {
+            entries,
+            page,
+            pages: (total + ENTRIES_PER_PAGE - 1) / ENTRIES_PER_PAGE,
+        }
+        .to_response(),
+        Err(error) => ErrorTemplate { error }.to_response(),
+    })
+}
+
+#[derive(Serialize)]
+struct ExportedEntry {
+    id: i32,
+    created_at: String,
+    actor: String,
+    client: Option<String>,
+    action: String,
+    target: String,
+    success: bool,
+    result: String,
+}
+
+impl From<AuditEntry> for ExportedEntry {
+    fn from(entry: AuditEntry) -> Self {
+        Self {
+            id: entry.id,
+            created_at: entry.created_at.to_string(),
+            actor: entry.actor,
+            client: entry.client,
+            action: entry.action,
+            target: entry.target,
+            success: entry.success,
+            result: entry.result,
+        }
+    }
+}
+
+#[get("/export.json")]
+async fn export_audit_log(db: Data<BlockingPool>) -> actix_web::Result<impl Responder> {
+    Ok(match db.run_read(AuditEntry::get_all).await? {
+        Ok(entries) => HttpResponse::Ok()
+            .insert_header((
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"audit_log.json\"",
+            ))
+            .json(
+                entries
+                    .into_iter()
+                    .map(ExportedEntry::from)
+                    .collect::<Vec<_>>(),
+            ),
+        Err(error) => ErrorTemplate { error }.to_response(),
+    })
+}
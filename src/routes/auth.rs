@@ -1,22 +1,39 @@
+use std::time::Duration;
+
 use actix_identity::Identity;
+use actix_session::Session;
 use actix_web::{
-    get, post,
-    web::{self, Data, Form},
+    get,
+    http::{
+        header::{self, HeaderValue},
+        StatusCode,
+    },
+    post,
+    web::{self, Data, Form, Query},
     HttpMessage, HttpRequest, HttpResponse, Responder,
 };
 use askama_actix::{Template, TemplateToResponse};
-use bcrypt::{verify, BcryptError};
-use log::error;
-use serde::Deserialize;
-use std::fs;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
 
-use crate::{Configuration, ConnectionPool};
+use crate::{
+    auth::{check_new_password, AuthBackend},
+    db::{BlockingPool, FAILURE, SUCCESS, THROTTLED},
+    models::{WebSession, WebUser},
+    oidc::{OidcLogin, PendingLogin},
+    proxy::{client_addr, url, Client},
+    totp, Configuration,
+};
 
 use super::ErrorTemplate;
 
 #[derive(Template)]
 #[template(path = "auth/login.html")]
-struct LoginTemplate {}
+struct LoginTemplate {
+    /// Label of the single sign-on button, if OpenID Connect is configured
+    oidc: Option<String>,
+}
 
 #[derive(Template)]
 #[template(path = "auth/status.html")]
@@ -30,84 +47,470 @@ pub struct LoginForm {
     password: String,
 }
 
-fn verify_apache_password(password: &str, hash: &str) -> Result<bool, BcryptError> {
-    // Apache htpasswd bcrypt format starts with $2y$
+#[get("/login")]
+async fn login_page(oidc: Option<Data<OidcLogin>>) -> impl Responder {
+    LoginTemplate {
+        oidc: oidc.map(|oidc| oidc.display_name().to_owned()),
+    }
+    .to_response()
+}
 
-    match &hash[..4] {
-        "$2y$" => {
-            let converted_hash = format!("$2b${}", &hash[4..]);
-            verify(password, &converted_hash)
-        }
-        "$2b$" => verify(password, hash),
-        hash_type => {
-            error!("Unsupported hash type '{hash_type}' encountered.");
-            Ok(false)
-        }
+/// Response for logins that have to wait after too many failures
+fn throttled(wait: Duration) -> HttpResponse {
+    let mut res = ErrorTemplate {
+        error: format!(
+            "Too many failed logins, please try again in {} second(s)",
+            wait.as_secs()
+        ),
     }
+    .to_response();
+    *res.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    res.headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(wait.as_secs()));
+    res
 }
 
-#[get("/login")]
-async fn login_page() -> impl Responder {
-    LoginTemplate {}.to_response()
+fn client_of(req: &HttpRequest) -> Option<String> {
+    req.extensions()
+        .get::<Client>()
+        .and_then(|client| client.addr)
+        .map(|addr| addr.to_string())
+}
+
+/// The response for logins that have to wait, recording them
+async fn check_throttling(
+    req: &HttpRequest,
+    db: &BlockingPool,
+    config: &Configuration,
+    username: &str,
+) -> actix_web::Result<Option<HttpResponse>> {
+    let (throttling, username, addr) = (
+        config.login_throttling.clone(),
+        username.to_owned(),
+        client_of(req),
+    );
+    let res = db
+        .run(move |conn| {
+            let wait = throttling.retry_after(conn, &username, addr.as_deref())?;
+            if wait.is_some() {
+                throttling.record(conn, username.clone(), addr, THROTTLED)?;
+            }
+            Ok::<_, String>(wait.map(|wait| (username, wait)))
+        })
+        .await?;
+    Ok(match res {
+        Ok(None) => None,
+        Ok(Some((username, wait))) => {
+            warn!("Throttled login of {username} from {}", client_addr(req));
+            Some(throttled(wait))
+        }
+        Err(error) => Some(ErrorTemplate { error }.to_response()),
+    })
+}
+
+/// Records a failed login
+async fn record_failure(
+    req: &HttpRequest,
+    db: &BlockingPool,
+    config: &Configuration,
+    username: &str,
+) -> actix_web::Result<Result<(), String>> {
+    warn!("Failed login of {username} from {}", client_addr(req));
+    let (throttling, username, client) = (
+        config.login_throttling.clone(),
+        username.to_owned(),
+        client_of(req),
+    );
+    Ok(db
+        .run(move |conn| throttling.record(conn, username, client, FAILURE))
+        .await?)
+}
+
+/// Creates the session of a user who passed every factor
+async fn finish_login(
+    req: &HttpRequest,
+    db: &BlockingPool,
+    config: &Configuration,
+    username: String,
+) -> actix_web::Result<HttpResponse> {
+    let (throttling, client, default_role) = (
+        config.login_throttling.clone(),
+        client_of(req),
+        config.default_role,
+    );
+    let name = username.clone();
+    let res = db
+        .run(move |conn| {
+            throttling.record(conn, name.clone(), client, SUCCESS)?;
+            WebUser::record_login(conn, &name, default_role)
+        })
+        .await?;
+    if let Err(error) = res {
+        return Ok(ErrorTemplate { error }.to_response());
+    }
+
+    info!("{username} logged in from {}", client_addr(req));
+    Identity::login(&req.extensions(), username)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Found()
+        .insert_header(("Location", "/"))
+        .finish())
 }
 
 #[post("/login")]
 async fn login(
     req: HttpRequest,
+    session: Session,
     form: Form<LoginForm>,
-    _pool: Data<ConnectionPool>,
+    auth_backend: Data<dyn AuthBackend>,
+    db: Data<BlockingPool>,
     config: Data<Configuration>,
 ) -> actix_web::Result<impl Responder> {
-    let htpasswd_path = config.htpasswd_path.as_path();
+    if let Some(response) = check_throttling(&req, &db, &config, &form.username).await? {
+        return Ok(response);
+    }
 
-    // Check if password file exists
-    if !htpasswd_path.exists() {
-        error!("Authentication file not found");
+    let is_valid = match auth_backend
+        .authenticate(&form.username, &form.password)
+        .await
+    {
+        Ok(valid) => valid,
+        Err(error) => return Ok(ErrorTemplate { error }.to_response()),
+    };
+    if !is_valid {
+        if let Err(error) = record_failure(&req, &db, &config, &form.username).await? {
+            return Ok(ErrorTemplate { error }.to_response());
+        }
         return Ok(ErrorTemplate {
-            error: "Authentication file not found".to_owned(),
+            error: "Invalid credentials".to_owned(),
         }
         .to_response());
     }
 
-    // Read and verify credentials from password file
-    let password_file = match fs::read_to_string(htpasswd_path) {
-        Ok(content) => content,
-        Err(e) => {
-            error!("Error reading authentication file: {e}");
-            return Ok(ErrorTemplate {
-                error: "Error reading authentication file".to_owned(),
-            }
-            .to_response());
+    let username = form.username.clone();
+    let web_user = match db
+        .run(move |conn| WebUser::get_by_username(conn, &username))
+        .await?
+    {
+        Ok(web_user) => web_user,
+        Err(error) => return Ok(ErrorTemplate { error }.to_response()),
+    };
+    // The session is only created once the code is entered too
+    if web_user.is_some_and(|web_user| web_user.has_totp()) {
+        let pending = PendingTotp {
+            username: form.username.clone(),
+            started: OffsetDateTime::now_utc().unix_timestamp(),
+        };
+        session
+            .insert(TOTP_SESSION_KEY, pending)
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+        return Ok(HttpResponse::Found()
+            .insert_header(("Location", "/auth/totp"))
+            .finish());
+    }
+
+    finish_login(&req, &db, &config, form.username.clone()).await
+}
+
+const TOTP_SESSION_KEY: &str = "totp_pending_login";
+
+/// Seconds to enter the code after the password
+const TOTP_TIMEOUT: i64 = 300;
+
+/// A login whose password was correct, waiting for the TOTP code
+#[derive(Serialize, Deserialize)]
+struct PendingTotp {
+    username: String,
+    /// Unix timestamp of the password check
+    started: i64,
+}
+
+#[derive(Template)]
+#[template(path = "auth/totp.html")]
+struct TotpTemplate {
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TotpForm {
+    code: String,
+}
+
+/// The login waiting for a TOTP code in this session, unless it timed out
+fn pending_totp(session: &Session) -> Option<PendingTotp> {
+    session
+        .get::<PendingTotp>(TOTP_SESSION_KEY)
+        .ok()
+        .flatten()
+        .filter(|pending| {
+            OffsetDateTime::now_utc().unix_timestamp() - pending.started <= TOTP_TIMEOUT
+        })
+}
+
+#[get("/totp")]
+async fn totp_page(session: Session) -> impl Responder {
+    if pending_totp(&session).is_none() {
+        return HttpResponse::Found()
+            .insert_header(("Location", "/auth/login"))
+            .finish();
+    }
+    TotpTemplate { error: None }.to_response()
+}
+
+#[post("/totp")]
+async fn totp_login(
+    req: HttpRequest,
+    session: Session,
+    form: Form<TotpForm>,
+    db: Data<BlockingPool>,
+    config: Data<Configuration>,
+) -> actix_web::Result<impl Responder> {
+    let Some(pending) = pending_totp(&session) else {
+        return Ok(ErrorTemplate {
+            error: String::from("No login in progress, please enter your password again"),
         }
+        .to_response());
     };
+    if let Some(response) = check_throttling(&req, &db, &config, &pending.username).await? {
+        return Ok(response);
+    }
 
-    let mut is_valid = false;
-    for line in password_file.lines() {
-        if let Some((username, hash)) = line.split_once(':') {
-            if username == form.username {
-                match verify_apache_password(&form.password, hash) {
-                    Ok(valid) => {
-                        is_valid = valid;
-                        break;
-                    }
-                    Err(_) => continue,
-                }
+    let (username, code) = (pending.username.clone(), form.into_inner().code);
+    let res = db
+        .run(move |conn| {
+            let web_user = WebUser::get_by_username(conn, &username)?
+                .ok_or_else(|| String::from("Unknown user"))?;
+            let Some(secret) = &web_user.totp_secret else {
+                return Ok(true);
+            };
+            match totp::verify(secret, &code, web_user.totp_last_step) {
+                Some(step) => web_user.use_totp_step(conn, step).map(|()| true),
+                None => web_user.use_recovery_code(conn, &code),
+            }
+        })
+        .await?;
+
+    match res {
+        Ok(true) => {
+            session.remove(TOTP_SESSION_KEY);
+            finish_login(&req, &db, &config, pending.username).await
+        }
+        Ok(false) => {
+            if let Err(error) = record_failure(&req, &db, &config, &pending.username).await? {
+                return Ok(ErrorTemplate { error }.to_response());
+            }
+            Ok(TotpTemplate {
+                error: Some(String::from("Invalid code")),
             }
+            .to_response())
         }
+        Err(error) => Ok(TotpTemplate { error: Some(error) }.to_response()),
     }
+}
 
-    if is_valid {
-        Identity::login(&req.extensions(), form.username.clone())
-            .map_err(actix_web::error::ErrorInternalServerError)?;
-        Ok(HttpResponse::Found()
-            .insert_header(("Location", "/"))
-            .finish())
-    } else {
-        Ok(ErrorTemplate {
-            error: "Invalid credentials".to_owned(),
+#[derive(Template)]
+#[template(path = "auth/reset.html")]
+struct ResetPasswordTemplate {
+    token: String,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ResetQuery {
+    token: String,
+}
+
+fn invalid_reset_link() -> HttpResponse {
+    ErrorTemplate {
+        error: String::from("This link is invalid or expired, please ask an admin for a new one"),
+    }
+    .to_response()
+}
+
+/// Opened from the link mailed by an admin
+#[get("/reset")]
+async fn reset_password_page(
+    db: Data<BlockingPool>,
+    query: Query<ResetQuery>,
+) -> actix_web::Result<impl Responder> {
+    let token = query.into_inner().token;
+    let lookup = token.clone();
+    Ok(
+        match db
+            .run(move |conn| WebUser::get_by_password_reset(conn, &lookup))
+            .await?
+        {
+            Ok(Some(_)) => ResetPasswordTemplate { token, error: None }.to_response(),
+            Ok(None) => invalid_reset_link(),
+            Err(error) => ErrorTemplate { error }.to_response(),
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct ResetPasswordForm {
+    token: String,
+    new_password: String,
+    repeated_password: String,
+}
+
+#[post("/reset")]
+async fn reset_password(
+    req: HttpRequest,
+    db: Data<BlockingPool>,
+    auth_backend: Data<dyn AuthBackend>,
+    form: Form<ResetPasswordForm>,
+) -> actix_web::Result<impl Responder> {
+    let form = form.into_inner();
+    let token = form.token.clone();
+    let web_user = match db
+        .run(move |conn| WebUser::get_by_password_reset(conn, &token))
+        .await?
+    {
+        Ok(Some(web_user)) => web_user,
+        Ok(None) => return Ok(invalid_reset_link()),
+        Err(error) => return Ok(ErrorTemplate { error }.to_response()),
+    };
+    if let Err(error) = check_new_password(&form.new_password, &form.repeated_password) {
+        return Ok(ResetPasswordTemplate {
+            token: form.token,
+            error: Some(error),
         }
-        .to_response())
+        .to_response());
+    }
+    if let Err(error) = auth_backend
+        .set_password(&web_user.username, &form.new_password)
+        .await
+    {
+        return Ok(ErrorTemplate { error }.to_response());
+    }
+
+    let username = web_user.username.clone();
+    let res = db
+        .run(move |conn| {
+            web_user.finish_password_reset(conn)?;
+            WebSession::delete_for_user(conn, &web_user.username)
+        })
+        .await?;
+    if let Err(error) = res {
+        return Ok(ErrorTemplate { error }.to_response());
     }
+    info!(
+        "{username} chose a new password with a reset link from {}",
+        client_addr(&req)
+    );
+    Ok(HttpResponse::Found()
+        .insert_header(("Location", "/auth/login"))
+        .finish())
+}
+
+const OIDC_SESSION_KEY: &str = "oidc_pending_login";
+
+fn oidc_error(error: String) -> HttpResponse {
+    error!("OpenID Connect login failed: {error}");
+    ErrorTemplate { error }.to_response()
+}
+
+#[get("/oidc")]
+async fn oidc_login(
+    session: Session,
+    oidc: Option<Data<OidcLogin>>,
+) -> actix_web::Result<impl Responder> {
+    let Some(oidc) = oidc else {
+        return Ok(oidc_error(String::from("Single sign-on is not configured")));
+    };
+
+    let (url, pending) = match oidc.start().await {
+        Ok(res) => res,
+        Err(error) => return Ok(oidc_error(error)),
+    };
+    session
+        .insert(OIDC_SESSION_KEY, pending)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Found()
+        .insert_header(("Location", url))
+        .finish())
+}
+
+#[derive(Deserialize)]
+struct OidcCallback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+#[get("/oidc/callback")]
+async fn oidc_callback(
+    req: HttpRequest,
+    session: Session,
+    oidc: Option<Data<OidcLogin>>,
+    db: Data<BlockingPool>,
+    auth_backend: Data<dyn AuthBackend>,
+    config: Data<Configuration>,
+    callback: Query<OidcCallback>,
+) -> actix_web::Result<impl Responder> {
+    let Some(oidc) = oidc else {
+        return Ok(oidc_error(String::from("Single sign-on is not configured")));
+    };
+    let callback = callback.into_inner();
+
+    if let Some(error) = callback.error {
+        return Ok(oidc_error(match callback.error_description {
+            Some(description) => format!("{error}: {description}"),
+            None => error,
+        }));
+    }
+    let (Some(code), Some(state)) = (callback.code, callback.state) else {
+        return Ok(oidc_error(String::from(
+            "The identity provider didn't return an authorization code",
+        )));
+    };
+    // Each login attempt can only be completed once
+    let Some(Ok(pending)) = session.remove_as::<PendingLogin>(OIDC_SESSION_KEY) else {
+        return Ok(oidc_error(String::from(
+            "No login in progress, please try again",
+        )));
+    };
+
+    let identity = match oidc.finish(pending, &state, code).await {
+        Ok(identity) => identity,
+        Err(error) => return Ok(oidc_error(error)),
+    };
+
+    // Don't let the identity provider hand out sessions of local users
+    let is_local_user = match auth_backend.has_user(&identity.username).await {
+        Ok(exists) => exists,
+        Err(error) => return Ok(oidc_error(error)),
+    };
+    if is_local_user {
+        return Ok(oidc_error(format!(
+            "The username '{}' is reserved for a local account",
+            identity.username
+        )));
+    }
+
+    let default_role = config.default_role;
+    let username = match db
+        .run(move |conn| {
+            WebUser::provision_oidc(conn, identity.subject, identity.username, default_role)
+        })
+        .await?
+    {
+        Ok(username) => username,
+        Err(error) => return Ok(oidc_error(error)),
+    };
+
+    info!(
+        "{username} logged in via single sign-on from {}",
+        client_addr(&req)
+    );
+    Identity::login(&req.extensions(), username)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Found()
+        .insert_header(("Location", "/"))
+        .finish())
 }
 
 #[post("/logout")]
@@ -115,7 +518,7 @@ async fn logout(identity: Identity) -> impl Responder {
     identity.logout();
     HttpResponse::Ok()
         .insert_header(("HX-Redirect", "/auth/login"))
-        .body("<a href=\"/auth/login\">Login</a>")
+        .body(format!("<a href=\"{}\">Login</a>", url("/auth/login")))
 }
 
 #[get("/status")]
@@ -129,6 +532,12 @@ async fn auth_status(identity: Option<Identity>) -> impl Responder {
 pub fn auth_config(cfg: &mut web::ServiceConfig) {
     cfg.service(login_page)
         .service(login)
+        .service(totp_page)
+        .service(totp_login)
+        .service(reset_password_page)
+        .service(reset_password)
+        .service(oidc_login)
+        .service(oidc_callback)
         .service(logout)
         .service(auth_status);
 }
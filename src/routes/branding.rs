@@ -0,0 +1,29 @@
+use actix_web::{
+    get,
+    http::header::{CacheControl, CacheDirective},
+    web, HttpResponse,
+};
+
+use crate::branding;
+
+pub fn branding_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(logo);
+}
+
+/// The configured logo. Public, the login page shows it too.
+#[get("/branding/logo")]
+async fn logo() -> HttpResponse {
+    let Some(logo) = branding::logo() else {
+        return HttpResponse::NotFound().finish();
+    };
+    HttpResponse::Ok()
+        .content_type(logo.content_type)
+        .insert_header(CacheControl(vec![CacheDirective::MaxAge(3600)]))
+        .insert_header(("X-Content-Type-Options", "nosniff"))
+        // SVG logos may contain scripts, which mustn't run when the logo is opened directly
+        .insert_header((
+            "Content-Security-Policy",
+            "default-src 'none'; style-src 'unsafe-inline'",
+        ))
+        .body(logo.data.as_slice())
+}
@@ -1,32 +1,43 @@
 use crate::{
+    jobs::{is_finished, Step, StepStatus},
     routes::{should_update, ForceUpdate},
-    ssh::{CachingSshClient, DiffItem, SshClientError},
+    ssh::{describe, AuthorizedKey, CachingSshClient, DiffItem, SshClientError},
     templates::AsHTML,
 };
 use actix_web::{
     get, post,
     web::{self, Data, Path},
-    Responder,
+    HttpRequest, Responder,
 };
 use askama_actix::{Template, TemplateToResponse};
 use serde::Deserialize;
 use time::OffsetDateTime;
 
 use crate::{
+    bulk_apply::BulkApplier,
+    db::BlockingPool,
     forms::{FormResponseBuilder, Modal},
-    routes::{ErrorTemplate, RenderErrorTemplate},
-    ssh::SshPublicKey,
-    ConnectionPool,
+    routes::{not_found, ErrorTemplate, RenderErrorTemplate},
+    ssh::{SshClient, SshPublicKey},
 };
 
 use crate::models::{Host, User};
 
 pub fn diff_config(cfg: &mut web::ServiceConfig) {
-    cfg.service(diff_page)
+    // Before the per host routes, which would match these paths too
+    cfg.service(apply_all_status)
+        .service(apply_all_dialog)
+        .service(apply_all)
+        .service(changes_page)
+        .service(render_changes)
+        .service(diff_page)
         .service(render_diff)
         .service(show_diff)
         .service(assign_key_dialog)
-        .service(authorize_user_dialog);
+        .service(assign_keys_dialog)
+        .service(authorize_user_dialog)
+        .service(apply_dialog)
+        .service(apply_diff);
 }
 
 #[derive(Template)]
@@ -36,8 +47,8 @@ struct DiffPageTemplate {
 }
 
 #[get("")]
-async fn diff_page(conn: Data<ConnectionPool>) -> actix_web::Result<impl Responder> {
-    let hosts = web::block(move || Host::get_all_hosts(&mut conn.get().unwrap())).await?;
+async fn diff_page(db: Data<BlockingPool>) -> actix_web::Result<impl Responder> {
+    let hosts = db.run(Host::get_all_hosts).await?;
 
     Ok(match hosts {
         Ok(hosts) => DiffPageTemplate { hosts }.to_response(),
@@ -51,38 +62,60 @@ struct RenderDiffTemplate {
     host: Host,
     diff: Result<Vec<(String, Vec<DiffItem>)>, SshClientError>,
     cached_from: OffsetDateTime,
+    /// Whether unknown keys can be selected for assigning them at once
+    has_unknown_keys: bool,
+}
+
+#[derive(Template)]
+#[template(path = "diff/pending.htm")]
+struct PendingDiffTemplate {
+    host: Host,
 }
 
 #[get("/{host_name}.htm")]
 async fn render_diff(
-    conn: Data<ConnectionPool>,
+    db: Data<BlockingPool>,
     caching_ssh_client: Data<CachingSshClient>,
     host_name: Path<String>,
     force_update: ForceUpdate,
+    req: HttpRequest,
 ) -> actix_web::Result<impl Responder> {
-    let res = Host::get_from_name(conn.get().unwrap(), host_name.to_string()).await;
+    let res = Host::get_from_name(&db, host_name.to_string()).await;
 
     let host = match res {
         Ok(maybe_host) => {
             let Some(host) = maybe_host else {
-                return Ok(RenderErrorTemplate {
-                    error: String::from("No such host."),
-                }
-                .to_response());
+                return Ok(not_found(&req, String::from("No such host.")));
             };
             host
         }
         Err(error) => return Ok(RenderErrorTemplate { error }.to_response()),
     };
 
-    let (cached_from, diff) = caching_ssh_client
-        .get_host_diff(host.clone(), should_update(force_update))
-        .await;
+    let force_update = should_update(force_update);
+    // With the background sync running, hosts aren't connected to while the page loads
+    let (cached_from, diff) = if force_update || !caching_ssh_client.is_syncing() {
+        caching_ssh_client
+            .get_host_diff(host.clone(), force_update)
+            .await
+    } else if let Some(cached) = caching_ssh_client.get_cached_host_diff(host.clone()).await {
+        cached
+    } else {
+        return Ok(PendingDiffTemplate { host }.to_response());
+    };
+
+    let has_unknown_keys = diff.as_ref().is_ok_and(|logins| {
+        logins
+            .iter()
+            .flat_map(|(_, items)| items)
+            .any(|item| matches!(item, DiffItem::UnknownKey(_)))
+    });
 
     Ok(RenderDiffTemplate {
         host,
         diff,
         cached_from,
+        has_unknown_keys,
     }
     .to_response())
 }
@@ -95,17 +128,15 @@ struct ShowDiffTemplate {
 
 #[get("/{name}")]
 async fn show_diff(
-    conn: Data<ConnectionPool>,
+    db: Data<BlockingPool>,
     host_name: Path<String>,
+    req: HttpRequest,
 ) -> actix_web::Result<impl Responder> {
     Ok(
-        match Host::get_from_name(conn.get().unwrap(), host_name.to_string()).await {
+        match Host::get_from_name(&db, host_name.to_string()).await {
             Ok(host) => {
                 let Some(host) = host else {
-                    return Ok(ErrorTemplate {
-                        error: String::from("Host not found"),
-                    }
-                    .to_response());
+                    return Ok(not_found(&req, String::from("Host not found")));
                 };
                 ShowDiffTemplate { host }.to_response()
             }
@@ -123,10 +154,10 @@ struct AssignKeyDialog {
 
 #[post("/assign_key_dialog")]
 async fn assign_key_dialog(
-    conn: Data<ConnectionPool>,
+    db: Data<BlockingPool>,
     key: web::Form<SshPublicKey>,
 ) -> actix_web::Result<impl Responder> {
-    let res = web::block(move || User::get_all_users(&mut conn.get().unwrap())).await?;
+    let res = db.run(User::get_all_users).await?;
 
     Ok(match res {
         Ok(users) => FormResponseBuilder::dialog(Modal {
@@ -138,12 +169,53 @@ async fn assign_key_dialog(
     })
 }
 
+#[derive(Template)]
+#[template(path = "diff/assign_keys_dialog.htm")]
+struct AssignKeysDialog {
+    /// Selected keys in OpenSSH format
+    keys: Vec<String>,
+    users: Vec<User>,
+}
+
+#[post("/assign_keys_dialog")]
+async fn assign_keys_dialog(
+    db: Data<BlockingPool>,
+    form: web::Form<Vec<(String, String)>>,
+) -> actix_web::Result<impl Responder> {
+    let mut keys: Vec<String> = form
+        .0
+        .into_iter()
+        .filter_map(|(name, value)| (name == "key").then_some(value))
+        .collect();
+    // The same key may be unknown for several logins
+    keys.sort();
+    keys.dedup();
+    if keys.is_empty() {
+        return Ok(FormResponseBuilder::error(String::from(
+            "Please select at least one key",
+        )));
+    }
+
+    let res = db.run(User::get_all_users).await?;
+
+    Ok(match res {
+        Ok(users) => FormResponseBuilder::dialog(Modal {
+            title: format!("Assign {} keys to a user", keys.len()),
+            request_target: String::from("/users/assign_keys"),
+            template: AssignKeysDialog { keys, users }.to_string(),
+        }),
+        Err(error) => FormResponseBuilder::error(error),
+    })
+}
+
 #[derive(Template)]
 #[template(path = "diff/authorize_user_dialog.htm")]
 struct AuthorizeUserDialog {
     host: (String, i32),
     user: (String, i32),
     login: String,
+    /// Why logging in with the key might fail anyway
+    advisory: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -158,21 +230,18 @@ struct AuthorizeUserForm {
 
 #[post("/authorize_user_dialog")]
 async fn authorize_user_dialog(
-    conn: Data<ConnectionPool>,
+    db: Data<BlockingPool>,
+    ssh_client: Data<SshClient>,
     form: web::Form<AuthorizeUserForm>,
 ) -> actix_web::Result<impl Responder> {
     let login = form.login.clone();
-    let (user, host) = web::block(move || {
-        let mut connection = conn.get().unwrap();
-
-        let user = User::get_user(&mut connection, form.username.clone());
-        let host = Host::get_from_name_sync(&mut connection, form.host_name.clone());
-        (
-            user.map(|u| (u.username, u.id)),
-            host.map(|h| h.map(|h| (h.name, h.id))),
-        )
-    })
-    .await?;
+    let (user, host) = db
+        .run(move |conn| {
+            let user = User::get_user(conn, form.username.clone());
+            let host = Host::get_from_name_sync(conn, form.host_name.clone());
+            (user.map(|u| (u.username, u.id)), host)
+        })
+        .await?;
 
     let user = match user {
         Ok(u) => u,
@@ -182,14 +251,283 @@ async fn authorize_user_dialog(
     let host = match host {
         Ok(h) => match h {
             Some(h) => h,
-            None => return Ok(FormResponseBuilder::error(String::from("Host not found"))),
+            None => {
+                return Ok(FormResponseBuilder::not_found(String::from(
+                    "Host not found",
+                )))
+            }
         },
         Err(error) => return Ok(FormResponseBuilder::error(error)),
     };
 
+    let advisory = if login == "root" {
+        ssh_client.root_login_advisory(host.clone()).await
+    } else {
+        None
+    };
+
     Ok(FormResponseBuilder::dialog(Modal {
         title: String::from("Authorize user"),
         request_target: String::from("/hosts/user/authorize"),
-        template: AuthorizeUserDialog { host, user, login }.to_string(),
+        template: AuthorizeUserDialog {
+            host: (host.name, host.id),
+            user,
+            login,
+            advisory,
+        }
+        .to_string(),
     }))
 }
+
+#[derive(Template)]
+#[template(path = "diff/apply_dialog.htm")]
+struct ApplyDialog {
+    /// Logins with differences and how many
+    logins: Vec<(String, usize)>,
+}
+
+#[post("/{name}/apply_dialog")]
+async fn apply_dialog(
+    db: Data<BlockingPool>,
+    caching_ssh_client: Data<CachingSshClient>,
+    host_name: Path<String>,
+) -> actix_web::Result<impl Responder> {
+    let host = match Host::get_from_name(&db, host_name.to_string()).await {
+        Ok(Some(host)) => host,
+        Ok(None) => {
+            return Ok(FormResponseBuilder::not_found(String::from(
+                "No such host.",
+            )))
+        }
+        Err(error) => return Ok(FormResponseBuilder::error(error)),
+    };
+
+    let logins = match caching_ssh_client
+        .get_host_diff(host.clone(), false)
+        .await
+        .1
+    {
+        Ok(diff) => diff
+            .into_iter()
+            .map(|(login, items)| (login, items.len()))
+            .collect(),
+        Err(error) => return Ok(FormResponseBuilder::error(error.to_string())),
+    };
+
+    Ok(FormResponseBuilder::dialog(Modal {
+        title: format!("Apply the database state to '{}'?", host.name),
+        request_target: format!("/diff/{}/apply", host.name),
+        template: ApplyDialog { logins }.to_string(),
+    }))
+}
+
+#[post("/{name}/apply")]
+async fn apply_diff(
+    db: Data<BlockingPool>,
+    ssh_client: Data<SshClient>,
+    caching_ssh_client: Data<CachingSshClient>,
+    host_name: Path<String>,
+) -> actix_web::Result<impl Responder> {
+    let host = match Host::get_from_name(&db, host_name.to_string()).await {
+        Ok(Some(host)) => host,
+        Ok(None) => {
+            return Ok(FormResponseBuilder::not_found(String::from(
+                "No such host.",
+            )))
+        }
+        Err(error) => return Ok(FormResponseBuilder::error(error)),
+    };
+
+    let res = ssh_client.apply_authorized_keys(host.clone()).await;
+    // Refresh the cache, so the diff shows what was actually written
+    let _ = caching_ssh_client.get_host_diff(host, true).await;
+
+    Ok(match res {
+        Ok(logins) => FormResponseBuilder::success(format!(
+            "Rewrote authorized_keys of {}",
+            logins.join(", ")
+        ))
+        .add_trigger(String::from("reloadDiff")),
+        Err(error) => {
+            FormResponseBuilder::error(error.to_string()).add_trigger(String::from("reloadDiff"))
+        }
+    })
+}
+
+#[derive(Template)]
+#[template(path = "diff/apply_all.htm")]
+struct ApplyAllStatusTemplate {
+    /// One step per host, after the validation webhook if configured
+    steps: Option<Vec<Step>>,
+}
+
+impl ApplyAllStatusTemplate {
+    fn finished(&self) -> bool {
+        self.steps.as_deref().is_none_or(is_finished)
+    }
+
+    fn count(&self, failed: bool) -> usize {
+        self.steps.as_deref().map_or(0, |steps| {
+            steps
+                .iter()
+                .filter(|step| matches!(step.status, StepStatus::Failed(_)) == failed)
+                .count()
+        })
+    }
+}
+
+#[get("/apply_all.htm")]
+async fn apply_all_status(bulk_applier: Data<BulkApplier>) -> impl Responder {
+    ApplyAllStatusTemplate {
+        steps: bulk_applier.status().await,
+    }
+}
+
+#[post("/apply_all_dialog")]
+async fn apply_all_dialog(db: Data<BlockingPool>) -> actix_web::Result<impl Responder> {
+    let hosts = db.run(Host::get_all_hosts).await?;
+
+    Ok(match hosts {
+        Ok(hosts) => FormResponseBuilder::dialog(Modal {
+            title: format!("Apply the database state to all {} hosts?", hosts.len()),
+            request_target: String::from("/diff/apply_all"),
+            template: ApplyAllDialog {}.to_string(),
+        }),
+        Err(error) => FormResponseBuilder::error(error),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "diff/apply_all_dialog.htm")]
+struct ApplyAllDialog {}
+
+#[post("/apply_all")]
+async fn apply_all(bulk_applier: Data<BulkApplier>) -> impl Responder {
+    match bulk_applier.start().await {
+        Ok(hosts) => FormResponseBuilder::success(format!("Applying to {hosts} hosts"))
+            .add_trigger(String::from("reload-apply-all")),
+        Err(error) => FormResponseBuilder::error(error),
+    }
+}
+
+/// What applying the database state changes in the authorized_keys of a login
+#[derive(Default)]
+struct LoginChanges {
+    login: String,
+    /// Keys that are written
+    add: Vec<String>,
+    /// Keys that are removed, with the reason
+    remove: Vec<String>,
+    /// Other drift, e.g. faulty lines or a file that isn't managed yet
+    other: Vec<String>,
+}
+
+fn key_name(key: &AuthorizedKey) -> String {
+    format!(
+        "{} {}",
+        key.algorithm,
+        key.comment.as_deref().unwrap_or("(no comment)")
+    )
+}
+
+impl LoginChanges {
+    fn new(login: String, items: &[DiffItem]) -> Self {
+        let mut changes = Self {
+            login,
+            ..Default::default()
+        };
+        for item in items {
+            match item {
+                DiffItem::KeyMissing(key, username) => {
+                    changes.add.push(format!("{} of {username}", key_name(key)))
+                }
+                DiffItem::UnknownKey(key) => {
+                    changes.remove.push(format!("{}, unknown", key_name(key)))
+                }
+                DiffItem::UnauthorizedKey(key, username) => changes
+                    .remove
+                    .push(format!("{} of {username}, not authorized", key_name(key))),
+                DiffItem::ExpiredKey(key, username) => changes
+                    .remove
+                    .push(format!("{} of {username}, expired", key_name(key))),
+                DiffItem::DuplicateKey(key) => {
+                    changes.remove.push(format!("{}, duplicate", key_name(key)))
+                }
+                DiffItem::FaultyKey(_, _) | DiffItem::PragmaMissing | DiffItem::Certificate(_) => {
+                    changes.other.push(describe(item));
+                }
+            }
+        }
+        changes
+    }
+}
+
+/// The changes of a host as of its last check
+struct HostChanges {
+    host: Host,
+    /// None if the host wasn't checked yet
+    checked: Option<OffsetDateTime>,
+    /// Changes per login, or why the host couldn't be read
+    changes: Result<Vec<LoginChanges>, String>,
+}
+
+#[derive(Template)]
+#[template(path = "diff/changes.html")]
+struct ChangesPageTemplate {}
+
+/// Everything that differs between the database and the hosts, as last seen
+#[get("/changes")]
+async fn changes_page() -> impl Responder {
+    ChangesPageTemplate {}
+}
+
+#[derive(Template)]
+#[template(path = "diff/changes.htm")]
+struct ChangesTemplate {
+    /// Hosts with changes, errors or not checked yet
+    hosts: Vec<HostChanges>,
+    /// Number of hosts matching the database
+    in_sync: usize,
+}
+
+#[get("/changes.htm")]
+async fn render_changes(
+    db: Data<BlockingPool>,
+    caching_ssh_client: Data<CachingSshClient>,
+) -> actix_web::Result<impl Responder> {
+    let hosts = match db.run(Host::get_all_hosts).await? {
+        Ok(hosts) => hosts,
+        Err(error) => return Ok(RenderErrorTemplate { error }.to_response()),
+    };
+
+    let mut changed = Vec::new();
+    let mut in_sync = 0;
+    for host in hosts {
+        let (checked, changes) = match caching_ssh_client.get_cached_host_diff(host.clone()).await {
+            Some((checked, Ok(logins))) => (
+                Some(checked),
+                Ok(logins
+                    .into_iter()
+                    .map(|(login, items)| LoginChanges::new(login, &items))
+                    .collect::<Vec<_>>()),
+            ),
+            Some((checked, Err(error))) => (Some(checked), Err(error.to_string())),
+            None => (None, Ok(Vec::new())),
+        };
+        if checked.is_some() && changes.as_ref().is_ok_and(Vec::is_empty) {
+            in_sync += 1;
+            continue;
+        }
+        changed.push(HostChanges {
+            host,
+            checked,
+            changes,
+        });
+    }
+
+    Ok(ChangesTemplate {
+        hosts: changed,
+        in_sync,
+    }
+    .to_response())
+}
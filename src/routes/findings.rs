@@ -0,0 +1,121 @@
+use actix_identity::Identity;
+use actix_web::{
+    get, post,
+    web::{self, Data, Path},
+    Responder,
+};
+use askama_actix::{Template, TemplateToResponse};
+use serde::Deserialize;
+
+use crate::{
+    anomalies::AnomalyDetector,
+    db::{BlockingPool, ACKNOWLEDGED, DISMISSED},
+    forms::FormResponseBuilder,
+    models::Finding,
+    policy::PolicyScanner,
+    routes::RenderErrorTemplate,
+};
+
+pub fn findings_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(findings_page)
+        .service(render_findings)
+        .service(analyze)
+        .service(acknowledge_finding)
+        .service(dismiss_finding);
+}
+
+#[derive(Deserialize)]
+struct FindingsQuery {
+    /// Also show acknowledged and dismissed findings
+    #[serde(default)]
+    all: bool,
+}
+
+#[derive(Template)]
+#[template(path = "findings/index.html")]
+struct FindingsTemplate {
+    all: bool,
+}
+
+#[get("")]
+async fn findings_page(query: web::Query<FindingsQuery>) -> impl Responder {
+    FindingsTemplate { all: query.all }.to_response()
+}
+
+#[derive(Template)]
+#[template(path = "findings/findings.htm")]
+struct FindingsListTemplate {
+    findings: Vec<Finding>,
+}
+
+#[get("/findings.htm")]
+async fn render_findings(
+    db: Data<BlockingPool>,
+    query: web::Query<FindingsQuery>,
+) -> actix_web::Result<impl Responder> {
+    let all = query.all;
+    Ok(
+        match db.run(move |conn| Finding::get_all(conn, all)).await? {
+            Ok(findings) => FindingsListTemplate { findings }.to_response(),
+            Err(error) => RenderErrorTemplate { error }.to_response(),
+        },
+    )
+}
+
+/// Runs the anomaly detection and the policy scan now instead of waiting for the next interval
+#[post("/analyze")]
+async fn analyze(
+    detector: Data<AnomalyDetector>,
+    policy_scanner: Data<PolicyScanner>,
+) -> impl Responder {
+    let raised = match detector.run().await {
+        Ok(anomalies) => policy_scanner
+            .run()
+            .await
+            .map(|violations| anomalies + violations),
+        Err(error) => Err(error),
+    };
+    match raised {
+        Ok(0) => FormResponseBuilder::success(String::from("Nothing new found")),
+        Ok(raised) => FormResponseBuilder::success(format!("Found {raised} new findings")),
+        Err(error) => FormResponseBuilder::error(error),
+    }
+    .add_trigger(String::from("reload-findings"))
+}
+
+async fn decide(
+    db: Data<BlockingPool>,
+    identity: Identity,
+    id: i32,
+    status: &'static str,
+) -> actix_web::Result<FormResponseBuilder> {
+    let username = identity.id().unwrap_or_default();
+    let res = db
+        .run(move |conn| Finding::decide(conn, id, status, &username))
+        .await?;
+    Ok(match res {
+        Ok(()) => FormResponseBuilder::success(format!("The finding is {status} now")),
+        Err(error) => FormResponseBuilder::error(error),
+    }
+    .add_trigger(String::from("reload-findings")))
+}
+
+/// Marks a finding as seen and being looked into
+#[post("/{id}/acknowledge")]
+async fn acknowledge_finding(
+    db: Data<BlockingPool>,
+    identity: Identity,
+    id: Path<i32>,
+) -> actix_web::Result<impl Responder> {
+    decide(db, identity, id.into_inner(), ACKNOWLEDGED).await
+}
+
+/// Marks a finding as harmless, so it isn't raised again
+#[post("/{id}/dismiss")]
+async fn dismiss_finding(
+    db: Data<BlockingPool>,
+    identity: Identity,
+    id: Path<i32>,
+) -> actix_web::Result<impl Responder> {
+    decide(db, identity, id.into_inner(), DISMISSED).await
+}
@@ -1,81 +1,92 @@
+use std::collections::{BTreeMap, HashMap};
+
 use actix_web::{
-    get, post,
+    get,
+    http::StatusCode,
+    post,
     web::{self, Data, Path},
-    Responder,
+    HttpRequest, Responder,
 };
 use askama_actix::{Template, TemplateToResponse};
-use log::{debug, info};
+use log::{debug, info, warn};
+use qrcode::{render::svg, QrCode};
 use serde::Deserialize;
 
 use crate::{
-    db::UserAndOptions,
+    db::{
+        now, BlockingPool, HostData, HostDataError, HostImport, HostImportFormat, UserAndOptions,
+        HOST_CSV_COLUMNS, PENDING,
+    },
+    decommission::{Decommissioner, RELEASE_STEP},
     forms::{FormResponseBuilder, Modal},
-    routes::{should_update, ErrorTemplate, ForceUpdate, RenderErrorTemplate},
-    ssh::{CachingSshClient, ConnectionDetails, KeyDiffItem, SshClient, SshClientError},
-    ConnectionPool, DbConnection,
+    jobs::{is_finished, Step, StepStatus},
+    notifications::Notifier,
+    routes::{
+        actor_of, attachment, not_found, proposer, should_update, ErrorTemplate, ForceUpdate,
+        RenderErrorTemplate, APPROVAL_REQUIRED,
+    },
+    ssh::{
+        check_sshfp, suggest_jumphost, CachingSshClient, ConnectionDetails, Forwarding,
+        KeyDiffItem, KeyOptions, KnownHosts, SshClient, SshClientError, SshfpCheck, TRUST_MANUAL,
+    },
+    Configuration,
+};
+
+use crate::models::{
+    format_expiry, parse_expiry, Host, HostGroup, NewHost, NewPendingAuthorization,
+    PendingAuthorization, PendingRemoval, User,
 };
+use crate::removal_queue::RemovalQueue;
 
-use crate::models::{Host, NewHost, User};
+use super::users::UserChoice;
 
 pub fn hosts_config(cfg: &mut web::ServiceConfig) {
     cfg.service(hosts_page)
         .service(render_hosts)
+        .service(render_groups)
+        .service(delete_group)
         .service(show_host)
+        .service(render_host_groups)
+        .service(add_to_group)
+        .service(remove_from_group)
         .service(get_logins)
         .service(add_host)
+        .service(import_hosts)
         .service(authorize_user)
+        .service(set_forwarding_policy)
+        .service(set_owner)
+        .service(set_importance)
+        .service(end_observation)
+        .service(set_default_options)
         .service(gen_authorized_keys)
+        .service(preview_authorized_keys)
+        .service(download_authorized_keys)
         .service(set_authorized_keys)
         .service(add_host_key)
         .service(delete)
         .service(delete_authorization)
+        .service(copy_authorizations)
+        .service(render_pending_removals)
+        .service(retry_pending_removals)
+        .service(cancel_pending_removal)
         .service(edit_host_form)
-        .service(edit_host);
+        .service(edit_host)
+        .service(decommission)
+        .service(decommission_status);
 }
 
 #[derive(Template)]
 #[template(path = "hosts/index.html")]
-struct HostsTemplate {}
-
-#[get("")]
-async fn hosts_page() -> impl Responder {
-    HostsTemplate {}
-}
-
-type HostData = (Host, Option<String>, Vec<UserAndOptions>, Vec<User>);
-
-enum HostDataError {
-    HostNotFound,
-    DatabaseError(String),
+struct HostsTemplate {
+    groups: Vec<HostGroup>,
 }
 
-fn get_all_host_data(conn: &mut DbConnection, host: String) -> Result<HostData, HostDataError> {
-    let maybe_host = Host::get_from_name_sync(conn, host).map_err(HostDataError::DatabaseError)?;
-
-    let Some(host) = maybe_host else {
-        return Err(HostDataError::HostNotFound);
-    };
-
-    let jumphost = if let Some(id) = host.jump_via {
-        Host::get_from_id_sync(conn, id)
-            .map_err(HostDataError::DatabaseError)?
-            .map(|h| h.name)
-    } else {
-        None
-    };
-
-    let authorized_users = host
-        .get_authorized_users(conn)
-        .map_err(HostDataError::DatabaseError)?;
-
-    // Skip getting users if we can't connect
-    if host.key_fingerprint.is_none() {
-        return Ok((host, jumphost, authorized_users, vec![]));
-    }
-
-    let user_list = User::get_all_users(conn).map_err(HostDataError::DatabaseError)?;
-
-    Ok((host, jumphost, authorized_users, user_list))
+#[get("")]
+async fn hosts_page(db: Data<BlockingPool>) -> actix_web::Result<impl Responder> {
+    Ok(match db.run(HostGroup::get_all).await? {
+        Ok(groups) => HostsTemplate { groups }.to_response(),
+        Err(error) => ErrorTemplate { error }.to_response(),
+    })
 }
 
 #[derive(Template)]
@@ -86,19 +97,17 @@ struct LoginsTemplate {
 
 #[get("/{name}/logins")]
 async fn get_logins(
-    conn: Data<ConnectionPool>,
+    db: Data<BlockingPool>,
     caching_ssh_client: Data<CachingSshClient>,
     host_name: Path<String>,
     update: ForceUpdate,
+    req: HttpRequest,
 ) -> actix_web::Result<impl Responder> {
-    let host = Host::get_from_name(conn.get().unwrap(), host_name.to_string()).await;
+    let host = Host::get_from_name(&db, host_name.to_string()).await;
 
     match host {
         Err(error) => Ok(RenderErrorTemplate { error }.to_response()),
-        Ok(None) => Ok(RenderErrorTemplate {
-            error: "Host not found".to_owned(),
-        }
-        .to_response()),
+        Ok(None) => Ok(not_found(&req, "Host not found".to_owned())),
         Ok(Some(host)) => {
             let logins = caching_ssh_client
                 .get_logins(host, should_update(update))
@@ -114,37 +123,81 @@ struct ShowHostTemplate {
     host: Host,
     jumphost: Option<String>,
     authorized_users: Vec<UserAndOptions>,
+    expiries: BTreeMap<i32, time::PrimitiveDateTime>,
+    user_keys: BTreeMap<String, Vec<String>>,
+    deployments: BTreeMap<String, time::PrimitiveDateTime>,
+    /// Keys on the host by login when it was last read, if it was
+    present_keys: Option<HashMap<String, Vec<String>>>,
     user_list: Vec<User>,
 }
 
+impl ShowHostTemplate {
+    /// When an authorization expires, and whether it did
+    fn expiry(&self, authorization: &i32) -> Option<(String, bool)> {
+        self.expiries
+            .get(authorization)
+            .map(|expires_at| (format_expiry(*expires_at), *expires_at <= now()))
+    }
+
+    fn key_count(&self, username: &str) -> usize {
+        self.user_keys.get(username).map_or(0, Vec::len)
+    }
+
+    /// How many keys of the user the login had when the host was last read, and whether
+    /// that were all of them
+    fn presence(&self, username: &str, login: &str) -> Option<(usize, bool)> {
+        let present = self.present_keys.as_ref()?.get(login);
+        let count = self.user_keys.get(username).map_or(0, |keys| {
+            keys.iter()
+                .filter(|key| present.is_some_and(|present| present.contains(key)))
+                .count()
+        });
+        Some((count, count == self.key_count(username)))
+    }
+
+    fn deployed(&self, login: &str) -> Option<String> {
+        self.deployments.get(login).map(|at| format_expiry(*at))
+    }
+}
+
 #[get("/{name}")]
 async fn show_host(
-    conn: Data<ConnectionPool>,
+    db: Data<BlockingPool>,
+    caching_ssh_client: Data<CachingSshClient>,
     host: Path<String>,
+    req: HttpRequest,
 ) -> actix_web::Result<impl Responder> {
-    let res =
-        web::block(move || get_all_host_data(&mut conn.get().unwrap(), host.to_string())).await?;
-
-    let (host, jumphost, authorized_users, user_list) = match res {
-        Ok(host_data) => host_data,
-        Err(e) => {
-            return Ok(match e {
-                HostDataError::HostNotFound => ErrorTemplate {
-                    error: String::from("Host not found"),
-                }
-                .to_response(),
-                HostDataError::DatabaseError(e) => ErrorTemplate { error: e }.to_response(),
-            });
-        }
-    };
+    let res = db.run(move |conn| HostData::load(conn, &host)).await?;
 
-    Ok(ShowHostTemplate {
-        host,
-        jumphost,
-        authorized_users,
-        user_list,
-    }
-    .to_response())
+    Ok(match res {
+        Ok(HostData {
+            host,
+            jumphost,
+            authorized_users,
+            expiries,
+            user_keys,
+            deployments,
+            user_list,
+        }) => {
+            let present_keys = caching_ssh_client.get_cached_keys(&host.name).await;
+            ShowHostTemplate {
+                host,
+                jumphost,
+                authorized_users,
+                expiries,
+                user_keys,
+                deployments,
+                present_keys,
+                user_list,
+            }
+            .to_response()
+        }
+        Err(HostDataError::HostNotFound) => not_found(&req, String::from("Host not found")),
+        Err(e) => ErrorTemplate {
+            error: e.to_string(),
+        }
+        .to_response(),
+    })
 }
 
 #[derive(Deserialize)]
@@ -154,14 +207,14 @@ struct AddHostkeyForm {
 
 #[post("/{id}/add_hostkey")]
 async fn add_host_key(
-    conn: Data<ConnectionPool>,
+    db: Data<BlockingPool>,
     ssh_client: Data<SshClient>,
+    notifier: Data<Notifier>,
+    req: HttpRequest,
     host_id: Path<i32>,
     new_hostkey: web::Form<AddHostkeyForm>,
 ) -> actix_web::Result<impl Responder> {
-    let cloned_conn = conn.clone();
-
-    let host = match Host::get_from_id(conn.get().unwrap(), *host_id).await {
+    let host = match Host::get_from_id(&db, *host_id).await {
         Ok(h) => h,
         Err(e) => return Ok(FormResponseBuilder::error(e)),
     };
@@ -169,19 +222,31 @@ async fn add_host_key(
     match host {
         Some(host) => {
             if let Some(ref new_hostkey) = new_hostkey.key_fingerprint {
-                let res =
-                    host.update_fingerprint(&mut cloned_conn.get().unwrap(), new_hostkey.clone());
+                let (db_host, fingerprint) = (host.clone(), new_hostkey.clone());
+                let res = db
+                    .run(move |conn| db_host.update_fingerprint(conn, fingerprint, TRUST_MANUAL))
+                    .await?;
                 return Ok(match res {
-                    Ok(()) => FormResponseBuilder::created("Added hostkey".to_owned())
-                        .add_trigger("reloadDiff".to_owned()),
+                    Ok(()) => {
+                        if host
+                            .key_fingerprint
+                            .as_ref()
+                            .is_some_and(|old| old != new_hostkey)
+                        {
+                            notifier.host_key_replaced(&host.name, &actor_of(&req), new_hostkey);
+                        }
+                        FormResponseBuilder::created("Added hostkey".to_owned())
+                            .add_trigger("reloadDiff".to_owned())
+                    }
                     Err(e) => FormResponseBuilder::error(e),
                 });
             }
 
             let target = host.to_connection().unwrap();
-            let maybe_jumphost = host
-                .jump_via
-                .map(|jump| Host::get_from_id_sync(&mut cloned_conn.get().unwrap(), jump));
+            let maybe_jumphost = match host.jump_via {
+                Some(jump) => Some(Host::get_from_id(&db, jump).await),
+                None => None,
+            };
 
             let connection_res = match maybe_jumphost {
                 Some(Ok(None)) => {
@@ -214,7 +279,10 @@ async fn add_host_key(
                     address: host.address,
                     port: host.port,
                     jumphost: host.jump_via,
+                    jumphost_note: None,
                     key_fingerprint,
+                    check_sshfp: false,
+                    sshfp: None,
                 }
                 .to_string(),
             }))
@@ -234,6 +302,22 @@ struct HostkeyDialog {
     port: i32,
     key_fingerprint: String,
     jumphost: Option<i32>,
+    /// Why the connection goes through a suggested jumphost, or why it doesn't
+    jumphost_note: Option<String>,
+    /// Whether to compare the key with SSHFP records again when adding the host
+    check_sshfp: bool,
+    /// Result of comparing the key with the SSHFP records
+    sshfp: Option<Result<Option<SshfpCheck>, String>>,
+}
+
+impl HostkeyDialog {
+    /// The fingerprint as an inline SVG QR code, to compare it on the host's console with a phone
+    fn fingerprint_qr(&self) -> Option<String> {
+        let code = QrCode::new(self.key_fingerprint.as_bytes()).ok()?;
+        let svg = code.render::<svg::Color>().min_dimensions(160, 160).build();
+        // Without the XML declaration, which doesn't belong into HTML
+        svg.find("<svg").map(|start| svg[start..].to_owned())
+    }
 }
 
 #[derive(Deserialize)]
@@ -244,23 +328,28 @@ struct HostAddForm {
     port: i32,
     jumphost: Option<i32>,
     key_fingerprint: Option<String>,
+    /// Compare the host key with the SSHFP records of the address
+    #[serde(default)]
+    check_sshfp: bool,
+    /// Without a jumphost, try the one suggested for a private address
+    #[serde(default)]
+    detect_jumphost: bool,
 }
 
 #[post("/add")]
 async fn add_host(
-    conn: Data<ConnectionPool>,
+    db: Data<BlockingPool>,
     ssh_client: Data<SshClient>,
     form: web::Form<HostAddForm>,
 ) -> actix_web::Result<impl Responder> {
     let form = form.0;
 
     // TODO: better error handling for jumphost (serde deserialize opt)
-    let cloned_conn = conn.clone();
     let maybe_jumphost: Option<Host> = if let Some(via) = form.jumphost {
         if via < 0 {
             None
         } else {
-            match Host::get_from_id(cloned_conn.get().unwrap(), via).await {
+            match Host::get_from_id(&db, via).await {
                 Ok(j) => j,
                 Err(_) => {
                     return Ok(FormResponseBuilder::not_found(String::from(
@@ -282,14 +371,54 @@ async fn add_host(
         &address.hostname, &address.port, maybe_jumphost
     );
     let Some(key_fingerprint) = form.key_fingerprint else {
-        let connection_res = match maybe_jumphost {
-            Some(via) => ssh_client.get_hostkey_via(via, address).await,
-            None => ssh_client.get_hostkey(address).await,
+        let suggestion = match (&maybe_jumphost, form.detect_jumphost) {
+            (None, true) => match db.run(Host::get_all_hosts).await? {
+                Ok(hosts) => suggest_jumphost(&hosts, &form.address),
+                Err(e) => {
+                    warn!("Not suggesting a jumphost for {}: {e}", form.address);
+                    None
+                }
+            },
+            _ => None,
+        };
+        // The suggested chain has to work, otherwise the host is tried directly
+        let (connection_res, jumphost, jumphost_note) = match (maybe_jumphost, suggestion) {
+            (Some(via), _) => (
+                ssh_client.get_hostkey_via(via, address).await,
+                form.jumphost,
+                None,
+            ),
+            (None, Some(suggestion)) => {
+                let via = suggestion.jumphost;
+                match ssh_client
+                    .get_hostkey_via(via.clone(), address.clone())
+                    .await
+                {
+                    Ok(receiver) => (
+                        Ok(receiver),
+                        Some(via.id),
+                        Some(format!(
+                            "{} is private, so the host is added via {} ({}).",
+                            form.address, via.name, suggestion.reason
+                        )),
+                    ),
+                    Err(e) => (
+                        ssh_client.get_hostkey(address).await,
+                        form.jumphost,
+                        Some(format!(
+                            "The suggested jumphost {} ({}) failed, so none is used: {e}",
+                            via.name, suggestion.reason
+                        )),
+                    ),
+                }
+            }
+            (None, None) => (ssh_client.get_hostkey(address).await, form.jumphost, None),
         };
 
-        let key_receiver = match connection_res {
-            Ok(r) => r,
-            Err(e) => return Ok(FormResponseBuilder::error(e.to_string())),
+        let key_receiver = match (connection_res, &jumphost_note) {
+            (Ok(r), _) => r,
+            (Err(e), Some(note)) => return Ok(FormResponseBuilder::error(format!("{e}. {note}"))),
+            (Err(e), None) => return Ok(FormResponseBuilder::error(e.to_string())),
         };
 
         let Ok(key_fingerprint) = web::block(move || key_receiver.recv()).await? else {
@@ -298,6 +427,10 @@ async fn add_host(
             )));
         };
 
+        let sshfp = match form.check_sshfp {
+            true => Some(check_sshfp(&form.address, &key_fingerprint).await),
+            false => None,
+        };
         return Ok(FormResponseBuilder::dialog(Modal {
             title: String::from("Please check the hostkey"),
             request_target: String::from("/hosts/add"),
@@ -306,13 +439,32 @@ async fn add_host(
                 username: form.username,
                 address: form.address,
                 port: form.port,
-                jumphost: form.jumphost,
+                jumphost,
+                jumphost_note,
                 key_fingerprint,
+                check_sshfp: form.check_sshfp,
+                sshfp,
             }
             .to_string(),
         }));
     };
 
+    // Looked up again, the dialog could have been tampered with
+    let key_trust = match form.check_sshfp {
+        true => match check_sshfp(&form.address, &key_fingerprint).await {
+            Ok(Some(check)) if check.is_secure_mismatch() => {
+                return Ok(FormResponseBuilder::error(check.describe()));
+            }
+            Ok(Some(check)) => check.trust(),
+            Ok(None) => TRUST_MANUAL,
+            Err(e) => {
+                warn!("Not using SSHFP records of {}: {e}", form.address);
+                TRUST_MANUAL
+            }
+        },
+        false => TRUST_MANUAL,
+    };
+
     if let Err(error) = {
         match maybe_jumphost {
             Some(ref via) => {
@@ -342,8 +494,10 @@ async fn add_host(
         username: form.username,
         key_fingerprint,
         jump_via: maybe_jumphost.map(|h| h.id),
+        key_trust: Some(key_trust.to_owned()),
+        observed_until: ssh_client.observation_end(),
     };
-    let res = web::block(move || Host::add_host(&mut conn.get().unwrap(), &new_host)).await?;
+    let res = db.run(move |conn| Host::add_host(conn, &new_host)).await?;
 
     Ok(match res {
         Ok(id) => match ssh_client.install_script_on_host(id).await {
@@ -355,50 +509,576 @@ async fn add_host(
     })
 }
 
+#[derive(Template)]
+#[template(path = "hosts/import_dialog.htm")]
+struct HostImportDialog {
+    hosts: String,
+    format: &'static str,
+    known_hosts: String,
+    checked: HostImport,
+}
+
+#[derive(Deserialize)]
+struct HostImportForm {
+    /// CSV rows or an ssh_config file
+    hosts: String,
+    #[serde(default)]
+    format: HostImportFormat,
+    /// Host keys to add the hosts with
+    #[serde(default)]
+    known_hosts: String,
+    /// Whether the rows were shown to the user
+    #[serde(default)]
+    confirm: bool,
+}
+
+#[post("/import")]
+async fn import_hosts(
+    db: Data<BlockingPool>,
+    ssh_client: Data<SshClient>,
+    form: web::Form<HostImportForm>,
+) -> actix_web::Result<impl Responder> {
+    let HostImportForm {
+        hosts,
+        format,
+        known_hosts,
+        confirm,
+    } = form.into_inner();
+    let observed_until = ssh_client.observation_end();
+    let parsed_known_hosts = KnownHosts::parse(&known_hosts);
+    if !known_hosts.trim().is_empty() && parsed_known_hosts.is_empty() {
+        return Ok(FormResponseBuilder::error(String::from(
+            "The known_hosts file contains no host keys",
+        )));
+    }
+
+    if confirm {
+        let res = db
+            .run(move |conn| {
+                let checked = HostImport::check(conn, format, &hosts, &parsed_known_hosts)?;
+                let failed = checked.failed.len();
+                let without_key = checked
+                    .rows
+                    .iter()
+                    .filter(|row| row.key_fingerprint.is_none())
+                    .count();
+                checked
+                    .create(conn, observed_until)
+                    .map(|added| (added, failed, without_key))
+            })
+            .await?;
+        return Ok(match res {
+            Ok((0, _, _)) => {
+                FormResponseBuilder::error(String::from("None of the rows can be added"))
+            }
+            Ok((added, failed, without_key)) => {
+                let mut message = format!("Added {added} hosts.");
+                if failed > 0 {
+                    message.push_str(&format!(" {failed} rows were refused."));
+                }
+                if without_key > 0 {
+                    message.push_str(&format!(
+                        " Check the host keys of {without_key} hosts on their pages."
+                    ));
+                }
+                FormResponseBuilder::created(message).add_trigger(String::from("reload-hosts"))
+            }
+            Err(e) => FormResponseBuilder::error(e),
+        });
+    }
+
+    let hosts_to_check = hosts.clone();
+    let checked = match db
+        .run(move |conn| HostImport::check(conn, format, &hosts_to_check, &parsed_known_hosts))
+        .await?
+    {
+        Ok(checked) => checked,
+        Err(e) => return Ok(FormResponseBuilder::error(e)),
+    };
+    if checked.rows.is_empty() && checked.failed.is_empty() {
+        return Ok(FormResponseBuilder::error(match format {
+            HostImportFormat::Csv => {
+                format!("No hosts found, expected the columns {HOST_CSV_COLUMNS}")
+            }
+            HostImportFormat::SshConfig => {
+                String::from("No hosts found, expected Host lines without wildcards")
+            }
+            HostImportFormat::AnsibleIni | HostImportFormat::AnsibleYaml => {
+                String::from("No hosts found in the inventory")
+            }
+        }));
+    }
+
+    Ok(FormResponseBuilder::dialog(Modal {
+        title: format!(
+            "{} hosts can be added, {} rows are refused",
+            checked.rows.len(),
+            checked.failed.len()
+        ),
+        request_target: String::from("/hosts/import"),
+        template: HostImportDialog {
+            hosts,
+            format: format.as_str(),
+            known_hosts,
+            checked,
+        }
+        .to_string(),
+    }))
+}
+
 #[derive(Template)]
 #[template(path = "hosts/list.htm")]
 struct RenderHostsTemplate {
     hosts: Vec<Host>,
+    /// All hosts, to choose a jump host from
+    jumphosts: Vec<Host>,
+    /// Host ids and names of their groups
+    memberships: Vec<(i32, String)>,
+}
+
+impl RenderHostsTemplate {
+    fn groups_of(&self, host: &Host) -> String {
+        self.memberships
+            .iter()
+            .filter(|(host_id, _)| *host_id == host.id)
+            .map(|(_, group)| group.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[derive(Deserialize)]
+struct HostsFilter {
+    #[serde(default)]
+    group: String,
 }
 
 #[get("/list.htm")]
-async fn render_hosts(conn: Data<ConnectionPool>) -> actix_web::Result<impl Responder> {
-    let all_hosts = web::block(move || Host::get_all_hosts(&mut conn.get().unwrap())).await?;
+async fn render_hosts(
+    db: Data<BlockingPool>,
+    filter: web::Query<HostsFilter>,
+) -> actix_web::Result<impl Responder> {
+    let group = Some(filter.into_inner().group).filter(|group| !group.is_empty());
+    let res = db
+        .run(move |conn| {
+            let hosts = Host::get_all_hosts_in(conn, group.as_deref())?;
+            let jumphosts = Host::get_all_hosts(conn)?;
+            let memberships = HostGroup::get_memberships(conn)?;
+            Ok::<_, String>((hosts, jumphosts, memberships))
+        })
+        .await?;
+
+    Ok(match res {
+        Ok((hosts, jumphosts, memberships)) => RenderHostsTemplate {
+            hosts,
+            jumphosts,
+            memberships,
+        }
+        .to_response(),
+        Err(error) => RenderErrorTemplate { error }.to_response(),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "hosts/groups.htm")]
+struct RenderGroupsTemplate {
+    /// Groups and their hosts
+    groups: Vec<(HostGroup, Vec<Host>)>,
+}
+
+#[get("/groups.htm")]
+async fn render_groups(db: Data<BlockingPool>) -> actix_web::Result<impl Responder> {
+    let res = db
+        .run(|conn| {
+            HostGroup::get_all(conn)?
+                .into_iter()
+                .map(|group| group.get_hosts(conn).map(|hosts| (group, hosts)))
+                .collect::<Result<Vec<_>, String>>()
+        })
+        .await?;
 
-    Ok(match all_hosts {
-        Ok(all_hosts) => RenderHostsTemplate { hosts: all_hosts }.to_response(),
+    Ok(match res {
+        Ok(groups) => RenderGroupsTemplate { groups }.to_response(),
         Err(error) => RenderErrorTemplate { error }.to_response(),
     })
 }
 
+#[post("/groups/{group}/delete")]
+async fn delete_group(
+    db: Data<BlockingPool>,
+    group: Path<String>,
+) -> actix_web::Result<impl Responder> {
+    let res = db
+        .run(move |conn| {
+            HostGroup::get_from_name(conn, &group)?
+                .ok_or_else(|| String::from("Group not found"))?
+                .delete(conn)
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(()) => FormResponseBuilder::success(String::from("Deleted the group"))
+            .add_trigger(String::from("reload-host-groups"))
+            .add_trigger(String::from("reload-hosts")),
+        Err(error) => FormResponseBuilder::error(error),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "hosts/host_groups.htm")]
+struct HostGroupsTemplate {
+    host_name: String,
+    groups: Vec<HostGroup>,
+    /// Every group, to choose from
+    all_groups: Vec<HostGroup>,
+    /// Access granted only by the rules of the groups
+    rule_access: Vec<UserAndOptions>,
+}
+
+#[get("/{name}/groups.htm")]
+async fn render_host_groups(
+    db: Data<BlockingPool>,
+    host_name: Path<String>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let host_name = host_name.into_inner();
+    let name = host_name.clone();
+    let res = db
+        .run(move |conn| {
+            let Some(host) = Host::get_from_name_sync(conn, name)? else {
+                return Ok(None);
+            };
+            let rule_access = host
+                .get_authorized_users(conn)?
+                .into_iter()
+                .filter(|(id, _, _, _)| *id == 0)
+                .collect();
+            Ok::<_, String>(Some((
+                host.get_groups(conn)?,
+                HostGroup::get_all(conn)?,
+                rule_access,
+            )))
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(Some((groups, all_groups, rule_access))) => HostGroupsTemplate {
+            host_name,
+            groups,
+            all_groups,
+            rule_access,
+        }
+        .to_response(),
+        Ok(None) => not_found(&req, String::from("Host not found")),
+        Err(error) => RenderErrorTemplate { error }.to_response(),
+    })
+}
+
+#[derive(Deserialize)]
+struct GroupForm {
+    group: String,
+}
+
+#[post("/{name}/groups")]
+async fn add_to_group(
+    db: Data<BlockingPool>,
+    host_name: Path<String>,
+    form: web::Form<GroupForm>,
+) -> actix_web::Result<impl Responder> {
+    let res = db
+        .run(move |conn| {
+            Host::get_from_name_sync(conn, host_name.into_inner())?
+                .ok_or_else(|| String::from("Host not found"))?
+                .add_to_group(conn, &form.group)
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(()) => FormResponseBuilder::success(String::from("Added the host to the group"))
+            .add_trigger(String::from("reload-host-groups")),
+        Err(error) => FormResponseBuilder::error(error),
+    })
+}
+
+#[post("/{name}/groups/remove")]
+async fn remove_from_group(
+    db: Data<BlockingPool>,
+    host_name: Path<String>,
+    form: web::Form<GroupForm>,
+) -> actix_web::Result<impl Responder> {
+    let res = db
+        .run(move |conn| {
+            Host::get_from_name_sync(conn, host_name.into_inner())?
+                .ok_or_else(|| String::from("Host not found"))?
+                .remove_from_group(conn, &form.group)
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(()) => FormResponseBuilder::success(String::from("Removed the host from the group"))
+            .add_trigger(String::from("reload-host-groups")),
+        Err(error) => FormResponseBuilder::error(error),
+    })
+}
+
 #[derive(Deserialize)]
 struct AuthorizeUserForm {
     host_id: i32,
-    user_id: i32,
+    user_id: String,
+    #[serde(default)]
+    new_username: String,
+    #[serde(default)]
+    new_email: String,
     login: String,
     options: Option<String>,
+    /// Empty, "none" or "restricted"
+    #[serde(default)]
+    forwarding: String,
+    #[serde(default)]
+    permit_open: String,
+    #[serde(default)]
+    permit_listen: String,
+    /// Empty if the authorization doesn't expire
+    #[serde(default)]
+    expires_at: String,
 }
 
 #[post("/user/authorize")]
 async fn authorize_user(
-    conn: Data<ConnectionPool>,
-
+    db: Data<BlockingPool>,
+    ssh_client: Data<SshClient>,
+    config: Data<Configuration>,
+    req: HttpRequest,
     form: web::Form<AuthorizeUserForm>,
 ) -> actix_web::Result<impl Responder> {
-    let res = web::block(move || {
-        Host::authorize_user(
-            &mut conn.get().unwrap(),
-            form.host_id,
-            form.user_id,
-            form.login.clone(),
-            form.options.clone(),
-        )
+    let form = form.into_inner();
+    let expires_at = match parse_expiry(&form.expires_at) {
+        Ok(expires_at) => expires_at,
+        Err(e) => return Ok(FormResponseBuilder::error(e)),
+    };
+    let proposed_by = proposer(&req, &config);
+    let proposal = proposed_by.is_some();
+    let login = form.login.clone();
+    let choice = UserChoice {
+        user_id: form.user_id,
+        new_username: form.new_username,
+        new_email: form.new_email,
+    };
+    let res = db
+        .run(move |conn| {
+            let host = Host::get_from_id_sync(conn, form.host_id)?
+                .ok_or_else(|| String::from("Host not found"))?;
+            let options =
+                Forwarding::from_form(&form.forwarding, &form.permit_open, &form.permit_listen)?
+                    .apply(form.options)?;
+            let user = choice.get_or_create(conn)?;
+            match proposed_by {
+                Some(proposed_by) => PendingAuthorization::propose(
+                    conn,
+                    NewPendingAuthorization {
+                        created_at: now(),
+                        proposed_by,
+                        host_id: host.id,
+                        user_id: user.id,
+                        login: form.login,
+                        options: options.clone(),
+                        expires_at,
+                        status: PENDING.to_owned(),
+                    },
+                )?,
+                None => Host::authorize_user(
+                    conn,
+                    host.id,
+                    user.id,
+                    form.login,
+                    options.clone(),
+                    expires_at,
+                )?,
+            }
+            Ok::<_, String>((host, options))
+        })
+        .await?;
+
+    let (host, options) = match res {
+        Ok(res) => res,
+        Err(e) => return Ok(FormResponseBuilder::error(e)),
+    };
+
+    let mut warnings = Vec::new();
+    if host.forbid_forwarding
+        && options
+            .and_then(|options| options.parse::<KeyOptions>().ok())
+            .is_some_and(|options| options.permits_forwarding())
+    {
+        warnings.push(String::from(
+            "This host forbids port forwarding, the forwarding options won't be deployed",
+        ));
+    }
+    // Authorizing root doesn't help if sshd doesn't let root in with a key
+    if login == "root" {
+        warnings.extend(ssh_client.root_login_advisory(host).await);
+    }
+
+    let done = match proposal {
+        true => "Proposed the authorization, it takes effect once an admin approves it",
+        false => "Authorized user",
+    };
+    let message = match warnings.is_empty() {
+        true => done.to_owned(),
+        false => format!("{done}. Warning: {}", warnings.join(". ")),
+    };
+    let response = FormResponseBuilder::success(message).add_trigger("reloadDiff".to_owned());
+    Ok(match proposal {
+        true => response.set_status(StatusCode::ACCEPTED),
+        false => response,
     })
-    .await?;
+}
+
+#[derive(Deserialize)]
+struct ForwardingPolicyForm {
+    forbid_forwarding: bool,
+}
+
+#[post("/{name}/forwarding_policy")]
+async fn set_forwarding_policy(
+    db: Data<BlockingPool>,
+    caching_ssh_client: Data<CachingSshClient>,
+    host_name: Path<String>,
+    form: web::Form<ForwardingPolicyForm>,
+) -> actix_web::Result<impl Responder> {
+    let host_name = host_name.into_inner();
+    let forbid = form.forbid_forwarding;
+    let name = host_name.clone();
+    let res = db
+        .run(move |conn| {
+            let host = Host::get_from_name_sync(conn, name)?
+                .ok_or_else(|| String::from("Host not found"))?;
+            host.set_forbid_forwarding(conn, forbid)
+        })
+        .await?;
 
     Ok(match res {
-        Ok(()) => FormResponseBuilder::success(String::from("Authorized user"))
-            .add_trigger("reloadDiff".to_owned()),
+        Ok(()) => {
+            // The cached diff was computed with the old policy
+            caching_ssh_client.remove(&host_name).await;
+            FormResponseBuilder::success(String::from(match forbid {
+                true => "Port forwarding is now forbidden on this host",
+                false => "Port forwarding is now controlled by the authorizations",
+            }))
+        }
+        Err(e) => FormResponseBuilder::error(e),
+    })
+}
+
+#[derive(Deserialize)]
+struct OwnerForm {
+    owner: String,
+}
+
+#[post("/{name}/owner")]
+async fn set_owner(
+    db: Data<BlockingPool>,
+    host_name: Path<String>,
+    form: web::Form<OwnerForm>,
+) -> actix_web::Result<impl Responder> {
+    let owner = Some(form.into_inner().owner.trim().to_owned()).filter(|owner| !owner.is_empty());
+    let res = db
+        .run(move |conn| {
+            let host = Host::get_from_name_sync(conn, host_name.into_inner())?
+                .ok_or_else(|| String::from("Host not found"))?;
+            host.set_owner(conn, owner.clone())?;
+            Ok::<_, String>(owner)
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(Some(owner)) => {
+            FormResponseBuilder::success(format!("{owner} now approves access requests"))
+        }
+        Ok(None) => {
+            FormResponseBuilder::success(String::from("Operators now approve access requests"))
+        }
+        Err(e) => FormResponseBuilder::error(e),
+    })
+}
+
+#[derive(Deserialize)]
+struct ImportanceForm {
+    importance: String,
+}
+
+#[post("/{name}/importance")]
+async fn set_importance(
+    db: Data<BlockingPool>,
+    host_name: Path<String>,
+    form: web::Form<ImportanceForm>,
+) -> actix_web::Result<impl Responder> {
+    let importance = form.into_inner().importance;
+    let res = db
+        .run(move |conn| {
+            let host = Host::get_from_name_sync(conn, host_name.into_inner())?
+                .ok_or_else(|| String::from("Host not found"))?;
+            host.set_importance(conn, &importance)?;
+            Ok::<_, String>(importance)
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(importance) => FormResponseBuilder::success(format!("The host is {importance} now")),
+        Err(e) => FormResponseBuilder::error(e),
+    })
+}
+
+#[post("/{name}/end_observation")]
+async fn end_observation(
+    db: Data<BlockingPool>,
+    host_name: Path<String>,
+) -> actix_web::Result<impl Responder> {
+    let res = db
+        .run(move |conn| {
+            let host = Host::get_from_name_sync(conn, host_name.into_inner())?
+                .ok_or_else(|| String::from("Host not found"))?;
+            host.end_observation(conn)
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(()) => FormResponseBuilder::success(String::from(
+            "The host is no longer observed, ssm changes it from now on",
+        )),
+        Err(e) => FormResponseBuilder::error(e),
+    })
+}
+
+#[derive(Deserialize)]
+struct DefaultOptionsForm {
+    default_options: String,
+}
+
+#[post("/{name}/default_options")]
+async fn set_default_options(
+    db: Data<BlockingPool>,
+    caching_ssh_client: Data<CachingSshClient>,
+    host_name: Path<String>,
+    form: web::Form<DefaultOptionsForm>,
+) -> actix_web::Result<impl Responder> {
+    let host_name = host_name.into_inner();
+    let options = Some(form.into_inner().default_options).filter(|o| !o.trim().is_empty());
+    let name = host_name.clone();
+    let res = db
+        .run(move |conn| {
+            let host = Host::get_from_name_sync(conn, name)?
+                .ok_or_else(|| String::from("Host not found"))?;
+            host.set_default_options(conn, options)
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(()) => {
+            caching_ssh_client.remove(&host_name).await;
+            FormResponseBuilder::success(String::from("Changed the default options"))
+                .add_trigger("reloadDiff".to_owned())
+        }
         Err(e) => FormResponseBuilder::error(e),
     })
 }
@@ -419,23 +1099,24 @@ struct AuthorizedKeyfileDialog {
 
 #[post("/gen_authorized_keys")]
 async fn gen_authorized_keys(
-    conn: Data<ConnectionPool>,
+    db: Data<BlockingPool>,
     ssh_client: Data<SshClient>,
     form: web::Form<GenAuthorizedKeysForm>,
 ) -> actix_web::Result<impl Responder> {
     let host_name = &form.host_name;
     let login = &form.login;
 
-    let authorized_keys = match Host::get_from_name(conn.get().unwrap(), host_name.to_owned()).await
-    {
+    let authorized_keys = match Host::get_from_name(&db, host_name.to_owned()).await {
         Err(error) => {
             return Ok(FormResponseBuilder::error(error));
         }
         Ok(None) => {
-            return Ok(FormResponseBuilder::error("No such host.".to_owned()));
+            return Ok(FormResponseBuilder::not_found("No such host.".to_owned()));
         }
         Ok(Some(host)) => {
-            host.get_authorized_keys_file_for(&ssh_client, &mut conn.get().unwrap(), login.as_ref())
+            let (ssh_client, login) = (ssh_client.clone(), login.clone());
+            db.run(move |conn| host.get_authorized_keys_file_for(&ssh_client, conn, &login))
+                .await?
         }
     };
 
@@ -467,6 +1148,89 @@ async fn gen_authorized_keys(
     }))
 }
 
+/// Renders the authorized_keys file ssm would write for a login, without connecting to the host.
+/// None if there is no such host
+async fn render_authorized_keys(
+    db: &BlockingPool,
+    ssh_client: &SshClient,
+    host_name: String,
+    login: String,
+) -> Result<Option<String>, String> {
+    let ssh_client = ssh_client.clone();
+    db.run(move |conn| {
+        let Some(host) = Host::get_from_name_sync(conn, host_name)? else {
+            return Ok(None);
+        };
+        host.get_authorized_keys_file_for(&ssh_client, conn, &login)
+            .map(Some)
+    })
+    .await?
+}
+
+#[derive(Deserialize)]
+struct PreviewAuthorizedKeysForm {
+    login: String,
+}
+
+#[derive(Template)]
+#[template(path = "hosts/preview_authorized_keys_dialog.htm")]
+struct PreviewAuthorizedKeysDialog {
+    host_name: String,
+    login: String,
+    authorized_keys: String,
+}
+
+#[post("/{name}/preview_authorized_keys")]
+async fn preview_authorized_keys(
+    db: Data<BlockingPool>,
+    ssh_client: Data<SshClient>,
+    host_name: Path<String>,
+    form: web::Form<PreviewAuthorizedKeysForm>,
+) -> actix_web::Result<impl Responder> {
+    let (host_name, login) = (host_name.into_inner(), form.into_inner().login);
+
+    Ok(
+        match render_authorized_keys(&db, &ssh_client, host_name.clone(), login.clone()).await {
+            Ok(Some(authorized_keys)) => FormResponseBuilder::dialog(Modal {
+                title: format!(
+                    "authorized_keys of '{login}' on '{host_name}' (nothing is applied):"
+                ),
+                request_target: format!("/hosts/{host_name}/set_authorized_keys"),
+                template: PreviewAuthorizedKeysDialog {
+                    host_name,
+                    login,
+                    authorized_keys,
+                }
+                .to_string(),
+            }),
+            Ok(None) => FormResponseBuilder::not_found(String::from("No such host.")),
+            Err(error) => FormResponseBuilder::error(error),
+        },
+    )
+}
+
+#[get("/{name}/authorized_keys/{login}")]
+async fn download_authorized_keys(
+    db: Data<BlockingPool>,
+    ssh_client: Data<SshClient>,
+    path: Path<(String, String)>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let (host_name, login) = path.into_inner();
+
+    Ok(
+        match render_authorized_keys(&db, &ssh_client, host_name.clone(), login.clone()).await {
+            Ok(Some(authorized_keys)) => attachment(
+                "text/plain; charset=utf-8",
+                format!("{host_name}-{login}-authorized_keys"),
+                authorized_keys,
+            ),
+            Ok(None) => not_found(&req, String::from("No such host.")),
+            Err(error) => ErrorTemplate { error }.to_response(),
+        },
+    )
+}
+
 #[derive(Deserialize)]
 struct SetAuthorizedKeysForm {
     login: String,
@@ -488,7 +1252,7 @@ async fn set_authorized_keys(
         .await;
 
     Ok(match res {
-        Ok(()) => FormResponseBuilder::success(String::from("Applied authorized_keys"))
+        Ok(_) => FormResponseBuilder::success(String::from("Applied authorized_keys"))
             .add_trigger("reloadDiff".to_owned()),
         Err(error) => FormResponseBuilder::error(error.to_string()),
     })
@@ -509,42 +1273,44 @@ struct HostDeleteForm {
 
 #[post("/{name}/delete")]
 async fn delete(
-    conn: Data<ConnectionPool>,
+    db: Data<BlockingPool>,
     caching_ssh_client: Data<CachingSshClient>,
     form: web::Form<HostDeleteForm>,
     host_name: Path<String>,
-) -> impl Responder {
-    let host = match Host::get_from_name(conn.get().unwrap(), host_name.to_owned()).await {
+) -> actix_web::Result<impl Responder> {
+    let host = match Host::get_from_name(&db, host_name.to_owned()).await {
         Ok(None) => {
-            return FormResponseBuilder::error("Host not found".to_owned());
+            return Ok(FormResponseBuilder::not_found("Host not found".to_owned()));
         }
         Err(error) => {
-            return FormResponseBuilder::error(format!("Database error: {error}"));
+            return Ok(FormResponseBuilder::error(format!(
+                "Database error: {error}"
+            )));
         }
         Ok(Some(host)) => host,
     };
 
     if form.confirm {
-        return match host.delete(&mut conn.get().unwrap()) {
+        return Ok(match db.run(move |conn| host.delete(conn)).await? {
             Ok(amt) => {
                 caching_ssh_client.remove(host_name.as_str()).await;
-                return FormResponseBuilder::success(format!("Deleted {amt} record(s)"));
+                FormResponseBuilder::success(format!("Deleted {amt} record(s)"))
             }
             Err(e) => FormResponseBuilder::error(format!("Failed to delete host: {e}")),
-        };
+        });
     }
 
-    let mut connection = conn.get().unwrap();
-
-    let res = host
-        .get_authorized_users(&mut connection)
-        .and_then(|authorizations| {
-            host.get_dependant_hosts(&mut connection)
-                .map(|hosts| (authorizations, hosts))
-        });
+    let res = db
+        .run(move |conn| {
+            host.get_authorized_users(conn).and_then(|authorizations| {
+                host.get_dependant_hosts(conn)
+                    .map(|hosts| (authorizations, hosts))
+            })
+        })
+        .await?;
 
     // TODO: resolve authorizations of dependant hosts
-    match res {
+    Ok(match res {
         Ok((authorizations, affected_hosts)) => FormResponseBuilder::dialog(Modal {
             title: format!("In addition to {host_name}, these entries will be affected"),
             request_target: format!("/hosts/{host_name}/delete"),
@@ -555,6 +1321,239 @@ async fn delete(
             .to_string(),
         }),
         Err(error) => FormResponseBuilder::error(format!("Failed to get authorizations: {error}")),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "hosts/copy_authorizations_dialog.htm")]
+struct CopyAuthorizationsTemplate {
+    source: String,
+    authorizations: Vec<UserAndOptions>,
+}
+
+#[derive(Deserialize)]
+struct CopyAuthorizationsForm {
+    /// Host to copy the authorizations from
+    source: String,
+    #[serde(default)]
+    confirm: bool,
+}
+
+/// Copies the authorizations of another host, after showing which ones will be added
+#[post("/{name}/copy_authorizations")]
+async fn copy_authorizations(
+    db: Data<BlockingPool>,
+    caching_ssh_client: Data<CachingSshClient>,
+    config: Data<Configuration>,
+    req: HttpRequest,
+    host_name: Path<String>,
+    form: web::Form<CopyAuthorizationsForm>,
+) -> actix_web::Result<impl Responder> {
+    if proposer(&req, &config).is_some() {
+        return Ok(FormResponseBuilder::error(APPROVAL_REQUIRED.to_owned()));
+    }
+    let host_name = host_name.into_inner();
+    let CopyAuthorizationsForm { source, confirm } = form.into_inner();
+    let source = source.trim().to_owned();
+    let name = host_name.clone();
+    let source_name = source.clone();
+    let res = db
+        .run(move |conn| {
+            let host = Host::get_from_name_sync(conn, name)?
+                .ok_or_else(|| String::from("Host not found"))?;
+            let source = Host::get_from_name_sync(conn, source_name)?
+                .ok_or_else(|| String::from("Source host not found"))?;
+            if host.id == source.id {
+                return Err(String::from("A host can't copy its own authorizations"));
+            }
+            match confirm {
+                true => host.copy_authorizations_from(conn, &source),
+                false => host.get_missing_authorizations(conn, &source),
+            }
+        })
+        .await?;
+
+    let authorizations = match res {
+        Ok(authorizations) => authorizations,
+        Err(error) => return Ok(FormResponseBuilder::error(error)),
+    };
+    if confirm {
+        caching_ssh_client.remove(&host_name).await;
+        return Ok(FormResponseBuilder::success(format!(
+            "Copied {} authorizations from {source}",
+            authorizations.len()
+        ))
+        .add_trigger("reloadDiff".to_owned()));
+    }
+    if authorizations.is_empty() {
+        return Ok(FormResponseBuilder::success(format!(
+            "{host_name} already has every authorization of {source}"
+        )));
+    }
+    Ok(FormResponseBuilder::dialog(Modal {
+        title: format!("These authorizations will be copied from {source} to {host_name}"),
+        request_target: format!("/hosts/{host_name}/copy_authorizations"),
+        template: CopyAuthorizationsTemplate {
+            source,
+            authorizations,
+        }
+        .to_string(),
+    }))
+}
+
+#[derive(Template)]
+#[template(path = "hosts/pending_removals.htm")]
+struct RenderPendingRemovalsTemplate {
+    host_name: String,
+    removals: Vec<PendingRemoval>,
+}
+
+impl RenderPendingRemovalsTemplate {
+    /// The end of a key, enough to recognize it
+    fn short_key(&self, key: &str) -> String {
+        let start = key.len().saturating_sub(16);
+        format!("…{}", key.get(start..).unwrap_or(key))
+    }
+}
+
+#[get("/{name}/pending_removals.htm")]
+async fn render_pending_removals(
+    db: Data<BlockingPool>,
+    host_name: Path<String>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let name = host_name.into_inner();
+    let res = db
+        .run(move |conn| {
+            let Some(host) = Host::get_from_name_sync(conn, name)? else {
+                return Ok(None);
+            };
+            let removals = PendingRemoval::get_for_host(conn, &host)?;
+            Ok::<_, String>(Some((host.name, removals)))
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(Some((host_name, removals))) => RenderPendingRemovalsTemplate {
+            host_name,
+            removals,
+        }
+        .to_response(),
+        Ok(None) => not_found(&req, String::from("Host not found")),
+        Err(error) => RenderErrorTemplate { error }.to_response(),
+    })
+}
+
+#[post("/{name}/pending_removals/retry")]
+async fn retry_pending_removals(
+    db: Data<BlockingPool>,
+    removals: Data<RemovalQueue>,
+    host_name: Path<String>,
+) -> actix_web::Result<impl Responder> {
+    let host = match Host::get_from_name(&db, host_name.into_inner()).await {
+        Ok(Some(host)) => host,
+        Ok(None) => return Ok(FormResponseBuilder::not_found("Host not found".to_owned())),
+        Err(error) => return Ok(FormResponseBuilder::error(error)),
+    };
+
+    Ok(match removals.retry(host).await {
+        Ok((removed, 0)) => FormResponseBuilder::success(format!("Removed {removed} key(s)")),
+        Ok((removed, queued)) => {
+            FormResponseBuilder::error(format!("Removed {removed} key(s), {queued} still failing"))
+        }
+        Err(error) => FormResponseBuilder::error(error),
+    }
+    .add_trigger(String::from("reload-pending-removals")))
+}
+
+#[derive(Deserialize)]
+struct CancelRemovalForm {
+    id: i32,
+}
+
+#[post("/{name}/pending_removals/cancel")]
+async fn cancel_pending_removal(
+    db: Data<BlockingPool>,
+    host_name: Path<String>,
+    form: web::Form<CancelRemovalForm>,
+) -> actix_web::Result<impl Responder> {
+    let id = form.id;
+    let res = db
+        .run(move |conn| {
+            let host = Host::get_from_name_sync(conn, host_name.into_inner())?
+                .ok_or_else(|| String::from("Host not found"))?;
+            PendingRemoval::cancel(conn, &host, id)
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(()) => FormResponseBuilder::success(String::from(
+            "Cancelled the removal, the key stays on the host",
+        ))
+        .add_trigger(String::from("reload-pending-removals")),
+        Err(error) => FormResponseBuilder::error(error),
+    })
+}
+
+#[derive(Deserialize)]
+struct DecommissionForm {
+    #[serde(default)]
+    skip_unreachable: bool,
+}
+
+#[post("/{name}/decommission")]
+async fn decommission(
+    db: Data<BlockingPool>,
+    decommissioner: Data<Decommissioner>,
+    form: web::Form<DecommissionForm>,
+    host_name: Path<String>,
+) -> actix_web::Result<impl Responder> {
+    let host = match Host::get_from_name(&db, host_name.to_string()).await {
+        Ok(Some(host)) => host,
+        Ok(None) => return Ok(FormResponseBuilder::not_found("Host not found".to_owned())),
+        Err(error) => return Ok(FormResponseBuilder::error(error)),
+    };
+
+    Ok(
+        match decommissioner.start(host, form.skip_unreachable).await {
+            Ok(()) => FormResponseBuilder::success(String::from("Started decommissioning"))
+                .add_trigger("reload-decommission".to_owned()),
+            Err(error) => FormResponseBuilder::error(error),
+        },
+    )
+}
+
+#[derive(Template)]
+#[template(path = "hosts/decommission.htm")]
+struct DecommissionStatusTemplate {
+    host_name: String,
+    steps: Option<Vec<Step>>,
+}
+
+impl DecommissionStatusTemplate {
+    fn finished(&self) -> bool {
+        self.steps.as_deref().is_none_or(is_finished)
+    }
+
+    /// Whether the host was deleted
+    fn released(&self) -> bool {
+        self.steps.as_ref().is_some_and(|steps| {
+            steps
+                .get(RELEASE_STEP)
+                .is_some_and(|step| matches!(step.status, StepStatus::Done(_)))
+        })
+    }
+}
+
+#[get("/{name}/decommission.htm")]
+async fn decommission_status(
+    decommissioner: Data<Decommissioner>,
+    host_name: Path<String>,
+) -> impl Responder {
+    let steps = decommissioner.status(&host_name).await;
+    DecommissionStatusTemplate {
+        host_name: host_name.to_string(),
+        steps,
     }
 }
 
@@ -566,14 +1565,11 @@ struct DeleteAuthorizationForm {
 #[post("/delete_authorization")]
 async fn delete_authorization(
     form: web::Form<DeleteAuthorizationForm>,
-    conn: Data<ConnectionPool>,
+    db: Data<BlockingPool>,
 ) -> actix_web::Result<impl Responder> {
-    let res = web::block(move || {
-        let mut connection = conn.get().unwrap();
-
-        Host::delete_authorization(&mut connection, form.authorization_id)
-    })
-    .await?;
+    let res = db
+        .run(move |conn| Host::delete_authorization(conn, form.authorization_id))
+        .await?;
 
     Ok(match res {
         Ok(()) => FormResponseBuilder::success("Deleted authorization.".to_owned())
@@ -600,15 +1596,16 @@ struct EditHostView {
 
 #[get("/{name}/edit")]
 async fn edit_host_form(
-    conn: actix_web::web::Data<crate::ConnectionPool>,
+    db: actix_web::web::Data<BlockingPool>,
     host_name: actix_web::web::Path<String>,
+    req: HttpRequest,
 ) -> actix_web::Result<impl actix_web::Responder> {
-    let host_result = crate::models::Host::get_from_name(conn.get().unwrap(), host_name.to_string())
+    let host_result = crate::models::Host::get_from_name(&db, host_name.to_string())
         .await
         .map_err(actix_web::error::ErrorInternalServerError)?;
 
     if let Some(host) = host_result {
-        debug!("ssm::routes::hosts: Display edit form for host {}", host.name);
+        debug!(host = host.name.as_str(); "Display edit form for host {}", host.name);
         let view = EditHostView {
             name: host.name,
             address: host.address,
@@ -619,7 +1616,7 @@ async fn edit_host_form(
         };
         Ok(EditHostTemplate { host: view }.to_response())
     } else {
-        Ok(crate::routes::ErrorTemplate { error: "Host not found".to_string() }.to_response())
+        Ok(not_found(&req, "Host not found".to_string()))
     }
 }
 
@@ -662,23 +1659,32 @@ struct EditHostForm {
 
 #[post("/{name}/edit")]
 async fn edit_host(
-    conn: actix_web::web::Data<crate::ConnectionPool>,
+    db: actix_web::web::Data<BlockingPool>,
     host_name: actix_web::web::Path<String>,
     form: actix_web::web::Form<EditHostForm>,
 ) -> actix_web::Result<impl actix_web::Responder> {
-    let mut db_conn = conn.get().unwrap();
-    match crate::models::Host::update_host(
-        &mut db_conn,
-        host_name.to_string(),
-        form.name.clone(),
-        form.address.clone(),
-        form.username.clone(),
-        form.port,
-        form.key_fingerprint.clone(),
-        form.jump_via,
-    ) {
+    let old_name = host_name.to_string();
+    let res = db
+        .run(move |conn| {
+            crate::models::Host::update_host(
+                conn,
+                old_name,
+                form.name.clone(),
+                form.address.clone(),
+                form.username.clone(),
+                form.port,
+                form.key_fingerprint.clone(),
+                form.jump_via,
+            )
+            .map_err(|e| e.to_string())
+        })
+        .await?;
+    match res {
         Ok(()) => {
-            info!("ssm::routes::hosts: Host {} updated successfully", host_name);
+            info!(
+                host = host_name.as_str(), action = "edit_host";
+                "Host {host_name} updated successfully"
+            );
             Ok(actix_web::HttpResponse::Found().append_header(("Location", "/hosts")).finish())
         },
         Err(e) => Ok(crate::routes::ErrorTemplate { error: e.to_string() }.to_response()),
@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+use actix_web::{
+    get, post,
+    web::{self, Data},
+    Responder,
+};
+use askama_actix::{Template, TemplateToResponse};
+use croner::Cron;
+
+use crate::{
+    forms::FormResponseBuilder,
+    health::{HealthMonitor, HealthState},
+    Configuration,
+};
+
+pub fn jobs_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(jobs_page)
+        .service(render_health)
+        .service(check_health);
+}
+
+fn every(interval: Option<Duration>) -> String {
+    interval.map_or_else(
+        || String::from("Disabled"),
+        |interval| format!("Every {}s", interval.as_secs()),
+    )
+}
+
+fn schedule(cron: Option<&Cron>) -> String {
+    cron.map_or_else(
+        || String::from("Disabled"),
+        |cron| format!("Cron '{}'", cron.pattern),
+    )
+}
+
+#[derive(Template)]
+#[template(path = "jobs/index.html")]
+struct JobsTemplate {
+    /// Name and schedule of every fleet operation
+    jobs: Vec<(&'static str, String)>,
+}
+
+/// Background fleet operations and whether they are slowed down
+#[get("")]
+async fn jobs_page(config: Data<Configuration>) -> impl Responder {
+    let ssh = &config.ssh;
+    JobsTemplate {
+        jobs: vec![
+            ("Background sync", every(ssh.sync_interval)),
+            ("Check job", schedule(ssh.check_schedule.as_ref())),
+            ("Update job", schedule(ssh.update_schedule.as_ref())),
+            ("Integrity report", schedule(ssh.report_schedule.as_ref())),
+            ("Key removal retries", every(ssh.removal_retry_interval)),
+            (
+                "Authorization expiry",
+                every(ssh.authorization_expiry_interval),
+            ),
+        ],
+    }
+    .to_response()
+}
+
+#[derive(Template)]
+#[template(path = "jobs/health.htm")]
+struct HealthTemplate {
+    enabled: bool,
+    state: HealthState,
+}
+
+#[get("/health.htm")]
+async fn render_health(health: Data<HealthMonitor>) -> impl Responder {
+    HealthTemplate {
+        enabled: health.is_enabled(),
+        state: health.state().await,
+    }
+    .to_response()
+}
+
+/// Checks the health now instead of waiting for the next interval
+#[post("/check")]
+async fn check_health(health: Data<HealthMonitor>) -> impl Responder {
+    let status = health.check().await;
+    FormResponseBuilder::success(format!("Fleet operations are {status}"))
+        .add_trigger(String::from("reload-health"))
+}
@@ -7,25 +7,84 @@ use askama_actix::{Template, TemplateToResponse};
 use serde::Deserialize;
 
 use crate::{
-    db::UsernameAndKey, forms::FormResponseBuilder, routes::ErrorTemplate, ConnectionPool,
+    db::{BlockingPool, KeyWithOwner},
+    forms::FormResponseBuilder,
+    removal_queue::RemovalQueue,
+    routes::ErrorTemplate,
 };
 
-use crate::models::PublicUserKey;
+use crate::models::{parse_expiry, PublicUserKey};
+
+#[derive(Deserialize)]
+struct KeyFilter {
+    /// Key type, empty for every type
+    #[serde(default)]
+    algorithm: String,
+    /// "enabled" or "disabled" owners, empty for every owner
+    #[serde(default)]
+    owner: String,
+    #[serde(default)]
+    expired: bool,
+    /// Keys of users that aren't authorized anywhere
+    #[serde(default)]
+    unassigned: bool,
+    #[serde(default)]
+    weak: bool,
+}
+
+impl KeyFilter {
+    fn matches(&self, entry: &KeyWithOwner) -> bool {
+        (self.algorithm.is_empty() || entry.key.key_type == self.algorithm)
+            && match self.owner.as_str() {
+                "enabled" => entry.enabled,
+                "disabled" => !entry.enabled,
+                _ => true,
+            }
+            && (!self.expired || entry.key.is_expired())
+            && (!self.unassigned || !entry.assigned)
+            && (!self.weak || entry.key.weakness().is_some())
+    }
+}
 
 #[derive(Template)]
 #[template(path = "keys/index.html")]
 struct KeysPageTemplate {
-    keys: Vec<UsernameAndKey>,
+    keys: Vec<KeyWithOwner>,
+    filter: KeyFilter,
+    /// Key types to filter by
+    algorithms: Vec<String>,
+    /// Number of keys without filters
+    total: usize,
 }
 
 #[get("")]
-pub async fn list_keys(conn: Data<ConnectionPool>) -> actix_web::Result<impl Responder> {
-    let all_keys =
-        web::block(move || PublicUserKey::get_all_keys_with_username(&mut conn.get().unwrap()))
-            .await?;
+pub async fn list_keys(
+    db: Data<BlockingPool>,
+    filter: web::Query<KeyFilter>,
+) -> actix_web::Result<impl Responder> {
+    let all_keys = db.run(PublicUserKey::get_all_keys_with_owner).await?;
 
     Ok(match all_keys {
-        Ok(keys) => KeysPageTemplate { keys }.to_response(),
+        Ok(keys) => {
+            let filter = filter.into_inner();
+            let mut algorithms: Vec<String> = keys
+                .iter()
+                .map(|entry| entry.key.key_type.clone())
+                .collect();
+            algorithms.sort();
+            algorithms.dedup();
+            let total = keys.len();
+            KeysPageTemplate {
+                keys: keys
+                    .into_iter()
+                    .filter(|entry| filter.matches(entry))
+                    .collect(),
+                filter,
+                algorithms,
+                total,
+            }
+            .to_response()
+        }
         Err(error) => ErrorTemplate { error }.to_response(),
     })
 }
@@ -37,20 +96,66 @@ struct DeleteKeyForm {
 
 #[post("delete")]
 pub async fn delete(
-    conn: Data<ConnectionPool>,
+    db: Data<BlockingPool>,
     form: web::Form<DeleteKeyForm>,
 ) -> actix_web::Result<impl Responder> {
-    let res =
-        web::block(move || PublicUserKey::delete_key(&mut conn.get().unwrap(), form.id)).await?;
+    let res = db
+        .run(move |conn| PublicUserKey::delete_key(conn, form.id))
+        .await?;
 
     Ok(match res {
-        Ok(()) => FormResponseBuilder::success("Deleted key".to_owned())
+        Ok(_) => FormResponseBuilder::success("Deleted key".to_owned())
             .add_trigger("reload-keys".to_owned())
             .into_response(),
         Err(e) => FormResponseBuilder::error(e).into_response(),
     })
 }
 
+/// Deletes a key and removes it from every host, see `RemovalQueue::revoke_everywhere`
+#[post("/revoke")]
+async fn revoke(
+    removals: Data<RemovalQueue>,
+    form: web::Form<DeleteKeyForm>,
+) -> actix_web::Result<impl Responder> {
+    let results = match removals.revoke_everywhere(form.id).await {
+        Ok(results) => results,
+        Err(e) => return Ok(FormResponseBuilder::error(e)),
+    };
+
+    let (removed, failed): (Vec<_>, Vec<_>) = results
+        .into_iter()
+        .partition(|result| result.error.is_none());
+    let mut message = match (removed.is_empty(), failed.is_empty()) {
+        (true, true) => String::from("Revoked the key, it wasn't on any host"),
+        (true, false) => String::from("Revoked the key"),
+        (false, _) => format!(
+            "Revoked the key and removed it from {}",
+            removed
+                .iter()
+                .map(|result| format!("{}@{}", result.login, result.host))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    if failed.is_empty() {
+        return Ok(FormResponseBuilder::success(message));
+    }
+    message += &format!(
+        ". Failed on {}, retrying later",
+        failed
+            .iter()
+            .map(|result| format!(
+                "{}@{} ({})",
+                result.login,
+                result.host,
+                result.error.as_deref().unwrap_or_default()
+            ))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    Ok(FormResponseBuilder::error(message))
+}
+
 #[derive(Deserialize)]
 struct UpdateKeyCommentForm {
     comment: String,
@@ -58,16 +163,14 @@ struct UpdateKeyCommentForm {
 
 #[post("/update_comment/{id}")]
 pub async fn update_key_comment(
-    conn: Data<ConnectionPool>,
+    db: Data<BlockingPool>,
     key_id: web::Path<i32>,
     form: web::Form<UpdateKeyCommentForm>,
 ) -> actix_web::Result<impl Responder> {
     let key_id = key_id.into_inner();
-    let result = web::block(move || {
-        let mut conn = conn.get().unwrap();
-        PublicUserKey::update_comment(&mut conn, key_id, &form.comment)
-    })
-    .await?;
+    let result = db
+        .run(move |conn| PublicUserKey::update_comment(conn, key_id, &form.comment))
+        .await?;
 
     Ok(match result {
         Ok(()) => FormResponseBuilder::success("Comment updated successfully".to_owned())
@@ -77,8 +180,42 @@ pub async fn update_key_comment(
     })
 }
 
+#[derive(Deserialize)]
+struct UpdateKeyExpiryForm {
+    /// Empty to keep the key forever
+    #[serde(default)]
+    expires_at: String,
+}
+
+#[post("/update_expiry/{id}")]
+async fn update_key_expiry(
+    db: Data<BlockingPool>,
+    key_id: web::Path<i32>,
+    form: web::Form<UpdateKeyExpiryForm>,
+) -> actix_web::Result<impl Responder> {
+    let expires_at = match parse_expiry(&form.expires_at) {
+        Ok(expires_at) => expires_at,
+        Err(e) => return Ok(FormResponseBuilder::error(e)),
+    };
+    let key_id = key_id.into_inner();
+    let result = db
+        .run(move |conn| PublicUserKey::set_expiry(conn, key_id, expires_at))
+        .await?;
+
+    Ok(match result {
+        Ok(()) => FormResponseBuilder::success(match expires_at {
+            Some(_) => String::from("Expiry date set"),
+            None => String::from("The key doesn't expire anymore"),
+        })
+        .add_trigger("reload-keys".to_owned()),
+        Err(e) => FormResponseBuilder::error(e),
+    })
+}
+
 pub fn keys_config(cfg: &mut web::ServiceConfig) {
     cfg.service(list_keys)
         .service(delete)
-        .service(update_key_comment);
+        .service(revoke)
+        .service(update_key_comment)
+        .service(update_key_expiry);
 }
@@ -0,0 +1,114 @@
+use std::{sync::Arc, time::Duration};
+
+use actix_web::{
+    get,
+    web::{self, Bytes, Data},
+    HttpRequest, HttpResponse, Responder,
+};
+use askama_actix::Template;
+use futures::stream;
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::log_tail::{LogBuffer, LogEvent, LogFilter};
+
+/// Proxies close connections that stay silent for too long
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+pub fn logs_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(logs_page).service(stream_logs);
+}
+
+#[derive(Template)]
+#[template(path = "logs/index.html")]
+struct LogsTemplate {
+    events: Vec<LogEvent>,
+    filter: LogFilter,
+    levels: [&'static str; 5],
+}
+
+impl LogsTemplate {
+    fn is_level(&self, level: &str) -> bool {
+        self.filter.level == level
+    }
+}
+
+#[get("")]
+async fn logs_page(buffer: Data<Arc<LogBuffer>>, filter: web::Query<LogFilter>) -> impl Responder {
+    let filter = filter.into_inner();
+    LogsTemplate {
+        events: buffer.recent(&filter, 0),
+        filter,
+        levels: ["ERROR", "WARN", "INFO", "DEBUG", "TRACE"],
+    }
+}
+
+#[derive(Deserialize)]
+struct StreamQuery {
+    #[serde(flatten)]
+    filter: LogFilter,
+    /// Id of the last event the page already shows
+    #[serde(default)]
+    after: u64,
+}
+
+fn server_sent_event(event: &LogEvent) -> Bytes {
+    let data = serde_json::to_string(event).unwrap_or_default();
+    Bytes::from(format!("id: {}\ndata: {data}\n\n", event.id))
+}
+
+/// Streams the matching events as server-sent events, starting with the buffered
+/// ones the page doesn't show yet
+#[get("/stream")]
+async fn stream_logs(
+    req: HttpRequest,
+    buffer: Data<Arc<LogBuffer>>,
+    query: web::Query<StreamQuery>,
+) -> HttpResponse {
+    let StreamQuery { filter, after } = query.into_inner();
+    // Sent by the browser when it reconnects
+    let after = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|id| id.to_str().ok()?.parse().ok())
+        .map_or(after, |id: u64| id.max(after));
+    // Subscribing first means nothing logged in between is missed
+    let live = buffer.subscribe();
+    let missed = buffer.recent(&filter, after);
+    let last = missed.last().map_or(after, |event| event.id);
+
+    let missed = stream::iter(
+        missed
+            .iter()
+            .map(|event| Ok::<_, actix_web::Error>(server_sent_event(event)))
+            .collect::<Vec<_>>(),
+    );
+    let live = stream::unfold(
+        (live, filter, last),
+        |(mut live, filter, last)| async move {
+            loop {
+                match tokio::time::timeout(KEEPALIVE, live.recv()).await {
+                    Err(_) => {
+                        return Some((
+                            Ok(Bytes::from_static(b": keepalive\n\n")),
+                            (live, filter, last),
+                        ))
+                    }
+                    Ok(Ok(event)) if event.id > last && filter.matches(&event) => {
+                        let bytes = server_sent_event(&event);
+                        return Some((Ok(bytes), (live, filter, event.id)));
+                    }
+                    Ok(Ok(_) | Err(RecvError::Lagged(_))) => {}
+                    Ok(Err(RecvError::Closed)) => return None,
+                }
+            }
+        },
+    );
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        // Keeps nginx from buffering the stream
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(futures::StreamExt::chain(missed, live))
+}
@@ -0,0 +1,122 @@
+use actix_web::{
+    get,
+    http::header,
+    web::{self, Data},
+    HttpRequest, HttpResponse,
+};
+
+use crate::{
+    db::{BlockingPool, Inventory},
+    metrics::Exposition,
+    perf::PerfStats,
+    ssh::{CachingSshClient, SshClient},
+    Configuration,
+};
+
+pub fn metrics_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(metrics);
+}
+
+/// Compares without returning early, so the time taken says nothing about the token
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Metrics in the Prometheus text format. Only served with `metrics_token` configured,
+/// which scrapers send as bearer token.
+#[get("")]
+async fn metrics(
+    req: HttpRequest,
+    config: Data<Configuration>,
+    db: Data<BlockingPool>,
+    ssh_client: Data<SshClient>,
+    caching_ssh_client: Data<CachingSshClient>,
+    perf: Data<PerfStats>,
+) -> HttpResponse {
+    let Some(token) = &config.metrics_token else {
+        return HttpResponse::NotFound().finish();
+    };
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|bearer| constant_time_eq(bearer.trim().as_bytes(), token.as_bytes()));
+    if !authorized {
+        return HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+            .finish();
+    }
+
+    let mut exposition = Exposition::default();
+    let (attempts, failures) = ssh_client.connection_totals();
+    exposition.counter(
+        "ssm_ssh_connection_attempts_total",
+        "SSH connections to hosts that were attempted",
+        attempts,
+    );
+    exposition.counter(
+        "ssm_ssh_connection_failures_total",
+        "SSH connections to hosts that failed",
+        failures,
+    );
+    exposition.fleet(caching_ssh_client.fleet_metrics());
+
+    let pool = db.stats();
+    exposition.gauge(
+        "ssm_db_pool_size",
+        "Database operations that can run at once",
+        i64::try_from(pool.size).unwrap_or(i64::MAX),
+    );
+    exposition.gauge(
+        "ssm_db_queued",
+        "Database operations waiting for a free slot",
+        i64::try_from(pool.queued).unwrap_or(i64::MAX),
+    );
+    exposition.gauge(
+        "ssm_db_running",
+        "Database operations running",
+        i64::try_from(pool.running).unwrap_or(i64::MAX),
+    );
+    exposition.counter(
+        "ssm_db_rejected_total",
+        "Database operations that got no slot before the timeout",
+        pool.rejected,
+    );
+    exposition.counter(
+        "ssm_db_timed_out_total",
+        "Database operations that didn't finish before the timeout",
+        pool.timed_out,
+    );
+    exposition.histogram(
+        "ssm_db_wait_seconds",
+        "Time database operations waited for a free slot",
+        &db.wait_times(),
+    );
+
+    // The metrics above are still useful while the database is unavailable
+    if let Ok(Ok(inventory)) = db.run_read(Inventory::count).await {
+        exposition.gauge("ssm_hosts", "Hosts in the database", inventory.hosts);
+        exposition.gauge("ssm_users", "Users in the database", inventory.users);
+        exposition.gauge("ssm_keys", "Public keys of users", inventory.keys);
+        exposition.gauge(
+            "ssm_authorizations",
+            "Direct authorizations of users on hosts",
+            inventory.authorizations,
+        );
+    }
+
+    let routes = perf.routes();
+    exposition.histograms(
+        "ssm_http_request_duration_seconds",
+        "Handler latency by method and route pattern",
+        "route",
+        routes
+            .iter()
+            .map(|route| (route.route.as_str(), &route.histogram)),
+    );
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(exposition.finish())
+}
@@ -1,25 +1,71 @@
+mod account;
+mod admin;
+mod api;
+mod approvals;
+mod archive;
+mod audit;
 pub mod auth;
+mod branding;
 mod diff;
+mod findings;
 mod hosts;
+mod jobs;
 mod keys;
+mod logs;
+mod metrics;
+mod perf;
+mod portal;
+mod probes;
+mod reports;
+mod selection;
+mod teams;
+mod tokens;
 mod users;
+mod web_users;
 
 use actix_web::{
+    body::MessageBody,
     get,
-    http::StatusCode,
+    http::{
+        header::{ContentDisposition, DispositionParam, DispositionType},
+        Method, StatusCode,
+    },
     web::{self},
-    Responder,
+    HttpMessage, HttpRequest, HttpResponse, Responder,
 };
 use askama_actix::Template;
 use serde::Deserialize;
 
+use crate::{audit::Actor, auth::Role, forms::FormResponseBuilder, Configuration};
+
+pub use api::ApiResponse;
+
 pub fn route_config(cfg: &mut web::ServiceConfig) {
     cfg.service(index)
+        .service(web::scope("/api").configure(api::api_config))
         .service(web::scope("/hosts").configure(hosts::hosts_config))
         .service(web::scope("/users").configure(users::users_config))
+        .service(web::scope("/teams").configure(teams::teams_config))
         .service(web::scope("/keys").configure(keys::keys_config))
         .service(web::scope("/diff").configure(diff::diff_config))
-        .default_service(web::to(not_found));
+        .service(web::scope("/archive").configure(archive::archive_config))
+        .service(web::scope("/tokens").configure(tokens::tokens_config))
+        .service(web::scope("/perf").configure(perf::perf_config))
+        .service(web::scope("/logs").configure(logs::logs_config))
+        .service(web::scope("/metrics").configure(metrics::metrics_config))
+        .service(web::scope("/web_users").configure(web_users::web_users_config))
+        .service(web::scope("/audit").configure(audit::audit_config))
+        .service(web::scope("/admin").configure(admin::admin_config))
+        .service(web::scope("/findings").configure(findings::findings_config))
+        .service(web::scope("/approvals").configure(approvals::approvals_config))
+        .service(web::scope("/jobs").configure(jobs::jobs_config))
+        .service(web::scope("/reports").configure(reports::reports_config))
+        .service(web::scope("/portal").configure(portal::portal_config))
+        .service(web::scope("/account").configure(account::account_config))
+        .service(web::scope("/selection").configure(selection::selection_config))
+        .configure(probes::probes_config)
+        .configure(branding::branding_config)
+        .default_service(web::to(page_not_found));
 }
 
 #[derive(Deserialize)]
@@ -29,6 +75,21 @@ struct ForceUpdateQuery {
 
 type ForceUpdate = web::Query<ForceUpdateQuery>;
 
+/// A response the browser downloads as a file
+fn attachment(
+    content_type: &str,
+    filename: String,
+    body: impl MessageBody + 'static,
+) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(filename)],
+        })
+        .body(body)
+}
+
 fn should_update(force_update: ForceUpdate) -> bool {
     force_update.force_update.is_some_and(|update| update)
 }
@@ -47,12 +108,96 @@ struct RenderErrorTemplate {
 
 #[derive(Template)]
 #[template(path = "404.html")]
-struct NotFoundTemplate {}
+struct NotFoundTemplate {
+    error: String,
+}
 
-async fn not_found() -> impl Responder {
-    NotFoundTemplate {}
+async fn page_not_found(req: HttpRequest) -> HttpResponse {
+    not_found(&req, String::from("The requested page was not found"))
+}
+
+/// Response for a failed request with the right status code. The API gets JSON, HTMX forms a
+/// message, HTMX fragments the error in their place and page loads the error page
+fn error_response(req: &HttpRequest, status: StatusCode, error: String) -> HttpResponse {
+    if req.path().starts_with("/api/") {
+        return ApiResponse::with_status(status, error);
+    }
+    if req.headers().contains_key("HX-Request") {
+        if req.method() == Method::GET {
+            // forms.js swaps responses with this header in despite the status code
+            return RenderErrorTemplate { error }
+                .customize()
+                .with_status(status)
+                .insert_header(("X-ERROR-FRAGMENT", "true"))
+                .respond_to(req)
+                .map_into_boxed_body();
+        }
+        return FormResponseBuilder::error(error)
+            .set_status(status)
+            .into_response();
+    }
+    if status == StatusCode::NOT_FOUND {
+        return NotFoundTemplate { error }
+            .customize()
+            .with_status(status)
+            .respond_to(req)
+            .map_into_boxed_body();
+    }
+    ErrorTemplate { error }
         .customize()
-        .with_status(StatusCode::NOT_FOUND)
+        .with_status(status)
+        .respond_to(req)
+        .map_into_boxed_body()
+}
+
+/// Response for something that doesn't exist
+fn not_found(req: &HttpRequest, error: String) -> HttpResponse {
+    error_response(req, StatusCode::NOT_FOUND, error)
+}
+
+/// The role the `AuthMiddleware` found for this request
+fn role_of(req: &HttpRequest) -> Role {
+    req.extensions()
+        .get::<Role>()
+        .copied()
+        .unwrap_or(Role::Viewer)
+}
+
+/// Who made this request, as recorded in the audit log
+fn actor_of(req: &HttpRequest) -> String {
+    req.extensions()
+        .get::<Actor>()
+        .map_or_else(|| String::from("unknown"), |actor| actor.0.clone())
+}
+
+/// Why authorizations of several hosts at once are refused while they need approval
+const APPROVAL_REQUIRED: &str =
+    "Authorizations need the approval of an admin, propose them one host at a time";
+
+/// Who proposes the authorizations of this request, if they need the approval of an admin
+fn proposer(req: &HttpRequest, config: &Configuration) -> Option<String> {
+    if !config.authorization_approval || role_of(req) >= Role::Admin {
+        return None;
+    }
+    Some(actor_of(req))
+}
+
+/// Response for users whose role doesn't allow the request
+pub fn forbidden(req: &HttpRequest, required: Role) -> HttpResponse {
+    error_response(
+        req,
+        StatusCode::FORBIDDEN,
+        format!("This requires the {required} role"),
+    )
+}
+
+/// Response for requests without the CSRF token of the session
+pub fn invalid_csrf_token(req: &HttpRequest) -> HttpResponse {
+    error_response(
+        req,
+        StatusCode::FORBIDDEN,
+        String::from("The page has expired, please reload it and try again"),
+    )
 }
 
 #[derive(Template)]
@@ -0,0 +1,34 @@
+use actix_web::{
+    get,
+    web::{self, Data},
+    Responder,
+};
+use askama_actix::Template;
+
+use crate::{
+    db::{BlockingPool, BlockingStats},
+    perf::{PerfStats, RouteTiming, SlowQuery},
+};
+
+pub fn perf_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(perf_page);
+}
+
+#[derive(Template)]
+#[template(path = "perf/index.html")]
+struct PerfTemplate {
+    pool: BlockingStats,
+    routes: Vec<RouteTiming>,
+    slow_queries: Vec<SlowQuery>,
+    slow_query_threshold: std::time::Duration,
+}
+
+#[get("")]
+async fn perf_page(db: Data<BlockingPool>, perf: Data<PerfStats>) -> impl Responder {
+    PerfTemplate {
+        pool: db.stats(),
+        routes: perf.routes(),
+        slow_queries: perf.slow_queries(),
+        slow_query_threshold: perf.slow_query_threshold(),
+    }
+}
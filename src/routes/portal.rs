@@ -0,0 +1,436 @@
+use actix_identity::Identity;
+use actix_web::{
+    get, post,
+    web::{self, Data, Path},
+    HttpRequest, Responder,
+};
+use askama_actix::{Template, TemplateToResponse};
+use log::warn;
+use serde::Deserialize;
+
+use crate::{
+    attestation,
+    auth::Role,
+    db::{self, AccessRequestWithNames, BlockingPool, UserAndOptions, DENIED, PENDING},
+    forms::FormResponseBuilder,
+    models::{
+        parse_key_line, AccessRequest, Host, NewAccessRequest, NewPendingAuthorization,
+        PendingAuthorization, PublicUserKey, User,
+    },
+    routes::{proposer, role_of, users::with_fingerprints, ErrorTemplate, RenderErrorTemplate},
+    ssh::SshClient,
+    Configuration, DbConnection,
+};
+
+pub fn portal_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(portal_page)
+        .service(render_account)
+        .service(add_own_keys)
+        .service(render_requests)
+        .service(request_access)
+        .service(approve_request)
+        .service(deny_request);
+}
+
+#[derive(Template)]
+#[template(path = "portal/index.html")]
+struct PortalTemplate {
+    /// The user with the same name as the web user
+    user: Option<User>,
+    hosts: Vec<String>,
+    /// For creating an attested key, if attestation is configured
+    challenge: Option<String>,
+}
+
+/// Self-service page of the logged in web user
+#[get("")]
+async fn portal_page(
+    db: Data<BlockingPool>,
+    identity: Identity,
+) -> actix_web::Result<impl Responder> {
+    let username = identity.id().unwrap_or_default();
+    let challenge = attestation::challenge(&username).ok();
+
+    let res = db
+        .run(move |conn| {
+            let user = User::get_from_name(conn, &username)?;
+            let hosts = Host::get_all_hosts(conn)?
+                .into_iter()
+                .map(|host| host.name)
+                .collect();
+            Ok::<_, String>((user, hosts))
+        })
+        .await?;
+
+    Ok(match res {
+        Ok((user, hosts)) => PortalTemplate {
+            user,
+            hosts,
+            challenge,
+        }
+        .to_response(),
+        Err(error) => ErrorTemplate { error }.to_response(),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "users/portal_account.html")]
+struct AccountTemplate {
+    keys: Vec<(PublicUserKey, Result<String, String>)>,
+    authorizations: Vec<UserAndOptions>,
+}
+
+/// Keys and access of the logged in web user
+#[get("/account.htm")]
+async fn render_account(
+    db: Data<BlockingPool>,
+    identity: Identity,
+) -> actix_web::Result<impl Responder> {
+    let username = identity.id().unwrap_or_default();
+
+    let res = db
+        .run(move |conn| {
+            let Some(user) = User::get_from_name(conn, &username)? else {
+                return Ok((Vec::new(), Vec::new()));
+            };
+            Ok::<_, String>((user.get_keys(conn)?, user.get_authorizations(conn)?))
+        })
+        .await?;
+
+    Ok(match res {
+        Ok((keys, authorizations)) => AccountTemplate {
+            keys: with_fingerprints(keys),
+            authorizations,
+        }
+        .to_response(),
+        Err(error) => RenderErrorTemplate { error }.to_response(),
+    })
+}
+
+#[derive(Deserialize)]
+struct AddKeysForm {
+    /// Public keys in OpenSSH format, one per line
+    keys: String,
+    /// Base64 encoded FIDO attestation of a single sk- key
+    #[serde(default)]
+    attestation: String,
+    /// The challenge the attested key was created with
+    #[serde(default)]
+    challenge: String,
+}
+
+/// Adds public keys to the user of the logged in web user
+#[post("/keys")]
+async fn add_own_keys(
+    db: Data<BlockingPool>,
+    identity: Identity,
+    form: web::Form<AddKeysForm>,
+) -> actix_web::Result<impl Responder> {
+    let username = identity.id().unwrap_or_default();
+    let keys: Vec<String> = form
+        .keys
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(ToOwned::to_owned)
+        .collect();
+    if keys.is_empty() {
+        return Ok(FormResponseBuilder::error(String::from(
+            "Please paste at least one public key",
+        )));
+    }
+    let mut attested = None;
+    if !form.attestation.trim().is_empty() {
+        let [key] = keys.as_slice() else {
+            return Ok(FormResponseBuilder::error(String::from(
+                "An attestation is for a single key",
+            )));
+        };
+        let res = ssh_key::PublicKey::from_openssh(key)
+            .map_err(|_| format!("Invalid key '{key}'"))
+            .and_then(|key| {
+                attestation::verify(&username, &key, &form.attestation, &form.challenge)
+            });
+        match res {
+            Ok(attestation) => attested = Some(attestation),
+            Err(error) => return Ok(FormResponseBuilder::error(error)),
+        }
+    }
+
+    let res = db
+        .run(move |conn| {
+            let user = User::get_from_name(conn, &username)?
+                .ok_or_else(|| format!("There is no user named '{username}' to add keys to"))?;
+
+            // Keep going, so one invalid key doesn't block the others
+            let failed: Vec<String> = keys
+                .iter()
+                .filter_map(|line| {
+                    ssh_key::PublicKey::from_openssh(line)
+                        .map_err(|_| format!("Invalid key '{line}'"))
+                        .and_then(|_| parse_key_line(line, user.id))
+                        .map(|key| match attested.take() {
+                            Some(attestation) => key.with_attestation(attestation),
+                            None => key,
+                        })
+                        .and_then(|key| PublicUserKey::add_key(conn, key))
+                        .err()
+                })
+                .collect();
+            Ok::<_, String>((keys.len() - failed.len(), failed))
+        })
+        .await?;
+
+    Ok(match res {
+        Ok((added, failed)) if failed.is_empty() => {
+            FormResponseBuilder::created(format!("Added {added} key(s)"))
+        }
+        Ok((added, failed)) => FormResponseBuilder::error(format!(
+            "Added {added} key(s), {} failed: {}",
+            failed.len(),
+            failed.join(", ")
+        )),
+        Err(error) => FormResponseBuilder::error(error),
+    }
+    .add_trigger(String::from("reload-portal-account")))
+}
+
+#[derive(Template)]
+#[template(path = "portal/requests.htm")]
+struct RequestsTemplate {
+    own: Vec<AccessRequestWithNames>,
+    to_approve: Vec<AccessRequestWithNames>,
+}
+
+#[get("/requests.htm")]
+async fn render_requests(
+    db: Data<BlockingPool>,
+    identity: Identity,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let username = identity.id().unwrap_or_default();
+    // Operators approve requests for every host, others only for the hosts they own
+    let is_operator = role_of(&req) >= Role::Operator;
+
+    let res = db
+        .run(move |conn| {
+            let own = AccessRequest::get_by_requester(conn, &username)?;
+            let approver = (!is_operator).then_some(username.as_str());
+            let to_approve = AccessRequest::get_pending(conn, approver)?
+                .into_iter()
+                .filter(|(request, _, _)| request.requested_by != username)
+                .collect();
+            Ok::<_, String>((own, to_approve))
+        })
+        .await?;
+
+    Ok(match res {
+        Ok((own, to_approve)) => RequestsTemplate { own, to_approve }.to_response(),
+        Err(error) => RenderErrorTemplate { error }.to_response(),
+    })
+}
+
+#[derive(Deserialize)]
+struct RequestAccessForm {
+    host_name: String,
+    login: String,
+    justification: String,
+}
+
+#[post("/request")]
+async fn request_access(
+    db: Data<BlockingPool>,
+    identity: Identity,
+    form: web::Form<RequestAccessForm>,
+) -> actix_web::Result<impl Responder> {
+    let username = identity.id().unwrap_or_default();
+    let form = form.into_inner();
+    if form.login.trim().is_empty() || form.justification.trim().is_empty() {
+        return Ok(FormResponseBuilder::error(String::from(
+            "Please enter a login and why you need access",
+        )));
+    }
+
+    let res = db
+        .run(move |conn| {
+            let user = User::get_from_name(conn, &username)?
+                .ok_or_else(|| format!("There is no user named '{username}' to give access to"))?;
+            let host = Host::get_from_name_sync(conn, form.host_name)?
+                .ok_or_else(|| String::from("Host not found"))?;
+            AccessRequest::create(
+                conn,
+                NewAccessRequest {
+                    created_at: db::now(),
+                    requested_by: username,
+                    user_id: user.id,
+                    host_id: host.id,
+                    login: form.login.trim().to_owned(),
+                    justification: form.justification.trim().to_owned(),
+                    status: PENDING.to_owned(),
+                },
+            )?;
+            Ok::<_, String>(host.owner)
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(owner) => FormResponseBuilder::success(match owner {
+            Some(owner) => format!("Requested access, waiting for approval by {owner}"),
+            None => String::from("Requested access, waiting for approval by an operator"),
+        })
+        .add_trigger(String::from("reload-access-requests")),
+        Err(error) => FormResponseBuilder::error(error),
+    })
+}
+
+/// Loads a pending request and checks that the current web user may decide it
+fn decidable_request(
+    conn: &mut DbConnection,
+    id: i32,
+    username: &str,
+    role: Role,
+) -> Result<(AccessRequest, Host), String> {
+    let request =
+        AccessRequest::get_from_id(conn, id)?.ok_or_else(|| String::from("Request not found"))?;
+    let host = Host::get_from_id_sync(conn, request.host_id)?
+        .ok_or_else(|| String::from("Host not found"))?;
+
+    if request.requested_by == username {
+        return Err(String::from("You can't decide your own request"));
+    }
+    let is_owner = host.owner.as_deref() == Some(username);
+    if !is_owner && role < Role::Operator {
+        return Err(String::from(
+            "Only the owner of the host or an operator can decide this request",
+        ));
+    }
+    if request.status != PENDING {
+        return Err(String::from("This request was already decided"));
+    }
+    Ok((request, host))
+}
+
+/// Result of an approved request whose authorization waits for an admin
+const PROPOSED: &str = "Proposed, waiting for the approval of an admin";
+
+#[post("/requests/{id}/approve")]
+async fn approve_request(
+    db: Data<BlockingPool>,
+    ssh_client: Data<SshClient>,
+    config: Data<Configuration>,
+    identity: Identity,
+    req: HttpRequest,
+    id: Path<i32>,
+) -> actix_web::Result<impl Responder> {
+    let username = identity.id().unwrap_or_default();
+    let (id, role) = (id.into_inner(), role_of(&req));
+    let proposed_by = proposer(&req, &config);
+    let proposal = proposed_by.is_some();
+
+    let res = db
+        .run(move |conn| {
+            let (request, host) = decidable_request(conn, id, &username, role)?;
+            match proposed_by {
+                Some(proposed_by) => {
+                    request.approve(conn, &username, Some(PROPOSED.to_owned()), |conn| {
+                        PendingAuthorization::propose(
+                            conn,
+                            NewPendingAuthorization {
+                                created_at: db::now(),
+                                proposed_by,
+                                host_id: host.id,
+                                user_id: request.user_id,
+                                login: request.login.clone(),
+                                options: None,
+                                expires_at: None,
+                                status: PENDING.to_owned(),
+                            },
+                        )
+                    })?
+                }
+                None => request.approve(conn, &username, None, |conn| {
+                    Host::authorize_user(
+                        conn,
+                        host.id,
+                        request.user_id,
+                        request.login.clone(),
+                        None,
+                        None,
+                    )
+                })?,
+            }
+            Ok((request, host))
+        })
+        .await?;
+    let (request, host) = match res {
+        Ok(res) => res,
+        Err(error) => return Ok(FormResponseBuilder::error(error)),
+    };
+    if proposal {
+        return Ok(
+            FormResponseBuilder::success(format!("Approved. {PROPOSED}"))
+                .add_trigger(String::from("reload-access-requests")),
+        );
+    }
+
+    // Deploy right away, so the requester doesn't have to wait for the next sync
+    let login = request.login.clone();
+    let client = SshClient::clone(&ssh_client);
+    let keys_host = host.clone();
+    let deployed = match db
+        .run(move |conn| keys_host.get_authorized_keys_file_for(&client, conn, &login))
+        .await
+        .map_err(String::from)
+        .and_then(|res| res)
+    {
+        Ok(authorized_keys) => ssh_client
+            .set_authorized_keys(host.name.clone(), request.login.clone(), authorized_keys)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+        Err(e) => Err(e),
+    };
+
+    let result = match &deployed {
+        Ok(()) => format!("Deployed to {}@{}", request.login, host.name),
+        Err(e) => {
+            warn!("Failed to deploy access request {}: {e}", request.id);
+            format!("Authorized, but deploying failed: {e}")
+        }
+    };
+    let recorded = result.clone();
+    let res = db
+        .run(move |conn| request.set_result(conn, recorded))
+        .await?;
+
+    Ok(match (res, deployed) {
+        (Err(error), _) => FormResponseBuilder::error(error),
+        (Ok(()), Ok(())) => FormResponseBuilder::success(format!("Approved. {result}")),
+        (Ok(()), Err(_)) => FormResponseBuilder::error(format!("Approved. {result}")),
+    }
+    .add_trigger(String::from("reload-access-requests")))
+}
+
+#[post("/requests/{id}/deny")]
+async fn deny_request(
+    db: Data<BlockingPool>,
+    identity: Identity,
+    req: HttpRequest,
+    id: Path<i32>,
+) -> actix_web::Result<impl Responder> {
+    let username = identity.id().unwrap_or_default();
+    let (id, role) = (id.into_inner(), role_of(&req));
+
+    let res = db
+        .run(move |conn| {
+            let (request, _) = decidable_request(conn, id, &username, role)?;
+            request.decide(conn, DENIED, &username, None)
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(()) => FormResponseBuilder::success(String::from("Denied the request")),
+        Err(error) => FormResponseBuilder::error(error),
+    }
+    .add_trigger(String::from("reload-access-requests")))
+}
@@ -0,0 +1,56 @@
+use actix_web::{
+    get,
+    web::{self, Data},
+    HttpResponse,
+};
+use diesel::{sql_query, RunQueryDsl};
+
+use crate::{db::BlockingPool, load_private_key, Configuration};
+
+pub fn probes_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(healthz).service(readyz);
+}
+
+/// Whether the pool hands out a connection that answers a query
+async fn check_database(db: &BlockingPool) -> Result<(), String> {
+    db.run(|conn| sql_query("SELECT 1").execute(conn))
+        .await
+        .map_err(|e| e.to_string())?
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// One line per check, with 503 if any of them failed
+fn probe_response(checks: &[(&str, Result<(), String>)]) -> HttpResponse {
+    let body: String = checks
+        .iter()
+        .map(|(name, res)| match res {
+            Ok(()) => format!("{name}: ok\n"),
+            Err(e) => format!("{name}: {e}\n"),
+        })
+        .collect();
+    if checks.iter().all(|(_, res)| res.is_ok()) {
+        HttpResponse::Ok().content_type("text/plain").body(body)
+    } else {
+        HttpResponse::ServiceUnavailable()
+            .content_type("text/plain")
+            .body(body)
+    }
+}
+
+/// Liveness probe, fails while the database is unreachable
+#[get("/healthz")]
+async fn healthz(db: Data<BlockingPool>) -> HttpResponse {
+    probe_response(&[("database", check_database(&db).await)])
+}
+
+/// Readiness probe. With `probe_ssh_key`, it also fails while the private key can't be
+/// loaded, e.g. because a mounted secret is missing.
+#[get("/readyz")]
+async fn readyz(db: Data<BlockingPool>, config: Data<Configuration>) -> HttpResponse {
+    let mut checks = vec![("database", check_database(&db).await)];
+    if config.probe_ssh_key {
+        checks.push(("ssh_key", load_private_key(&config.ssh).map(|_| ())));
+    }
+    probe_response(&checks)
+}
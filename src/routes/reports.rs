@@ -0,0 +1,104 @@
+use actix_web::{
+    get, post,
+    web::{self, Data, Path},
+    HttpRequest, Responder,
+};
+use askama_actix::{Template, TemplateToResponse};
+
+use crate::{
+    db::BlockingPool,
+    forms::FormResponseBuilder,
+    models::IntegrityReport,
+    report::{changes, HostChanges, HostReport, IntegrityReporter},
+    routes::{not_found, ErrorTemplate},
+};
+
+pub fn reports_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(reports_page)
+        .service(generate_report)
+        .service(show_report);
+}
+
+#[derive(Template)]
+#[template(path = "reports/index.html")]
+struct ReportsTemplate {
+    reports: Vec<(i32, time::PrimitiveDateTime, String)>,
+    running: bool,
+}
+
+#[get("")]
+async fn reports_page(
+    db: Data<BlockingPool>,
+    reporter: Data<IntegrityReporter>,
+) -> actix_web::Result<impl Responder> {
+    Ok(match db.run_read(IntegrityReport::get_all).await? {
+        Ok(reports) => ReportsTemplate {
+            reports,
+            running: reporter.is_running(),
+        }
+        .to_response(),
+        Err(error) => ErrorTemplate { error }.to_response(),
+    })
+}
+
+#[post("/generate")]
+async fn generate_report(reporter: Data<IntegrityReporter>) -> impl Responder {
+    match reporter.start().await {
+        Ok(()) => FormResponseBuilder::success(String::from(
+            "Generating a report, reload this page in a while to see it",
+        )),
+        Err(error) => FormResponseBuilder::error(error),
+    }
+}
+
+#[derive(Template)]
+#[template(path = "reports/show.html")]
+struct ShowReportTemplate {
+    report: IntegrityReport,
+    hosts: Vec<HostReport>,
+    /// The previous report and what changed since then, by host
+    previous: Option<(IntegrityReport, HostChanges)>,
+}
+
+#[get("/{id}")]
+async fn show_report(
+    db: Data<BlockingPool>,
+    id: Path<i32>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+    let res = db
+        .run_read(move |conn| {
+            let Some(report) = IntegrityReport::get_from_id(conn, id)? else {
+                return Ok(None);
+            };
+            let previous = report.get_previous(conn)?;
+            Ok(Some((report, previous)))
+        })
+        .await?;
+
+    let (report, previous) = match res {
+        Ok(Some(res)) => res,
+        Ok(None) => return Ok(not_found(&req, String::from("Report not found"))),
+        Err(error) => return Ok(ErrorTemplate { error }.to_response()),
+    };
+    let template = report.host_reports().and_then(|hosts| {
+        let previous = match previous {
+            Some(previous) => {
+                let changes = changes(&previous.host_reports()?, &hosts);
+                Some((previous, changes))
+            }
+            None => None,
+        };
+        Ok(ShowReportTemplate {
+            report,
+            hosts,
+            previous,
+        })
+    });
+
+    Ok(match template {
+        Ok(template) => template.to_response(),
+        Err(error) => ErrorTemplate { error }.to_response(),
+    })
+}
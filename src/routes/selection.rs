@@ -0,0 +1,379 @@
+use actix_session::Session;
+use actix_web::{
+    get,
+    http::{header, StatusCode},
+    post,
+    web::{self, Data, Json, Path},
+    HttpRequest, HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bulk_apply::BulkApplier,
+    db::BlockingPool,
+    forms::FormResponseBuilder,
+    models::{parse_expiry, Host, HostGroup, PublicUserKey, User},
+    selection::{SelectionKind, SelectionStore},
+    ssh::CachingSshClient,
+    Configuration,
+};
+
+use super::{proposer, ApiResponse, APPROVAL_REQUIRED};
+
+const SELECTION_SESSION_KEY: &str = "selection_id";
+
+pub fn selection_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(authorize_hosts)
+        .service(sync_hosts)
+        .service(delete_hosts)
+        .service(delete_keys)
+        .service(expire_keys)
+        .service(get_selection)
+        .service(change_selection);
+}
+
+/// The id of the selection of this session, created on first use
+fn selection_id(session: &Session) -> actix_web::Result<String> {
+    if let Some(id) = session.get::<String>(SELECTION_SESSION_KEY)? {
+        return Ok(id);
+    }
+    let id = SelectionStore::new_id();
+    session.insert(SELECTION_SESSION_KEY, &id)?;
+    Ok(id)
+}
+
+/// Browsers send the origin of cross-site requests, which must not use the selection.
+/// All changes also need a JSON body, which plain cross-site forms can't send.
+fn is_same_origin(req: &HttpRequest) -> bool {
+    let Some(origin) = req.headers().get(header::ORIGIN) else {
+        return true;
+    };
+    let host = req.connection_info().host().to_owned();
+    origin
+        .to_str()
+        .ok()
+        .and_then(|origin| origin.split_once("://"))
+        .is_some_and(|(_, origin_host)| origin_host == host)
+}
+
+fn cross_origin() -> FormResponseBuilder {
+    FormResponseBuilder::error(String::from(
+        "Cross-origin requests can't use the selection",
+    ))
+    .set_status(StatusCode::FORBIDDEN)
+}
+
+#[derive(Serialize)]
+struct SelectionResponse {
+    items: Vec<String>,
+}
+
+#[get("/{kind}")]
+async fn get_selection(
+    selections: Data<SelectionStore>,
+    session: Session,
+    kind: Path<String>,
+) -> actix_web::Result<HttpResponse> {
+    let kind = match kind.parse::<SelectionKind>() {
+        Ok(kind) => kind,
+        Err(e) => return Ok(ApiResponse::not_found(e)),
+    };
+    let id = selection_id(&session)?;
+    Ok(ApiResponse::ok(SelectionResponse {
+        items: selections.get(&id, kind).await,
+    }))
+}
+
+#[derive(Deserialize)]
+struct SelectionChange {
+    /// Start from an empty selection
+    #[serde(default)]
+    clear: bool,
+    #[serde(default)]
+    add: Vec<String>,
+    #[serde(default)]
+    remove: Vec<String>,
+}
+
+#[post("/{kind}")]
+async fn change_selection(
+    selections: Data<SelectionStore>,
+    session: Session,
+    req: HttpRequest,
+    kind: Path<String>,
+    change: Json<SelectionChange>,
+) -> actix_web::Result<HttpResponse> {
+    if !is_same_origin(&req) {
+        return Ok(ApiResponse::with_status(
+            StatusCode::FORBIDDEN,
+            String::from("Cross-origin requests can't use the selection"),
+        ));
+    }
+    let kind = match kind.parse::<SelectionKind>() {
+        Ok(kind) => kind,
+        Err(e) => return Ok(ApiResponse::not_found(e)),
+    };
+    let id = selection_id(&session)?;
+    let change = change.into_inner();
+    Ok(
+        match selections
+            .change(&id, kind, change.clear, change.add, change.remove)
+            .await
+        {
+            Ok(items) => ApiResponse::ok(SelectionResponse { items }),
+            Err(e) => ApiResponse::error(e),
+        },
+    )
+}
+
+/// Summary of a bulk operation, with the failures per item
+fn bulk_result(done: String, failed: Vec<String>) -> FormResponseBuilder {
+    if failed.is_empty() {
+        FormResponseBuilder::success(done)
+    } else {
+        FormResponseBuilder::error(format!(
+            "{done}, {} failed: {}",
+            failed.len(),
+            failed.join("; ")
+        ))
+    }
+}
+
+#[derive(Deserialize)]
+struct AuthorizeHostsForm {
+    username: String,
+    login: String,
+    options: Option<String>,
+    /// Authorize on this host group instead of the selected hosts
+    host_group: Option<String>,
+}
+
+/// Authorizes a user on every selected host or every host of a group, all at once
+#[post("/hosts/authorize")]
+async fn authorize_hosts(
+    db: Data<BlockingPool>,
+    selections: Data<SelectionStore>,
+    config: Data<Configuration>,
+    session: Session,
+    req: HttpRequest,
+    form: Json<AuthorizeHostsForm>,
+) -> actix_web::Result<FormResponseBuilder> {
+    if !is_same_origin(&req) {
+        return Ok(cross_origin());
+    }
+    if proposer(&req, &config).is_some() {
+        return Ok(FormResponseBuilder::error(APPROVAL_REQUIRED.to_owned()));
+    }
+    let selected = selections
+        .get(&selection_id(&session)?, SelectionKind::Hosts)
+        .await;
+    let form = form.into_inner();
+    if form.login.trim().is_empty() {
+        return Ok(FormResponseBuilder::error(String::from(
+            "Please enter a login",
+        )));
+    }
+
+    let res = db
+        .run(move |conn| {
+            let user = User::get_from_name(conn, &form.username)?
+                .ok_or_else(|| String::from("User not found"))?;
+            let hosts = match form.host_group.filter(|group| !group.is_empty()) {
+                Some(group) => HostGroup::get_from_name(conn, &group)?
+                    .ok_or_else(|| String::from("Host group not found"))?
+                    .get_hosts(conn)?,
+                None => Host::get_all_named(conn, &selected)?,
+            };
+            if hosts.is_empty() {
+                return Err(String::from("There are no hosts to authorize on"));
+            }
+            let (authorized, skipped) = Host::authorize_user_on_hosts(
+                conn,
+                &hosts,
+                user.id,
+                form.login.trim(),
+                form.options,
+            )?;
+            Ok((user.username, authorized, skipped))
+        })
+        .await?;
+
+    Ok(match res {
+        Ok((username, authorized, skipped)) => {
+            let mut message = format!("Authorized {username} on {} hosts", authorized.len());
+            if !skipped.is_empty() {
+                message += &format!(", already authorized on {}", skipped.join(", "));
+            }
+            FormResponseBuilder::success(message).add_trigger(String::from("reloadDiff"))
+        }
+        Err(error) => FormResponseBuilder::error(error),
+    })
+}
+
+#[derive(Deserialize)]
+struct EmptyForm {}
+
+/// Applies the database state to every selected host in the background
+#[post("/hosts/sync")]
+async fn sync_hosts(
+    bulk_applier: Data<BulkApplier>,
+    selections: Data<SelectionStore>,
+    session: Session,
+    req: HttpRequest,
+    _form: Json<EmptyForm>,
+) -> actix_web::Result<FormResponseBuilder> {
+    if !is_same_origin(&req) {
+        return Ok(cross_origin());
+    }
+    let hosts = selections
+        .get(&selection_id(&session)?, SelectionKind::Hosts)
+        .await;
+
+    Ok(match bulk_applier.start_for(hosts).await {
+        Ok(count) => FormResponseBuilder::success(format!(
+            "Applying to {count} hosts, see the progress on the diff page"
+        )),
+        Err(error) => FormResponseBuilder::error(error),
+    })
+}
+
+#[post("/hosts/delete")]
+async fn delete_hosts(
+    db: Data<BlockingPool>,
+    caching_ssh_client: Data<CachingSshClient>,
+    selections: Data<SelectionStore>,
+    session: Session,
+    req: HttpRequest,
+    _form: Json<EmptyForm>,
+) -> actix_web::Result<FormResponseBuilder> {
+    if !is_same_origin(&req) {
+        return Ok(cross_origin());
+    }
+    let id = selection_id(&session)?;
+    let hosts = selections.get(&id, SelectionKind::Hosts).await;
+
+    let res = db
+        .run(move |conn| {
+            let mut deleted = Vec::new();
+            let mut failed = Vec::new();
+            for name in hosts {
+                let res = Host::get_from_name_sync(conn, name.clone())?
+                    .ok_or_else(|| String::from("Host not found"))
+                    .and_then(|host| host.delete(conn));
+                match res {
+                    Ok(_) => deleted.push(name),
+                    Err(e) => failed.push(format!("{name}: {e}")),
+                }
+            }
+            Ok::<_, String>((deleted, failed))
+        })
+        .await?;
+
+    let (deleted, failed) = match res {
+        Ok(res) => res,
+        Err(error) => return Ok(FormResponseBuilder::error(error)),
+    };
+    for name in &deleted {
+        caching_ssh_client.remove(name).await;
+    }
+    selections.clear(&id, SelectionKind::Hosts).await;
+    Ok(
+        bulk_result(format!("Deleted {} hosts", deleted.len()), failed)
+            .add_trigger(String::from("reload-hosts"))
+            .add_trigger(String::from("reload-selection")),
+    )
+}
+
+#[post("/keys/delete")]
+async fn delete_keys(
+    db: Data<BlockingPool>,
+    selections: Data<SelectionStore>,
+    session: Session,
+    req: HttpRequest,
+    _form: Json<EmptyForm>,
+) -> actix_web::Result<FormResponseBuilder> {
+    if !is_same_origin(&req) {
+        return Ok(cross_origin());
+    }
+    let id = selection_id(&session)?;
+    let keys = selections.get(&id, SelectionKind::Keys).await;
+
+    let res = db
+        .run(move |conn| {
+            let mut done = 0;
+            let mut failed = Vec::new();
+            for key in keys {
+                let res = key
+                    .parse::<i32>()
+                    .map_err(|_| String::from("Invalid key id"))
+                    .and_then(|key_id| PublicUserKey::delete_key(conn, key_id));
+                match res {
+                    Ok(_) => done += 1,
+                    Err(e) => failed.push(format!("{key}: {e}")),
+                }
+            }
+            (done, failed)
+        })
+        .await?;
+
+    selections.clear(&id, SelectionKind::Keys).await;
+    let (done, failed) = res;
+    Ok(bulk_result(format!("Deleted {done} keys"), failed)
+        .add_trigger(String::from("reload-keys"))
+        .add_trigger(String::from("reload-selection")))
+}
+
+#[derive(Deserialize)]
+struct ExpireKeysForm {
+    /// Empty to keep the keys forever
+    #[serde(default)]
+    expires_at: String,
+}
+
+/// Sets when every selected key expires
+#[post("/keys/expire")]
+async fn expire_keys(
+    db: Data<BlockingPool>,
+    selections: Data<SelectionStore>,
+    session: Session,
+    req: HttpRequest,
+    form: Json<ExpireKeysForm>,
+) -> actix_web::Result<FormResponseBuilder> {
+    if !is_same_origin(&req) {
+        return Ok(cross_origin());
+    }
+    let expires_at = match parse_expiry(&form.expires_at) {
+        Ok(expires_at) => expires_at,
+        Err(e) => return Ok(FormResponseBuilder::error(e)),
+    };
+    let keys = selections
+        .get(&selection_id(&session)?, SelectionKind::Keys)
+        .await;
+    if keys.is_empty() {
+        return Ok(FormResponseBuilder::error(String::from("No keys selected")));
+    }
+
+    let (done, failed) = db
+        .run(move |conn| {
+            let mut done = 0;
+            let mut failed = Vec::new();
+            for key in keys {
+                let res = key
+                    .parse::<i32>()
+                    .map_err(|_| String::from("Invalid key id"))
+                    .and_then(|key_id| PublicUserKey::set_expiry(conn, key_id, expires_at));
+                match res {
+                    Ok(()) => done += 1,
+                    Err(e) => failed.push(format!("{key}: {e}")),
+                }
+            }
+            (done, failed)
+        })
+        .await?;
+
+    let message = match expires_at {
+        Some(_) => format!("Set the expiry of {done} keys"),
+        None => format!("{done} keys don't expire anymore"),
+    };
+    Ok(bulk_result(message, failed).add_trigger(String::from("reload-keys")))
+}
@@ -0,0 +1,357 @@
+use actix_web::{
+    get,
+    http::header::{HeaderName, HeaderValue},
+    post,
+    web::{self, Data, Path},
+    HttpRequest, Responder,
+};
+use askama_actix::{Template, TemplateToResponse};
+use serde::Deserialize;
+
+use crate::{
+    db::{BlockingPool, GroupAuthorizationWithNames},
+    forms::FormResponseBuilder,
+    models::{GroupAuthorization, HostGroup, User, UserGroup},
+    routes::{not_found, proposer, ErrorTemplate, RenderErrorTemplate, APPROVAL_REQUIRED},
+    Configuration, DbConnection,
+};
+
+pub fn teams_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(teams_page)
+        .service(render_teams)
+        .service(add_team)
+        .service(render_rules)
+        .service(add_rule)
+        .service(delete_rule)
+        .service(show_team)
+        .service(render_members)
+        .service(add_member)
+        .service(remove_member)
+        .service(rename_team)
+        .service(delete_team);
+}
+
+#[derive(Template)]
+#[template(path = "teams/index.html")]
+struct TeamsTemplate {}
+
+#[get("")]
+async fn teams_page() -> impl Responder {
+    TeamsTemplate {}
+}
+
+#[derive(Template)]
+#[template(path = "teams/list.htm")]
+struct RenderTeamsTemplate {
+    /// Teams and their members
+    teams: Vec<(UserGroup, Vec<User>)>,
+}
+
+#[get("/list.htm")]
+async fn render_teams(db: Data<BlockingPool>) -> actix_web::Result<impl Responder> {
+    let res = db
+        .run(|conn| {
+            UserGroup::get_all(conn)?
+                .into_iter()
+                .map(|team| team.get_members(conn).map(|members| (team, members)))
+                .collect::<Result<Vec<_>, String>>()
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(teams) => RenderTeamsTemplate { teams }.to_response(),
+        Err(error) => RenderErrorTemplate { error }.to_response(),
+    })
+}
+
+#[derive(Deserialize)]
+struct TeamForm {
+    name: String,
+}
+
+#[post("/add")]
+async fn add_team(
+    db: Data<BlockingPool>,
+    form: web::Form<TeamForm>,
+) -> actix_web::Result<impl Responder> {
+    let res = db
+        .run(move |conn| UserGroup::create(conn, &form.name))
+        .await?;
+
+    Ok(match res {
+        Ok(team) => FormResponseBuilder::created(format!("Created the team {}", team.name))
+            .add_trigger(String::from("reload-teams")),
+        Err(error) => FormResponseBuilder::error(error),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "teams/rules.htm")]
+struct RenderRulesTemplate {
+    rules: Vec<GroupAuthorizationWithNames>,
+    /// Names of the teams and host groups to choose from
+    teams: Vec<String>,
+    host_groups: Vec<String>,
+}
+
+#[get("/rules.htm")]
+async fn render_rules(db: Data<BlockingPool>) -> actix_web::Result<impl Responder> {
+    let res = db
+        .run(|conn| {
+            let rules = GroupAuthorization::get_all_with_names(conn)?;
+            let teams = UserGroup::get_all(conn)?;
+            let host_groups = HostGroup::get_all(conn)?;
+            Ok::<_, String>((rules, teams, host_groups))
+        })
+        .await?;
+
+    Ok(match res {
+        Ok((rules, teams, host_groups)) => RenderRulesTemplate {
+            rules,
+            teams: teams.into_iter().map(|team| team.name).collect(),
+            host_groups: host_groups.into_iter().map(|group| group.name).collect(),
+        }
+        .to_response(),
+        Err(error) => RenderErrorTemplate { error }.to_response(),
+    })
+}
+
+#[derive(Deserialize)]
+struct RuleForm {
+    team: String,
+    host_group: String,
+    login: String,
+    options: Option<String>,
+}
+
+#[post("/rules/add")]
+async fn add_rule(
+    db: Data<BlockingPool>,
+    config: Data<Configuration>,
+    req: HttpRequest,
+    form: web::Form<RuleForm>,
+) -> actix_web::Result<impl Responder> {
+    // A rule authorizes on every host of the group
+    if proposer(&req, &config).is_some() {
+        return Ok(FormResponseBuilder::error(APPROVAL_REQUIRED.to_owned()));
+    }
+    let form = form.into_inner();
+    let res = db
+        .run(move |conn| {
+            GroupAuthorization::create(
+                conn,
+                &form.team,
+                &form.host_group,
+                &form.login,
+                form.options,
+            )
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(()) => FormResponseBuilder::created(String::from("Added the rule"))
+            .add_trigger(String::from("reload-rules")),
+        Err(error) => FormResponseBuilder::error(error),
+    })
+}
+
+#[derive(Deserialize)]
+struct DeleteRuleForm {
+    id: i32,
+}
+
+#[post("/rules/delete")]
+async fn delete_rule(
+    db: Data<BlockingPool>,
+    form: web::Form<DeleteRuleForm>,
+) -> actix_web::Result<impl Responder> {
+    let id = form.id;
+    let res = db
+        .run(move |conn| GroupAuthorization::delete(conn, id))
+        .await?;
+
+    Ok(match res {
+        Ok(()) => FormResponseBuilder::success(String::from("Deleted the rule"))
+            .add_trigger(String::from("reload-rules")),
+        Err(error) => FormResponseBuilder::error(error),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "teams/show_team.html")]
+struct ShowTeamTemplate {
+    team: UserGroup,
+}
+
+#[get("/{name}")]
+async fn show_team(
+    db: Data<BlockingPool>,
+    name: Path<String>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let res = db
+        .run(move |conn| UserGroup::get_from_name(conn, &name))
+        .await?;
+
+    Ok(match res {
+        Ok(Some(team)) => ShowTeamTemplate { team }.to_response(),
+        Ok(None) => not_found(&req, String::from("Team not found")),
+        Err(error) => ErrorTemplate { error }.to_response(),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "teams/members.htm")]
+struct RenderMembersTemplate {
+    team_name: String,
+    members: Vec<User>,
+    /// Usernames of everyone who isn't a member yet
+    others: Vec<String>,
+}
+
+#[get("/{name}/members.htm")]
+async fn render_members(
+    db: Data<BlockingPool>,
+    name: Path<String>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let res = db
+        .run(move |conn| {
+            let Some(team) = UserGroup::get_from_name(conn, &name)? else {
+                return Ok(None);
+            };
+            let members = team.get_members(conn)?;
+            let others = User::get_all_users(conn)?
+                .into_iter()
+                .filter(|user| !members.iter().any(|member| member.id == user.id))
+                .map(|user| user.username)
+                .collect();
+            Ok::<_, String>(Some((team.name, members, others)))
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(Some((team_name, members, others))) => RenderMembersTemplate {
+            team_name,
+            members,
+            others,
+        }
+        .to_response(),
+        Ok(None) => not_found(&req, String::from("Team not found")),
+        Err(error) => RenderErrorTemplate { error }.to_response(),
+    })
+}
+
+#[derive(Deserialize)]
+struct MemberForm {
+    username: String,
+}
+
+/// Loads a team and a user for changing the membership
+fn team_and_user(
+    conn: &mut DbConnection,
+    team: &str,
+    username: &str,
+) -> Result<(UserGroup, User), String> {
+    let team =
+        UserGroup::get_from_name(conn, team)?.ok_or_else(|| String::from("Team not found"))?;
+    let user =
+        User::get_from_name(conn, username)?.ok_or_else(|| String::from("User not found"))?;
+    Ok((team, user))
+}
+
+#[post("/{name}/members")]
+async fn add_member(
+    db: Data<BlockingPool>,
+    config: Data<Configuration>,
+    req: HttpRequest,
+    name: Path<String>,
+    form: web::Form<MemberForm>,
+) -> actix_web::Result<impl Responder> {
+    // Members get the access of the team's rules
+    if proposer(&req, &config).is_some() {
+        return Ok(FormResponseBuilder::error(APPROVAL_REQUIRED.to_owned()));
+    }
+    let res = db
+        .run(move |conn| {
+            let (team, user) = team_and_user(conn, &name, &form.username)?;
+            team.add_member(conn, &user)
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(()) => FormResponseBuilder::success(String::from("Added the user to the team"))
+            .add_trigger(String::from("reload-team-members")),
+        Err(error) => FormResponseBuilder::error(error),
+    })
+}
+
+#[post("/{name}/members/remove")]
+async fn remove_member(
+    db: Data<BlockingPool>,
+    name: Path<String>,
+    form: web::Form<MemberForm>,
+) -> actix_web::Result<impl Responder> {
+    let res = db
+        .run(move |conn| {
+            let (team, user) = team_and_user(conn, &name, &form.username)?;
+            team.remove_member(conn, &user)
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(()) => FormResponseBuilder::success(String::from("Removed the user from the team"))
+            .add_trigger(String::from("reload-team-members")),
+        Err(error) => FormResponseBuilder::error(error),
+    })
+}
+
+#[post("/{name}/rename")]
+async fn rename_team(
+    db: Data<BlockingPool>,
+    name: Path<String>,
+    form: web::Form<TeamForm>,
+) -> actix_web::Result<impl Responder> {
+    let res = db
+        .run(move |conn| {
+            let team = UserGroup::get_from_name(conn, &name)?
+                .ok_or_else(|| String::from("Team not found"))?;
+            team.rename(conn, &form.name)?;
+            Ok::<_, String>(form.name.trim().to_owned())
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(new_name) => {
+            // The page of the team moved
+            let mut res = FormResponseBuilder::success(format!("Renamed the team to {new_name}"))
+                .into_response();
+            if let Ok(location) = HeaderValue::from_str(&format!("/teams/{new_name}")) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static("hx-redirect"), location);
+            }
+            res
+        }
+        Err(error) => FormResponseBuilder::error(error).into_response(),
+    })
+}
+
+#[post("/{name}/delete")]
+async fn delete_team(
+    db: Data<BlockingPool>,
+    name: Path<String>,
+) -> actix_web::Result<impl Responder> {
+    let res = db
+        .run(move |conn| {
+            UserGroup::get_from_name(conn, &name)?
+                .ok_or_else(|| String::from("Team not found"))?
+                .delete(conn)
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(()) => FormResponseBuilder::success(String::from("Deleted the team"))
+            .add_trigger(String::from("reload-teams")),
+        Err(error) => FormResponseBuilder::error(error),
+    })
+}
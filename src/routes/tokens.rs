@@ -0,0 +1,104 @@
+use actix_identity::Identity;
+use actix_web::{
+    get, post,
+    web::{self, Data},
+    Responder,
+};
+use askama_actix::{Template, TemplateToResponse};
+use serde::Deserialize;
+
+use crate::{
+    db::BlockingPool,
+    forms::{FormResponseBuilder, Modal},
+    models::ApiToken,
+    routes::RenderErrorTemplate,
+};
+
+pub fn tokens_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(tokens_page)
+        .service(render_tokens)
+        .service(add_token)
+        .service(delete_token);
+}
+
+#[derive(Template)]
+#[template(path = "tokens/index.html")]
+struct TokensTemplate {}
+
+#[get("")]
+async fn tokens_page() -> impl Responder {
+    TokensTemplate {}
+}
+
+#[derive(Template)]
+#[template(path = "tokens/list.htm")]
+struct RenderTokensTemplate {
+    tokens: Vec<ApiToken>,
+}
+
+#[get("/list.htm")]
+async fn render_tokens(db: Data<BlockingPool>) -> actix_web::Result<impl Responder> {
+    Ok(match db.run(ApiToken::get_all).await? {
+        Ok(tokens) => RenderTokensTemplate { tokens }.to_response(),
+        Err(error) => RenderErrorTemplate { error }.to_response(),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "tokens/created_dialog.htm")]
+struct CreatedTokenDialog {
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct AddTokenForm {
+    name: String,
+}
+
+#[post("/add")]
+async fn add_token(
+    db: Data<BlockingPool>,
+    identity: Identity,
+    form: web::Form<AddTokenForm>,
+) -> actix_web::Result<impl Responder> {
+    let name = form.0.name.trim().to_owned();
+    if name.is_empty() {
+        return Ok(FormResponseBuilder::error(String::from(
+            "Please give the token a name",
+        )));
+    }
+    let created_by = identity.id().unwrap_or_default();
+
+    let res = db
+        .run(move |conn| ApiToken::create(conn, name, created_by))
+        .await?;
+    Ok(match res {
+        Ok(token) => FormResponseBuilder::dialog(Modal {
+            title: String::from("Your new API token"),
+            request_target: String::new(),
+            template: CreatedTokenDialog { token }.to_string(),
+        })
+        .add_trigger(String::from("reload-tokens")),
+        Err(e) => FormResponseBuilder::error(e),
+    })
+}
+
+#[derive(Deserialize)]
+struct DeleteTokenForm {
+    id: i32,
+}
+
+#[post("/delete")]
+async fn delete_token(
+    db: Data<BlockingPool>,
+    form: web::Form<DeleteTokenForm>,
+) -> actix_web::Result<impl Responder> {
+    let id = form.0.id;
+
+    let res = db.run(move |conn| ApiToken::delete(conn, id)).await?;
+    Ok(match res {
+        Ok(()) => FormResponseBuilder::success(String::from("Revoked token"))
+            .add_trigger(String::from("reload-tokens")),
+        Err(e) => FormResponseBuilder::error(e),
+    })
+}
@@ -1,7 +1,9 @@
 use actix_web::{
-    get, post,
+    get,
+    http::header,
+    post,
     web::{self, Data, Path},
-    Responder,
+    HttpMessage, HttpRequest, HttpResponse, Responder,
 };
 use askama_actix::{Template, TemplateToResponse};
 use serde::Deserialize;
@@ -9,22 +11,37 @@ use ssh_key::PublicKey;
 
 use crate::{
     db::UserAndOptions,
+    db::{account_login, BlockingPool},
     forms::FormResponseBuilder,
-    routes::{ErrorTemplate, RenderErrorTemplate},
-    ConnectionPool,
+    jobs::{is_finished, Step, StepStatus},
+    key_import::{provider_label, KeyImporter, GITHUB, GITLAB},
+    offboarding::Offboarder,
+    privacy::Erased,
+    routes::{not_found, ErrorTemplate, RenderErrorTemplate},
+    ssh::CachingSshClient,
+    Configuration, DbConnection,
 };
 
-use crate::models::{NewPublicUserKey, NewUser, PublicUserKey, User};
+use crate::models::{
+    parse_expiry, parse_key_line, NewPublicUserKey, NewUser, PublicUserKey, User, UserGroup,
+};
 
 pub fn users_config(cfg: &mut web::ServiceConfig) {
     cfg.service(users_page)
         .service(render_users)
         .service(show_user)
+        .service(export_user_data)
         .service(render_user_keys)
+        .service(impersonate_user)
+        .service(offboard_user)
+        .service(offboarding_status)
         .service(list_user_authorizations)
         .service(add_user)
         .service(assign_key_to_user)
+        .service(assign_keys_to_user)
         .service(delete_user)
+        .service(set_default_options)
+        .service(import_keys)
         .service(edit_user);
 }
 
@@ -44,8 +61,8 @@ struct RenderUsersTemplate {
 }
 
 #[get("/list.htm")]
-async fn render_users(conn: Data<ConnectionPool>) -> actix_web::Result<impl Responder> {
-    let all_users = web::block(move || User::get_all_users(&mut conn.get().unwrap())).await?;
+async fn render_users(db: Data<BlockingPool>) -> actix_web::Result<impl Responder> {
+    let all_users = db.run(User::get_all_users).await?;
 
     Ok(match all_users {
         Ok(users) => RenderUsersTemplate { users }.to_response(),
@@ -57,30 +74,51 @@ async fn render_users(conn: Data<ConnectionPool>) -> actix_web::Result<impl Resp
 #[template(path = "users/show_user.html")]
 struct ShowUserTemplate {
     user: User,
+    teams: Vec<UserGroup>,
+    /// Accounts keys were last imported from
+    github_login: Option<String>,
+    gitlab_login: Option<String>,
 }
 
 #[get("/{name}")]
 async fn show_user(
-    conn: Data<ConnectionPool>,
+    db: Data<BlockingPool>,
     user: Path<String>,
+    req: HttpRequest,
 ) -> actix_web::Result<impl Responder> {
-    let mut conn = conn.clone().get().unwrap();
-    let maybe_user = web::block(move || User::get_user(&mut conn, user.to_string())).await?;
+    let maybe_user = db
+        .run(move |conn| {
+            let Some(user) = User::get_from_name(conn, &user)? else {
+                return Ok(None);
+            };
+            let teams = user.get_groups(conn)?;
+            let github_login = account_login(conn, user.id, GITHUB)?;
+            let gitlab_login = account_login(conn, user.id, GITLAB)?;
+            Ok::<_, String>(Some((user, teams, github_login, gitlab_login)))
+        })
+        .await?;
 
     Ok(match maybe_user {
-        Ok(user) => ShowUserTemplate { user }.to_response(),
+        Ok(Some((user, teams, github_login, gitlab_login))) => ShowUserTemplate {
+            user,
+            teams,
+            github_login,
+            gitlab_login,
+        }
+        .to_response(),
+        Ok(None) => not_found(&req, String::from("User not found")),
         Err(error) => ErrorTemplate { error }.to_response(),
     })
 }
 
 #[post("/add")]
 async fn add_user(
-    conn: Data<ConnectionPool>,
+    db: Data<BlockingPool>,
     form: web::Form<NewUser>,
 ) -> actix_web::Result<impl Responder> {
     let new_user = form.0;
 
-    let res = web::block(move || User::add_user(&mut conn.get().unwrap(), new_user)).await?;
+    let res = db.run(move |conn| User::add_user(conn, new_user)).await?;
     Ok(match res {
         Ok(_) => FormResponseBuilder::created(String::from("Added user"))
             .add_trigger(String::from("reload-users")),
@@ -95,19 +133,66 @@ struct DeleteUserForm {
 
 #[post("/delete")]
 async fn delete_user(
-    conn: Data<ConnectionPool>,
+    db: Data<BlockingPool>,
+    config: Data<Configuration>,
     form: web::Form<DeleteUserForm>,
+    req: HttpRequest,
 ) -> actix_web::Result<impl Responder> {
     let username = form.0.username;
 
-    let res =
-        web::block(move || User::delete_user(&mut conn.get().unwrap(), username.as_str())).await?;
+    if config.anonymize_deleted_users {
+        let res = db
+            .run(move |conn| {
+                let user = User::get_user(conn, username)?;
+                let pseudonym = user.delete_anonymized(conn)?;
+                Ok::<_, String>(Erased::new(&user, pseudonym))
+            })
+            .await?;
+        return Ok(match res {
+            Ok(erased) => {
+                req.extensions_mut().insert(erased);
+                FormResponseBuilder::success(String::from("Deleted and anonymized user"))
+            }
+            Err(e) => FormResponseBuilder::error(e),
+        });
+    }
+
+    let res = db
+        .run(move |conn| User::delete_user(conn, username.as_str()))
+        .await?;
     Ok(match res {
-        Ok(()) => FormResponseBuilder::success(String::from("Deleted user")),
+        Ok(_) => FormResponseBuilder::success(String::from("Deleted user")),
         Err(e) => FormResponseBuilder::error(e),
     })
 }
 
+/// Everything stored about the user, for data subject access requests
+#[get("/{username}/export.json")]
+async fn export_user_data(
+    db: Data<BlockingPool>,
+    username: Path<String>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let username = username.into_inner();
+    let filename = format!("attachment; filename=\"{username}.json\"");
+    let res = db
+        .run_read(move |conn| {
+            let Some(user) = User::get_from_name(conn, &username)? else {
+                return Ok(None);
+            };
+            user.export_data(conn).map(Some)
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(Some(data)) => HttpResponse::Ok()
+            .insert_header((header::CONTENT_DISPOSITION, filename))
+            .json(data),
+        Ok(None) => not_found(&req, String::from("User not found")),
+        Err(error) => ErrorTemplate { error }.to_response(),
+    })
+}
+
 #[derive(Template)]
 #[template(path = "users/list_keys.htm")]
 struct ListUserKeysTemplate {
@@ -116,34 +201,82 @@ struct ListUserKeysTemplate {
 
 #[get("/{username}/list_keys.htm")]
 async fn render_user_keys(
-    conn: Data<ConnectionPool>,
+    db: Data<BlockingPool>,
     username: Path<String>,
+    req: HttpRequest,
 ) -> actix_web::Result<impl Responder> {
-    let maybe_user_keys = web::block(move || {
-        let mut connection = conn.get().unwrap();
-        let user = User::get_user(&mut connection, username.to_string())?;
+    let maybe_user_keys = db
+        .run(move |conn| {
+            let Some(user) = User::get_from_name(conn, &username)? else {
+                return Ok(None);
+            };
 
-        user.get_keys(&mut connection)
-    })
-    .await?;
+            user.get_keys(conn).map(Some)
+        })
+        .await?;
 
     Ok(match maybe_user_keys {
-        Ok(keys) => {
-            let public_keys: Vec<(PublicUserKey, Result<String, String>)> = keys
-                .into_iter()
-                .map(|key| {
-                    let fingerprint = PublicKey::try_from(&key)
-                        .map(|k| k.fingerprint(ssh_key::HashAlg::Sha256).to_string());
-
-                    (key, fingerprint)
-                })
-                .collect();
-            ListUserKeysTemplate { keys: public_keys }.to_response()
+        Ok(Some(keys)) => ListUserKeysTemplate {
+            keys: with_fingerprints(keys),
         }
+        .to_response(),
+        Ok(None) => not_found(&req, String::from("User not found")),
         Err(error) => RenderErrorTemplate { error }.to_response(),
     })
 }
 
+pub(super) fn with_fingerprints(
+    keys: Vec<PublicUserKey>,
+) -> Vec<(PublicUserKey, Result<String, String>)> {
+    keys.into_iter()
+        .map(|key| {
+            let fingerprint = PublicKey::try_from(&key)
+                .map(|k| k.fingerprint(ssh_key::HashAlg::Sha256).to_string());
+
+            (key, fingerprint)
+        })
+        .collect()
+}
+
+#[derive(Template)]
+#[template(path = "users/portal.html")]
+struct ImpersonateUserTemplate {
+    user: User,
+    keys: Vec<(PublicUserKey, Result<String, String>)>,
+    authorizations: Vec<UserAndOptions>,
+}
+
+/// Shows the self-service view of a user, read-only
+#[get("/{username}/portal")]
+async fn impersonate_user(
+    db: Data<BlockingPool>,
+    username: Path<String>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let res = db
+        .run(move |conn| {
+            let Some(user) = User::get_from_name(conn, &username)? else {
+                return Ok(None);
+            };
+            let keys = user.get_keys(conn)?;
+            let authorizations = user.get_authorizations(conn)?;
+
+            Ok::<_, String>(Some((user, keys, authorizations)))
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(Some((user, keys, authorizations))) => ImpersonateUserTemplate {
+            user,
+            keys: with_fingerprints(keys),
+            authorizations,
+        }
+        .to_response(),
+        Ok(None) => not_found(&req, String::from("User not found")),
+        Err(error) => ErrorTemplate { error }.to_response(),
+    })
+}
+
 #[derive(Template)]
 #[template(path = "users/list_authorizations.htm")]
 struct ListUserAuthorizationsTemplate {
@@ -152,34 +285,45 @@ struct ListUserAuthorizationsTemplate {
 
 #[get("/{username}/list_authorizations.htm")]
 async fn list_user_authorizations(
-    conn: Data<ConnectionPool>,
+    db: Data<BlockingPool>,
     username: Path<String>,
+    req: HttpRequest,
 ) -> actix_web::Result<impl Responder> {
-    let maybe_user_auth = web::block(move || {
-        let mut connection = conn.get().unwrap();
-        let user = User::get_user(&mut connection, username.to_string())?;
+    let maybe_user_auth = db
+        .run(move |conn| {
+            let Some(user) = User::get_from_name(conn, &username)? else {
+                return Ok(None);
+            };
 
-        user.get_authorizations(&mut connection)
-    })
-    .await?;
+            user.get_authorizations(conn).map(Some)
+        })
+        .await?;
 
     Ok(match maybe_user_auth {
-        Ok(authorizations) => ListUserAuthorizationsTemplate { authorizations }.to_response(),
+        Ok(Some(authorizations)) => ListUserAuthorizationsTemplate { authorizations }.to_response(),
+        Ok(None) => not_found(&req, String::from("User not found")),
         Err(error) => RenderErrorTemplate { error }.to_response(),
     })
 }
 
 #[derive(Deserialize)]
 struct AssignKeyDialogForm {
-    user_id: i32,
+    user_id: String,
+    #[serde(default)]
+    new_username: String,
+    #[serde(default)]
+    new_email: String,
     key_type: String,
     key_base64: String,
     key_comment: Option<String>,
+    /// Empty if the key doesn't expire
+    #[serde(default)]
+    expires_at: String,
 }
 
 #[post("/assign_key")]
 async fn assign_key_to_user(
-    conn: Data<ConnectionPool>,
+    db: Data<BlockingPool>,
     form: web::Form<AssignKeyDialogForm>,
 ) -> actix_web::Result<impl Responder> {
     let Ok(algo) = ssh_key::Algorithm::new(&form.key_type) else {
@@ -188,22 +332,265 @@ async fn assign_key_to_user(
         ));
     };
 
-    let new_key = NewPublicUserKey::new(
-        algo,
-        form.key_base64.clone(),
-        form.key_comment.clone(),
-        form.user_id,
-    );
+    let expires_at = match parse_expiry(&form.expires_at) {
+        Ok(expires_at) => expires_at,
+        Err(e) => return Ok(FormResponseBuilder::error(e)),
+    };
+
+    let form = form.into_inner();
+    let choice = UserChoice {
+        user_id: form.user_id,
+        new_username: form.new_username,
+        new_email: form.new_email,
+    };
 
-    let res = web::block(move || PublicUserKey::add_key(&mut conn.get().unwrap(), new_key)).await?;
+    let res = db
+        .run(move |conn| {
+            let user = choice.get_or_create(conn)?;
+            let new_key = NewPublicUserKey::new(algo, form.key_base64, form.key_comment, user.id)
+                .with_expiry(expires_at);
+            PublicUserKey::add_key(conn, new_key).map(|()| user.username)
+        })
+        .await?;
 
     Ok(match res {
-        Ok(()) => FormResponseBuilder::created(String::from("Added key"))
+        Ok(username) => FormResponseBuilder::created(format!("Added key to '{username}'"))
             .add_trigger("reloadDiff".to_owned()),
         Err(e) => FormResponseBuilder::error(e),
     })
 }
 
+/// The user picked with `components::user_selection`: an existing one, or
+/// one that is created together with the action of the dialog
+pub(super) struct UserChoice {
+    /// Empty if a new user should be created
+    pub user_id: String,
+    pub new_username: String,
+    pub new_email: String,
+}
+
+impl UserChoice {
+    pub fn get_or_create(self, conn: &mut DbConnection) -> Result<User, String> {
+        if !self.user_id.is_empty() {
+            let id = self
+                .user_id
+                .parse::<i32>()
+                .map_err(|_| String::from("Invalid user"))?;
+            return User::get_by_id(conn, id)?.ok_or_else(|| String::from("User not found"));
+        }
+
+        let username = self.new_username.trim();
+        if username.is_empty() {
+            return Err(String::from(
+                "Please select a user or enter a name for the new user",
+            ));
+        }
+        let email = Some(self.new_email.trim())
+            .filter(|email| !email.is_empty())
+            .map(ToOwned::to_owned);
+        let username = User::add_user(
+            conn,
+            NewUser {
+                username: username.to_owned(),
+                email,
+            },
+        )?;
+        User::get_user(conn, username)
+    }
+}
+
+/// Assigns several keys to an existing user or one created on the fly
+#[post("/assign_keys")]
+async fn assign_keys_to_user(
+    db: Data<BlockingPool>,
+    form: web::Form<Vec<(String, String)>>,
+) -> actix_web::Result<impl Responder> {
+    let mut keys = Vec::new();
+    let mut choice = UserChoice {
+        user_id: String::new(),
+        new_username: String::new(),
+        new_email: String::new(),
+    };
+    for (name, value) in form.0 {
+        match name.as_str() {
+            "key" => keys.push(value),
+            "user_id" => choice.user_id = value,
+            "new_username" => choice.new_username = value,
+            "new_email" => choice.new_email = value,
+            _ => {}
+        }
+    }
+    if keys.is_empty() {
+        return Ok(FormResponseBuilder::error(String::from("No keys selected")));
+    }
+
+    let res = db
+        .run(move |conn| {
+            let user = choice.get_or_create(conn)?;
+
+            // Keep going, so one duplicate doesn't block the other keys
+            let failed: Vec<String> = keys
+                .iter()
+                .filter_map(|line| {
+                    parse_key_line(line, user.id)
+                        .and_then(|key| PublicUserKey::add_key(conn, key))
+                        .err()
+                })
+                .collect();
+            Ok((user.username, keys.len() - failed.len(), failed))
+        })
+        .await?;
+
+    Ok(match res {
+        Ok((username, added, failed)) if failed.is_empty() => {
+            FormResponseBuilder::created(format!("Assigned {added} keys to '{username}'"))
+                .add_trigger("reloadDiff".to_owned())
+        }
+        Ok((username, added, failed)) => FormResponseBuilder::error(format!(
+            "Assigned {added} keys to '{username}', {} failed: {}",
+            failed.len(),
+            failed.join(", ")
+        ))
+        .add_trigger("reloadDiff".to_owned()),
+        Err(e) => FormResponseBuilder::error(e),
+    })
+}
+
+#[post("/{username}/offboard")]
+async fn offboard_user(
+    db: Data<BlockingPool>,
+    offboarder: Data<Offboarder>,
+    username: Path<String>,
+) -> actix_web::Result<impl Responder> {
+    let user = match db
+        .run(move |conn| User::get_from_name(conn, &username))
+        .await?
+    {
+        Ok(Some(user)) => user,
+        Ok(None) => return Ok(FormResponseBuilder::not_found("User not found".to_owned())),
+        Err(error) => return Ok(FormResponseBuilder::error(error)),
+    };
+
+    Ok(match offboarder.start(user).await {
+        Ok(()) => FormResponseBuilder::success(String::from("Started offboarding"))
+            .add_trigger("reload-offboarding".to_owned()),
+        Err(error) => FormResponseBuilder::error(error),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "users/offboarding.htm")]
+struct OffboardingStatusTemplate {
+    username: String,
+    steps: Option<Vec<Step>>,
+}
+
+impl OffboardingStatusTemplate {
+    fn finished(&self) -> bool {
+        self.steps.as_deref().is_none_or(is_finished)
+    }
+}
+
+#[get("/{username}/offboarding.htm")]
+async fn offboarding_status(
+    offboarder: Data<Offboarder>,
+    username: Path<String>,
+) -> impl Responder {
+    let steps = offboarder.status(&username).await;
+    OffboardingStatusTemplate {
+        username: username.to_string(),
+        steps,
+    }
+}
+
+#[derive(Deserialize)]
+struct DefaultOptionsForm {
+    default_options: String,
+}
+
+#[post("/{username}/default_options")]
+async fn set_default_options(
+    db: Data<BlockingPool>,
+    caching_ssh_client: Data<CachingSshClient>,
+    username: Path<String>,
+    form: web::Form<DefaultOptionsForm>,
+) -> actix_web::Result<impl Responder> {
+    let options = Some(form.into_inner().default_options).filter(|o| !o.trim().is_empty());
+    let res = db
+        .run(move |conn| {
+            let user = User::get_from_name(conn, &username)?
+                .ok_or_else(|| String::from("User not found"))?;
+            user.set_default_options(conn, options)?;
+            user.get_authorizations(conn)
+        })
+        .await?;
+
+    Ok(match res {
+        Ok(authorizations) => {
+            // The cached diffs of these hosts were computed with the old defaults
+            for (_, host_name, _, _) in authorizations {
+                caching_ssh_client.remove(&host_name).await;
+            }
+            FormResponseBuilder::success(String::from("Changed the default options"))
+        }
+        Err(e) => FormResponseBuilder::error(e),
+    })
+}
+
+#[derive(Deserialize)]
+struct ImportKeysForm {
+    provider: String,
+    login: String,
+}
+
+/// Imports the keys of the user's account at a code host
+#[post("/{username}/import_keys")]
+async fn import_keys(
+    importer: Data<KeyImporter>,
+    username: Path<String>,
+    form: web::Form<ImportKeysForm>,
+) -> actix_web::Result<impl Responder> {
+    let provider = match form.provider.as_str() {
+        GITHUB => GITHUB,
+        GITLAB => GITLAB,
+        provider => {
+            return Ok(FormResponseBuilder::error(format!(
+                "Unknown provider '{provider}'"
+            )))
+        }
+    };
+    let res = importer
+        .import(username.into_inner(), provider, &form.login)
+        .await;
+
+    Ok(match res {
+        Ok(summary) => {
+            let mut message = format!(
+                "Added {} keys from {}, {} were known already",
+                summary.added,
+                provider_label(provider),
+                summary.known
+            );
+            if summary.removed > 0 {
+                message += &format!(", {} aren't listed anymore", summary.removed);
+            }
+            let response = if summary.failed.is_empty() {
+                FormResponseBuilder::success(message)
+            } else {
+                FormResponseBuilder::error(format!(
+                    "{message}, {} failed: {}",
+                    summary.failed.len(),
+                    summary.failed.join(", ")
+                ))
+            };
+            response
+                .add_trigger(String::from("reload-keys"))
+                .add_trigger(String::from("reloadDiff"))
+        }
+        Err(e) => FormResponseBuilder::error(e),
+    })
+}
+
 #[derive(Deserialize)]
 struct EditUserForm {
     old_username: String,
@@ -213,19 +600,20 @@ struct EditUserForm {
 
 #[post("/edit")]
 async fn edit_user(
-    conn: Data<ConnectionPool>,
+    db: Data<BlockingPool>,
     form: web::Form<EditUserForm>,
 ) -> actix_web::Result<impl Responder> {
-    let mut conn = conn.get().unwrap();
-    match User::update_user(
-        &mut conn,
-        &form.old_username,
-        &form.new_username,
-        form.enabled,
-    ) {
+    let new_username = form.new_username.clone();
+    let res = db
+        .run(move |conn| {
+            User::update_user(conn, &form.old_username, &form.new_username, form.enabled)
+        })
+        .await?;
+
+    match res {
         Ok(_) => {
             let response = actix_web::HttpResponse::Found()
-                .insert_header(("Location", format!("/users/{}", form.new_username)))
+                .insert_header(("Location", format!("/users/{new_username}")))
                 .finish();
             Ok(response)
         }
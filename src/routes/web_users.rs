@@ -0,0 +1,276 @@
+use actix_identity::Identity;
+use actix_web::{
+    get, post,
+    web::{self, Data},
+    HttpRequest, Responder,
+};
+use askama_actix::{Template, TemplateToResponse};
+use log::info;
+use serde::Deserialize;
+
+use crate::{
+    auth::{AuthBackend, Role},
+    branding,
+    db::BlockingPool,
+    forms::FormResponseBuilder,
+    models::{LoginAttempt, User, WebSession, WebUser},
+    notifications::Notifier,
+    proxy::absolute_url,
+    routes::RenderErrorTemplate,
+};
+
+pub fn web_users_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web_users_page)
+        .service(render_web_users)
+        .service(render_login_attempts)
+        .service(render_sessions)
+        .service(revoke_session)
+        .service(log_out_everywhere)
+        .service(set_role)
+        .service(reset_totp)
+        .service(reset_password);
+}
+
+#[derive(Template)]
+#[template(path = "web_users/index.html")]
+struct WebUsersTemplate {}
+
+#[get("")]
+async fn web_users_page() -> impl Responder {
+    WebUsersTemplate {}
+}
+
+#[derive(Template)]
+#[template(path = "web_users/list.htm")]
+struct RenderWebUsersTemplate {
+    web_users: Vec<WebUser>,
+    roles: &'static [Role],
+}
+
+#[get("/list.htm")]
+async fn render_web_users(db: Data<BlockingPool>) -> actix_web::Result<impl Responder> {
+    Ok(match db.run(WebUser::get_all).await? {
+        Ok(web_users) => RenderWebUsersTemplate {
+            web_users,
+            roles: &Role::ALL,
+        }
+        .to_response(),
+        Err(error) => RenderErrorTemplate { error }.to_response(),
+    })
+}
+
+/// Login attempts shown for review
+const LOGIN_ATTEMPTS_SHOWN: i64 = 100;
+
+#[derive(Template)]
+#[template(path = "web_users/login_attempts.htm")]
+struct RenderLoginAttemptsTemplate {
+    attempts: Vec<LoginAttempt>,
+}
+
+#[get("/login_attempts.htm")]
+async fn render_login_attempts(db: Data<BlockingPool>) -> actix_web::Result<impl Responder> {
+    Ok(
+        match db
+            .run(|conn| LoginAttempt::get_latest(conn, LOGIN_ATTEMPTS_SHOWN))
+            .await?
+        {
+            Ok(attempts) => RenderLoginAttemptsTemplate { attempts }.to_response(),
+            Err(error) => RenderErrorTemplate { error }.to_response(),
+        },
+    )
+}
+
+#[derive(Template)]
+#[template(path = "web_users/sessions.htm")]
+struct RenderSessionsTemplate {
+    sessions: Vec<WebSession>,
+}
+
+#[get("/sessions.htm")]
+async fn render_sessions(db: Data<BlockingPool>) -> actix_web::Result<impl Responder> {
+    Ok(match db.run(WebSession::get_logged_in).await? {
+        Ok(sessions) => RenderSessionsTemplate { sessions }.to_response(),
+        Err(error) => RenderErrorTemplate { error }.to_response(),
+    })
+}
+
+#[derive(Deserialize)]
+struct RevokeSessionForm {
+    id: i32,
+}
+
+#[post("/sessions/revoke")]
+async fn revoke_session(
+    db: Data<BlockingPool>,
+    form: web::Form<RevokeSessionForm>,
+) -> actix_web::Result<impl Responder> {
+    let id = form.0.id;
+
+    let res = db.run(move |conn| WebSession::delete(conn, id)).await?;
+    Ok(match res {
+        Ok(()) => FormResponseBuilder::success(String::from("Revoked session"))
+            .add_trigger(String::from("reload-sessions")),
+        Err(e) => FormResponseBuilder::error(e),
+    })
+}
+
+#[derive(Deserialize)]
+struct LogOutEverywhereForm {
+    username: String,
+}
+
+#[post("/sessions/log_out")]
+async fn log_out_everywhere(
+    db: Data<BlockingPool>,
+    form: web::Form<LogOutEverywhereForm>,
+) -> actix_web::Result<impl Responder> {
+    let username = form.0.username;
+
+    let name = username.clone();
+    let res = db
+        .run(move |conn| WebSession::delete_for_user(conn, &name))
+        .await?;
+    Ok(match res {
+        Ok(count) => {
+            FormResponseBuilder::success(format!("Ended {count} session(s) of {username}"))
+                .add_trigger(String::from("reload-sessions"))
+        }
+        Err(e) => FormResponseBuilder::error(e),
+    })
+}
+
+#[derive(Deserialize)]
+struct SetRoleForm {
+    id: i32,
+    role: Role,
+}
+
+#[post("/role")]
+async fn set_role(
+    db: Data<BlockingPool>,
+    identity: Identity,
+    form: web::Form<SetRoleForm>,
+) -> actix_web::Result<impl Responder> {
+    let SetRoleForm { id, role } = form.0;
+    let username = identity.id().unwrap_or_default();
+
+    let res = db
+        .run(move |conn| {
+            // Admins could otherwise lock everyone out of this page
+            let is_self = WebUser::get_all(conn)?
+                .iter()
+                .any(|user| user.id == id && user.username == username);
+            if is_self {
+                return Err(String::from("You can't change your own role"));
+            }
+            WebUser::set_role(conn, id, role)
+        })
+        .await?;
+    Ok(match res {
+        Ok(()) => FormResponseBuilder::success(format!("Changed role to {role}"))
+            .add_trigger(String::from("reload-web-users")),
+        Err(e) => FormResponseBuilder::error(e),
+    })
+}
+
+#[derive(Deserialize)]
+struct ResetTotpForm {
+    id: i32,
+}
+
+/// For users who lost their authenticator app and recovery codes
+#[post("/reset_totp")]
+async fn reset_totp(
+    db: Data<BlockingPool>,
+    form: web::Form<ResetTotpForm>,
+) -> actix_web::Result<impl Responder> {
+    let id = form.0.id;
+
+    let res = db.run(move |conn| WebUser::disable_totp(conn, id)).await?;
+    Ok(match res {
+        Ok(()) => FormResponseBuilder::success(String::from(
+            "Reset two-factor authentication, the user logs in with the password alone",
+        ))
+        .add_trigger(String::from("reload-web-users")),
+        Err(e) => FormResponseBuilder::error(e),
+    })
+}
+
+/// How long a password reset link works
+const PASSWORD_RESET_VALIDITY: time::Duration = time::Duration::hours(24);
+
+#[derive(Deserialize)]
+struct ResetPasswordForm {
+    id: i32,
+}
+
+/// Mails the user a link to choose a new password, to the email of the user of the same name
+#[post("/reset_password")]
+async fn reset_password(
+    req: HttpRequest,
+    db: Data<BlockingPool>,
+    auth_backend: Data<dyn AuthBackend>,
+    notifier: Data<Notifier>,
+    form: web::Form<ResetPasswordForm>,
+) -> actix_web::Result<impl Responder> {
+    if !auth_backend.can_set_password() {
+        return Ok(FormResponseBuilder::error(String::from(
+            "Passwords can't be changed with this authentication backend",
+        )));
+    }
+    let id = form.0.id;
+
+    let res = db
+        .run(move |conn| {
+            let web_user = WebUser::get_all(conn)?
+                .into_iter()
+                .find(|web_user| web_user.id == id)
+                .ok_or_else(|| String::from("Unknown web user"))?;
+            if web_user.oidc_subject.is_some() {
+                return Err(String::from(
+                    "The user signs in via single sign-on and has no password here",
+                ));
+            }
+            let email = User::get_from_name(conn, &web_user.username)?
+                .and_then(|user| user.email)
+                .ok_or_else(|| {
+                    format!(
+                        "There is no user '{}' with an email address to send the link to",
+                        web_user.username
+                    )
+                })?;
+            let token = web_user.create_password_reset(conn, PASSWORD_RESET_VALIDITY)?;
+            Ok((web_user.username, email, token))
+        })
+        .await?;
+    let (username, email, token) = match res {
+        Ok(res) => res,
+        Err(e) => return Ok(FormResponseBuilder::error(e)),
+    };
+
+    let link = absolute_url(&req, &format!("/auth/reset?token={token}"));
+    let instance = branding::instance_name();
+    let body = format!(
+        "An admin of {instance} asked you to choose a new password for your account '{username}'.\n\
+        Open this link within {} hours to do so, it works once:\n\n{link}\n\n\
+        Your sessions end once the new password is set.",
+        PASSWORD_RESET_VALIDITY.whole_hours()
+    );
+    Ok(
+        match notifier
+            .mail(
+                &email,
+                &format!("Reset your password for {instance}"),
+                &body,
+            )
+            .await
+        {
+            Ok(()) => {
+                info!("Sent a password reset link to {username}");
+                FormResponseBuilder::success(format!("Sent a password reset link to {email}"))
+            }
+            Err(e) => FormResponseBuilder::error(e),
+        },
+    )
+}
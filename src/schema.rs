@@ -15,6 +15,18 @@ diesel::table! {
         key_fingerprint -> Nullable<Text>,
         /// jumphost for ssh connections
         jump_via -> Nullable<Integer>,
+        /// forbid port forwarding regardless of the authorizations
+        forbid_forwarding -> Bool,
+        /// web user approving access requests for this host
+        owner -> Nullable<Text>,
+        /// options of every authorization on this host that doesn't set them itself
+        default_options -> Nullable<Text>,
+        /// how the host key was verified: manual, sshfp, sshfp-dnssec or known-hosts
+        key_trust -> Nullable<Text>,
+        /// critical, normal or low: order of syncs and remediation, severity of alerts
+        importance -> Text,
+        /// until when drift is only reported, without changing the host
+        observed_until -> Nullable<Timestamp>,
     }
 }
 
@@ -27,6 +39,10 @@ diesel::table! {
         username -> Text,
         /// whether this user is active
         enabled -> Bool,
+        /// optional contact address
+        email -> Nullable<Text>,
+        /// options of every authorization of this user that doesn't set them otherwise
+        default_options -> Nullable<Text>,
     }
 }
 
@@ -45,6 +61,8 @@ diesel::table! {
         login -> Text,
         /// ssh key options
         options -> Nullable<Text>,
+        /// when the authorization is dropped, never if null
+        expires_at -> Nullable<Timestamp>,
     }
 }
 
@@ -62,7 +80,468 @@ diesel::table! {
         comment -> Nullable<Text>,
         /// user this key belongs to
         user_id -> Integer,
+        /// when the key stops being authorized, never if null
+        expires_at -> Nullable<Timestamp>,
+        /// FIDO attestation written by ssh-keygen, for sk- keys
+        attestation -> Nullable<Binary>,
+        /// subject of the verified attestation certificate
+        attested_by -> Nullable<Text>,
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(host, user, authorization, user_key,);
+diesel::table! {
+    /// Decommissioned hosts
+    host_archive (id) {
+        /// unique id
+        id -> Integer,
+        /// display name at the time of decommissioning
+        name -> Text,
+        /// username for ssh connections
+        username -> Text,
+        /// hostname or ip address for ssh connections
+        address -> Text,
+        /// port for ssh connections
+        port -> Integer,
+        /// fingerprint of the hostkey
+        key_fingerprint -> Nullable<Text>,
+        /// when the host was decommissioned
+        decommissioned_at -> Timestamp,
+        /// who had access right before decommissioning
+        access_report -> Text,
+    }
+}
+
+diesel::table! {
+    /// Completion reports of offboarded users
+    user_offboarding (id) {
+        /// unique id
+        id -> Integer,
+        /// name of the user at the time of offboarding
+        username -> Text,
+        /// when the offboarding finished
+        offboarded_at -> Timestamp,
+        /// what was done
+        report -> Text,
+    }
+}
+
+diesel::table! {
+    /// Tokens for API clients
+    api_token (id) {
+        /// unique id
+        id -> Integer,
+        /// what this token is used for
+        name -> Text,
+        /// hex encoded sha256 of the token
+        token_hash -> Text,
+        /// who created this token
+        created_by -> Text,
+        /// when this token was created
+        created_at -> Timestamp,
+        /// when this token was last used
+        last_used -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    /// Users of the web interface and their roles
+    web_user (id) {
+        /// unique id
+        id -> Integer,
+        /// name used for the session
+        username -> Text,
+        /// `issuer subject` of the OpenID Connect identity, unset for password logins
+        oidc_subject -> Nullable<Text>,
+        /// when this user was provisioned
+        created_at -> Timestamp,
+        /// when this user last logged in
+        last_login -> Nullable<Timestamp>,
+        /// `viewer`, `operator` or `admin`
+        role -> Text,
+        /// base32 secret of the TOTP second factor, unset without one
+        totp_secret -> Nullable<Text>,
+        /// time step of the last accepted TOTP code, which can't be used again
+        totp_last_step -> Nullable<BigInt>,
+    }
+}
+
+diesel::table! {
+    /// Instances sharing the database for scheduled jobs
+    cluster_worker (id) {
+        /// configured or generated worker id
+        id -> Text,
+        /// when this worker was last seen alive
+        heartbeat -> Timestamp,
+    }
+}
+
+diesel::table! {
+    /// Which worker checks a host during scheduled runs
+    host_sync (host_id) {
+        /// the host to check
+        host_id -> Integer,
+        /// the worker holding the claim, unset if it was released
+        worker_id -> Nullable<Text>,
+        /// when the claim was taken
+        claimed_at -> Nullable<Timestamp>,
+        /// when the check finished, unset while running
+        finished_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    /// Changes made through the web interface, the API or the check job
+    audit_log (id) {
+        /// unique id
+        id -> Integer,
+        /// when the change was made
+        created_at -> Timestamp,
+        /// web user, api token or `scheduler`
+        actor -> Text,
+        /// method and path, e.g. `POST /hosts/web1/delete`
+        action -> Text,
+        /// submitted fields, without secrets
+        target -> Text,
+        /// whether the change succeeded
+        success -> Bool,
+        /// response status or error message
+        result -> Text,
+        /// address of the client, as told by a trusted proxy, unset for the scheduler
+        client -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    /// Snapshots of the state of all hosts
+    integrity_report (id) {
+        /// unique id
+        id -> Integer,
+        /// when the report was generated
+        created_at -> Timestamp,
+        /// host counts for the overview
+        summary -> Text,
+        /// JSON encoded report of every host
+        hosts -> Text,
+    }
+}
+
+diesel::joinable!(access_request -> host (host_id));
+diesel::joinable!(access_request -> user (user_id));
+diesel::table! {
+    /// Requests of users for access to a host
+    access_request (id) {
+        /// unique id
+        id -> Integer,
+        /// when the request was made
+        created_at -> Timestamp,
+        /// web user who made the request
+        requested_by -> Text,
+        /// user who gets access
+        user_id -> Integer,
+        /// host to access
+        host_id -> Integer,
+        /// username on the host
+        login -> Text,
+        /// why access is needed
+        justification -> Text,
+        /// pending, approved or denied
+        status -> Text,
+        /// web user who approved or denied the request
+        decided_by -> Nullable<Text>,
+        /// when the request was approved or denied
+        decided_at -> Nullable<Timestamp>,
+        /// outcome of the deployment
+        result -> Nullable<Text>,
+    }
+}
+
+diesel::joinable!(host_sync -> host (host_id));
+
+diesel::table! {
+    /// Named groups of hosts, like "web" or "prod-eu"
+    host_group (id) {
+        /// unique id
+        id -> Integer,
+        /// unique name
+        name -> Text,
+    }
+}
+
+diesel::joinable!(host_group_member -> host_group (group_id));
+diesel::joinable!(host_group_member -> host (host_id));
+diesel::table! {
+    /// Which hosts are in which groups
+    host_group_member (group_id, host_id) {
+        /// the group
+        group_id -> Integer,
+        /// the host in it
+        host_id -> Integer,
+    }
+}
+
+diesel::table! {
+    /// Teams of users
+    user_group (id) {
+        /// unique id
+        id -> Integer,
+        /// unique name
+        name -> Text,
+    }
+}
+
+diesel::joinable!(user_group_member -> user_group (group_id));
+diesel::joinable!(user_group_member -> user (user_id));
+diesel::table! {
+    /// Which users are in which teams
+    user_group_member (group_id, user_id) {
+        /// the team
+        group_id -> Integer,
+        /// the user in it
+        user_id -> Integer,
+    }
+}
+
+diesel::joinable!(group_authorization -> user_group (user_group_id));
+diesel::joinable!(group_authorization -> host_group (host_group_id));
+diesel::table! {
+    /// Rules letting every member of a team access every host of a host group
+    group_authorization (id) {
+        /// unique id
+        id -> Integer,
+        /// the team
+        user_group_id -> Integer,
+        /// the hosts
+        host_group_id -> Integer,
+        /// the login on the hosts
+        login -> Text,
+        /// options of the keys, unless an authorization sets them
+        options -> Nullable<Text>,
+    }
+}
+
+diesel::joinable!(pending_removal -> host (host_id));
+diesel::table! {
+    /// Keys that couldn't be removed from a host yet and are retried
+    pending_removal (id) {
+        /// unique id
+        id -> Integer,
+        /// when the removal failed first
+        created_at -> Timestamp,
+        /// host to remove the key from
+        host_id -> Integer,
+        /// username on the host
+        login -> Text,
+        /// the key to remove (base64)
+        key_base64 -> Text,
+        /// why the key is removed, e.g. `offboarding of alice`
+        reason -> Text,
+        /// number of failed attempts
+        attempts -> Integer,
+        /// when the removal was last tried
+        last_attempt -> Nullable<Timestamp>,
+        /// why the last attempt failed
+        last_error -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    /// Suspicious patterns found by the anomaly detection
+    finding (id) {
+        /// unique id
+        id -> Integer,
+        /// when the pattern was found
+        created_at -> Timestamp,
+        /// which heuristic found it, e.g. `widespread_key`
+        kind -> Text,
+        /// what it is about, unique per kind, e.g. `key:12`
+        subject -> Text,
+        /// description of what was found
+        details -> Text,
+        /// open, acknowledged or dismissed
+        status -> Text,
+        /// web user who acknowledged or dismissed the finding
+        decided_by -> Nullable<Text>,
+        /// when the finding was acknowledged or dismissed
+        decided_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::joinable!(pending_authorization -> host (host_id));
+diesel::joinable!(pending_authorization -> user (user_id));
+diesel::table! {
+    /// Authorizations proposed by operators, waiting for the approval of an admin
+    pending_authorization (id) {
+        /// unique id
+        id -> Integer,
+        /// when the authorization was proposed
+        created_at -> Timestamp,
+        /// web user who proposed the authorization
+        proposed_by -> Text,
+        /// host to authorize the user on
+        host_id -> Integer,
+        /// user to authorize
+        user_id -> Integer,
+        /// username on the host
+        login -> Text,
+        /// key options
+        options -> Nullable<Text>,
+        /// when the authorization is dropped, never if null
+        expires_at -> Nullable<Timestamp>,
+        /// pending, approved or rejected
+        status -> Text,
+        /// web user who approved or rejected the authorization
+        decided_by -> Nullable<Text>,
+        /// when the authorization was approved or rejected
+        decided_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::joinable!(deployment -> host (host_id));
+diesel::table! {
+    /// When ssm last wrote the authorized_keys of a login
+    deployment (host_id, login) {
+        /// the host
+        host_id -> Integer,
+        /// the login whose authorized_keys was written
+        login -> Text,
+        /// when it was written
+        deployed_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    /// Password logins, for throttling them and for review
+    login_attempt (id) {
+        /// unique id
+        id -> Integer,
+        /// when the login was attempted
+        created_at -> Timestamp,
+        /// username as entered
+        username -> Text,
+        /// address of the client, as told by a trusted proxy
+        client -> Nullable<Text>,
+        /// success, failure, or throttled if the password wasn't checked
+        outcome -> Text,
+    }
+}
+
+diesel::joinable!(recovery_code -> web_user (web_user_id));
+diesel::table! {
+    /// Single-use codes replacing the TOTP code, e.g. after losing the phone
+    recovery_code (id) {
+        /// unique id
+        id -> Integer,
+        /// the web user
+        web_user_id -> Integer,
+        /// SHA-256 of the code, hex encoded
+        code_hash -> Text,
+    }
+}
+
+diesel::table! {
+    /// Sessions of browsers, stored server-side so they can be revoked
+    web_session (id) {
+        /// unique id
+        id -> Integer,
+        /// SHA-256 of the key in the session cookie, hex encoded
+        key_hash -> Text,
+        /// the session state as JSON object
+        state -> Text,
+        /// the logged in web user, if any
+        username -> Nullable<Text>,
+        /// address of the client, as told by a trusted proxy
+        client -> Nullable<Text>,
+        /// when the session was created
+        created_at -> Timestamp,
+        /// when the session was last used, updated at most once a minute
+        last_activity -> Timestamp,
+        /// when the session ends without further activity
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::joinable!(password_reset -> web_user (web_user_id));
+diesel::table! {
+    /// One-time links for web users to choose a new password
+    password_reset (id) {
+        /// unique id
+        id -> Integer,
+        /// the web user
+        web_user_id -> Integer,
+        /// SHA-256 of the token in the link, hex encoded
+        token_hash -> Text,
+        /// when an admin requested the reset
+        created_at -> Timestamp,
+        /// when the link stops working
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::joinable!(host_discovery -> host (host_id));
+diesel::table! {
+    /// Hosts created by a discovery, e.g. of EC2 instances
+    host_discovery (host_id) {
+        /// the host
+        host_id -> Integer,
+        /// what found the host, e.g. ec2
+        source -> Text,
+        /// id of the host at the source, e.g. the instance id
+        external_id -> Text,
+    }
+}
+
+diesel::joinable!(user_account -> user (user_id));
+diesel::table! {
+    /// Accounts of users at code hosts their keys are imported from
+    user_account (user_id, provider) {
+        /// the user
+        user_id -> Integer,
+        /// where the account is, e.g. github
+        provider -> Text,
+        /// name of the account there
+        login -> Text,
+    }
+}
+
+diesel::joinable!(imported_key -> user_key (key_id));
+diesel::table! {
+    /// Keys imported from the account of their user at a code host
+    imported_key (key_id) {
+        /// the key
+        key_id -> Integer,
+        /// where the key was listed, e.g. github
+        provider -> Text,
+    }
+}
+
+diesel::allow_tables_to_appear_in_same_query!(
+    host,
+    user,
+    authorization,
+    user_key,
+    host_archive,
+    user_offboarding,
+    api_token,
+    web_user,
+    cluster_worker,
+    host_sync,
+    audit_log,
+    integrity_report,
+    access_request,
+    host_group,
+    host_group_member,
+    user_group,
+    user_group_member,
+    group_authorization,
+    pending_removal,
+    finding,
+    pending_authorization,
+    deployment,
+    login_attempt,
+    recovery_code,
+    web_session,
+    password_reset,
+    host_discovery,
+    user_account,
+    imported_key,
+);
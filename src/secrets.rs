@@ -0,0 +1,147 @@
+use std::{env, fmt, path::PathBuf, process::Command, str::FromStr};
+
+use serde::Deserialize;
+
+/// Where a secret is read from at startup.
+///
+/// In the configuration a secret is written as `<source>:<value>`:
+/// - `secret+file:/run/secrets/db_url` reads the file. Plain `file:` is left alone, as
+///   SQLite URIs start with it.
+/// - `env:DB_PASSWORD` reads the environment variable
+/// - `systemd:ssm-key` reads the systemd credential from `$CREDENTIALS_DIRECTORY`
+/// - `command:pass show ssm` runs the command with `sh -c` and reads stdout
+///
+/// Anything else is used as is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecretSource {
+    Literal(String),
+    File(PathBuf),
+    Env(String),
+    Systemd(String),
+    Command(String),
+}
+
+impl FromStr for SecretSource {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.split_once(':') {
+            Some(("secret+file", path)) => Self::File(PathBuf::from(path)),
+            Some(("env", var)) => Self::Env(var.to_owned()),
+            Some(("systemd", name)) => Self::Systemd(name.to_owned()),
+            Some(("command", command)) => Self::Command(command.to_owned()),
+            _ => Self::Literal(s.to_owned()),
+        })
+    }
+}
+
+impl fmt::Display for SecretSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Literal(_) => write!(f, "configuration value"),
+            Self::File(path) => write!(f, "file '{}'", path.display()),
+            Self::Env(var) => write!(f, "environment variable '{var}'"),
+            Self::Systemd(name) => write!(f, "systemd credential '{name}'"),
+            Self::Command(command) => write!(f, "command '{command}'"),
+        }
+    }
+}
+
+fn read_file(path: &PathBuf) -> Result<String, String> {
+    std::fs::read_to_string(path).map_err(|e| format!("Couldn't read '{}': {e}", path.display()))
+}
+
+impl SecretSource {
+    /// Reads the secret. A single trailing newline is stripped from
+    /// files and command output.
+    pub fn resolve(&self) -> Result<String, String> {
+        let value = match self {
+            Self::Literal(value) => return Ok(value.clone()),
+            Self::File(path) => read_file(path)?,
+            Self::Env(var) => {
+                return env::var(var).map_err(|e| format!("Couldn't read ${var}: {e}"));
+            }
+            Self::Systemd(name) => {
+                let directory = env::var("CREDENTIALS_DIRECTORY").map_err(|_| {
+                    String::from(
+                        "$CREDENTIALS_DIRECTORY is not set, is LoadCredential= configured?",
+                    )
+                })?;
+                read_file(&PathBuf::from(directory).join(name))?
+            }
+            Self::Command(command) => {
+                let output = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .output()
+                    .map_err(|e| format!("Couldn't run '{command}': {e}"))?;
+
+                if !output.status.success() {
+                    return Err(format!("'{command}' failed with {}", output.status));
+                }
+                String::from_utf8(output.stdout)
+                    .map_err(|_| format!("'{command}' returned invalid UTF-8"))?
+            }
+        };
+
+        let value = value.strip_suffix('\n').unwrap_or(&value);
+        Ok(value.strip_suffix('\r').unwrap_or(value).to_owned())
+    }
+}
+
+/// Deserializes a [`SecretSource`] and reads it immediately
+pub fn deserialize_secret<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let Ok(source) = String::deserialize(deserializer)?.parse::<SecretSource>();
+
+    source
+        .resolve()
+        .map_err(|e| serde::de::Error::custom(format!("Failed to read secret from {source}: {e}")))
+}
+
+/// Like [`deserialize_secret`], for optional values
+pub fn deserialize_optional_secret<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_secret(deserializer).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sources() {
+        let cases = [
+            (
+                "secret+file:/run/secrets/db",
+                SecretSource::File(PathBuf::from("/run/secrets/db")),
+            ),
+            ("env:DB_URL", SecretSource::Env(String::from("DB_URL"))),
+            (
+                "systemd:ssm-key",
+                SecretSource::Systemd(String::from("ssm-key")),
+            ),
+            (
+                "command:pass show ssm",
+                SecretSource::Command(String::from("pass show ssm")),
+            ),
+            // SQLite URIs and URLs with a port are values
+            (
+                "file:ssm.db?mode=rwc",
+                SecretSource::Literal(String::from("file:ssm.db?mode=rwc")),
+            ),
+            (
+                "sqlite://ssm.db",
+                SecretSource::Literal(String::from("sqlite://ssm.db")),
+            ),
+            ("password", SecretSource::Literal(String::from("password"))),
+        ];
+        for (value, source) in cases {
+            assert_eq!(value.parse::<SecretSource>(), Ok(source), "{value}");
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use data_encoding::HEXLOWER;
+use rand::RngCore;
+use tokio::sync::RwLock;
+
+/// Selections not touched for this long are forgotten
+const SELECTION_TTL: Duration = Duration::from_secs(8 * 60 * 60);
+/// Most items one selection of a kind can hold
+pub const MAX_SELECTED: usize = 10_000;
+
+/// What can be selected for bulk operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SelectionKind {
+    /// Host names
+    Hosts,
+    /// Key ids
+    Keys,
+}
+
+impl FromStr for SelectionKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hosts" => Ok(Self::Hosts),
+            "keys" => Ok(Self::Keys),
+            _ => Err(format!("Nothing named '{s}' can be selected")),
+        }
+    }
+}
+
+impl SelectionKind {
+    fn check(self, item: &str) -> Result<(), String> {
+        match self {
+            Self::Hosts if item.trim().is_empty() => Err(String::from("Empty host name")),
+            Self::Keys if item.parse::<i32>().is_err() => Err(format!("Invalid key id '{item}'")),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Selection {
+    items: HashMap<SelectionKind, BTreeSet<String>>,
+    last_used: Option<Instant>,
+}
+
+/// Items selected in the web interface, per browser session. Kept in memory, so selections
+/// are lost on restart and not shared between instances.
+#[derive(Clone, Default)]
+pub struct SelectionStore {
+    selections: Arc<RwLock<HashMap<String, Selection>>>,
+}
+
+impl SelectionStore {
+    /// A new random id for the selection of a session
+    pub fn new_id() -> String {
+        let mut bytes = [0u8; 16];
+        rand::rngs::OsRng.fill_bytes(&mut bytes);
+        HEXLOWER.encode(&bytes)
+    }
+
+    /// The selected items, sorted
+    pub async fn get(&self, id: &str, kind: SelectionKind) -> Vec<String> {
+        let mut selections = self.selections.write().await;
+        match selections.get_mut(id) {
+            Some(selection) => {
+                selection.last_used = Some(Instant::now());
+                selection
+                    .items
+                    .get(&kind)
+                    .map(|items| items.iter().cloned().collect())
+                    .unwrap_or_default()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Adds and removes items, after clearing the selection if asked to. Returns the selection.
+    pub async fn change(
+        &self,
+        id: &str,
+        kind: SelectionKind,
+        clear: bool,
+        add: Vec<String>,
+        remove: Vec<String>,
+    ) -> Result<Vec<String>, String> {
+        for item in &add {
+            kind.check(item)?;
+        }
+
+        let mut selections = self.selections.write().await;
+        let now = Instant::now();
+        selections.retain(|_, selection| {
+            selection
+                .last_used
+                .is_some_and(|last_used| now.duration_since(last_used) < SELECTION_TTL)
+        });
+
+        let selection = selections.entry(id.to_owned()).or_default();
+        selection.last_used = Some(now);
+        let items = selection.items.entry(kind).or_default();
+        if clear {
+            items.clear();
+        }
+        for item in &remove {
+            items.remove(item);
+        }
+        for item in add {
+            if items.len() >= MAX_SELECTED {
+                return Err(format!("At most {MAX_SELECTED} items can be selected"));
+            }
+            items.insert(item);
+        }
+        Ok(items.iter().cloned().collect())
+    }
+
+    pub async fn clear(&self, id: &str, kind: SelectionKind) {
+        if let Some(selection) = self.selections.write().await.get_mut(id) {
+            selection.items.remove(&kind);
+        }
+    }
+}
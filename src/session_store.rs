@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+use actix_session::storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError};
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    Error, HttpMessage,
+};
+use data_encoding::HEXLOWER;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    db::{now, BlockingPool},
+    models::{NewWebSession, WebSession},
+    proxy::Client,
+};
+
+/// Sessions end after a day without activity
+const IDLE_TIMEOUT: time::Duration = time::Duration::days(1);
+
+/// Sessions nobody logged in with, e.g. of the login page, end sooner
+const ANONYMOUS_TIMEOUT: time::Duration = time::Duration::hours(1);
+
+/// How often the last activity of a session is written at most
+const TOUCH_INTERVAL: time::Duration = time::Duration::minutes(1);
+
+/// Where actix-identity keeps the logged in user in the session state
+const IDENTITY_KEY: &str = "actix_identity.user_id";
+
+tokio::task_local! {
+    static CLIENT: Option<String>;
+}
+
+/// Middleware around the session middleware, letting the store know the client address
+pub async fn with_client(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let client = req
+        .extensions()
+        .get::<Client>()
+        .and_then(|client| client.addr)
+        .map(|addr| addr.to_string());
+    CLIENT.scope(client, next.call(req)).await
+}
+
+fn client() -> Option<String> {
+    CLIENT.try_with(Clone::clone).ok().flatten()
+}
+
+/// Only the hash of the cookie value is stored, so the database doesn't hand out sessions
+fn hash_key(key: &SessionKey) -> String {
+    HEXLOWER.encode(&Sha256::digest(key.as_ref().as_bytes()))
+}
+
+fn new_key() -> Result<SessionKey, anyhow::Error> {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    SessionKey::try_from(HEXLOWER.encode(&bytes)).map_err(anyhow::Error::from)
+}
+
+fn username(state: &HashMap<String, String>) -> Option<String> {
+    state
+        .get(IDENTITY_KEY)
+        .and_then(|user| serde_json::from_str(user).ok())
+}
+
+/// When a session with this user ends without further activity
+fn expires_at(username: Option<&str>) -> time::PrimitiveDateTime {
+    now() + username.map_or(ANONYMOUS_TIMEOUT, |_| IDLE_TIMEOUT)
+}
+
+/// Keeps the sessions in the database, so admins can see and revoke them
+#[derive(Clone)]
+pub struct DbSessionStore {
+    db: BlockingPool,
+}
+
+impl DbSessionStore {
+    pub const fn new(db: BlockingPool) -> Self {
+        Self { db }
+    }
+}
+
+impl SessionStore for DbSessionStore {
+    async fn load(
+        &self,
+        session_key: &SessionKey,
+    ) -> Result<Option<HashMap<String, String>>, LoadError> {
+        let key_hash = hash_key(session_key);
+        let client = client();
+        self.db
+            .run(move |conn| {
+                let Some(session) = WebSession::get_by_key_hash(conn, &key_hash)? else {
+                    return Ok(None);
+                };
+                if session.last_activity + TOUCH_INTERVAL < now() || session.client != client {
+                    let expires_at = expires_at(session.username.as_deref());
+                    WebSession::touch(conn, session.id, client, expires_at)?;
+                }
+                Ok::<_, String>(Some(session.state))
+            })
+            .await
+            .map_err(|e| LoadError::Other(anyhow::anyhow!(e)))?
+            .map_err(|e| LoadError::Other(anyhow::anyhow!(e)))?
+            .map(|state| serde_json::from_str(&state))
+            .transpose()
+            .map_err(|e| LoadError::Deserialization(e.into()))
+    }
+
+    async fn save(
+        &self,
+        session_state: HashMap<String, String>,
+        _ttl: &actix_web::cookie::time::Duration,
+    ) -> Result<SessionKey, SaveError> {
+        let key = new_key().map_err(SaveError::Other)?;
+        let state = serde_json::to_string(&session_state)
+            .map_err(|e| SaveError::Serialization(e.into()))?;
+        let username = username(&session_state);
+        let session = NewWebSession {
+            key_hash: hash_key(&key),
+            state,
+            expires_at: expires_at(username.as_deref()),
+            username,
+            client: client(),
+            created_at: now(),
+            last_activity: now(),
+        };
+        self.db
+            .run(move |conn| {
+                // Sessions are only created now and then, a good time to clean up
+                WebSession::prune(conn)?;
+                WebSession::create(conn, session)
+            })
+            .await
+            .map_err(|e| SaveError::Other(anyhow::anyhow!(e)))?
+            .map_err(|e| SaveError::Other(anyhow::anyhow!(e)))?;
+        Ok(key)
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: HashMap<String, String>,
+        _ttl: &actix_web::cookie::time::Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        let key_hash = hash_key(&session_key);
+        let state = serde_json::to_string(&session_state)
+            .map_err(|e| UpdateError::Serialization(e.into()))?;
+        let username = username(&session_state);
+        let expires_at = expires_at(username.as_deref());
+        let exists = self
+            .db
+            .run(move |conn| WebSession::update_state(conn, &key_hash, state, username, expires_at))
+            .await
+            .map_err(|e| UpdateError::Other(anyhow::anyhow!(e)))?
+            .map_err(|e| UpdateError::Other(anyhow::anyhow!(e)))?;
+        // Not created again, the session was revoked while the request ran
+        if !exists {
+            return Err(UpdateError::Other(anyhow::anyhow!(
+                "The session was revoked"
+            )));
+        }
+        Ok(session_key)
+    }
+
+    async fn update_ttl(
+        &self,
+        _session_key: &SessionKey,
+        _ttl: &actix_web::cookie::time::Duration,
+    ) -> Result<(), anyhow::Error> {
+        // Loading a session already extends it
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        let key_hash = hash_key(session_key);
+        self.db
+            .run(move |conn| WebSession::delete_by_key_hash(conn, &key_hash))
+            .await
+            .map_err(|e| anyhow::anyhow!(e))?
+            .map_err(|e| anyhow::anyhow!(e))
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use actix_web::dev::ServerHandle;
+use log::{info, warn};
+use tokio::{sync::Notify, time::Instant};
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+static STOPPED: Notify = Notify::const_new();
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+/// Descriptions of the running operations by id
+static RUNNING: Mutex<Option<HashMap<u64, String>>> = Mutex::new(None);
+
+/// Whether the server is shutting down. Fleet operations don't start on further hosts then.
+pub fn is_stopping() -> bool {
+    STOPPING.load(Ordering::Relaxed)
+}
+
+/// Completes once the server is shutting down
+pub async fn stopped() {
+    // Created before checking, so a shutdown in between isn't missed
+    let notified = STOPPED.notified();
+    if !is_stopping() {
+        notified.await;
+    }
+}
+
+/// A fleet operation or a write to a host, which a shutdown waits for until it is dropped
+pub struct Operation {
+    id: u64,
+}
+
+impl Operation {
+    pub fn begin(description: impl Into<String>) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        RUNNING
+            .lock()
+            .expect("Operations lock poisoned")
+            .get_or_insert_with(HashMap::new)
+            .insert(id, description.into());
+        Self { id }
+    }
+}
+
+impl Drop for Operation {
+    fn drop(&mut self) {
+        if let Some(running) = RUNNING.lock().expect("Operations lock poisoned").as_mut() {
+            running.remove(&self.id);
+        }
+    }
+}
+
+fn running_operations() -> Vec<String> {
+    let mut running: Vec<String> = RUNNING
+        .lock()
+        .expect("Operations lock poisoned")
+        .as_ref()
+        .map(|running| running.values().cloned().collect())
+        .unwrap_or_default();
+    running.sort();
+    running
+}
+
+/// Stops the server gracefully on SIGTERM or Ctrl-C: no new connections are accepted, fleet
+/// operations stop starting on further hosts and the running operations and requests may
+/// finish, each within `timeout`. The workers keep running until then, since operations
+/// started by requests run on them.
+pub fn handle_signals(server: ServerHandle, timeout: Duration) {
+    tokio::spawn(async move {
+        let ctrl_c = tokio::signal::ctrl_c();
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let Ok(mut sigterm) = signal(SignalKind::terminate()) else {
+                warn!("Failed to listen for SIGTERM");
+                return;
+            };
+            tokio::select! {
+                _ = ctrl_c => {}
+                _ = sigterm.recv() => {}
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = ctrl_c.await;
+
+        info!("Shutting down");
+        STOPPING.store(true, Ordering::Relaxed);
+        STOPPED.notify_waiters();
+        server.pause().await;
+        drain(timeout).await;
+        server.stop(true).await;
+    });
+}
+
+/// Waits up to `timeout` for the running operations to finish
+async fn drain(timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    let mut running = running_operations();
+    if !running.is_empty() {
+        info!("Waiting for {}", running.join(", "));
+    }
+    while !running.is_empty() && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        running = running_operations();
+    }
+    if !running.is_empty() {
+        warn!(
+            "Stopping while still running after {}s: {}",
+            timeout.as_secs(),
+            running.join(", ")
+        );
+    }
+}
@@ -0,0 +1,237 @@
+use std::{sync::Arc, time::Duration};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::Deserialize;
+use time::{format_description::well_known::Rfc2822, OffsetDateTime};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{crypto::ring, pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+
+use crate::secrets;
+
+const fn default_port() -> u16 {
+    587
+}
+
+const fn default_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// How the connection to the mail server is encrypted
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Plain connection upgraded with STARTTLS, usually on port 587
+    #[default]
+    StartTls,
+    /// TLS right away, usually on port 465
+    Tls,
+    /// No encryption, only for a relay on the same machine
+    None,
+}
+
+/// The mail server notifications are sent through
+#[derive(Debug, Clone, Deserialize)]
+pub struct SmtpConfig {
+    host: String,
+    #[serde(default = "default_port")]
+    port: u16,
+    #[serde(default)]
+    security: SmtpSecurity,
+    /// Logs in with AUTH PLAIN if set
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default, deserialize_with = "secrets::deserialize_optional_secret")]
+    password: Option<String>,
+    /// Sender address
+    from: String,
+    /// Timeout in seconds for the whole delivery
+    #[serde(
+        default = "default_timeout",
+        deserialize_with = "crate::deserialize_timeout"
+    )]
+    timeout: Duration,
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// A connection to the mail server, reading replies line by line
+struct Session<S> {
+    stream: BufReader<S>,
+}
+
+impl<S: Stream> Session<S> {
+    fn new(stream: S) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    /// Sends a command and checks that the reply has the expected code
+    async fn command(&mut self, command: &str, expected: &[u16]) -> Result<String, String> {
+        self.stream
+            .get_mut()
+            .write_all(format!("{command}\r\n").as_bytes())
+            .await
+            .map_err(|e| format!("Couldn't write to the mail server: {e}"))?;
+        self.reply(expected).await
+    }
+
+    /// Reads a reply, which may span several `250-...` lines
+    async fn reply(&mut self, expected: &[u16]) -> Result<String, String> {
+        let mut text = String::new();
+        loop {
+            let mut line = String::new();
+            match self.stream.read_line(&mut line).await {
+                Ok(0) => return Err(String::from("The mail server closed the connection")),
+                Ok(_) => {}
+                Err(e) => return Err(format!("Couldn't read from the mail server: {e}")),
+            }
+            let line = line.trim_end();
+            text.push_str(line);
+            text.push('\n');
+            // The last line has a space after the code, the others a dash
+            if line.as_bytes().get(3) != Some(&b'-') {
+                let code = line
+                    .get(..3)
+                    .and_then(|code| code.parse::<u16>().ok())
+                    .ok_or_else(|| format!("Unexpected reply from the mail server: {line}"))?;
+                return match expected.contains(&code) {
+                    true => Ok(text),
+                    false => Err(format!("The mail server refused: {}", text.trim_end())),
+                };
+            }
+        }
+    }
+
+    async fn ehlo(&mut self) -> Result<String, String> {
+        self.command("EHLO ssm", &[250]).await
+    }
+}
+
+fn tls_connector() -> Result<TlsConnector, String> {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("Couldn't set up TLS: {e}"))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+async fn start_tls(stream: TcpStream, host: &str) -> Result<Box<dyn Stream>, String> {
+    let name = ServerName::try_from(host.to_owned())
+        .map_err(|e| format!("Invalid mail server name '{host}': {e}"))?;
+    let stream = tls_connector()?
+        .connect(name, stream)
+        .await
+        .map_err(|e| format!("TLS with the mail server failed: {e}"))?;
+    Ok(Box::new(stream))
+}
+
+/// Removes line breaks, so values can't add headers
+fn header_value(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+/// Encodes a non-ASCII subject as RFC 2047 encoded word
+fn encode_subject(subject: &str) -> String {
+    let subject = header_value(subject);
+    match subject.is_ascii() {
+        true => subject,
+        false => format!("=?utf-8?B?{}?=", BASE64.encode(subject)),
+    }
+}
+
+impl SmtpConfig {
+    /// Sends a plain text mail to every recipient
+    pub async fn send(&self, to: &[String], subject: &str, body: &str) -> Result<(), String> {
+        tokio::time::timeout(self.timeout, self.deliver(to, subject, body))
+            .await
+            .map_err(|_| String::from("Timed out sending the mail"))?
+    }
+
+    async fn connect(&self) -> Result<Session<Box<dyn Stream>>, String> {
+        let tcp = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .map_err(|e| format!("Couldn't connect to {}:{}: {e}", self.host, self.port))?;
+
+        let stream: Box<dyn Stream> = match self.security {
+            SmtpSecurity::Tls => start_tls(tcp, &self.host).await?,
+            SmtpSecurity::None => Box::new(tcp),
+            SmtpSecurity::StartTls => {
+                let mut plain = Session::new(tcp);
+                plain.reply(&[220]).await?;
+                plain.ehlo().await?;
+                plain.command("STARTTLS", &[220]).await?;
+                // Nothing else is buffered, the server waits for the handshake
+                start_tls(plain.stream.into_inner(), &self.host).await?
+            }
+        };
+        let mut session = Session::new(stream);
+        // The greeting was already read before STARTTLS
+        if self.security != SmtpSecurity::StartTls {
+            session.reply(&[220]).await?;
+        }
+        session.ehlo().await?;
+        Ok(session)
+    }
+
+    async fn deliver(&self, to: &[String], subject: &str, body: &str) -> Result<(), String> {
+        let mut session = self.connect().await?;
+
+        if let Some(username) = &self.username {
+            let password = self.password.as_deref().unwrap_or_default();
+            let credentials = BASE64.encode(format!("\0{username}\0{password}"));
+            session
+                .command(&format!("AUTH PLAIN {credentials}"), &[235])
+                .await?;
+        }
+
+        session
+            .command(&format!("MAIL FROM:<{}>", header_value(&self.from)), &[250])
+            .await?;
+        for recipient in to {
+            session
+                .command(
+                    &format!("RCPT TO:<{}>", header_value(recipient)),
+                    &[250, 251],
+                )
+                .await?;
+        }
+        session.command("DATA", &[354]).await?;
+
+        let date = OffsetDateTime::now_utc()
+            .format(&Rfc2822)
+            .map_err(|e| e.to_string())?;
+        let mut message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {date}\r\nMIME-Version: 1.0\r\n\
+            Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+            header_value(&self.from),
+            header_value(&to.join(", ")),
+            encode_subject(subject),
+        );
+        for line in body.lines() {
+            // Lines starting with a dot would otherwise end the message early
+            if line.starts_with('.') {
+                message.push('.');
+            }
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+        message.push('.');
+        session.command(&message, &[250]).await?;
+
+        // The mail is accepted, a failing QUIT doesn't matter
+        let _ = session.command("QUIT", &[221]).await;
+        Ok(())
+    }
+}
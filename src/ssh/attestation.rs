@@ -0,0 +1,403 @@
+use ring::signature::{self, UnparsedPublicKey, VerificationAlgorithm};
+use sha2::{Digest, Sha256};
+use ssh_key::{public::KeyData, PublicKey};
+use x509_parser::{
+    oid_registry::{OID_EC_P256, OID_KEY_TYPE_EC_PUBLIC_KEY, OID_SIG_ED25519},
+    prelude::{FromDer, X509Certificate},
+    x509::SubjectPublicKeyInfo,
+};
+
+/// Written by `ssh-keygen -O write-attestation=<file>` since OpenSSH 8.4
+const ATTESTATION_V01: &[u8] = b"ssh-sk-attest-v01";
+
+/// Authenticator data flag: the data includes the attested credential
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+/// COSE key parameters, RFC 9053
+const COSE_KTY: i64 = 1;
+const COSE_CRV: i64 = -1;
+const COSE_X: i64 = -2;
+const COSE_Y: i64 = -3;
+const COSE_KTY_OKP: i64 = 1;
+const COSE_KTY_EC2: i64 = 2;
+const COSE_CRV_P256: i64 = 1;
+const COSE_CRV_ED25519: i64 = 6;
+
+/// Reads an SSH `string`: a big-endian u32 length, then the bytes
+fn read_string<'a>(data: &mut &'a [u8]) -> Result<&'a [u8], String> {
+    let truncated = || String::from("The attestation is truncated");
+    let (len, rest) = data.split_first_chunk::<4>().ok_or_else(truncated)?;
+    let len = u32::from_be_bytes(*len) as usize;
+    if rest.len() < len {
+        return Err(truncated());
+    }
+    let (string, rest) = rest.split_at(len);
+    *data = rest;
+    Ok(string)
+}
+
+/// Reads the head of a CBOR item: its major type and argument
+fn cbor_head(data: &mut &[u8]) -> Result<(u8, u64), String> {
+    let truncated = || String::from("The authenticator data is truncated");
+    let (&first, rest) = data.split_first().ok_or_else(truncated)?;
+    let len = match first & 0x1f {
+        info @ 0..=23 => {
+            *data = rest;
+            return Ok((first >> 5, u64::from(info)));
+        }
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        _ => return Err(String::from("Unsupported CBOR in the authenticator data")),
+    };
+    if rest.len() < len {
+        return Err(truncated());
+    }
+    let (argument, rest) = rest.split_at(len);
+    *data = rest;
+    Ok((
+        first >> 5,
+        argument
+            .iter()
+            .fold(0, |value, byte| value << 8 | u64::from(*byte)),
+    ))
+}
+
+fn cbor_bytes<'a>(data: &mut &'a [u8]) -> Result<&'a [u8], String> {
+    match cbor_head(data)? {
+        (2, len) if len as usize <= data.len() => {
+            let (bytes, rest) = data.split_at(len as usize);
+            *data = rest;
+            Ok(bytes)
+        }
+        (2, _) => Err(String::from("The authenticator data is truncated")),
+        _ => Err(String::from("Expected a CBOR byte string")),
+    }
+}
+
+/// A value of a COSE key, which only holds integers and byte strings
+#[derive(Debug, PartialEq, Eq)]
+enum CoseValue<'a> {
+    Int(i64),
+    Bytes(&'a [u8]),
+}
+
+fn cbor_int(major: u8, argument: u64) -> Result<i64, String> {
+    let value = i64::try_from(argument).map_err(|_| String::from("CBOR integer out of range"))?;
+    match major {
+        0 => Ok(value),
+        1 => Ok(-1 - value),
+        _ => Err(String::from("Expected a CBOR integer")),
+    }
+}
+
+fn cose_key<'a>(data: &mut &'a [u8]) -> Result<Vec<(i64, CoseValue<'a>)>, String> {
+    let (5, pairs) = cbor_head(data)? else {
+        return Err(String::from("The credential public key isn't a COSE key"));
+    };
+    (0..pairs)
+        .map(|_| {
+            let (major, argument) = cbor_head(data)?;
+            let label = cbor_int(major, argument)?;
+            let value = match data.first().map(|byte| byte >> 5) {
+                Some(2) => CoseValue::Bytes(cbor_bytes(data)?),
+                _ => {
+                    let (major, argument) = cbor_head(data)?;
+                    CoseValue::Int(cbor_int(major, argument)?)
+                }
+            };
+            Ok((label, value))
+        })
+        .collect()
+}
+
+fn cose_param<'a, 'b>(key: &'b [(i64, CoseValue<'a>)], label: i64) -> Option<&'b CoseValue<'a>> {
+    key.iter()
+        .find(|(l, _)| *l == label)
+        .map(|(_, value)| value)
+}
+
+/// The parts of the authenticator data that tie it to an SSH key
+struct AuthenticatorData<'a> {
+    rp_id_hash: &'a [u8],
+    credential_key: Vec<(i64, CoseValue<'a>)>,
+}
+
+impl<'a> AuthenticatorData<'a> {
+    /// Layout from the WebAuthn spec: RP id hash, flags, counter, then the attested credential:
+    /// AAGUID, credential id and the COSE public key
+    fn parse(data: &'a [u8]) -> Result<Self, String> {
+        let truncated = || String::from("The authenticator data is truncated");
+        if data.len() < 37 + 18 {
+            return Err(truncated());
+        }
+        let (rp_id_hash, rest) = data.split_at(32);
+        if rest[0] & FLAG_ATTESTED_CREDENTIAL == 0 {
+            return Err(String::from(
+                "The authenticator data doesn't include the credential",
+            ));
+        }
+        // Flags, counter and AAGUID
+        let rest = &rest[1 + 4 + 16..];
+        let id_len = usize::from(u16::from_be_bytes([rest[0], rest[1]]));
+        let mut rest = rest.get(2 + id_len..).ok_or_else(truncated)?;
+        Ok(Self {
+            rp_id_hash,
+            credential_key: cose_key(&mut rest)?,
+        })
+    }
+
+    /// Whether the attested credential is the SSH key
+    fn check_key(&self, key: &PublicKey) -> Result<(), String> {
+        let (application, kty, crv, point) = match key.key_data() {
+            KeyData::SkEcdsaSha2NistP256(sk) => (
+                sk.application(),
+                COSE_KTY_EC2,
+                COSE_CRV_P256,
+                sk.ec_point().as_bytes().to_vec(),
+            ),
+            KeyData::SkEd25519(sk) => (
+                sk.application(),
+                COSE_KTY_OKP,
+                COSE_CRV_ED25519,
+                sk.public_key().as_ref().to_vec(),
+            ),
+            _ => return Err(String::from("Only sk- keys can have an attestation")),
+        };
+        if self.rp_id_hash != Sha256::digest(application.as_bytes()).as_slice() {
+            return Err(format!(
+                "The attestation is for another application than '{application}'"
+            ));
+        }
+
+        let key = &self.credential_key;
+        if cose_param(key, COSE_KTY) != Some(&CoseValue::Int(kty))
+            || cose_param(key, COSE_CRV) != Some(&CoseValue::Int(crv))
+        {
+            return Err(String::from("The attested credential has another key type"));
+        }
+        let attested = match (cose_param(key, COSE_X), cose_param(key, COSE_Y)) {
+            (Some(CoseValue::Bytes(x)), Some(CoseValue::Bytes(y))) if kty == COSE_KTY_EC2 => {
+                [&[0x04][..], x, y].concat()
+            }
+            (Some(CoseValue::Bytes(x)), None) if kty == COSE_KTY_OKP => x.to_vec(),
+            _ => return Err(String::from("The attested credential key is incomplete")),
+        };
+        if attested != point {
+            return Err(String::from("The attestation is for another key"));
+        }
+        Ok(())
+    }
+}
+
+/// The algorithm the attestation certificate signs with
+fn verification_algorithm(
+    key: &SubjectPublicKeyInfo,
+) -> Result<&'static dyn VerificationAlgorithm, String> {
+    let algorithm = &key.algorithm;
+    let curve = algorithm
+        .parameters
+        .as_ref()
+        .and_then(|parameters| parameters.as_oid().ok());
+    if algorithm.algorithm == OID_KEY_TYPE_EC_PUBLIC_KEY && curve == Some(OID_EC_P256) {
+        Ok(&signature::ECDSA_P256_SHA256_ASN1)
+    } else if algorithm.algorithm == OID_SIG_ED25519 {
+        Ok(&signature::ED25519)
+    } else {
+        Err(String::from(
+            "The attestation certificate uses an unsupported key type",
+        ))
+    }
+}
+
+/// FIDO attestation of a hardware-backed key, as written by `ssh-keygen -t ed25519-sk
+/// -O challenge=<file> -O write-attestation=<file>`
+pub struct Attestation {
+    /// DER encoded attestation certificate of the authenticator
+    certificate: Vec<u8>,
+    /// Over the authenticator data and the hash of the challenge
+    signature: Vec<u8>,
+    auth_data: Vec<u8>,
+}
+
+impl Attestation {
+    pub fn parse(mut data: &[u8]) -> Result<Self, String> {
+        if read_string(&mut data)? != ATTESTATION_V01 {
+            return Err(String::from(
+                "Unsupported attestation format, expected ssh-sk-attest-v01",
+            ));
+        }
+        let certificate = read_string(&mut data)?.to_vec();
+        let signature = read_string(&mut data)?.to_vec();
+        // CBOR encoded byte string
+        let mut auth_data = read_string(&mut data)?;
+        let auth_data = cbor_bytes(&mut auth_data)?.to_vec();
+        Ok(Self {
+            certificate,
+            signature,
+            auth_data,
+        })
+    }
+
+    /// Checks that the authenticator holding `key` signed the `challenge` when it created the key
+    /// and that its attestation certificate is one of the `roots` or issued by one of them.
+    /// Returns the subject of the attestation certificate.
+    pub fn verify(
+        &self,
+        key: &PublicKey,
+        challenge: &[u8],
+        roots: &[Vec<u8>],
+    ) -> Result<String, String> {
+        let (_, certificate) = X509Certificate::from_der(&self.certificate)
+            .map_err(|e| format!("Invalid attestation certificate: {e}"))?;
+        let trusted = roots.iter().any(|root| {
+            *root == self.certificate
+                || X509Certificate::from_der(root).is_ok_and(|(_, root)| {
+                    certificate.issuer() == root.subject()
+                        && certificate
+                            .verify_signature(Some(root.public_key()))
+                            .is_ok()
+                })
+        });
+        if !trusted {
+            return Err(format!(
+                "The attestation certificate '{}' isn't issued by a trusted root",
+                certificate.subject()
+            ));
+        }
+        if !certificate.validity().is_valid() {
+            return Err(String::from("The attestation certificate has expired"));
+        }
+
+        let auth_data = AuthenticatorData::parse(&self.auth_data)?;
+        auth_data.check_key(key)?;
+
+        let public_key = certificate.public_key();
+        let public_key = UnparsedPublicKey::new(
+            verification_algorithm(public_key)?,
+            &public_key.subject_public_key.data,
+        );
+        // OpenSSH 8.9 and later hash the challenge, earlier versions pass 32 bytes unhashed
+        let hashed = [&self.auth_data[..], &Sha256::digest(challenge)].concat();
+        let signed = public_key.verify(&hashed, &self.signature).is_ok()
+            || (challenge.len() == 32
+                && public_key
+                    .verify(&[&self.auth_data[..], challenge].concat(), &self.signature)
+                    .is_ok());
+        if !signed {
+            return Err(String::from(
+                "The attestation signature doesn't match the challenge",
+            ));
+        }
+        Ok(certificate.subject().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+    use super::*;
+
+    // Made with a test root and attestation certificate for the challenge below
+    const CHALLENGE: &[u8] = b"ssm test challenge";
+    const ROOT: &str = concat!(
+        "MIIBTjCB9aADAgECAhRInSGgd+sfhFQmePe7Yhl5HCF+KTAKBggqhkjOPQQDAjAcMRowGAYDVQQD",
+        "DBFUZXN0IEZJRE8gUm9vdCBDQTAgFw0yMDAxMDEwMDAwMDBaGA8yMDUwMDEwMTAwMDAwMFowHDEa",
+        "MBgGA1UEAwwRVGVzdCBGSURPIFJvb3QgQ0EwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAAT31Mvd",
+        "hkbX2rZEAYXsQlC7tebR24tVaV4dKsl2zhB8mn2AGgyBtGMXpY2PZqKwPctSybKZeq0HeBdPlo+/",
+        "COhZoxMwETAPBgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0gAMEUCIQCfwffMb8SHO0FKs5XK",
+        "xuxBtMLUkj9PvMAo5TkXB4fKzgIgNXSPtPnD0iGPw/+OVdZlzmAciY2oXhhughcSgs3Tzjw=",
+    );
+
+    const ECDSA_KEY: &str = concat!(
+        "sk-ecdsa-sha2-nistp256@openssh.com AAAAInNrLWVjZHNhLXNoYTItbmlzdHAyNTZAb3Blb",
+        "nNzaC5jb20AAAAIbmlzdHAyNTYAAABBBJH5c3vu/gS0MSsDyUkR/A0hpU6Nq+iTDM7W+OcY2xkkj",
+        "66XIQCZD/cV4PTVilawm2m43DenX4pSDwPaTgHZkLMAAAAEc3NoOg== test@token",
+    );
+
+    const ECDSA_ATTESTATION: &str = concat!(
+        "AAAAEXNzaC1zay1hdHRlc3QtdjAxAAABXDCCAVgwgf+gAwIBAgIUP93bNb/WboSCYch+zDBSZugS",
+        "/rUwCgYIKoZIzj0EAwIwHDEaMBgGA1UEAwwRVGVzdCBGSURPIFJvb3QgQ0EwIBcNMjAwMTAxMDAw",
+        "MDAwWhgPMjA1MDAxMDEwMDAwMDBaMCkxJzAlBgNVBAMMHlRlc3QgQXV0aGVudGljYXRvciBBdHRl",
+        "c3RhdGlvbjBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABCOVEmRSbv7R2kmzZxxd1lc0KhWTyx7E",
+        "DQta6gfvR4Vjl5HsODuqJ6UW6CZ1JSmrJYH4zS0+I2boI8p4Y56tZLejEDAOMAwGA1UdEwEB/wQC",
+        "MAAwCgYIKoZIzj0EAwIDSAAwRQIgZ/dOTgtDTZ1uomqoupvTQ0fSgImX6jXBwdoyEskzubECIQDH",
+        "jUtXXRvBrVfRoVqmj2f4MAiqJFxfEFr2R/mOEMoUvAAAAEgwRgIhAJtlT9J6dt7XUSLxs6sE1UQH",
+        "TeHkaMx738K28TKc+aGPAiEA/EF9m/mSblK6jeQnsHHb0eX1Q08B9ijHRASUWP3f0lIAAACWWJTj",
+        "BhDooWIRWWD+HsIj5lKcn0tugCANy15cMhyK8eKxv0EAAAAAAAECAwQFBgcICQoLDA0ODwAQpKKw",
+        "0ZzS72hRycnY0xBRNKUBAgMmIAEhWCCR+XN77v4EtDErA8lJEfwNIaVOjavokwzO1vjnGNsZJCJY",
+        "II+ulyEAmQ/3FeD01YpWsJtpuNw3p1+KUg8D2k4B2ZCzAAAAAAAAAAA=",
+    );
+
+    const ED25519_KEY: &str = concat!(
+        "sk-ssh-ed25519@openssh.com AAAAGnNrLXNzaC1lZDI1NTE5QG9wZW5zc2guY29tAAAAIGFpo",
+        "7eMIayZfwGaknrBbwWYBGyr+ByBWmH+eaJHtCpCAAAABHNzaDo= test@token",
+    );
+
+    const ED25519_ATTESTATION: &str = concat!(
+        "AAAAEXNzaC1zay1hdHRlc3QtdjAxAAABXDCCAVgwgf+gAwIBAgIUP93bNb/WboSCYch+zDBSZugS",
+        "/rUwCgYIKoZIzj0EAwIwHDEaMBgGA1UEAwwRVGVzdCBGSURPIFJvb3QgQ0EwIBcNMjAwMTAxMDAw",
+        "MDAwWhgPMjA1MDAxMDEwMDAwMDBaMCkxJzAlBgNVBAMMHlRlc3QgQXV0aGVudGljYXRvciBBdHRl",
+        "c3RhdGlvbjBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABCOVEmRSbv7R2kmzZxxd1lc0KhWTyx7E",
+        "DQta6gfvR4Vjl5HsODuqJ6UW6CZ1JSmrJYH4zS0+I2boI8p4Y56tZLejEDAOMAwGA1UdEwEB/wQC",
+        "MAAwCgYIKoZIzj0EAwIDSAAwRQIgZ/dOTgtDTZ1uomqoupvTQ0fSgImX6jXBwdoyEskzubECIQDH",
+        "jUtXXRvBrVfRoVqmj2f4MAiqJFxfEFr2R/mOEMoUvAAAAEYwRAIgCE4cz7vSLG01d7bEZw4I/Whn",
+        "TAhP5IUtNQjtHhLCQFICIGCBNCMUM/plExn6rwbYiGD6VliljEkKvDCbwNDVMRcRAAAAc1hx4wYQ",
+        "6KFiEVlg/h7CI+ZSnJ9LboAgDcteXDIcivHisb9BAAAAAAABAgMEBQYHCAkKCwwNDg8AEEYS+7RL",
+        "jZ1BpLnSnOCpYrikAQEDJyAGIVggYWmjt4whrJl/AZqSesFvBZgEbKv4HIFaYf55oke0KkIAAAAA",
+        "AAAAAA==",
+    );
+
+    fn roots() -> Vec<Vec<u8>> {
+        vec![BASE64.decode(ROOT).unwrap()]
+    }
+
+    fn attestation(data: &str) -> Attestation {
+        Attestation::parse(&BASE64.decode(data).unwrap()).unwrap()
+    }
+
+    fn key(line: &str) -> PublicKey {
+        PublicKey::from_openssh(line).unwrap()
+    }
+
+    #[test]
+    fn verifies_attested_keys() {
+        assert_eq!(
+            attestation(ECDSA_ATTESTATION).verify(&key(ECDSA_KEY), CHALLENGE, &roots()),
+            Ok(String::from("CN=Test Authenticator Attestation"))
+        );
+        assert!(attestation(ED25519_ATTESTATION)
+            .verify(&key(ED25519_KEY), CHALLENGE, &roots())
+            .is_ok());
+    }
+
+    #[test]
+    fn rejects_other_challenges() {
+        assert!(attestation(ECDSA_ATTESTATION)
+            .verify(&key(ECDSA_KEY), b"another challenge", &roots())
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_other_keys() {
+        assert_eq!(
+            attestation(ECDSA_ATTESTATION).verify(&key(ED25519_KEY), CHALLENGE, &roots()),
+            Err(String::from("The attested credential has another key type"))
+        );
+    }
+
+    #[test]
+    fn rejects_untrusted_certificates() {
+        assert!(attestation(ECDSA_ATTESTATION)
+            .verify(&key(ECDSA_KEY), CHALLENGE, &[])
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        assert!(Attestation::parse(b"\0\0\0\x11ssh-sk-attest-v00").is_err());
+        assert!(Attestation::parse(b"\0\0\0\x20ssh").is_err());
+    }
+}
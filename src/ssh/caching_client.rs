@@ -1,12 +1,24 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use diesel::r2d2::{ConnectionManager, PooledConnection};
+use futures::{stream, StreamExt};
+use log::{error, info};
 use time::OffsetDateTime;
-use tokio::sync::RwLock;
+use tokio::{sync::RwLock, time::MissedTickBehavior};
 
 use crate::{
+    db::BlockingPool,
+    health::HealthMonitor,
+    metrics::FleetMetrics,
     models::{Host, PublicUserKey},
-    ConnectionPool, DbConnection,
+    notifications::Notifier,
+    shutdown::{self, Operation},
 };
 
 use super::{
@@ -14,35 +26,174 @@ use super::{
     HostDiff, HostName, Login, SshClient,
 };
 
+/// How many hosts the background sync connects to at the same time
+const SYNC_CONCURRENCY: usize = 8;
+
+/// Entries of all authorized_keys files of a host
+fn count_entries(authorized_keys: &AuthorizedKeys) -> Option<usize> {
+    authorized_keys
+        .as_ref()
+        .ok()
+        .map(|logins| logins.iter().map(|(_, _, entries)| entries.len()).sum())
+}
+
+fn has_drift(diff: &Result<Vec<(Login, Vec<DiffItem>)>, SshClientError>) -> bool {
+    diff.as_ref().is_ok_and(|logins| !logins.is_empty())
+}
+
 #[derive(Debug)]
 pub struct CachingSshClient {
-    conn: ConnectionPool,
+    db: BlockingPool,
     ssh_client: SshClient,
     cache: RwLock<Cache>,
+    /// Whether the background sync keeps the cache filled
+    syncing: AtomicBool,
+    /// Slows down syncs and checks of all hosts while the system is overloaded
+    health: Option<HealthMonitor>,
+    /// Mails changed host keys and unexpected keys
+    notifier: Notifier,
+    /// Diff durations and results for `/metrics`
+    metrics: FleetMetrics,
 }
 
 impl CachingSshClient {
-    pub fn new(conn: ConnectionPool, ssh_client: SshClient) -> Self {
+    pub fn new(db: BlockingPool, ssh_client: SshClient) -> Self {
         Self {
-            conn,
+            db,
             ssh_client,
             cache: RwLock::new(HashMap::new()),
+            syncing: AtomicBool::new(false),
+            health: None,
+            notifier: Notifier::default(),
+            metrics: FleetMetrics::default(),
+        }
+    }
+
+    pub fn with_health(mut self, health: HealthMonitor) -> Self {
+        self.health = Some(health);
+        self
+    }
+
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = notifier;
+        self
+    }
+
+    pub const fn fleet_metrics(&self) -> &FleetMetrics {
+        &self.metrics
+    }
+
+    /// Waits as long as the health monitor asks before the next host of a fleet operation
+    async fn throttle(&self) {
+        if let Some(health) = &self.health {
+            health.throttle().await;
+        }
+    }
+
+    /// Refreshes the cache of all hosts now and then every `interval`
+    pub fn start_sync(self: &Arc<Self>, interval: Duration) {
+        self.syncing.store(true, Ordering::Relaxed);
+        let this = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            // A slow sync shouldn't be followed by a burst of catch-up runs
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                this.sync_all().await;
+            }
+        });
+    }
+
+    pub fn is_syncing(&self) -> bool {
+        self.syncing.load(Ordering::Relaxed)
+    }
+
+    /// Critical hosts are synced first
+    async fn sync_all(&self) {
+        let hosts = match self
+            .db
+            .run(Host::get_all_by_priority)
+            .await
+            .map_err(String::from)
+            .and_then(|res| res)
+        {
+            Ok(hosts) => hosts,
+            Err(e) => {
+                error!("Failed to sync hosts: {e}");
+                return;
+            }
+        };
+
+        let _operation = Operation::begin("sync");
+        let total = hosts.len();
+        let reachable = stream::iter(hosts)
+            .take_while(|_| std::future::ready(!shutdown::is_stopping()))
+            .map(|host| async move {
+                self.throttle().await;
+                matches!(self.get_entry(&host.name, true).await, Ok((_, Ok(_))))
+            })
+            .buffer_unordered(SYNC_CONCURRENCY)
+            .filter(|reachable| std::future::ready(*reachable))
+            .count()
+            .await;
+        info!("Synced {total} hosts, {} unreachable", total - reachable);
+    }
+
+    /// Logins whose authorized_keys contained this key (base64) when last read, by host name
+    pub async fn find_key(&self, key: &str) -> Vec<(HostName, Login)> {
+        let cache = self.cache.read().await;
+        let mut found = Vec::new();
+        for (host_name, (_, logins)) in cache.iter() {
+            let Ok(logins) = logins else {
+                continue;
+            };
+            for (login, _, entries) in logins {
+                if entries
+                    .iter()
+                    .any(|entry| entry.as_ref().is_ok_and(|entry| entry.base64 == key))
+                {
+                    found.push((host_name.clone(), login.clone()));
+                }
+            }
         }
+        found
+    }
+
+    /// Keys (base64) in the authorized_keys of each login when the host was last read,
+    /// without connecting to it. None if it wasn't read successfully yet.
+    pub async fn get_cached_keys(&self, host_name: &str) -> Option<HashMap<Login, Vec<String>>> {
+        let cache = self.cache.read().await;
+        let Ok(logins) = &cache.get(host_name)?.1 else {
+            return None;
+        };
+        Some(
+            logins
+                .iter()
+                .map(|(login, _, entries)| {
+                    let keys = entries
+                        .iter()
+                        .filter_map(|entry| entry.as_ref().ok())
+                        .map(|entry| entry.base64.clone())
+                        .collect();
+                    (login.clone(), keys)
+                })
+                .collect(),
+        )
     }
 
     /// Removes a cache entry entirely. This should only be used when the underlying host no longer exists.
     pub async fn remove(&self, host_name: &str) {
         let mut lock = self.cache.write().await;
         let _ = lock.remove(host_name);
+        self.metrics.remove(host_name);
     }
 
     async fn get_current_host_data(
         &self,
         host_name: &str,
     ) -> Result<AuthorizedKeys, SshClientError> {
-        let conn = self.conn.get().unwrap();
-
-        match Host::get_from_name(conn, host_name.to_owned()).await? {
+        match Host::get_from_name(&self.db, host_name.to_owned()).await? {
             Some(host) => Ok(self.ssh_client.clone().get_authorized_keys(host).await),
             None => Err(SshClientError::NoSuchHost),
         }
@@ -68,18 +219,20 @@ impl CachingSshClient {
         Ok(lock.get(host_name).expect("We just inserted this").clone())
     }
 
-    fn calculate_diff(
+    async fn calculate_diff(
         &self,
-        mut conn: PooledConnection<ConnectionManager<DbConnection>>,
         host_entries: Vec<(Login, bool, Vec<AuthorizedKeyEntry>)>,
         host: &Host,
     ) -> Result<Vec<(Login, Vec<DiffItem>)>, SshClientError> {
-        let db_authorized_entries = host.get_authorized_keys(&mut conn)?;
-
-        let mut conn = self.conn.get().unwrap();
-        let all_user_keys = PublicUserKey::get_all_keys_with_username(&mut conn)?;
-
-        let own_key_base64 = self.ssh_client.get_own_key_b64();
+        let db_host = host.clone();
+        let (db_authorized_entries, all_user_keys) = self
+            .db
+            .run(move |conn| {
+                db_host.get_authorized_keys(conn).and_then(|entries| {
+                    PublicUserKey::get_all_keys_with_username(conn).map(|keys| (entries, keys))
+                })
+            })
+            .await??;
 
         let mut diff_items = Vec::new();
         let mut used_indecies = Vec::new();
@@ -98,8 +251,12 @@ impl CachingSshClient {
                         continue 'entries;
                     }
                 };
-                // Check if this is the key-manager key
-                if host_entry.base64.eq(&own_key_base64) {
+                if host_entry.certificate.is_some() {
+                    this_user_diff.push(DiffItem::Certificate(host_entry));
+                    continue 'entries;
+                }
+                // Check if this is the key-manager key or a break-glass key
+                if self.ssh_client.is_protected_key(&host_entry.base64) {
                     // TODO: also check if options are set correct
                     continue 'entries;
                 }
@@ -118,8 +275,10 @@ impl CachingSshClient {
 
                 for (username, key) in &all_user_keys {
                     if host_entry.base64.eq(&key.key_base64) {
-                        this_user_diff
-                            .push(DiffItem::UnauthorizedKey(host_entry, username.clone()));
+                        this_user_diff.push(match key.is_expired() {
+                            true => DiffItem::ExpiredKey(host_entry, username.clone()),
+                            false => DiffItem::UnauthorizedKey(host_entry, username.clone()),
+                        });
                         continue 'entries;
                     }
                 }
@@ -143,6 +302,7 @@ impl CachingSshClient {
 
     /// Get the difference between the supposed and actual state of the authorized keys
     pub async fn get_host_diff(&self, host: Host, force_update: bool) -> HostDiff {
+        let started = Instant::now();
         let (inserted, cached_authorized_keys) =
             match self.get_entry(&host.name, force_update).await {
                 Ok(t) => t,
@@ -151,28 +311,47 @@ impl CachingSshClient {
                 }
             };
 
-        let host_authorized_entries = match cached_authorized_keys {
-            Ok(authorized_entries) => authorized_entries,
-            Err(e) => {
-                return (inserted, Err(e));
-            }
+        let authorized_keys = count_entries(&cached_authorized_keys);
+        let diff = match cached_authorized_keys {
+            Ok(authorized_entries) => self.calculate_diff(authorized_entries, &host).await,
+            Err(e) => Err(e),
         };
+        self.notifier.host_diff(&host, &diff);
+        self.metrics.record_diff(
+            &host.name,
+            force_update.then_some(started),
+            has_drift(&diff),
+            authorized_keys,
+        );
+        (inserted, diff)
+    }
 
-        let conn = self.conn.get().unwrap();
+    /// The difference of a host from the cache, without connecting to it
+    pub async fn get_cached_host_diff(&self, host: Host) -> Option<HostDiff> {
+        let (checked, cached_authorized_keys) = self.cache.read().await.get(&host.name).cloned()?;
 
-        (
-            inserted,
-            self.calculate_diff(conn, host_authorized_entries, &host),
-        )
+        let authorized_keys = count_entries(&cached_authorized_keys);
+        let diff = match cached_authorized_keys {
+            Ok(entries) => self.calculate_diff(entries, &host).await,
+            Err(e) => Err(e),
+        };
+        self.metrics
+            .record_diff(&host.name, None, has_drift(&diff), authorized_keys);
+        Some((checked, diff))
     }
 
-    /// Gets the current state of all known hosts, forcing an update
+    /// Gets the current state of all known hosts, critical ones first, forcing an update
     pub async fn get_current_state(&self) -> Result<Vec<(HostName, HostDiff)>, String> {
-        let hosts = Host::get_all_hosts(&mut self.conn.get().unwrap())?;
+        let hosts = self.db.run(Host::get_all_by_priority).await??;
 
+        let _operation = Operation::begin("check of all hosts");
         let mut state = Vec::with_capacity(hosts.len());
 
         for host in hosts.into_iter() {
+            if shutdown::is_stopping() {
+                return Err(String::from("Shutting down"));
+            }
+            self.throttle().await;
             let hostname = host.name.to_owned();
             let res = self.get_host_diff(host, true).await;
             state.push((hostname, res));
@@ -181,6 +360,33 @@ impl CachingSshClient {
         Ok(state)
     }
 
+    /// Logins on the host with keys that ssm neither wrote nor has authorizations for
+    pub async fn get_unmanaged_logins(
+        &self,
+        host: Host,
+        force_update: bool,
+    ) -> Result<Vec<Login>, SshClientError> {
+        let entries = self.get_entry(&host.name, force_update).await?.1?;
+        let db_host = host.clone();
+        let authorized = self
+            .db
+            .run(move |conn| db_host.get_authorized_users(conn))
+            .await??;
+
+        Ok(entries
+            .into_iter()
+            .filter(|(login, has_pragma, entries)| {
+                !has_pragma
+                    && !entries.is_empty()
+                    && *login != host.username
+                    && !authorized
+                        .iter()
+                        .any(|(_, _, authorized, _)| authorized == login)
+            })
+            .map(|(login, _, _)| login)
+            .collect())
+    }
+
     pub async fn get_logins(
         &self,
         host: Host,
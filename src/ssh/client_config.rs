@@ -0,0 +1,259 @@
+/// A host of an OpenSSH client configuration (`~/.ssh/config`), with the values
+/// that apply to it from every matching `Host` block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshConfigHost {
+    /// Line of the first `Host` block naming it
+    pub line: usize,
+    pub alias: String,
+    /// The alias unless `HostName` is set
+    pub hostname: String,
+    pub port: Option<String>,
+    pub user: Option<String>,
+    pub proxy_jump: Option<String>,
+}
+
+struct Block {
+    /// `None` for `Match` blocks, whose criteria aren't evaluated
+    patterns: Option<Vec<String>>,
+    options: Vec<(String, String)>,
+}
+
+/// Splits `Keyword value`, `Keyword=value` and `Keyword = value`
+fn split_directive(line: &str) -> Option<(String, &str)> {
+    let end = line.find(|c: char| c.is_whitespace() || c == '=')?;
+    let (keyword, rest) = line.split_at(end);
+    let rest = rest.trim_start();
+    let rest = rest.strip_prefix('=').unwrap_or(rest).trim();
+    Some((keyword.to_lowercase(), rest))
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+/// Matches `*` and `?` wildcards
+fn glob_matches(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            glob_matches(rest, name) || (!name.is_empty() && glob_matches(pattern, &name[1..]))
+        }
+        (Some((b'?', rest)), Some((_, name_rest))) => glob_matches(rest, name_rest),
+        (Some((p, rest)), Some((n, name_rest))) => {
+            p.eq_ignore_ascii_case(n) && glob_matches(rest, name_rest)
+        }
+        _ => false,
+    }
+}
+
+/// Like ssh: one of the patterns has to match and none of the negated ones
+pub(super) fn patterns_match(patterns: &[String], alias: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns {
+        match pattern.strip_prefix('!') {
+            Some(negated) if glob_matches(negated.as_bytes(), alias.as_bytes()) => return false,
+            Some(_) => {}
+            None => matched |= glob_matches(pattern.as_bytes(), alias.as_bytes()),
+        }
+    }
+    matched
+}
+
+fn is_concrete(pattern: &str) -> bool {
+    !pattern.contains(['*', '?', '!'])
+}
+
+/// Every host named without wildcards in a `Host` line. Like ssh, the first value
+/// for each keyword wins, so defaults in `Host *` at the end apply to every host.
+/// `Match` blocks and `Include` directives are ignored.
+pub fn parse_ssh_config(config: &str) -> Vec<SshConfigHost> {
+    // Directives before the first block apply to every host
+    let mut blocks = vec![Block {
+        patterns: Some(vec![String::from("*")]),
+        options: Vec::new(),
+    }];
+    let mut aliases: Vec<(usize, String)> = Vec::new();
+
+    for (index, line) in config.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((keyword, value)) = split_directive(line) else {
+            continue;
+        };
+        match keyword.as_str() {
+            "host" => {
+                let patterns: Vec<String> = value
+                    .split_whitespace()
+                    .map(|pattern| unquote(pattern).to_owned())
+                    .collect();
+                for pattern in patterns.iter().filter(|p| is_concrete(p)) {
+                    if !aliases.iter().any(|(_, alias)| alias == pattern) {
+                        aliases.push((index + 1, pattern.clone()));
+                    }
+                }
+                blocks.push(Block {
+                    patterns: Some(patterns),
+                    options: Vec::new(),
+                });
+            }
+            "match" => blocks.push(Block {
+                patterns: None,
+                options: Vec::new(),
+            }),
+            _ => {
+                if let Some(block) = blocks.last_mut() {
+                    block.options.push((keyword, unquote(value).to_owned()));
+                }
+            }
+        }
+    }
+
+    aliases
+        .into_iter()
+        .map(|(line, alias)| {
+            let mut hostname = None;
+            let mut port = None;
+            let mut user = None;
+            let mut proxy_jump = None;
+            let matching = blocks.iter().filter(|block| {
+                block
+                    .patterns
+                    .as_ref()
+                    .is_some_and(|patterns| patterns_match(patterns, &alias))
+            });
+            for (keyword, value) in matching.flat_map(|block| &block.options) {
+                let slot = match keyword.as_str() {
+                    "hostname" => &mut hostname,
+                    "port" => &mut port,
+                    "user" => &mut user,
+                    "proxyjump" => &mut proxy_jump,
+                    _ => continue,
+                };
+                if slot.is_none() {
+                    *slot = Some(value.clone());
+                }
+            }
+            let hostname = hostname
+                .map(|hostname| hostname.replace("%h", &alias).replace("%%", "%"))
+                .unwrap_or_else(|| alias.clone());
+            SshConfigHost {
+                line,
+                hostname,
+                port,
+                user,
+                proxy_jump,
+                alias,
+            }
+        })
+        .collect()
+}
+
+impl SshConfigHost {
+    /// The alias of the host to jump through. Only one hop is supported, as a jumphost
+    /// has its own jumphost rather than a chain.
+    pub fn jumphost(&self) -> Result<Option<String>, String> {
+        let Some(proxy_jump) = &self.proxy_jump else {
+            return Ok(None);
+        };
+        if proxy_jump.eq_ignore_ascii_case("none") {
+            return Ok(None);
+        }
+        if proxy_jump.contains(',') {
+            return Err(format!(
+                "ProxyJump {proxy_jump} has several hops, set the ProxyJump of each jumphost instead"
+            ));
+        }
+        let hop = proxy_jump
+            .strip_prefix("ssh://")
+            .unwrap_or(proxy_jump)
+            .rsplit('@')
+            .next()
+            .unwrap_or_default();
+        let host = match hop.strip_prefix('[') {
+            Some(bracketed) => bracketed.split(']').next().unwrap_or_default(),
+            None => hop.split(':').next().unwrap_or_default(),
+        };
+        Ok(Some(host.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "
+# Defaults before any block
+User deploy
+
+Host bastion
+    HostName bastion.example.com
+    User root
+
+Host web-1 web-2
+    HostName %h.internal
+    ProxyJump admin@bastion:2222
+
+Host db
+  Port=2200
+  ProxyJump none
+
+Match host db
+    User ignored
+
+Host *.example.com !bastion*
+    User nobody
+
+Host *
+    User fallback
+    Port 22
+";
+
+    #[test]
+    fn hosts() {
+        let hosts = parse_ssh_config(CONFIG);
+        let aliases: Vec<_> = hosts.iter().map(|h| h.alias.as_str()).collect();
+        assert_eq!(aliases, ["bastion", "web-1", "web-2", "db"]);
+
+        assert_eq!(hosts[0].hostname, "bastion.example.com");
+        assert_eq!(hosts[0].user.as_deref(), Some("deploy"));
+        assert_eq!(hosts[0].port.as_deref(), Some("22"));
+        assert_eq!(hosts[0].line, 5);
+
+        assert_eq!(hosts[1].hostname, "web-1.internal");
+        assert_eq!(hosts[2].hostname, "web-2.internal");
+        assert_eq!(hosts[3].hostname, "db");
+        assert_eq!(hosts[3].port.as_deref(), Some("2200"));
+    }
+
+    #[test]
+    fn jumphosts() {
+        let hosts = parse_ssh_config(CONFIG);
+        assert_eq!(hosts[0].jumphost(), Ok(None));
+        assert_eq!(hosts[1].jumphost(), Ok(Some(String::from("bastion"))));
+        assert_eq!(hosts[3].jumphost(), Ok(None));
+
+        let chained = parse_ssh_config("Host a\nProxyJump b,c");
+        assert!(chained[0].jumphost().is_err());
+        let ipv6 = parse_ssh_config("Host a\nProxyJump ssh://me@[fd00::1]:22");
+        assert_eq!(ipv6[0].jumphost(), Ok(Some(String::from("fd00::1"))));
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(patterns_match(&[String::from("web-?")], "web-1"));
+        assert!(!patterns_match(&[String::from("web-?")], "web-10"));
+        assert!(patterns_match(
+            &[String::from("*"), String::from("!db")],
+            "web"
+        ));
+        assert!(!patterns_match(
+            &[String::from("*"), String::from("!db")],
+            "db"
+        ));
+    }
+}
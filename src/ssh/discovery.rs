@@ -0,0 +1,141 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+};
+
+use crate::models::Host;
+
+/// Name parts of hosts that are usually only there to jump through
+const BASTION_NAMES: [&str; 3] = ["bastion", "jump", "gateway"];
+
+/// Name suffixes that only resolve inside a private network
+const PRIVATE_SUFFIXES: [&str; 4] = [".internal", ".local", ".lan", ".intranet"];
+
+/// Whether the address is only reachable from inside a private network: RFC 1918, shared
+/// (CGNAT), link-local and unique local addresses, or names of internal zones
+pub fn is_private_address(address: &str) -> bool {
+    match address.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            ip.is_private()
+                || ip.is_link_local()
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64)
+        }
+        Ok(IpAddr::V6(ip)) => {
+            let first = ip.segments()[0];
+            first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+        }
+        Err(_) => {
+            let name = address.trim_end_matches('.').to_lowercase();
+            PRIVATE_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+        }
+    }
+}
+
+/// The /24 an IPv4 address is in
+fn subnet(address: &str) -> Option<Ipv4Addr> {
+    let [a, b, c, _] = address.parse::<Ipv4Addr>().ok()?.octets();
+    Some(Ipv4Addr::new(a, b, c, 0))
+}
+
+fn looks_like_bastion(host: &Host) -> bool {
+    let name = host.name.to_lowercase();
+    host.jump_via.is_none()
+        && !is_private_address(&host.address)
+        && BASTION_NAMES.iter().any(|part| name.contains(part))
+}
+
+/// A jumphost a new host is probably only reachable through
+#[derive(Debug, Clone)]
+pub struct JumphostSuggestion {
+    pub jumphost: Host,
+    /// Why it was suggested, e.g. `used by 3 hosts in 10.0.1.0/24`
+    pub reason: String,
+}
+
+/// The jumphost used most by the hosts matching the filter
+fn most_used(hosts: &[Host], filter: impl Fn(&Host) -> bool) -> Option<(&Host, usize)> {
+    let mut uses: HashMap<i32, usize> = HashMap::new();
+    for via in hosts
+        .iter()
+        .filter(|host| filter(host))
+        .filter_map(|host| host.jump_via)
+    {
+        *uses.entry(via).or_default() += 1;
+    }
+    let (via, count) = uses
+        .into_iter()
+        .max_by_key(|(via, count)| (*count, std::cmp::Reverse(*via)))?;
+    hosts
+        .iter()
+        .find(|host| host.id == via)
+        .map(|jumphost| (jumphost, count))
+}
+
+/// Suggests a jumphost for a host with a private address, from the existing hosts: the one
+/// its neighbours in the same /24 use, else the one most hosts with private addresses use,
+/// else a host named like a bastion. Public addresses get no suggestion.
+pub fn suggest_jumphost(hosts: &[Host], address: &str) -> Option<JumphostSuggestion> {
+    if !is_private_address(address) {
+        return None;
+    }
+
+    if let Some(net) = subnet(address) {
+        if let Some((jumphost, count)) = most_used(hosts, |host| subnet(&host.address) == Some(net))
+        {
+            return Some(JumphostSuggestion {
+                jumphost: jumphost.clone(),
+                reason: format!("used by {count} host(s) in {net}/24"),
+            });
+        }
+    }
+    if let Some((jumphost, count)) = most_used(hosts, |host| is_private_address(&host.address)) {
+        return Some(JumphostSuggestion {
+            jumphost: jumphost.clone(),
+            reason: format!("used by {count} host(s) with private addresses"),
+        });
+    }
+    hosts
+        .iter()
+        .find(|host| looks_like_bastion(host))
+        .map(|jumphost| JumphostSuggestion {
+            jumphost: jumphost.clone(),
+            reason: String::from("named like a bastion host"),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_addresses() {
+        for address in [
+            "10.1.2.3",
+            "172.20.0.1",
+            "192.168.1.10",
+            "100.64.0.5",
+            "169.254.1.1",
+            "fd00::1",
+            "[fe80::1]",
+            "db-1.internal",
+            "nas.lan.",
+        ] {
+            assert!(is_private_address(address), "{address}");
+        }
+        for address in [
+            "8.8.8.8",
+            "172.32.0.1",
+            "100.128.0.1",
+            "2001:db8::1",
+            "example.com",
+        ] {
+            assert!(!is_private_address(address), "{address}");
+        }
+    }
+
+    #[test]
+    fn subnets() {
+        assert_eq!(subnet("10.0.1.17"), Some(Ipv4Addr::new(10, 0, 1, 0)));
+        assert_eq!(subnet("db-1.internal"), None);
+    }
+}
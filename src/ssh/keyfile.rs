@@ -0,0 +1,250 @@
+use serde::Deserialize;
+
+use crate::db::AllowedUserOnHost;
+
+/// Start of the part of an authorized_keys file that ssm manages. Entries outside of it
+/// survive when ssm writes the file
+pub const BLOCK_BEGIN: &str = "# BEGIN ssh-key-manager";
+pub const BLOCK_END: &str = "# END ssh-key-manager";
+
+/// Lines of the managed block, without the markers. None if the file has no block
+pub fn managed_block(content: &str) -> Option<Vec<&str>> {
+    let mut lines = content
+        .lines()
+        .skip_while(|line| line.trim() != BLOCK_BEGIN);
+    lines.next()?;
+    Some(lines.take_while(|line| line.trim() != BLOCK_END).collect())
+}
+
+/// The file with `managed` as its managed block. An existing block is replaced, otherwise the
+/// block is appended. Files entirely written by ssm before (starting with `pragma`) are replaced.
+pub fn with_managed_block(current: &str, managed: &str, pragma: &str) -> String {
+    let mut block = String::with_capacity(managed.len() + BLOCK_BEGIN.len() + BLOCK_END.len() + 3);
+    block.push_str(BLOCK_BEGIN);
+    block.push('\n');
+    for line in managed.lines().filter(|line| !line.trim().is_empty()) {
+        block.push_str(line);
+        block.push('\n');
+    }
+    block.push_str(BLOCK_END);
+    block.push('\n');
+
+    let lines: Vec<&str> = current
+        .lines()
+        // Added by the script when reading read-only files
+        .filter(|line| !line.starts_with("# !"))
+        .collect();
+    if lines.first().is_some_and(|first| *first == pragma) {
+        return block;
+    }
+
+    let begin = lines.iter().position(|line| line.trim() == BLOCK_BEGIN);
+    let (before, after) = match begin {
+        Some(begin) => {
+            let end = lines[begin..]
+                .iter()
+                .position(|line| line.trim() == BLOCK_END)
+                // An unterminated block extends to the end of the file
+                .map_or(lines.len(), |end| begin + end + 1);
+            (&lines[..begin], &lines[end..])
+        }
+        None => (&lines[..], &[][..]),
+    };
+
+    let mut file = String::with_capacity(current.len() + block.len());
+    for line in trim_trailing_empty(before) {
+        file.push_str(line);
+        file.push('\n');
+    }
+    file.push_str(&block);
+    for line in trim_trailing_empty(after) {
+        file.push_str(line);
+        file.push('\n');
+    }
+    file
+}
+
+/// How the entries of a generated authorized_keys file are ordered
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyOrder {
+    /// In the order of the authorizations
+    #[default]
+    Unsorted,
+    /// Sorted by key comment and key
+    Alphabetical,
+    /// A section per user, sorted by username
+    User,
+    /// A section per team granting the access, followed by the users authorized directly
+    Group,
+}
+
+/// Renders the entries of a login, followed by `own_keys`, the key of ssm and the break-glass
+/// keys. Orders with sections start each with a comment.
+pub fn render_authorized_keys(
+    mut entries: Vec<AllowedUserOnHost>,
+    own_keys: &[String],
+    order: KeyOrder,
+) -> String {
+    let by_key = |a: &AllowedUserOnHost, b: &AllowedUserOnHost| {
+        (&a.key.comment, &a.key.key_type, &a.key.key_base64).cmp(&(
+            &b.key.comment,
+            &b.key.key_type,
+            &b.key.key_base64,
+        ))
+    };
+    let section: fn(&AllowedUserOnHost) -> Option<String> = match order {
+        KeyOrder::Unsorted => |_| None,
+        KeyOrder::Alphabetical => {
+            entries.sort_by(|a, b| by_key(a, b).then_with(|| a.username.cmp(&b.username)));
+            |_| None
+        }
+        KeyOrder::User => {
+            entries.sort_by(|a, b| a.username.cmp(&b.username).then_with(|| by_key(a, b)));
+            |entry| Some(format!("# user {}", entry.username))
+        }
+        KeyOrder::Group => {
+            // Teams first, the users authorized directly last
+            entries.sort_by(|a, b| {
+                (a.team.is_none(), &a.team, &a.username)
+                    .cmp(&(b.team.is_none(), &b.team, &b.username))
+                    .then_with(|| by_key(a, b))
+            });
+            |entry| {
+                Some(match &entry.team {
+                    Some(team) => format!("# team {team}"),
+                    None => String::from("# authorized directly"),
+                })
+            }
+        }
+    };
+
+    let mut file = String::with_capacity((entries.len() + own_keys.len() + 2) * 150);
+    let mut current = None;
+    for entry in &entries {
+        if let Some(header) = section(entry).filter(|header| current.as_ref() != Some(header)) {
+            file.push_str(&header);
+            file.push('\n');
+            current = Some(header);
+        }
+        if let Some(options) = &entry.options {
+            file.push_str(options);
+            file.push(' ');
+        }
+        file.push_str(&entry.key.to_openssh());
+        file.push('\n');
+    }
+    if matches!(order, KeyOrder::User | KeyOrder::Group) && !own_keys.is_empty() {
+        file.push_str("# ssm\n");
+    }
+    for key in own_keys {
+        file.push_str(key);
+        file.push('\n');
+    }
+    file
+}
+
+fn trim_trailing_empty<'a>(lines: &'a [&'a str]) -> &'a [&'a str] {
+    let len = lines
+        .iter()
+        .rposition(|line| !line.trim().is_empty())
+        .map_or(0, |last| last + 1);
+    &lines[..len]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::models::PublicUserKey;
+
+    const PRAGMA: &str = "# Auto-generated";
+
+    fn entry(username: &str, comment: &str, team: Option<&str>) -> AllowedUserOnHost {
+        AllowedUserOnHost {
+            key: PublicUserKey {
+                id: 0,
+                key_type: String::from("ssh-ed25519"),
+                key_base64: format!("AAAA{username}"),
+                comment: Some(comment.to_owned()),
+                user_id: 0,
+                expires_at: None,
+                attestation: None,
+                attested_by: None,
+            },
+            login: String::from("root"),
+            username: username.to_owned(),
+            options: None,
+            team: team.map(str::to_owned),
+        }
+    }
+
+    fn entries() -> Vec<AllowedUserOnHost> {
+        vec![
+            entry("carol", "b@laptop", None),
+            entry("bob", "c@laptop", Some("ops")),
+            entry("alice", "a@laptop", Some("dev")),
+        ]
+    }
+
+    #[test]
+    fn appends_block() {
+        let current = "ssh-ed25519 AAAA hand@made\n\n";
+        assert_eq!(
+            with_managed_block(current, "ssh-ed25519 BBBB ssm\n", PRAGMA),
+            "ssh-ed25519 AAAA hand@made\n# BEGIN ssh-key-manager\nssh-ed25519 BBBB ssm\n# END ssh-key-manager\n"
+        );
+        assert_eq!(
+            with_managed_block("", "", PRAGMA),
+            "# BEGIN ssh-key-manager\n# END ssh-key-manager\n"
+        );
+    }
+
+    #[test]
+    fn replaces_block() {
+        let current = "a\n# BEGIN ssh-key-manager\nold\n# END ssh-key-manager\nb\n";
+        let file = with_managed_block(current, "new", PRAGMA);
+        assert_eq!(
+            file,
+            "a\n# BEGIN ssh-key-manager\nnew\n# END ssh-key-manager\nb\n"
+        );
+        assert_eq!(managed_block(&file), Some(vec!["new"]));
+        assert_eq!(managed_block("a\nb\n"), None);
+
+        let unterminated = "a\n# BEGIN ssh-key-manager\nold\n";
+        assert_eq!(
+            with_managed_block(unterminated, "new", PRAGMA),
+            "a\n# BEGIN ssh-key-manager\nnew\n# END ssh-key-manager\n"
+        );
+    }
+
+    #[test]
+    fn replaces_files_written_by_ssm() {
+        let current = "# Auto-generated\nssh-ed25519 AAAA old\n";
+        assert_eq!(
+            with_managed_block(current, "new", PRAGMA),
+            "# BEGIN ssh-key-manager\nnew\n# END ssh-key-manager\n"
+        );
+    }
+
+    #[test]
+    fn renders_in_order() {
+        let own = [String::from("ssh-ed25519 SSM ssm")];
+        assert_eq!(
+            render_authorized_keys(entries(), &own, KeyOrder::Unsorted),
+            "ssh-ed25519 AAAAcarol b@laptop\nssh-ed25519 AAAAbob c@laptop\nssh-ed25519 AAAAalice a@laptop\nssh-ed25519 SSM ssm\n"
+        );
+        assert_eq!(
+            render_authorized_keys(entries(), &[], KeyOrder::Alphabetical),
+            "ssh-ed25519 AAAAalice a@laptop\nssh-ed25519 AAAAcarol b@laptop\nssh-ed25519 AAAAbob c@laptop\n"
+        );
+        assert_eq!(
+            render_authorized_keys(entries(), &own, KeyOrder::User),
+            "# user alice\nssh-ed25519 AAAAalice a@laptop\n# user bob\nssh-ed25519 AAAAbob c@laptop\n# user carol\nssh-ed25519 AAAAcarol b@laptop\n# ssm\nssh-ed25519 SSM ssm\n"
+        );
+        assert_eq!(
+            render_authorized_keys(entries(), &[], KeyOrder::Group),
+            "# team dev\nssh-ed25519 AAAAalice a@laptop\n# team ops\nssh-ed25519 AAAAbob c@laptop\n# authorized directly\nssh-ed25519 AAAAcarol b@laptop\n"
+        );
+    }
+}
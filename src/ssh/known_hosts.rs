@@ -0,0 +1,181 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use ssh_key::{Algorithm, EcdsaCurve, HashAlg, PublicKey};
+
+use super::client_config::patterns_match;
+
+/// The host key was taken from a known_hosts file
+pub const TRUST_KNOWN_HOSTS: &str = "known-hosts";
+
+/// Host key algorithms in the order russh prefers them, so the stored fingerprint is
+/// the one of the key the host will present
+const PREFERENCE: [Algorithm; 5] = [
+    Algorithm::Ed25519,
+    Algorithm::Ecdsa {
+        curve: EcdsaCurve::NistP256,
+    },
+    Algorithm::Ecdsa {
+        curve: EcdsaCurve::NistP384,
+    },
+    Algorithm::Ecdsa {
+        curve: EcdsaCurve::NistP521,
+    },
+    Algorithm::Rsa { hash: None },
+];
+
+enum HostPatterns {
+    /// Comma separated names, with `*` and `?` wildcards and `!` negations
+    Plain(Vec<String>),
+    /// `|1|salt|hash` written by `ssh-keygen -H` or `HashKnownHosts yes`
+    Hashed { salt: Vec<u8>, hash: Vec<u8> },
+}
+
+struct KnownHost {
+    hosts: HostPatterns,
+    revoked: bool,
+    key: PublicKey,
+}
+
+/// Host keys of an OpenSSH `known_hosts` file
+#[derive(Default)]
+pub struct KnownHosts {
+    entries: Vec<KnownHost>,
+}
+
+impl HostPatterns {
+    fn parse(field: &str) -> Option<Self> {
+        let Some(hashed) = field.strip_prefix("|1|") else {
+            return Some(Self::Plain(field.split(',').map(str::to_owned).collect()));
+        };
+        let (salt, hash) = hashed.split_once('|')?;
+        Some(Self::Hashed {
+            salt: BASE64.decode(salt).ok()?,
+            hash: BASE64.decode(hash).ok()?,
+        })
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Self::Plain(patterns) => patterns_match(patterns, name),
+            Self::Hashed { salt, hash } => {
+                let mut mac =
+                    <Hmac<Sha1> as Mac>::new_from_slice(salt).expect("HMAC takes keys of any size");
+                mac.update(name.as_bytes());
+                mac.verify_slice(hash).is_ok()
+            }
+        }
+    }
+}
+
+impl KnownHosts {
+    /// Parses `[marker] hosts keytype base64 [comment]` lines. Lines that can't be
+    /// parsed and certificate authorities are skipped.
+    pub fn parse(known_hosts: &str) -> Self {
+        let entries = known_hosts
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let mut hosts = fields.next()?;
+                let revoked = match hosts {
+                    "@revoked" => true,
+                    "@cert-authority" => return None,
+                    _ => false,
+                };
+                if revoked {
+                    hosts = fields.next()?;
+                }
+                let (key_type, base64) = (fields.next()?, fields.next()?);
+                Some(KnownHost {
+                    hosts: HostPatterns::parse(hosts)?,
+                    revoked,
+                    key: PublicKey::from_openssh(&format!("{key_type} {base64}")).ok()?,
+                })
+            })
+            .collect();
+        Self { entries }
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Fingerprint of the key a host will present, looked up like ssh does:
+    /// by the address, or `[address]:port` for ports other than 22
+    pub fn fingerprint(&self, address: &str, port: i32) -> Option<String> {
+        let name = match port {
+            22 => address.to_owned(),
+            port => format!("[{address}]:{port}"),
+        };
+        let matching: Vec<&KnownHost> = self
+            .entries
+            .iter()
+            .filter(|entry| entry.hosts.matches(&name))
+            .collect();
+        let is_revoked = |key: &PublicKey| {
+            matching
+                .iter()
+                .any(|entry| entry.revoked && entry.key.key_data() == key.key_data())
+        };
+        PREFERENCE.iter().find_map(|algorithm| {
+            matching
+                .iter()
+                .filter(|entry| !entry.revoked && !is_revoked(&entry.key))
+                .find(|entry| entry.key.algorithm() == *algorithm)
+                .map(|entry| entry.key.fingerprint(HashAlg::default()).to_string())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ED25519: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIJSIRR+/xXmaT7UMIGxJ5sqteRSHWr036NT4N6TO6Zk9";
+    const ED25519_FINGERPRINT: &str = "SHA256:xCHKGVQJMPm/G15FVTDI8DrwfmjgkuduHiaPxBkaWBE";
+    const ECDSA: &str = "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBJoTIbqmaLTtt5JGTPbn++8arExZQ8gMuUzUJ/EzgpyRp4pEWr0D8ppKHCsxGlGCPGd2iokZJoMsDJLF8rV/uIM=";
+    const ECDSA_FINGERPRINT: &str = "SHA256:RG4N9qY8+kdBeU0Fp85lLp2F42PEmZBDTP7KRboQAjY";
+
+    #[test]
+    fn preferred_key() {
+        let known_hosts = KnownHosts::parse(&format!(
+            "web-1,10.0.0.1 {ECDSA}\nweb-1 {ED25519} root@web-1\n[web-1]:2222 {ECDSA}"
+        ));
+        assert_eq!(
+            known_hosts.fingerprint("web-1", 22).as_deref(),
+            Some(ED25519_FINGERPRINT)
+        );
+        assert_eq!(
+            known_hosts.fingerprint("10.0.0.1", 22).as_deref(),
+            Some(ECDSA_FINGERPRINT)
+        );
+        assert_eq!(
+            known_hosts.fingerprint("web-1", 2222).as_deref(),
+            Some(ECDSA_FINGERPRINT)
+        );
+        assert_eq!(known_hosts.fingerprint("web-2", 22), None);
+    }
+
+    #[test]
+    fn hashed_and_revoked() {
+        let known_hosts = KnownHosts::parse(&format!(
+            "|1|o4WYJewMJW0QZ11vmP7Oa74RlGE=|fY5uGvnHrLxC9VH/rSgr/t2akAU= {ED25519}\n\
+             *.example.com,!db.example.com {ECDSA}\n\
+             @revoked www.example.com {ECDSA}\n\
+             @cert-authority * {ED25519}"
+        ));
+        assert_eq!(
+            known_hosts.fingerprint("web-1", 22).as_deref(),
+            Some(ED25519_FINGERPRINT)
+        );
+        assert_eq!(
+            known_hosts.fingerprint("app.example.com", 22).as_deref(),
+            Some(ECDSA_FINGERPRINT)
+        );
+        assert_eq!(known_hosts.fingerprint("db.example.com", 22), None);
+        assert_eq!(known_hosts.fingerprint("www.example.com", 22), None);
+    }
+}
@@ -1,12 +1,31 @@
-use ssh_key::{authorized_keys::ConfigOpts, Algorithm};
+use ssh_key::Algorithm;
 use std::collections::HashMap;
 use time::OffsetDateTime;
 
+mod attestation;
 mod caching_client;
+mod client_config;
+mod discovery;
+mod keyfile;
+mod known_hosts;
+mod options;
+mod remediation;
+mod sftp;
 mod sshclient;
+mod sshd;
+mod sshfp;
 
+pub use attestation::Attestation;
 pub use caching_client::CachingSshClient;
+pub use client_config::{parse_ssh_config, SshConfigHost};
+pub use discovery::suggest_jumphost;
+pub use keyfile::{render_authorized_keys, KeyOrder};
+pub use known_hosts::{KnownHosts, TRUST_KNOWN_HOSTS};
+pub use options::{Forwarding, KeyOptions, OptionSources};
+pub use remediation::{describe, remediate, RemediationPolicy};
 pub use sshclient::{SshClient, SshClientError};
+pub use sshd::SshdConfig;
+pub use sshfp::{check_sshfp, SshfpCheck, TRUST_MANUAL, TRUST_SSHFP, TRUST_SSHFP_DNSSEC};
 
 #[derive(Debug, Clone, serde::Deserialize)]
 pub struct SshPublicKey {
@@ -22,10 +41,53 @@ pub type AuthorizedKeyEntry = Result<AuthorizedKey, (ErrorMsg, Line)>;
 
 #[derive(Debug, Clone)]
 pub struct AuthorizedKey {
-    pub options: ConfigOpts,
+    pub options: KeyOptions,
     pub algorithm: Algorithm,
     pub base64: String,
     pub comment: Option<String>,
+    /// Set if this entry is an OpenSSH certificate instead of a plain key
+    pub certificate: Option<CertificateInfo>,
+}
+
+/// Details parsed from a `*-cert-v01@openssh.com` entry
+#[derive(Debug, Clone)]
+pub struct CertificateInfo {
+    /// Either "user" or "host"
+    pub cert_type: &'static str,
+    pub key_id: String,
+    pub serial: u64,
+    /// An empty list means the certificate is valid for any principal
+    pub principals: Vec<String>,
+    /// Unix timestamp
+    pub valid_after: u64,
+    /// Unix timestamp, `u64::MAX` means forever
+    pub valid_before: u64,
+    /// Fingerprint of the signing CA key
+    pub signing_key: String,
+}
+
+impl CertificateInfo {
+    fn format_timestamp(timestamp: u64) -> String {
+        i64::try_from(timestamp)
+            .ok()
+            .and_then(|t| OffsetDateTime::from_unix_timestamp(t).ok())
+            .map_or_else(|| String::from("forever"), |t| t.to_string())
+    }
+
+    /// Human readable validity period
+    pub fn validity(&self) -> String {
+        format!(
+            "from {} to {}",
+            Self::format_timestamp(self.valid_after),
+            Self::format_timestamp(self.valid_before)
+        )
+    }
+
+    /// Whether the certificate isn't valid right now, either not yet or not anymore
+    pub fn is_expired(&self) -> bool {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        u64::try_from(now).is_ok_and(|now| now < self.valid_after || now >= self.valid_before)
+    }
 }
 
 impl std::fmt::Display for SshPublicKey {
@@ -84,13 +146,41 @@ pub enum DiffItem {
     UnknownKey(AuthorizedKey),
     /// An unauthorized key belonging to a known user is present.
     UnauthorizedKey(AuthorizedKey, String),
+    /// An expired key of a known user is present.
+    ExpiredKey(AuthorizedKey, String),
     /// There is a duplicate key
     DuplicateKey(AuthorizedKey),
     /// There was an error Parsing this entry,
     FaultyKey(ErrorMsg, Line),
     /// The Pragma is missing, meaning this file is not yet managed
     PragmaMissing,
+    /// An OpenSSH certificate is present
+    Certificate(AuthorizedKey),
+}
+/// How urgently a drift item needs attention
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Someone has access who shouldn't
+    Critical,
+    /// Someone lacks access they should have
+    Warning,
+    /// Everything else
+    Info,
+}
+
+impl DiffItem {
+    pub const fn severity(&self) -> Severity {
+        match self {
+            Self::UnknownKey(_) | Self::UnauthorizedKey(_, _) | Self::ExpiredKey(_, _) => {
+                Severity::Critical
+            }
+            Self::KeyMissing(_, _) | Self::DuplicateKey(_) => Severity::Warning,
+            Self::FaultyKey(_, _) | Self::PragmaMissing | Self::Certificate(_) => Severity::Info,
+        }
+    }
 }
+
 type HostName = String;
 type AuthorizedKeys = Result<Vec<(Login, bool, Vec<AuthorizedKeyEntry>)>, SshClientError>;
 type CacheValue = (OffsetDateTime, AuthorizedKeys);
@@ -0,0 +1,535 @@
+use std::{fmt, str::FromStr};
+
+/// A single authorized_keys option like `no-pty` or `from="10.0.0.0/8"`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyOption {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+/// The options field of an authorized_keys line.
+///
+/// Options keep their order and may repeat, e.g. several `environment=`.
+/// Values are always written quoted, unquoted values are accepted when parsing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyOptions(Vec<KeyOption>);
+
+impl KeyOptions {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Validates user supplied options and brings them into their canonical form
+    pub fn normalize(options: Option<String>) -> Result<Option<String>, String> {
+        let Some(options) = options else {
+            return Ok(None);
+        };
+        let options = options.parse::<Self>()?;
+        options.validate_forwarding()?;
+
+        Ok((!options.is_empty()).then(|| options.to_string()))
+    }
+
+    /// Whether any option allows forwarding to or from specific ports
+    pub fn permits_forwarding(&self) -> bool {
+        self.0
+            .iter()
+            .any(|option| FORWARDING_PERMISSIONS.contains(&option.name.as_str()))
+    }
+
+    /// Applies a host policy forbidding any port forwarding, which overrides
+    /// whatever the authorization allows.
+    pub fn forbid_forwarding(mut self) -> Self {
+        self.remove_forwarding();
+        self.0.push(KeyOption {
+            name: String::from("no-port-forwarding"),
+            value: None,
+        });
+        self
+    }
+
+    fn remove_forwarding(&mut self) {
+        self.0.retain(|option| {
+            !FORWARDING_PERMISSIONS.contains(&option.name.as_str())
+                && option.name != "port-forwarding"
+                && option.name != "no-port-forwarding"
+        });
+    }
+
+    fn validate_forwarding(&self) -> Result<(), String> {
+        for option in &self.0 {
+            if !FORWARDING_PERMISSIONS.contains(&option.name.as_str()) {
+                continue;
+            }
+            match &option.value {
+                Some(value) => validate_forwarding_target(&option.name, value)?,
+                None => return Err(format!("'{}' needs a value", option.name)),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where the options of an authorized_keys entry come from
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OptionSources<'a> {
+    /// Options of the authorization itself
+    pub explicit: Option<&'a str>,
+    /// Options of the access rule the user is authorized through
+    pub group: Option<&'a str>,
+    /// Default options of the host
+    pub host: Option<&'a str>,
+    /// Default options of the user
+    pub user: Option<&'a str>,
+    /// The host forbids port forwarding, whatever the other sources say
+    pub deny_forwarding: bool,
+}
+
+impl OptionSources<'_> {
+    /// The options of an entry, with the precedence
+    /// deny > explicit > group > host default > user default.
+    ///
+    /// An option hides the same option of the sources after it, `pty` and `no-pty` count as
+    /// the same option. Repeatable options like `permitopen` are all taken from the first
+    /// source that has them.
+    pub fn resolve(&self) -> Result<KeyOptions, String> {
+        let mut resolved = KeyOptions::default();
+        let mut taken: Vec<String> = Vec::new();
+        for source in [self.explicit, self.group, self.host, self.user]
+            .into_iter()
+            .flatten()
+        {
+            let options = source.parse::<KeyOptions>()?;
+            let names: Vec<String> = options
+                .0
+                .iter()
+                .map(|option| precedence_name(&option.name).to_owned())
+                .collect();
+            resolved.0.extend(
+                options
+                    .0
+                    .into_iter()
+                    .filter(|option| !taken.iter().any(|t| t == precedence_name(&option.name))),
+            );
+            taken.extend(names);
+        }
+
+        Ok(match self.deny_forwarding {
+            true => resolved.forbid_forwarding(),
+            false => resolved,
+        })
+    }
+}
+
+/// The name under which an option overrides others, without `no-`
+fn precedence_name(name: &str) -> &str {
+    name.strip_prefix("no-").unwrap_or(name)
+}
+
+/// Options allowing forwarding to or from specific ports
+const FORWARDING_PERMISSIONS: [&str; 2] = ["permitopen", "permitlisten"];
+
+/// Checks a `permitopen` (`host:port`) or `permitlisten` (`[host:]port`) value.
+/// The port may be `*`, IPv6 addresses have to be in brackets.
+fn validate_forwarding_target(name: &str, target: &str) -> Result<(), String> {
+    if target == "none" || (name == "permitopen" && target == "any") {
+        return Ok(());
+    }
+    let (host, port) = match target.rsplit_once(':') {
+        Some((host, port)) => (Some(host), port),
+        None if name == "permitlisten" => (None, target),
+        None => return Err(format!("'{target}' has to look like host:port")),
+    };
+    if let Some(host) = host {
+        let valid = match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+            Some(address) => !address.is_empty() && !address.contains(['[', ']', ' ']),
+            None => !host.is_empty() && !host.contains([':', '[', ']', ' ']),
+        };
+        if !valid {
+            return Err(format!("'{target}' has an invalid host"));
+        }
+    }
+    if port != "*" && !port.parse::<u16>().is_ok_and(|port| port > 0) {
+        return Err(format!("'{target}' has an invalid port"));
+    }
+    Ok(())
+}
+
+/// Structured port forwarding settings of an authorization
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Forwarding {
+    /// Keep whatever the options say
+    #[default]
+    Unchanged,
+    /// No port forwarding at all
+    Forbidden,
+    /// Only forwarding to and from the listed targets
+    Restricted {
+        open: Vec<String>,
+        listen: Vec<String>,
+    },
+}
+
+impl Forwarding {
+    /// Reads the forwarding fields of a form. Targets are separated by
+    /// whitespace or commas.
+    pub fn from_form(mode: &str, permit_open: &str, permit_listen: &str) -> Result<Self, String> {
+        let targets = |s: &str| -> Vec<String> {
+            s.split([',', ' ', '\t'])
+                .filter(|target| !target.is_empty())
+                .map(str::to_owned)
+                .collect()
+        };
+        match mode {
+            "" => Ok(Self::Unchanged),
+            "none" => Ok(Self::Forbidden),
+            "restricted" => Ok(Self::Restricted {
+                open: targets(permit_open),
+                listen: targets(permit_listen),
+            }),
+            mode => Err(format!("Unknown forwarding mode '{mode}'")),
+        }
+    }
+
+    /// Renders these settings into free-form options, replacing the
+    /// forwarding options there
+    pub fn apply(self, options: Option<String>) -> Result<Option<String>, String> {
+        if self == Self::Unchanged {
+            return Ok(options);
+        }
+        let mut options = options.unwrap_or_default().parse::<KeyOptions>()?;
+        options.remove_forwarding();
+
+        let option = |name: &str, value: Option<String>| KeyOption {
+            name: name.to_owned(),
+            value,
+        };
+        match self {
+            Self::Unchanged => {}
+            Self::Forbidden => options.0.push(option("no-port-forwarding", None)),
+            Self::Restricted { open, listen } => {
+                // Without any target the other direction would be unrestricted
+                let none = || vec![String::from("none")];
+                let open = if open.is_empty() { none() } else { open };
+                let listen = if listen.is_empty() { none() } else { listen };
+                for target in open {
+                    options.0.push(option("permitopen", Some(target)));
+                }
+                for target in listen {
+                    options.0.push(option("permitlisten", Some(target)));
+                }
+            }
+        }
+        Ok(Some(options.to_string()))
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+/// Reads a quoted value, the opening quote is already consumed.
+/// Like sshd, only `\"` is an escape sequence.
+fn parse_quoted(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<String, String> {
+    let mut value = String::new();
+    loop {
+        match chars.next() {
+            None => return Err(String::from("Missing closing quote")),
+            Some('"') => return Ok(value),
+            Some('\\') if chars.peek() == Some(&'"') => {
+                chars.next();
+                value.push('"');
+            }
+            Some(c) => value.push(c),
+        }
+    }
+}
+
+impl FromStr for KeyOptions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Ok(Self::default());
+        }
+        if s.contains(['\n', '\r']) {
+            return Err(String::from("Options can't contain line breaks"));
+        }
+
+        let mut options = Vec::new();
+        let mut chars = s.chars().peekable();
+        loop {
+            let mut name = String::new();
+            while let Some(&c) = chars.peek() {
+                if !is_name_char(c) {
+                    break;
+                }
+                name.push(c);
+                chars.next();
+            }
+            if name.is_empty() {
+                return Err(match chars.peek() {
+                    Some(c) => format!("Unexpected '{c}' where an option name was expected"),
+                    None => String::from("Empty option"),
+                });
+            }
+
+            let value = match chars.peek() {
+                Some('=') => {
+                    chars.next();
+                    if chars.peek() == Some(&'"') {
+                        chars.next();
+                        Some(parse_quoted(&mut chars)?)
+                    } else {
+                        let mut value = String::new();
+                        while let Some(&c) = chars.peek() {
+                            if c == ',' {
+                                break;
+                            }
+                            if c.is_whitespace() || c == '"' {
+                                return Err(format!("The value of '{name}' has to be quoted"));
+                            }
+                            value.push(c);
+                            chars.next();
+                        }
+                        Some(value)
+                    }
+                }
+                _ => None,
+            };
+            options.push(KeyOption { name, value });
+
+            match chars.next() {
+                None => break,
+                Some(',') => {}
+                Some(c) => return Err(format!("Unexpected '{c}' after an option")),
+            }
+        }
+
+        Ok(Self(options))
+    }
+}
+
+impl fmt::Display for KeyOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{}=\"{}\"", self.name, value.replace('"', "\\\"")),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+impl fmt::Display for KeyOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, option) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{option}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(s: &str) -> String {
+        let options = s.parse::<KeyOptions>().expect("valid options");
+        let serialized = options.to_string();
+        assert_eq!(
+            serialized
+                .parse::<KeyOptions>()
+                .expect("serialized options parse"),
+            options
+        );
+        serialized
+    }
+
+    #[test]
+    fn flags_and_values() {
+        let s = r#"no-pty,no-agent-forwarding,command="/usr/bin/backup --daily""#;
+        assert_eq!(round_trip(s), s);
+    }
+
+    #[test]
+    fn repeated_options_keep_order() {
+        let s = r#"from="10.0.0.0/8",environment="A=1",from="192.168.0.1",environment="B=2""#;
+        let options = s.parse::<KeyOptions>().unwrap();
+        let names: Vec<_> = options.0.iter().map(|o| o.name.as_str()).collect();
+        assert_eq!(names, ["from", "environment", "from", "environment"]);
+        assert_eq!(round_trip(s), s);
+    }
+
+    #[test]
+    fn commas_and_escaped_quotes_in_values() {
+        let s = r#"from="10.0.0.1,10.0.0.2",command="echo \"hi, there\"""#;
+        let options = s.parse::<KeyOptions>().unwrap();
+        assert_eq!(options.0[0].value.as_deref(), Some("10.0.0.1,10.0.0.2"));
+        assert_eq!(options.0[1].value.as_deref(), Some(r#"echo "hi, there""#));
+        assert_eq!(round_trip(s), s);
+    }
+
+    #[test]
+    fn backslashes_are_literal() {
+        let s = r#"command="C:\\backup\run""#;
+        let options = s.parse::<KeyOptions>().unwrap();
+        assert_eq!(options.0[0].value.as_deref(), Some(r"C:\\backup\run"));
+        assert_eq!(round_trip(s), s);
+    }
+
+    #[test]
+    fn unquoted_values_are_quoted() {
+        assert_eq!(
+            round_trip("from=10.0.0.1,no-pty"),
+            r#"from="10.0.0.1",no-pty"#
+        );
+    }
+
+    #[test]
+    fn empty() {
+        assert!("".parse::<KeyOptions>().unwrap().is_empty());
+        assert_eq!(KeyOptions::normalize(Some(String::from("  "))), Ok(None));
+        assert_eq!(KeyOptions::normalize(None), Ok(None));
+    }
+
+    #[test]
+    fn forwarding_targets() {
+        for s in [
+            r#"permitopen="db.internal:5432""#,
+            r#"permitopen="[::1]:*""#,
+            r#"permitopen="any""#,
+            r#"permitlisten="8080""#,
+            r#"permitlisten="localhost:*""#,
+            r#"permitlisten="none""#,
+        ] {
+            assert!(
+                KeyOptions::normalize(Some(s.to_owned())).is_ok(),
+                "rejected {s:?}"
+            );
+        }
+        for s in [
+            r#"permitopen="db.internal""#,
+            r#"permitopen=":5432""#,
+            r#"permitopen="::1:22""#,
+            r#"permitlisten="localhost:0""#,
+            r#"permitlisten="localhost:http""#,
+            "permitopen",
+        ] {
+            assert!(
+                KeyOptions::normalize(Some(s.to_owned())).is_err(),
+                "accepted {s:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn structured_forwarding() {
+        let restricted = Forwarding::from_form("restricted", "db:5432, cache:6379", "").unwrap();
+        assert_eq!(
+            restricted.apply(Some(String::from(r#"no-pty,permitopen="old:1""#))),
+            Ok(Some(String::from(
+                r#"no-pty,permitopen="db:5432",permitopen="cache:6379",permitlisten="none""#
+            )))
+        );
+        assert_eq!(
+            Forwarding::from_form("none", "ignored:1", "")
+                .unwrap()
+                .apply(None),
+            Ok(Some(String::from("no-port-forwarding")))
+        );
+        assert_eq!(
+            Forwarding::from_form("", "", "").unwrap().apply(None),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn host_policy_overrides_forwarding() {
+        let options =
+            r#"permitopen="db:5432",no-pty,port-forwarding"#.parse::<KeyOptions>().unwrap();
+        assert!(options.permits_forwarding());
+        let options = options.forbid_forwarding();
+        assert!(!options.permits_forwarding());
+        assert_eq!(options.to_string(), "no-pty,no-port-forwarding");
+    }
+
+    #[test]
+    fn precedence_of_sources() {
+        let sources = OptionSources {
+            explicit: Some("pty,from=\"10.0.0.1\""),
+            group: Some("no-pty,command=\"/bin/group\""),
+            host: Some("command=\"/bin/host\",no-X11-forwarding"),
+            user: Some("from=\"0.0.0.0/0\",X11-forwarding,no-agent-forwarding"),
+            deny_forwarding: false,
+        };
+        assert_eq!(
+            sources.resolve().unwrap().to_string(),
+            r#"pty,from="10.0.0.1",command="/bin/group",no-X11-forwarding,no-agent-forwarding"#
+        );
+
+        // Without explicit options and group, the defaults apply
+        let sources = OptionSources {
+            explicit: None,
+            group: None,
+            ..sources
+        };
+        assert_eq!(
+            sources.resolve().unwrap().to_string(),
+            r#"command="/bin/host",no-X11-forwarding,from="0.0.0.0/0",no-agent-forwarding"#
+        );
+        assert!(OptionSources::default().resolve().unwrap().is_empty());
+    }
+
+    #[test]
+    fn repeated_options_come_from_one_source() {
+        let sources = OptionSources {
+            explicit: Some(r#"permitopen="a:1",permitopen="b:2""#),
+            host: Some(r#"permitopen="c:3",environment="A=1""#),
+            user: Some(r#"environment="B=2",environment="C=3""#),
+            ..Default::default()
+        };
+        assert_eq!(
+            sources.resolve().unwrap().to_string(),
+            r#"permitopen="a:1",permitopen="b:2",environment="A=1""#
+        );
+    }
+
+    #[test]
+    fn deny_overrides_every_source() {
+        let sources = OptionSources {
+            explicit: Some(r#"permitopen="db:5432",no-pty"#),
+            group: Some("port-forwarding"),
+            user: Some(r#"permitlisten="8080""#),
+            deny_forwarding: true,
+            ..Default::default()
+        };
+        let options = sources.resolve().unwrap();
+        assert!(!options.permits_forwarding());
+        assert_eq!(options.to_string(), "no-pty,no-port-forwarding");
+
+        let invalid = OptionSources {
+            host: Some("no-pty,"),
+            ..Default::default()
+        };
+        assert!(invalid.resolve().is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        for s in [
+            r#"command="unterminated"#,
+            "no-pty,,no-X11-forwarding",
+            "no-pty,",
+            "no-pty no-X11-forwarding",
+            "from=a b",
+            "command=\"a\nb\"",
+            r#"from="a"x"#,
+            "=value",
+        ] {
+            assert!(s.parse::<KeyOptions>().is_err(), "accepted {s:?}");
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+
+use log::{error, info, log, Level};
+use serde::Deserialize;
+
+use crate::{
+    db::BlockingPool,
+    models::{AuditEntry, Host, HostGroup},
+};
+
+use super::{DiffItem, HostDiff, HostName, Severity, SshClient};
+
+/// What to do when drift of a given severity is detected
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Only log the drift
+    #[default]
+    Alert,
+    /// Overwrite the authorized_keys file with the expected state
+    Remediate,
+}
+
+/// Decides what happens to drift on the hosts in `host_group`, or on all hosts without one.
+/// The first matching policy applies; hosts without a policy are left alone.
+#[derive(Debug, Clone, Deserialize)]
+// A left over `hosts` glob would otherwise make the policy apply to every host
+#[serde(deny_unknown_fields)]
+pub struct RemediationPolicy {
+    /// Only hosts in this host group, e.g. `prod`
+    #[serde(default)]
+    host_group: Option<String>,
+    #[serde(default)]
+    critical: Action,
+    #[serde(default)]
+    warning: Action,
+    #[serde(default)]
+    info: Action,
+}
+
+impl RemediationPolicy {
+    fn applies(&self, host_groups: &[String]) -> bool {
+        self.host_group
+            .as_ref()
+            .is_none_or(|group| host_groups.contains(group))
+    }
+
+    const fn action(&self, severity: Severity) -> Action {
+        match severity {
+            Severity::Critical => self.critical,
+            Severity::Warning => self.warning,
+            Severity::Info => self.info,
+        }
+    }
+
+    /// Whether the policy allows remediating every one of the items
+    fn remediates(&self, items: &[DiffItem]) -> bool {
+        items
+            .iter()
+            .all(|item| self.action(item.severity()) == Action::Remediate)
+    }
+}
+
+/// The first policy applying to a host in these groups
+fn policy_for<'a>(
+    policies: &'a [RemediationPolicy],
+    host_groups: &[String],
+) -> Option<&'a RemediationPolicy> {
+    policies.iter().find(|policy| policy.applies(host_groups))
+}
+
+/// A short description of a drift item for logs and reports
+pub fn describe(item: &DiffItem) -> String {
+    match item {
+        DiffItem::KeyMissing(key, user) => format!("missing key {} of '{user}'", key.algorithm),
+        DiffItem::UnknownKey(key) => format!(
+            "unknown key {} ({})",
+            key.algorithm,
+            key.comment.as_deref().unwrap_or("no comment")
+        ),
+        DiffItem::UnauthorizedKey(key, user) => {
+            format!("unauthorized key {} of '{user}'", key.algorithm)
+        }
+        DiffItem::ExpiredKey(key, user) => format!("expired key {} of '{user}'", key.algorithm),
+        DiffItem::DuplicateKey(key) => format!("duplicate key {}", key.algorithm),
+        DiffItem::FaultyKey(error, _) => format!("faulty entry: {error}"),
+        DiffItem::PragmaMissing => String::from("file is not managed yet"),
+        DiffItem::Certificate(cert) => format!("certificate {}", cert.algorithm),
+    }
+}
+
+/// Applies the first matching policy to the drift of every host, in the order of `state`.
+///
+/// A login is only remediated if the policy allows remediating every
+/// item found for it and the host isn't observed, otherwise all of its items are reported,
+/// as errors on critical hosts.
+pub async fn remediate(
+    policies: &[RemediationPolicy],
+    ssh_client: &SshClient,
+    db: &BlockingPool,
+    state: &[(HostName, HostDiff)],
+) {
+    if policies.is_empty() {
+        return;
+    }
+    let (hosts, memberships) = match db
+        .run(|conn| {
+            Ok((
+                Host::get_all_hosts(conn)?,
+                HostGroup::get_memberships(conn)?,
+            ))
+        })
+        .await
+        .map_err(String::from)
+        .and_then(|res| res)
+    {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("Failed to load the hosts to remediate: {e}");
+            return;
+        }
+    };
+    let names = |filter: fn(&Host) -> bool| -> Vec<String> {
+        hosts
+            .iter()
+            .filter(|host| filter(host))
+            .map(|host| host.name.clone())
+            .collect()
+    };
+    let critical = names(Host::is_critical);
+    // The drift of observed hosts is only reported
+    let observed = names(|host| host.observed_until().is_some());
+    let mut host_groups: HashMap<&str, Vec<String>> = HashMap::new();
+    for (host_id, group) in memberships {
+        if let Some(host) = hosts.iter().find(|host| host.id == host_id) {
+            host_groups.entry(&host.name).or_default().push(group);
+        }
+    }
+
+    for (host_name, (_, diff)) in state {
+        let groups = host_groups
+            .get(host_name.as_str())
+            .map_or(&[][..], Vec::as_slice);
+        let Some(policy) = policy_for(policies, groups) else {
+            continue;
+        };
+        let Ok(logins) = diff else {
+            continue;
+        };
+
+        for (login, items) in logins {
+            let remediate = !observed.contains(host_name) && policy.remediates(items);
+
+            if !remediate {
+                let level = match critical.contains(host_name) {
+                    true => Level::Error,
+                    false => Level::Warn,
+                };
+                for item in items {
+                    log!(
+                        level,
+                        host = host_name.as_str(), login = login.as_str(), action = "drift";
+                        "Drift on {host_name} for '{login}' ({:?}): {}",
+                        item.severity(),
+                        describe(item)
+                    );
+                }
+                continue;
+            }
+
+            for item in items {
+                info!(
+                    host = host_name.as_str(), login = login.as_str(), action = "remediate";
+                    "Remediating drift on {host_name} for '{login}': {}",
+                    describe(item)
+                );
+            }
+
+            let res = remediate_login(ssh_client, db, host_name, login).await;
+            if let Err(e) = &res {
+                error!(
+                    host = host_name.as_str(), login = login.as_str(), action = "remediate";
+                    "Failed to remediate {host_name} for '{login}': {e}"
+                );
+            } else {
+                info!(
+                    host = host_name.as_str(), login = login.as_str(), action = "remediate";
+                    "Remediated {host_name} for '{login}'"
+                );
+            }
+
+            // Names the backup, to roll back to the previous file by hand
+            let target = format!("host={host_name}, login={login}");
+            let fixed = items.iter().map(describe).collect::<Vec<_>>().join(", ");
+            let result = res.map(|backup| match backup {
+                Some(backup) => format!("Remediated {fixed}, the previous file is {backup}"),
+                None => format!("Remediated {fixed}"),
+            });
+            if let Err(e) = db
+                .run(move |conn| {
+                    AuditEntry::record(
+                        conn,
+                        String::from("scheduler"),
+                        String::from("remediate drift"),
+                        target,
+                        result,
+                    )
+                })
+                .await
+                .map_err(String::from)
+                .and_then(|res| res)
+            {
+                error!("Failed to record audit log entry: {e}");
+            }
+        }
+    }
+}
+
+/// Writes the expected authorized_keys of a login. Returns where the previous file was kept.
+async fn remediate_login(
+    ssh_client: &SshClient,
+    db: &BlockingPool,
+    host_name: &str,
+    login: &str,
+) -> Result<Option<String>, String> {
+    let host = Host::get_from_name(db, host_name.to_owned())
+        .await?
+        .ok_or_else(|| String::from("No such host"))?;
+
+    let (client, keyfile_login) = (ssh_client.clone(), login.to_owned());
+    let authorized_keys = db
+        .run(move |conn| host.get_authorized_keys_file_for(&client, conn, &keyfile_login))
+        .await??;
+
+    ssh_client
+        .set_authorized_keys(host_name.to_owned(), login.to_owned(), authorized_keys)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use ssh_key::Algorithm;
+
+    use super::*;
+    use crate::ssh::{AuthorizedKey, KeyOptions};
+
+    fn policies(json: serde_json::Value) -> Vec<RemediationPolicy> {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn key() -> AuthorizedKey {
+        AuthorizedKey {
+            options: KeyOptions::default(),
+            algorithm: Algorithm::Ed25519,
+            base64: String::from(
+                "AAAAC3NzaC1lZDI1NTE5AAAAIAxTf8anGjRB8KZVR/jjPvKhPeoDir8UUyEoVkJ9forE",
+            ),
+            comment: None,
+            certificate: None,
+        }
+    }
+
+    #[test]
+    fn first_policy_of_the_groups_applies() {
+        let policies = policies(serde_json::json!([
+            {"host_group": "prod", "critical": "remediate"},
+            {"host_group": "staging", "warning": "remediate"},
+            {},
+        ]));
+        let groups = |names: &[&str]| names.iter().map(|&n| n.to_owned()).collect::<Vec<_>>();
+
+        let policy = policy_for(&policies, &groups(&["web", "prod"])).unwrap();
+        assert_eq!(policy.host_group.as_deref(), Some("prod"));
+        let policy = policy_for(&policies, &groups(&["staging", "prod"])).unwrap();
+        assert_eq!(policy.host_group.as_deref(), Some("prod"));
+        let policy = policy_for(&policies, &groups(&["staging"])).unwrap();
+        assert_eq!(policy.host_group.as_deref(), Some("staging"));
+        let policy = policy_for(&policies, &[]).unwrap();
+        assert_eq!(policy.host_group, None);
+
+        assert!(policy_for(&policies[..2], &groups(&["web"])).is_none());
+    }
+
+    #[test]
+    fn refuses_host_globs() {
+        let res = serde_json::from_value::<RemediationPolicy>(serde_json::json!({
+            "hosts": "prod-*",
+            "critical": "remediate",
+        }));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn dispatches_by_severity() {
+        let policy =
+            &policies(serde_json::json!([{"critical": "remediate", "info": "remediate"}]))[0];
+        let critical = DiffItem::UnknownKey(key());
+        let warning = DiffItem::KeyMissing(key(), String::from("alice"));
+        let info = DiffItem::PragmaMissing;
+
+        assert_eq!(policy.action(critical.severity()), Action::Remediate);
+        assert_eq!(policy.action(warning.severity()), Action::Alert);
+        assert_eq!(policy.action(info.severity()), Action::Remediate);
+
+        assert!(policy.remediates(&[critical.clone(), info.clone()]));
+        // One item to only alert about keeps the whole login as it is
+        assert!(!policy.remediates(&[critical, warning.clone(), info]));
+        assert!(!policy.remediates(&[warning]));
+    }
+}
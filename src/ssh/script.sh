@@ -8,7 +8,7 @@ set -eu
 authorized_keys_location=".ssh/authorized_keys"
 externaly_managed_keyfile="${HOME}/.ssh/external_managed_keys"
 readonly_keyfile="${HOME}/.ssh/readonly_keys"
-version="Secure SSH Manager script v0.3-alpha"
+version="Secure SSH Manager script v0.7-alpha"
 keyfile_head="# Auto-generated by Secure SSH Manager. DO NOT EDIT!"
 
 cleanup() {
@@ -25,9 +25,12 @@ Secure SSH Manager ${version}
 Usage: $(basename "$0") COMMAND [OPTIONS]
 
 Commands:
-  get_authorized_keyfile USER    Display authorized keys for specified user
+  get_authorized_keyfile USER    Display authorized keys for specified user, exits with 2 if there are none
   set_authorized_keyfile USER    Set authorized keys for specified user (read from stdin)
+  get_authorized_keyfile_location USER
+                                 Print where the authorized keys of specified user are, fails if read-only
   get_ssh_users                  List all users with SSH access
+  get_sshd_config                Display the sshd settings relevant for key logins
   update                         Update this script (read from stdin)
   version                        Display version information
 EOF
@@ -59,7 +62,11 @@ do_getent_passwd() {
 get_authorized_keys_location() {
   user="$1"
   home=$(do_getent_passwd "${user}" | cut -d: -f6)
-  
+  if [ -z "${home}" ]; then
+    echo "Unknown user ${user}" >&2
+    exit 1
+  fi
+
   echo "${home}/${authorized_keys_location}"
 }
 
@@ -105,7 +112,7 @@ handle_get_authorized_keyfile() {
     if [ ! -e "${keyfile_location}" ]; then
         echo "Couldn't find authorized_keys for this user."
         echo "Tried location: ${keyfile_location}"
-        exit 1
+        exit 2
     fi
     if is_keyfile_readonly; then
         print_keyfile_comments
@@ -137,6 +144,18 @@ handle_set_authorized_keyfile() {
     exit 0
 }
 
+handle_get_authorized_keyfile_location() {
+    user="$1"
+
+    if is_keyfile_readonly; then
+        echo "Keyfile is readonly, aborting."
+        exit 1
+    fi
+
+    get_authorized_keys_location "${user}"
+    exit 0
+}
+
 handle_get_ssh_users() {
     printf "" > "${TMP}/homedirs.$$"
     
@@ -150,6 +169,16 @@ handle_get_ssh_users() {
     exit 0
 }
 
+handle_get_sshd_config() {
+    sshd_bin=$(command -v sshd 2>/dev/null || echo /usr/sbin/sshd)
+    # The effective configuration needs root, fall back to reading the file
+    if [ -x "${sshd_bin}" ] && "${sshd_bin}" -T 2>/dev/null | grep -Ei "^(permitrootlogin|pubkeyauthentication) "; then
+        exit 0
+    fi
+    grep -Ei "^[[:space:]]*(permitrootlogin|pubkeyauthentication)[[:space:]]" /etc/ssh/sshd_config 2>/dev/null || true
+    exit 0
+}
+
 handle_update() {
     newfile="${0}.new"
     cat - > "${newfile}"
@@ -181,7 +210,9 @@ shift
 case "${command}" in
     get_authorized_keyfile)  handle_get_authorized_keyfile "$@" ;;
     set_authorized_keyfile)  handle_set_authorized_keyfile "$@" ;;
+    get_authorized_keyfile_location) handle_get_authorized_keyfile_location "$@" ;;
     get_ssh_users)           handle_get_ssh_users ;;
+    get_sshd_config)         handle_get_sshd_config ;;
     update)                  handle_update ;;
     version)                 handle_version ;;
     *)
@@ -0,0 +1,155 @@
+use log::{debug, warn};
+use russh_sftp::{
+    client::{error::Error as SftpError, RawSftpSession},
+    protocol::{FileAttributes, OpenFlags, Packet, StatusCode},
+};
+use time::OffsetDateTime;
+
+use super::SshClientError;
+
+/// Largest chunk written in one request, the minimum every server has to support
+const WRITE_CHUNK: usize = 32 * 1024;
+
+impl From<SftpError> for SshClientError {
+    fn from(value: SftpError) -> Self {
+        Self::ExecutionError(format!("SFTP: {value}"))
+    }
+}
+
+fn is_missing(error: &SftpError) -> bool {
+    matches!(error, SftpError::Status(status) if status.status_code == StatusCode::NoSuchFile)
+}
+
+/// Suffix of temporary and backup files, e.g. `20261015T190000`
+fn timestamp() -> String {
+    let now = OffsetDateTime::now_utc();
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}",
+        now.year(),
+        u8::from(now.month()),
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second()
+    )
+}
+
+/// Encodes the arguments of an OpenSSH extension as SSH strings
+fn ssh_strings(strings: &[&str]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for s in strings {
+        data.extend_from_slice(&u32::try_from(s.len()).unwrap_or(u32::MAX).to_be_bytes());
+        data.extend_from_slice(s.as_bytes());
+    }
+    data
+}
+
+/// Replaces a file on the host without it ever being partially written: the content goes to a
+/// temporary file with mode 600 next to it, the previous file is kept as `<path>.<timestamp>.bak`
+/// and the temporary file is renamed over it. Needs an OpenSSH sftp-server. Returns the path of
+/// the backup, if there was a previous file.
+pub async fn replace_file(
+    sftp: &RawSftpSession,
+    path: &str,
+    content: &[u8],
+) -> Result<Option<String>, SshClientError> {
+    let stamp = timestamp();
+    let tmp = format!("{path}.ssm-{stamp}.tmp");
+
+    let previous = match sftp.stat(path).await {
+        Ok(attrs) => Some(attrs.attrs),
+        Err(e) if is_missing(&e) => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    let attrs = FileAttributes {
+        permissions: Some(0o600),
+        ..FileAttributes::empty()
+    };
+    let handle = sftp
+        .open(
+            tmp.as_str(),
+            OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+            attrs.clone(),
+        )
+        .await?
+        .handle;
+
+    let written = write_all(sftp, &handle, content, attrs, previous.as_ref()).await;
+    let closed = sftp.close(handle).await.map(|_| ()).map_err(Into::into);
+    if let Err(e) = written.and(closed) {
+        discard(sftp, &tmp).await;
+        return Err(e);
+    }
+
+    let backup = match previous {
+        Some(_) => {
+            let backup = format!("{path}.{stamp}.bak");
+            debug!("Keeping the previous {path} as {backup}");
+            if let Err(e) = sftp.hardlink(path, backup.as_str()).await {
+                // Better no change than one that can't be undone
+                discard(sftp, &tmp).await;
+                return Err(SshClientError::ExecutionError(format!(
+                    "Couldn't back up {path}: {e}"
+                )));
+            }
+            Some(backup)
+        }
+        None => None,
+    };
+
+    let renamed = match sftp
+        .extended("posix-rename@openssh.com", ssh_strings(&[&tmp, path]))
+        .await
+    {
+        Ok(Packet::Status(status)) if status.status_code == StatusCode::Ok => Ok(()),
+        Ok(Packet::Status(status)) => Err(SftpError::Status(status)),
+        Ok(_) => Err(SftpError::UnexpectedPacket),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = renamed {
+        discard(sftp, &tmp).await;
+        return Err(SshClientError::ExecutionError(format!(
+            "Couldn't move the new file to {path}: {e}"
+        )));
+    }
+    Ok(backup)
+}
+
+async fn discard(sftp: &RawSftpSession, tmp: &str) {
+    if let Err(e) = sftp.remove(tmp).await {
+        warn!("Failed to remove temporary file {tmp}: {e}");
+    }
+}
+
+async fn write_all(
+    sftp: &RawSftpSession,
+    handle: &str,
+    content: &[u8],
+    attrs: FileAttributes,
+    previous: Option<&FileAttributes>,
+) -> Result<(), SshClientError> {
+    let mut offset = 0;
+    for chunk in content.chunks(WRITE_CHUNK) {
+        sftp.write(handle, offset, chunk.to_vec()).await?;
+        offset += chunk.len() as u64;
+    }
+    // The mode given on open is subject to the umask of the server
+    sftp.fsetstat(handle, attrs).await?;
+
+    // Keep the owner, so the file still belongs to the login when written by another one
+    if let Some(previous) = previous {
+        let owner = FileAttributes {
+            uid: previous.uid,
+            gid: previous.gid,
+            ..FileAttributes::empty()
+        };
+        if let Err(e) = sftp.fsetstat(handle, owner).await {
+            warn!("Couldn't keep the owner of the authorized_keys file: {e}");
+        }
+    }
+    if let Err(e) = sftp.fsync(handle).await {
+        debug!("Couldn't fsync: {e}");
+    }
+    Ok(())
+}
@@ -9,34 +9,89 @@ use log::info;
 use log::warn;
 use russh::keys::key::PrivateKeyWithHashAlg;
 use russh::keys::PublicKeyBase64;
+use russh_sftp::client::RawSftpSession;
 use ssh_encoding::Base64Writer;
 use ssh_encoding::Encode;
 use ssh_key::authorized_keys::Entry;
+use ssh_key::Certificate;
 use ssh_key::PublicKey;
-use std::io::Cursor;
+use std::collections::VecDeque;
 use std::ops::Deref;
 use std::str::FromStr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::mpsc;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::time::Duration;
+use std::time::Instant;
 use tokio::io::AsyncRead;
 
 const PRAGMA: &str = "# Auto-generated by Secure SSH Manager. DO NOT EDIT!";
-
+/// Has to match `version` in script.sh. Hosts running another version get the script reinstalled.
+const SCRIPT_VERSION: &str = "Secure SSH Manager script v0.7-alpha";
+/// Exit code of `get_authorized_keyfile` if the login has no authorized_keys file
+const KEYFILE_MISSING: u32 = 2;
+
+use crate::models::format_expiry;
+use crate::otel::{Span, SpanKind};
+use crate::shutdown::Operation;
 use crate::SshConfig;
-use crate::{models::Host, ConnectionPool};
+use crate::{db::BlockingPool, models::Host};
 
+use super::keyfile;
+use super::sftp;
 use super::AuthorizedKey;
 use super::AuthorizedKeyEntry;
 use super::AuthorizedKeys;
+use super::CertificateInfo;
 use super::ConnectionDetails;
 use super::KeyDiffItem;
+use super::KeyOptions;
+use super::KeyOrder;
+use super::SshdConfig;
+
+/// Connections older than this don't count towards the failure rate
+const CONNECTION_WINDOW: Duration = Duration::from_secs(300);
+
+/// Outcomes of the recent connection attempts, as a health signal
+#[derive(Debug, Default)]
+struct ConnectionOutcomes {
+    recent: Mutex<VecDeque<(Instant, bool)>>,
+    /// Since startup, for `/metrics`
+    attempts: AtomicU64,
+    failures: AtomicU64,
+}
+
+impl ConnectionOutcomes {
+    fn record(&self, success: bool) {
+        self.attempts.fetch_add(1, Ordering::Relaxed);
+        if !success {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut recent = self.recent.lock().unwrap_or_else(PoisonError::into_inner);
+        recent.push_back((Instant::now(), success));
+        Self::prune(&mut recent);
+    }
+
+    fn prune(recent: &mut VecDeque<(Instant, bool)>) {
+        while recent
+            .front()
+            .is_some_and(|(at, _)| at.elapsed() > CONNECTION_WINDOW)
+        {
+            recent.pop_front();
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct SshClient {
-    conn: ConnectionPool,
+    db: BlockingPool,
     key: Arc<PrivateKeyWithHashAlg>,
     config: Arc<SshConfig>,
     connection_config: Arc<russh::client::Config>,
+    outcomes: Arc<ConnectionOutcomes>,
 }
 
 #[derive(Debug, Clone)]
@@ -53,6 +108,10 @@ pub enum SshClientError {
     NotAuthenticated,
 
     SshError(String),
+    /// The change would remove the access of ssm or a break-glass key
+    Lockout(String),
+    /// The host is new and only observed until then
+    Observed(String),
 }
 
 impl fmt::Display for SshClientError {
@@ -67,6 +126,11 @@ impl fmt::Display for SshClientError {
             Self::ExecutionError(t) | Self::SshError(t) => {
                 write!(f, "{t}")
             }
+            Self::Lockout(t) => write!(f, "Refusing to lock ssm out: {t}"),
+            Self::Observed(until) => write!(
+                f,
+                "The host is observed until {until}, its drift is only reported."
+            ),
         }
     }
 }
@@ -87,6 +151,12 @@ impl From<BlockingError> for SshClientError {
     }
 }
 
+impl From<crate::db::BlockingError> for SshClientError {
+    fn from(value: crate::db::BlockingError) -> Self {
+        Self::ExecutionError(value.to_string())
+    }
+}
+
 #[derive(Debug)]
 struct SshHandler {
     hostkey_fingerprint: String,
@@ -142,15 +212,36 @@ impl russh::client::Handler for SshFirstConnectionHandler {
     }
 }
 impl SshClient {
-    pub fn new(conn: ConnectionPool, key: PrivateKeyWithHashAlg, config: SshConfig) -> Self {
+    pub fn new(db: BlockingPool, key: PrivateKeyWithHashAlg, config: SshConfig) -> Self {
         Self {
-            conn,
+            db,
             key: key.into(),
             config: config.into(),
             connection_config: russh::client::Config::default().into(),
+            outcomes: Arc::default(),
         }
     }
 
+    /// Connection attempts of the last five minutes and how many of them failed
+    pub fn connection_failures(&self) -> (usize, usize) {
+        let mut recent = self
+            .outcomes
+            .recent
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        ConnectionOutcomes::prune(&mut recent);
+        let failed = recent.iter().filter(|(_, success)| !success).count();
+        (recent.len(), failed)
+    }
+
+    /// Connection attempts since startup and how many of them failed
+    pub fn connection_totals(&self) -> (u64, u64) {
+        (
+            self.outcomes.attempts.load(Ordering::Relaxed),
+            self.outcomes.failures.load(Ordering::Relaxed),
+        )
+    }
+
     fn get_key(&self) -> PrivateKeyWithHashAlg {
         Arc::clone(&self.key).deref().to_owned()
     }
@@ -163,6 +254,68 @@ impl SshClient {
         self.key.public_key_base64()
     }
 
+    /// Configured keys that are always authorized for the login of ssm
+    pub fn get_break_glass_keys(&self) -> &[String] {
+        &self.config.break_glass_keys
+    }
+
+    pub fn key_order(&self) -> KeyOrder {
+        self.config.key_order
+    }
+
+    /// Until when hosts added now are only observed, if they are
+    pub fn observation_end(&self) -> Option<time::PrimitiveDateTime> {
+        self.config
+            .observation_period
+            .map(|period| crate::db::now() + period)
+    }
+
+    /// Refuses changes to hosts that are still observed
+    fn check_observation(host: &Host) -> Result<(), SshClientError> {
+        match host.observed_until() {
+            Some(until) => Err(SshClientError::Observed(format_expiry(until))),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether removing this key (base64) could lock ssm or the admins out of a host
+    pub fn is_protected_key(&self, base64: &str) -> bool {
+        base64 == self.get_own_key_b64()
+            || self
+                .config
+                .break_glass_keys
+                .iter()
+                .any(|key| parse_authorized_key(key).is_ok_and(|key| key.base64 == base64))
+    }
+
+    /// Refuses files for the login of ssm that lack its own key or a break-glass key
+    fn check_lockout(&self, host: &Host, login: &str, file: &str) -> Result<(), SshClientError> {
+        if login != host.username {
+            return Ok(());
+        }
+        let present: Vec<String> = file
+            .lines()
+            .filter_map(|line| parse_authorized_key(line).ok())
+            .map(|key| key.base64)
+            .collect();
+
+        if !present.contains(&self.get_own_key_b64()) {
+            return Err(SshClientError::Lockout(format!(
+                "The authorized_keys of {login} on {} would lack the key ssm logs in with",
+                host.name
+            )));
+        }
+        for key in &self.config.break_glass_keys {
+            if !parse_authorized_key(key).is_ok_and(|key| present.contains(&key.base64)) {
+                return Err(SshClientError::Lockout(format!(
+                    "The authorized_keys of {login} on {} would lack the break-glass key {key}",
+                    host.name
+                )));
+            }
+        }
+        Ok(())
+    }
+
     /// Tries to connect to a host and returns hostkeys to validate
     pub async fn get_hostkey(
         &self,
@@ -264,11 +417,13 @@ impl SshClient {
         let handler = SshHandler {
             hostkey_fingerprint: key_fingerprint.clone(),
         };
+        let outcomes = Arc::clone(&self.outcomes);
+        let (host_name, address, port) = (host.name.clone(), host.address.clone(), host.port);
 
-        async move {
+        let connect = async move {
             let mut handle = match host.jump_via {
                 Some(via) => {
-                    let jump_host = Host::get_from_id(self.conn.get().unwrap(), via)
+                    let jump_host = Host::get_from_id(&self.db, via)
                         .await?
                         .ok_or(SshClientError::NoSuchHost)?;
                     let stream = self.connect_via(jump_host, host.to_connection()?).await?;
@@ -296,6 +451,15 @@ impl SshClient {
             };
 
             Ok(handle)
+        };
+        let span = Span::start("ssh connect", SpanKind::Client)
+            .with_attribute("host.name", host_name)
+            .with_attribute("server.address", address)
+            .with_attribute("server.port", port);
+        async move {
+            let res = span.run(connect).await;
+            outcomes.record(res.is_ok());
+            res
         }
         .boxed()
     }
@@ -319,7 +483,19 @@ impl SshClient {
         .map_err(SshClientError::from)
     }
 
+    /// Connects and authenticates on a host without executing anything
+    pub async fn check_connection(&self, host: Host) -> Result<(), SshClientError> {
+        self.clone().connect(host).await.map(|_| ())
+    }
+
     pub async fn get_authorized_keys(self, host: Host) -> AuthorizedKeys {
+        Span::start("ssh get_authorized_keys", SpanKind::Internal)
+            .with_attribute("host.name", host.name.clone())
+            .run(self.read_authorized_keys(host))
+            .await
+    }
+
+    async fn read_authorized_keys(self, host: Host) -> AuthorizedKeys {
         let handle = self.clone().connect(host.clone()).await?;
         let users = self.get_ssh_users(&handle).await?;
 
@@ -334,7 +510,8 @@ impl SshClient {
         Ok(user_vec)
     }
 
-    /// Returns if the pragma is set and a list of authorized key entries
+    /// Returns if ssm manages the file, i.e. the pragma is set or it has a managed block,
+    /// and a list of the authorized key entries ssm manages
     async fn get_authorized_keys_for(
         &self,
         handle: &russh::client::Handle<SshHandler>,
@@ -344,58 +521,222 @@ impl SshClient {
             .execute_bash(handle, BashCommand::GetAuthorizedKeyfile(user))
             .await??;
 
-        let mut iter = res.trim().lines().peekable();
-        let has_pragma = iter.peek().is_some_and(|first| PRAGMA.to_owned().eq(first));
+        let (managed, lines) = match keyfile::managed_block(&res) {
+            Some(block) => (true, block),
+            None => {
+                let lines: Vec<&str> = res.trim().lines().collect();
+                (lines.first().is_some_and(|first| PRAGMA.eq(*first)), lines)
+            }
+        };
         Ok((
-            has_pragma,
-            iter.filter(|line| !line.trim_start().starts_with('#'))
-                .map(|line| {
-                    Entry::from_str(line)
-                        .map_err(|e| (e.to_string(), line.to_owned()))
-                        .map(|key| {
-                            //TODO: algorithm to estimate size
-                            let mut buf = vec![0u8; 1024];
-                            let mut writer = Base64Writer::new(&mut buf).expect("buf is non-zero");
-
-                            let pkey = key.public_key();
-                            let comment = pkey.comment();
-
-                            pkey.key_data().encode(&mut writer).expect("Buffer overrun");
-                            let b64 = writer.finish().expect("Buffer overrun");
-
-                            AuthorizedKey {
-                                options: key.config_opts().clone(),
-                                algorithm: pkey.algorithm(),
-                                base64: b64.to_owned(),
-                                comment: if comment.is_empty() {
-                                    None
-                                } else {
-                                    Some(comment.to_owned())
-                                },
-                            }
-                        })
-                })
+            managed,
+            lines
+                .into_iter()
+                .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+                .map(parse_authorized_key)
                 .collect(),
         ))
     }
 
+    /// Writes the entries ssm manages for a login. Depending on the configuration this
+    /// replaces the whole file or only the managed block in it. Returns where the previous
+    /// file was kept, if there was one.
+    async fn write_managed_keys(
+        &self,
+        handle: &russh::client::Handle<SshHandler>,
+        host: &Host,
+        login: String,
+        authorized_keys: String,
+    ) -> Result<Option<String>, SshClientError> {
+        let _operation = Operation::begin(format!("writing {login} on {}", host.name));
+        let file = match self.config.manage_whole_keyfile {
+            true => format!("{PRAGMA}\n{authorized_keys}"),
+            false => {
+                // A missing file is created. Any other failure to read it aborts, as the
+                // entries around the managed block would be lost.
+                let current = self
+                    .read_authorized_keyfile(handle, login.clone())
+                    .await?
+                    .unwrap_or_default();
+                keyfile::with_managed_block(&current, &authorized_keys, PRAGMA)
+            }
+        };
+        self.check_lockout(host, &login, &file)?;
+        let backup = self
+            .upload_authorized_keys(handle, login.clone(), file)
+            .await?;
+
+        // The keys are written, not knowing when only affects the host page
+        let host = host.clone();
+        if let Err(e) = self
+            .db
+            .run(move |conn| host.record_deployment(conn, &login))
+            .await
+            .map_err(String::from)
+            .and_then(|res| res)
+        {
+            warn!("Failed to record a deployment: {e}");
+        }
+        Ok(backup)
+    }
+
+    /// Replaces the authorized_keys file of a login atomically via SFTP, keeping a backup
+    async fn upload_authorized_keys(
+        &self,
+        handle: &russh::client::Handle<SshHandler>,
+        login: String,
+        file: String,
+    ) -> Result<Option<String>, SshClientError> {
+        // Also refuses read-only files
+        let location = self
+            .execute_bash(handle, BashCommand::GetAuthorizedKeyfileLocation(login))
+            .await??;
+
+        let channel = handle.channel_open_session().await?;
+        channel.request_subsystem(true, "sftp").await?;
+        let session = RawSftpSession::new(channel.into_stream());
+        session.set_timeout(self.config.timeout.as_secs()).await;
+        session.init().await?;
+
+        let res = sftp::replace_file(&session, location.trim(), file.as_bytes()).await;
+        if let Err(e) = session.close_session() {
+            debug!("Failed to close SFTP session: {e}");
+        }
+        res
+    }
+
+    /// Writes the authorized_keys of a login. Returns where the previous file was kept, if
+    /// there was one.
     pub async fn set_authorized_keys(
         &self,
         host_name: String,
         login: String,
         authorized_keys: String,
-    ) -> Result<(), SshClientError> {
-        let host = Host::get_from_name(self.conn.get().unwrap(), host_name)
+    ) -> Result<Option<String>, SshClientError> {
+        let host = Host::get_from_name(&self.db, host_name)
             .await?
             .ok_or(SshClientError::NoSuchHost)?;
+        Self::check_observation(&host)?;
         let handle = self.clone().connect(host.clone()).await?;
-        self.execute_bash(
-            &handle,
-            BashCommand::SetAuthorizedKeyfile(login, authorized_keys),
-        )
-        .await??;
+        self.write_managed_keys(&handle, &host, login, authorized_keys)
+            .await
+    }
 
-        Ok(())
+    /// Writes the authorized_keys expected from the database to every managed login
+    /// of a host: logins with authorizations, the login of ssm itself and logins whose
+    /// file was written by ssm before. Returns the rewritten logins.
+    pub async fn apply_authorized_keys(&self, host: Host) -> Result<Vec<String>, SshClientError> {
+        Self::check_observation(&host)?;
+        let handle = self.clone().connect(host.clone()).await?;
+        let users = self.get_ssh_users(&handle).await?;
+
+        let db_host = host.clone();
+        let authorized_logins: Vec<String> = self
+            .db
+            .run(move |conn| db_host.get_authorized_users(conn))
+            .await?
+            .map_err(SshClientError::ExecutionError)?
+            .into_iter()
+            .map(|(_, _, login, _)| login)
+            .collect();
+        for login in &authorized_logins {
+            if !users.contains(login) {
+                warn!(
+                    host = host.name.as_str(), login = login.as_str(), action = "apply";
+                    "Skipping login '{login}' missing on {}", host.name
+                );
+            }
+        }
+
+        let mut logins = Vec::new();
+        for user in users {
+            let managed = user == host.username
+                || authorized_logins.contains(&user)
+                || self.get_authorized_keys_for(&handle, user.clone()).await?.0;
+            if managed {
+                logins.push(user);
+            }
+        }
+
+        for login in &logins {
+            let (db_host, client, keyfile_login) = (host.clone(), self.clone(), login.clone());
+            let authorized_keys = self
+                .db
+                .run(move |conn| {
+                    db_host.get_authorized_keys_file_for(&client, conn, &keyfile_login)
+                })
+                .await?
+                .map_err(SshClientError::ExecutionError)?;
+            self.write_managed_keys(&handle, &host, login.clone(), authorized_keys)
+                .await?;
+        }
+
+        Ok(logins)
+    }
+
+    /// Removes all entries using one of `keys` (base64) from the authorized_keys of a login.
+    /// Other entries are left untouched. Refuses to remove the key of ssm or a break-glass key.
+    pub async fn remove_keys(
+        &self,
+        host: Host,
+        login: String,
+        keys: &[String],
+    ) -> Result<(), SshClientError> {
+        if let Some(key) = keys.iter().find(|key| self.is_protected_key(key)) {
+            return Err(SshClientError::Lockout(format!(
+                "{key} is the key of ssm or a break-glass key"
+            )));
+        }
+        self.remove_entries(host, login, keys).await
+    }
+
+    /// Removes the key of ssm along with `keys` from the login of ssm, when giving up a host.
+    /// Break-glass keys stay.
+    pub async fn remove_own_access(
+        &self,
+        host: Host,
+        keys: &[String],
+    ) -> Result<(), SshClientError> {
+        let mut keys: Vec<String> = keys
+            .iter()
+            .filter(|key| !self.is_protected_key(key))
+            .cloned()
+            .collect();
+        keys.push(self.get_own_key_b64());
+        let login = host.username.clone();
+        self.remove_entries(host, login, &keys).await
+    }
+
+    async fn remove_entries(
+        &self,
+        host: Host,
+        login: String,
+        keys: &[String],
+    ) -> Result<(), SshClientError> {
+        Self::check_observation(&host)?;
+        let _operation = Operation::begin(format!("removing keys of {login} on {}", host.name));
+        let handle = self.clone().connect(host).await?;
+        let current = self
+            .execute_bash(&handle, BashCommand::GetAuthorizedKeyfile(login.clone()))
+            .await??;
+
+        let remaining = current
+            .lines()
+            .filter(|line| !line.trim().is_empty() && !line.eq(&PRAGMA) && !line.starts_with("# !"))
+            .filter(|line| {
+                parse_authorized_key(line).map_or(true, |key| !keys.contains(&key.base64))
+            })
+            .fold(String::new(), |buf, line| buf + line + "\n");
+
+        // Files without the pragma keep not having it, markers of the managed block are kept
+        let file = if current.lines().next() == Some(PRAGMA) {
+            format!("{PRAGMA}\n{remaining}")
+        } else {
+            remaining
+        };
+        self.upload_authorized_keys(&handle, login, file)
+            .await
+            .map(|_| ())
     }
 
     async fn get_ssh_users(
@@ -409,8 +750,27 @@ impl SshClient {
         Ok(res.lines().map(std::borrow::ToOwned::to_owned).collect())
     }
 
+    pub async fn get_sshd_config(&self, host: Host) -> Result<SshdConfig, SshClientError> {
+        let handle = self.clone().connect(host).await?;
+        let res = self
+            .execute_bash(&handle, BashCommand::GetSshdConfig)
+            .await??;
+
+        Ok(SshdConfig::parse(&res))
+    }
+
+    /// Warns if a key authorized for root on this host couldn't be used to log in
+    pub async fn root_login_advisory(&self, host: Host) -> Option<String> {
+        match self.get_sshd_config(host).await {
+            Ok(config) => config.root_login_advisory(),
+            Err(e) => Some(format!(
+                "Couldn't check whether sshd allows root logins: {e}"
+            )),
+        }
+    }
+
     pub async fn install_script_on_host(&self, host: i32) -> Result<(), SshClientError> {
-        let host = Host::get_from_id(self.conn.get().unwrap(), host)
+        let host = Host::get_from_id(&self.db, host)
             .await?
             .ok_or(SshClientError::NoSuchHost)?;
         let handle = self.clone().connect(host).await?;
@@ -445,16 +805,46 @@ impl SshClient {
         }
     }
 
+    /// The authorized_keys file of a login, `None` only if it doesn't exist
+    async fn read_authorized_keyfile(
+        &self,
+        handle: &russh::client::Handle<SshHandler>,
+        login: String,
+    ) -> Result<Option<String>, SshClientError> {
+        match self
+            .run_bash(handle, BashCommand::GetAuthorizedKeyfile(login))
+            .await?
+        {
+            (0, file) => Ok(Some(file)),
+            (KEYFILE_MISSING, _) => Ok(None),
+            (_, error) => Err(SshClientError::ExecutionError(error)),
+        }
+    }
+
     async fn execute_bash(
         &self,
         handle: &russh::client::Handle<SshHandler>,
         command: BashCommand,
     ) -> Result<BashResult, SshClientError> {
+        let (exit_code, result) = self.run_bash(handle, command).await?;
+        Ok(match exit_code {
+            0 => BashResult::Ok(result),
+            _ => BashResult::Err(result),
+        })
+    }
+
+    /// Runs a command of the script, installing it first if needed, and returns the exit
+    /// code and output
+    async fn run_bash(
+        &self,
+        handle: &russh::client::Handle<SshHandler>,
+        command: BashCommand,
+    ) -> Result<(u32, String), SshClientError> {
         let (exit_code, result) = self
             .execute(handle, BashCommand::Version.to_string().as_str())
             .await?;
         // TODO: checksums
-        if exit_code != 0 || !result.contains("Secure SSH Manager") {
+        if exit_code != 0 || !result.contains(SCRIPT_VERSION) {
             warn!("Script on host seems to be invalid. Trying to install");
             match self.install_script(handle).await {
                 Ok(()) => {
@@ -472,31 +862,7 @@ impl SshClient {
         let command_str = command.to_string();
         debug!("Executing bash command {}", &command_str);
 
-        let stdin: Option<String> = match command {
-            BashCommand::SetAuthorizedKeyfile(_, new_keyfile) => Some(new_keyfile),
-            BashCommand::Update(new_script) => Some(new_script),
-
-            BashCommand::GetAuthorizedKeyfile(_)
-            | BashCommand::GetSshUsers
-            | BashCommand::Version => None,
-        };
-
-        let (exit_code, result) = match stdin {
-            Some(stdin) => {
-                self.execute_with_data(
-                    handle,
-                    Cursor::new(stdin.into_bytes()),
-                    command_str.as_str(),
-                )
-                .await
-            }
-            None => self.execute(handle, command_str.as_str()).await,
-        }?;
-
-        Ok(match exit_code {
-            0 => BashResult::Ok(result),
-            _ => BashResult::Err(result),
-        })
+        self.execute(handle, command_str.as_str()).await
     }
 
     async fn execute(
@@ -515,6 +881,21 @@ impl SshClient {
         data: R,
         command: &str,
     ) -> Result<(u32, String), SshClientError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        Span::start("ssh run_command", SpanKind::Client)
+            .with_attribute("ssh.command", command)
+            .run(self.run_command(handle, data, command))
+            .await
+    }
+
+    async fn run_command<R>(
+        &self,
+        handle: &russh::client::Handle<SshHandler>,
+        data: R,
+        command: &str,
+    ) -> Result<(u32, String), SshClientError>
     where
         R: AsyncRead + Unpin,
     {
@@ -571,7 +952,7 @@ impl SshClient {
         host_name: String,
         login: String,
     ) -> Result<Vec<KeyDiffItem>, SshClientError> {
-        let Some(host) = Host::get_from_name(self.conn.get().unwrap(), host_name).await? else {
+        let Some(host) = Host::get_from_name(&self.db, host_name).await? else {
             return Err(SshClientError::NoSuchHost);
         };
 
@@ -580,6 +961,13 @@ impl SshClient {
         let curr_keys = self
             .execute_bash(&conn, BashCommand::GetAuthorizedKeyfile(login))
             .await??;
+        // Only the managed block is written, so only it changes
+        let curr_keys = match keyfile::managed_block(&curr_keys) {
+            Some(block) if !self.config.manage_whole_keyfile => block
+                .into_iter()
+                .fold(String::new(), |buf, line| buf + line + "\n"),
+            _ => curr_keys,
+        };
 
         let new_keys = new.to_owned();
 
@@ -596,19 +984,131 @@ impl SshClient {
     }
 }
 
+/// Parses a single authorized_keys line, recognizing OpenSSH certificates
+fn parse_authorized_key(line: &str) -> AuthorizedKeyEntry {
+    if let Some(res) = parse_certificate(line) {
+        return res.map_err(|e| (e, line.to_owned()));
+    }
+
+    let key = Entry::from_str(line).map_err(|e| (e.to_string(), line.to_owned()))?;
+
+    //TODO: algorithm to estimate size
+    let mut buf = vec![0u8; 1024];
+    let mut writer = Base64Writer::new(&mut buf).expect("buf is non-zero");
+
+    let pkey = key.public_key();
+    let comment = pkey.comment();
+
+    pkey.key_data().encode(&mut writer).expect("Buffer overrun");
+    let b64 = writer.finish().expect("Buffer overrun");
+
+    Ok(AuthorizedKey {
+        options: key
+            .config_opts()
+            .as_str()
+            .parse()
+            .map_err(|e| (e, line.to_owned()))?,
+        algorithm: pkey.algorithm(),
+        base64: b64.to_owned(),
+        comment: if comment.is_empty() {
+            None
+        } else {
+            Some(comment.to_owned())
+        },
+        certificate: None,
+    })
+}
+
+/// Only the certificate types OpenSSH knows, any other name ending in `-cert-v01@openssh.com`
+/// would be accepted as [`ssh_key::Algorithm::Other`]
+fn is_certificate_type(token: &str) -> bool {
+    ssh_key::Algorithm::new_certificate(token)
+        .is_ok_and(|algorithm| !matches!(algorithm, ssh_key::Algorithm::Other(_)))
+}
+
+/// Splits the options off the start of an authorized_keys line. They end at the first
+/// whitespace outside of double quotes, in which `\"` doesn't end the quote.
+fn split_options(line: &str) -> (&str, &str) {
+    let (mut quoted, mut escaped) = (false, false);
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => return (&line[..i], line[i..].trim_start()),
+            _ => {}
+        }
+    }
+    (line, "")
+}
+
+/// Returns None if this line doesn't contain a certificate. Like sshd, only the field after
+/// the options, if any, is taken as the key type, never text in options or the comment.
+fn parse_certificate(line: &str) -> Option<Result<AuthorizedKey, String>> {
+    let line = line.trim();
+    let (options, certificate) = match line.split_whitespace().next() {
+        Some(first) if is_certificate_type(first) => ("", line),
+        _ => split_options(line),
+    };
+    let mut fields = certificate.split_whitespace();
+    if !fields.next().is_some_and(is_certificate_type) {
+        return None;
+    }
+    let base64 = fields.next().unwrap_or_default();
+
+    Some(certificate_entry(options, certificate, base64))
+}
+
+fn certificate_entry(
+    options: &str,
+    certificate: &str,
+    base64: &str,
+) -> Result<AuthorizedKey, String> {
+    let options = options.parse::<KeyOptions>()?;
+    let certificate = Certificate::from_openssh(certificate).map_err(|e| e.to_string())?;
+    let comment = certificate.comment();
+
+    Ok(AuthorizedKey {
+        options,
+        algorithm: certificate.algorithm(),
+        base64: base64.to_owned(),
+        comment: if comment.is_empty() {
+            None
+        } else {
+            Some(comment.to_owned())
+        },
+        certificate: Some(CertificateInfo {
+            cert_type: if certificate.cert_type().is_host() {
+                "host"
+            } else {
+                "user"
+            },
+            key_id: certificate.key_id().to_owned(),
+            serial: certificate.serial(),
+            principals: certificate.valid_principals().to_vec(),
+            valid_after: certificate.valid_after(),
+            valid_before: certificate.valid_before(),
+            signing_key: certificate
+                .signature_key()
+                .fingerprint(ssh_key::HashAlg::default())
+                .to_string(),
+        }),
+    })
+}
+
 type User = String;
 pub enum BashCommand {
     /// Read the authorized keys for a user
     GetAuthorizedKeyfile(User),
 
-    /// Set authorized keys for a user
-    SetAuthorizedKeyfile(User, String),
+    /// Where the authorized keys of a user are, fails if they are read-only
+    GetAuthorizedKeyfileLocation(User),
 
     /// Get all users that are allowed to login via SSH
     GetSshUsers,
 
-    /// Update the bash script on the server
-    Update(String),
+    /// Get the sshd settings relevant for key logins
+    GetSshdConfig,
 
     /// Check the script version
     Version,
@@ -619,11 +1119,11 @@ impl std::fmt::Display for BashCommand {
         write!(f, ".ssh/ssm.sh ")?;
         match self {
             Self::GetAuthorizedKeyfile(user) => write!(f, "get_authorized_keyfile {user}"),
-            Self::SetAuthorizedKeyfile(user, _new_keyfile) => {
-                write!(f, "set_authorized_keyfile {user}")
+            Self::GetAuthorizedKeyfileLocation(user) => {
+                write!(f, "get_authorized_keyfile_location {user}")
             }
             Self::GetSshUsers => write!(f, "get_ssh_users"),
-            Self::Update(_script) => write!(f, "update_script"),
+            Self::GetSshdConfig => write!(f, "get_sshd_config"),
             Self::Version => write!(f, "version"),
         }
     }
@@ -638,3 +1138,76 @@ impl From<BashExecError> for SshClientError {
 type BashExecError = String;
 type BashExecResponse = String;
 pub type BashResult = Result<BashExecResponse, BashExecError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CERTIFICATE: &str = "ssh-ed25519-cert-v01@openssh.com AAAAIHNzaC1lZDI1NTE5LWNlcnQtdjAxQG9wZW5zc2guY29tAAAAIA3SEVdRAbhosxyl8iNihdBz+NT/ZWncMr90nZS85ulAAAAAIEFAnmqzGCdn1uZQpsCr98N2j2Hbwnt9LLGqV8voDvavAAAAAAAAACoAAAABAAAACGFsaWNlLWlkAAAAEQAAAAVhbGljZQAAAARyb290AAAAAF4L4QAAAAAA9IUFgAAAAAAAAACCAAAAFXBlcm1pdC1YMTEtZm9yd2FyZGluZwAAAAAAAAAXcGVybWl0LWFnZW50LWZvcndhcmRpbmcAAAAAAAAAFnBlcm1pdC1wb3J0LWZvcndhcmRpbmcAAAAAAAAACnBlcm1pdC1wdHkAAAAAAAAADnBlcm1pdC11c2VyLXJjAAAAAAAAAAAAAAAzAAAAC3NzaC1lZDI1NTE5AAAAICBG/y8Vl5UxVvI/1Suadfy3kRQAkab5EMEIE+g1ajDdAAAAUwAAAAtzc2gtZWQyNTUxOQAAAECtqQ2FHpoN1MhWQyHCJcs4pZp5Slb3iSKaXtaqke+KIYLBgVEqjUGAgzJhEauO4xFXF6HgXDdIwQhq8yuxDUYO user";
+
+    fn certificate(line: &str) -> AuthorizedKey {
+        parse_authorized_key(line).expect("certificate parses")
+    }
+
+    #[test]
+    fn parses_certificates() {
+        let key = certificate(CERTIFICATE);
+        let info = key.certificate.expect("is a certificate");
+        assert_eq!(info.cert_type, "user");
+        assert_eq!(info.key_id, "alice-id");
+        assert_eq!(info.serial, 42);
+        assert_eq!(info.principals, ["alice", "root"]);
+        assert_eq!(
+            info.signing_key,
+            "SHA256:1B758D/EwCGRaqDbpByZs1vNgIC5aklKXuE6Pd7x7zY"
+        );
+        assert_eq!(key.comment.as_deref(), Some("user"));
+        assert!(CERTIFICATE.contains(&key.base64));
+        assert!(key.options.to_string().is_empty());
+    }
+
+    #[test]
+    fn parses_certificates_with_options_and_comments() {
+        let line =
+            format!(r#"no-pty,command="echo \"a b\"",from="10.0.0.0/8" {CERTIFICATE} two words"#);
+        let key = certificate(&line);
+        assert!(key.certificate.is_some());
+        assert_eq!(key.comment.as_deref(), Some("user two words"));
+        let options = key.options.to_string();
+        assert!(options.contains("no-pty"), "{options}");
+        assert!(options.contains("10.0.0.0/8"), "{options}");
+    }
+
+    #[test]
+    fn ignores_certificate_types_outside_the_key_type() {
+        let plain =
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAxTf8anGjRB8KZVR/jjPvKhPeoDir8UUyEoVkJ9forE";
+        // In an option or the comment the type doesn't make a certificate
+        for line in [
+            format!(r#"command="echo ssh-ed25519-cert-v01@openssh.com x" {plain}"#),
+            format!("{plain} ssh-ed25519-cert-v01@openssh.com"),
+            format!(r#"command="ssh-ed25519-cert-v01@openssh.com" {plain} c"#),
+        ] {
+            assert!(parse_certificate(&line).is_none(), "{line}");
+        }
+        let line = format!("{plain} ssh-ed25519-cert-v01@openssh.com");
+        let key = parse_authorized_key(&line).expect("plain key parses");
+        assert!(key.certificate.is_none());
+        // Nor does a quoted part of the options that looks like a key
+        let line = format!(r#"command="x {CERTIFICATE}" {plain}"#);
+        assert!(parse_certificate(&line).is_none());
+    }
+
+    #[test]
+    fn certificate_validity() {
+        let mut info = certificate(CERTIFICATE)
+            .certificate
+            .expect("is a certificate");
+        assert!(!info.is_expired());
+        info.valid_before = 1_600_000_000;
+        assert!(info.is_expired());
+        info.valid_after = u64::MAX - 1;
+        info.valid_before = u64::MAX;
+        assert!(info.is_expired());
+    }
+}
@@ -0,0 +1,58 @@
+/// The parts of the effective sshd configuration of a host that decide
+/// whether a deployed key can actually be used
+#[derive(Debug, Clone, Default)]
+pub struct SshdConfig {
+    pub permit_root_login: Option<String>,
+    pub pubkey_authentication: Option<String>,
+}
+
+impl SshdConfig {
+    /// Parses `keyword value` lines, either from `sshd -T` or sshd_config.
+    /// Like sshd, the first value for each keyword wins.
+    pub fn parse(output: &str) -> Self {
+        let mut config = Self::default();
+
+        for line in output.lines() {
+            let mut parts = line.split_whitespace();
+            let (Some(keyword), Some(value)) = (parts.next(), parts.next()) else {
+                continue;
+            };
+            let value = Some(value.to_lowercase());
+
+            match keyword.to_lowercase().as_str() {
+                "permitrootlogin" if config.permit_root_login.is_none() => {
+                    config.permit_root_login = value;
+                }
+                "pubkeyauthentication" if config.pubkey_authentication.is_none() => {
+                    config.pubkey_authentication = value;
+                }
+                _ => {}
+            }
+        }
+
+        config
+    }
+
+    /// Explains why logging in as root with a key would fail, if it would
+    pub fn root_login_advisory(&self) -> Option<String> {
+        if self.pubkey_authentication.as_deref() == Some("no") {
+            return Some(String::from(
+                "Public key authentication is disabled on this host (PubkeyAuthentication no). \
+                 The key will be deployed, but only password logins are accepted.",
+            ));
+        }
+
+        // Unset means the OpenSSH default of prohibit-password, which allows keys
+        match self.permit_root_login.as_deref() {
+            Some("no") => Some(String::from(
+                "Root login is disabled on this host (PermitRootLogin no). \
+                 The key will be deployed, but logging in as root will fail.",
+            )),
+            Some("forced-commands-only") => Some(String::from(
+                "Root may only log in with keys restricted by a command= option \
+                 (PermitRootLogin forced-commands-only).",
+            )),
+            _ => None,
+        }
+    }
+}
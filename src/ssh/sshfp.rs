@@ -0,0 +1,176 @@
+use std::{net::IpAddr, str::FromStr};
+
+use hickory_resolver::{
+    error::{ResolveError, ResolveErrorKind},
+    proto::rr::{
+        rdata::sshfp::{FingerprintType, SSHFP},
+        RData, RecordType,
+    },
+    system_conf::read_system_conf,
+    TokioAsyncResolver,
+};
+use ssh_key::Fingerprint;
+
+/// The host key was compared by hand, or ssm can't tell
+pub const TRUST_MANUAL: &str = "manual";
+/// An SSHFP record matched, but DNSSEC didn't validate it
+pub const TRUST_SSHFP: &str = "sshfp";
+/// A DNSSEC validated SSHFP record matched
+pub const TRUST_SSHFP_DNSSEC: &str = "sshfp-dnssec";
+
+/// What the SSHFP records of a host say about its host key
+#[derive(Debug, Clone)]
+pub struct SshfpCheck {
+    /// Number of SHA-256 records
+    pub records: usize,
+    /// Whether one of them is the fingerprint of the host key
+    pub matches: bool,
+    /// None if DNSSEC validated the records, otherwise why not
+    pub unvalidated: Option<String>,
+}
+
+impl SshfpCheck {
+    /// The trust source to store with the host key
+    pub fn trust(&self) -> &'static str {
+        match (self.matches, &self.unvalidated) {
+            (true, None) => TRUST_SSHFP_DNSSEC,
+            (true, Some(_)) => TRUST_SSHFP,
+            (false, _) => TRUST_MANUAL,
+        }
+    }
+
+    /// Records that DNSSEC vouches for, but none of which is the host key
+    pub fn is_secure_mismatch(&self) -> bool {
+        !self.matches && self.unvalidated.is_none()
+    }
+
+    pub fn describe(&self) -> String {
+        let dnssec = match &self.unvalidated {
+            None => String::from("validated by DNSSEC"),
+            Some(reason) => format!("not validated by DNSSEC ({reason})"),
+        };
+        match self.matches {
+            true => format!("The host key matches an SSHFP record, {dnssec}."),
+            false => format!(
+                "None of the {} SSHFP records matches the host key, {dnssec}. Someone may be intercepting the connection.",
+                self.records
+            ),
+        }
+    }
+}
+
+/// Number of SHA-256 records and whether one of them has this fingerprint
+fn compare(fingerprint: &str, records: &[SSHFP]) -> (usize, bool) {
+    let expected = Fingerprint::from_str(fingerprint)
+        .ok()
+        .and_then(|fingerprint| fingerprint.sha256());
+    let sha256: Vec<&SSHFP> = records
+        .iter()
+        .filter(|record| record.fingerprint_type() == FingerprintType::SHA256)
+        .collect();
+    let matches = expected.is_some_and(|expected| {
+        sha256
+            .iter()
+            .any(|record| record.fingerprint() == expected.as_slice())
+    });
+    (sha256.len(), matches)
+}
+
+fn resolver(validate: bool) -> Result<TokioAsyncResolver, String> {
+    let (config, mut options) =
+        read_system_conf().map_err(|e| format!("Couldn't read the resolver configuration: {e}"))?;
+    options.validate = validate;
+    Ok(TokioAsyncResolver::tokio(config, options))
+}
+
+async fn lookup(resolver: &TokioAsyncResolver, name: &str) -> Result<Vec<SSHFP>, ResolveError> {
+    Ok(resolver
+        .lookup(name, RecordType::SSHFP)
+        .await?
+        .iter()
+        .filter_map(|data| match data {
+            RData::SSHFP(record) => Some(record.clone()),
+            _ => None,
+        })
+        .collect())
+}
+
+fn is_missing(error: &ResolveError) -> bool {
+    matches!(error.kind(), ResolveErrorKind::NoRecordsFound { .. })
+}
+
+/// Compares the SSHFP records of `hostname` with the SHA-256 fingerprint of its host key.
+/// None if the address is an IP or there are no records.
+pub async fn check_sshfp(hostname: &str, fingerprint: &str) -> Result<Option<SshfpCheck>, String> {
+    if hostname.parse::<IpAddr>().is_ok() {
+        return Ok(None);
+    }
+    // Absolute, so the search domains don't apply
+    let name = format!("{}.", hostname.trim_end_matches('.'));
+
+    // Unsigned zones fail validation as well, so look again without to tell them apart
+    let (records, unvalidated) = match lookup(&resolver(true)?, &name).await {
+        Ok(records) => (records, None),
+        Err(validation_error) => match lookup(&resolver(false)?, &name).await {
+            Ok(records) => (records, Some(validation_error.to_string())),
+            Err(e) if is_missing(&e) => return Ok(None),
+            Err(e) => return Err(format!("Couldn't look up SSHFP records: {e}")),
+        },
+    };
+
+    let (records, matches) = compare(fingerprint, &records);
+    if records == 0 {
+        return Ok(None);
+    }
+    Ok(Some(SshfpCheck {
+        records,
+        matches,
+        unvalidated,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_resolver::proto::rr::rdata::sshfp::Algorithm;
+
+    use super::*;
+
+    const FINGERPRINT: &str = "SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU";
+
+    fn sha256_of_empty() -> Vec<u8> {
+        Fingerprint::from_str(FINGERPRINT)
+            .unwrap()
+            .sha256()
+            .unwrap()
+            .to_vec()
+    }
+
+    #[test]
+    fn matches_sha256_records() {
+        let records = [
+            SSHFP::new(Algorithm::Ed25519, FingerprintType::SHA1, vec![0; 20]),
+            SSHFP::new(Algorithm::Ed25519, FingerprintType::SHA256, vec![0; 32]),
+            SSHFP::new(Algorithm::ECDSA, FingerprintType::SHA256, sha256_of_empty()),
+        ];
+        assert_eq!(compare(FINGERPRINT, &records), (2, true));
+        assert_eq!(compare(FINGERPRINT, &records[..2]), (1, false));
+        assert_eq!(compare("not a fingerprint", &records), (2, false));
+    }
+
+    #[test]
+    fn trust_depends_on_dnssec() {
+        let mut check = SshfpCheck {
+            records: 1,
+            matches: true,
+            unvalidated: None,
+        };
+        assert_eq!(check.trust(), TRUST_SSHFP_DNSSEC);
+        check.unvalidated = Some(String::from("unsigned"));
+        assert_eq!(check.trust(), TRUST_SSHFP);
+        check.matches = false;
+        assert_eq!(check.trust(), TRUST_MANUAL);
+        assert!(!check.is_secure_mismatch());
+        check.unvalidated = None;
+        assert!(check.is_secure_mismatch());
+    }
+}
@@ -0,0 +1,234 @@
+use std::{
+    fmt, fs, io,
+    net::{SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use actix_http::{Extensions, HttpService, Protocol, Request, Response};
+use actix_server::Server;
+use actix_service::{
+    fn_service, map_config, IntoServiceFactory, ServiceFactory, ServiceFactoryExt,
+};
+use actix_web::{body::MessageBody, dev::AppConfig};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use tokio::{net::TcpStream, time::MissedTickBehavior};
+use tokio_rustls::{
+    rustls::{
+        crypto::{ring, CryptoProvider},
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+/// Clients that take longer to complete the handshake are disconnected
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(3);
+
+const fn default_reload_interval() -> Option<Duration> {
+    Some(Duration::from_secs(60))
+}
+
+/// Serving HTTPS directly, without a reverse proxy
+#[derive(Debug, Deserialize, Clone)]
+pub struct TlsConfig {
+    /// PEM file with the certificate, followed by the intermediate certificates
+    cert_file: PathBuf,
+    /// PEM file with the private key of the certificate
+    key_file: PathBuf,
+    /// Seconds between checks whether the files changed, e.g. after a renewal
+    /// (default 60, 0 disables)
+    #[serde(
+        default = "default_reload_interval",
+        deserialize_with = "crate::deserialize_interval"
+    )]
+    reload_interval: Option<Duration>,
+}
+
+/// Hands out the current certificate, so a reload applies to new connections right away
+struct Certificates {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl fmt::Debug for Certificates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Certificates").finish_non_exhaustive()
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(&self.current.read().expect("lock poisoned")))
+    }
+}
+
+/// When the certificate and the key were last modified
+fn modified(config: &TlsConfig) -> Option<(SystemTime, SystemTime)> {
+    let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+    Some((modified(&config.cert_file)?, modified(&config.key_file)?))
+}
+
+fn load(provider: &CryptoProvider, config: &TlsConfig) -> Result<CertifiedKey, String> {
+    let read = |path: &Path| fs::read(path).map_err(|e| format!("Failed to read {path:?}: {e}"));
+
+    let certs = CertificateDer::pem_slice_iter(&read(&config.cert_file)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid certificate in {:?}: {e}", config.cert_file))?;
+    if certs.is_empty() {
+        return Err(format!(
+            "There are no certificates in {:?}",
+            config.cert_file
+        ));
+    }
+    let key = PrivateKeyDer::from_pem_slice(&read(&config.key_file)?)
+        .map_err(|e| format!("Invalid private key in {:?}: {e}", config.key_file))?;
+    let key = provider
+        .key_provider
+        .load_private_key(key)
+        .map_err(|e| format!("Unsupported private key in {:?}: {e}", config.key_file))?;
+
+    let certified = CertifiedKey::new(certs, key);
+    certified.keys_match().map_err(|e| {
+        format!(
+            "The key in {:?} doesn't belong to the certificate in {:?}: {e}",
+            config.key_file, config.cert_file
+        )
+    })?;
+    Ok(certified)
+}
+
+/// Connection data of requests received over the HTTPS listener
+pub struct Https;
+
+/// The TLS settings of the listener, with the certificate reloaded when its files change
+#[derive(Clone)]
+pub struct Tls {
+    config: TlsConfig,
+    provider: Arc<CryptoProvider>,
+    certificates: Arc<Certificates>,
+    server_config: Arc<ServerConfig>,
+}
+
+impl TlsConfig {
+    /// Reads the certificate and the key
+    pub fn load(self) -> Result<Tls, String> {
+        let provider = Arc::new(ring::default_provider());
+        let certificates = Arc::new(Certificates {
+            current: RwLock::new(Arc::new(load(&provider, &self)?)),
+        });
+        let mut server_config = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| format!("Failed to set up TLS: {e}"))?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::clone(&certificates) as Arc<dyn ResolvesServerCert>);
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(Tls {
+            config: self,
+            provider,
+            certificates,
+            server_config: Arc::new(server_config),
+        })
+    }
+}
+
+impl Tls {
+    /// Checks every configured interval whether the certificate or the key changed, and
+    /// reloads them if so. Until both are valid again the previous certificate is kept.
+    pub fn start(&self) {
+        let Some(interval) = self.config.reload_interval else {
+            return;
+        };
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut loaded = modified(&this.config);
+            let mut interval = tokio::time::interval(interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let current = modified(&this.config);
+                if current.is_none() || current == loaded {
+                    continue;
+                }
+                // Not tried again until the files change once more
+                loaded = current;
+                match load(&this.provider, &this.config) {
+                    Ok(certified) => {
+                        *this.certificates.current.write().expect("lock poisoned") =
+                            Arc::new(certified);
+                        info!("Reloaded the TLS certificate {:?}", this.config.cert_file);
+                    }
+                    Err(e) => warn!("Keeping the previous TLS certificate: {e}"),
+                }
+            }
+        });
+    }
+
+    /// Serves the app over HTTPS on `addr`, like `HttpServer` does over HTTP
+    pub fn serve<F, I, S, B>(
+        &self,
+        factory: F,
+        addr: SocketAddr,
+        shutdown_timeout: Duration,
+    ) -> io::Result<Server>
+    where
+        F: Fn() -> I + Send + Clone + 'static,
+        I: IntoServiceFactory<S, Request>,
+        S: ServiceFactory<Request, Config = AppConfig> + 'static,
+        S::Error: Into<actix_web::Error> + 'static,
+        S::InitError: fmt::Debug,
+        S::Response: Into<Response<B>> + 'static,
+        <S::Service as actix_service::Service<Request>>::Future: 'static,
+        S::Service: 'static,
+        B: MessageBody + 'static,
+    {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let acceptor = TlsAcceptor::from(Arc::clone(&self.server_config));
+
+        let server = Server::build()
+            .disable_signals()
+            .shutdown_timeout(shutdown_timeout.as_secs())
+            .listen("ssm-https", listener, move || {
+                let acceptor = acceptor.clone();
+                let app = factory()
+                    .into_factory()
+                    .map_err(|e| e.into().error_response());
+
+                fn_service(move |stream: TcpStream| {
+                    let acceptor = acceptor.clone();
+                    async move {
+                        let peer = stream.peer_addr().ok();
+                        let stream =
+                            tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+                                .await
+                                .map_err(|_| {
+                                    io::Error::new(io::ErrorKind::TimedOut, "handshake timed out")
+                                })??;
+                        let protocol = match stream.get_ref().1.alpn_protocol() {
+                            Some(b"h2") => Protocol::Http2,
+                            _ => Protocol::Http1,
+                        };
+                        Ok((stream, protocol, peer))
+                    }
+                })
+                .map_err(|e: io::Error| debug!("TLS handshake failed: {e}"))
+                .and_then(
+                    HttpService::build()
+                        .local_addr(addr)
+                        .on_connect_ext(|_: &_, data: &mut Extensions| {
+                            data.insert(Https);
+                        })
+                        .finish(map_config(app, |()| AppConfig::default()))
+                        .map_err(|e| debug!("Failed to serve a TLS connection: {e}")),
+                )
+            })?
+            .run();
+        info!("Serving HTTPS on {addr}");
+        Ok(server)
+    }
+}
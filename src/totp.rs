@@ -0,0 +1,160 @@
+use data_encoding::{BASE32_NOPAD, HEXLOWER};
+use hmac::{Hmac, Mac};
+use qrcode::{render::svg, QrCode};
+use rand::RngCore;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+/// Seconds each code is valid, as authenticator apps assume
+const STEP: i64 = 30;
+const DIGITS: u32 = 6;
+/// Codes of the neighbouring steps are accepted too, for clocks that are a bit off
+const ALLOWED_DRIFT: i64 = 1;
+const RECOVERY_CODES: usize = 10;
+
+/// A random secret for a new enrollment, base32 encoded for authenticator apps
+pub fn new_secret() -> String {
+    let mut secret = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut secret);
+    BASE32_NOPAD.encode(&secret)
+}
+
+/// Percent-encodes everything but unreserved characters
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                char::from(b).to_string()
+            }
+            b => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// The `otpauth://` URI authenticator apps scan from the QR code
+pub fn provisioning_uri(secret: &str, username: &str) -> String {
+    let issuer = encode(crate::branding::instance_name());
+    format!(
+        "otpauth://totp/{issuer}:{}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP}",
+        encode(username)
+    )
+}
+
+/// The provisioning URI as an inline SVG QR code
+pub fn qr_code(uri: &str) -> Option<String> {
+    let code = QrCode::new(uri.as_bytes()).ok()?;
+    let svg = code.render::<svg::Color>().min_dimensions(200, 200).build();
+    // Without the XML declaration, which doesn't belong into HTML
+    svg.find("<svg").map(|start| svg[start..].to_owned())
+}
+
+/// The code of a time step (RFC 6238)
+fn code_at(secret: &[u8], step: i64) -> u32 {
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = usize::from(hash[hash.len() - 1] & 0x0f);
+    let truncated = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    truncated % 10u32.pow(DIGITS)
+}
+
+/// The time step of a valid `code`, which has to be after `last_step` so codes can't be
+/// used twice
+pub fn verify(secret: &str, code: &str, last_step: Option<i64>) -> Option<i64> {
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+    let code = code.parse::<u32>().ok()?;
+    let now = OffsetDateTime::now_utc().unix_timestamp() / STEP;
+    (now - ALLOWED_DRIFT..=now + ALLOWED_DRIFT)
+        .filter(|step| last_step.is_none_or(|last| *step > last))
+        .find(|step| code_at(&secret, *step) == code)
+}
+
+/// New single-use recovery codes, shown to the user once
+pub fn new_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let mut bytes = [0u8; 5];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            let hex = HEXLOWER.encode(&bytes);
+            format!("{}-{}", &hex[..5], &hex[5..])
+        })
+        .collect()
+}
+
+/// How recovery codes are stored, ignoring case, spaces and dashes of the entered code
+pub fn hash_recovery_code(code: &str) -> String {
+    let code: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    HEXLOWER.encode(&Sha256::digest(code.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    fn current_code() -> String {
+        let step = OffsetDateTime::now_utc().unix_timestamp() / STEP;
+        format!("{:06}", code_at(SECRET, step))
+    }
+
+    #[test]
+    fn codes_match_rfc_6238() {
+        // The SHA-1 vectors of RFC 6238, truncated to six digits
+        assert_eq!(code_at(SECRET, 59 / STEP), 287_082);
+        assert_eq!(code_at(SECRET, 1_111_111_109 / STEP), 81_804);
+        assert_eq!(code_at(SECRET, 2_000_000_000 / STEP), 279_037);
+    }
+
+    #[test]
+    fn codes_are_used_once() {
+        let secret = BASE32_NOPAD.encode(SECRET);
+        let code = current_code();
+        let step = verify(&secret, &code, None).unwrap();
+        assert_eq!(verify(&secret, &code, Some(step)), None);
+        assert_eq!(verify(&secret, &code, Some(step + 1)), None);
+        assert_eq!(verify(&secret, &code, Some(step - 2)), Some(step));
+    }
+
+    #[test]
+    fn refuses_other_codes() {
+        let secret = BASE32_NOPAD.encode(SECRET);
+        let code = current_code();
+        let wrong = format!("{:06}", (code.parse::<u32>().unwrap() + 1) % 1_000_000);
+        assert_eq!(verify(&secret, &wrong, None), None);
+        assert_eq!(verify(&secret, &code[..5], None), None);
+        assert_eq!(verify("not base32!", &code, None), None);
+        // Spaces as some apps show them are fine
+        let spaced = format!("{} {}", &code[..3], &code[3..]);
+        assert!(verify(&secret, &spaced, None).is_some());
+    }
+
+    #[test]
+    fn recovery_codes_are_distinct_and_normalized() {
+        let codes = new_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        let mut hashes: Vec<String> = codes.iter().map(|c| hash_recovery_code(c)).collect();
+        hashes.sort();
+        hashes.dedup();
+        assert_eq!(hashes.len(), RECOVERY_CODES);
+
+        let code = &codes[0];
+        let typed = format!(" {} ", code.to_uppercase().replace('-', " "));
+        assert_eq!(hash_recovery_code(&typed), hash_recovery_code(code));
+    }
+}
@@ -21,7 +21,36 @@ function show_response(html, is_success) {
   snackbar.prepend(div);
 }
 
+// Requests changing anything need the token of the session
+const csrfToken = document.querySelector('meta[name="csrf-token"]').content;
+
+document.body.addEventListener("htmx:configRequest", (event) => {
+  event.detail.headers["X-CSRF-Token"] = csrfToken;
+});
+
+document.addEventListener("submit", (event) => {
+  const form = event.target;
+  if (form.method !== "post" || form.querySelector('input[name="csrf_token"]')) return;
+  const input = document.createElement("input");
+  input.type = "hidden";
+  input.name = "csrf_token";
+  input.value = csrfToken;
+  form.appendChild(input);
+}, true);
+
+// Errors of fragments are shown where the fragment would be
+const isErrorFragment = (xhr) => xhr.getResponseHeader("X-ERROR-FRAGMENT") === "true";
+
+document.body.addEventListener("htmx:beforeSwap", (event) => {
+  if (isErrorFragment(event.detail.xhr)) {
+    event.detail.shouldSwap = true;
+    event.detail.isError = false;
+  }
+});
+
 document.body.addEventListener("htmx:afterRequest", (event) => {
+  if (isErrorFragment(event.detail.xhr)) return;
+
   const isFormResponse = (event.detail.xhr.getResponseHeader("X-FORM") === "true");
   const isSuccess = (event.detail.successful === true);
   if (!isSuccess) {
@@ -51,3 +80,10 @@ document.body.addEventListener("htmx:afterRequest", (event) => {
 
   show_response(event.detail.xhr.response, true);
 });
+
+// File inputs fill the textarea they name, so forms stay plain text
+document.addEventListener("change", async (event) => {
+  const input = event.target;
+  if (!input.matches('input[type="file"][data-fill]') || !input.files.length) return;
+  document.getElementById(input.dataset.fill).value = await input.files[0].text();
+});
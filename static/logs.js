@@ -0,0 +1,27 @@
+// Appends the events streamed from /logs/stream to the log table, with the filter of the page.
+// EventSource reconnects by itself and sends the id of the last event it received.
+
+const logEvents = document.getElementById("log-events");
+const logStatus = document.getElementById("log-status");
+const logBasePath = document.querySelector('meta[name="base-path"]')?.content ?? "";
+
+function appendLogEvent(event) {
+  const row = document.createElement("tr");
+  for (const [value, code] of [[event.time], [event.level], [event.module, true], [event.message]]) {
+    const cell = document.createElement("td");
+    const text = code ? document.createElement("code") : cell;
+    text.textContent = value;
+    if (code) cell.appendChild(text);
+    row.appendChild(cell);
+  }
+  const atBottom = window.innerHeight + window.scrollY >= document.body.scrollHeight - 10;
+  logEvents.appendChild(row);
+  if (atBottom) window.scrollTo(0, document.body.scrollHeight);
+}
+
+const logQuery = new URLSearchParams(location.search);
+logQuery.set("after", logEvents.dataset.lastId);
+const logSource = new EventSource(`${logBasePath}/logs/stream?${logQuery}`);
+logSource.onopen = () => { logStatus.textContent = "Live"; };
+logSource.onerror = () => { logStatus.textContent = "Disconnected, reconnecting…"; };
+logSource.onmessage = (message) => appendLogEvent(JSON.parse(message.data));
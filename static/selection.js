@@ -0,0 +1,70 @@
+// Checkboxes with data-select="<kind>" reflect the selection stored on the server, so it
+// survives reloading and filtering lists. Elements with data-selection-count="<kind>" show
+// how many items are selected.
+
+function showSelection(kind, items) {
+  const selected = new Set(items);
+  document.querySelectorAll(`input[data-select="${kind}"]`).forEach((checkbox) => {
+    checkbox.checked = selected.has(checkbox.value);
+  });
+  document.querySelectorAll(`[data-selection-count="${kind}"]`).forEach((counter) => {
+    counter.textContent = items.length;
+  });
+}
+
+// ssm may be served under a sub-path
+const basePath = document.querySelector('meta[name="base-path"]')?.content ?? "";
+
+async function selectionRequest(kind, change) {
+  const response = await fetch(`${basePath}/selection/${kind}`, change === undefined ? {} : {
+    method: "POST",
+    headers: { "Content-Type": "application/json", "X-CSRF-Token": csrfToken },
+    body: JSON.stringify(change),
+  });
+  const body = await response.json();
+  if (!response.ok) {
+    show_response(`<b>An error occured:</b><br><i>${body.error}</i>`, false);
+    return loadSelection(kind);
+  }
+  showSelection(kind, body.items);
+}
+
+function loadSelection(kind) {
+  return selectionRequest(kind);
+}
+
+function changeSelection(kind, change) {
+  return selectionRequest(kind, change);
+}
+
+function selectionKinds() {
+  const kinds = new Set();
+  document.querySelectorAll("[data-select], [data-selection-count]").forEach((element) => {
+    kinds.add(element.dataset.select || element.dataset.selectionCount);
+  });
+  return kinds;
+}
+
+document.body.addEventListener("change", (event) => {
+  const checkbox = event.target;
+  if (!checkbox.matches("input[data-select]")) return;
+  const change = checkbox.checked ? { add: [checkbox.value] } : { remove: [checkbox.value] };
+  changeSelection(checkbox.dataset.select, change);
+});
+
+document.body.addEventListener("click", (event) => {
+  const button = event.target.closest("[data-select-all], [data-select-none]");
+  if (!button) return;
+  if (button.dataset.selectNone) {
+    changeSelection(button.dataset.selectNone, { clear: true });
+    return;
+  }
+  const kind = button.dataset.selectAll;
+  const visible = [...document.querySelectorAll(`input[data-select="${kind}"]`)].map((checkbox) => checkbox.value);
+  changeSelection(kind, { add: visible });
+});
+
+// Lists are loaded and reloaded by htmx
+document.body.addEventListener("htmx:afterSettle", () => selectionKinds().forEach(loadSelection));
+document.body.addEventListener("reload-selection", () => selectionKinds().forEach(loadSelection));
+selectionKinds().forEach(loadSelection);